@@ -0,0 +1,63 @@
+//! `yubikey-server`: JSON-RPC signing service backed by a YubiKey
+
+#![forbid(unsafe_code)]
+#![warn(
+    missing_docs,
+    rust_2018_idioms,
+    unused_lifetimes,
+    unused_qualifications
+)]
+
+use clap::Parser;
+use std::{env, process::exit};
+use yubikey::{pin::EnvPinProvider, Serial, YubiKey};
+use yubikey_cli::server::{Acl, Server};
+
+/// Serve signing requests for the keys of a YubiKey over JSON-RPC
+#[derive(Debug, Parser)]
+struct ServerOpts {
+    /// Serial number of the YubiKey to use
+    #[clap(short = 's', long = "serial")]
+    serial: Option<Serial>,
+
+    /// Address to listen on
+    #[clap(short = 'l', long = "listen", default_value = "127.0.0.1:7878")]
+    listen: String,
+
+    /// Slots and usages clients may use, e.g. `9c:sign,9d:decrypt`
+    #[clap(short = 'a', long = "allow", default_value = "")]
+    allow: Acl,
+
+    /// Maximum number of clients served at once
+    #[clap(long = "max-connections", default_value = "16")]
+    max_connections: usize,
+
+    /// Environment variable containing the PIN
+    #[clap(long = "pin-env", default_value = yubikey::pin::DEFAULT_PIN_VAR)]
+    pin_env: String,
+}
+
+fn main() {
+    let opts = ServerOpts::parse();
+
+    if env::var("RUST_LOG").is_ok() {
+        env_logger::builder().format_timestamp(None).init();
+    }
+
+    let yubikey = match opts.serial {
+        Some(serial) => YubiKey::open_by_serial(serial),
+        None => YubiKey::open(),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("error: couldn't open YubiKey: {}", e);
+        exit(1);
+    });
+
+    let server = Server::new(yubikey, &opts.allow, EnvPinProvider::new(opts.pin_env))
+        .with_max_connections(opts.max_connections);
+
+    if let Err(e) = server.serve(&opts.listen) {
+        eprintln!("error: couldn't serve on {}: {}", opts.listen, e);
+        exit(1);
+    }
+}
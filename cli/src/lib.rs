@@ -11,3 +11,5 @@
 #[macro_use]
 pub mod terminal;
 pub mod commands;
+#[cfg(feature = "server")]
+pub mod server;
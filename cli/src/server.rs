@@ -0,0 +1,537 @@
+//! JSON-RPC signing service.
+//!
+//! Serves [JSON-RPC 2.0] requests over TCP, one request per line, with the
+//! following methods:
+//!
+//! - `sign`: sign a prepared digest or padded block with the key in a slot,
+//!   as with [`piv::sign_data`]. Params: `{"slot": "9c", "algorithm":
+//!   "ECCP256", "data": "<hex>"}`; result: the signature, in hex
+//! - `decrypt` (with the `untested` feature): decrypt data or perform key
+//!   agreement with the key in a slot. Same params and result as `sign`
+//! - `inventory`: list the keys on the YubiKey. Result: `{"serial": ...,
+//!   "version": ..., "slots": [{"slot": ..., "public_key": "<hex DER>",
+//!   "fingerprint": "<hex SHA-256>"}]}`
+//!
+//! Private key operations are only allowed on the slots and usages granted
+//! in the [`Acl`], and the PIN is supplied by a [`PinProvider`] when needed.
+//! Errors are reported with the stable [`Error::code`] as their data.
+//!
+//! The service doesn't authenticate clients, so it must only be exposed to
+//! trusted ones (e.g. on localhost, or behind an authenticating proxy). At
+//! most [`Server::DEFAULT_MAX_CONNECTIONS`] clients are served at once
+//! (see [`Server::with_max_connections`]); further ones wait to be accepted.
+//! Clients idle for a minute, or sending a request line longer than 64 KiB,
+//! are disconnected.
+//!
+//! JSON-RPC is used rather than gRPC, which would pull in an async runtime
+//! and a protobuf toolchain for three methods. A gRPC front end can be put
+//! in front of the service (or added next to it) if needed.
+//!
+//! [JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+use x509_cert::der::{referenced::OwnedToRef, Encode};
+use yubikey::{
+    fingerprint,
+    inventory::Inventory,
+    pin::PinProvider,
+    piv::{self, AlgorithmId, SlotId, SLOTS},
+    Error, KeyUsage, KeyUsagePolicy, YubiKey,
+};
+
+/// JSON-RPC error code of requests which aren't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+
+/// JSON-RPC error code of requests which aren't valid request objects.
+const INVALID_REQUEST: i64 = -32600;
+
+/// JSON-RPC error code of unknown methods.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// JSON-RPC error code of invalid params.
+const INVALID_PARAMS: i64 = -32602;
+
+/// JSON-RPC error code of errors returned by the YubiKey.
+const YUBIKEY_ERROR: i64 = -32000;
+
+/// How long a client may stay idle before it is disconnected.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum length of a request line, newline included.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// Slots and usages clients are allowed to use.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    grants: Vec<(SlotId, KeyUsage)>,
+}
+
+impl Acl {
+    /// Allow the given usage of the given slot.
+    pub fn allow(mut self, slot: SlotId, usage: KeyUsage) -> Self {
+        self.grants.push((slot, usage));
+        self
+    }
+
+    /// Get the key usage policy enforcing this ACL, denying everything not
+    /// explicitly allowed.
+    pub fn policy(&self) -> KeyUsagePolicy {
+        let mut policy = KeyUsagePolicy::permissive();
+
+        for slot in SLOTS {
+            for usage in [KeyUsage::Sign, KeyUsage::Decrypt] {
+                policy = if self.grants.contains(&(slot, usage)) {
+                    policy.allow(slot, usage)
+                } else {
+                    policy.deny(slot, usage)
+                };
+            }
+        }
+
+        policy
+    }
+}
+
+impl FromStr for Acl {
+    type Err = String;
+
+    /// Parse an ACL from comma-separated `slot:usage` grants, e.g.
+    /// `9c:sign,9d:decrypt`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.split(',')
+            .filter(|grant| !grant.is_empty())
+            .try_fold(Acl::default(), |acl, grant| {
+                let (slot, usage) = grant
+                    .split_once(':')
+                    .ok_or_else(|| format!("invalid grant '{}': expected slot:usage", grant))?;
+                let slot = slot
+                    .parse()
+                    .map_err(|_| format!("invalid slot '{}'", slot))?;
+                let usage = match usage {
+                    "sign" => KeyUsage::Sign,
+                    "decrypt" => KeyUsage::Decrypt,
+                    _ => return Err(format!("invalid usage '{}'", usage)),
+                };
+                Ok(acl.allow(slot, usage))
+            })
+    }
+}
+
+/// JSON-RPC signing service backed by a YubiKey.
+pub struct Server {
+    yubikey: Arc<Mutex<YubiKey>>,
+    max_connections: usize,
+}
+
+impl Server {
+    /// Number of clients served at once by default.
+    pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+    /// Create a service for the given YubiKey, enforcing the given ACL and
+    /// getting the PIN from the given provider.
+    pub fn new(mut yubikey: YubiKey, acl: &Acl, pin_provider: impl PinProvider + 'static) -> Self {
+        yubikey.set_key_usage_policy(acl.policy());
+        yubikey.set_pin_provider(Some(Box::new(pin_provider)));
+
+        Self {
+            yubikey: Arc::new(Mutex::new(yubikey)),
+            max_connections: Self::DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+
+    /// Set how many clients are served at once (at least one).
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Serve clients connecting to the given address, each on its own thread.
+    ///
+    /// Once the maximum number of clients are connected, new connections
+    /// aren't accepted until one of them disconnects.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let limit = Arc::new(ConnectionLimit::new(self.max_connections));
+
+        loop {
+            let permit = limit.acquire();
+            let (stream, _) = listener.accept()?;
+            let yubikey = Arc::clone(&self.yubikey);
+
+            thread::spawn(move || {
+                if let Err(e) = handle_client(&yubikey, stream) {
+                    log::warn!("client connection failed: {}", e);
+                }
+
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Bound on the number of clients served at once.
+struct ConnectionLimit {
+    max: usize,
+    active: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ConnectionLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            active: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until fewer than the maximum number of clients are served, and
+    /// count one more until the returned permit is dropped.
+    fn acquire(self: &Arc<Self>) -> ConnectionPermit {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+
+        while *active >= self.max {
+            active = self
+                .released
+                .wait(active)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        *active += 1;
+        ConnectionPermit(Arc::clone(self))
+    }
+}
+
+/// Client counted by a [`ConnectionLimit`].
+struct ConnectionPermit(Arc<ConnectionLimit>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().unwrap_or_else(|e| e.into_inner());
+        *active -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Serve the requests of a client until it disconnects, stays idle for
+/// [`READ_TIMEOUT`] or sends a request longer than [`MAX_LINE`].
+fn handle_client(yubikey: &Mutex<YubiKey>, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if reader.by_ref().take(MAX_LINE).read_line(&mut line)? == 0 {
+            break;
+        }
+
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE {
+            let response = error_response(Value::Null, INVALID_REQUEST, "request too long", None);
+            writeln!(writer, "{}", response)?;
+            break;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = handle_request(yubikey, &line);
+        writeln!(writer, "{}", response)?;
+    }
+
+    Ok(())
+}
+
+/// Handle a JSON-RPC request, returning the response.
+fn handle_request(yubikey: &Mutex<YubiKey>, request: &str) -> Value {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => return error_response(Value::Null, PARSE_ERROR, &e.to_string(), None),
+    };
+
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let mut yubikey = match yubikey.lock() {
+        Ok(yubikey) => yubikey,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    let result = match method {
+        "sign" => private_key_operation(&mut yubikey, params, piv::sign_data),
+        #[cfg(feature = "untested")]
+        "decrypt" => private_key_operation(&mut yubikey, params, piv::decrypt_data),
+        "inventory" => inventory(&mut yubikey),
+        _ => {
+            return error_response(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("unknown method '{}'", method),
+                None,
+            )
+        }
+    };
+
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(RpcError::InvalidParams(message)) => error_response(id, INVALID_PARAMS, &message, None),
+        Err(RpcError::YubiKey(e)) => {
+            error_response(id, YUBIKEY_ERROR, &e.to_string(), Some(e.code()))
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str, data: Option<&str>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message, "data": data },
+    })
+}
+
+/// Errors handling a request.
+enum RpcError {
+    InvalidParams(String),
+    YubiKey(Error),
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        RpcError::YubiKey(e)
+    }
+}
+
+/// Params of private key operations.
+#[derive(Deserialize)]
+struct OperationParams {
+    slot: String,
+    algorithm: String,
+    data: String,
+}
+
+fn private_key_operation(
+    yubikey: &mut YubiKey,
+    params: Value,
+    operation: fn(&mut YubiKey, &[u8], AlgorithmId, SlotId) -> yubikey::Result<yubikey::Buffer>,
+) -> Result<Value, RpcError> {
+    let params: OperationParams =
+        serde_json::from_value(params).map_err(|e| RpcError::InvalidParams(e.to_string()))?;
+
+    let slot = params
+        .slot
+        .parse()
+        .map_err(|_| RpcError::InvalidParams(format!("invalid slot '{}'", params.slot)))?;
+    let algorithm = parse_algorithm(&params.algorithm).ok_or_else(|| {
+        RpcError::InvalidParams(format!("invalid algorithm '{}'", params.algorithm))
+    })?;
+    let data = hex::mixed::decode_vec(&params.data)
+        .map_err(|_| RpcError::InvalidParams("data must be hex-encoded".into()))?;
+
+    let output = operation(yubikey, &data, algorithm, slot)?;
+    Ok(Value::String(hex::lower::encode_string(&output)))
+}
+
+fn inventory(yubikey: &mut YubiKey) -> Result<Value, RpcError> {
+    let inventory = Inventory::collect(yubikey)?;
+
+    let slots = inventory
+        .slots
+        .iter()
+        .map(|entry| {
+            let public_key = entry.public_key.to_der().map_err(|_| Error::ParseError)?;
+            let fingerprint = fingerprint::sha256(entry.public_key.owned_to_ref())?;
+
+            Ok(json!({
+                "slot": entry.slot.to_string(),
+                "algorithm": entry.algorithm.map(|algorithm| format!("{:?}", algorithm)),
+                "public_key": hex::lower::encode_string(&public_key),
+                "fingerprint": hex::lower::encode_string(&fingerprint),
+            }))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(json!({
+        "serial": inventory.serial.to_string(),
+        "version": inventory.version.to_string(),
+        "slots": slots,
+    }))
+}
+
+fn parse_algorithm(algorithm: &str) -> Option<AlgorithmId> {
+    match algorithm.to_ascii_uppercase().as_str() {
+        "RSA1024" => Some(AlgorithmId::Rsa1024),
+        "RSA2048" => Some(AlgorithmId::Rsa2048),
+        "RSA3072" => Some(AlgorithmId::Rsa3072),
+        "RSA4096" => Some(AlgorithmId::Rsa4096),
+        "ECCP256" => Some(AlgorithmId::EccP256),
+        "ECCP384" => Some(AlgorithmId::EccP384),
+        "ED25519" => Some(AlgorithmId::Ed25519),
+        "X25519" => Some(AlgorithmId::X25519),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+    use yubikey::{emulator::Emulator, Buffer, PinPolicy, Serial, TouchPolicy};
+
+    struct FixedPin;
+
+    impl PinProvider for FixedPin {
+        fn pin(&mut self, _serial: Serial) -> yubikey::Result<Option<Buffer>> {
+            Ok(Some(Buffer::new(b"123456".to_vec())))
+        }
+    }
+
+    /// Server for an emulated YubiKey with a P-256 key in slot 9c, allowed
+    /// to sign with it.
+    fn server() -> Server {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        piv::generate(
+            &mut yubikey,
+            SlotId::Signature,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Never,
+        )
+        .expect("generate");
+
+        let acl = Acl::default().allow(SlotId::Signature, KeyUsage::Sign);
+        Server::new(yubikey, &acl, FixedPin)
+    }
+
+    fn error_code(response: &Value) -> Option<i64> {
+        response["error"]["code"].as_i64()
+    }
+
+    #[test]
+    fn acl_from_str() {
+        let acl: Acl = "9c:sign,9d:decrypt".parse().expect("parse");
+        assert_eq!(
+            acl.grants,
+            [
+                (SlotId::Signature, KeyUsage::Sign),
+                (SlotId::KeyManagement, KeyUsage::Decrypt)
+            ]
+        );
+
+        assert!(Acl::from_str("").expect("parse").grants.is_empty());
+
+        for invalid in ["9c", "9c:encrypt", "zz:sign", "9c:sign,9d"] {
+            assert!(Acl::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_algorithms() {
+        assert_eq!(parse_algorithm("ECCP256"), Some(AlgorithmId::EccP256));
+        assert_eq!(parse_algorithm("rsa2048"), Some(AlgorithmId::Rsa2048));
+        assert_eq!(parse_algorithm("Ed25519"), Some(AlgorithmId::Ed25519));
+        assert_eq!(parse_algorithm("ECCP521"), None);
+        assert_eq!(parse_algorithm(""), None);
+    }
+
+    #[test]
+    fn handle_requests() {
+        let server = server();
+        let request = |request: &str| handle_request(&server.yubikey, request);
+
+        let response = request("not json");
+        assert_eq!(error_code(&response), Some(PARSE_ERROR));
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": 1, "method": "format"}"#);
+        assert_eq!(error_code(&response), Some(METHOD_NOT_FOUND));
+        assert_eq!(response["id"], 1);
+
+        let response = request(
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "sign",
+                "params": {"slot": "9c", "algorithm": "ECCP521", "data": "00"}}"#,
+        );
+        assert_eq!(error_code(&response), Some(INVALID_PARAMS));
+
+        let response = request(
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "sign",
+                "params": {"slot": "9c", "algorithm": "ECCP256", "data": "zz"}}"#,
+        );
+        assert_eq!(error_code(&response), Some(INVALID_PARAMS));
+
+        let digest = "00".repeat(32);
+
+        // Not granted by the ACL
+        let response = request(&format!(
+            r#"{{"jsonrpc": "2.0", "id": 4, "method": "sign",
+                "params": {{"slot": "9a", "algorithm": "ECCP256", "data": "{}"}}}}"#,
+            digest
+        ));
+        assert_eq!(error_code(&response), Some(YUBIKEY_ERROR));
+        assert!(response["error"]["data"].is_string());
+
+        let response = request(&format!(
+            r#"{{"jsonrpc": "2.0", "id": 5, "method": "sign",
+                "params": {{"slot": "9c", "algorithm": "ECCP256", "data": "{}"}}}}"#,
+            digest
+        ));
+        assert_eq!(response["id"], 5);
+        assert!(response["result"].as_str().map_or(false, |s| !s.is_empty()));
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": 6, "method": "inventory"}"#);
+        assert_eq!(response["id"], 6);
+        assert_eq!(response["result"]["version"], "5.4.3");
+
+        let slots = response["result"]["slots"].as_array().expect("slots");
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots[0]["slot"], SlotId::Signature.to_string());
+        assert_eq!(slots[0]["algorithm"], "EccP256");
+    }
+
+    #[test]
+    fn request_too_long() {
+        let server = server();
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let mut client =
+            TcpStream::connect(listener.local_addr().expect("address")).expect("connect");
+        let (stream, _) = listener.accept().expect("accept");
+        let handler = thread::spawn(move || handle_client(&server.yubikey, stream));
+
+        client.write_all(&[b'x'; MAX_LINE as usize]).expect("write");
+        let mut response = String::new();
+        client.read_to_string(&mut response).expect("read");
+        handler.join().expect("handler").expect("handle client");
+
+        let response: Value = serde_json::from_str(&response).expect("response");
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn connection_limit() {
+        let limit = Arc::new(ConnectionLimit::new(2));
+        let first = limit.acquire();
+        let _second = limit.acquire();
+
+        let (sender, receiver) = mpsc::channel();
+        let waiting = Arc::clone(&limit);
+        thread::spawn(move || {
+            let _third = waiting.acquire();
+            let _ = sender.send(());
+        });
+
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(first);
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
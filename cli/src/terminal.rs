@@ -9,7 +9,6 @@ use std::{
     sync::Mutex,
 };
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, StandardStreamLock, WriteColor};
-use x509_cert::der::Encode;
 use yubikey::{certificate::Certificate, piv::*, YubiKey};
 
 /// Print a success status message (in green if colors are enabled)
@@ -180,9 +179,8 @@ pub fn print_cert_info(
             return Ok(());
         }
     };
+    let fingerprint = Sha256::digest(cert.as_der());
     let cert = &cert.cert;
-
-    let fingerprint = Sha256::digest(cert.to_der().unwrap());
     let slot_id: u8 = slot.into();
     print_cert_attr(stream, "Slot", format!("{:x}", slot_id))?;
     print_cert_attr(
@@ -196,7 +194,7 @@ pub fn print_cert_info(
     print_cert_attr(
         stream,
         "Fingerprint",
-        &hex::upper::encode_string(&fingerprint),
+        hex::upper::encode_string(&fingerprint),
     )?;
     print_cert_attr(
         stream,
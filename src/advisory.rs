@@ -0,0 +1,115 @@
+//! Yubico security advisories affecting the PIV application.
+//!
+//! This is a small offline database mapping YubiKey firmware versions to the
+//! published security advisories which affect them, so that audit tools can
+//! report the risk associated with a particular device.
+//!
+//! See <https://www.yubico.com/support/security-advisories/> for the full
+//! list of advisories.
+
+use crate::yubikey::Version;
+
+/// Security advisory published by Yubico.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Advisory {
+    /// Yubico Security Advisory identifier, e.g. `YSA-2017-01`
+    pub id: &'static str,
+
+    /// CVE identifier, if one was assigned
+    pub cve: Option<&'static str>,
+
+    /// Short description of the issue
+    pub title: &'static str,
+
+    /// Link to the advisory
+    pub url: &'static str,
+
+    /// First affected firmware version
+    first_affected: [u8; 3],
+
+    /// First firmware version containing a fix
+    first_fixed: [u8; 3],
+}
+
+impl Advisory {
+    /// Does this advisory affect the given firmware version?
+    pub fn affects(&self, version: Version) -> bool {
+        self.first_affected() <= version && version < self.first_fixed()
+    }
+
+    /// Get the first firmware version which is affected by this advisory.
+    pub fn first_affected(&self) -> Version {
+        Version::new(self.first_affected)
+    }
+
+    /// Get the first firmware version which is no longer affected by this advisory.
+    pub fn first_fixed(&self) -> Version {
+        Version::new(self.first_fixed)
+    }
+}
+
+/// Infineon RSA key generation weakness (ROCA).
+pub const YSA_2017_01: Advisory = Advisory {
+    id: "YSA-2017-01",
+    cve: Some("CVE-2017-15361"),
+    title: "Infineon weak RSA key generation (ROCA)",
+    url: "https://www.yubico.com/support/security-advisories/ysa-2017-01/",
+    first_affected: [4, 2, 6],
+    first_fixed: [4, 3, 5],
+};
+
+/// Reduced initial randomness on YubiKey FIPS Series devices.
+pub const YSA_2019_02: Advisory = Advisory {
+    id: "YSA-2019-02",
+    cve: None,
+    title: "Reduced initial randomness on YubiKey FIPS Series",
+    url: "https://www.yubico.com/support/security-advisories/ysa-2019-02/",
+    first_affected: [4, 4, 2],
+    first_fixed: [4, 4, 5],
+};
+
+/// Side channel in the Infineon ECDSA implementation (EUCLEAK).
+pub const YSA_2024_03: Advisory = Advisory {
+    id: "YSA-2024-03",
+    cve: Some("CVE-2024-45678"),
+    title: "Infineon ECDSA side-channel (EUCLEAK)",
+    url: "https://www.yubico.com/support/security-advisories/ysa-2024-03/",
+    first_affected: [5, 0, 0],
+    first_fixed: [5, 7, 0],
+};
+
+/// All known advisories affecting the PIV application.
+pub const ADVISORIES: &[Advisory] = &[YSA_2017_01, YSA_2019_02, YSA_2024_03];
+
+/// Get the advisories which affect the given firmware version.
+pub fn affecting(version: Version) -> impl Iterator<Item = &'static Advisory> {
+    ADVISORIES
+        .iter()
+        .filter(move |advisory| advisory.affects(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn affects_version_range() {
+        assert!(!YSA_2017_01.affects(Version::new([4, 2, 5])));
+        assert!(YSA_2017_01.affects(Version::new([4, 2, 6])));
+        assert!(YSA_2017_01.affects(Version::new([4, 3, 4])));
+        assert!(!YSA_2017_01.affects(Version::new([4, 3, 5])));
+    }
+
+    #[test]
+    fn advisories_affecting() {
+        let ids = |version| {
+            affecting(Version::new(version))
+                .map(|advisory| advisory.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids([4, 4, 3]), ["YSA-2019-02"]);
+        assert_eq!(ids([5, 4, 3]), ["YSA-2024-03"]);
+        assert!(ids([5, 7, 0]).is_empty());
+    }
+}
@@ -30,12 +30,26 @@
 // (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use crate::{transaction::Transaction, Buffer, Result};
+use crate::{device, scp03, scp11, transaction::Transaction, Buffer, Result};
 use log::trace;
 use zeroize::{Zeroize, Zeroizing};
 
 /// Maximum amount of command data that can be included in an APDU
-const APDU_DATA_MAX: usize = 0xFF;
+pub(crate) const APDU_DATA_MAX: usize = 0xFF;
+
+/// Maximum amount of command data that can be included in an extended APDU
+pub(crate) const EXTENDED_APDU_DATA_MAX: usize = 0xFFFF;
+
+/// Instructions of other applications (or of the security domain) known not
+/// to modify the card.
+const OTHER_READS: [u8; 6] = [
+    device::INS_READ_CONFIG,
+    scp03::INS_INITIALIZE_UPDATE,
+    scp03::INS_EXTERNAL_AUTHENTICATE,
+    scp11::INS_GET_DATA,
+    scp11::INS_PERFORM_SECURITY_OPERATION,
+    scp11::INS_INTERNAL_AUTHENTICATE,
+];
 
 /// Application Protocol Data Unit (APDU).
 ///
@@ -56,6 +70,9 @@ pub(crate) struct Apdu {
 
     /// Command data to be sent (`lc` is calculated as `data.len()`)
     data: Vec<u8>,
+
+    /// Use the extended length encoding for `lc` and `le`
+    extended: bool,
 }
 
 impl Apdu {
@@ -67,9 +84,17 @@ impl Apdu {
             p1: 0,
             p2: 0,
             data: vec![],
+            extended: false,
         }
     }
 
+    /// Encode this APDU with extended lengths, allowing up to 65535 bytes of
+    /// command data and 65536 bytes of response data.
+    pub fn extended(&mut self) -> &mut Self {
+        self.extended = true;
+        self
+    }
+
     /// Set this APDU's class
     pub fn cla(&mut self, value: u8) -> &mut Self {
         self.cla = value;
@@ -91,17 +116,23 @@ impl Apdu {
 
     /// Set the command data for this APDU.
     ///
-    /// Panics if the byte slice is more than 255 bytes!
+    /// Panics if the byte slice is more than 255 bytes (or 65535 bytes for
+    /// an [extended](Apdu::extended) APDU)!
     pub fn data(&mut self, bytes: impl AsRef<[u8]>) -> &mut Self {
         assert!(self.data.is_empty(), "APDU command already set!");
 
         let bytes = bytes.as_ref();
+        let max = if self.extended {
+            EXTENDED_APDU_DATA_MAX
+        } else {
+            APDU_DATA_MAX
+        };
 
         assert!(
-            bytes.len() <= APDU_DATA_MAX,
+            bytes.len() <= max,
             "APDU command data too long: {} (max: {})",
             bytes.len(),
-            APDU_DATA_MAX
+            max
         );
 
         self.data.extend_from_slice(bytes);
@@ -118,13 +149,27 @@ impl Apdu {
 
     /// Serialize this APDU as a self-zeroizing byte buffer
     pub fn to_bytes(&self) -> Buffer {
-        let mut bytes = Vec::with_capacity(5 + self.data.len());
+        let mut bytes = Vec::with_capacity(9 + self.data.len());
         bytes.push(self.cla);
         bytes.push(self.ins.code());
         bytes.push(self.p1);
         bytes.push(self.p2);
-        bytes.push(self.data.len() as u8);
-        bytes.extend_from_slice(self.data.as_ref());
+
+        if self.extended {
+            bytes.push(0);
+
+            if !self.data.is_empty() {
+                bytes.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(self.data.as_ref());
+            }
+
+            // Le: as much response data as possible (65536 bytes)
+            bytes.extend_from_slice(&[0, 0]);
+        } else {
+            bytes.push(self.data.len() as u8);
+            bytes.extend_from_slice(self.data.as_ref());
+        }
+
         Zeroizing::new(bytes)
     }
 }
@@ -198,11 +243,52 @@ pub enum Ins {
     /// Get slot metadata
     GetMetadata,
 
+    /// Move key between slots, or delete it
+    MoveKey,
+
     /// Other/unrecognized instruction codes
     Other(u8),
 }
 
 impl Ins {
+    /// Is this one of Yubico's vendor specific instructions, rather than one
+    /// defined by SP 800-73-4?
+    pub fn is_proprietary(self) -> bool {
+        matches!(
+            self,
+            Ins::SetMgmKey
+                | Ins::ImportKey
+                | Ins::GetVersion
+                | Ins::Reset
+                | Ins::SetPinRetries
+                | Ins::Attest
+                | Ins::GetSerial
+                | Ins::GetMetadata
+                | Ins::MoveKey
+        )
+    }
+
+    /// Does this instruction modify the card (its keys, data objects or
+    /// credentials)?
+    ///
+    /// Unrecognized instructions (e.g. of other applications, such as OATH
+    /// `PUT`) are assumed to, unless they're known to only read the card.
+    pub fn modifies_card(self) -> bool {
+        match self {
+            Ins::ChangeReference
+            | Ins::ResetRetry
+            | Ins::GenerateAsymmetric
+            | Ins::PutData
+            | Ins::SetMgmKey
+            | Ins::ImportKey
+            | Ins::Reset
+            | Ins::SetPinRetries
+            | Ins::MoveKey => true,
+            Ins::Other(code) => !OTHER_READS.contains(&code),
+            _ => false,
+        }
+    }
+
     /// Get the code that corresponds to this instruction
     pub fn code(self) -> u8 {
         match self {
@@ -223,6 +309,7 @@ impl Ins {
             Ins::Attest => 0xf9,
             Ins::GetSerial => 0xf8,
             Ins::GetMetadata => 0xf7,
+            Ins::MoveKey => 0xf6,
             Ins::Other(code) => code,
         }
     }
@@ -248,6 +335,7 @@ impl From<u8> for Ins {
             0xf9 => Ins::Attest,
             0xf8 => Ins::GetSerial,
             0xf7 => Ins::GetMetadata,
+            0xf6 => Ins::MoveKey,
             code => Ins::Other(code),
         }
     }
@@ -484,7 +572,53 @@ impl From<StatusWords> for u16 {
 
 #[cfg(test)]
 mod tests {
-    use super::StatusWords;
+    use super::{Apdu, Ins, StatusWords};
+
+    #[test]
+    fn extended_encoding() {
+        assert_eq!(
+            &Apdu::new(0xdb).params(0x3f, 0xff).data([1, 2]).to_bytes()[..],
+            [0x00, 0xdb, 0x3f, 0xff, 0x02, 1, 2]
+        );
+        assert_eq!(
+            &Apdu::new(0xdb)
+                .extended()
+                .params(0x3f, 0xff)
+                .data([1, 2])
+                .to_bytes()[..],
+            [0x00, 0xdb, 0x3f, 0xff, 0x00, 0x00, 0x02, 1, 2, 0x00, 0x00]
+        );
+        assert_eq!(
+            &Apdu::new(0xcb).extended().to_bytes()[..],
+            [0x00, 0xcb, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        let data = vec![0xaa; 1000];
+        let apdu = Apdu::new(0xdb).extended().data(&data).to_bytes();
+        assert_eq!(&apdu[4..7], [0x00, 0x03, 0xe8]);
+        assert_eq!(apdu.len(), 4 + 3 + 1000 + 2);
+    }
+
+    #[test]
+    fn proprietary_instructions() {
+        assert!(Ins::from(0xf7).is_proprietary());
+        assert!(Ins::from(0xff).is_proprietary());
+        assert!(!Ins::from(0x87).is_proprietary());
+        assert!(!Ins::from(0xcb).is_proprietary());
+    }
+
+    #[test]
+    fn modifying_instructions() {
+        assert!(Ins::from(0xdb).modifies_card());
+        assert!(Ins::from(0x47).modifies_card());
+        assert!(Ins::from(0xf6).modifies_card());
+        assert!(!Ins::from(0xcb).modifies_card());
+        assert!(!Ins::from(0x87).modifies_card());
+
+        // OATH PUT, and reading the Management application configuration
+        assert!(Ins::from(0x01).modifies_card());
+        assert!(!Ins::from(0x1d).modifies_card());
+    }
 
     #[test]
     fn status_words_round_trip() {
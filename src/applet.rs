@@ -0,0 +1,250 @@
+//! Discovery of the applets installed on a card.
+//!
+//! Besides YubiKeys, this crate can talk to other smart cards (e.g. custom
+//! JavaCards) running a Yubico-compatible PIV applet. Such cards may host
+//! several PIV instances, or applets this crate doesn't know about.
+//!
+//! Applets are discovered by probing: each candidate AID is selected, and
+//! answers it if installed. [`piv_instances`] lists every applet answering the
+//! PIV RID (`A0 00 00 03 08`), and [`selected_piv_applet`] reports the one
+//! this crate operates on, which is the first of them.
+//!
+//! Selecting another applet ends the current PIN session, as when calling
+//! [`YubiKey::clear_auth_state`] (although the cached PIN isn't forgotten).
+
+use crate::{
+    apdu::{Apdu, Ins},
+    error::Result,
+    mgm, oath, otp, piv,
+    serialization::Tlv,
+    transaction::Transaction,
+    yubikey::YubiKey,
+};
+use log::debug;
+use std::fmt;
+
+/// OpenPGP card application ID.
+const OPENPGP_APPLET_ID: &[u8] = &[0xd2, 0x76, 0x00, 0x01, 0x24, 0x01];
+
+/// FIDO U2F application ID.
+const FIDO_APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x06, 0x47, 0x2f, 0x00, 0x01];
+
+/// Well-known applets, by name and AID (or AID prefix).
+pub const KNOWN_APPLETS: &[(&str, &[u8])] = &[
+    (piv::APPLET_NAME, piv::APPLET_ID),
+    (mgm::APPLET_NAME, mgm::APPLET_ID),
+    (otp::APPLET_NAME, otp::APPLET_ID),
+    ("OpenPGP", OPENPGP_APPLET_ID),
+    (oath::APPLET_NAME, oath::APPLET_ID),
+    ("FIDO", FIDO_APPLET_ID),
+];
+
+/// Maximum number of PIV instances enumerated.
+const MAX_INSTANCES: usize = 16;
+
+/// SELECT P2: return the FCI of the first matching applet.
+const SELECT_FIRST: u8 = 0x00;
+
+/// SELECT P2: return the FCI of the next matching applet.
+const SELECT_NEXT: u8 = 0x02;
+
+/// Application property template tag.
+const TAG_APT: u16 = 0x61;
+
+/// Application identifier tag.
+const TAG_AID: u16 = 0x4f;
+
+/// Length of a registered application provider identifier (RID).
+const RID_LEN: usize = 5;
+
+/// Applet installed on a card.
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct Applet {
+    /// Application identifier (AID) of the applet
+    aid: Vec<u8>,
+}
+
+impl Applet {
+    /// Get the application identifier (AID) of this applet.
+    pub fn aid(&self) -> &[u8] {
+        &self.aid
+    }
+
+    /// Get the name of this applet, if it's one of the [`KNOWN_APPLETS`].
+    pub fn name(&self) -> Option<&'static str> {
+        KNOWN_APPLETS
+            .iter()
+            .find(|(_, aid)| self.aid.starts_with(aid))
+            .map(|&(name, _)| name)
+    }
+
+    /// Is this a PIV applet?
+    pub fn is_piv(&self) -> bool {
+        self.aid.starts_with(piv::APPLET_ID)
+    }
+}
+
+impl fmt::Debug for Applet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Applet({})", self)
+    }
+}
+
+impl fmt::Display for Applet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.aid {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        if let Some(name) = self.name() {
+            write!(f, " ({})", name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Probe which of the given AIDs (or AID prefixes) are installed on the card.
+///
+/// Returns the applets answering them, in the same order. Pass
+/// [`KNOWN_APPLETS`] AIDs to look for well-known applets, or the AIDs of
+/// custom applets.
+pub fn probe(yubikey: &mut YubiKey, candidates: &[&[u8]]) -> Result<Vec<Applet>> {
+    with_probing(yubikey, |txn| {
+        let mut applets = vec![];
+
+        for candidate in candidates {
+            if let Some(applet) = select(txn, candidate, SELECT_FIRST)? {
+                applets.push(applet);
+            }
+        }
+
+        Ok(applets)
+    })
+}
+
+/// List the PIV applets installed on the card.
+///
+/// Cards may host several instances of PIV-compatible applets sharing the
+/// PIV RID; they are listed in the order the card selects them.
+pub fn piv_instances(yubikey: &mut YubiKey) -> Result<Vec<Applet>> {
+    with_probing(yubikey, |txn| {
+        let mut instances: Vec<Applet> = vec![];
+        let mut p2 = SELECT_FIRST;
+
+        while instances.len() < MAX_INSTANCES {
+            match select(txn, piv::APPLET_ID, p2)? {
+                // Cards not supporting "next occurrence" select the first again
+                Some(applet) if !instances.contains(&applet) => instances.push(applet),
+                _ => break,
+            }
+
+            p2 = SELECT_NEXT;
+        }
+
+        Ok(instances)
+    })
+}
+
+/// Get the PIV applet this crate operates on.
+///
+/// This is the first PIV applet selected by the card: see [`piv_instances`].
+pub fn selected_piv_applet(yubikey: &mut YubiKey) -> Result<Option<Applet>> {
+    with_probing(yubikey, |txn| select(txn, piv::APPLET_ID, SELECT_FIRST))
+}
+
+/// Run the given probe, then reselect the PIV application.
+fn with_probing<T>(
+    yubikey: &mut YubiKey,
+    probe: impl FnOnce(&Transaction<'_>) -> Result<T>,
+) -> Result<T> {
+    // Selecting another applet clears the PIV applet's security status
+    yubikey.pin_verified = false;
+    let txn = yubikey.begin_transaction()?;
+
+    let result = probe(&txn);
+    txn.select_application()?;
+    result
+}
+
+/// Select the applet with the given AID, if installed.
+fn select(txn: &Transaction<'_>, aid: &[u8], p2: u8) -> Result<Option<Applet>> {
+    let response = Apdu::new(Ins::SelectApplication)
+        .params(0x04, p2)
+        .data(aid)
+        .transmit(txn, 255)?;
+
+    if !response.is_success() {
+        debug!(
+            "no applet selected for AID {:02x?}: {:04x}",
+            aid,
+            response.status_words().code()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(Applet {
+        aid: selected_aid(response.data(), aid),
+    }))
+}
+
+/// Get the AID of the applet selected with the given AID, from the
+/// application property template in its response if there is one.
+///
+/// PIV applets only report the proprietary identifier extension (PIX) of
+/// their AID there, which follows the RID they were selected with.
+fn selected_aid(response: &[u8], requested: &[u8]) -> Vec<u8> {
+    let reported = Tlv::parse(response)
+        .ok()
+        .filter(|(_, apt)| apt.tag == TAG_APT)
+        .and_then(|(_, apt)| {
+            let mut rest = apt.value;
+
+            while let Ok((next, tlv)) = Tlv::parse(rest) {
+                if tlv.tag == TAG_AID {
+                    return Some(tlv.value);
+                }
+                rest = next;
+            }
+
+            None
+        });
+
+    match reported {
+        Some(aid)
+            if aid.len() > RID_LEN
+                && aid.starts_with(&requested[..RID_LEN.min(requested.len())]) =>
+        {
+            aid.to_vec()
+        }
+        Some(pix) if requested.len() >= RID_LEN => [&requested[..RID_LEN], pix].concat(),
+        _ => requested.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_selected_aid() {
+        // Response of the YubiKey PIV applet, reporting its PIX
+        let response = [
+            0x61, 0x11, 0x4f, 0x06, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x79, 0x07, 0x4f, 0x05,
+            0xa0, 0x00, 0x00, 0x03, 0x08,
+        ];
+        let aid = selected_aid(&response, piv::APPLET_ID);
+        assert_eq!(
+            aid,
+            [0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00]
+        );
+
+        let applet = Applet { aid };
+        assert!(applet.is_piv());
+        assert_eq!(applet.to_string(), "A000000308000010000100 (PIV)");
+
+        // Responses without an application property template
+        assert_eq!(selected_aid(b"5.4.3", mgm::APPLET_ID), mgm::APPLET_ID);
+        assert_eq!(selected_aid(&[], oath::APPLET_ID), oath::APPLET_ID);
+    }
+}
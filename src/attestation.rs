@@ -0,0 +1,512 @@
+//! Verification of attestations proving keys were generated on a YubiKey.
+//!
+//! The YubiKey only attests keys which were generated on it, never imported
+//! ones. An attestation certificate for a slot is signed by the intermediate
+//! attestation certificate stored in [`SlotId::Attestation`], and records the
+//! PIN and touch policies of the attested key.
+//!
+//! [`AttestationRequirements::verify`] checks an attestation against the
+//! public key it's expected to cover, so that certificates are only issued
+//! for keys which are actually hardware-backed: see
+//! [`Certificate::generate_self_signed_attested`].
+//!
+//! The intermediate attestation certificate must be signed by one of the
+//! process-wide trusted roots, if any are configured with
+//! [`set_trusted_roots`] or [`add_trusted_root`] (e.g. Yubico's PIV
+//! attestation root CA, or the CA of YubiKeys re-keyed for enterprise
+//! attestation). Otherwise, it's trusted as read from the YubiKey, so to rule
+//! out a tampered device, configure a trusted root.
+//!
+//! # Offline verification
+//!
+//! A [`VerificationBundle`] holds trusted root certificates and the
+//! intermediate attestation certificates of YubiKeys, read once per device
+//! and cached. Exported with [`VerificationBundle::to_der`], it lets
+//! attestations be verified with [`AttestationRequirements::verify_offline`]
+//! where neither the YubiKeys nor the network are available, e.g. in an
+//! air-gapped CA.
+//!
+//! No root certificates are built in: load Yubico's PIV attestation root CA
+//! (published at <https://developers.yubico.com/PIV/Introduction/PIV_attestation.html>)
+//! with [`add_trusted_root`] or [`VerificationBundle::new`].
+
+use crate::{
+    capability::Capability,
+    error::{Error, Result},
+    piv::{self, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    yubikey::{Serial, YubiKey},
+    Certificate,
+};
+use log::{debug, error};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+};
+use x509_cert::{
+    der::{asn1::AnyRef, oid::ObjectIdentifier, Decode, Encode},
+    spki::SubjectPublicKeyInfoRef,
+};
+
+/// Yubico's PIN and touch policy extension, present in attestation
+/// certificates.
+const YUBICO_POLICY_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.8");
+
+/// Root certificates trusted to sign intermediate attestation certificates.
+static TRUSTED_ROOTS: Mutex<Vec<Certificate>> = Mutex::new(Vec::new());
+
+/// Get the process-wide trusted root certificates.
+pub fn trusted_roots() -> Vec<Certificate> {
+    TRUSTED_ROOTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Replace the process-wide trusted root certificates, which intermediate
+/// attestation certificates must be signed by.
+///
+/// With no trusted roots (the default), intermediate attestation
+/// certificates are trusted as read from the YubiKey.
+pub fn set_trusted_roots(roots: impl IntoIterator<Item = Certificate>) {
+    *TRUSTED_ROOTS.lock().unwrap_or_else(PoisonError::into_inner) = roots.into_iter().collect();
+}
+
+/// Add a process-wide trusted root certificate: see [`set_trusted_roots`].
+pub fn add_trusted_root(root: Certificate) {
+    TRUSTED_ROOTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(root);
+}
+
+/// Requirements an attestation must meet.
+///
+/// By default, any attestation of the expected key is accepted, regardless
+/// of its policies.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttestationRequirements {
+    /// PIN policy the attested key must have
+    pin_policy: Option<PinPolicy>,
+
+    /// Touch policy the attested key must have
+    touch_policy: Option<TouchPolicy>,
+}
+
+impl AttestationRequirements {
+    /// Require the attested key to have the given PIN policy.
+    pub fn pin_policy(mut self, pin_policy: PinPolicy) -> Self {
+        self.pin_policy = Some(pin_policy);
+        self
+    }
+
+    /// Require the attested key to have the given touch policy.
+    pub fn touch_policy(mut self, touch_policy: TouchPolicy) -> Self {
+        self.touch_policy = Some(touch_policy);
+        self
+    }
+
+    /// Attest the key in the given slot, and check that the attestation
+    /// covers `public_key` and meets these requirements.
+    ///
+    /// If any are configured, the intermediate attestation certificate must
+    /// be signed by one of the [trusted roots](trusted_roots).
+    ///
+    /// Returns the attestation certificate, or [`Error::AttestationError`] if
+    /// the key can't be attested (e.g. because it was imported) or the
+    /// attestation doesn't meet the requirements.
+    pub fn verify(
+        &self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<Certificate> {
+        if !yubikey.supports(Capability::Attestation) {
+            error!("YubiKey firmware doesn't support attestation");
+            return Err(Error::NotSupported);
+        }
+
+        let attestation = attest(yubikey, slot)?;
+        let intermediate = piv::attestation_certificate(yubikey)?;
+
+        let roots = VerificationBundle::with_trusted_roots();
+        if !roots.roots.is_empty() {
+            roots.check_chain(&intermediate)?;
+        }
+
+        self.verify_signed(&attestation, &intermediate, public_key)?;
+        Ok(attestation)
+    }
+
+    /// Attest the key in the given slot like [`AttestationRequirements::verify`],
+    /// using the intermediate attestation certificate cached in the given
+    /// bundle (see [`VerificationBundle::intermediate`]), which must chain to
+    /// one of its roots if it has any.
+    pub fn verify_with_bundle(
+        &self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+        bundle: &mut VerificationBundle,
+    ) -> Result<Certificate> {
+        if !yubikey.supports(Capability::Attestation) {
+            error!("YubiKey firmware doesn't support attestation");
+            return Err(Error::NotSupported);
+        }
+
+        let attestation = attest(yubikey, slot)?;
+        let intermediate = bundle.intermediate(yubikey)?;
+
+        self.verify_signed(&attestation, intermediate, public_key)?;
+        Ok(attestation)
+    }
+
+    /// Check that the given attestation certificate, produced by a YubiKey
+    /// whose intermediate attestation certificate is in the given bundle,
+    /// covers `public_key` and meets these requirements, without access to
+    /// the YubiKey.
+    ///
+    /// The intermediate attestation certificate must chain to one of the
+    /// bundle's roots.
+    pub fn verify_offline(
+        &self,
+        bundle: &VerificationBundle,
+        attestation: &Certificate,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<()> {
+        if bundle.roots.is_empty() {
+            error!("verification bundle has no root certificates");
+            return Err(Error::AttestationError);
+        }
+
+        let intermediate = bundle
+            .intermediates
+            .iter()
+            .find(|intermediate| {
+                attestation
+                    .verify_with_key(intermediate.subject_pki())
+                    .is_ok()
+            })
+            .ok_or_else(|| {
+                error!("attestation isn't signed by an intermediate in the bundle");
+                Error::AttestationError
+            })?;
+
+        bundle.check_chain(intermediate)?;
+        self.verify_signed(attestation, intermediate, public_key)
+    }
+
+    /// Check the attestation is signed by the given intermediate attestation
+    /// certificate, covers `public_key`, and meets these requirements.
+    pub(crate) fn verify_signed(
+        &self,
+        attestation: &Certificate,
+        intermediate: &Certificate,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<()> {
+        if attestation
+            .verify_with_key(intermediate.subject_pki())
+            .is_err()
+        {
+            error!("attestation has an invalid signature");
+            return Err(Error::AttestationError);
+        }
+
+        if attestation.subject_pki() != public_key {
+            error!("attestation is for a different key");
+            return Err(Error::AttestationError);
+        }
+
+        self.check(attestation)
+    }
+
+    /// Check the policies recorded in the given attestation certificate meet
+    /// these requirements.
+    pub fn check(&self, attestation: &Certificate) -> Result<()> {
+        let (pin_policy, touch_policy) = policies(attestation)?;
+
+        if self
+            .pin_policy
+            .map_or(false, |required| required != pin_policy)
+        {
+            error!(
+                "attested key has PIN policy {:?} (required {:?})",
+                pin_policy, self.pin_policy
+            );
+            return Err(Error::AttestationError);
+        }
+
+        if self
+            .touch_policy
+            .map_or(false, |required| required != touch_policy)
+        {
+            error!(
+                "attested key has touch policy {:?} (required {:?})",
+                touch_policy, self.touch_policy
+            );
+            return Err(Error::AttestationError);
+        }
+
+        Ok(())
+    }
+}
+
+/// Trusted root certificates and cached intermediate attestation
+/// certificates, to verify attestations offline: see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct VerificationBundle {
+    /// Trusted root certificates
+    roots: Vec<Certificate>,
+
+    /// Intermediate attestation certificates of YubiKeys
+    intermediates: Vec<Certificate>,
+
+    /// Index in `intermediates` of the certificate of each YubiKey read
+    devices: BTreeMap<Serial, usize>,
+}
+
+impl VerificationBundle {
+    /// Create a bundle trusting the given root certificates (e.g. Yubico's
+    /// PIV attestation root CA).
+    pub fn new(roots: impl IntoIterator<Item = Certificate>) -> Self {
+        Self {
+            roots: roots.into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Create a bundle trusting the process-wide [trusted roots](trusted_roots).
+    pub fn with_trusted_roots() -> Self {
+        Self::new(trusted_roots())
+    }
+
+    /// Get the trusted root certificates.
+    pub fn roots(&self) -> &[Certificate] {
+        &self.roots
+    }
+
+    /// Get the intermediate attestation certificates in this bundle.
+    pub fn intermediates(&self) -> &[Certificate] {
+        &self.intermediates
+    }
+
+    /// Get the intermediate attestation certificate of the given YubiKey,
+    /// reading it from [`SlotId::Attestation`] the first time and caching it.
+    ///
+    /// If this bundle has root certificates, the intermediate attestation
+    /// certificate must chain to one of them.
+    pub fn intermediate(&mut self, yubikey: &mut YubiKey) -> Result<&Certificate> {
+        let index = match self.devices.get(&yubikey.serial()) {
+            Some(&index) => index,
+            None => {
+                let intermediate = piv::attestation_certificate(yubikey)?;
+
+                if !self.roots.is_empty() {
+                    self.check_chain(&intermediate)?;
+                }
+
+                let index = self.add_intermediate(intermediate);
+                self.devices.insert(yubikey.serial(), index);
+                index
+            }
+        };
+
+        Ok(&self.intermediates[index])
+    }
+
+    /// Encode this bundle as a DER `SEQUENCE OF Certificate`, holding the
+    /// root certificates and then the intermediate attestation certificates.
+    pub fn to_der(&self) -> Result<Vec<u8>> {
+        let certs = self
+            .roots
+            .iter()
+            .chain(&self.intermediates)
+            .map(|cert| AnyRef::from_der(cert.as_der()))
+            .collect::<core::result::Result<Vec<_>, _>>()?;
+
+        Ok(certs.to_der()?)
+    }
+
+    /// Parse a bundle encoded with [`VerificationBundle::to_der`].
+    ///
+    /// Self-signed certificates are trusted as roots, and the others are
+    /// taken as intermediate attestation certificates. The YubiKeys they
+    /// belong to aren't recorded, so they're read again by
+    /// [`VerificationBundle::intermediate`].
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let mut bundle = Self::default();
+
+        for cert in Vec::<AnyRef<'_>>::from_der(der)? {
+            let cert = Certificate::from_bytes(cert.to_der()?)?;
+
+            if cert.verify_self_signed().is_ok() {
+                bundle.roots.push(cert);
+            } else {
+                bundle.add_intermediate(cert);
+            }
+        }
+
+        Ok(bundle)
+    }
+
+    /// Add an intermediate attestation certificate, unless it's already
+    /// present, returning its index.
+    fn add_intermediate(&mut self, intermediate: Certificate) -> usize {
+        match self
+            .intermediates
+            .iter()
+            .position(|cert| cert.as_der() == intermediate.as_der())
+        {
+            Some(index) => index,
+            None => {
+                self.intermediates.push(intermediate);
+                self.intermediates.len() - 1
+            }
+        }
+    }
+
+    /// Check the given intermediate attestation certificate is signed by one
+    /// of the root certificates.
+    pub(crate) fn check_chain(&self, intermediate: &Certificate) -> Result<()> {
+        match self
+            .roots
+            .iter()
+            .find(|root| intermediate.verify_with_key(root.subject_pki()).is_ok())
+        {
+            Some(root) => {
+                debug!("attestation intermediate chains to '{}'", root.subject());
+                Ok(())
+            }
+            None => {
+                error!(
+                    "attestation intermediate '{}' isn't signed by a trusted root",
+                    intermediate.subject()
+                );
+                Err(Error::AttestationError)
+            }
+        }
+    }
+}
+
+/// Attest the key in the given slot.
+fn attest(yubikey: &mut YubiKey, slot: SlotId) -> Result<Certificate> {
+    piv::attest(yubikey, slot).map_err(|e| {
+        error!("could not attest key in slot {}: {}", slot, e);
+        Error::AttestationError
+    })
+}
+
+/// Get the PIN and touch policies recorded in the given attestation
+/// certificate.
+pub fn policies(attestation: &Certificate) -> Result<(PinPolicy, TouchPolicy)> {
+    let extension = attestation
+        .cert
+        .tbs_certificate
+        .extensions
+        .iter()
+        .flatten()
+        .find(|extension| extension.extn_id == YUBICO_POLICY_OID)
+        .ok_or_else(|| {
+            error!("attestation certificate has no policy extension");
+            Error::AttestationError
+        })?;
+
+    match extension.extn_value.as_bytes() {
+        [pin_policy, touch_policy, ..] => Ok((
+            PinPolicy::try_from(*pin_policy)?,
+            TouchPolicy::try_from(*touch_policy)?,
+        )),
+        _ => {
+            error!("invalid policy extension in attestation certificate");
+            Err(Error::AttestationError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::{ecdsa::DerSignature, pkcs8::EncodePublicKey};
+    use std::{str::FromStr, time::Duration};
+    use x509_cert::{
+        builder::{Builder, CertificateBuilder, Profile},
+        name::Name,
+        serial_number::SerialNumber,
+        spki::SubjectPublicKeyInfoOwned,
+        time::Validity,
+    };
+
+    fn issue(
+        profile: Profile,
+        subject: &str,
+        key: &p256::ecdsa::SigningKey,
+        issuer_key: &p256::ecdsa::SigningKey,
+    ) -> Certificate {
+        let public_key = key.verifying_key().to_public_key_der().expect("public key");
+        let cert = CertificateBuilder::new(
+            profile,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str(subject).expect("name"),
+            SubjectPublicKeyInfoOwned::from_der(public_key.as_bytes()).expect("SPKI"),
+            issuer_key,
+        )
+        .expect("builder")
+        .build::<DerSignature>()
+        .expect("build");
+
+        Certificate::from_bytes(cert.to_der().expect("encode")).expect("decode")
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let root_key = p256::ecdsa::SigningKey::random(&mut rand_core::OsRng);
+        let intermediate_key = p256::ecdsa::SigningKey::random(&mut rand_core::OsRng);
+
+        let root = issue(Profile::Root, "CN=Root", &root_key, &root_key);
+        let intermediate = issue(
+            Profile::SubCA {
+                issuer: Name::from_str("CN=Root").expect("name"),
+                path_len_constraint: None,
+            },
+            "CN=Intermediate",
+            &intermediate_key,
+            &root_key,
+        );
+        let untrusted = issue(
+            Profile::Root,
+            "CN=Other",
+            &intermediate_key,
+            &intermediate_key,
+        );
+
+        set_trusted_roots([root.clone()]);
+        add_trusted_root(untrusted.clone());
+        assert_eq!(VerificationBundle::with_trusted_roots().roots().len(), 2);
+        set_trusted_roots([]);
+        assert!(trusted_roots().is_empty());
+
+        let mut bundle = VerificationBundle::new([root]);
+        assert_eq!(bundle.add_intermediate(intermediate.clone()), 0);
+        assert_eq!(bundle.add_intermediate(intermediate.clone()), 0);
+        assert!(bundle.check_chain(&intermediate).is_ok());
+        assert_eq!(bundle.check_chain(&untrusted), Err(Error::AttestationError));
+
+        let bundle =
+            VerificationBundle::from_der(&bundle.to_der().expect("encode")).expect("decode");
+        assert_eq!(bundle.roots().len(), 1);
+        assert_eq!(bundle.intermediates().len(), 1);
+        assert_eq!(bundle.intermediates()[0].as_der(), intermediate.as_der());
+
+        // Without roots, nothing can be verified offline
+        assert_eq!(
+            AttestationRequirements::default().verify_offline(
+                &VerificationBundle::default(),
+                &intermediate,
+                intermediate.subject_pki()
+            ),
+            Err(Error::AttestationError)
+        );
+    }
+}
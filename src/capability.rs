@@ -0,0 +1,73 @@
+//! Detection of the features supported by a YubiKey's firmware.
+
+use crate::yubikey::Version;
+
+/// Features of the PIV application which are only available on some
+/// YubiKey firmware versions.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Capability {
+    /// PIN and touch policies for generated and imported keys.
+    PinTouchPolicy,
+
+    /// [`TouchPolicy::Cached`](crate::TouchPolicy::Cached) for generated and
+    /// imported keys.
+    CachedTouch,
+
+    /// Attestation of keys generated on the YubiKey.
+    Attestation,
+
+    /// Reading the device serial number through the PIV application.
+    Serial,
+
+    /// Reading metadata about the keys in each slot.
+    Metadata,
+
+    /// AES management keys.
+    AesManagementKey,
+
+    /// Requesting a random challenge for the management key, which can be
+    /// used as a source of randomness.
+    RandomChallenge,
+
+    /// Extended-length APDUs, carrying up to 65535 bytes of command data
+    /// and 65536 bytes of response data, rather than chaining short APDUs.
+    ExtendedApdu,
+
+    /// 3072 and 4096-bit RSA keys.
+    LargeRsaKeys,
+
+    /// Ed25519 and X25519 keys.
+    Curve25519Keys,
+
+    /// Moving keys between slots.
+    MoveKey,
+
+    /// Deleting the key in a slot.
+    DeleteKey,
+}
+
+impl Capability {
+    /// Get the earliest firmware version which supports this capability.
+    pub fn min_version(self) -> Version {
+        Version::new(match self {
+            Capability::PinTouchPolicy => [4, 0, 0],
+            Capability::CachedTouch => [4, 3, 0],
+            Capability::Attestation => [4, 3, 0],
+            Capability::Serial => [5, 0, 0],
+            Capability::Metadata => [5, 3, 0],
+            Capability::AesManagementKey => [5, 4, 0],
+            Capability::RandomChallenge => [4, 0, 0],
+            Capability::ExtendedApdu => [4, 0, 0],
+            Capability::LargeRsaKeys => [5, 7, 0],
+            Capability::Curve25519Keys => [5, 7, 0],
+            Capability::MoveKey => [5, 7, 0],
+            Capability::DeleteKey => [5, 7, 0],
+        })
+    }
+
+    /// Is this capability supported by the given firmware version?
+    pub fn is_supported_by(self, version: Version) -> bool {
+        version >= self.min_version()
+    }
+}
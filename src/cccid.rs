@@ -89,8 +89,8 @@ impl CccId {
 
     /// Get Cardholder Capability Container (CCC) ID
     pub fn get(yubikey: &mut YubiKey) -> Result<Self> {
-        let txn = yubikey.begin_transaction()?;
-        let response = txn.fetch_object(OBJ_CAPABILITY)?;
+        let response = yubikey
+            .idempotent(|yubikey| yubikey.begin_transaction()?.fetch_object(OBJ_CAPABILITY))?;
         Ok(response[..Self::BYTE_SIZE].try_into().map(Self)?)
     }
 
@@ -0,0 +1,510 @@
+//! Transport speaking USB CCID directly to a YubiKey, without PC/SC.
+//!
+//! Many container and embedded deployments can't run `pcscd`. There, a
+//! [`CcidTransport`] exchanges APDUs with the YubiKey's CCID interface itself,
+//! framing them in the CCID messages a PC/SC daemon would send (YubiKeys use
+//! short APDU level exchanges, so no T=1 framing is needed).
+//!
+//! USB access goes through a [`CcidDevice`]. [`UsbDevice`] implements it
+//! with [`nusb`](https://docs.rs/nusb), claiming the CCID interface of a
+//! YubiKey (interface class `0x0b`) and transferring data over its bulk
+//! endpoints; other USB libraries (e.g. `rusb`) can be used by implementing
+//! [`CcidDevice`] on top of them. The transport is then passed to
+//! [`YubiKey::open_with_transport`](crate::YubiKey::open_with_transport):
+//!
+//! ```no_run
+//! use yubikey::{
+//!     ccid::{CcidTransport, UsbDevice},
+//!     YubiKey,
+//! };
+//!
+//! let transport = CcidTransport::new(UsbDevice::open()?)?;
+//! let mut yubikey = YubiKey::open_with_transport(transport, "usb")?;
+//! yubikey.verify_pin(b"123456")?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! A CCID interface can only be claimed by one process at a time, so this
+//! fails while `pcscd` (or another application) holds the YubiKey.
+
+use crate::{
+    error::{Error, Result},
+    transport::{Disposition, Exchange, Transport},
+};
+use futures_lite::future::block_on;
+use log::{debug, error};
+use nusb::{
+    transfer::{Direction, EndpointType, RequestBuffer},
+    DeviceInfo, Interface,
+};
+use std::{cell::RefCell, fmt, io};
+
+/// USB vendor ID of Yubico
+const YUBICO_VENDOR_ID: u16 = 0x1050;
+
+/// USB interface class of CCID (smart card) interfaces
+const CCID_INTERFACE_CLASS: u8 = 0x0b;
+
+/// `PC_to_RDR_IccPowerOn` message type
+const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+
+/// `PC_to_RDR_IccPowerOff` message type
+const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+
+/// `PC_to_RDR_XfrBlock` message type
+const PC_TO_RDR_XFR_BLOCK: u8 = 0x6f;
+
+/// `RDR_to_PC_DataBlock` message type
+const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+
+/// `RDR_to_PC_SlotStatus` message type
+const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+
+/// Length of the header of CCID messages
+const HEADER_LEN: usize = 10;
+
+/// Command status bits of `bStatus`: the command failed
+const STATUS_FAILED: u8 = 0x40;
+
+/// Command status bits of `bStatus`: more time is requested (e.g. while the
+/// YubiKey waits for a touch)
+const STATUS_TIME_EXTENSION: u8 = 0x80;
+
+/// Largest message read from the YubiKey
+const MAX_MESSAGE_LEN: usize = HEADER_LEN + 65_538;
+
+/// Bulk endpoints of the CCID interface of a YubiKey.
+pub trait CcidDevice: Send {
+    /// Write a message to the bulk OUT endpoint.
+    fn write_bulk(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Read from the bulk IN endpoint into `buf`, returning the number of
+    /// bytes read. A message may be split across several reads.
+    fn read_bulk(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// CCID interface of a YubiKey claimed with `nusb`.
+pub struct UsbDevice {
+    interface: Interface,
+
+    /// Address of the bulk IN endpoint
+    bulk_in: u8,
+
+    /// Address of the bulk OUT endpoint
+    bulk_out: u8,
+
+    /// Maximum packet size of the bulk IN endpoint
+    max_packet_size: usize,
+}
+
+impl UsbDevice {
+    /// List the YubiKeys plugged in with their CCID interface enabled.
+    pub fn list() -> Result<Vec<DeviceInfo>> {
+        let devices = nusb::list_devices().map_err(io_error)?;
+
+        Ok(devices
+            .filter(|info| {
+                info.vendor_id() == YUBICO_VENDOR_ID
+                    && info
+                        .interfaces()
+                        .any(|interface| interface.class() == CCID_INTERFACE_CLASS)
+            })
+            .collect())
+    }
+
+    /// Claim the CCID interface of the first YubiKey plugged in.
+    ///
+    /// Returns [`Error::NotFound`] if no YubiKey with its CCID interface
+    /// enabled is plugged in.
+    pub fn open() -> Result<Self> {
+        match Self::list()?.first() {
+            Some(info) => Self::from_info(info),
+            None => {
+                error!("no YubiKey with a CCID interface found");
+                Err(Error::NotFound)
+            }
+        }
+    }
+
+    /// Claim the CCID interface of the given USB device, as listed by
+    /// [`UsbDevice::list`].
+    pub fn from_info(info: &DeviceInfo) -> Result<Self> {
+        let number = info
+            .interfaces()
+            .find(|interface| interface.class() == CCID_INTERFACE_CLASS)
+            .map(|interface| interface.interface_number())
+            .ok_or_else(|| {
+                error!("USB device has no CCID interface");
+                Error::NotFound
+            })?;
+
+        let interface = info
+            .open()
+            .and_then(|device| device.claim_interface(number))
+            .map_err(io_error)?;
+
+        let mut bulk_in = None;
+        let mut bulk_out = None;
+
+        if let Some(alt_setting) = interface.descriptors().next() {
+            for endpoint in alt_setting.endpoints() {
+                if endpoint.transfer_type() != EndpointType::Bulk {
+                    continue;
+                }
+
+                match endpoint.direction() {
+                    Direction::In => {
+                        bulk_in = Some((endpoint.address(), endpoint.max_packet_size()))
+                    }
+                    Direction::Out => bulk_out = Some(endpoint.address()),
+                }
+            }
+        }
+
+        match (bulk_in, bulk_out) {
+            (Some((bulk_in, max_packet_size)), Some(bulk_out)) if max_packet_size > 0 => Ok(Self {
+                interface,
+                bulk_in,
+                bulk_out,
+                max_packet_size,
+            }),
+            _ => {
+                error!("CCID interface is missing its bulk endpoints");
+                Err(Error::GenericError)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UsbDevice")
+            .field("interface", &self.interface.interface_number())
+            .field("bulk_in", &self.bulk_in)
+            .field("bulk_out", &self.bulk_out)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CcidDevice for UsbDevice {
+    fn write_bulk(&mut self, data: &[u8]) -> io::Result<()> {
+        block_on(self.interface.bulk_out(self.bulk_out, data.to_vec()))
+            .into_result()
+            .map(|_| ())
+            .map_err(io::Error::from)
+    }
+
+    fn read_bulk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // IN transfers must be a whole number of packets
+        let len = buf.len() - buf.len() % self.max_packet_size;
+        let data = block_on(
+            self.interface
+                .bulk_in(self.bulk_in, RequestBuffer::new(len)),
+        )
+        .into_result()?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+/// [`Transport`] to a YubiKey over its USB CCID interface.
+pub struct CcidTransport<D: CcidDevice> {
+    device: D,
+
+    /// Sequence number of the next message
+    seq: u8,
+
+    /// ATR returned when the card was last powered on, `None` once powered
+    /// off
+    atr: Option<Vec<u8>>,
+}
+
+impl<D: CcidDevice> CcidTransport<D> {
+    /// Power on the card behind the given CCID interface, and create a
+    /// transport to it.
+    pub fn new(device: D) -> Result<Self> {
+        let mut transport = Self {
+            device,
+            seq: 0,
+            atr: None,
+        };
+
+        transport.power_on()?;
+        Ok(transport)
+    }
+
+    /// Get the ATR of the card, if it's powered on.
+    pub fn atr(&self) -> Option<&[u8]> {
+        self.atr.as_deref()
+    }
+
+    fn power_on(&mut self) -> Result<()> {
+        // Automatic voltage selection
+        let atr = self.exchange(PC_TO_RDR_ICC_POWER_ON, [0; 3], &[])?;
+        debug!("CCID card powered on, ATR: {:02x?}", atr);
+        self.atr = Some(atr);
+        Ok(())
+    }
+
+    fn power_off(&mut self) -> Result<()> {
+        self.atr = None;
+        self.exchange(PC_TO_RDR_ICC_POWER_OFF, [0; 3], &[])
+            .map(|_| ())
+    }
+
+    /// Send a message, and receive the data of the response.
+    fn exchange(&mut self, message_type: u8, params: [u8; 3], data: &[u8]) -> Result<Vec<u8>> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+
+        let len = u32::try_from(data.len()).map_err(|_| Error::SizeError)?;
+        let mut message = Vec::with_capacity(HEADER_LEN + data.len());
+        message.push(message_type);
+        message.extend_from_slice(&len.to_le_bytes());
+        message.push(0); // bSlot
+        message.push(seq);
+        message.extend_from_slice(&params);
+        message.extend_from_slice(data);
+
+        self.device.write_bulk(&message).map_err(io_error)?;
+
+        loop {
+            let response = self.read_message()?;
+
+            if response[6] != seq {
+                error!(
+                    "CCID response out of sequence: expected {}, got {}",
+                    seq, response[6]
+                );
+                return Err(Error::GenericError);
+            }
+
+            let expected_type = match message_type {
+                PC_TO_RDR_ICC_POWER_OFF => RDR_TO_PC_SLOT_STATUS,
+                _ => RDR_TO_PC_DATA_BLOCK,
+            };
+
+            if response[0] != expected_type {
+                error!("unexpected CCID response type {:02x}", response[0]);
+                return Err(Error::GenericError);
+            }
+
+            match response[7] & 0xc0 {
+                0 => return Ok(response[HEADER_LEN..].to_vec()),
+                STATUS_TIME_EXTENSION => debug!("CCID time extension requested"),
+                STATUS_FAILED => {
+                    error!("CCID command failed with error {:02x}", response[8]);
+                    return Err(Error::GenericError);
+                }
+                status => {
+                    error!("invalid CCID command status {:02x}", status);
+                    return Err(Error::GenericError);
+                }
+            }
+        }
+    }
+
+    /// Read a complete message from the bulk IN endpoint.
+    fn read_message(&mut self) -> Result<Vec<u8>> {
+        let mut message = vec![];
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+
+        loop {
+            let n = self.device.read_bulk(&mut buf).map_err(io_error)?;
+            if n == 0 {
+                error!("CCID device returned no data");
+                return Err(Error::GenericError);
+            }
+
+            message.extend_from_slice(&buf[..n]);
+
+            if message.len() >= HEADER_LEN {
+                let len = u32::from_le_bytes([message[1], message[2], message[3], message[4]]);
+                let total = HEADER_LEN + len as usize;
+
+                if total > MAX_MESSAGE_LEN {
+                    error!("CCID message too long ({} bytes)", total);
+                    return Err(Error::SizeError);
+                }
+
+                if message.len() >= total {
+                    message.truncate(total);
+                    return Ok(message);
+                }
+            }
+        }
+    }
+}
+
+impl<D: CcidDevice> fmt::Debug for CcidTransport<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CcidTransport")
+            .field("atr", &self.atr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D: CcidDevice> Transport for CcidTransport<D> {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        if self.atr.is_none() {
+            self.power_on()?;
+        }
+
+        // The CCID interface is claimed exclusively, so no locking is needed
+        Ok(Box::new(CcidExchange(RefCell::new(self))))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.atr.is_some()
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        if disposition != Disposition::LeaveCard && self.atr.is_some() {
+            self.power_off()?;
+        }
+
+        if self.atr.is_none() {
+            self.power_on()?;
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()> {
+        if disposition == Disposition::LeaveCard {
+            self.atr = None;
+            return Ok(());
+        }
+
+        self.power_off()
+    }
+}
+
+/// Exchange of APDUs over a CCID interface.
+struct CcidExchange<'tx, D: CcidDevice>(RefCell<&'tx mut CcidTransport<D>>);
+
+impl<D: CcidDevice> Exchange for CcidExchange<'_, D> {
+    fn transmit(&self, command: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        // Default block waiting time, short APDU level exchange
+        let response = self
+            .0
+            .borrow_mut()
+            .exchange(PC_TO_RDR_XFR_BLOCK, [0; 3], command)?;
+
+        if response.len() > recv_len {
+            error!(
+                "response too long: {} bytes, expected at most {}",
+                response.len(),
+                recv_len
+            );
+            return Err(Error::SizeError);
+        }
+
+        Ok(response)
+    }
+}
+
+fn io_error(e: io::Error) -> Error {
+    error!("CCID I/O error: {}", e);
+
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected => Error::CardRemoved,
+        _ => Error::GenericError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Serial, Version, YubiKey};
+    use std::collections::VecDeque;
+
+    /// Simulated CCID interface of a YubiKey 5 answering the commands sent
+    /// when opening it, in 64-byte packets.
+    #[derive(Default)]
+    struct MockDevice {
+        packets: VecDeque<Vec<u8>>,
+        powered: bool,
+    }
+
+    impl MockDevice {
+        fn respond(&mut self, message_type: u8, seq: u8, status: u8, data: &[u8]) {
+            let mut message = vec![message_type];
+            message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            message.extend_from_slice(&[0, seq, status, 0, 0]);
+            message.extend_from_slice(data);
+            self.packets
+                .extend(message.chunks(64).map(|packet| packet.to_vec()));
+        }
+    }
+
+    impl CcidDevice for MockDevice {
+        fn write_bulk(&mut self, data: &[u8]) -> io::Result<()> {
+            let seq = data[6];
+
+            match data[0] {
+                PC_TO_RDR_ICC_POWER_ON => {
+                    self.powered = true;
+                    self.respond(RDR_TO_PC_DATA_BLOCK, seq, 0, &[0x3b, 0xfd]);
+                }
+                PC_TO_RDR_ICC_POWER_OFF => {
+                    self.powered = false;
+                    self.respond(RDR_TO_PC_SLOT_STATUS, seq, 0, &[]);
+                }
+                PC_TO_RDR_XFR_BLOCK => {
+                    assert!(self.powered);
+                    let response = match data[HEADER_LEN + 1] {
+                        // GET VERSION, after a time extension
+                        0xfd => {
+                            self.respond(RDR_TO_PC_DATA_BLOCK, seq, STATUS_TIME_EXTENSION, &[]);
+                            vec![5, 4, 3, 0x90, 0x00]
+                        }
+                        // GET SERIAL
+                        0xf8 => vec![0x00, 0xbc, 0x61, 0x4e, 0x90, 0x00],
+                        // GET DATA, with a response spanning several packets
+                        0xcb => {
+                            let mut response = vec![0x53, 0x64];
+                            response.extend_from_slice(&[0x42; 100]);
+                            response.extend_from_slice(&[0x90, 0x00]);
+                            response
+                        }
+                        _ => vec![0x90, 0x00],
+                    };
+                    self.respond(RDR_TO_PC_DATA_BLOCK, seq, 0, &response);
+                }
+                _ => self.respond(RDR_TO_PC_SLOT_STATUS, seq, STATUS_FAILED, &[]),
+            }
+
+            Ok(())
+        }
+
+        fn read_bulk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let packet = self.packets.pop_front().expect("response pending");
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    #[test]
+    fn open_over_ccid() {
+        let transport = CcidTransport::new(MockDevice::default()).expect("power on");
+        assert_eq!(transport.atr(), Some(&[0x3b, 0xfd][..]));
+
+        let yubikey = YubiKey::open_with_transport(transport, "ccid").expect("open");
+        assert_eq!(yubikey.version(), Version::new([5, 4, 3]));
+        assert_eq!(yubikey.serial(), Serial(12_345_678));
+        assert!(yubikey.close().is_ok());
+
+        let mut transport = CcidTransport::new(MockDevice::default()).expect("power on");
+        let response = transport
+            .begin_transaction()
+            .expect("transaction")
+            .transmit(&[0x00, 0xcb, 0x3f, 0xff, 0x03, 0x5c, 0x01, 0x7e], 261)
+            .expect("GET DATA");
+        assert_eq!(response.len(), 104);
+
+        assert!(transport.disconnect(Disposition::ResetCard).is_ok());
+        assert!(!transport.is_connected());
+    }
+}
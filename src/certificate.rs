@@ -31,28 +31,39 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use crate::{
-    consts::CB_OBJ_MAX,
+    consts::{CB_OBJ_MAX, CB_OBJ_MAX_NEO},
     error::{Error, Result},
-    piv::SlotId,
+    middleware::Operation,
+    piv::{self, SlotId},
     serialization::*,
     transaction::Transaction,
-    yubikey::YubiKey,
+    verify,
+    yubikey::{Version, YubiKey},
     Buffer,
 };
-use log::error;
+use log::{debug, error};
+use sha2::{Sha256, Sha384, Sha512};
+use std::{borrow::Cow, ops::Range};
 use x509_cert::{
     builder::{Builder, CertificateBuilder, Profile},
-    der::{self, referenced::OwnedToRef, Decode, Encode},
+    der::{
+        self, asn1::BitStringRef, oid::db::rfc5912, referenced::OwnedToRef, Decode, Encode, Header,
+        Reader, SliceReader,
+    },
+    ext::pkix::BasicConstraints,
     name::Name,
     serial_number::SerialNumber,
-    spki::{SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef},
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef},
     time::Validity,
 };
 use zeroize::Zeroizing;
 
-const TAG_CERT: u8 = 0x70;
-const TAG_CERT_COMPRESS: u8 = 0x71;
-const TAG_CERT_LRC: u8 = 0xFE;
+#[cfg(feature = "untested")]
+use crate::attestation::AttestationRequirements;
+
+const TAG_CERT: u16 = 0x70;
+const TAG_CERT_COMPRESS: u16 = 0x71;
+const TAG_CERT_LRC: u16 = 0xFE;
 
 /// Information about how a [`Certificate`] is stored within a YubiKey.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -89,7 +100,22 @@ impl From<CertInfo> for u8 {
 #[derive(Clone, Debug)]
 pub struct Certificate {
     /// Inner certificate
+    ///
+    /// Modifying it doesn't change the encoding returned by
+    /// [`Certificate::as_der`], [`Certificate::tbs_bytes`] and
+    /// [`Certificate::signature_bytes`], which is the one the certificate was
+    /// read or generated with. The modified certificate is encoded again when
+    /// written to or verified, however.
     pub cert: x509_cert::Certificate,
+
+    /// DER encoding of the certificate, exactly as it was read or generated
+    der: Vec<u8>,
+
+    /// Location of the `TBSCertificate` within `der`
+    tbs: Range<usize>,
+
+    /// Location of the signature value within `der`
+    signature: Range<usize>,
 }
 
 impl Certificate {
@@ -126,16 +152,55 @@ impl Certificate {
         extensions(&mut builder)?;
 
         let cert = builder.build().map_err(|_| Error::KeyError)?;
-        let cert = Self { cert };
+        let der = cert.to_der().map_err(|_| Error::InvalidObject)?;
+        let cert = Self::from_bytes(der)?;
         cert.write(yubikey, key, CertInfo::Uncompressed)?;
 
         Ok(cert)
     }
 
+    /// Creates a new self-signed certificate for the given key as
+    /// [`Certificate::generate_self_signed`] does, but only once an
+    /// attestation proves the key was generated on the YubiKey and meets the
+    /// given requirements.
+    ///
+    /// Returns [`Error::AttestationError`] without signing anything if the key
+    /// can't be attested (e.g. because it was imported), or the attestation
+    /// doesn't meet the requirements.
+    #[cfg(feature = "untested")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_self_signed_attested<F, KT: yubikey_signer::KeyType>(
+        yubikey: &mut YubiKey,
+        key: SlotId,
+        serial: SerialNumber,
+        validity: Validity,
+        subject: Name,
+        subject_pki: SubjectPublicKeyInfoOwned,
+        requirements: &AttestationRequirements,
+        extensions: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(&mut CertificateBuilder<'_, yubikey_signer::Signer<'_, KT>>) -> der::Result<()>,
+    {
+        requirements.verify(yubikey, key, subject_pki.owned_to_ref())?;
+
+        Self::generate_self_signed::<F, KT>(
+            yubikey,
+            key,
+            serial,
+            validity,
+            subject,
+            subject_pki,
+            extensions,
+        )
+    }
+
     /// Read a certificate from the given slot in the YubiKey
     pub fn read(yubikey: &mut YubiKey, slot: SlotId) -> Result<Self> {
-        let txn = yubikey.begin_transaction()?;
-        let buf = read_certificate(&txn, slot)?;
+        let buf = yubikey.idempotent(|yubikey| {
+            let txn = yubikey.begin_transaction()?;
+            read_certificate(&txn, slot)
+        })?;
 
         if buf.is_empty() {
             return Err(Error::InvalidObject);
@@ -146,9 +211,81 @@ impl Certificate {
 
     /// Write this certificate into the YubiKey in the given slot
     pub fn write(&self, yubikey: &mut YubiKey, slot: SlotId, certinfo: CertInfo) -> Result<()> {
-        let txn = yubikey.begin_transaction()?;
-        let data = self.cert.to_der().map_err(|_| Error::InvalidObject)?;
-        write_certificate(&txn, slot, Some(&data), certinfo)
+        let current = self.current()?;
+        current.check_size(yubikey.version(), certinfo)?;
+        current.write_unchecked(yubikey, slot, certinfo)
+    }
+
+    /// Write this certificate into the YubiKey in the given slot, unless the
+    /// slot already contains it with the same [`CertInfo`].
+    ///
+    /// This avoids needless flash wear when provisioning is re-run. Returns
+    /// whether the certificate was written.
+    pub fn write_if_changed(
+        &self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        certinfo: CertInfo,
+    ) -> Result<bool> {
+        let current = self.current()?;
+        current.check_size(yubikey.version(), certinfo)?;
+
+        let mut buf = [0u8; CB_OBJ_MAX];
+        let len = encode_certificate(&mut buf, &current.der, certinfo)?;
+        let existing = yubikey.begin_transaction()?.fetch_object(slot.object_id());
+
+        if existing.map_or(false, |existing| *existing == buf[..len]) {
+            debug!(
+                "slot {} already contains this certificate; skipping write",
+                slot
+            );
+            return Ok(false);
+        }
+
+        current.write_unchecked(yubikey, slot, certinfo)?;
+        Ok(true)
+    }
+
+    /// Write this certificate, already checked to fit in the slot.
+    fn write_unchecked(
+        &self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        certinfo: CertInfo,
+    ) -> Result<()> {
+        yubikey.run_write(
+            Operation::WriteCertificate { slot },
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+                write_certificate(&txn, slot, Some(&self.der), certinfo)
+            },
+        )
+    }
+
+    /// Check this certificate fits in a slot of a YubiKey with the given
+    /// firmware version, when stored with the given [`CertInfo`].
+    ///
+    /// Writing a certificate checks this beforehand, returning
+    /// [`Error::CertificateTooLarge`] rather than an opaque error from the
+    /// YubiKey.
+    pub fn check_size(&self, version: Version, certinfo: CertInfo) -> Result<()> {
+        let size = encoded_len(self.current()?.der.len());
+        let max = max_object_size(version);
+
+        if size > max {
+            error!(
+                "certificate object is {} bytes, but YubiKeys with firmware {} store at most {}",
+                size, version, max
+            );
+            return Err(Error::CertificateTooLarge {
+                size,
+                max,
+                compressed: certinfo == CertInfo::Gzip,
+            });
+        }
+
+        Ok(())
     }
 
     /// Delete a certificate located at the given slot of the given YubiKey
@@ -167,9 +304,33 @@ impl Certificate {
             return Err(Error::SizeError);
         }
 
-        x509_cert::Certificate::from_der(&cert)
-            .map(|cert| Self { cert })
-            .map_err(|_| Error::InvalidObject)
+        let der = cert.to_vec();
+        let cert = x509_cert::Certificate::from_der(&der).map_err(|_| Error::InvalidObject)?;
+        let (tbs, signature) = locate_fields(&der).map_err(|_| Error::InvalidObject)?;
+
+        Ok(Self {
+            cert,
+            der,
+            tbs,
+            signature,
+        })
+    }
+
+    /// Returns the DER encoding of the certificate, exactly as it was read
+    /// from the YubiKey (or generated), without re-encoding it.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Returns the DER encoding of the `TBSCertificate`, i.e. the bytes
+    /// covered by the certificate's signature.
+    pub fn tbs_bytes(&self) -> &[u8] {
+        &self.der[self.tbs.clone()]
+    }
+
+    /// Returns the raw signature value of the certificate.
+    pub fn signature_bytes(&self) -> &[u8] {
+        &self.der[self.signature.clone()]
     }
 
     /// Returns the Issuer field of the certificate.
@@ -182,6 +343,15 @@ impl Certificate {
         self.cert.tbs_certificate.subject.to_string()
     }
 
+    /// Is this a CA certificate, i.e. does its basic constraints extension
+    /// assert it may sign other certificates?
+    pub fn is_ca(&self) -> bool {
+        match self.cert.tbs_certificate.get::<BasicConstraints>() {
+            Ok(Some((_, constraints))) => constraints.ca,
+            _ => false,
+        }
+    }
+
     /// Returns the SubjectPublicKeyInfo field of the certificate.
     pub fn subject_pki(&self) -> SubjectPublicKeyInfoRef<'_> {
         self.cert
@@ -189,6 +359,95 @@ impl Certificate {
             .subject_public_key_info
             .owned_to_ref()
     }
+
+    /// Verify the signature of this certificate against its own public key.
+    pub fn verify_self_signed(&self) -> Result<()> {
+        self.verify_with_key(self.subject_pki())
+    }
+
+    /// Verify the signature of this certificate against the public key of the
+    /// key stored in the given slot of the YubiKey.
+    ///
+    /// The public key is read from the slot metadata, which requires firmware
+    /// 5.3 or newer.
+    pub fn verify_with_slot(&self, yubikey: &mut YubiKey, slot: SlotId) -> Result<()> {
+        let public = piv::metadata(yubikey, slot)?
+            .public
+            .ok_or(Error::NotFound)?;
+
+        self.verify_with_key(public.owned_to_ref())
+    }
+
+    /// Verify the signature of this certificate against the given public key.
+    pub fn verify_with_key(&self, public_key: SubjectPublicKeyInfoRef<'_>) -> Result<()> {
+        let current = self.current()?;
+
+        verify_signature(
+            public_key,
+            &current.cert.signature_algorithm,
+            current.tbs_bytes(),
+            current.signature_bytes(),
+        )
+    }
+
+    /// Get this certificate, encoded again if [`Certificate::cert`] has been
+    /// modified since it was read or generated.
+    fn current(&self) -> Result<Cow<'_, Self>> {
+        let modified = x509_cert::Certificate::from_der(&self.der)
+            .map_or(true, |original| original != self.cert);
+
+        if !modified {
+            return Ok(Cow::Borrowed(self));
+        }
+
+        let der = self.cert.to_der().map_err(|_| Error::InvalidObject)?;
+        Self::from_bytes(der).map(Cow::Owned)
+    }
+}
+
+/// Verify an X.509 signature made with one of the algorithms supported by the
+/// YubiKey.
+pub(crate) fn verify_signature(
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    algorithm: &AlgorithmIdentifierOwned,
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    match algorithm.oid {
+        rfc5912::SHA_256_WITH_RSA_ENCRYPTION => {
+            verify::pkcs1v15::<Sha256>(public_key, msg, signature)
+        }
+        rfc5912::SHA_384_WITH_RSA_ENCRYPTION => {
+            verify::pkcs1v15::<Sha384>(public_key, msg, signature)
+        }
+        rfc5912::SHA_512_WITH_RSA_ENCRYPTION => {
+            verify::pkcs1v15::<Sha512>(public_key, msg, signature)
+        }
+        rfc5912::ECDSA_WITH_SHA_256 => verify::ecdsa::<Sha256>(public_key, msg, signature),
+        rfc5912::ECDSA_WITH_SHA_384 => verify::ecdsa::<Sha384>(public_key, msg, signature),
+        rfc5912::ECDSA_WITH_SHA_512 => verify::ecdsa::<Sha512>(public_key, msg, signature),
+        verify::ED25519_OID => verify::ed25519(public_key, msg, signature),
+        _ => Err(Error::AlgorithmError),
+    }
+}
+
+/// Find the `TBSCertificate` and signature value within a DER encoded certificate
+fn locate_fields(der: &[u8]) -> der::Result<(Range<usize>, Range<usize>)> {
+    let mut reader = SliceReader::new(der)?;
+    let header = Header::decode(&mut reader)?;
+    let tbs_start = usize::try_from(header.encoded_len()?)?;
+
+    let (tbs_len, signature_len) = reader.read_nested(header.length, |reader| {
+        let tbs_len = reader.tlv_bytes()?.len();
+        let _signature_algorithm = reader.tlv_bytes()?;
+        let signature_len = BitStringRef::decode(reader)?.raw_bytes().len();
+        Ok((tbs_len, signature_len))
+    })?;
+
+    Ok((
+        tbs_start..tbs_start + tbs_len,
+        der.len() - signature_len..der.len(),
+    ))
 }
 
 /// Read certificate
@@ -197,6 +456,8 @@ pub(crate) fn read_certificate(txn: &Transaction<'_>, slot: SlotId) -> Result<Bu
 
     let buf = match txn.fetch_object(object_id) {
         Ok(b) => b,
+        // Reported so the read is repeated once the card is reconnected to
+        Err(e) if e.is_card_reset() => return Err(e),
         Err(_) => {
             // TODO(tarcieri): is this really ok?
             return Ok(Zeroizing::new(vec![]));
@@ -204,7 +465,7 @@ pub(crate) fn read_certificate(txn: &Transaction<'_>, slot: SlotId) -> Result<Bu
     };
 
     // TODO(str4d): Check the rest of the buffer (TAG_CERT_COMPRESS and TAG_CERT_LRC)
-    if buf[0] == TAG_CERT {
+    if u16::from(buf[0]) == TAG_CERT {
         Tlv::parse_single(buf, TAG_CERT).or_else(|_| {
             // TODO(tarcieri): is this really ok?
             Ok(Zeroizing::new(vec![]))
@@ -225,18 +486,47 @@ pub(crate) fn write_certificate(
 
     if let Some(data) = data {
         let mut buf = [0u8; CB_OBJ_MAX];
-        let mut offset = Tlv::write(&mut buf, TAG_CERT, data)?;
-
-        // write compression info and LRC trailer
-        offset += Tlv::write(&mut buf[offset..], TAG_CERT_COMPRESS, &[certinfo.into()])?;
-        offset += Tlv::write(&mut buf[offset..], TAG_CERT_LRC, &[])?;
-
+        let offset = encode_certificate(&mut buf, data, certinfo)?;
         txn.save_object(object_id, &buf[..offset])
     } else {
         txn.save_object(object_id, &[])
     }
 }
 
+/// Get the length of the object encoding a certificate of the given length
+fn encoded_len(len: usize) -> usize {
+    let length_len = match len {
+        0..=0x7f => 1,
+        0x80..=0xff => 2,
+        _ => 3,
+    };
+
+    // certificate TLV, compression info TLV and LRC trailer
+    1 + length_len + len + 3 + 2
+}
+
+/// Get the maximum size of a data object on YubiKeys with the given
+/// firmware version
+pub(crate) fn max_object_size(version: Version) -> usize {
+    // The YubiKey NEO has smaller buffers
+    if version.major < 4 {
+        CB_OBJ_MAX_NEO
+    } else {
+        CB_OBJ_MAX
+    }
+}
+
+/// Encode a certificate object into the given buffer, returning its length
+pub(crate) fn encode_certificate(buf: &mut [u8], data: &[u8], certinfo: CertInfo) -> Result<usize> {
+    let mut offset = Tlv::write(buf, TAG_CERT, data)?;
+
+    // write compression info and LRC trailer
+    offset += Tlv::write(&mut buf[offset..], TAG_CERT_COMPRESS, &[certinfo.into()])?;
+    offset += Tlv::write(&mut buf[offset..], TAG_CERT_LRC, &[])?;
+
+    Ok(offset)
+}
+
 pub mod yubikey_signer {
     //! Signer implementation for yubikey
 
@@ -247,7 +537,7 @@ pub mod yubikey_signer {
         YubiKey,
     };
     use der::{
-        asn1::{Any, OctetString},
+        asn1::{Any, BitString, OctetString},
         oid::db::rfc5912,
         Encode, Sequence,
     };
@@ -318,6 +608,45 @@ pub mod yubikey_signer {
         }
     }
 
+    /// Ed25519 keys (requires firmware 5.7 or newer)
+    ///
+    /// Messages are signed as they are (PureEdDSA), rather than hashed first.
+    pub struct Ed25519;
+
+    impl KeyType for Ed25519 {
+        const ALGORITHM: AlgorithmId = AlgorithmId::Ed25519;
+        type Error = signature::Error;
+        type Signature = Ed25519Signature;
+        type VerifyingKey = ed25519_dalek::VerifyingKey;
+        type PublicKey = ed25519_dalek::VerifyingKey;
+
+        fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
+            Ok(input.to_vec())
+        }
+
+        fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
+            Self::Signature::try_from(input)
+        }
+    }
+
+    /// Ed25519 signature, which can be encoded in certificates
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Ed25519Signature(pub ed25519_dalek::Signature);
+
+    impl TryFrom<&[u8]> for Ed25519Signature {
+        type Error = signature::Error;
+
+        fn try_from(bytes: &[u8]) -> SigResult<Self> {
+            ed25519_dalek::Signature::from_slice(bytes).map(Self)
+        }
+    }
+
+    impl SignatureBitStringEncoding for Ed25519Signature {
+        fn to_bitstring(&self) -> der::Result<BitString> {
+            BitString::from_bytes(&self.0.to_bytes())
+        }
+    }
+
     /// Trait used to handle subtypes of RSA keys
     pub trait RsaLength {
         /// The length of the RSA key in bits
@@ -342,6 +671,22 @@ pub mod yubikey_signer {
         const ALGORITHM: AlgorithmId = AlgorithmId::Rsa2048;
     }
 
+    /// RSA 3072 bits key
+    pub struct Rsa3072;
+
+    impl RsaLength for Rsa3072 {
+        const BIT_LENGTH: usize = 3072;
+        const ALGORITHM: AlgorithmId = AlgorithmId::Rsa3072;
+    }
+
+    /// RSA 4096 bits key
+    pub struct Rsa4096;
+
+    impl RsaLength for Rsa4096 {
+        const BIT_LENGTH: usize = 4096;
+        const ALGORITHM: AlgorithmId = AlgorithmId::Rsa4096;
+    }
+
     /// RSA keys used to sign certificates
     pub struct YubiRsa<N: RsaLength> {
         _len: PhantomData<N>,
@@ -457,3 +802,45 @@ pub mod yubikey_signer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_len_matches_encoding() {
+        let mut buf = [0u8; CB_OBJ_MAX];
+
+        for len in [0, 0x7f, 0x80, 0xff, 0x100, 2000] {
+            let data = vec![0u8; len];
+            let encoded = encode_certificate(&mut buf, &data, CertInfo::Uncompressed)
+                .expect("certificate encoded");
+            assert_eq!(encoded_len(len), encoded);
+        }
+    }
+
+    #[test]
+    fn ed25519_signature_encoding() {
+        use ed25519_dalek::{ed25519::signature::Signer, SigningKey};
+        use x509_cert::spki::SignatureBitStringEncoding;
+        use yubikey_signer::{Ed25519, Ed25519Signature, KeyType};
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let msg = b"message";
+        let signature = key.sign(&Ed25519::prepare(msg).expect("prepare"));
+        let decoded = Ed25519::read_signature(&signature.to_bytes()).expect("signature");
+
+        assert_eq!(decoded, Ed25519Signature(signature));
+        assert_eq!(
+            decoded.to_bitstring().expect("bit string").raw_bytes(),
+            &signature.to_bytes()
+        );
+        assert!(Ed25519::read_signature(&[0; 63]).is_err());
+    }
+
+    #[test]
+    fn neo_object_size() {
+        assert_eq!(max_object_size(Version::new([3, 4, 9])), CB_OBJ_MAX_NEO);
+        assert_eq!(max_object_size(Version::new([5, 4, 3])), CB_OBJ_MAX);
+    }
+}
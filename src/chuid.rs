@@ -106,8 +106,8 @@ impl ChuId {
 
     /// Get Cardholder Unique Identifier (CHUID)
     pub fn get(yubikey: &mut YubiKey) -> Result<ChuId> {
-        let txn = yubikey.begin_transaction()?;
-        let response = txn.fetch_object(OBJ_CHUID)?;
+        let response =
+            yubikey.idempotent(|yubikey| yubikey.begin_transaction()?.fetch_object(OBJ_CHUID))?;
         Ok(response[..Self::BYTE_SIZE].try_into().map(Self)?)
     }
 
@@ -0,0 +1,105 @@
+//! Source of the current time.
+//!
+//! A [`YubiKey`](crate::YubiKey) reads the current time from a [`Clock`],
+//! which can be replaced with [`YubiKey::set_clock`](crate::YubiKey::set_clock)
+//! for deterministic tests, or on systems whose clock can't be trusted (e.g.
+//! an air-gapped CA laptop without a battery-backed clock). It's used for:
+//!
+//! - the time a [`Report`](crate::report::Report) was generated at
+//! - the time the PIN was last changed, as recorded by
+//!   `YubiKey::set_pin_last_changed`
+//! - deciding when the connection must be revalidated: see
+//!   [`YubiKey::set_revalidate_after`](crate::YubiKey::set_revalidate_after)
+//! - measuring [`RateLimits`](crate::RateLimits)
+//!
+//! [`validity`] computes certificate validity periods from a clock, in place
+//! of `Validity::from_now`, and [`unix_time`] the current time for expiry
+//! checks such as
+//! [`SlotAssignment::expires_before`](crate::fleet::SlotAssignment::expires_before).
+
+use crate::error::{Error, Result};
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use x509_cert::time::{Time, Validity};
+
+/// Source of the current time.
+pub trait Clock: Send {
+    /// Get the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// Clock reading the system time.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock always returning the same time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FixedClock(pub SystemTime);
+
+impl FixedClock {
+    /// Create a clock fixed at the given time since the Unix epoch.
+    pub fn from_unix_time(time: Duration) -> Self {
+        Self(UNIX_EPOCH + time)
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Clock").field(&self.now()).finish()
+    }
+}
+
+/// Get the current time of the given clock, since the Unix epoch.
+pub fn unix_time(clock: &dyn Clock) -> Result<Duration> {
+    Ok(clock.now().duration_since(UNIX_EPOCH)?)
+}
+
+/// Get a certificate validity period starting at the current time of the
+/// given clock, and lasting for the given duration.
+pub fn validity(clock: &dyn Clock, duration: Duration) -> Result<Validity> {
+    let now = clock.now();
+    let then = now.checked_add(duration).ok_or(Error::RangeError)?;
+
+    Ok(Validity {
+        not_before: Time::try_from(now)?,
+        not_after: Time::try_from(then)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_validity() {
+        let clock = FixedClock::from_unix_time(Duration::from_secs(1_577_836_800));
+        let validity = validity(&clock, Duration::from_secs(86_400)).expect("validity in range");
+
+        assert_eq!(
+            validity.not_before.to_unix_duration(),
+            Duration::from_secs(1_577_836_800)
+        );
+        assert_eq!(
+            validity.not_after.to_unix_duration(),
+            Duration::from_secs(1_577_923_200)
+        );
+        assert_eq!(
+            unix_time(&clock).expect("after the epoch"),
+            Duration::from_secs(1_577_836_800)
+        );
+    }
+}
@@ -35,8 +35,9 @@ use crate::{
         TAG_ADMIN_FLAGS_1, TAG_ADMIN_SALT, TAG_ADMIN_TIMESTAMP, TAG_PROTECTED_FLAGS_1,
         TAG_PROTECTED_MGM,
     },
+    error::Error,
     metadata::{AdminData, ProtectedData},
-    mgm::{MgmType, ADMIN_FLAGS_1_PROTECTED_MGM},
+    mgm::{MgmType, ADMIN_FLAGS_1_PROTECTED_MGM, CB_ADMIN_SALT},
     yubikey::{YubiKey, ADMIN_FLAGS_1_PUK_BLOCKED},
     Result,
 };
@@ -84,41 +85,21 @@ impl Config {
         let txn = yubikey.begin_transaction()?;
 
         if let Ok(admin_data) = AdminData::read(&txn) {
-            if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
-                if item.is_empty() {
-                    error!("empty response for admin flags metadata item! ignoring");
-                } else {
-                    if item[0] & ADMIN_FLAGS_1_PUK_BLOCKED != 0 {
-                        config.puk_blocked = true;
-                    }
+            let admin = AdminMetadata::from_admin_data(&admin_data);
+            config.puk_blocked = admin.puk_blocked;
+            config.pin_last_changed = admin.pin_last_changed;
 
-                    if item[0] & ADMIN_FLAGS_1_PROTECTED_MGM != 0 {
-                        config.mgm_type = MgmType::Protected;
-                    }
-                }
+            if admin.mgm_key_protected {
+                config.mgm_type = MgmType::Protected;
             }
 
-            if admin_data.get_item(TAG_ADMIN_SALT).is_ok() {
+            if admin.salt.is_some() {
                 if config.mgm_type != MgmType::Manual {
                     error!("conflicting types of MGM key administration configured");
                 } else {
                     config.mgm_type = MgmType::Derived;
                 }
             }
-
-            if let Ok(item) = admin_data.get_item(TAG_ADMIN_TIMESTAMP) {
-                if item.len() != CB_ADMIN_TIMESTAMP {
-                    error!("pin timestamp in admin metadata is an invalid size");
-                } else {
-                    // TODO(tarcieri): double-check endianness is correct
-                    let pin_last_changed = u32::from_le_bytes([item[0], item[1], item[2], item[3]]);
-
-                    if pin_last_changed != 0 {
-                        config.pin_last_changed =
-                            Some(UNIX_EPOCH + Duration::from_secs(pin_last_changed as u64));
-                    }
-                }
-            }
         }
 
         if let Ok(protected_data) = ProtectedData::read(&txn) {
@@ -145,3 +126,119 @@ impl Config {
         Ok(config)
     }
 }
+
+/// Contents of the Yubico administrative data object, in which Yubico's
+/// tools record how the credentials of a YubiKey are managed.
+///
+/// Tools changing credentials without this crate's helpers (e.g.
+/// `YubiKey::block_puk`) should keep these flags consistent with them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AdminMetadata {
+    /// The PUK has been blocked on purpose
+    pub puk_blocked: bool,
+
+    /// The management key is stored in the PIN-protected data object
+    pub mgm_key_protected: bool,
+
+    /// Salt of a management key derived from the PIN (a deprecated scheme)
+    pub salt: Option<[u8; CB_ADMIN_SALT]>,
+
+    /// Time the PIN was last changed, if recorded
+    pub pin_last_changed: Option<SystemTime>,
+
+    /// Flag bits which aren't known to this crate, preserved when written
+    other_flags: u8,
+}
+
+impl AdminMetadata {
+    /// Read the administrative data object of the YubiKey, returning the
+    /// default (no flags set) if there's none.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let txn = yubikey.begin_transaction()?;
+
+        match AdminData::read(&txn) {
+            Ok(admin_data) => Ok(Self::from_admin_data(&admin_data)),
+            Err(Error::NotFound) => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write these values to the administrative data object of the YubiKey,
+    /// preserving any items of it unknown to this crate.
+    ///
+    /// The management key must be authenticated.
+    #[cfg(feature = "untested")]
+    pub fn write(&self, yubikey: &mut YubiKey) -> Result<()> {
+        let txn = yubikey.begin_transaction()?;
+
+        let mut admin_data = match AdminData::read(&txn) {
+            Ok(admin_data) => admin_data,
+            Err(Error::NotFound) => AdminData::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut flags = self.other_flags;
+        if self.puk_blocked {
+            flags |= ADMIN_FLAGS_1_PUK_BLOCKED;
+        }
+        if self.mgm_key_protected {
+            flags |= ADMIN_FLAGS_1_PROTECTED_MGM;
+        }
+
+        let timestamp = match self.pin_last_changed {
+            Some(time) => {
+                let secs = time.duration_since(UNIX_EPOCH)?.as_secs();
+                u32::try_from(secs)
+                    .map_err(|_| Error::RangeError)?
+                    .to_le_bytes()
+                    .to_vec()
+            }
+            None => vec![],
+        };
+
+        admin_data.set_item(TAG_ADMIN_FLAGS_1, &[flags])?;
+        admin_data.set_item(TAG_ADMIN_SALT, self.salt.as_ref().map_or(&[], |salt| salt))?;
+        admin_data.set_item(TAG_ADMIN_TIMESTAMP, &timestamp)?;
+        admin_data.write(&txn)
+    }
+
+    /// Parse the items of the administrative data object.
+    pub(crate) fn from_admin_data(admin_data: &AdminData) -> Self {
+        let mut admin = Self::default();
+
+        if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
+            match item.first() {
+                Some(&flags) => {
+                    admin.puk_blocked = flags & ADMIN_FLAGS_1_PUK_BLOCKED != 0;
+                    admin.mgm_key_protected = flags & ADMIN_FLAGS_1_PROTECTED_MGM != 0;
+                    admin.other_flags =
+                        flags & !(ADMIN_FLAGS_1_PUK_BLOCKED | ADMIN_FLAGS_1_PROTECTED_MGM);
+                }
+                None => error!("empty response for admin flags metadata item! ignoring"),
+            }
+        }
+
+        if let Ok(item) = admin_data.get_item(TAG_ADMIN_SALT) {
+            match item.try_into() {
+                Ok(salt) => admin.salt = Some(salt),
+                Err(_) => error!("salt in admin metadata is an invalid size"),
+            }
+        }
+
+        if let Ok(item) = admin_data.get_item(TAG_ADMIN_TIMESTAMP) {
+            if item.len() != CB_ADMIN_TIMESTAMP {
+                error!("pin timestamp in admin metadata is an invalid size");
+            } else {
+                // TODO(tarcieri): double-check endianness is correct
+                let pin_last_changed = u32::from_le_bytes([item[0], item[1], item[2], item[3]]);
+
+                if pin_last_changed != 0 {
+                    admin.pin_last_changed =
+                        Some(UNIX_EPOCH + Duration::from_secs(pin_last_changed as u64));
+                }
+            }
+        }
+
+        admin
+    }
+}
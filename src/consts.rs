@@ -6,15 +6,55 @@ pub(crate) const CB_BUF_MAX: usize = 3072;
 /// YubiKey max object size
 pub(crate) const CB_OBJ_MAX: usize = CB_BUF_MAX - 9;
 
+/// YubiKey NEO max buffer size
+pub(crate) const CB_BUF_MAX_NEO: usize = 2048;
+
+/// YubiKey NEO max object size
+pub(crate) const CB_OBJ_MAX_NEO: usize = CB_BUF_MAX_NEO - 9;
+
+/// Storage shared by all the data objects of the PIV application of a
+/// YubiKey 4 or 5 (approximately: each object takes some overhead too)
+pub(crate) const CB_STORAGE_MAX: usize = 51_200;
+
 pub(crate) const CB_OBJ_TAG_MIN: usize = 2; // 1 byte tag + 1 byte len
 #[cfg(feature = "untested")]
 pub(crate) const CB_OBJ_TAG_MAX: usize = CB_OBJ_TAG_MIN + 2; // 1 byte tag + 3 bytes len
 
+// Objects allocated by this crate in Yubico's vendor-specific range
+// (0x5fff00-0x5fffff), next to the ones Yubico uses:
+//
+// - 0x5fff00: admin data
+// - 0x5fff01: attestation certificate
+// - 0x5fff10: MS container map
+// - 0x5fff11-0x5fff15: MS roots
+// - 0x5fff20: slot labels
+// - 0x5fff21: write sequence journal
+// - 0x5fff22: provisioning log
+// - 0x5fff30-0x5fff3f: write sequence backups, one per object written
+//
+// New objects must be allocated here, outside the ranges above.
+
+/// Object storing slot labels
+pub(crate) const OBJ_SLOT_LABELS: u32 = 0x005f_ff20;
+
+/// Object storing the journal of an interrupted write sequence
+pub(crate) const OBJ_JOURNAL: u32 = 0x005f_ff21;
+
+/// Object storing the provisioning log
+pub(crate) const OBJ_PROVISIONING_LOG: u32 = 0x005f_ff22;
+
+/// First of the objects storing the backups of the objects overwritten by a
+/// write sequence
+pub(crate) const OBJ_JOURNAL_BACKUP: u32 = 0x005f_ff30;
+
+/// Number of objects storing write sequence backups
+pub(crate) const JOURNAL_BACKUPS: usize = 16;
+
 // Admin tags
-pub(crate) const TAG_ADMIN_FLAGS_1: u8 = 0x81;
-pub(crate) const TAG_ADMIN_SALT: u8 = 0x82;
-pub(crate) const TAG_ADMIN_TIMESTAMP: u8 = 0x83;
+pub(crate) const TAG_ADMIN_FLAGS_1: u16 = 0x81;
+pub(crate) const TAG_ADMIN_SALT: u16 = 0x82;
+pub(crate) const TAG_ADMIN_TIMESTAMP: u16 = 0x83;
 
 // Protected tags
-pub(crate) const TAG_PROTECTED_FLAGS_1: u8 = 0x81;
-pub(crate) const TAG_PROTECTED_MGM: u8 = 0x89;
+pub(crate) const TAG_PROTECTED_FLAGS_1: u16 = 0x81;
+pub(crate) const TAG_PROTECTED_MGM: u16 = 0x89;
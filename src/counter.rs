@@ -0,0 +1,313 @@
+//! Host-side counters of the private key operations performed with each slot.
+//!
+//! PIV keys have no signature counter, so there's no way to tell from the
+//! YubiKey how much a key has been used, as needed by rotation policies
+//! (e.g. "rotate signing keys after 100,000 signatures or a year of use").
+//! An [`OperationCounter`] added to a [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::add_middleware`](crate::YubiKey::add_middleware) emulates one,
+//! recording the number of signatures and decryptions performed with each
+//! slot and when it was last used in a [`CounterStore`], such as a
+//! [`FileCounterStore`].
+//!
+//! Counts are incremented and stored before each operation is performed, so
+//! they never undercount (e.g. if the process crashes mid-operation), and
+//! operations are refused if the counts can't be stored. Only operations
+//! performed through counted handles are accounted for.
+
+use crate::{
+    clock::{self, Clock, SystemClock},
+    error::{Error, Result},
+    middleware::{Middleware, Next, Operation},
+    piv::SlotId,
+    yubikey::Serial,
+};
+use log::error;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Usage of the key in a slot.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SlotUsage {
+    /// Number of signatures made with the key
+    pub signatures: u64,
+
+    /// Number of decryptions (or key agreements) made with the key
+    pub decryptions: u64,
+
+    /// When the key was last used, in seconds since the Unix epoch
+    pub last_used: Option<u64>,
+}
+
+/// Usage of the keys in each slot of a YubiKey.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counters {
+    slots: BTreeMap<SlotId, SlotUsage>,
+}
+
+impl Counters {
+    /// Get the usage of the key in the given slot.
+    pub fn get(&self, slot: SlotId) -> SlotUsage {
+        self.slots.get(&slot).copied().unwrap_or_default()
+    }
+
+    /// Iterate over the used slots and their usage.
+    pub fn iter(&self) -> impl Iterator<Item = (SlotId, SlotUsage)> + '_ {
+        self.slots.iter().map(|(&slot, &usage)| (slot, usage))
+    }
+
+    /// Reset the usage of the key in the given slot, e.g. once it has been
+    /// rotated.
+    pub fn reset(&mut self, slot: SlotId) {
+        self.slots.remove(&slot);
+    }
+
+    /// Parse counters from the text format used by [`FileCounterStore`]:
+    /// one line per slot, with the slot, the number of signatures and
+    /// decryptions, and the time it was last used (or `-`).
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut slots = BTreeMap::new();
+
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+
+            let (slot, signatures, decryptions, last_used) = match *fields {
+                [slot, signatures, decryptions, last_used] => {
+                    (slot, signatures, decryptions, last_used)
+                }
+                _ => return Err(Error::ParseError),
+            };
+
+            let number = |field: &str| field.parse::<u64>().map_err(|_| Error::ParseError);
+
+            slots.insert(
+                slot.parse()?,
+                SlotUsage {
+                    signatures: number(signatures)?,
+                    decryptions: number(decryptions)?,
+                    last_used: match last_used {
+                        "-" => None,
+                        time => Some(number(time)?),
+                    },
+                },
+            );
+        }
+
+        Ok(Self { slots })
+    }
+
+    /// Serialize counters in the text format parsed by [`Counters::parse`].
+    pub fn serialize(&self) -> String {
+        self.iter()
+            .map(|(slot, usage)| {
+                let last_used = usage
+                    .last_used
+                    .map_or_else(|| "-".to_owned(), |time| time.to_string());
+
+                format!(
+                    "{} {} {} {}\n",
+                    slot_name(slot),
+                    usage.signatures,
+                    usage.decryptions,
+                    last_used
+                )
+            })
+            .collect()
+    }
+}
+
+/// Get the name of a slot as parsed by `SlotId::from_str` (e.g. `9c`).
+fn slot_name(slot: SlotId) -> String {
+    format!("{:02x}", u8::from(slot))
+}
+
+/// Persistent storage of [`Counters`].
+pub trait CounterStore: Send {
+    /// Load the counters of the YubiKey with the given serial number,
+    /// returning empty counters if none have been stored.
+    fn load(&mut self, serial: Serial) -> Result<Counters>;
+
+    /// Store the counters of the YubiKey with the given serial number.
+    fn store(&mut self, serial: Serial, counters: &Counters) -> Result<()>;
+}
+
+/// Store keeping counters in memory, e.g. for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryCounterStore {
+    counters: BTreeMap<Serial, Counters>,
+}
+
+impl CounterStore for MemoryCounterStore {
+    fn load(&mut self, serial: Serial) -> Result<Counters> {
+        Ok(self.counters.get(&serial).cloned().unwrap_or_default())
+    }
+
+    fn store(&mut self, serial: Serial, counters: &Counters) -> Result<()> {
+        self.counters.insert(serial, counters.clone());
+        Ok(())
+    }
+}
+
+/// Store shared between several YubiKey handles (or threads).
+impl<S: CounterStore> CounterStore for Arc<Mutex<S>> {
+    fn load(&mut self, serial: Serial) -> Result<Counters> {
+        self.lock().map_err(|_| Error::GenericError)?.load(serial)
+    }
+
+    fn store(&mut self, serial: Serial, counters: &Counters) -> Result<()> {
+        self.lock()
+            .map_err(|_| Error::GenericError)?
+            .store(serial, counters)
+    }
+}
+
+/// Store keeping the counters of each YubiKey in a file named after its
+/// serial number (e.g. `12345678.counters`) in a directory.
+///
+/// Files are replaced atomically, so they're never left partially written.
+#[derive(Clone, Debug)]
+pub struct FileCounterStore {
+    dir: PathBuf,
+}
+
+impl FileCounterStore {
+    /// Create a store keeping counters in the given directory, which must
+    /// exist.
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    fn path(&self, serial: Serial, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", serial, extension))
+    }
+}
+
+impl CounterStore for FileCounterStore {
+    fn load(&mut self, serial: Serial) -> Result<Counters> {
+        match fs::read_to_string(self.path(serial, "counters")) {
+            Ok(contents) => Counters::parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Counters::default()),
+            Err(e) => {
+                error!("couldn't read counters of YubiKey {}: {}", serial, e);
+                Err(Error::GenericError)
+            }
+        }
+    }
+
+    fn store(&mut self, serial: Serial, counters: &Counters) -> Result<()> {
+        let tmp = self.path(serial, "counters.tmp");
+
+        fs::write(&tmp, counters.serialize())
+            .and_then(|()| fs::rename(&tmp, self.path(serial, "counters")))
+            .map_err(|e| {
+                error!("couldn't store counters of YubiKey {}: {}", serial, e);
+                Error::GenericError
+            })
+    }
+}
+
+/// [`Middleware`] counting the private key operations performed with each
+/// slot in a [`CounterStore`].
+pub struct OperationCounter {
+    serial: Serial,
+    store: Box<dyn CounterStore>,
+    clock: Box<dyn Clock>,
+}
+
+impl OperationCounter {
+    /// Count the operations performed with the YubiKey with the given serial
+    /// number in the given store.
+    pub fn new(serial: Serial, store: impl CounterStore + 'static) -> Self {
+        Self {
+            serial,
+            store: Box::new(store),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Set the [`Clock`] the time operations are performed at is read from.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Load the current counters from the store.
+    pub fn counters(&mut self) -> Result<Counters> {
+        self.store.load(self.serial)
+    }
+
+    fn record(&mut self, slot: SlotId, operation: Operation) -> Result<()> {
+        let mut counters = self.store.load(self.serial)?;
+        let usage = counters.slots.entry(slot).or_default();
+
+        match operation {
+            Operation::Sign { .. } => usage.signatures += 1,
+            _ => usage.decryptions += 1,
+        }
+
+        usage.last_used = Some(clock::unix_time(self.clock.as_ref())?.as_secs());
+        self.store.store(self.serial, &counters)
+    }
+}
+
+impl Middleware for OperationCounter {
+    fn handle(&mut self, operation: Operation, mut next: Next<'_>) -> Result<()> {
+        match operation {
+            Operation::Sign { slot, .. } | Operation::Decrypt { slot, .. } => {
+                self.record(slot, operation)?;
+            }
+            _ => (),
+        }
+
+        next.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::FixedClock, middleware, piv::AlgorithmId};
+    use std::time::Duration;
+
+    #[test]
+    fn count_operations() {
+        let mut store = Arc::new(Mutex::new(MemoryCounterStore::default()));
+        let clock = FixedClock::from_unix_time(Duration::from_secs(1_700_000_000));
+        let counter = OperationCounter::new(Serial(1234), store.clone()).with_clock(clock);
+        let mut chain: Vec<Box<dyn Middleware>> = vec![Box::new(counter)];
+
+        for operation in [
+            Operation::Sign {
+                slot: SlotId::Signature,
+                algorithm: AlgorithmId::EccP256,
+            },
+            Operation::Sign {
+                slot: SlotId::Signature,
+                algorithm: AlgorithmId::EccP256,
+            },
+            Operation::Decrypt {
+                slot: SlotId::KeyManagement,
+                algorithm: AlgorithmId::EccP256,
+            },
+            Operation::VerifyPin,
+        ] {
+            middleware::dispatch(&mut chain, operation, &mut || Ok(())).expect("dispatch");
+        }
+
+        let counters = store.load(Serial(1234)).expect("load counters");
+        assert_eq!(
+            counters,
+            Counters::parse("9c 2 0 1700000000\n9d 0 1 1700000000\n").expect("parse counters")
+        );
+        assert_eq!(
+            Counters::parse(&counters.serialize()).expect("round trip"),
+            counters
+        );
+        assert_eq!(Counters::parse("9c 1 x -"), Err(Error::ParseError));
+    }
+}
@@ -0,0 +1,399 @@
+//! Device information reported by the YubiKey Management application.
+
+use crate::{
+    apdu::{Apdu, Ins, StatusWords},
+    error::{Error, Result},
+    mgm,
+    serialization::Tlv,
+    transaction::Transaction,
+    yubikey::{Serial, Version},
+};
+use log::{debug, error};
+use std::fmt::{self, Display};
+
+/// Read configuration instruction of the Management application.
+pub(crate) const INS_READ_CONFIG: u8 = 0x1d;
+
+/// Maximum number of pages of device information to read.
+const MAX_PAGES: u8 = 8;
+
+const TAG_USB_SUPPORTED: u16 = 0x01;
+const TAG_SERIAL: u16 = 0x02;
+const TAG_USB_ENABLED: u16 = 0x03;
+const TAG_FORM_FACTOR: u16 = 0x04;
+const TAG_VERSION: u16 = 0x05;
+const TAG_CONFIG_LOCK: u16 = 0x0a;
+const TAG_NFC_SUPPORTED: u16 = 0x0d;
+const TAG_NFC_ENABLED: u16 = 0x0e;
+const TAG_MORE_DATA: u16 = 0x10;
+const TAG_PART_NUMBER: u16 = 0x13;
+const TAG_FIPS_CAPABLE: u16 = 0x14;
+const TAG_FIPS_APPROVED: u16 = 0x15;
+
+/// Form factor flag set on YubiKey FIPS Series devices.
+const FORM_FACTOR_FIPS: u8 = 0x80;
+
+/// Form factor flag set on Security Key Series devices.
+const FORM_FACTOR_SKY: u8 = 0x40;
+
+/// Applications supported or enabled on a YubiKey.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Capabilities(pub u16);
+
+impl Capabilities {
+    /// Yubico OTP
+    pub const OTP: Self = Self(0x0001);
+
+    /// FIDO U2F
+    pub const U2F: Self = Self(0x0002);
+
+    /// OpenPGP
+    pub const OPENPGP: Self = Self(0x0008);
+
+    /// PIV
+    pub const PIV: Self = Self(0x0010);
+
+    /// OATH
+    pub const OATH: Self = Self(0x0020);
+
+    /// YubiHSM Auth
+    pub const HSMAUTH: Self = Self(0x0100);
+
+    /// FIDO2
+    pub const FIDO2: Self = Self(0x0200);
+
+    /// Are all of the given capabilities present?
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn parse(value: &[u8]) -> Result<Self> {
+        match *value {
+            [byte] => Ok(Self(byte.into())),
+            [high, low] => Ok(Self(u16::from_be_bytes([high, low]))),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Physical form factor of a YubiKey.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FormFactor {
+    /// Unknown form factor
+    Unknown,
+
+    /// USB-A keychain
+    UsbAKeychain,
+
+    /// USB-A nano
+    UsbANano,
+
+    /// USB-C keychain
+    UsbCKeychain,
+
+    /// USB-C nano
+    UsbCNano,
+
+    /// USB-C and Lightning
+    UsbCLightning,
+
+    /// USB-A with fingerprint sensor
+    UsbABio,
+
+    /// USB-C with fingerprint sensor
+    UsbCBio,
+}
+
+impl From<u8> for FormFactor {
+    fn from(code: u8) -> Self {
+        match code & 0x0f {
+            0x01 => FormFactor::UsbAKeychain,
+            0x02 => FormFactor::UsbANano,
+            0x03 => FormFactor::UsbCKeychain,
+            0x04 => FormFactor::UsbCNano,
+            0x05 => FormFactor::UsbCLightning,
+            0x06 => FormFactor::UsbABio,
+            0x07 => FormFactor::UsbCBio,
+            _ => FormFactor::Unknown,
+        }
+    }
+}
+
+/// Product variant of a YubiKey, for variants which differ in the
+/// applications they provide.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProductVariant {
+    /// Standard YubiKey
+    Standard,
+
+    /// YubiKey FIPS Series, validated to FIPS 140-2 or 140-3
+    Fips,
+
+    /// YubiKey 5 CSPN Series, certified by ANSSI
+    Cspn,
+
+    /// Security Key Series, which only provides FIDO (and no PIV)
+    SecurityKey,
+}
+
+impl Display for ProductVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProductVariant::Standard => "YubiKey",
+            ProductVariant::Fips => "YubiKey FIPS",
+            ProductVariant::Cspn => "YubiKey CSPN",
+            ProductVariant::SecurityKey => "Security Key",
+        })
+    }
+}
+
+/// Information about a YubiKey, as reported by its Management application.
+///
+/// This is available on YubiKey 5 Series and Security Key Series devices
+/// (including Security Keys connected over NFC, which have no PIV
+/// application).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceInfo {
+    /// Serial number, if the device has one and it's visible
+    pub serial: Option<Serial>,
+
+    /// Firmware version
+    pub version: Option<Version>,
+
+    /// Physical form factor
+    pub form_factor: FormFactor,
+
+    /// Applications supported over USB
+    pub usb_supported: Capabilities,
+
+    /// Applications enabled over USB
+    pub usb_enabled: Capabilities,
+
+    /// Applications supported over NFC, if the device supports NFC
+    pub nfc_supported: Option<Capabilities>,
+
+    /// Applications enabled over NFC, if the device supports NFC
+    pub nfc_enabled: Option<Capabilities>,
+
+    /// Whether the configuration is protected by a lock code
+    pub config_locked: bool,
+
+    /// Part number, if reported (firmware 5.7+)
+    pub part_number: Option<String>,
+
+    /// Applications which can be FIPS approved (firmware 5.7+)
+    pub fips_capable: Capabilities,
+
+    /// Applications which are currently in a FIPS approved state (firmware 5.7+)
+    pub fips_approved: Capabilities,
+
+    /// Raw form factor byte, including the FIPS and Security Key flags
+    form_factor_code: u8,
+}
+
+impl DeviceInfo {
+    /// Read the device information by selecting the Management application.
+    ///
+    /// This leaves the Management application selected.
+    pub(crate) fn read(txn: &Transaction<'_>) -> Result<Self> {
+        let status_words = Apdu::new(Ins::SelectApplication)
+            .p1(0x04)
+            .data(mgm::APPLET_ID)
+            .transmit(txn, 255)?
+            .status_words();
+
+        if !status_words.is_success() {
+            error!(
+                "failed selecting mgmt application: {:04x}",
+                status_words.code()
+            );
+            return Err(match status_words {
+                StatusWords::NotFoundError => Error::AppletNotFound {
+                    applet_name: mgm::APPLET_NAME,
+                },
+                _ => Error::GenericError,
+            });
+        }
+
+        let mut data = vec![];
+
+        for page in 0..MAX_PAGES {
+            let response = Apdu::new(INS_READ_CONFIG).p1(page).transmit(txn, 261)?;
+
+            if !response.is_success() {
+                error!(
+                    "failed reading device info: {:04x}",
+                    response.status_words().code()
+                );
+                return Err(Error::NotSupported);
+            }
+
+            let (&len, page_data) = response.data().split_first().ok_or(Error::SizeError)?;
+            let page_data = page_data.get(..usize::from(len)).ok_or(Error::SizeError)?;
+
+            let more = parse_tlvs(page_data)?
+                .iter()
+                .any(|&(tag, value)| tag == TAG_MORE_DATA && value == [1]);
+            data.extend_from_slice(page_data);
+
+            if !more {
+                break;
+            }
+        }
+
+        Self::parse(&data)
+    }
+
+    /// Parse device information from the concatenated TLVs of each page.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self> {
+        let mut info = Self {
+            serial: None,
+            version: None,
+            form_factor: FormFactor::Unknown,
+            usb_supported: Capabilities::default(),
+            usb_enabled: Capabilities::default(),
+            nfc_supported: None,
+            nfc_enabled: None,
+            config_locked: false,
+            part_number: None,
+            fips_capable: Capabilities::default(),
+            fips_approved: Capabilities::default(),
+            form_factor_code: 0,
+        };
+
+        for (tag, value) in parse_tlvs(data)? {
+            match tag {
+                TAG_USB_SUPPORTED => info.usb_supported = Capabilities::parse(value)?,
+                TAG_SERIAL => info.serial = Some(value.try_into()?),
+                TAG_USB_ENABLED => info.usb_enabled = Capabilities::parse(value)?,
+                TAG_FORM_FACTOR => {
+                    let code = *value.first().ok_or(Error::ParseError)?;
+                    info.form_factor = FormFactor::from(code);
+                    info.form_factor_code = code;
+                }
+                TAG_VERSION => info.version = Some(Version::new(value.try_into()?)),
+                TAG_CONFIG_LOCK => info.config_locked = value.first() == Some(&1),
+                TAG_NFC_SUPPORTED => info.nfc_supported = Some(Capabilities::parse(value)?),
+                TAG_NFC_ENABLED => info.nfc_enabled = Some(Capabilities::parse(value)?),
+                TAG_PART_NUMBER => {
+                    info.part_number = std::str::from_utf8(value)
+                        .ok()
+                        .filter(|part_number| !part_number.is_empty())
+                        .map(String::from)
+                }
+                TAG_FIPS_CAPABLE => info.fips_capable = Capabilities::parse(value)?,
+                TAG_FIPS_APPROVED => info.fips_approved = Capabilities::parse(value)?,
+                _ => debug!("ignoring device info tag {:02x}", tag),
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Get the product variant of this YubiKey.
+    ///
+    /// CSPN devices are recognized the same way as by Yubico's `ykman`: a
+    /// YubiKey 5 whose only applications are OTP, U2F, FIDO2 and PIV.
+    pub fn variant(&self) -> ProductVariant {
+        let cspn_capabilities =
+            Capabilities::OTP | Capabilities::U2F | Capabilities::FIDO2 | Capabilities::PIV;
+
+        if self.form_factor_code & FORM_FACTOR_SKY != 0 {
+            ProductVariant::SecurityKey
+        } else if self.form_factor_code & FORM_FACTOR_FIPS != 0 || self.fips_capable.0 != 0 {
+            ProductVariant::Fips
+        } else if self.version.map_or(false, |version| version.major == 5)
+            && self.usb_supported == cspn_capabilities
+        {
+            ProductVariant::Cspn
+        } else {
+            ProductVariant::Standard
+        }
+    }
+
+    /// Is the PIV application operating in FIPS approved mode?
+    ///
+    /// Only firmware 5.7 and newer reports this, so it's `false` for older
+    /// FIPS Series YubiKeys.
+    pub fn is_piv_fips_approved(&self) -> bool {
+        self.fips_approved.contains(Capabilities::PIV)
+    }
+
+    /// Does this YubiKey provide the PIV application over any transport?
+    pub fn has_piv(&self) -> bool {
+        self.usb_supported.contains(Capabilities::PIV)
+            || self
+                .nfc_supported
+                .map_or(false, |nfc| nfc.contains(Capabilities::PIV))
+    }
+}
+
+/// Parse a sequence of single-byte tag TLVs.
+fn parse_tlvs(mut data: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+    let mut tlvs = vec![];
+
+    while !data.is_empty() {
+        let (rest, tlv) = Tlv::parse(data)?;
+        tlvs.push((tlv.tag, tlv.value));
+        data = rest;
+    }
+
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_security_key() {
+        // Security Key NFC: FIDO only, USB-A keychain with the SKY flag
+        let info = DeviceInfo::parse(&[
+            0x01, 0x02, 0x02, 0x02, 0x03, 0x02, 0x02, 0x02, 0x04, 0x01, 0x41, 0x05, 0x03, 0x05,
+            0x04, 0x03, 0x0d, 0x02, 0x02, 0x02,
+        ])
+        .expect("valid device info");
+
+        assert_eq!(info.form_factor, FormFactor::UsbAKeychain);
+        assert_eq!(info.version, Some(Version::new([5, 4, 3])));
+        assert_eq!(info.variant(), ProductVariant::SecurityKey);
+        assert!(!info.has_piv());
+    }
+
+    #[test]
+    fn parse_fips() {
+        let info = DeviceInfo::parse(&[
+            0x01, 0x02, 0x02, 0x3b, 0x02, 0x04, 0x00, 0xbc, 0x61, 0x4e, 0x04, 0x01, 0x83, 0x05,
+            0x03, 0x05, 0x04, 0x03,
+        ])
+        .expect("valid device info");
+
+        assert_eq!(info.serial, Some(Serial(12345678)));
+        assert_eq!(info.form_factor, FormFactor::UsbCKeychain);
+        assert_eq!(info.variant(), ProductVariant::Fips);
+        assert!(info.has_piv());
+        assert!(!info.is_piv_fips_approved());
+    }
+
+    #[test]
+    fn parse_fips_approved() {
+        let info = DeviceInfo::parse(&[
+            0x01, 0x02, 0x02, 0x3b, 0x05, 0x03, 0x05, 0x07, 0x02, 0x14, 0x02, 0x00, 0x10, 0x15,
+            0x02, 0x00, 0x10,
+        ])
+        .expect("valid device info");
+
+        assert_eq!(info.variant(), ProductVariant::Fips);
+        assert!(info.is_piv_fips_approved());
+    }
+}
@@ -0,0 +1,312 @@
+//! Diagnosis of failures to access PC/SC readers.
+//!
+//! Failing to open a PC/SC context or connect to a reader usually surfaces as
+//! a terse PC/SC error (e.g. "The Smart card resource manager is not
+//! running"), whose actual cause depends on how the system is set up. On
+//! Linux, the common causes are:
+//!
+//! - `pcscd` isn't running, nor set up to be started on demand by systemd
+//! - the user can't access the `pcscd` socket, as they aren't in the group
+//!   owning it
+//! - polkit rules deny the user access to `pcscd` (e.g. in remote sessions)
+//!
+//! [`diagnose`] inspects the system to determine which of these apply to an
+//! [`Error`], and returns [`Diagnosis`]es which frontends can present along
+//! with their [remediation](Diagnosis::remediation).
+
+use crate::error::Error;
+use std::{fmt, path::PathBuf};
+
+#[cfg(target_os = "linux")]
+use std::{env, fs, os::unix::fs::MetadataExt, path::Path};
+
+/// Default path of the `pcscd` socket.
+#[cfg(target_os = "linux")]
+const PCSCD_SOCKET: &str = "/run/pcscd/pcscd.comm";
+
+/// Environment variable overriding the path of the `pcscd` socket.
+#[cfg(target_os = "linux")]
+const PCSCD_SOCKET_ENV: &str = "PCSCLITE_CSOCK_NAME";
+
+/// polkit policy installed by `pcscd` when built with polkit support.
+#[cfg(target_os = "linux")]
+const POLKIT_POLICY: &str = "/usr/share/polkit-1/actions/org.debian.pcsc-lite.policy";
+
+/// polkit action authorizing access to `pcscd`.
+#[cfg(target_os = "linux")]
+const POLKIT_ACTION: &str = "org.debian.pcsc-lite.access_pcsc";
+
+/// Likely cause of a failure to access PC/SC readers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Diagnosis {
+    /// `pcscd` isn't running, as its socket doesn't exist.
+    ServiceNotRunning {
+        /// Path of the `pcscd` socket
+        socket: PathBuf,
+    },
+
+    /// The PC/SC service isn't available, for an undetermined reason.
+    ServiceUnavailable,
+
+    /// The user isn't allowed to connect to the `pcscd` socket.
+    SocketPermissionDenied {
+        /// Path of the `pcscd` socket
+        socket: PathBuf,
+
+        /// Name of the group owning the socket, if it can be determined
+        group: Option<String>,
+    },
+
+    /// polkit denies the user access to `pcscd`.
+    PolkitDenied {
+        /// polkit action which must be authorized
+        action: &'static str,
+    },
+
+    /// Access to the PC/SC service was denied, for an undetermined reason.
+    AccessDenied,
+
+    /// No readers are connected.
+    NoReaders,
+
+    /// The reader is in exclusive use by another application.
+    ReaderInUse,
+}
+
+impl Diagnosis {
+    /// Get the suggested remediation for this diagnosis.
+    pub fn remediation(&self) -> String {
+        match self {
+            Diagnosis::ServiceNotRunning { .. } => {
+                "start pcscd, e.g. with `sudo systemctl enable --now pcscd.socket`".into()
+            }
+            Diagnosis::ServiceUnavailable => {
+                "make sure the smart card service (pcscd on Linux and macOS, \"Smart Card\" on Windows) is running".into()
+            }
+            Diagnosis::SocketPermissionDenied {
+                group: Some(group), ..
+            } => format!(
+                "add the user to the `{}` group (e.g. `sudo usermod -aG {} $USER`), then log in again",
+                group, group
+            ),
+            Diagnosis::SocketPermissionDenied { socket, .. } => format!(
+                "allow the user to read and write {}",
+                socket.display()
+            ),
+            Diagnosis::PolkitDenied { action } => format!(
+                "add a polkit rule allowing the user the `{}` action (remote and inactive sessions are denied by default)",
+                action
+            ),
+            Diagnosis::AccessDenied => "check the user is allowed to access the smart card service".into(),
+            Diagnosis::NoReaders => "connect a YubiKey, and check it has the CCID interface enabled".into(),
+            Diagnosis::ReaderInUse => {
+                "close applications holding the YubiKey exclusively (e.g. `gpgconf --kill scdaemon`)".into()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnosis::ServiceNotRunning { socket } => write!(
+                f,
+                "pcscd isn't running ({} doesn't exist)",
+                socket.display()
+            ),
+            Diagnosis::ServiceUnavailable => f.write_str("the smart card service isn't available"),
+            Diagnosis::SocketPermissionDenied { socket, .. } => {
+                write!(f, "permission denied to {}", socket.display())
+            }
+            Diagnosis::PolkitDenied { .. } => f.write_str("polkit denies access to pcscd"),
+            Diagnosis::AccessDenied => f.write_str("access to the smart card service was denied"),
+            Diagnosis::NoReaders => f.write_str("no smart card readers are connected"),
+            Diagnosis::ReaderInUse => f.write_str("the reader is in use by another application"),
+        }
+    }
+}
+
+/// Diagnose the likely causes of the given error, if it's a failure to
+/// access PC/SC readers.
+///
+/// Returns an empty list for other errors.
+pub fn diagnose(error: &Error) -> Vec<Diagnosis> {
+    let inner = match error {
+        Error::PcscError { inner: Some(inner) } => *inner,
+        _ => return vec![],
+    };
+
+    match inner {
+        pcsc::Error::NoService | pcsc::Error::ServiceStopped => {
+            let mut diagnoses = socket_diagnoses();
+
+            if diagnoses.is_empty() {
+                diagnoses.push(Diagnosis::ServiceUnavailable);
+            }
+
+            diagnoses
+        }
+        pcsc::Error::NoAccess | pcsc::Error::SecurityViolation => {
+            let mut diagnoses = socket_diagnoses();
+            diagnoses.extend(polkit_diagnoses());
+
+            if diagnoses.is_empty() {
+                diagnoses.push(Diagnosis::AccessDenied);
+            }
+
+            diagnoses
+        }
+        pcsc::Error::NoReadersAvailable | pcsc::Error::UnknownReader => vec![Diagnosis::NoReaders],
+        pcsc::Error::SharingViolation => vec![Diagnosis::ReaderInUse],
+        _ => vec![],
+    }
+}
+
+/// Check the `pcscd` socket exists and is accessible.
+#[cfg(target_os = "linux")]
+fn socket_diagnoses() -> Vec<Diagnosis> {
+    let socket = env::var_os(PCSCD_SOCKET_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(PCSCD_SOCKET));
+
+    let metadata = match fs::metadata(&socket) {
+        Ok(metadata) => metadata,
+        Err(_) => return vec![Diagnosis::ServiceNotRunning { socket }],
+    };
+
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let credentials = Credentials::parse(&status);
+
+    if credentials.can_access(metadata.mode(), metadata.uid(), metadata.gid()) {
+        return vec![];
+    }
+
+    let group = fs::read_to_string("/etc/group")
+        .ok()
+        .and_then(|groups| group_name(&groups, metadata.gid()));
+
+    vec![Diagnosis::SocketPermissionDenied { socket, group }]
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_diagnoses() -> Vec<Diagnosis> {
+    vec![]
+}
+
+/// Check whether `pcscd` enforces polkit rules.
+#[cfg(target_os = "linux")]
+fn polkit_diagnoses() -> Vec<Diagnosis> {
+    if Path::new(POLKIT_POLICY).exists() {
+        vec![Diagnosis::PolkitDenied {
+            action: POLKIT_ACTION,
+        }]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn polkit_diagnoses() -> Vec<Diagnosis> {
+    vec![]
+}
+
+/// Effective user and groups of the current process.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+struct Credentials {
+    uid: Option<u32>,
+    groups: Vec<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl Credentials {
+    /// Parse the credentials from the contents of `/proc/<pid>/status`.
+    fn parse(status: &str) -> Self {
+        let mut credentials = Self::default();
+
+        for line in status.lines() {
+            if let Some(uids) = line.strip_prefix("Uid:") {
+                // Real, effective, saved and filesystem UIDs
+                credentials.uid = uids
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|uid| uid.parse().ok());
+            } else if let Some(gids) = line.strip_prefix("Gid:") {
+                credentials.groups.extend(
+                    gids.split_whitespace()
+                        .nth(1)
+                        .and_then(|gid| gid.parse::<u32>().ok()),
+                );
+            } else if let Some(groups) = line.strip_prefix("Groups:") {
+                credentials.groups.extend(
+                    groups
+                        .split_whitespace()
+                        .filter_map(|gid| gid.parse::<u32>().ok()),
+                );
+            }
+        }
+
+        credentials
+    }
+
+    /// Can these credentials read and write a file with the given mode and
+    /// owner?
+    fn can_access(&self, mode: u32, uid: u32, gid: u32) -> bool {
+        const RW: u32 = 0o6;
+
+        // Root, or unknown credentials: assume permissions aren't the issue
+        if self.uid.map_or(true, |own_uid| own_uid == 0) {
+            return true;
+        }
+
+        if self.uid == Some(uid) {
+            mode >> 6 & RW == RW
+        } else if self.groups.contains(&gid) {
+            mode >> 3 & RW == RW
+        } else {
+            mode & RW == RW
+        }
+    }
+}
+
+/// Find the name of the group with the given ID in the contents of
+/// `/etc/group`.
+#[cfg(target_os = "linux")]
+fn group_name(groups: &str, gid: u32) -> Option<String> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let id = fields.nth(1)?.parse::<u32>().ok()?;
+        (id == gid).then(|| name.to_owned())
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_permissions() {
+        let credentials = Credentials::parse(
+            "Name:\tykman\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\t1000\t1000\t1000\nGroups:\t27 1000\n",
+        );
+        assert_eq!(credentials.uid, Some(1000));
+
+        assert!(credentials.can_access(0o666, 0, 0));
+        assert!(credentials.can_access(0o660, 0, 27));
+        assert!(!credentials.can_access(0o660, 0, 46));
+        assert!(credentials.can_access(0o600, 1000, 46));
+
+        let groups = "root:x:0:\nsudo:x:27:alice\nplugdev:x:46:\n";
+        assert_eq!(group_name(groups, 46).as_deref(), Some("plugdev"));
+        assert_eq!(group_name(groups, 1234), None);
+    }
+
+    #[test]
+    fn diagnose_pcsc_errors() {
+        let error = Error::from(pcsc::Error::SharingViolation);
+        assert_eq!(diagnose(&error), [Diagnosis::ReaderInUse]);
+        assert!(diagnose(&Error::WrongPin { tries: 2 }).is_empty());
+    }
+}
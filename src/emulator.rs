@@ -0,0 +1,3050 @@
+//! Software emulation of a YubiKey's PIV application, for tests.
+//!
+//! An [`Emulator`] is a [`Transport`] answering APDUs from a PIV application
+//! simulated in memory, as a YubiKey 5 (firmware 5.4.3) in its factory state
+//! would. Code using this crate can be tested without a physical YubiKey:
+//!
+//! ```
+//! use yubikey::{emulator::Emulator, Serial};
+//!
+//! let mut yubikey = Emulator::new(Serial(12345678)).open()?;
+//! yubikey.verify_pin(b"123456")?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! It supports the PIN and PUK flows, management key authentication, key
+//! generation (RSA 1024/2048, ECC P-256/P-384), signing, decryption and key
+//! agreement, slot metadata, data objects (e.g. certificates), setting the
+//! management key and PIN retries, and resetting the application. Touch is
+//! never required.
+//!
+//! Keys and challenges are random, unless the emulated YubiKey is seeded with
+//! [`Emulator::with_seed`]: the same operations then generate the same keys
+//! every time, so complete issuance flows can be compared to golden files.
+//!
+//! [`Emulator::reset_card`] simulates the card being reset by another
+//! application, to test recovery from it. More generally, a [`Fault`] can be
+//! injected into the exchange of a given command with
+//! [`Emulator::inject_fault`], to exercise error handling deterministically.
+//! SCP03 secure channels are emulated once enabled with
+//! [`Emulator::with_scp03`], and SCP11 ones with [`Emulator::with_scp11`].
+//!
+//! Attestation isn't emulated: attesting keys fails as it does on firmware
+//! which doesn't support it. Neither are other YubiKey applications.
+
+use crate::{
+    apdu::{Ins, StatusWords},
+    certificate::Certificate,
+    mgm::MgmKey3Des,
+    piv::{self, AlgorithmId, Origin, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    scp03::{self, Scp03Keys, Session},
+    scp11,
+    serialization::Tlv,
+    transport::{Disposition, Exchange, Transport},
+    yubikey::{Serial, YubiKey},
+    Buffer, Error, Result,
+};
+use cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use elliptic_curve::sec1::ToEncodedPoint;
+use rand_core::{impls, CryptoRng, OsRng, RngCore};
+use rsa::{
+    traits::{PrivateKeyParts, PublicKeyParts},
+    BigUint, RsaPrivateKey,
+};
+use sha2::{Digest, Sha256};
+use signature::hazmat::PrehashSigner;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+use zeroize::Zeroizing;
+
+/// Firmware version of the emulated YubiKey
+const VERSION: [u8; 3] = [5, 4, 3];
+
+/// Factory default PIN
+const DEFAULT_PIN: &[u8] = b"123456";
+
+/// Factory default PUK
+const DEFAULT_PUK: &[u8] = b"12345678";
+
+/// Factory default (3DES) management key
+const DEFAULT_MGM_KEY: [u8; 24] = [
+    1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+];
+
+/// Factory default number of PIN and PUK retries
+const DEFAULT_TRIES: u8 = 3;
+
+/// Size of the PIN and PUK fields, padded with `0xff`
+const PIN_LEN: usize = 8;
+
+/// Key reference of the PIN
+const PIN_REF: u8 = 0x80;
+
+/// Key reference of the PUK
+const PUK_REF: u8 = 0x81;
+
+/// Key reference of the management key
+const MGM_REF: u8 = 0x9b;
+
+/// Largest response returned at once, the rest being left for GET RESPONSE
+const MAX_RESPONSE: usize = 256;
+
+/// Emulated YubiKey, whose PIV application is simulated in memory.
+///
+/// Cloning it returns another connection to the same emulated YubiKey, e.g.
+/// to open it again after closing it and check its state was kept.
+#[derive(Clone)]
+pub struct Emulator {
+    applet: Arc<Mutex<PivApplet>>,
+    connected: bool,
+
+    /// Number of card resets this connection has seen
+    resets: u64,
+}
+
+impl Emulator {
+    /// Create an emulated YubiKey with the given serial number, in its
+    /// factory state.
+    pub fn new(serial: Serial) -> Self {
+        Self {
+            applet: Arc::new(Mutex::new(PivApplet::new(serial.into()))),
+            connected: true,
+            resets: 0,
+        }
+    }
+
+    /// Derive all randomness of the emulated YubiKey (generated keys,
+    /// challenges...) from the given seed rather than the OS RNG, so runs
+    /// performing the same operations produce the same results.
+    ///
+    /// The keys generated this way are predictable: they must only ever be
+    /// used in tests.
+    pub fn with_seed(self, seed: u64) -> Self {
+        if let Ok(mut applet) = self.applet() {
+            applet.rng = EmulatorRng::seeded(seed);
+        }
+
+        self
+    }
+
+    /// Accept SCP03 secure channels opened with the given keys, as a
+    /// YubiKey whose security domain has them provisioned would.
+    pub fn with_scp03(self, keys: Scp03Keys) -> Self {
+        if let Ok(mut applet) = self.applet() {
+            applet.scp03_keys = Some(keys);
+        }
+
+        self
+    }
+
+    /// Accept SCP11 secure channels, as a YubiKey whose security domain has
+    /// the given SD key and certificate chain (leaf last) provisioned would.
+    ///
+    /// The same SD key is used for SCP11a and SCP11b, whatever its key
+    /// version. OCE certificates sent for SCP11a aren't verified.
+    pub fn with_scp11(self, key: p256::SecretKey, certificates: Vec<Certificate>) -> Self {
+        if let Ok(mut applet) = self.applet() {
+            let chain = certificates
+                .iter()
+                .flat_map(|certificate| certificate.as_der().to_vec())
+                .collect();
+            applet.scp11 = Some((key, chain));
+        }
+
+        self
+    }
+
+    /// Open a [`YubiKey`] connected to this emulated YubiKey.
+    pub fn open(&self) -> Result<YubiKey> {
+        let mut connection = self.clone();
+        connection.resets = self.applet()?.resets;
+        YubiKey::open_with_transport(connection, "Yubico YubiKey (emulated)")
+    }
+
+    /// Open a [`YubiKey`] connected to this emulated YubiKey, authenticated
+    /// with the default management key.
+    pub fn open_authenticated(&self) -> Result<YubiKey> {
+        let mut yubikey = self.open()?;
+        yubikey.authenticate(MgmKey3Des::from_bytes(DEFAULT_MGM_KEY)?)?;
+        Ok(yubikey)
+    }
+
+    /// Reset the card, as another application sharing it (or the reader being
+    /// power-cycled) would: the security status is cleared, and transactions
+    /// on existing connections fail with [`Error::CardReset`] until they
+    /// reconnect.
+    pub fn reset_card(&self) -> Result<()> {
+        let mut applet = self.applet()?;
+        applet.end_session();
+        applet.resets += 1;
+        Ok(())
+    }
+
+    /// Make the `n`th command sent to the emulated YubiKey from now on (the
+    /// next one if `n` is 0) fail with the given [`Fault`].
+    ///
+    /// Every command APDU transmitted counts, on any connection, including
+    /// those sending the chained parts of a command or getting the rest of a
+    /// response.
+    pub fn inject_fault(&self, n: usize, fault: Fault) -> Result<()> {
+        let mut applet = self.applet()?;
+        let index = applet.commands + n;
+        applet.faults.insert(index, fault);
+        Ok(())
+    }
+
+    fn applet(&self) -> Result<MutexGuard<'_, PivApplet>> {
+        self.applet.lock().map_err(|_| Error::GenericError)
+    }
+}
+
+impl fmt::Debug for Emulator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Emulator")
+            .field("connected", &self.connected)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transport for Emulator {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        if !self.connected {
+            return Err(Error::CardRemoved);
+        }
+
+        let applet = self.applet()?;
+
+        if applet.resets != self.resets {
+            return Err(Error::CardReset);
+        }
+
+        Ok(Box::new(EmulatorExchange(RefCell::new(applet))))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        let resets = {
+            let mut applet = self.applet()?;
+
+            if disposition != Disposition::LeaveCard {
+                applet.end_session();
+            }
+
+            applet.resets
+        };
+
+        self.resets = resets;
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()> {
+        if disposition != Disposition::LeaveCard {
+            self.applet()?.end_session();
+        }
+
+        self.connected = false;
+        Ok(())
+    }
+}
+
+/// Failure of the exchange of a command with an emulated YubiKey: see
+/// [`Emulator::inject_fault`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// The card leaves the field (or is unplugged) as the command is sent,
+    /// and comes back: the command isn't processed, transmitting it fails
+    /// with [`Error::CardRemoved`], the security status is lost, and the next
+    /// transaction fails with [`Error::CardReset`] until reconnecting.
+    FieldLoss,
+
+    /// The card is reset by another application just before the command is
+    /// sent, as with [`Emulator::reset_card`]: the command isn't processed,
+    /// and transmitting it fails with [`Error::CardReset`].
+    Reset,
+
+    /// The command isn't processed, and the card answers with the given
+    /// status words (e.g. `0x6982`, security status not satisfied).
+    Status(u16),
+
+    /// The command is processed, but only the given number of bytes of its
+    /// response (including the status words) are received.
+    Truncate(usize),
+
+    /// The command is processed, but the byte at the given index of its
+    /// response (e.g. of its R-MAC) is corrupted.
+    Corrupt(usize),
+
+    /// The command is processed, but the response is only received after
+    /// the given delay.
+    Delay(Duration),
+}
+
+/// Transaction with an emulated YubiKey, holding it exclusively.
+struct EmulatorExchange<'a>(RefCell<MutexGuard<'a, PivApplet>>);
+
+impl Exchange for EmulatorExchange<'_> {
+    fn transmit(&self, command: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+        let mut applet = self.0.borrow_mut();
+        let index = applet.commands;
+        applet.commands += 1;
+
+        match applet.faults.remove(&index) {
+            None => Ok(applet.process(command)),
+            Some(fault @ (Fault::FieldLoss | Fault::Reset)) => {
+                applet.end_session();
+                applet.resets += 1;
+
+                Err(match fault {
+                    Fault::FieldLoss => Error::CardRemoved,
+                    _ => Error::CardReset,
+                })
+            }
+            Some(Fault::Status(status_words)) => Ok(status_words.to_be_bytes().to_vec()),
+            Some(Fault::Truncate(len)) => {
+                let mut response = applet.process(command);
+                response.truncate(len);
+                Ok(response)
+            }
+            Some(Fault::Corrupt(index)) => {
+                let mut response = applet.process(command);
+                if let Some(byte) = response.get_mut(index) {
+                    *byte ^= 0xff;
+                }
+                Ok(response)
+            }
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                Ok(applet.process(command))
+            }
+        }
+    }
+}
+
+/// Response data, or the status words of a failed command.
+type Reply = std::result::Result<Vec<u8>, StatusWords>;
+
+/// Private key in a slot.
+struct SlotKey {
+    algorithm: AlgorithmId,
+    key: PrivateKey,
+    pin_policy: PinPolicy,
+    origin: Origin,
+}
+
+enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    P256(p256::SecretKey),
+    P384(p384::SecretKey),
+}
+
+/// State of the emulated PIV application.
+struct PivApplet {
+    serial: u32,
+    pin: Vec<u8>,
+    puk: Vec<u8>,
+    pin_tries: u8,
+    pin_remaining: u8,
+    puk_tries: u8,
+    puk_remaining: u8,
+    mgm_algorithm: u8,
+    mgm_key: Vec<u8>,
+    keys: BTreeMap<u8, SlotKey>,
+    objects: BTreeMap<u32, Vec<u8>>,
+
+    /// Security status, reset when the application is selected again
+    pin_verified: bool,
+    pin_just_verified: bool,
+    mgm_authenticated: bool,
+    witness: Option<Vec<u8>>,
+
+    /// Data of chained commands received so far
+    chained: Vec<u8>,
+
+    /// Response data left to be returned by GET RESPONSE
+    pending: Vec<u8>,
+
+    /// Number of times the card was reset
+    resets: u64,
+
+    /// Number of commands transmitted so far
+    commands: usize,
+
+    /// Faults injected into the exchange of commands, by index
+    faults: BTreeMap<usize, Fault>,
+
+    /// SCP03 keys, if secure channels are emulated
+    scp03_keys: Option<Scp03Keys>,
+
+    /// Secure channel session started by INITIALIZE UPDATE, and the host
+    /// cryptogram expected to authenticate it
+    scp03_pending: Option<(Session, [u8; 8])>,
+
+    /// SCP11 SD key and certificate chain (DER, leaf last), if secure
+    /// channels are emulated
+    scp11: Option<(p256::SecretKey, Vec<u8>)>,
+
+    /// OCE public key received with PERFORM SECURITY OPERATION, for SCP11a
+    scp11_oce: Option<p256::PublicKey>,
+
+    /// Open secure channel session (SCP03 or SCP11)
+    secure_session: Option<Session>,
+
+    /// Source of the randomness of keys and challenges
+    rng: EmulatorRng,
+}
+
+impl PivApplet {
+    fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            pin: DEFAULT_PIN.into(),
+            puk: DEFAULT_PUK.into(),
+            pin_tries: DEFAULT_TRIES,
+            pin_remaining: DEFAULT_TRIES,
+            puk_tries: DEFAULT_TRIES,
+            puk_remaining: DEFAULT_TRIES,
+            mgm_algorithm: 0x03,
+            mgm_key: DEFAULT_MGM_KEY.into(),
+            keys: BTreeMap::new(),
+            objects: BTreeMap::new(),
+            pin_verified: false,
+            pin_just_verified: false,
+            mgm_authenticated: false,
+            witness: None,
+            chained: vec![],
+            pending: vec![],
+            resets: 0,
+            commands: 0,
+            faults: BTreeMap::new(),
+            scp03_keys: None,
+            scp03_pending: None,
+            scp11: None,
+            scp11_oce: None,
+            secure_session: None,
+            rng: EmulatorRng::default(),
+        }
+    }
+
+    fn end_session(&mut self) {
+        self.pin_verified = false;
+        self.pin_just_verified = false;
+        self.mgm_authenticated = false;
+        self.witness = None;
+        self.scp03_pending = None;
+        self.scp11_oce = None;
+        self.secure_session = None;
+    }
+
+    /// Process a serialized command APDU, returning the response APDU.
+    fn process(&mut self, command: &[u8]) -> Vec<u8> {
+        let (cla, ins, p1, p2, data) = match command {
+            // Extended APDU, with or without command data
+            [cla, ins, p1, p2, 0, lc1, lc2, rest @ ..] if !rest.is_empty() => {
+                let lc = usize::from(u16::from_be_bytes([*lc1, *lc2]));
+
+                match rest.get(..lc) {
+                    Some(data) => (*cla, *ins, *p1, *p2, data),
+                    None => return respond(vec![], StatusWords::WrongLengthError),
+                }
+            }
+            [cla, ins, p1, p2, 0, _, _] => (*cla, *ins, *p1, *p2, &[][..]),
+            [cla, ins, p1, p2, rest @ ..] => (*cla, *ins, *p1, *p2, rest.get(1..).unwrap_or(&[])),
+            _ => return respond(vec![], StatusWords::WrongLengthError),
+        };
+
+        let ins = Ins::from(ins);
+
+        if ins == Ins::GetResponseApdu {
+            let pending = std::mem::take(&mut self.pending);
+            return self.respond(pending);
+        }
+
+        self.pending.clear();
+
+        if cla & 0x10 != 0 {
+            self.chained.extend_from_slice(data);
+            return respond(vec![], StatusWords::Success);
+        }
+
+        let mut chained = std::mem::take(&mut self.chained);
+        chained.extend_from_slice(data);
+
+        // Secured commands are chained once wrapped, so they're unwrapped whole
+        let secured =
+            cla & scp03::CLA_SECURE != 0 && ins != Ins::Other(scp03::INS_EXTERNAL_AUTHENTICATE);
+
+        let chained: Buffer = if secured {
+            let unwrapped = self
+                .secure_session
+                .as_mut()
+                .and_then(|session| session.unwrap_command(cla, ins.code(), p1, p2, &chained));
+
+            match unwrapped {
+                Some(unwrapped) => unwrapped,
+                None => {
+                    self.end_session();
+                    return respond(vec![], StatusWords::SecurityStatusError);
+                }
+            }
+        } else if self.secure_session.is_some() && ins != Ins::SelectApplication {
+            return respond(vec![], StatusWords::SecurityStatusError);
+        } else {
+            Zeroizing::new(chained)
+        };
+        let data = &chained[..];
+
+        // PIN_ALWAYS keys require the PIN to be verified by the previous command
+        let pin_just_verified = std::mem::replace(&mut self.pin_just_verified, false);
+
+        let reply = match ins {
+            Ins::SelectApplication => self.select(p1, data),
+            Ins::GetVersion => Ok(VERSION.to_vec()),
+            Ins::GetSerial => Ok(self.serial.to_be_bytes().to_vec()),
+            Ins::Verify => self.verify(p2, data),
+            Ins::ChangeReference => self.change_reference(p2, data),
+            Ins::ResetRetry => self.reset_retry(p2, data),
+            Ins::Authenticate if p2 == MGM_REF => self.authenticate_mgm(p1, data),
+            Ins::Authenticate => self.private_key_operation(p1, p2, data, pin_just_verified),
+            Ins::GenerateAsymmetric => self.generate(p2, data),
+            Ins::ImportKey => self.import_key(p1, p2, data),
+            Ins::GetData => self.get_data(data),
+            Ins::GetMetadata => self.get_metadata(p2),
+            Ins::PutData => self.put_data(data),
+            Ins::SetMgmKey => self.set_mgm_key(data),
+            Ins::SetPinRetries => self.set_pin_retries(p1, p2),
+            Ins::Reset => self.reset(),
+            Ins::Other(scp03::INS_INITIALIZE_UPDATE) => self.initialize_update(p1, data),
+            Ins::Other(scp03::INS_EXTERNAL_AUTHENTICATE) if cla & scp03::CLA_SECURE != 0 => {
+                self.external_authenticate(cla, p1, p2, data)
+            }
+            Ins::Other(scp11::INS_GET_DATA) => self.get_sd_data(p1, p2, data),
+            Ins::Other(scp11::INS_PERFORM_SECURITY_OPERATION) => {
+                self.perform_security_operation(p2, data)
+            }
+            Ins::Other(scp11::INS_MUTUAL_AUTHENTICATE) => self.scp11_authenticate(true, p2, data),
+            Ins::Other(scp11::INS_INTERNAL_AUTHENTICATE) => {
+                self.scp11_authenticate(false, p2, data)
+            }
+            _ => Err(StatusWords::NotSupportedError),
+        };
+
+        match reply {
+            Ok(data) if secured => {
+                let wrapped = self
+                    .secure_session
+                    .as_ref()
+                    .map(|session| session.wrap_response(&data, StatusWords::Success))
+                    .unwrap_or_default();
+                self.respond(wrapped)
+            }
+            Ok(data) => self.respond(data),
+            // Warnings are authenticated too, errors aren't
+            Err(sw) if secured && scp03::has_response_mac(&sw.code().to_be_bytes()) => {
+                let wrapped = self
+                    .secure_session
+                    .as_ref()
+                    .map(|session| session.wrap_response(&[], sw))
+                    .unwrap_or_default();
+                respond(wrapped, sw)
+            }
+            Err(sw) => respond(vec![], sw),
+        }
+    }
+
+    /// Respond with the given data, leaving what doesn't fit for GET RESPONSE.
+    fn respond(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+        if data.len() <= MAX_RESPONSE {
+            return respond(data, StatusWords::Success);
+        }
+
+        self.pending = data.split_off(MAX_RESPONSE);
+        let len = self.pending.len().min(0xff) as u8;
+        respond(data, StatusWords::BytesRemaining { len })
+    }
+
+    fn select(&mut self, p1: u8, aid: &[u8]) -> Reply {
+        if p1 != 0x04 || !aid.starts_with(piv::APPLET_ID) {
+            return Err(StatusWords::NotFoundError);
+        }
+
+        self.end_session();
+        Ok(vec![])
+    }
+
+    fn verify(&mut self, reference: u8, data: &[u8]) -> Reply {
+        if reference != PIN_REF {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        // Without data, only the security status is queried
+        if data.is_empty() {
+            return if self.pin_verified {
+                Ok(vec![])
+            } else {
+                Err(tries_left(self.pin_remaining))
+            };
+        }
+
+        if let Err(sw) = self.check_reference(PIN_REF, data) {
+            self.pin_verified = false;
+            return Err(sw);
+        }
+
+        self.pin_verified = true;
+        self.pin_just_verified = true;
+        Ok(vec![])
+    }
+
+    fn change_reference(&mut self, reference: u8, data: &[u8]) -> Reply {
+        if data.len() != PIN_LEN * 2 {
+            return Err(StatusWords::WrongLengthError);
+        }
+
+        let new = new_reference(&data[PIN_LEN..])?;
+        self.check_reference(reference, &data[..PIN_LEN])?;
+
+        match reference {
+            PIN_REF => self.pin = new,
+            _ => self.puk = new,
+        }
+
+        Ok(vec![])
+    }
+
+    fn reset_retry(&mut self, reference: u8, data: &[u8]) -> Reply {
+        if reference != PIN_REF {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        if data.len() != PIN_LEN * 2 {
+            return Err(StatusWords::WrongLengthError);
+        }
+
+        let new = new_reference(&data[PIN_LEN..])?;
+        self.check_reference(PUK_REF, &data[..PIN_LEN])?;
+
+        self.pin = new;
+        self.pin_remaining = self.pin_tries;
+        Ok(vec![])
+    }
+
+    /// Check the given (padded) PIN or PUK, counting failed attempts.
+    fn check_reference(
+        &mut self,
+        reference: u8,
+        attempt: &[u8],
+    ) -> std::result::Result<(), StatusWords> {
+        let (secret, tries, remaining) = match reference {
+            PIN_REF => (&self.pin, self.pin_tries, &mut self.pin_remaining),
+            PUK_REF => (&self.puk, self.puk_tries, &mut self.puk_remaining),
+            _ => return Err(StatusWords::ReferenceDataNotFoundError),
+        };
+
+        if *remaining == 0 {
+            return Err(StatusWords::AuthBlockedError);
+        }
+
+        if attempt.len() != PIN_LEN || unpad(attempt) != &secret[..] {
+            *remaining -= 1;
+            return Err(tries_left(*remaining));
+        }
+
+        *remaining = tries;
+        Ok(())
+    }
+
+    fn authenticate_mgm(&mut self, algorithm: u8, data: &[u8]) -> Reply {
+        if algorithm != self.mgm_algorithm {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let template = DynamicAuth::parse(data)?;
+        let block_size = mgm_block_size(algorithm);
+
+        match (template.witness, template.challenge) {
+            // Request for a witness, encrypted with the management key
+            (Some([]), None) => {
+                let witness = self.random(block_size);
+                let encrypted = self.mgm_encrypt(&witness)?;
+                self.witness = Some(witness);
+                Ok(Tlv::encode(0x7c, &Tlv::encode(0x80, &encrypted)))
+            }
+            // Request for a challenge
+            (None, Some([])) => Ok(Tlv::encode(
+                0x7c,
+                &Tlv::encode(0x81, &self.random(block_size)),
+            )),
+            // Decrypted witness and a challenge for the card to encrypt
+            (Some(witness), Some(challenge)) => {
+                let expected = self.witness.take();
+                if expected.as_deref() != Some(witness) {
+                    return Err(StatusWords::SecurityStatusError);
+                }
+
+                let response = self.mgm_encrypt(challenge)?;
+                self.mgm_authenticated = true;
+                Ok(Tlv::encode(0x7c, &Tlv::encode(0x82, &response)))
+            }
+            _ => Err(StatusWords::IncorrectParamError),
+        }
+    }
+
+    fn random(&mut self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        self.rng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn mgm_encrypt(&self, block: &[u8]) -> Reply {
+        match self.mgm_algorithm {
+            0x03 => encrypt_block::<des::TdesEde3>(&self.mgm_key, block),
+            0x08 => encrypt_block::<aes::Aes128>(&self.mgm_key, block),
+            0x0a => encrypt_block::<aes::Aes192>(&self.mgm_key, block),
+            _ => encrypt_block::<aes::Aes256>(&self.mgm_key, block),
+        }
+    }
+
+    fn private_key_operation(
+        &mut self,
+        algorithm: u8,
+        slot: u8,
+        data: &[u8],
+        pin_just_verified: bool,
+    ) -> Reply {
+        let key = self
+            .keys
+            .get(&slot)
+            .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+        if u8::from(key.algorithm) != algorithm {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let authorized = match key.pin_policy {
+            PinPolicy::Never => true,
+            PinPolicy::Always => self.pin_verified && pin_just_verified,
+            _ => self.pin_verified,
+        };
+
+        if !authorized {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let template = DynamicAuth::parse(data)?;
+
+        let output = match (&key.key, template.challenge, template.exponentiation) {
+            (PrivateKey::Rsa(key), Some(block), None) => rsa_private_operation(key, block),
+            (PrivateKey::P256(key), Some(digest), None) => {
+                let signature: p256::ecdsa::Signature = p256::ecdsa::SigningKey::from(key)
+                    .sign_prehash(digest)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            (PrivateKey::P384(key), Some(digest), None) => {
+                let signature: p384::ecdsa::Signature = p384::ecdsa::SigningKey::from(key)
+                    .sign_prehash(digest)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                Ok(signature.to_der().as_bytes().to_vec())
+            }
+            (PrivateKey::P256(key), None, Some(point)) => {
+                let public = p256::PublicKey::from_sec1_bytes(point)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                let shared =
+                    p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), public.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+            (PrivateKey::P384(key), None, Some(point)) => {
+                let public = p384::PublicKey::from_sec1_bytes(point)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                let shared =
+                    p384::ecdh::diffie_hellman(key.to_nonzero_scalar(), public.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+            _ => Err(StatusWords::IncorrectParamError),
+        }?;
+
+        Ok(Tlv::encode(0x7c, &Tlv::encode(0x82, &output)))
+    }
+
+    fn generate(&mut self, slot: u8, data: &[u8]) -> Reply {
+        if !self.mgm_authenticated {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let slot_id = match SlotId::try_from(slot) {
+            Ok(SlotId::Management(_)) | Err(_) => return Err(StatusWords::IncorrectSlotError),
+            Ok(slot_id) => slot_id,
+        };
+
+        let (_, template) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+        if template.tag != 0xac {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let mut algorithm = None;
+        let mut pin_policy = PinPolicy::Default;
+        let mut remaining = template.value;
+
+        while !remaining.is_empty() {
+            let (rest, param) =
+                Tlv::parse(remaining).map_err(|_| StatusWords::IncorrectParamError)?;
+
+            match (param.tag, param.value) {
+                (0x80, [id]) => algorithm = AlgorithmId::try_from(*id).ok(),
+                (0xaa, [policy]) => {
+                    pin_policy = PinPolicy::try_from(*policy)
+                        .map_err(|_| StatusWords::IncorrectParamError)?
+                }
+                // Touch is never required
+                (0xab, [_]) => (),
+                _ => return Err(StatusWords::IncorrectParamError),
+            }
+
+            remaining = rest;
+        }
+
+        let algorithm = algorithm.ok_or(StatusWords::IncorrectParamError)?;
+
+        let key = match algorithm {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => {
+                let bits = algorithm.rsa_len().expect("RSA algorithm") * 8;
+
+                let key = RsaPrivateKey::new(&mut self.rng, bits)
+                    .map_err(|_| StatusWords::CommandAbortedError)?;
+                PrivateKey::Rsa(Box::new(key))
+            }
+            AlgorithmId::EccP256 => PrivateKey::P256(p256::SecretKey::random(&mut self.rng)),
+            AlgorithmId::EccP384 => PrivateKey::P384(p384::SecretKey::random(&mut self.rng)),
+            // Not supported by the emulated firmware
+            AlgorithmId::Ed25519 | AlgorithmId::X25519 => {
+                return Err(StatusWords::IncorrectParamError)
+            }
+        };
+
+        let public = public_key(&key);
+        self.keys.insert(
+            slot,
+            SlotKey {
+                algorithm,
+                key,
+                pin_policy: slot_id.resolve_pin_policy(pin_policy),
+                origin: Origin::Generated,
+            },
+        );
+
+        Ok(Tlv::encode(0x7f49, &public))
+    }
+
+    fn import_key(&mut self, algorithm: u8, slot: u8, data: &[u8]) -> Reply {
+        if !self.mgm_authenticated {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let slot_id = match SlotId::try_from(slot) {
+            Ok(SlotId::Management(_)) | Err(_) => return Err(StatusWords::IncorrectSlotError),
+            Ok(slot_id) => slot_id,
+        };
+
+        let algorithm =
+            AlgorithmId::try_from(algorithm).map_err(|_| StatusWords::NotSupportedError)?;
+
+        let mut primes = vec![];
+        let mut scalar = None;
+        let mut pin_policy = PinPolicy::Default;
+        let mut remaining = data;
+
+        while !remaining.is_empty() {
+            let (rest, param) =
+                Tlv::parse(remaining).map_err(|_| StatusWords::IncorrectParamError)?;
+
+            match (param.tag, param.value) {
+                (0x01 | 0x02, prime) => primes.push(BigUint::from_bytes_be(prime)),
+                // The CRT parameters are derived from the primes again
+                (0x03..=0x05, _) => (),
+                (0x06, value) => scalar = Some(value),
+                (0xaa, [policy]) => {
+                    pin_policy = PinPolicy::try_from(*policy)
+                        .map_err(|_| StatusWords::IncorrectParamError)?
+                }
+                (0xab, [_]) => (),
+                _ => return Err(StatusWords::IncorrectParamError),
+            }
+
+            remaining = rest;
+        }
+
+        let key = match (algorithm, scalar) {
+            (
+                AlgorithmId::Rsa1024
+                | AlgorithmId::Rsa2048
+                | AlgorithmId::Rsa3072
+                | AlgorithmId::Rsa4096,
+                None,
+            ) => {
+                let bits = algorithm.rsa_len().unwrap_or_default() * 8;
+                let key = RsaPrivateKey::from_primes(primes, BigUint::from(65537u32))
+                    .map_err(|_| StatusWords::DataInvalidError)?;
+
+                if key.n().bits() != bits {
+                    return Err(StatusWords::DataInvalidError);
+                }
+
+                PrivateKey::Rsa(Box::new(key))
+            }
+            (AlgorithmId::EccP256, Some(scalar)) => PrivateKey::P256(
+                p256::SecretKey::from_slice(scalar).map_err(|_| StatusWords::DataInvalidError)?,
+            ),
+            (AlgorithmId::EccP384, Some(scalar)) => PrivateKey::P384(
+                p384::SecretKey::from_slice(scalar).map_err(|_| StatusWords::DataInvalidError)?,
+            ),
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        self.keys.insert(
+            slot,
+            SlotKey {
+                algorithm,
+                key,
+                pin_policy: slot_id.resolve_pin_policy(pin_policy),
+                origin: Origin::Imported,
+            },
+        );
+
+        Ok(vec![])
+    }
+
+    /// GET METADATA (firmware 5.3): the algorithm, policies, origin and public
+    /// key of the key in a slot, or whether the PIN, PUK or management key
+    /// are the default ones.
+    fn get_metadata(&self, slot: u8) -> Reply {
+        let touch_policy = TouchPolicy::Never.into();
+
+        let metadata = match slot {
+            PIN_REF | PUK_REF => {
+                let (default, tries, remaining) = if slot == PIN_REF {
+                    (self.pin == DEFAULT_PIN, self.pin_tries, self.pin_remaining)
+                } else {
+                    (self.puk == DEFAULT_PUK, self.puk_tries, self.puk_remaining)
+                };
+
+                [
+                    Tlv::encode(0x01, &[0xff]),
+                    Tlv::encode(0x05, &[u8::from(default)]),
+                    Tlv::encode(0x06, &[tries, remaining]),
+                ]
+                .concat()
+            }
+            MGM_REF => {
+                let default = self.mgm_algorithm == 0x03 && self.mgm_key == DEFAULT_MGM_KEY;
+
+                [
+                    Tlv::encode(0x01, &[self.mgm_algorithm]),
+                    Tlv::encode(0x02, &[PinPolicy::Never.into(), touch_policy]),
+                    Tlv::encode(0x05, &[u8::from(default)]),
+                ]
+                .concat()
+            }
+            _ => {
+                let key = self
+                    .keys
+                    .get(&slot)
+                    .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+                [
+                    Tlv::encode(0x01, &[key.algorithm.into()]),
+                    Tlv::encode(0x02, &[key.pin_policy.into(), touch_policy]),
+                    Tlv::encode(0x03, &[key.origin.into()]),
+                    Tlv::encode(0x04, &public_key(&key.key)),
+                ]
+                .concat()
+            }
+        };
+
+        Ok(metadata)
+    }
+
+    fn get_data(&mut self, data: &[u8]) -> Reply {
+        let (_, object_id) = parse_object_id(data)?;
+
+        self.objects
+            .get(&object_id)
+            .map(|object| Tlv::encode(0x53, object))
+            .ok_or(StatusWords::NotFoundError)
+    }
+
+    fn put_data(&mut self, data: &[u8]) -> Reply {
+        if !self.mgm_authenticated {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let (data, object_id) = parse_object_id(data)?;
+        let (_, object) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+
+        if object.tag != 0x53 {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        // Writing an empty object deletes it
+        if object.value.is_empty() {
+            self.objects.remove(&object_id);
+        } else {
+            self.objects.insert(object_id, object.value.to_vec());
+        }
+
+        Ok(vec![])
+    }
+
+    fn set_mgm_key(&mut self, data: &[u8]) -> Reply {
+        if !self.mgm_authenticated {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let (algorithm, key) = match data {
+            [algorithm, MGM_REF, len, key @ ..] if usize::from(*len) == key.len() => {
+                (*algorithm, key)
+            }
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        let key_len = match algorithm {
+            0x03 | 0x0a => 24,
+            0x08 => 16,
+            0x0c => 32,
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        if key.len() != key_len {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        self.mgm_algorithm = algorithm;
+        self.mgm_key = key.to_vec();
+        Ok(vec![])
+    }
+
+    fn set_pin_retries(&mut self, pin_tries: u8, puk_tries: u8) -> Reply {
+        if !self.mgm_authenticated || !self.pin_verified {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        if pin_tries == 0 || puk_tries == 0 {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        // Setting the retries also resets the PIN and PUK
+        self.pin = DEFAULT_PIN.into();
+        self.puk = DEFAULT_PUK.into();
+        self.pin_tries = pin_tries;
+        self.pin_remaining = pin_tries;
+        self.puk_tries = puk_tries;
+        self.puk_remaining = puk_tries;
+        Ok(vec![])
+    }
+
+    fn reset(&mut self) -> Reply {
+        // The PIV application can only be reset once the PIN and PUK are blocked
+        if self.pin_remaining != 0 || self.puk_remaining != 0 {
+            return Err(StatusWords::ConditionsNotSatisfiedError);
+        }
+
+        // The secure channel belongs to the security domain, not to PIV
+        let scp03_keys = self.scp03_keys.take();
+        let scp11 = self.scp11.take();
+        let secure_session = self.secure_session.take();
+        // The randomness isn't reset, so seeded runs stay reproducible
+        let rng = std::mem::take(&mut self.rng);
+        let (commands, faults) = (self.commands, std::mem::take(&mut self.faults));
+
+        *self = Self::new(self.serial);
+        self.scp03_keys = scp03_keys;
+        self.scp11 = scp11;
+        self.secure_session = secure_session;
+        self.rng = rng;
+        self.commands = commands;
+        self.faults = faults;
+        Ok(vec![])
+    }
+
+    fn initialize_update(&mut self, key_version: u8, host_challenge: &[u8]) -> Reply {
+        let card_challenge = self.random(8);
+        let keys = self
+            .scp03_keys
+            .as_ref()
+            .ok_or(StatusWords::NotSupportedError)?;
+
+        if key_version != 0 && key_version != keys.key_version() {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        if host_challenge.len() != 8 {
+            return Err(StatusWords::WrongLengthError);
+        }
+
+        let context = [host_challenge, &card_challenge].concat();
+        let session = Session::new(keys, &context);
+
+        // Key diversification data, key information, card challenge and
+        // card cryptogram
+        let mut response = vec![0u8; 10];
+        response.extend_from_slice(&[keys.key_version(), scp03::SCP03, 0x00]);
+        response.extend_from_slice(&card_challenge);
+        response.extend_from_slice(&session.cryptogram(scp03::DERIVE_CARD_CRYPTOGRAM, &context));
+
+        let host_cryptogram = session.cryptogram(scp03::DERIVE_HOST_CRYPTOGRAM, &context);
+        self.secure_session = None;
+        self.scp03_pending = Some((session, host_cryptogram));
+        Ok(response)
+    }
+
+    fn external_authenticate(&mut self, cla: u8, security_level: u8, p2: u8, data: &[u8]) -> Reply {
+        let (mut session, host_cryptogram) = self
+            .scp03_pending
+            .take()
+            .ok_or(StatusWords::ConditionsNotSatisfiedError)?;
+
+        if security_level != scp03::SECURITY_LEVEL {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        if data.len() != 16 {
+            return Err(StatusWords::WrongLengthError);
+        }
+
+        let (cryptogram, mac) = data.split_at(8);
+        let header = [
+            cla,
+            scp03::INS_EXTERNAL_AUTHENTICATE,
+            security_level,
+            p2,
+            16,
+        ];
+        let expected = session.command_mac(&[&header[..], cryptogram].concat());
+
+        if cryptogram != host_cryptogram || mac != expected {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        self.secure_session = Some(session);
+        Ok(vec![])
+    }
+
+    fn get_sd_data(&mut self, p1: u8, p2: u8, data: &[u8]) -> Reply {
+        let (_, chain) = self.scp11.as_ref().ok_or(StatusWords::NotSupportedError)?;
+
+        if u16::from_be_bytes([p1, p2]) != scp11::TAG_CERTIFICATE_STORE {
+            return Err(StatusWords::NotFoundError);
+        }
+
+        // Key reference (tag 0x83) within a control reference template
+        match Tlv::parse(data).and_then(|(_, template)| Tlv::parse(template.value)) {
+            Ok((
+                _,
+                Tlv {
+                    tag: 0x83,
+                    value: [scp11::KID_SCP11A | scp11::KID_SCP11B, _],
+                },
+            )) => Ok(chain.clone()),
+            _ => Err(StatusWords::NotFoundError),
+        }
+    }
+
+    fn perform_security_operation(&mut self, p2: u8, data: &[u8]) -> Reply {
+        if p2 & 0x7f != scp11::KID_OCE_CA {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        let certificate =
+            Certificate::from_bytes(data.to_vec()).map_err(|_| StatusWords::DataInvalidError)?;
+
+        // Only the leaf certificate's key is needed
+        if p2 & 0x80 == 0 {
+            let key = certificate.subject_pki().subject_public_key.raw_bytes();
+            self.scp11_oce = Some(
+                p256::PublicKey::from_sec1_bytes(key).map_err(|_| StatusWords::DataInvalidError)?,
+            );
+        }
+
+        Ok(vec![])
+    }
+
+    fn scp11_authenticate(&mut self, mutual: bool, key_id: u8, data: &[u8]) -> Reply {
+        let (key, _) = self.scp11.as_ref().ok_or(StatusWords::NotSupportedError)?;
+
+        let expected_key_id = if mutual {
+            scp11::KID_SCP11A
+        } else {
+            scp11::KID_SCP11B
+        };
+        if key_id != expected_key_id {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        let host_ephemeral = match Tlv::parse(data).and_then(|(rest, _)| Tlv::parse(rest)) {
+            Ok((
+                _,
+                Tlv {
+                    tag: scp11::TAG_EPHEMERAL_KEY,
+                    value,
+                },
+            )) => p256::PublicKey::from_sec1_bytes(value)
+                .map_err(|_| StatusWords::DataInvalidError)?,
+            _ => return Err(StatusWords::DataInvalidError),
+        };
+
+        let static_key = if mutual {
+            self.scp11_oce
+                .take()
+                .ok_or(StatusWords::ConditionsNotSatisfiedError)?
+        } else {
+            host_ephemeral
+        };
+
+        let ephemeral = p256::SecretKey::random(&mut self.rng);
+        let mut shared_secrets =
+            Zeroizing::new(scp11::diffie_hellman(&ephemeral, &host_ephemeral).to_vec());
+        shared_secrets.extend_from_slice(&*scp11::diffie_hellman(key, &static_key));
+
+        let ephemeral_tlv = Tlv::encode(
+            scp11::TAG_EPHEMERAL_KEY,
+            ephemeral.public_key().to_encoded_point(false).as_bytes(),
+        );
+        let (session, receipt) = scp11::session(&shared_secrets, &[data, &ephemeral_tlv].concat());
+
+        self.scp03_pending = None;
+        self.secure_session = Some(session);
+        Ok([ephemeral_tlv, Tlv::encode(scp11::TAG_RECEIPT, &receipt)].concat())
+    }
+}
+
+/// Dynamic authentication template (tag `0x7c`) sent with GENERAL
+/// AUTHENTICATE.
+#[derive(Default)]
+struct DynamicAuth<'a> {
+    witness: Option<&'a [u8]>,
+    challenge: Option<&'a [u8]>,
+    exponentiation: Option<&'a [u8]>,
+}
+
+impl<'a> DynamicAuth<'a> {
+    fn parse(data: &'a [u8]) -> std::result::Result<Self, StatusWords> {
+        let (_, template) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+        if template.tag != 0x7c {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let mut auth = Self::default();
+        let mut remaining = template.value;
+
+        while !remaining.is_empty() {
+            let (rest, item) =
+                Tlv::parse(remaining).map_err(|_| StatusWords::IncorrectParamError)?;
+
+            match item.tag {
+                0x80 => auth.witness = Some(item.value),
+                0x81 => auth.challenge = Some(item.value),
+                0x85 => auth.exponentiation = Some(item.value),
+                // Requests for the response (tag 0x82) are implied
+                _ => (),
+            }
+
+            remaining = rest;
+        }
+
+        Ok(auth)
+    }
+}
+
+/// Serialize a response APDU.
+fn respond(mut data: Vec<u8>, sw: StatusWords) -> Vec<u8> {
+    data.extend_from_slice(&sw.code().to_be_bytes());
+    data
+}
+
+/// Status words of a failed PIN or PUK attempt.
+fn tries_left(remaining: u8) -> StatusWords {
+    match remaining {
+        0 => StatusWords::AuthBlockedError,
+        tries => StatusWords::VerifyFailError { tries },
+    }
+}
+
+/// Strip the `0xff` padding of a PIN or PUK.
+fn unpad(padded: &[u8]) -> &[u8] {
+    let len = padded
+        .iter()
+        .position(|&b| b == 0xff)
+        .unwrap_or(padded.len());
+    &padded[..len]
+}
+
+/// Check a new (padded) PIN or PUK is 6 to 8 characters long.
+fn new_reference(padded: &[u8]) -> std::result::Result<Vec<u8>, StatusWords> {
+    let new = unpad(padded);
+
+    if new.len() < 6 {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    Ok(new.to_vec())
+}
+
+fn mgm_block_size(algorithm: u8) -> usize {
+    match algorithm {
+        0x03 => 8,
+        _ => 16,
+    }
+}
+
+/// Randomness of the emulated YubiKey: the OS RNG, or SHA-256 in counter
+/// mode over a seed.
+#[derive(Default)]
+struct EmulatorRng {
+    seeded: Option<([u8; 32], u64)>,
+}
+
+impl EmulatorRng {
+    fn seeded(seed: u64) -> Self {
+        Self {
+            seeded: Some((Sha256::digest(seed.to_be_bytes()).into(), 0)),
+        }
+    }
+}
+
+impl RngCore for EmulatorRng {
+    fn next_u32(&mut self) -> u32 {
+        impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let (key, counter) = match &mut self.seeded {
+            Some(seeded) => seeded,
+            None => return OsRng.fill_bytes(dest),
+        };
+
+        for chunk in dest.chunks_mut(32) {
+            let block = Sha256::new()
+                .chain_update(*key)
+                .chain_update(counter.to_be_bytes())
+                .finalize();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            *counter += 1;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for EmulatorRng {}
+
+fn encrypt_block<C: BlockEncrypt + KeyInit>(key: &[u8], block: &[u8]) -> Reply {
+    let cipher = C::new_from_slice(key).map_err(|_| StatusWords::CommandAbortedError)?;
+
+    if block.len() != C::block_size() {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    let mut output = block.to_vec();
+    cipher.encrypt_block(GenericArray::from_mut_slice(&mut output));
+    Ok(output)
+}
+
+/// Perform the raw RSA private key operation on a block the size of the
+/// modulus.
+fn rsa_private_operation(key: &RsaPrivateKey, block: &[u8]) -> Reply {
+    let input = BigUint::from_bytes_be(block);
+
+    if block.len() != key.size() || &input >= key.n() {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    let output = input.modpow(key.d(), key.n()).to_bytes_be();
+
+    let mut padded = vec![0u8; key.size() - output.len()];
+    padded.extend(output);
+    Ok(padded)
+}
+
+/// Encode the public key of a private key as in the responses to GENERATE
+/// ASYMMETRIC and GET METADATA.
+fn public_key(key: &PrivateKey) -> Vec<u8> {
+    match key {
+        PrivateKey::Rsa(key) => [
+            Tlv::encode(0x81, &key.n().to_bytes_be()),
+            Tlv::encode(0x82, &key.e().to_bytes_be()),
+        ]
+        .concat(),
+        PrivateKey::P256(key) => {
+            Tlv::encode(0x86, key.public_key().to_encoded_point(false).as_bytes())
+        }
+        PrivateKey::P384(key) => {
+            Tlv::encode(0x86, key.public_key().to_encoded_point(false).as_bytes())
+        }
+    }
+}
+
+/// Parse the object ID tag (`0x5c`) of GET DATA and PUT DATA, returning the
+/// rest of the data.
+fn parse_object_id(data: &[u8]) -> std::result::Result<(&[u8], u32), StatusWords> {
+    let (rest, tag) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+
+    if tag.tag != 0x5c || tag.value.is_empty() || tag.value.len() > 3 {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    let object_id = tag
+        .value
+        .iter()
+        .fold(0, |id, &byte| (id << 8) | u32::from(byte));
+
+    Ok((rest, object_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apdu::Apdu,
+        certificate::{
+            yubikey_signer::{Rsa2048, YubiRsa},
+            CertInfo, Certificate,
+        },
+        middleware::Operation,
+        pin::{PinEscalation, PinProvider},
+        piv::RetiredSlotId,
+        policy::{PinPolicy, TouchPolicy},
+        provisioning::{self, ProvisioningAction, ProvisioningLog},
+        scp03::Scp03Keys,
+        scp11::Scp11Params,
+        wirelog::ApduObserver,
+        ReadOnlyYubiKey,
+    };
+    use p256::{
+        ecdsa::{signature::hazmat::PrehashVerifier, DerSignature, VerifyingKey},
+        pkcs8::EncodePublicKey,
+    };
+    use rsa::RsaPublicKey;
+    use sha2::{Digest, Sha256};
+    use std::{
+        str::FromStr,
+        time::{Instant, SystemTime},
+    };
+    use x509_cert::{
+        builder::{Builder, CertificateBuilder, Profile},
+        der::{referenced::OwnedToRef, Decode, Encode},
+        name::Name,
+        serial_number::SerialNumber,
+        spki::SubjectPublicKeyInfoOwned,
+        time::Validity,
+    };
+
+    fn authenticate(yubikey: &mut YubiKey) {
+        let mgm_key = MgmKey3Des::from_bytes(DEFAULT_MGM_KEY).expect("management key");
+        yubikey.authenticate(mgm_key).expect("authenticate");
+    }
+
+    #[test]
+    fn pin_flows() {
+        let emulator = Emulator::new(Serial(12_345_678));
+        let mut yubikey = emulator.open().expect("open");
+        assert_eq!(yubikey.serial(), Serial(12_345_678));
+
+        assert_eq!(
+            yubikey.verify_pin(b"000000"),
+            Err(Error::WrongPin { tries: 2 })
+        );
+        assert_eq!(yubikey.get_pin_retries(), Ok(2));
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+        assert_eq!(yubikey.get_pin_retries(), Ok(3));
+
+        for tries in (0..3).rev() {
+            assert_eq!(
+                yubikey.verify_pin(b"000000"),
+                Err(Error::WrongPin { tries })
+            );
+        }
+
+        assert_eq!(
+            yubikey.verify_pin(DEFAULT_PIN),
+            Err(Error::WrongPin { tries: 0 })
+        );
+
+        // The emulated YubiKey keeps its state across connections
+        assert!(yubikey.close().is_ok());
+        let mut yubikey = emulator.open().expect("open again");
+        assert_eq!(yubikey.get_pin_retries(), Ok(0));
+    }
+
+    #[test]
+    fn pin_escalation() {
+        struct SlowPinProvider(Duration);
+
+        impl PinProvider for SlowPinProvider {
+            fn pin(&mut self, _serial: Serial) -> Result<Option<Buffer>> {
+                thread::sleep(self.0);
+                Ok(Some(Buffer::new(DEFAULT_PIN.to_vec())))
+            }
+        }
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+
+        yubikey.set_pin_escalation(PinEscalation::FailFast);
+        assert_eq!(
+            yubikey.ensure_pin_verified(PinPolicy::Once),
+            Err(Error::PinRequired)
+        );
+
+        // A slow provider's answer is used once it arrives
+        yubikey.set_pin_provider(Some(Box::new(SlowPinProvider(Duration::from_millis(200)))));
+        yubikey.set_pin_escalation(PinEscalation::Queue);
+        assert_eq!(
+            yubikey.ensure_pin_verified(PinPolicy::Once),
+            Err(Error::PinRequired)
+        );
+        assert!(yubikey.is_pin_pending());
+
+        yubikey.set_pin_escalation(PinEscalation::Deadline(Duration::from_secs(10)));
+        assert!(yubikey.ensure_pin_verified(PinPolicy::Once).is_ok());
+        assert!(yubikey.is_pin_verified());
+        assert!(!yubikey.is_pin_pending());
+    }
+
+    #[test]
+    fn pin_retry_floor() {
+        struct DefaultPinProvider;
+
+        impl PinProvider for DefaultPinProvider {
+            fn pin(&mut self, _serial: Serial) -> Result<Option<Buffer>> {
+                Ok(Some(Buffer::new(DEFAULT_PIN.to_vec())))
+            }
+        }
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        yubikey.set_pin_provider(Some(Box::new(DefaultPinProvider)));
+
+        for tries in [2, 1] {
+            assert_eq!(
+                yubikey.verify_pin(b"000000"),
+                Err(Error::WrongPin { tries })
+            );
+        }
+
+        // The provider isn't trusted with the last try
+        assert_eq!(
+            yubikey.ensure_pin_verified(PinPolicy::Once),
+            Err(Error::PinRequired)
+        );
+        assert_eq!(yubikey.get_pin_retries(), Ok(1));
+
+        yubikey.set_pin_retry_floor(0);
+        assert!(yubikey.ensure_pin_verified(PinPolicy::Once).is_ok());
+        assert_eq!(yubikey.get_pin_retries(), Ok(3));
+    }
+
+    #[test]
+    fn wrong_pin_not_retried() {
+        struct WrongPinProvider(Arc<Mutex<usize>>);
+
+        impl PinProvider for WrongPinProvider {
+            fn pin(&mut self, _serial: Serial) -> Result<Option<Buffer>> {
+                *self.0.lock().expect("lock") += 1;
+                Ok(Some(Buffer::new(b"000000".to_vec())))
+            }
+        }
+
+        let emulator = Emulator::new(Serial(1));
+        let mut yubikey = emulator.open().expect("open");
+        let calls = Arc::new(Mutex::new(0));
+
+        // The provider is removed once its PIN has been rejected
+        yubikey.set_pin_provider(Some(Box::new(WrongPinProvider(Arc::clone(&calls)))));
+        assert_eq!(
+            yubikey.ensure_pin_verified(PinPolicy::Once),
+            Err(Error::WrongPin { tries: 2 })
+        );
+        assert!(yubikey.ensure_pin_verified(PinPolicy::Once).is_ok());
+        assert_eq!(*calls.lock().expect("lock"), 1);
+        assert_eq!(yubikey.get_pin_retries(), Ok(2));
+
+        // So is a cached PIN changed from elsewhere
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        emulator.applet().expect("applet").pin = b"654321".to_vec();
+
+        assert_eq!(
+            yubikey.ensure_pin_verified(PinPolicy::Always),
+            Err(Error::WrongPin { tries: 2 })
+        );
+        assert!(yubikey.ensure_pin_verified(PinPolicy::Always).is_ok());
+        assert_eq!(yubikey.get_pin_retries(), Ok(2));
+    }
+
+    #[test]
+    fn injected_faults() {
+        let emulator = Emulator::new(Serial(1));
+        let mut yubikey = emulator.open().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+
+        // Reads are repeated once the card is reconnected to, and the PIN
+        // verified again
+        emulator.inject_fault(0, Fault::Reset).expect("inject");
+        assert_eq!(
+            Certificate::read(&mut yubikey, SlotId::Authentication).map(|_| ()),
+            Err(Error::InvalidObject)
+        );
+        assert!(yubikey.is_pin_verified());
+
+        // The command in flight when the card leaves the field fails, and so
+        // does the next operation, which finds the card reset
+        emulator.inject_fault(0, Fault::FieldLoss).expect("inject");
+        for error in [Error::CardRemoved, Error::CardReset] {
+            assert_eq!(yubikey.verify_pin(DEFAULT_PIN), Err(error));
+        }
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+
+        emulator
+            .inject_fault(0, Fault::Status(0x6982))
+            .expect("inject");
+        assert_eq!(yubikey.verify_pin(DEFAULT_PIN), Err(Error::GenericError));
+
+        emulator
+            .inject_fault(0, Fault::Truncate(1))
+            .expect("inject");
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_err());
+
+        let delay = Duration::from_millis(50);
+        emulator
+            .inject_fault(0, Fault::Delay(delay))
+            .expect("inject");
+        let start = Instant::now();
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+        assert!(start.elapsed() >= delay);
+
+        // Only the given command fails
+        emulator
+            .inject_fault(1, Fault::Status(0x6982))
+            .expect("inject");
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+        assert_eq!(yubikey.verify_pin(DEFAULT_PIN), Err(Error::GenericError));
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+    }
+
+    #[test]
+    fn remote_transport() {
+        use crate::remote::{self, RemoteTransport};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("address");
+        let emulator = Emulator::new(Serial(1));
+
+        let mut served = emulator.clone();
+        let agent = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            let reader = stream.try_clone().expect("clone");
+            remote::serve(&mut served, reader, stream)
+        });
+
+        let transport = RemoteTransport::connect(addr).expect("connect");
+        let mut yubikey = YubiKey::open_with_transport(transport, "remote").expect("open");
+        assert_eq!(yubikey.serial(), Serial(1));
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+        assert_eq!(
+            yubikey.verify_pin(b"000000"),
+            Err(Error::WrongPin { tries: 2 })
+        );
+
+        // Card resets are reported as such, and recovered from
+        emulator.reset_card().expect("reset");
+        assert_eq!(yubikey.get_pin_retries(), Ok(2));
+
+        assert!(yubikey.close().is_ok());
+        assert_eq!(agent.join().expect("agent"), Ok(()));
+    }
+
+    #[test]
+    fn recover_from_card_reset() {
+        let emulator = Emulator::new(Serial(1));
+        let mut yubikey = emulator.open().expect("open");
+
+        // Reads are repeated once the card is reconnected to
+        emulator.reset_card().expect("reset");
+        assert_eq!(yubikey.get_pin_retries(), Ok(3));
+
+        // The cached PIN is verified again
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+        emulator.reset_card().expect("reset");
+        assert_eq!(
+            piv::metadata(&mut yubikey, SlotId::Authentication).err(),
+            Some(Error::NotFound)
+        );
+        assert!(yubikey.is_pin_verified());
+
+        // Recovering is attempted again if it fails
+        emulator.reset_card().expect("reset");
+        emulator
+            .inject_fault(0, Fault::Status(0x6a82))
+            .expect("inject");
+        assert_eq!(
+            piv::metadata(&mut yubikey, SlotId::Authentication).err(),
+            Some(Error::AppletNotFound { applet_name: "PIV" })
+        );
+        assert!(yubikey.card_reset.get());
+        assert_eq!(
+            piv::metadata(&mut yubikey, SlotId::Authentication).err(),
+            Some(Error::NotFound)
+        );
+        assert!(!yubikey.card_reset.get());
+        assert!(yubikey.is_pin_verified());
+
+        // Without a cached PIN, the lost PIN verification is reported
+        yubikey.pin = None;
+        emulator.reset_card().expect("reset");
+        assert_eq!(
+            piv::metadata(&mut yubikey, SlotId::Authentication).err(),
+            Some(Error::SessionLost)
+        );
+        assert!(!yubikey.is_pin_verified());
+    }
+
+    #[test]
+    fn apdu_observer() {
+        #[derive(Clone, Default)]
+        struct Apdus(Arc<Mutex<Vec<Vec<u8>>>>);
+
+        impl ApduObserver for Apdus {
+            fn command(&mut self, apdu: &[u8]) {
+                self.0.lock().expect("poisoned").push(apdu.to_vec());
+            }
+
+            fn response(&mut self, apdu: &[u8]) {
+                self.0.lock().expect("poisoned").push(apdu.to_vec());
+            }
+        }
+
+        let apdus = Apdus::default();
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        yubikey.set_apdu_observer(Some(Box::new(apdus.clone())));
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        yubikey.set_apdu_observer(None);
+        yubikey.get_pin_retries().expect("PIN retries");
+
+        let apdus = apdus.0.lock().expect("poisoned");
+        let verify = apdus
+            .iter()
+            .find(|apdu| apdu.get(1) == Some(&Ins::Verify.code()) && apdu.len() > 5)
+            .expect("VERIFY observed");
+        assert!(verify[5..].iter().all(|&byte| byte == 0));
+        assert_eq!(apdus.last().map(Vec::as_slice), Some(&[0x90, 0x00][..]));
+    }
+
+    #[test]
+    fn generate_requires_firmware() {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        // RSA 3072 and 4096, Ed25519 and X25519 require firmware 5.7
+        for algorithm in [
+            AlgorithmId::Rsa3072,
+            AlgorithmId::Rsa4096,
+            AlgorithmId::Ed25519,
+            AlgorithmId::X25519,
+        ] {
+            assert_eq!(
+                piv::generate(
+                    &mut yubikey,
+                    SlotId::Authentication,
+                    algorithm,
+                    PinPolicy::Default,
+                    TouchPolicy::Default,
+                ),
+                Err(Error::AlgorithmError)
+            );
+        }
+    }
+
+    #[test]
+    fn move_and_delete_key_require_firmware() {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        assert_eq!(
+            piv::move_key(
+                &mut yubikey,
+                SlotId::Retired(RetiredSlotId::R1),
+                SlotId::Authentication
+            ),
+            Err(Error::NotSupported)
+        );
+        assert_eq!(
+            piv::delete_key(&mut yubikey, SlotId::Authentication),
+            Err(Error::NotSupported)
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Authentication;
+
+        assert_eq!(
+            piv::metadata(&mut yubikey, slot).err(),
+            Some(Error::NotFound)
+        );
+
+        authenticate(&mut yubikey);
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let metadata = piv::metadata(&mut yubikey, slot).expect("metadata");
+        assert_eq!(
+            metadata.algorithm,
+            piv::ManagementAlgorithmId::Asymmetric(AlgorithmId::EccP256)
+        );
+        assert_eq!(metadata.policy, Some((PinPolicy::Once, TouchPolicy::Never)));
+        assert_eq!(metadata.origin, Some(Origin::Generated));
+        assert_eq!(metadata.public, Some(public_key));
+
+        assert!(yubikey.verify_pin(b"000000").is_err());
+        let pin = yubikey.pin_metadata().expect("PIN metadata");
+        assert!(pin.default);
+        assert_eq!((pin.total_retries, pin.remaining_retries), (3, 2));
+
+        let mgm = yubikey.mgm_metadata().expect("management key metadata");
+        assert!(mgm.default);
+        assert_eq!(mgm.touch_policy, TouchPolicy::Never);
+    }
+
+    #[test]
+    fn generate_and_sign() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Authentication;
+
+        assert_eq!(
+            piv::generate(
+                &mut yubikey,
+                slot,
+                AlgorithmId::EccP256,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            ),
+            Err(Error::AuthenticationError)
+        );
+
+        authenticate(&mut yubikey);
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let digest = Sha256::digest(b"emulated");
+        assert_eq!(
+            piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, slot),
+            Err(Error::AuthenticationError)
+        );
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        let signature =
+            piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, slot).expect("sign");
+
+        let verifying_key =
+            VerifyingKey::from_sec1_bytes(public_key.subject_public_key.raw_bytes())
+                .expect("public key");
+        let signature = DerSignature::try_from(&signature[..]).expect("signature");
+        assert!(verifying_key.verify_prehash(&digest, &signature).is_ok());
+    }
+
+    #[test]
+    fn key_usage_policy() {
+        use crate::usage::{KeyUsage, KeyUsagePolicy};
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::KeyManagement;
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+        piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let digest = Sha256::digest(b"emulated");
+        yubikey.set_key_usage_policy(KeyUsagePolicy::strict());
+        assert_eq!(
+            piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, slot),
+            Err(Error::OperationDenied)
+        );
+
+        yubikey.set_key_usage_policy(KeyUsagePolicy::strict().allow(slot, KeyUsage::Sign));
+        assert!(piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, slot).is_ok());
+    }
+
+    #[test]
+    fn seeded_key_generation() {
+        fn generate(emulator: Emulator, algorithm: AlgorithmId) -> SubjectPublicKeyInfoOwned {
+            let mut yubikey = emulator.open_authenticated().expect("open");
+            piv::generate(
+                &mut yubikey,
+                SlotId::Signature,
+                algorithm,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            )
+            .expect("generate")
+        }
+
+        for algorithm in [AlgorithmId::EccP256, AlgorithmId::Rsa1024] {
+            let key = generate(Emulator::new(Serial(1)).with_seed(42), algorithm);
+            assert_eq!(
+                generate(Emulator::new(Serial(1)).with_seed(42), algorithm),
+                key
+            );
+            assert_ne!(
+                generate(Emulator::new(Serial(1)).with_seed(43), algorithm),
+                key
+            );
+            assert_ne!(generate(Emulator::new(Serial(1)), algorithm), key);
+        }
+    }
+
+    #[test]
+    fn rsa_private_operation() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::KeyManagement;
+
+        authenticate(&mut yubikey);
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::Rsa1024,
+            PinPolicy::Never,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        let public_key = RsaPublicKey::try_from(public_key.owned_to_ref()).expect("public key");
+
+        let mut block = [0x5a; 128];
+        block[0] = 0;
+        let output =
+            piv::sign_data(&mut yubikey, &block, AlgorithmId::Rsa1024, slot).expect("sign");
+
+        let recovered = BigUint::from_bytes_be(&output).modpow(public_key.e(), public_key.n());
+        assert_eq!(recovered.to_bytes_be(), &block[1..]);
+    }
+
+    #[test]
+    fn write_certificate() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Retired(RetiredSlotId::R1);
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let cert = Certificate::generate_self_signed::<_, p256::NistP256>(
+            &mut yubikey,
+            slot,
+            SerialNumber::from(42u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str("CN=emulated").expect("name"),
+            public_key,
+            |_builder| Ok(()),
+        )
+        .expect("self-signed certificate");
+
+        assert!(cert.verify_self_signed().is_ok());
+
+        let read = Certificate::read(&mut yubikey, slot).expect("read certificate");
+        assert_eq!(read.as_der(), cert.as_der());
+
+        cert.write(&mut yubikey, SlotId::Signature, CertInfo::Uncompressed)
+            .expect("write certificate");
+        assert!(Certificate::read(&mut yubikey, SlotId::Signature).is_ok());
+
+        // Writing the same certificate again is only skipped when asked to
+        assert!(!cert
+            .write_if_changed(&mut yubikey, SlotId::Signature, CertInfo::Uncompressed)
+            .expect("write certificate"));
+        assert!(cert
+            .write_if_changed(&mut yubikey, SlotId::Signature, CertInfo::Gzip)
+            .expect("write certificate"));
+
+        // Without extended APDUs, the certificate is sent with command chaining
+        assert!(yubikey.uses_extended_apdus());
+        yubikey
+            .set_extended_apdus(false)
+            .expect("disable extended APDUs");
+
+        cert.write(&mut yubikey, SlotId::KeyManagement, CertInfo::Uncompressed)
+            .expect("write certificate");
+        let read = Certificate::read(&mut yubikey, SlotId::KeyManagement).expect("read");
+        assert_eq!(read.as_der(), cert.as_der());
+
+        // Modifications of the inner certificate are written
+        let mut modified = cert.clone();
+        modified.cert.tbs_certificate.serial_number = SerialNumber::from(43u32);
+        assert_eq!(modified.as_der(), cert.as_der());
+        assert!(modified.verify_self_signed().is_err());
+
+        modified
+            .write(&mut yubikey, SlotId::KeyManagement, CertInfo::Uncompressed)
+            .expect("write certificate");
+        let read = Certificate::read(&mut yubikey, SlotId::KeyManagement).expect("read");
+        assert_eq!(read.cert, modified.cert);
+        assert_ne!(read.as_der(), cert.as_der());
+    }
+
+    #[test]
+    fn migrate_algorithm() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Signature;
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::Rsa2048,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        Certificate::generate_self_signed::<_, YubiRsa<Rsa2048>>(
+            &mut yubikey,
+            slot,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str("CN=migrated").expect("name"),
+            public_key,
+            |_builder| Ok(()),
+        )
+        .expect("RSA certificate");
+
+        // The certificate must be for the new key
+        let previous = Certificate::read(&mut yubikey, slot).expect("read");
+        assert_eq!(
+            provisioning::migrate_algorithm(
+                &mut yubikey,
+                slot,
+                AlgorithmId::EccP256,
+                |_, _, _| Ok(previous.clone()),
+            )
+            .map(|_| ()),
+            Err(Error::KeyError)
+        );
+
+        let migration = provisioning::migrate_algorithm(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            |yubikey, public_key, previous| {
+                let subject = &previous.expect("previous certificate").cert;
+                Certificate::generate_self_signed::<_, p256::NistP256>(
+                    yubikey,
+                    slot,
+                    SerialNumber::from(2u32),
+                    Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+                    subject.tbs_certificate.subject.clone(),
+                    public_key.clone(),
+                    |_builder| Ok(()),
+                )
+            },
+        )
+        .expect("migrate");
+
+        assert_eq!(migration.certificate.subject(), "CN=migrated");
+        assert!(migration.previous_certificate.is_some());
+
+        let read = Certificate::read(&mut yubikey, slot).expect("read");
+        assert_eq!(read.as_der(), migration.certificate.as_der());
+        assert!(read.verify_self_signed().is_ok());
+    }
+
+    #[test]
+    fn trust_store() {
+        use crate::truststore::{CertificateSource, TrustStore};
+        use x509_cert::ext::pkix::BasicConstraints;
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+
+        let mut certificates = vec![];
+        for (slot, ca) in [
+            (SlotId::Retired(RetiredSlotId::R1), true),
+            (SlotId::Authentication, false),
+        ] {
+            let public_key = piv::generate(
+                &mut yubikey,
+                slot,
+                AlgorithmId::EccP256,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            )
+            .expect("generate");
+            let certificate = Certificate::generate_self_signed::<_, p256::NistP256>(
+                &mut yubikey,
+                slot,
+                SerialNumber::from(1u32),
+                Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+                Name::from_str("CN=trust").expect("name"),
+                public_key,
+                |builder| {
+                    if ca {
+                        let constraints = BasicConstraints {
+                            ca: true,
+                            path_len_constraint: None,
+                        };
+                        builder
+                            .add_extension(&constraints)
+                            .map_err(|_| der::ErrorKind::Failed)?;
+                    }
+                    Ok(())
+                },
+            )
+            .expect("certificate");
+
+            assert_eq!(certificate.is_ca(), ca);
+            certificates.push(certificate);
+        }
+
+        #[cfg(feature = "untested")]
+        {
+            // PKCS#7 SignedData holding the certificate in the Authentication slot
+            let signed_data = [
+                Tlv::encode(0x02, &[1]),
+                Tlv::encode(0x31, &[]),
+                Tlv::encode(
+                    0x30,
+                    &Tlv::encode(0x06, &[42, 134, 72, 134, 247, 13, 1, 7, 1]),
+                ),
+                Tlv::encode(0xa0, certificates[1].as_der()),
+                Tlv::encode(0x31, &[]),
+            ]
+            .concat();
+            let content_info = Tlv::encode(
+                0x30,
+                &[
+                    Tlv::encode(0x06, &[42, 134, 72, 134, 247, 13, 1, 7, 2]),
+                    Tlv::encode(0xa0, &Tlv::encode(0x30, &signed_data)),
+                ]
+                .concat(),
+            );
+
+            let msroots = crate::MsRoots::new(content_info).expect("msroots");
+            assert_eq!(msroots.certificates().expect("certificates").len(), 1);
+            msroots.write(&mut yubikey).expect("write msroots");
+        }
+
+        let store = TrustStore::collect(&mut yubikey).expect("collect");
+        let sources: Vec<_> = store.certificates().iter().map(|c| c.source).collect();
+        assert_eq!(
+            sources[0],
+            CertificateSource::Slot(SlotId::Retired(RetiredSlotId::R1))
+        );
+        assert_eq!(
+            store.certificates()[0].certificate.as_der(),
+            certificates[0].as_der()
+        );
+
+        #[cfg(feature = "untested")]
+        assert_eq!(sources[1..], [CertificateSource::MsRoots]);
+        #[cfg(not(feature = "untested"))]
+        assert_eq!(sources.len(), 1);
+
+        let pem = store.to_pem().expect("PEM");
+        assert_eq!(
+            pem.matches("-----BEGIN CERTIFICATE-----").count(),
+            sources.len()
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        let slot = SlotId::Authentication;
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        let certificate = Certificate::generate_self_signed::<_, p256::NistP256>(
+            &mut yubikey,
+            slot,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str("CN=read-only").expect("name"),
+            public_key,
+            |_builder| Ok(()),
+        )
+        .expect("certificate");
+
+        let mut yubikey = ReadOnlyYubiKey::from(yubikey);
+        assert_eq!(yubikey.serial(), Serial(1));
+        assert_eq!(yubikey.get_pin_retries().expect("retries"), 3);
+        assert_eq!(
+            yubikey.certificate(slot).expect("read").as_der(),
+            certificate.as_der()
+        );
+
+        assert_eq!(yubikey.piv_keys().expect("keys").len(), 1);
+    }
+
+    #[test]
+    fn attestation_certificate() {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        assert!(piv::attestation_certificate(&mut yubikey).is_err());
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            SlotId::Authentication,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        let certificate = Certificate::generate_self_signed::<_, p256::NistP256>(
+            &mut yubikey,
+            SlotId::Authentication,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str("CN=attestation").expect("name"),
+            public_key,
+            |_builder| Ok(()),
+        )
+        .expect("certificate");
+        certificate
+            .write(&mut yubikey, SlotId::Attestation, CertInfo::Uncompressed)
+            .expect("write");
+
+        assert_eq!(
+            piv::attestation_certificate(&mut yubikey)
+                .expect("read")
+                .as_der(),
+            certificate.as_der()
+        );
+    }
+
+    #[test]
+    fn scoped_key() {
+        use crate::{scoped::SharedYubiKey, usage::KeyUsage, verify};
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        let slot = SlotId::Signature;
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Never,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        Certificate::generate_self_signed::<_, p256::NistP256>(
+            &mut yubikey,
+            slot,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str("CN=scoped").expect("name"),
+            public_key.clone(),
+            |_builder| Ok(()),
+        )
+        .expect("certificate");
+
+        let yubikey = SharedYubiKey::from(yubikey);
+        let signer = yubikey.scoped(slot, KeyUsage::Sign).clone();
+        assert_eq!(signer.public_key().expect("public key"), public_key);
+
+        let signature = signer.sign(b"message").expect("sign");
+        assert!(verify::ecdsa::<Sha256>(public_key.owned_to_ref(), b"message", &signature).is_ok());
+
+        let decryptor = yubikey.scoped(slot, KeyUsage::Decrypt);
+        assert_eq!(decryptor.sign(b"message"), Err(Error::OperationDenied));
+        assert_eq!(yubikey.lock().serial(), Serial(1));
+    }
+
+    #[test]
+    fn dry_run() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Authentication;
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+        yubikey.set_dry_run(true);
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        assert!(p256::PublicKey::try_from(public_key.owned_to_ref()).is_ok());
+        ProvisioningLog::append(&mut yubikey, "alice", ProvisioningAction::GenerateKey, None)
+            .expect("append");
+        assert_eq!(
+            crate::SlotLabels::default().write(&mut yubikey),
+            Err(Error::OperationDenied)
+        );
+
+        // So do commands of other applications not known to only read
+        let txn = yubikey.begin_transaction().expect("transaction");
+        assert_eq!(
+            Apdu::new(crate::oath::INS_PUT)
+                .data([0x71, 0x01, 0x61])
+                .transmit(&txn, 261)
+                .map(|_| ()),
+            Err(Error::OperationDenied)
+        );
+        drop(txn);
+
+        assert_eq!(yubikey.planned_operations().len(), 2);
+        assert_eq!(
+            yubikey.planned_operations()[0],
+            Operation::Generate {
+                slot,
+                algorithm: AlgorithmId::EccP256
+            }
+        );
+        assert!(ProvisioningLog::read(&mut yubikey)
+            .expect("read")
+            .entries()
+            .is_empty());
+
+        yubikey.set_dry_run(false);
+        assert!(yubikey.planned_operations().is_empty());
+        assert!(piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn device_info_keeps_pin_verified() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+
+        // The Management application isn't emulated, but PIV is reselected
+        assert!(yubikey.device_info().is_err());
+        assert!(yubikey.is_pin_verified());
+        assert!(yubikey.verify_pin(b"").is_ok());
+    }
+
+    #[test]
+    fn admin_drop_keeps_pin_verified() {
+        use crate::role::Operator;
+
+        let mut operator = Operator::new(Emulator::new(Serial(1)).open().expect("open"));
+        operator.verify_pin(DEFAULT_PIN).expect("verify PIN");
+
+        let mgm_key = MgmKey3Des::from_bytes(DEFAULT_MGM_KEY).expect("management key");
+        let mut admin = operator.elevate(mgm_key).expect("elevate");
+        assert!(admin
+            .begin_transaction()
+            .expect("txn")
+            .save_object(0x005f_c10d, b"data")
+            .is_ok());
+        drop(admin);
+
+        let mut yubikey = operator.into_inner();
+        assert!(yubikey.is_pin_verified());
+        assert!(yubikey.verify_pin(b"").is_ok());
+        assert!(yubikey
+            .begin_transaction()
+            .expect("txn")
+            .save_object(0x005f_c10d, b"data")
+            .is_err());
+
+        // A PIN which wasn't verified isn't verified on drop
+        let mut operator = Operator::new(Emulator::new(Serial(2)).open().expect("open"));
+        let mgm_key = MgmKey3Des::from_bytes(DEFAULT_MGM_KEY).expect("management key");
+        drop(operator.elevate(mgm_key).expect("elevate"));
+
+        let mut yubikey = operator.into_inner();
+        assert!(!yubikey.is_pin_verified());
+        assert!(yubikey.verify_pin(b"").is_err());
+    }
+
+    #[test]
+    fn conformance_mode_discards_slot_policies() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Signature;
+        let defaults = (slot.default_pin_policy(), slot.default_touch_policy());
+
+        // As cached from the slot metadata
+        yubikey
+            .slot_policies
+            .insert(slot, (PinPolicy::Never, TouchPolicy::Always));
+
+        yubikey.set_conformance_mode(true);
+        assert_eq!(yubikey.slot_policy(slot), Ok(defaults));
+    }
+
+    #[test]
+    fn write_sequence_dry_run() {
+        use crate::journal::{Journal, WriteSequence};
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.set_dry_run(true);
+
+        let mut sequence = WriteSequence::new();
+        sequence.write(0x005f_c10d, b"data").expect("write");
+        sequence.commit(&mut yubikey).expect("commit");
+        assert_eq!(yubikey.planned_operations(), [Operation::WriteSequence]);
+
+        yubikey.set_dry_run(false);
+        assert_eq!(Journal::read(&mut yubikey), Ok(None));
+        assert_eq!(
+            yubikey
+                .begin_transaction()
+                .expect("txn")
+                .fetch_object(0x005f_c10d),
+            Err(Error::NotFound)
+        );
+    }
+
+    #[test]
+    fn write_sequence_storage() {
+        use crate::{
+            consts::{CB_OBJ_MAX, OBJ_JOURNAL_BACKUP},
+            journal::{Journal, WriteSequence},
+            ObjectId,
+        };
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        let object_ids: Vec<ObjectId> = (0x005f_c10d..0x005f_c116).collect();
+        let data = vec![0x42; CB_OBJ_MAX];
+
+        // Written without anything to back up
+        let mut sequence = WriteSequence::new();
+        for object_id in &object_ids {
+            sequence.write(*object_id, &data).expect("write");
+        }
+        sequence.commit(&mut yubikey).expect("commit");
+
+        // Backing them up while writing as much again doesn't fit
+        let mut sequence = WriteSequence::new();
+        for object_id in &object_ids {
+            sequence
+                .write(*object_id, &[0x43; CB_OBJ_MAX])
+                .expect("write");
+        }
+        assert_eq!(sequence.commit(&mut yubikey), Err(Error::SizeError));
+
+        let txn = yubikey.begin_transaction().expect("txn");
+        assert_eq!(
+            txn.fetch_object(object_ids[0]).expect("fetch").as_slice(),
+            data.as_slice()
+        );
+        assert!(txn
+            .fetch_object(OBJ_JOURNAL_BACKUP)
+            .map_or(true, |backup| backup.is_empty()));
+        drop(txn);
+        assert_eq!(Journal::read(&mut yubikey), Ok(None));
+
+        // A smaller sequence fits, and its backups are cleared
+        let mut sequence = WriteSequence::new();
+        sequence.write(object_ids[0], b"data").expect("write");
+        sequence.commit(&mut yubikey).expect("commit");
+
+        let txn = yubikey.begin_transaction().expect("txn");
+        assert_eq!(
+            txn.fetch_object(object_ids[0]).expect("fetch").as_slice(),
+            b"data"
+        );
+        assert!(txn
+            .fetch_object(OBJ_JOURNAL_BACKUP)
+            .map_or(true, |backup| backup.is_empty()));
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn pin_management_is_destructive() {
+        use crate::guard::DestructiveGuard;
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+
+        yubikey.add_middleware(DestructiveGuard::new(Serial(1), []));
+        assert_eq!(yubikey.set_pin_retries(5, 5), Err(Error::OperationDenied));
+        assert_eq!(
+            yubikey.change_puk(b"12345678", b"87654321"),
+            Err(Error::OperationDenied)
+        );
+        assert_eq!(yubikey.block_puk(), Err(Error::OperationDenied));
+        assert_eq!(
+            YubiKey::set_pin_last_changed(&mut yubikey),
+            Err(Error::OperationDenied)
+        );
+
+        yubikey.clear_middleware();
+        yubikey.set_dry_run(true);
+        assert!(yubikey.set_pin_retries(5, 5).is_ok());
+        assert!(yubikey.block_puk().is_ok());
+        assert_eq!(
+            yubikey.planned_operations(),
+            [Operation::SetPinRetries, Operation::BlockPuk]
+        );
+
+        yubikey.set_dry_run(false);
+        assert_eq!(yubikey.get_pin_retries(), Ok(3));
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn recover_blocked_pin() {
+        use crate::recovery::{self, RecoveryState, RecoveryStep};
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        assert_eq!(
+            RecoveryState::assess(&mut yubikey),
+            Ok(RecoveryState::Unblocked { pin_retries: 3 })
+        );
+
+        for _ in 0..3 {
+            assert!(yubikey.verify_pin(b"000000").is_err());
+        }
+
+        // The PUK retries are read from its metadata
+        let state = RecoveryState::assess(&mut yubikey).expect("assess");
+        assert_eq!(
+            state,
+            RecoveryState::PinBlocked {
+                puk_retries: Some(3)
+            }
+        );
+        assert_eq!(state.next_steps(), [RecoveryStep::UnblockPin]);
+
+        assert_eq!(
+            recovery::unblock_pin(&mut yubikey, DEFAULT_PUK, b"654321"),
+            Ok(RecoveryState::Unblocked { pin_retries: 3 })
+        );
+        assert!(yubikey.verify_pin(b"654321").is_ok());
+
+        // Resetting is refused until the PIN is blocked
+        assert_eq!(recovery::reset_piv(&mut yubikey), Err(Error::ArgumentError));
+
+        for _ in 0..3 {
+            assert!(yubikey.verify_pin(b"000000").is_err());
+        }
+        yubikey.block_puk().expect("block PUK");
+
+        assert_eq!(
+            recovery::reset_piv(&mut yubikey),
+            Ok(RecoveryState::Unblocked { pin_retries: 3 })
+        );
+        assert!(yubikey.verify_pin(DEFAULT_PIN).is_ok());
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn admin_metadata() {
+        use crate::{inventory::CredentialStatus, AdminMetadata, MgmType};
+        use std::time::UNIX_EPOCH;
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        assert_eq!(
+            AdminMetadata::read(&mut yubikey).expect("read"),
+            AdminMetadata::default()
+        );
+
+        authenticate(&mut yubikey);
+
+        let mut admin = AdminMetadata::default();
+        admin.puk_blocked = true;
+        admin.mgm_key_protected = true;
+        admin.pin_last_changed = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        admin.write(&mut yubikey).expect("write");
+
+        assert_eq!(AdminMetadata::read(&mut yubikey).expect("read"), admin);
+
+        let config = yubikey.config().expect("config");
+        assert!(config.puk_blocked);
+        assert_eq!(config.mgm_type, MgmType::Protected);
+        assert_eq!(config.pin_last_changed, admin.pin_last_changed);
+
+        let credentials = CredentialStatus::collect(&mut yubikey).expect("credentials");
+        assert!(credentials.puk_blocked());
+        assert!(!credentials.pin_blocked());
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn import_rsa_private_key() {
+        use rsa::pkcs8::EncodePrivateKey;
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Signature;
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).expect("RSA key");
+
+        assert_eq!(
+            piv::import_rsa_private_key(
+                &mut yubikey,
+                slot,
+                &key,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            ),
+            Err(Error::AuthenticationError)
+        );
+
+        authenticate(&mut yubikey);
+        piv::import_rsa_private_key(
+            &mut yubikey,
+            slot,
+            &key,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("import");
+
+        let der = key.to_pkcs8_der().expect("PKCS#8");
+
+        // RSA 3072 and 4096 keys need firmware 5.7
+        for algorithm in [AlgorithmId::Rsa3072, AlgorithmId::Rsa4096] {
+            let key_data = piv::RsaKeyData::from_pkcs8_der(der.as_bytes()).expect("key data");
+            assert_eq!(
+                piv::import_rsa_key(
+                    &mut yubikey,
+                    SlotId::KeyManagement,
+                    algorithm,
+                    key_data,
+                    TouchPolicy::Default,
+                    PinPolicy::Default,
+                ),
+                Err(Error::NotSupported)
+            );
+        }
+
+        let key_data = piv::RsaKeyData::from_pkcs8_der(der.as_bytes()).expect("key data");
+        piv::import_rsa_key(
+            &mut yubikey,
+            SlotId::KeyManagement,
+            AlgorithmId::Rsa1024,
+            key_data,
+            TouchPolicy::Default,
+            PinPolicy::Default,
+        )
+        .expect("import PKCS#8");
+
+        // Signing with the imported key gives the result of the original one
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        let mut block = vec![0x00, 0x01];
+        block.resize(128, 0x42);
+
+        for slot in [slot, SlotId::KeyManagement] {
+            let signature =
+                piv::sign_data(&mut yubikey, &block, AlgorithmId::Rsa1024, slot).expect("sign");
+            let recovered = BigUint::from_bytes_be(&signature).modpow(key.e(), key.n());
+            assert_eq!(recovered.to_bytes_be(), &block[1..]);
+        }
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn key_agreement() {
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::KeyManagement;
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP384,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+        let public_key = p384::PublicKey::try_from(public_key.owned_to_ref()).expect("P-384");
+
+        let peer = p384::ecdh::EphemeralSecret::random(&mut OsRng);
+        let shared =
+            piv::key_agreement(&mut yubikey, slot, &peer.public_key()).expect("key agreement");
+        let expected = peer.diffie_hellman(&public_key);
+        assert_eq!(shared.raw_secret_bytes(), expected.raw_secret_bytes());
+
+        let key = piv::derive_symmetric_key(&shared, None, b"test", 32).expect("derive");
+        let mut expected_key = [0u8; 32];
+        expected
+            .extract::<Sha256>(None)
+            .expand(b"test", &mut expected_key)
+            .expect("expand");
+        assert_eq!(key.as_slice(), expected_key);
+
+        // The peer key must be on the curve of the key in the slot
+        let peer = p256::SecretKey::random(&mut OsRng).public_key();
+        assert!(piv::key_agreement(&mut yubikey, slot, &peer).is_err());
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn import_ecc_private_key() {
+        use elliptic_curve::pkcs8::EncodePrivateKey;
+        use piv::EccPrivateKey;
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        let p256_key = p256::SecretKey::random(&mut OsRng);
+        piv::import_ecc_private_key(
+            &mut yubikey,
+            SlotId::Authentication,
+            p256_key.clone(),
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("import P-256");
+
+        let p384_key = p384::SecretKey::random(&mut OsRng);
+        let sec1 = p384_key.to_sec1_der().expect("SEC1");
+        let key = EccPrivateKey::from_sec1_der(&sec1).expect("decode SEC1");
+        assert_eq!(key.algorithm(), AlgorithmId::EccP384);
+        piv::import_ecc_private_key(
+            &mut yubikey,
+            SlotId::KeyManagement,
+            key,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("import P-384");
+
+        let pkcs8 = p256_key.to_pkcs8_der().expect("PKCS#8");
+        let key = EccPrivateKey::from_pkcs8_der(pkcs8.as_bytes()).expect("decode PKCS#8");
+        assert_eq!(key.algorithm(), AlgorithmId::EccP256);
+
+        // Signing with the imported key verifies with the original one
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        let digest = Sha256::digest(b"imported");
+        let signature = piv::sign_data(
+            &mut yubikey,
+            &digest,
+            AlgorithmId::EccP256,
+            SlotId::Authentication,
+        )
+        .expect("sign");
+
+        let signature = p256::ecdsa::Signature::from_der(&signature).expect("signature");
+        VerifyingKey::from(p256_key.public_key())
+            .verify_prehash(&digest, &signature)
+            .expect("verify");
+    }
+
+    #[test]
+    fn provisioning_log() {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        assert!(ProvisioningLog::read(&mut yubikey)
+            .expect("read")
+            .entries()
+            .is_empty());
+
+        let slot = SlotId::Retired(RetiredSlotId::R1);
+        ProvisioningLog::append(
+            &mut yubikey,
+            "alice",
+            ProvisioningAction::GenerateKey,
+            Some(slot),
+        )
+        .expect("append");
+        let last =
+            ProvisioningLog::append(&mut yubikey, "bob", ProvisioningAction::Other(0x80), None)
+                .expect("append");
+
+        let log = ProvisioningLog::read(&mut yubikey).expect("read");
+        assert!(log.verify().is_ok());
+        assert_eq!(log.entries().len(), 2);
+        assert_eq!(log.entries()[0].slot(), Some(slot));
+        assert_eq!(log.entries()[1].actor(), "bob");
+        assert_eq!(log.head(), last.digest());
+
+        // Once the object is full, the oldest entries are dropped
+        for _ in 0..100 {
+            ProvisioningLog::append(&mut yubikey, "carol", ProvisioningAction::Reset, None)
+                .expect("append");
+        }
+
+        let log = ProvisioningLog::read(&mut yubikey).expect("read");
+        assert!(log.verify().is_ok());
+        assert!(log.entries().len() < 102);
+        assert_eq!(log.entries()[0].actor(), "carol");
+    }
+
+    #[test]
+    fn scp03() {
+        let keys = Scp03Keys::new(0x01, [0x11; 16], [0x22; 16]);
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        assert_eq!(yubikey.enable_scp03(keys.clone()), Err(Error::NotSupported));
+
+        let mut yubikey = Emulator::new(Serial(1))
+            .with_scp03(keys.clone())
+            .open()
+            .expect("open");
+
+        let wrong_keys = Scp03Keys::new(0x01, [0x11; 16], [0x33; 16]);
+        assert_eq!(
+            yubikey.enable_scp03(wrong_keys),
+            Err(Error::AuthenticationError)
+        );
+        assert!(!yubikey.uses_secure_channel());
+
+        yubikey.enable_scp03(keys).expect("enable SCP03");
+        assert!(yubikey.uses_secure_channel());
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+
+        // Large enough to be chained and to need GET RESPONSE
+        for _ in 0..20 {
+            ProvisioningLog::append(&mut yubikey, "alice", ProvisioningAction::Reset, None)
+                .expect("append");
+        }
+
+        let log = ProvisioningLog::read(&mut yubikey).expect("read");
+        assert!(log.verify().is_ok());
+        assert_eq!(log.entries().len(), 20);
+
+        // Reselecting the PIV application opens the secure channel again
+        assert_eq!(yubikey.get_pin_retries().expect("PIN retries"), 3);
+    }
+
+    #[test]
+    fn scp03_fails_closed() {
+        let keys = Scp03Keys::new(0x01, [0x11; 16], [0x22; 16]);
+        let emulator = Emulator::new(Serial(1)).with_scp03(keys.clone());
+        let mut yubikey = emulator.open().expect("open");
+        yubikey.enable_scp03(keys.clone()).expect("enable SCP03");
+
+        // The session ends when a response isn't authentic...
+        emulator.inject_fault(0, Fault::Corrupt(0)).expect("inject");
+        assert_eq!(
+            yubikey.verify_pin(DEFAULT_PIN),
+            Err(Error::SecureChannelError)
+        );
+
+        // ...and commands aren't sent in the clear instead
+        let commands = emulator.applet().expect("applet").commands;
+        assert_eq!(
+            yubikey.verify_pin(DEFAULT_PIN),
+            Err(Error::SecureChannelError)
+        );
+        assert_eq!(emulator.applet().expect("applet").commands, commands);
+
+        // Successful responses must have an R-MAC, errors needn't
+        yubikey.enable_scp03(keys).expect("enable SCP03");
+        emulator
+            .inject_fault(0, Fault::Status(0x6982))
+            .expect("inject");
+        assert_eq!(yubikey.verify_pin(DEFAULT_PIN), Err(Error::GenericError));
+
+        emulator
+            .inject_fault(0, Fault::Status(0x9000))
+            .expect("inject");
+        assert_eq!(
+            yubikey.verify_pin(DEFAULT_PIN),
+            Err(Error::SecureChannelError)
+        );
+    }
+
+    fn issue(subject: &str, key: &p256::SecretKey, issuer_key: &p256::SecretKey) -> Certificate {
+        let profile = if key == issuer_key {
+            Profile::Root
+        } else {
+            Profile::Leaf {
+                issuer: Name::from_str("CN=Root").expect("name"),
+                enable_key_agreement: true,
+                enable_key_encipherment: false,
+                include_subject_key_identifier: true,
+            }
+        };
+        let public_key = key.public_key().to_public_key_der().expect("public key");
+        let issuer_key = p256::ecdsa::SigningKey::from(issuer_key);
+
+        let cert = CertificateBuilder::new(
+            profile,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+            Name::from_str(subject).expect("name"),
+            SubjectPublicKeyInfoOwned::from_der(public_key.as_bytes()).expect("SPKI"),
+            &issuer_key,
+        )
+        .expect("builder")
+        .build::<DerSignature>()
+        .expect("build");
+
+        Certificate::from_bytes(cert.to_der().expect("encode")).expect("decode")
+    }
+
+    #[test]
+    fn scp11() {
+        let root_key = p256::SecretKey::random(&mut OsRng);
+        let sd_key = p256::SecretKey::random(&mut OsRng);
+        let oce_key = p256::SecretKey::random(&mut OsRng);
+        let root = issue("CN=Root", &root_key, &root_key);
+        let sd_certificate = issue("CN=SD", &sd_key, &root_key);
+        let oce_certificate = issue("CN=OCE", &oce_key, &root_key);
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        assert_eq!(
+            yubikey.enable_scp11(Scp11Params::scp11b(0x01, vec![root.clone()])),
+            Err(Error::NotSupported)
+        );
+
+        let mut yubikey = Emulator::new(Serial(1))
+            .with_scp11(sd_key.clone(), vec![sd_certificate])
+            .open()
+            .expect("open");
+
+        // The SD certificate isn't issued by this trust anchor
+        let other_root = issue("CN=Root", &oce_key, &oce_key);
+        assert_eq!(
+            yubikey.enable_scp11(Scp11Params::scp11b(0x01, vec![other_root])),
+            Err(Error::AuthenticationError)
+        );
+        assert!(!yubikey.uses_secure_channel());
+
+        // The SD certificate has expired
+        yubikey.set_clock(crate::clock::FixedClock(
+            SystemTime::now() + Duration::from_secs(7200),
+        ));
+        assert_eq!(
+            yubikey.enable_scp11(Scp11Params::scp11b(0x01, vec![root.clone()])),
+            Err(Error::AuthenticationError)
+        );
+        yubikey.set_clock(crate::clock::SystemClock);
+
+        // The intermediate certificate isn't a CA certificate
+        let mut other = Emulator::new(Serial(1))
+            .with_scp11(
+                sd_key.clone(),
+                vec![oce_certificate.clone(), issue("CN=SD", &sd_key, &oce_key)],
+            )
+            .open()
+            .expect("open");
+        assert_eq!(
+            other.enable_scp11(Scp11Params::scp11b(0x01, vec![root.clone()])),
+            Err(Error::AuthenticationError)
+        );
+
+        yubikey
+            .enable_scp11(Scp11Params::scp11b(0x01, vec![root.clone()]))
+            .expect("enable SCP11b");
+        assert!(yubikey.uses_secure_channel());
+
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+        ProvisioningLog::append(&mut yubikey, "alice", ProvisioningAction::Reset, None)
+            .expect("append");
+
+        let params = Scp11Params::scp11a(0x01, vec![root], 0x01, oce_key, vec![oce_certificate]);
+        assert!(params.is_mutual());
+        yubikey.enable_scp11(params).expect("enable SCP11a");
+
+        authenticate(&mut yubikey);
+        let log = ProvisioningLog::read(&mut yubikey).expect("read");
+        assert_eq!(log.entries().len(), 1);
+        assert_eq!(yubikey.get_pin_retries().expect("PIN retries"), 3);
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn signed_transcript() {
+        use crate::transcript::{SignedTranscript, Transcript};
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        let slot = SlotId::Retired(RetiredSlotId::R1);
+
+        let transcript = Transcript::new();
+        yubikey.add_middleware(transcript.clone());
+
+        assert!(yubikey.verify_pin(b"000000").is_err());
+        yubikey.verify_pin(DEFAULT_PIN).expect("verify PIN");
+        authenticate(&mut yubikey);
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let signed = transcript
+            .sign(&mut yubikey, slot, public_key.owned_to_ref())
+            .expect("sign transcript");
+
+        let parsed = SignedTranscript::from_der(signed.as_der()).expect("parse");
+        assert!(parsed.verify(public_key.owned_to_ref()).is_ok());
+        assert_eq!(parsed.serial(), Serial(1));
+        assert_eq!(parsed.signing_slot(), slot);
+
+        let entries = parsed.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].operation, Operation::VerifyPin);
+        assert_eq!(entries[0].error.as_deref(), Some("YK-PIV-0024"));
+        assert_eq!(entries[1].error, None);
+        assert_eq!(
+            entries[3].operation,
+            Operation::Generate {
+                slot,
+                algorithm: AlgorithmId::EccP256
+            }
+        );
+
+        // Signing the transcript is recorded after the entries it covers
+        assert_eq!(transcript.entries().len(), 5);
+    }
+}
@@ -0,0 +1,452 @@
+//! Encryption of data to several YubiKeys, any of which can decrypt it.
+//!
+//! Team secrets are typically shared by encrypting them to every member's
+//! YubiKey. [`seal`] encrypts data under a random content key with
+//! AES-256-GCM, and wraps that content key to the key in the
+//! [`SlotId::KeyManagement`] slot of each [`Recipient`]:
+//!
+//! - with RSAES-OAEP (SHA-256) for RSA keys;
+//! - with a key-encryption key agreed with an ephemeral key (ECDH, then
+//!   HKDF-SHA256) for P-256 and P-384 keys, as when sealing
+//!   [`Secrets`](crate::secrets::Secrets) to a slot.
+//!
+//! Only the recipients' public keys are needed to seal an envelope. It can
+//! then be opened with any one of their YubiKeys, either a given one with
+//! [`open`], or whichever is connected with [`open_with_present`].
+
+use crate::{
+    certificate::Certificate,
+    error::{Error, Result},
+    piv::{AlgorithmId, SlotId},
+    secrets, signer,
+    yubikey::{Serial, YubiKey},
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use log::error;
+use rand_core::{OsRng, RngCore};
+use rsa::{Oaep, RsaPublicKey};
+use sha2::Sha256;
+use x509_cert::{
+    der::referenced::{OwnedToRef, RefToOwned},
+    spki::{SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef},
+};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "untested")]
+use crate::{piv, Buffer};
+
+#[cfg(all(feature = "pcsc", feature = "untested"))]
+use {crate::reader::Context, log::debug};
+
+/// Magic bytes at the start of every envelope.
+const MAGIC: &[u8; 4] = b"YKEN";
+
+/// Version of the envelope format.
+const FORMAT_VERSION: u8 = 1;
+
+/// Size of the content key and key-encryption keys.
+const KEY_LEN: usize = 32;
+
+/// Size of the AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+
+/// HKDF info of the KEKs wrapping the content key, so they differ from those
+/// of secrets bundles sealed to the same key.
+const HKDF_INFO: &[u8] = b"yubikey.rs envelope";
+
+/// YubiKey an envelope is sealed to.
+#[derive(Clone, Debug)]
+pub struct Recipient {
+    /// Serial number of the YubiKey
+    pub serial: Serial,
+
+    /// Public key of the key in its [`SlotId::KeyManagement`] slot
+    pub public_key: SubjectPublicKeyInfoOwned,
+}
+
+impl Recipient {
+    /// Create a recipient from the serial number of a YubiKey and the public
+    /// key of the key in its [`SlotId::KeyManagement`] slot.
+    pub fn new(serial: Serial, public_key: SubjectPublicKeyInfoOwned) -> Self {
+        Self { serial, public_key }
+    }
+
+    /// Read the recipient for the given YubiKey, taking the public key from
+    /// the certificate in its [`SlotId::KeyManagement`] slot.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let cert = Certificate::read(yubikey, SlotId::KeyManagement)?;
+        Ok(Self::new(
+            yubikey.serial(),
+            cert.subject_pki().ref_to_owned(),
+        ))
+    }
+}
+
+/// Seal the given data into an envelope which can be opened by any of the
+/// given recipients.
+pub fn seal(plaintext: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>> {
+    if recipients.is_empty() {
+        error!("an envelope needs at least one recipient");
+        return Err(Error::ArgumentError);
+    }
+
+    let mut content_key = Zeroizing::new([0u8; KEY_LEN]);
+    OsRng.fill_bytes(content_key.as_mut());
+
+    let mut envelope = MAGIC.to_vec();
+    envelope.push(FORMAT_VERSION);
+    envelope.push(u8::try_from(recipients.len()).map_err(|_| Error::SizeError)?);
+
+    for recipient in recipients {
+        let (algorithm, wrapped) = wrap(&content_key, recipient.public_key.owned_to_ref())?;
+
+        envelope.extend_from_slice(&recipient.serial.0.to_be_bytes());
+        envelope.push(algorithm.into());
+        envelope.extend_from_slice(
+            &u16::try_from(wrapped.len())
+                .map_err(|_| Error::SizeError)?
+                .to_be_bytes(),
+        );
+        envelope.extend_from_slice(&wrapped);
+    }
+
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = Aes256Gcm::new(content_key.as_ref().into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &envelope,
+            },
+        )
+        .map_err(|_| Error::GenericError)?;
+
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&ciphertext);
+    Ok(envelope)
+}
+
+/// Get the serial numbers of the YubiKeys the given envelope is sealed to.
+pub fn recipients(envelope: &[u8]) -> Result<Vec<Serial>> {
+    Ok(Envelope::parse(envelope)?
+        .entries
+        .iter()
+        .map(|entry| entry.serial)
+        .collect())
+}
+
+/// Open an envelope sealed with [`seal`] with the given YubiKey, which must
+/// be one of its recipients.
+///
+/// The PIN and touch policies of the key in the [`SlotId::KeyManagement`]
+/// slot must be satisfied. Returns [`Error::NotFound`] if the YubiKey isn't a
+/// recipient, and [`Error::AuthenticationError`] if the envelope has been
+/// tampered with.
+#[cfg(feature = "untested")]
+pub fn open(envelope: &[u8], yubikey: &mut YubiKey) -> Result<Buffer> {
+    let envelope = Envelope::parse(envelope)?;
+    let serial = yubikey.serial();
+
+    let entry = envelope
+        .entries
+        .iter()
+        .find(|entry| entry.serial == serial)
+        .ok_or_else(|| {
+            error!("YubiKey {} isn't a recipient of the envelope", serial);
+            Error::NotFound
+        })?;
+
+    let content_key = entry.unwrap(yubikey)?;
+    envelope.decrypt(&content_key)
+}
+
+/// Open an envelope sealed with [`seal`] with whichever of its recipients is
+/// connected.
+///
+/// `prepare` is called with the recipient's YubiKey before the envelope is
+/// opened, e.g. to verify its PIN. Returns [`Error::NotFound`] if none of the
+/// recipients is connected.
+#[cfg(all(feature = "pcsc", feature = "untested"))]
+pub fn open_with_present<F>(envelope: &[u8], mut prepare: F) -> Result<Buffer>
+where
+    F: FnMut(&mut YubiKey) -> Result<()>,
+{
+    let recipients = recipients(envelope)?;
+    let mut readers = Context::open()?;
+
+    for reader in readers.iter()? {
+        let mut yubikey = match reader.open() {
+            Ok(yubikey) => yubikey,
+            Err(e) => {
+                debug!("skipping reader {}: {}", reader.name(), e);
+                continue;
+            }
+        };
+
+        if !recipients.contains(&yubikey.serial()) {
+            // We didn't want this YubiKey; don't reset it.
+            yubikey.leave_card();
+            continue;
+        }
+
+        prepare(&mut yubikey)?;
+        return open(envelope, &mut yubikey);
+    }
+
+    error!("none of the recipients of the envelope is connected");
+    Err(Error::NotFound)
+}
+
+/// Wrap the content key to the given public key.
+fn wrap(
+    content_key: &[u8; KEY_LEN],
+    public_key: SubjectPublicKeyInfoRef<'_>,
+) -> Result<(AlgorithmId, Vec<u8>)> {
+    let algorithm = signer::algorithm_of(public_key.clone())?;
+
+    let wrapped = match algorithm {
+        AlgorithmId::Rsa1024
+        | AlgorithmId::Rsa2048
+        | AlgorithmId::Rsa3072
+        | AlgorithmId::Rsa4096 => RsaPublicKey::try_from(public_key)
+            .map_err(|_| Error::KeyError)?
+            .encrypt(&mut OsRng, Oaep::new::<Sha256>(), content_key)
+            .map_err(|_| Error::GenericError)?,
+        AlgorithmId::EccP256 | AlgorithmId::EccP384 => {
+            let (_, ephemeral, shared) = secrets::agree_ephemeral(public_key)?;
+            wrap_agreed(content_key, &ephemeral, &shared)?
+        }
+        AlgorithmId::Ed25519 | AlgorithmId::X25519 => {
+            error!("{:?} keys can't be used to encrypt envelopes", algorithm);
+            return Err(Error::AlgorithmError);
+        }
+    };
+
+    Ok((algorithm, wrapped))
+}
+
+/// Wrap the content key under a KEK agreed with an ephemeral key, returning
+/// the ephemeral public key followed by the wrapped key.
+fn wrap_agreed(content_key: &[u8; KEY_LEN], ephemeral: &[u8], shared: &[u8]) -> Result<Vec<u8>> {
+    let kek = secrets::slot_kek(shared, ephemeral, HKDF_INFO)?;
+
+    // Each KEK is derived from a fresh ephemeral key and used once, so the
+    // nonce can be fixed
+    let wrapped = Aes256Gcm::new(kek.as_ref().into())
+        .encrypt(Nonce::from_slice(&[0; NONCE_LEN]), &content_key[..])
+        .map_err(|_| Error::GenericError)?;
+
+    let mut out = ephemeral.to_vec();
+    out.extend_from_slice(&wrapped);
+    Ok(out)
+}
+
+/// Parsed envelope.
+#[cfg_attr(not(feature = "untested"), allow(dead_code))]
+struct Envelope<'a> {
+    /// Everything before the nonce, authenticated with the content
+    header: &'a [u8],
+    entries: Vec<Entry<'a>>,
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+/// Content key wrapped to a recipient.
+#[cfg_attr(not(feature = "untested"), allow(dead_code))]
+struct Entry<'a> {
+    serial: Serial,
+    algorithm: AlgorithmId,
+    wrapped: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    fn parse(envelope: &'a [u8]) -> Result<Self> {
+        let mut rest = envelope;
+        let mut take = |len: usize| -> Result<&'a [u8]> {
+            if rest.len() < len {
+                error!("envelope is truncated");
+                return Err(Error::ParseError);
+            }
+
+            let (taken, remaining) = rest.split_at(len);
+            rest = remaining;
+            Ok(taken)
+        };
+
+        if take(MAGIC.len())? != MAGIC || take(1)? != [FORMAT_VERSION] {
+            error!("not an envelope, or unsupported version");
+            return Err(Error::ParseError);
+        }
+
+        let count = take(1)?[0];
+        let mut entries = Vec::with_capacity(usize::from(count));
+
+        for _ in 0..count {
+            let serial = Serial(u32::from_be_bytes(take(4)?.try_into()?));
+            let algorithm = AlgorithmId::try_from(take(1)?[0])?;
+            let len = u16::from_be_bytes(take(2)?.try_into()?);
+
+            entries.push(Entry {
+                serial,
+                algorithm,
+                wrapped: take(usize::from(len))?,
+            });
+        }
+
+        let nonce = take(NONCE_LEN)?;
+        let ciphertext = rest;
+        let header = &envelope[..envelope.len() - ciphertext.len() - NONCE_LEN];
+
+        Ok(Self {
+            header,
+            entries,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt the content with the given content key.
+    #[cfg(feature = "untested")]
+    fn decrypt(&self, content_key: &[u8; KEY_LEN]) -> Result<Buffer> {
+        Aes256Gcm::new(content_key.into())
+            .decrypt(
+                Nonce::from_slice(self.nonce),
+                Payload {
+                    msg: self.ciphertext,
+                    aad: self.header,
+                },
+            )
+            .map(Buffer::new)
+            .map_err(|_| {
+                error!("couldn't decrypt envelope");
+                Error::AuthenticationError
+            })
+    }
+}
+
+impl Entry<'_> {
+    /// Unwrap the content key with the given YubiKey.
+    #[cfg(feature = "untested")]
+    fn unwrap(&self, yubikey: &mut YubiKey) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+        let content_key = match self.algorithm {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => {
+                let block = piv::decrypt_data(
+                    yubikey,
+                    self.wrapped,
+                    self.algorithm,
+                    SlotId::KeyManagement,
+                )?;
+
+                signer::unpad(&block, Oaep::new::<Sha256>())
+                    .map_err(|_| Error::AuthenticationError)?
+            }
+            AlgorithmId::EccP256 | AlgorithmId::EccP384 => {
+                let point_len = match self.algorithm {
+                    AlgorithmId::EccP256 => 65,
+                    _ => 97,
+                };
+
+                if self.wrapped.len() < point_len {
+                    return Err(Error::ParseError);
+                }
+
+                let (ephemeral, wrapped) = self.wrapped.split_at(point_len);
+                let shared =
+                    piv::decrypt_data(yubikey, ephemeral, self.algorithm, SlotId::KeyManagement)?;
+                let kek = secrets::slot_kek(&shared, ephemeral, HKDF_INFO)?;
+
+                Aes256Gcm::new(kek.as_ref().into())
+                    .decrypt(Nonce::from_slice(&[0; NONCE_LEN]), wrapped)
+                    .map(Buffer::new)
+                    .map_err(|_| Error::AuthenticationError)?
+            }
+            AlgorithmId::Ed25519 | AlgorithmId::X25519 => return Err(Error::AlgorithmError),
+        };
+
+        <[u8; KEY_LEN]>::try_from(&content_key[..])
+            .map(Zeroizing::new)
+            .map_err(|_| Error::AuthenticationError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipient(serial: u32) -> Recipient {
+        let secret = p256::SecretKey::random(&mut OsRng);
+        let public_key =
+            SubjectPublicKeyInfoOwned::from_key(secret.public_key()).expect("public key");
+        Recipient::new(Serial(serial), public_key)
+    }
+
+    #[test]
+    fn seal_to_recipients() {
+        let envelope = seal(b"team secret", &[recipient(1), recipient(2)]).expect("sealed");
+        assert_eq!(
+            recipients(&envelope).expect("recipients"),
+            [Serial(1), Serial(2)]
+        );
+
+        assert_eq!(seal(b"team secret", &[]), Err(Error::ArgumentError));
+        assert_eq!(
+            recipients(&envelope[..envelope.len() - 40]).err(),
+            Some(Error::ParseError)
+        );
+    }
+
+    #[cfg(all(feature = "emulator", feature = "untested"))]
+    #[test]
+    fn open_with_any_recipient() {
+        use crate::{emulator::Emulator, PinPolicy, TouchPolicy};
+
+        let mut yubikeys = vec![];
+        let mut recipients = vec![];
+
+        for (serial, algorithm) in [(1, AlgorithmId::EccP256), (2, AlgorithmId::Rsa1024)] {
+            let mut yubikey = Emulator::new(Serial(serial))
+                .open_authenticated()
+                .expect("open");
+
+            let public_key = piv::generate(
+                &mut yubikey,
+                SlotId::KeyManagement,
+                algorithm,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            )
+            .expect("generate");
+
+            recipients.push(Recipient::new(Serial(serial), public_key));
+            yubikeys.push(yubikey);
+        }
+
+        let envelope = seal(b"team secret", &recipients).expect("sealed");
+
+        for yubikey in &mut yubikeys {
+            yubikey.verify_pin(b"123456").expect("verify PIN");
+            assert_eq!(
+                &open(&envelope, yubikey).expect("opened")[..],
+                b"team secret"
+            );
+        }
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().expect("ciphertext") ^= 1;
+        assert_eq!(
+            open(&tampered, &mut yubikeys[0]),
+            Err(Error::AuthenticationError)
+        );
+
+        let mut stranger = Emulator::new(Serial(3)).open().expect("open");
+        assert_eq!(open(&envelope, &mut stranger), Err(Error::NotFound));
+    }
+}
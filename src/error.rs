@@ -30,7 +30,10 @@
 // (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
 
 /// Result type with [`Error`].
 pub type Result<T> = core::result::Result<T, Error>;
@@ -54,9 +57,30 @@ pub enum Error {
     /// Argument error
     ArgumentError,
 
+    /// A key attestation is invalid or doesn't meet the requirements
+    AttestationError,
+
     /// Authentication error
     AuthenticationError,
 
+    /// The card was removed, or the connection to it lost
+    CardRemoved,
+
+    /// The card was reset or powered down since it was connected to
+    CardReset,
+
+    /// A certificate is too large to be stored in a slot
+    CertificateTooLarge {
+        /// Size of the encoded certificate object
+        size: usize,
+
+        /// Maximum size of an object on this YubiKey
+        max: usize,
+
+        /// Whether the certificate was to be stored compressed
+        compressed: bool,
+    },
+
     /// Generic error
     GenericError,
 
@@ -75,10 +99,20 @@ pub enum Error {
     /// Not found
     NotFound,
 
+    /// The YubiKey is a model without the PIV application (e.g. a Security Key)
+    NoPivApplication,
+
+    /// The YubiKey isn't operating in FIPS approved mode, but it's required
+    NotFipsApproved,
+
+    /// Operation denied by the key usage policy, a guard or dry-run mode
+    OperationDenied,
+
     /// Parse error
     ParseError,
 
     /// PCSC error
+    #[cfg(feature = "pcsc")]
     PcscError {
         /// Original PC/SC error
         inner: Option<pcsc::Error>,
@@ -87,9 +121,33 @@ pub enum Error {
     /// PIN locked
     PinLocked,
 
+    /// The PIN must be verified, but none is available (yet)
+    PinRequired,
+
+    /// PIN or touch policy not supported by this YubiKey
+    PolicyUnsupported,
+
     /// Range error
     RangeError,
 
+    /// Operation refused by the rate limits
+    RateLimited {
+        /// How long until the operation would be allowed, if it will be
+        retry_after: Option<Duration>,
+    },
+
+    /// The SCP03 secure channel failed, e.g. because a response wasn't
+    /// authentic
+    SecureChannelError,
+
+    /// The card was reset (e.g. by another application, or by the reader
+    /// being power-cycled), ending a PIN verification which can't be
+    /// restored as the PIN isn't cached
+    SessionLost,
+
+    /// Signature verification failed
+    SignatureError,
+
     /// Size error
     SizeError,
 
@@ -109,6 +167,7 @@ impl Error {
         Some(match self {
             Error::AlgorithmError => "YKPIV_ALGORITHM_ERROR",
             Error::AppletError => "YKPIV_APPLET_ERROR",
+            Error::CardRemoved | Error::CardReset => "YKPIV_PCSC_ERROR",
             Error::ArgumentError => "YKPIV_ARGUMENT_ERROR",
             Error::AuthenticationError => "YKPIV_AUTHENTICATION_ERROR",
             Error::GenericError => "YKPIV_GENERIC_ERROR",
@@ -117,6 +176,7 @@ impl Error {
             Error::MemoryError => "YKPIV_MEMORY_ERROR",
             Error::NotSupported => "YKPIV_NOT_SUPPORTED",
             Error::ParseError => "YKPIV_PARSE_ERROR",
+            #[cfg(feature = "pcsc")]
             Error::PcscError { .. } => "YKPIV_PCSC_ERROR",
             Error::PinLocked => "YKPIV_PIN_LOCKED",
             Error::RangeError => "YKPIV_RANGE_ERROR",
@@ -126,6 +186,47 @@ impl Error {
         })
     }
 
+    /// Stable machine-readable code of the error (e.g. `YK-PIV-0003`).
+    ///
+    /// Unlike the English message returned by [`Display`], codes never
+    /// change, so applications can use them to look up localized messages
+    /// and remediation steps. New kinds of errors get new codes.
+    pub fn code(self) -> &'static str {
+        match self {
+            Error::AlgorithmError => "YK-PIV-0001",
+            Error::AppletError => "YK-PIV-0002",
+            Error::AppletNotFound { .. } => "YK-PIV-0003",
+            Error::ArgumentError => "YK-PIV-0004",
+            Error::AttestationError => "YK-PIV-0005",
+            Error::AuthenticationError => "YK-PIV-0006",
+            Error::CertificateTooLarge { .. } => "YK-PIV-0007",
+            Error::GenericError => "YK-PIV-0008",
+            Error::InvalidObject => "YK-PIV-0009",
+            Error::KeyError => "YK-PIV-0010",
+            Error::MemoryError => "YK-PIV-0011",
+            Error::NotSupported => "YK-PIV-0012",
+            Error::NotFound => "YK-PIV-0013",
+            Error::NoPivApplication => "YK-PIV-0014",
+            Error::NotFipsApproved => "YK-PIV-0015",
+            Error::OperationDenied => "YK-PIV-0016",
+            Error::ParseError => "YK-PIV-0017",
+            #[cfg(feature = "pcsc")]
+            Error::PcscError { .. } => "YK-PIV-0018",
+            Error::PinLocked => "YK-PIV-0019",
+            Error::PolicyUnsupported => "YK-PIV-0020",
+            Error::RangeError => "YK-PIV-0021",
+            Error::SignatureError => "YK-PIV-0022",
+            Error::SizeError => "YK-PIV-0023",
+            Error::WrongPin { .. } => "YK-PIV-0024",
+            Error::RateLimited { .. } => "YK-PIV-0025",
+            Error::PinRequired => "YK-PIV-0026",
+            Error::SessionLost => "YK-PIV-0027",
+            Error::SecureChannelError => "YK-PIV-0028",
+            Error::CardRemoved => "YK-PIV-0029",
+            Error::CardReset => "YK-PIV-0030",
+        }
+    }
+
     /// Error message
     pub fn msg(self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -135,27 +236,89 @@ impl Error {
                 f.write_str(&format!("{} applet not found", applet_name))
             }
             Error::ArgumentError => f.write_str("argument error"),
+            Error::AttestationError => f.write_str("attestation error"),
             Error::AuthenticationError => f.write_str("authentication error"),
+            Error::CardRemoved => f.write_str("card was removed"),
+            Error::CardReset => f.write_str("card was reset"),
+            Error::CertificateTooLarge {
+                size,
+                max,
+                compressed: false,
+            } => f.write_fmt(format_args!(
+                "certificate is too large ({} bytes, maximum {}): compress it or shorten it (e.g. by removing extensions or intermediate certificates)",
+                size, max
+            )),
+            Error::CertificateTooLarge { size, max, .. } => f.write_fmt(format_args!(
+                "compressed certificate is too large ({} bytes, maximum {}): shorten it (e.g. by removing extensions or intermediate certificates)",
+                size, max
+            )),
             Error::GenericError => f.write_str("generic error"),
             Error::InvalidObject => f.write_str("invalid object"),
             Error::KeyError => f.write_str("key error"),
             Error::MemoryError => f.write_str("memory error"),
             Error::NotSupported => f.write_str("not supported"),
             Error::NotFound => f.write_str("not found"),
+            Error::NoPivApplication => f.write_str("this YubiKey model has no PIV application"),
+            Error::NotFipsApproved => f.write_str("YubiKey isn't in FIPS approved mode"),
+            Error::OperationDenied => f.write_str("operation denied"),
             Error::ParseError => f.write_str("parse error"),
 
+            #[cfg(feature = "pcsc")]
             Error::PcscError {
                 inner: Some(pcsc_error),
             } => f.write_fmt(format_args!("PC/SC error: {}", pcsc_error)),
 
+            #[cfg(feature = "pcsc")]
             Error::PcscError { .. } => f.write_str("PC/SC error"),
 
             Error::PinLocked => f.write_str("PIN locked"),
+            Error::PinRequired => f.write_str("PIN required"),
+            Error::PolicyUnsupported => f.write_str("policy not supported"),
             Error::RangeError => f.write_str("range error"),
+            Error::RateLimited {
+                retry_after: Some(retry_after),
+            } => f.write_fmt(format_args!(
+                "rate limit exceeded: retry in {}s",
+                retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+            )),
+            Error::RateLimited { .. } => f.write_str("rate limit exceeded"),
+            Error::SecureChannelError => f.write_str("secure channel error"),
+            Error::SessionLost => f.write_str("card was reset, PIN verification lost"),
+            Error::SignatureError => f.write_str("signature verification failed"),
             Error::SizeError => f.write_str("size error"),
             Error::WrongPin { .. } => f.write_str("wrong pin"),
         }
     }
+
+    /// Does this error indicate the card was reset or power-cycled since it
+    /// was connected to, so it needs to be reconnected to?
+    pub(crate) fn is_card_reset(self) -> bool {
+        match self {
+            Error::CardReset => true,
+            #[cfg(feature = "pcsc")]
+            Error::PcscError {
+                inner:
+                    Some(
+                        pcsc::Error::ResetCard
+                        | pcsc::Error::UnpoweredCard
+                        | pcsc::Error::NotTransacted,
+                    ),
+            } => true,
+            _ => false,
+        }
+    }
+
+    /// Does this error indicate the card was removed?
+    pub(crate) fn is_card_removed(self) -> bool {
+        match self {
+            Error::CardRemoved => true,
+            #[cfg(feature = "pcsc")]
+            Error::PcscError {
+                inner: Some(pcsc::Error::RemovedCard | pcsc::Error::NoSmartcard),
+            } => true,
+            _ => false,
+        }
+    }
 }
 
 impl Display for Error {
@@ -176,6 +339,7 @@ impl From<std::time::SystemTimeError> for Error {
     }
 }
 
+#[cfg(feature = "pcsc")]
 impl From<pcsc::Error> for Error {
     fn from(err: pcsc::Error) -> Error {
         Error::PcscError { inner: Some(err) }
@@ -185,6 +349,7 @@ impl From<pcsc::Error> for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            #[cfg(feature = "pcsc")]
             #[allow(trivial_casts)]
             Error::PcscError { inner } => inner.as_ref().map(|err| err as &_),
             _ => None,
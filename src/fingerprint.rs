@@ -0,0 +1,290 @@
+//! Fingerprints of public keys, in the formats used by other tools.
+//!
+//! These match the key in a slot (e.g. as returned by
+//! [`piv::generate`](crate::piv::generate) or read from a certificate) to the
+//! same key as it appears elsewhere:
+//!
+//! - [`sha256`]: SHA-256 of the DER-encoded `SubjectPublicKeyInfo`, as used
+//!   for public key pinning and by certificate transparency logs
+//! - [`ssh`]: OpenSSH `SHA256:` fingerprint, as printed by `ssh-keygen -l`,
+//!   and [`matches_authorized_key`] to find the key in `authorized_keys`
+//! - [`openpgp`]: OpenPGP v4 fingerprint, as printed by `gpg --fingerprint`
+//!
+//! RSA, ECDSA (P-256 and P-384) and Ed25519 keys are supported.
+
+use crate::{
+    error::{Error, Result},
+    verify::ED25519_OID,
+};
+use base64ct::{Base64, Base64Unpadded, Encoding};
+use rsa::{traits::PublicKeyParts, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use x509_cert::{
+    der::{oid::AssociatedOid, Encode},
+    spki::SubjectPublicKeyInfoRef,
+};
+
+/// OpenPGP public key algorithm ID of RSA keys.
+const OPENPGP_RSA: u8 = 1;
+
+/// OpenPGP public key algorithm ID of ECDSA keys.
+const OPENPGP_ECDSA: u8 = 19;
+
+/// OpenPGP public key algorithm ID of EdDSA keys.
+const OPENPGP_EDDSA: u8 = 22;
+
+/// Curve OID used by OpenPGP for Ed25519 keys (1.3.6.1.4.1.11591.15.1).
+const OPENPGP_ED25519_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+
+/// Public key in one of the supported algorithms.
+enum PublicKey<'a> {
+    Rsa(RsaPublicKey),
+    P256(&'a [u8]),
+    P384(&'a [u8]),
+    Ed25519(&'a [u8]),
+}
+
+impl<'a> PublicKey<'a> {
+    fn from_spki(public_key: SubjectPublicKeyInfoRef<'a>) -> Result<Self> {
+        if public_key.algorithm.oid == ED25519_OID {
+            return public_key
+                .subject_public_key
+                .as_bytes()
+                .filter(|bytes| bytes.len() == 32)
+                .map(PublicKey::Ed25519)
+                .ok_or(Error::KeyError);
+        }
+
+        if let Ok(curve) = public_key.algorithm.parameters_oid() {
+            let point = public_key
+                .subject_public_key
+                .as_bytes()
+                .ok_or(Error::KeyError)?;
+
+            return if curve == p256::NistP256::OID {
+                Ok(PublicKey::P256(point))
+            } else if curve == p384::NistP384::OID {
+                Ok(PublicKey::P384(point))
+            } else {
+                Err(Error::KeyError)
+            };
+        }
+
+        RsaPublicKey::try_from(public_key)
+            .map(PublicKey::Rsa)
+            .map_err(|_| Error::KeyError)
+    }
+}
+
+/// Compute the SHA-256 fingerprint of the DER-encoded `SubjectPublicKeyInfo`.
+pub fn sha256(public_key: SubjectPublicKeyInfoRef<'_>) -> Result<[u8; 32]> {
+    Ok(Sha256::digest(public_key.to_der()?).into())
+}
+
+/// Encode the given public key in the SSH wire format, as found
+/// (base64-encoded) in `authorized_keys` files.
+pub fn ssh_public_key(public_key: SubjectPublicKeyInfoRef<'_>) -> Result<Vec<u8>> {
+    let mut blob = vec![];
+
+    match PublicKey::from_spki(public_key)? {
+        PublicKey::Rsa(rsa) => {
+            ssh_string(&mut blob, b"ssh-rsa");
+            ssh_mpint(&mut blob, &rsa.e().to_bytes_be());
+            ssh_mpint(&mut blob, &rsa.n().to_bytes_be());
+        }
+        PublicKey::P256(point) => {
+            ssh_string(&mut blob, b"ecdsa-sha2-nistp256");
+            ssh_string(&mut blob, b"nistp256");
+            ssh_string(&mut blob, point);
+        }
+        PublicKey::P384(point) => {
+            ssh_string(&mut blob, b"ecdsa-sha2-nistp384");
+            ssh_string(&mut blob, b"nistp384");
+            ssh_string(&mut blob, point);
+        }
+        PublicKey::Ed25519(key) => {
+            ssh_string(&mut blob, b"ssh-ed25519");
+            ssh_string(&mut blob, key);
+        }
+    }
+
+    Ok(blob)
+}
+
+/// Format the given public key as an `authorized_keys` entry (without a
+/// comment).
+pub fn ssh_authorized_key(public_key: SubjectPublicKeyInfoRef<'_>) -> Result<String> {
+    let blob = ssh_public_key(public_key)?;
+    let key_type = ssh_key_type(&blob).ok_or(Error::KeyError)?;
+
+    Ok(format!("{} {}", key_type, Base64::encode_string(&blob)))
+}
+
+/// Compute the OpenSSH fingerprint of the given public key, as printed by
+/// `ssh-keygen -l` (e.g. `SHA256:7LQlhZV3...`).
+pub fn ssh(public_key: SubjectPublicKeyInfoRef<'_>) -> Result<String> {
+    let digest = Sha256::digest(ssh_public_key(public_key)?);
+    Ok(format!("SHA256:{}", Base64Unpadded::encode_string(&digest)))
+}
+
+/// Does the given line of an `authorized_keys` file contain the given public
+/// key?
+///
+/// Options before the key type and comments after the key are ignored.
+pub fn matches_authorized_key(public_key: SubjectPublicKeyInfoRef<'_>, line: &str) -> Result<bool> {
+    let blob = ssh_public_key(public_key)?;
+    let key_type = ssh_key_type(&blob).ok_or(Error::KeyError)?;
+
+    let mut tokens = line.split_whitespace();
+
+    while let Some(token) = tokens.next() {
+        if token == key_type {
+            return Ok(tokens
+                .next()
+                .and_then(|encoded| Base64::decode_vec(encoded).ok())
+                .map_or(false, |encoded| encoded == blob));
+        }
+    }
+
+    Ok(false)
+}
+
+/// Compute the OpenPGP v4 fingerprint of the given public key, as printed by
+/// `gpg --fingerprint`.
+///
+/// OpenPGP fingerprints cover the key creation time, given as seconds since
+/// the Unix epoch: it must match the time the key was imported into the
+/// OpenPGP keyring with. Ed25519 keys are fingerprinted as EdDSA keys.
+pub fn openpgp(public_key: SubjectPublicKeyInfoRef<'_>, created: u32) -> Result<[u8; 20]> {
+    let mut body = vec![4];
+    body.extend_from_slice(&created.to_be_bytes());
+
+    match PublicKey::from_spki(public_key)? {
+        PublicKey::Rsa(rsa) => {
+            body.push(OPENPGP_RSA);
+            openpgp_mpi(&mut body, &rsa.n().to_bytes_be());
+            openpgp_mpi(&mut body, &rsa.e().to_bytes_be());
+        }
+        PublicKey::P256(point) => {
+            body.push(OPENPGP_ECDSA);
+            openpgp_oid(&mut body, p256::NistP256::OID.as_bytes());
+            openpgp_mpi(&mut body, point);
+        }
+        PublicKey::P384(point) => {
+            body.push(OPENPGP_ECDSA);
+            openpgp_oid(&mut body, p384::NistP384::OID.as_bytes());
+            openpgp_mpi(&mut body, point);
+        }
+        PublicKey::Ed25519(key) => {
+            body.push(OPENPGP_EDDSA);
+            openpgp_oid(&mut body, OPENPGP_ED25519_OID);
+            openpgp_mpi(&mut body, &[&[0x40], key].concat());
+        }
+    }
+
+    let len = u16::try_from(body.len()).map_err(|_| Error::SizeError)?;
+
+    let mut hasher = Sha1::new();
+    hasher.update([0x99]);
+    hasher.update(len.to_be_bytes());
+    hasher.update(&body);
+    Ok(hasher.finalize().into())
+}
+
+/// Get the key type of a public key in the SSH wire format.
+fn ssh_key_type(blob: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(blob.get(4..4 + len)?).ok()
+}
+
+fn ssh_string(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn ssh_mpint(buf: &mut Vec<u8>, value: &[u8]) {
+    let value = strip_leading_zeros(value);
+
+    if value.first().map_or(false, |byte| byte & 0x80 != 0) {
+        ssh_string(buf, &[&[0], value].concat());
+    } else {
+        ssh_string(buf, value);
+    }
+}
+
+fn openpgp_mpi(buf: &mut Vec<u8>, value: &[u8]) {
+    let value = strip_leading_zeros(value);
+    let bits = value
+        .first()
+        .map_or(0, |byte| value.len() * 8 - byte.leading_zeros() as usize);
+
+    buf.extend_from_slice(&(bits as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn openpgp_oid(buf: &mut Vec<u8>, oid: &[u8]) {
+    buf.push(oid.len() as u8);
+    buf.extend_from_slice(oid);
+}
+
+fn strip_leading_zeros(value: &[u8]) -> &[u8] {
+    let zeros = value.iter().take_while(|&&byte| byte == 0).count();
+    &value[zeros..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_cert::{
+        der::{referenced::OwnedToRef, Decode},
+        spki::SubjectPublicKeyInfoOwned,
+    };
+
+    /// Decode a P-256 public key from its uncompressed point.
+    fn p256_key(point: &str) -> SubjectPublicKeyInfoOwned {
+        let der = hex::mixed::decode_vec(format!(
+            "3059301306072a8648ce3d020106082a8648ce3d030107034200{}",
+            point
+        ))
+        .expect("valid hex");
+        SubjectPublicKeyInfoOwned::from_der(&der).expect("valid SPKI")
+    }
+
+    #[test]
+    fn ssh_fingerprint() {
+        let key = p256_key(
+            "04884d6ed5e9b57a176b177794ed60dfd9cefa51e66b44d441faddeee59fbad5c0\
+             3ea83a147df14ac25763da3e307a65a9eefc04d85a506d13df5bb3c1926a7efa",
+        );
+        let key = key.owned_to_ref();
+        let entry = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBIhNbtXptXoXaxd3lO1g39nO+lHma0TUQfrd7uWfutXAPqg6FH3xSsJXY9o+MHplqe78BNhaUG0T31uzwZJqfvo=";
+
+        assert_eq!(
+            ssh(key.clone()).expect("supported key"),
+            "SHA256:7LQlhZV3R5BPlesjyXZlU6JupKvmnHISxYDryAiMj5g"
+        );
+        assert_eq!(
+            ssh_authorized_key(key.clone()).expect("supported key"),
+            entry
+        );
+        assert!(
+            matches_authorized_key(key.clone(), &format!("no-pty {} test", entry))
+                .expect("supported key")
+        );
+        assert!(!matches_authorized_key(key, "ssh-ed25519 AAAA test").expect("supported key"));
+    }
+
+    #[test]
+    fn openpgp_fingerprint() {
+        let key = p256_key(
+            "04200e31bbff7cc11f8defd7f8e641f311845f58032481676b24117775225acee7\
+             10861b15f67a0814e395f75fbfb70bd54fd828bbcfffb1250c6ab45be4398313",
+        );
+
+        assert_eq!(
+            openpgp(key.owned_to_ref(), 1_577_836_800).expect("supported key"),
+            *hex::mixed::decode_vec("3384354BC9461214A5D213B84DFC6D8C278574BF").expect("valid hex")
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Registry of YubiKeys for fleet management.
+//!
+//! A [`Fleet`] records the YubiKeys an organization has issued: who owns each
+//! device, which slots are expected to hold keys, and when the certificates
+//! in those slots expire. With the `serde` feature enabled it can be
+//! persisted in any format supported by [`serde`](https://serde.rs/).
+//!
+//! A registry can be compared against the actual state of connected devices
+//! with [`Fleet::diff`], in order to track the lifecycle of YubiKeys.
+//!
+//! [`scan_expiring`] reports the certificates of connected devices which are
+//! about to expire, e.g. from a monitoring job run periodically.
+
+use crate::{
+    error::{Error, Result},
+    piv::{Key, SlotId},
+    yubikey::{Serial, Version, YubiKey},
+};
+use std::time::Duration;
+
+#[cfg(feature = "pcsc")]
+use crate::{
+    clock::{self, SystemClock},
+    reader::Context,
+};
+#[cfg(feature = "pcsc")]
+use log::debug;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Registry of YubiKeys.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Fleet {
+    /// Registered devices
+    pub devices: Vec<Device>,
+}
+
+impl Fleet {
+    /// Find the registered device with the given serial number.
+    pub fn device(&self, serial: Serial) -> Option<&Device> {
+        self.devices.iter().find(|device| device.serial == serial)
+    }
+
+    /// Find the registered device with the given serial number for modification.
+    pub fn device_mut(&mut self, serial: Serial) -> Option<&mut Device> {
+        self.devices
+            .iter_mut()
+            .find(|device| device.serial == serial)
+    }
+
+    /// Register a device, replacing any device with the same serial number.
+    pub fn register(&mut self, device: Device) {
+        match self.device_mut(device.serial) {
+            Some(existing) => *existing = device,
+            None => self.devices.push(device),
+        }
+    }
+
+    /// Compare this registry against the devices found by a live scan.
+    ///
+    /// Registered devices missing from `live` are reported as
+    /// [`FleetChange::DeviceMissing`], so `live` should contain every device
+    /// which was reachable during the scan.
+    pub fn diff(&self, live: &[Device]) -> Vec<FleetChange> {
+        let mut changes = vec![];
+
+        for registered in &self.devices {
+            match live
+                .iter()
+                .find(|device| device.serial == registered.serial)
+            {
+                Some(device) => registered.diff_slots(device, &mut changes),
+                None => changes.push(FleetChange::DeviceMissing(registered.serial)),
+            }
+        }
+
+        for device in live {
+            if self.device(device.serial).is_none() {
+                changes.push(FleetChange::DeviceUnregistered(device.serial));
+            }
+        }
+
+        changes
+    }
+}
+
+/// A YubiKey in a [`Fleet`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Device {
+    /// Serial number of the YubiKey
+    pub serial: Serial,
+
+    /// Firmware version of the YubiKey, if known
+    pub version: Option<Version>,
+
+    /// Person or system the YubiKey is issued to
+    pub owner: Option<String>,
+
+    /// Slots which hold keys
+    pub slots: Vec<SlotAssignment>,
+}
+
+impl Device {
+    /// Create a device with no owner or slot assignments.
+    pub fn new(serial: Serial) -> Self {
+        Self {
+            serial,
+            version: None,
+            owner: None,
+            slots: vec![],
+        }
+    }
+
+    /// Read the current state of the given YubiKey.
+    ///
+    /// Slots are detected by the certificates stored in them.
+    pub fn scan(yubikey: &mut YubiKey) -> Result<Self> {
+        let slots = Key::list(yubikey)?
+            .iter()
+            .map(|key| {
+                let tbs_certificate = &key.certificate().cert.tbs_certificate;
+
+                SlotAssignment {
+                    slot: key.slot(),
+                    purpose: None,
+                    subject: Some(tbs_certificate.subject.to_string()),
+                    not_after: Some(tbs_certificate.validity.not_after.to_unix_duration()),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            serial: yubikey.serial(),
+            version: Some(yubikey.version()),
+            owner: None,
+            slots,
+        })
+    }
+
+    /// Find the assignment for the given slot.
+    pub fn slot(&self, slot: SlotId) -> Option<&SlotAssignment> {
+        self.slots.iter().find(|assignment| assignment.slot == slot)
+    }
+
+    /// Compare the slot assignments of this (registered) device against the
+    /// given live device.
+    fn diff_slots(&self, live: &Device, changes: &mut Vec<FleetChange>) {
+        let serial = self.serial;
+
+        for registered in &self.slots {
+            let slot = registered.slot;
+
+            match live.slot(slot) {
+                Some(current) => {
+                    if registered.subject != current.subject
+                        || registered.not_after != current.not_after
+                    {
+                        changes.push(FleetChange::CertificateChanged {
+                            serial,
+                            slot,
+                            not_after: current.not_after,
+                        });
+                    }
+                }
+                None => changes.push(FleetChange::SlotMissing { serial, slot }),
+            }
+        }
+
+        for current in &live.slots {
+            if self.slot(current.slot).is_none() {
+                changes.push(FleetChange::SlotUnexpected {
+                    serial,
+                    slot: current.slot,
+                });
+            }
+        }
+    }
+}
+
+/// Expected contents of a slot of a [`Device`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SlotAssignment {
+    /// Slot holding the key
+    pub slot: SlotId,
+
+    /// What the key is used for
+    pub purpose: Option<String>,
+
+    /// Subject of the certificate in the slot
+    pub subject: Option<String>,
+
+    /// Expiry of the certificate in the slot, since the Unix epoch
+    pub not_after: Option<Duration>,
+}
+
+impl SlotAssignment {
+    /// Does the certificate in this slot expire before the given time (since
+    /// the Unix epoch)?
+    pub fn expires_before(&self, time: Duration) -> bool {
+        self.not_after.map_or(false, |not_after| not_after < time)
+    }
+}
+
+/// Difference between a [`Fleet`] registry and the live state of its devices.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FleetChange {
+    /// A registered device wasn't found.
+    DeviceMissing(Serial),
+
+    /// A device was found which isn't registered.
+    DeviceUnregistered(Serial),
+
+    /// A slot which should hold a key is empty.
+    SlotMissing {
+        /// Serial number of the device
+        serial: Serial,
+        /// Empty slot
+        slot: SlotId,
+    },
+
+    /// A slot holds a key, but isn't registered.
+    SlotUnexpected {
+        /// Serial number of the device
+        serial: Serial,
+        /// Unregistered slot
+        slot: SlotId,
+    },
+
+    /// The certificate in a slot differs from the registered one.
+    CertificateChanged {
+        /// Serial number of the device
+        serial: Serial,
+        /// Slot containing the certificate
+        slot: SlotId,
+        /// Expiry of the certificate now in the slot
+        not_after: Option<Duration>,
+    },
+}
+
+/// Slots whose certificates are checked for expiry.
+#[derive(Copy, Clone, Debug)]
+pub enum Watched<'a> {
+    /// All slots of all devices
+    All,
+
+    /// The given slots of the devices with the given serial numbers
+    Slots(&'a [(Serial, SlotId)]),
+
+    /// The slots assigned to the devices registered in a fleet
+    Fleet(&'a Fleet),
+}
+
+impl Watched<'_> {
+    /// Is any slot of the device with the given serial number watched?
+    #[cfg(feature = "pcsc")]
+    fn device(&self, serial: Serial) -> bool {
+        match self {
+            Watched::All => true,
+            Watched::Slots(slots) => slots.iter().any(|(watched, _)| *watched == serial),
+            Watched::Fleet(fleet) => fleet.device(serial).is_some(),
+        }
+    }
+
+    /// Is the given slot of the device with the given serial number watched?
+    fn slot(&self, serial: Serial, slot: SlotId) -> bool {
+        match self {
+            Watched::All => true,
+            Watched::Slots(slots) => slots.contains(&(serial, slot)),
+            Watched::Fleet(fleet) => fleet
+                .device(serial)
+                .map_or(false, |device| device.slot(slot).is_some()),
+        }
+    }
+}
+
+/// Certificate about to expire (or already expired).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExpiringCertificate {
+    /// Serial number of the device
+    pub serial: Serial,
+
+    /// Slot containing the certificate
+    pub slot: SlotId,
+
+    /// Subject of the certificate
+    pub subject: Option<String>,
+
+    /// Expiry of the certificate, since the Unix epoch
+    pub not_after: Duration,
+}
+
+/// Result of checking the certificates of connected devices with
+/// [`scan_expiring`].
+#[derive(Debug, Default)]
+pub struct ExpiryReport {
+    /// Watched certificates expiring before the deadline, soonest first
+    pub expiring: Vec<ExpiringCertificate>,
+
+    /// Readers whose YubiKey couldn't be checked, and the errors encountered
+    pub errors: Vec<(String, Error)>,
+}
+
+/// Find the watched certificates of the given devices expiring before the
+/// given deadline (since the Unix epoch), soonest first.
+pub fn expiring_certificates(
+    devices: &[Device],
+    watched: Watched<'_>,
+    deadline: Duration,
+) -> Vec<ExpiringCertificate> {
+    let mut expiring: Vec<_> = devices
+        .iter()
+        .flat_map(|device| {
+            device
+                .slots
+                .iter()
+                .filter(move |assignment| {
+                    watched.slot(device.serial, assignment.slot)
+                        && assignment.expires_before(deadline)
+                })
+                .filter_map(move |assignment| {
+                    Some(ExpiringCertificate {
+                        serial: device.serial,
+                        slot: assignment.slot,
+                        subject: assignment.subject.clone(),
+                        not_after: assignment.not_after?,
+                    })
+                })
+        })
+        .collect();
+
+    expiring.sort_by_key(|certificate| certificate.not_after);
+    expiring
+}
+
+/// Check the certificates of the YubiKeys in all readers available in the
+/// given context, reporting the watched ones which expire within the given
+/// duration from now (e.g. `Duration::from_secs(30 * 86_400)` for 30 days).
+///
+/// Devices which aren't watched are left alone. An error opening or reading
+/// one YubiKey doesn't affect the others; it's recorded in the report.
+#[cfg(feature = "pcsc")]
+pub fn scan_expiring(
+    context: &mut Context,
+    watched: Watched<'_>,
+    within: Duration,
+) -> Result<ExpiryReport> {
+    let deadline = clock::unix_time(&SystemClock)?
+        .checked_add(within)
+        .ok_or(Error::RangeError)?;
+
+    let mut devices = vec![];
+    let mut errors = vec![];
+
+    for reader in context.iter()? {
+        let name = reader.name().into_owned();
+
+        let mut yubikey = match reader.open() {
+            Ok(yubikey) => yubikey,
+            Err(e) => {
+                debug!("error opening reader {}: {}", name, e);
+                errors.push((name, e));
+                continue;
+            }
+        };
+
+        if watched.device(yubikey.serial()) {
+            match Device::scan(&mut yubikey) {
+                Ok(device) => devices.push(device),
+                Err(e) => {
+                    debug!("error scanning reader {}: {}", name, e);
+                    errors.push((name, e));
+                }
+            }
+        }
+
+        // Don't reset the YubiKey
+        yubikey.leave_card();
+    }
+
+    Ok(ExpiryReport {
+        expiring: expiring_certificates(&devices, watched, deadline),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assignment(slot: SlotId, not_after: u64) -> SlotAssignment {
+        SlotAssignment {
+            slot,
+            purpose: None,
+            subject: Some("CN=test".to_owned()),
+            not_after: Some(Duration::from_secs(not_after)),
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let mut registered = Device::new(Serial(1));
+        registered.slots = vec![
+            assignment(SlotId::Authentication, 100),
+            assignment(SlotId::Signature, 100),
+        ];
+
+        let mut fleet = Fleet::default();
+        fleet.register(registered);
+        fleet.register(Device::new(Serial(2)));
+
+        let mut live = Device::new(Serial(1));
+        live.slots = vec![
+            assignment(SlotId::Authentication, 200),
+            assignment(SlotId::KeyManagement, 100),
+        ];
+
+        assert_eq!(
+            fleet.diff(&[live, Device::new(Serial(3))]),
+            [
+                FleetChange::CertificateChanged {
+                    serial: Serial(1),
+                    slot: SlotId::Authentication,
+                    not_after: Some(Duration::from_secs(200)),
+                },
+                FleetChange::SlotMissing {
+                    serial: Serial(1),
+                    slot: SlotId::Signature,
+                },
+                FleetChange::SlotUnexpected {
+                    serial: Serial(1),
+                    slot: SlotId::KeyManagement,
+                },
+                FleetChange::DeviceMissing(Serial(2)),
+                FleetChange::DeviceUnregistered(Serial(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn expiring() {
+        let mut first = Device::new(Serial(1));
+        first.slots = vec![
+            assignment(SlotId::Authentication, 300),
+            assignment(SlotId::Signature, 100),
+            assignment(SlotId::KeyManagement, 1000),
+        ];
+        let mut second = Device::new(Serial(2));
+        second.slots = vec![assignment(SlotId::Authentication, 200)];
+        let devices = [first, second];
+
+        let found = expiring_certificates(&devices, Watched::All, Duration::from_secs(500));
+        assert_eq!(
+            found
+                .iter()
+                .map(|certificate| (certificate.serial, certificate.slot))
+                .collect::<Vec<_>>(),
+            [
+                (Serial(1), SlotId::Signature),
+                (Serial(2), SlotId::Authentication),
+                (Serial(1), SlotId::Authentication),
+            ]
+        );
+
+        let watched = [(Serial(1), SlotId::Authentication)];
+        let found =
+            expiring_certificates(&devices, Watched::Slots(&watched), Duration::from_secs(500));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].not_after, Duration::from_secs(300));
+
+        let mut fleet = Fleet::default();
+        let mut registered = Device::new(Serial(2));
+        registered.slots = vec![assignment(SlotId::Authentication, 200)];
+        fleet.register(registered);
+
+        let found =
+            expiring_certificates(&devices, Watched::Fleet(&fleet), Duration::from_secs(500));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].serial, Serial(2));
+    }
+}
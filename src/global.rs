@@ -0,0 +1,195 @@
+//! Process-wide shared YubiKey handle.
+//!
+//! Applications (and test suites) talking to a single YubiKey from several
+//! places tend to keep a global `Lazy<Mutex<YubiKey>>`. [`global`] provides
+//! that handle, opened on first use, and additionally:
+//!
+//! - selects which YubiKey to open, and how to set it up, as configured with
+//!   [`init`];
+//! - revalidates the connection when the handle is taken (see
+//!   [`YubiKey::revalidate`]), and opens the YubiKey again if that fails,
+//!   e.g. because it was unplugged and plugged back in;
+//! - discards the handle if a thread panics while holding it, so other
+//!   threads get a freshly opened one rather than a poisoned lock.
+//!
+//! ```no_run
+//! use yubikey::{global::GlobalConfig, Serial};
+//!
+//! yubikey::global::init(GlobalConfig::new().serial(Serial(12_345_678)));
+//!
+//! let version = yubikey::global()?.version();
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+
+use crate::{
+    error::Result,
+    yubikey::{Serial, YubiKey},
+};
+use log::{info, warn};
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+/// Function setting up the YubiKey each time it's opened.
+type SetupFn = Box<dyn Fn(&mut YubiKey) -> Result<()> + Send>;
+
+/// State of the global handle.
+struct Global {
+    config: Option<GlobalConfig>,
+    yubikey: Option<YubiKey>,
+}
+
+static GLOBAL: Mutex<Global> = Mutex::new(Global {
+    config: None,
+    yubikey: None,
+});
+
+/// Configuration of the handle returned by [`global`].
+#[derive(Default)]
+pub struct GlobalConfig {
+    serial: Option<Serial>,
+    reader: Option<String>,
+    setup: Option<SetupFn>,
+}
+
+impl GlobalConfig {
+    /// Open the only connected YubiKey, as with [`YubiKey::open`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the YubiKey with the given serial number.
+    pub fn serial(mut self, serial: Serial) -> Self {
+        self.serial = Some(serial);
+        self
+    }
+
+    /// Open the YubiKey in the PC/SC reader with the given name.
+    ///
+    /// If a serial number is configured too, it takes precedence.
+    pub fn reader(mut self, name: impl Into<String>) -> Self {
+        self.reader = Some(name.into());
+        self
+    }
+
+    /// Set up the YubiKey with the given function each time it's opened,
+    /// e.g. to set a [`PinProvider`](crate::pin::PinProvider) or
+    /// middleware. Errors are returned by [`global`].
+    pub fn setup<F>(mut self, setup: F) -> Self
+    where
+        F: Fn(&mut YubiKey) -> Result<()> + Send + 'static,
+    {
+        self.setup = Some(Box::new(setup));
+        self
+    }
+
+    fn open(&self) -> Result<YubiKey> {
+        let mut yubikey = match (self.serial, &self.reader) {
+            (Some(serial), _) => YubiKey::open_by_serial(serial)?,
+            (None, Some(name)) => YubiKey::open_by_reader(name)?,
+            (None, None) => YubiKey::open()?,
+        };
+
+        if let Some(setup) = &self.setup {
+            setup(&mut yubikey)?;
+        }
+
+        info!("opened global handle to YubiKey {}", yubikey.serial());
+        Ok(yubikey)
+    }
+}
+
+impl fmt::Debug for GlobalConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlobalConfig")
+            .field("serial", &self.serial)
+            .field("reader", &self.reader)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configure the handle returned by [`global`].
+///
+/// This closes the current handle, if any: the YubiKey is opened with the
+/// new configuration when next taken.
+pub fn init(config: GlobalConfig) {
+    let mut global = lock();
+    global.config = Some(config);
+    global.yubikey = None;
+}
+
+/// Take the process-wide YubiKey handle, opening it if needed.
+///
+/// The handle is locked until the returned guard is dropped, so other
+/// threads calling this meanwhile block.
+pub fn global() -> Result<GlobalYubiKey> {
+    let mut global = lock();
+
+    if let Some(yubikey) = &mut global.yubikey {
+        if let Err(e) = yubikey.revalidate() {
+            warn!("global YubiKey handle is unusable ({}); reopening", e);
+            global.yubikey = None;
+        }
+    }
+
+    if global.yubikey.is_none() {
+        let yubikey = match &global.config {
+            Some(config) => config.open()?,
+            None => GlobalConfig::new().open()?,
+        };
+
+        global.yubikey = Some(yubikey);
+    }
+
+    Ok(GlobalYubiKey(global))
+}
+
+/// Close the process-wide YubiKey handle, if it's open.
+///
+/// It's opened again when next taken with [`global`].
+pub fn close() {
+    lock().yubikey = None;
+}
+
+/// Lock the global state.
+///
+/// Handles are discarded when a thread panics while holding them, so the
+/// state is consistent even if the lock was poisoned.
+fn lock() -> MutexGuard<'static, Global> {
+    GLOBAL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locked process-wide YubiKey handle, returned by [`global`].
+pub struct GlobalYubiKey(MutexGuard<'static, Global>);
+
+impl Deref for GlobalYubiKey {
+    type Target = YubiKey;
+
+    fn deref(&self) -> &YubiKey {
+        self.0.yubikey.as_ref().expect("global YubiKey is open")
+    }
+}
+
+impl DerefMut for GlobalYubiKey {
+    fn deref_mut(&mut self) -> &mut YubiKey {
+        self.0.yubikey.as_mut().expect("global YubiKey is open")
+    }
+}
+
+impl Drop for GlobalYubiKey {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            warn!("thread panicked while holding the global YubiKey handle; closing it");
+            self.0.yubikey = None;
+        }
+    }
+}
+
+impl fmt::Debug for GlobalYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GlobalYubiKey").field(&**self).finish()
+    }
+}
@@ -0,0 +1,161 @@
+//! Guard against destructive operations on YubiKeys not meant to be modified.
+//!
+//! Generating or importing a key overwrites the key already in the slot,
+//! writing a certificate or data object overwrites the one already stored,
+//! changing the management key locks out anyone holding the old one, and
+//! resetting the PIV application destroys every key and certificate. Running
+//! code which does any of these (e.g. this crate's integration tests) against
+//! the wrong YubiKey is unrecoverable.
+//!
+//! A [`DestructiveGuard`] added to a [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::add_middleware`](crate::YubiKey::add_middleware) refuses
+//! [destructive operations](Operation::is_destructive) unless the YubiKey's
+//! serial number is on an allowlist, typically taken from the
+//! `YUBIKEY_ALLOW_DESTRUCTIVE` environment variable:
+//!
+//! ```no_run
+//! use yubikey::{guard::DestructiveGuard, YubiKey};
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! let guard = DestructiveGuard::from_env(yubikey.serial())?;
+//! yubikey.add_middleware(guard);
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+
+use crate::{
+    error::{Error, Result},
+    middleware::{Middleware, Next, Operation},
+    yubikey::Serial,
+};
+use log::error;
+use std::{env, str::FromStr};
+
+/// Environment variable listing the serial numbers of the YubiKeys on which
+/// destructive operations are allowed, separated by commas.
+pub const ALLOW_DESTRUCTIVE_VAR: &str = "YUBIKEY_ALLOW_DESTRUCTIVE";
+
+/// Middleware refusing destructive operations on YubiKeys which aren't
+/// explicitly allowed.
+#[derive(Clone, Debug)]
+pub struct DestructiveGuard {
+    serial: Serial,
+    allowed: Vec<Serial>,
+}
+
+impl DestructiveGuard {
+    /// Guard the YubiKey with the given serial number, allowing destructive
+    /// operations only if it's one of `allowed`.
+    pub fn new(serial: Serial, allowed: impl IntoIterator<Item = Serial>) -> Self {
+        Self {
+            serial,
+            allowed: allowed.into_iter().collect(),
+        }
+    }
+
+    /// Guard the YubiKey with the given serial number, allowing destructive
+    /// operations only if it's listed in [`ALLOW_DESTRUCTIVE_VAR`].
+    ///
+    /// Returns [`Error::ArgumentError`] if the variable isn't a list of
+    /// serial numbers. If it isn't set, no destructive operations are
+    /// allowed.
+    pub fn from_env(serial: Serial) -> Result<Self> {
+        let allowed = match env::var(ALLOW_DESTRUCTIVE_VAR) {
+            Ok(allowed) => parse_allowlist(&allowed)?,
+            Err(env::VarError::NotPresent) => vec![],
+            Err(env::VarError::NotUnicode(_)) => {
+                error!("{} is not valid unicode", ALLOW_DESTRUCTIVE_VAR);
+                return Err(Error::ArgumentError);
+            }
+        };
+
+        Ok(Self::new(serial, allowed))
+    }
+
+    /// Are destructive operations allowed on the guarded YubiKey?
+    pub fn allows_destructive(&self) -> bool {
+        self.allowed.contains(&self.serial)
+    }
+}
+
+impl Middleware for DestructiveGuard {
+    fn handle(&mut self, operation: Operation, mut next: Next<'_>) -> Result<()> {
+        if operation.is_destructive() && !self.allows_destructive() {
+            error!(
+                "refusing {:?} on YubiKey {}: add its serial number to {} to allow it",
+                operation, self.serial, ALLOW_DESTRUCTIVE_VAR
+            );
+            return Err(Error::OperationDenied);
+        }
+
+        next.run()
+    }
+}
+
+/// Parse a comma-separated list of serial numbers.
+fn parse_allowlist(allowed: &str) -> Result<Vec<Serial>> {
+    allowed
+        .split(',')
+        .map(str::trim)
+        .filter(|serial| !serial.is_empty())
+        .map(|serial| {
+            Serial::from_str(serial).map_err(|_| {
+                error!(
+                    "invalid serial number in {}: {}",
+                    ALLOW_DESTRUCTIVE_VAR, serial
+                );
+                Error::ArgumentError
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        middleware,
+        piv::{AlgorithmId, SlotId},
+    };
+
+    #[test]
+    fn allowlist() {
+        assert_eq!(
+            parse_allowlist(" 123, 456,").expect("parse"),
+            [Serial(123), Serial(456)]
+        );
+        assert_eq!(parse_allowlist("123,abc"), Err(Error::ArgumentError));
+    }
+
+    #[test]
+    fn refuses_destructive_operations() {
+        let generate = Operation::Generate {
+            slot: SlotId::Authentication,
+            algorithm: AlgorithmId::EccP256,
+        };
+
+        let mut chain: Vec<Box<dyn Middleware>> =
+            vec![Box::new(DestructiveGuard::new(Serial(1), [Serial(2)]))];
+        let mut performed = 0;
+
+        let write_certificate = Operation::WriteCertificate {
+            slot: SlotId::Authentication,
+        };
+
+        for operation in [
+            Operation::VerifyPin,
+            generate,
+            write_certificate,
+            Operation::Reset,
+        ] {
+            let _ = middleware::dispatch(&mut chain, operation, &mut || {
+                performed += 1;
+                Ok(())
+            });
+        }
+        assert_eq!(performed, 1);
+
+        let mut chain: Vec<Box<dyn Middleware>> =
+            vec![Box::new(DestructiveGuard::new(Serial(2), [Serial(2)]))];
+        assert!(middleware::dispatch(&mut chain, Operation::Reset, &mut || Ok(())).is_ok());
+    }
+}
@@ -0,0 +1,417 @@
+//! Structured description of a YubiKey, mirroring `ykman info` and
+//! `ykman piv info`.
+//!
+//! [`Info`] gathers the device information, the status of its applications
+//! and of the PIV application, using the same names and values as Yubico's
+//! `ykman` CLI (e.g. `"Keychain (USB-C)"` or `"TDES"`), so scripts parsing
+//! `ykman` output can be ported to it. With the `serde` feature enabled, it
+//! can be serialized, e.g. to JSON with [`YubiKey::info_json`].
+
+use crate::{
+    certificate::Certificate,
+    device::{Capabilities, DeviceInfo, FormFactor, ProductVariant},
+    error::Result,
+    piv::{self, AlgorithmId, ManagementAlgorithmId, SlotId, SLOTS},
+    yubikey::YubiKey,
+};
+use log::debug;
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Applications as named by `ykman`, in the order it lists them.
+const APPLICATIONS: &[(&str, Capabilities)] = &[
+    ("Yubico OTP", Capabilities::OTP),
+    ("FIDO U2F", Capabilities::U2F),
+    ("FIDO2", Capabilities::FIDO2),
+    ("OATH", Capabilities::OATH),
+    ("PIV", Capabilities::PIV),
+    ("OpenPGP", Capabilities::OPENPGP),
+    ("YubiHSM Auth", Capabilities::HSMAUTH),
+];
+
+/// Description of a YubiKey, as output by `ykman info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Info {
+    /// Device type (e.g. `YubiKey 5C NFC`), if known
+    pub device_type: Option<String>,
+
+    /// Serial number
+    pub serial_number: u32,
+
+    /// Firmware version
+    pub firmware_version: String,
+
+    /// Form factor (e.g. `Keychain (USB-A)`), if known
+    pub form_factor: Option<String>,
+
+    /// USB interfaces enabled (`OTP`, `FIDO` and/or `CCID`), if known
+    pub enabled_usb_interfaces: Option<Vec<String>>,
+
+    /// Whether the NFC transport is enabled, if known and available
+    pub nfc_transport_enabled: Option<bool>,
+
+    /// Whether the configuration is locked, if known
+    pub configuration_locked: Option<bool>,
+
+    /// Status of each application, if known
+    pub applications: Vec<ApplicationInfo>,
+
+    /// Status of the PIV application
+    pub piv: PivInfo,
+}
+
+/// Status of an application over each transport, as output by `ykman info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ApplicationInfo {
+    /// Name of the application (e.g. `FIDO U2F`)
+    pub name: String,
+
+    /// Status over USB: `Enabled`, `Disabled` or `Not available`
+    pub usb: String,
+
+    /// Status over NFC: `Enabled`, `Disabled` or `Not available`
+    pub nfc: String,
+}
+
+/// Status of the PIV application, as output by `ykman piv info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct PivInfo {
+    /// PIV application version
+    pub version: String,
+
+    /// Number of PIN tries remaining, if it could be read
+    pub pin_tries_remaining: Option<u8>,
+
+    /// Management key algorithm (`TDES`, `AES128`, `AES192` or `AES256`)
+    pub management_key_algorithm: String,
+
+    /// Slots containing a certificate
+    pub slots: Vec<SlotInfo>,
+}
+
+/// Certificate in a PIV slot, as output by `ykman piv info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SlotInfo {
+    /// Slot (e.g. `9A`)
+    pub slot: String,
+
+    /// Algorithm of the key (e.g. `ECCP256`), if known
+    pub algorithm: Option<String>,
+
+    /// Subject DN of the certificate
+    pub subject: String,
+
+    /// Issuer DN of the certificate
+    pub issuer: String,
+
+    /// Serial number of the certificate, in hex
+    pub serial: String,
+
+    /// SHA-256 fingerprint of the certificate, in hex
+    pub fingerprint: String,
+
+    /// Start of the validity period of the certificate
+    pub not_before: String,
+
+    /// End of the validity period of the certificate
+    pub not_after: String,
+}
+
+impl Info {
+    /// Gather the description of the given YubiKey.
+    ///
+    /// Device information is only available on YubiKey 5 Series (and newer)
+    /// devices: the corresponding fields are left empty on older ones.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let device_info = yubikey
+            .device_info()
+            .map_err(|e| debug!("couldn't read device info: {}", e))
+            .ok();
+
+        let mut info = Self {
+            device_type: None,
+            serial_number: yubikey.serial().into(),
+            firmware_version: yubikey.version().to_string(),
+            form_factor: None,
+            enabled_usb_interfaces: None,
+            nfc_transport_enabled: None,
+            configuration_locked: None,
+            applications: vec![],
+            piv: PivInfo::read(yubikey)?,
+        };
+
+        if let Some(device_info) = device_info {
+            info.device_type = Some(device_type(&device_info, yubikey.version().major));
+            info.form_factor = Some(form_factor_name(device_info.form_factor).into());
+            info.enabled_usb_interfaces = Some(usb_interfaces(device_info.usb_enabled));
+            info.nfc_transport_enabled = device_info.nfc_enabled.map(|nfc| nfc.0 != 0);
+            info.configuration_locked = Some(device_info.config_locked);
+            info.applications = APPLICATIONS
+                .iter()
+                .map(|&(name, capability)| ApplicationInfo {
+                    name: name.into(),
+                    usb: status(
+                        Some(device_info.usb_supported),
+                        Some(device_info.usb_enabled),
+                        capability,
+                    ),
+                    nfc: status(
+                        device_info.nfc_supported,
+                        device_info.nfc_enabled,
+                        capability,
+                    ),
+                })
+                .collect();
+        }
+
+        Ok(info)
+    }
+}
+
+impl PivInfo {
+    /// Gather the status of the PIV application of the given YubiKey.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let management_key_algorithm = match yubikey.mgm_metadata() {
+            Ok(metadata) => management_algorithm_name(metadata.algorithm),
+            // Firmware without metadata only supports 3DES management keys
+            Err(_) => "TDES",
+        };
+
+        let mut slots = vec![];
+
+        for slot in SLOTS {
+            if let SlotId::Management(_) = slot {
+                continue;
+            }
+
+            let cert = match Certificate::read(yubikey, slot) {
+                Ok(cert) => cert,
+                Err(_) => continue,
+            };
+
+            let algorithm = piv::metadata(yubikey, slot)
+                .ok()
+                .and_then(|metadata| match metadata.algorithm {
+                    ManagementAlgorithmId::Asymmetric(algorithm) => Some(algorithm),
+                    _ => None,
+                })
+                .map(|algorithm| algorithm_name(algorithm).into());
+
+            let tbs = &cert.cert.tbs_certificate;
+
+            slots.push(SlotInfo {
+                slot: format!("{:02X}", u8::from(slot)),
+                algorithm,
+                subject: cert.subject(),
+                issuer: cert.issuer(),
+                serial: hex::lower::encode_string(tbs.serial_number.as_bytes()),
+                fingerprint: hex::lower::encode_string(&Sha256::digest(cert.as_der())),
+                not_before: tbs.validity.not_before.to_string(),
+                not_after: tbs.validity.not_after.to_string(),
+            });
+        }
+
+        // Reading the PIN retries reselects the PIV application, so do it last
+        let pin_tries_remaining = match yubikey.get_pin_retries() {
+            Ok(tries) => Some(tries),
+            Err(e) => {
+                debug!("couldn't read PIN retries: {}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            version: yubikey.version().to_string(),
+            pin_tries_remaining,
+            management_key_algorithm: management_key_algorithm.into(),
+            slots,
+        })
+    }
+}
+
+/// Get the device type of a YubiKey, as named by `ykman` (e.g. `YubiKey 5C
+/// NFC`).
+fn device_type(info: &DeviceInfo, major: u8) -> String {
+    let mut name = match info.variant() {
+        ProductVariant::SecurityKey => "Security Key".to_owned(),
+        _ if matches!(info.form_factor, FormFactor::UsbABio | FormFactor::UsbCBio) => {
+            "YubiKey Bio".to_owned()
+        }
+        _ => format!("YubiKey {}", major),
+    };
+
+    match info.form_factor {
+        FormFactor::UsbCKeychain | FormFactor::UsbCNano => name.push('C'),
+        FormFactor::UsbCLightning => name.push_str("Ci"),
+        _ => (),
+    }
+
+    if let ProductVariant::SecurityKey = info.variant() {
+        // ykman separates the connector of Security Keys: "Security Key C NFC"
+        if name.ends_with('C') {
+            name.insert(name.len() - 1, ' ');
+        }
+    }
+
+    if info.nfc_supported.map_or(false, |nfc| nfc.0 != 0) {
+        name.push_str(" NFC");
+    }
+
+    if matches!(
+        info.form_factor,
+        FormFactor::UsbANano | FormFactor::UsbCNano
+    ) {
+        name.push_str(" Nano");
+    }
+
+    match info.variant() {
+        ProductVariant::Fips => name.push_str(" FIPS"),
+        ProductVariant::Cspn => name.push_str(" CSPN"),
+        _ => (),
+    }
+
+    name
+}
+
+/// Get the name of a form factor, as output by `ykman`.
+fn form_factor_name(form_factor: FormFactor) -> &'static str {
+    match form_factor {
+        FormFactor::UsbAKeychain => "Keychain (USB-A)",
+        FormFactor::UsbANano => "Nano (USB-A)",
+        FormFactor::UsbCKeychain => "Keychain (USB-C)",
+        FormFactor::UsbCNano => "Nano (USB-C)",
+        FormFactor::UsbCLightning => "Keychain (USB-C, Lightning)",
+        FormFactor::UsbABio => "Bio (USB-A)",
+        FormFactor::UsbCBio => "Bio (USB-C)",
+        _ => "Unknown",
+    }
+}
+
+/// Get the USB interfaces providing the given applications.
+fn usb_interfaces(enabled: Capabilities) -> Vec<String> {
+    let interfaces = [
+        ("OTP", Capabilities::OTP.0),
+        ("FIDO", Capabilities::U2F.0 | Capabilities::FIDO2.0),
+        (
+            "CCID",
+            Capabilities::OATH.0
+                | Capabilities::PIV.0
+                | Capabilities::OPENPGP.0
+                | Capabilities::HSMAUTH.0,
+        ),
+    ];
+
+    interfaces
+        .iter()
+        .filter(|(_, applications)| enabled.0 & applications != 0)
+        .map(|(name, _)| (*name).into())
+        .collect()
+}
+
+/// Get the status of an application over a transport, as output by `ykman`.
+fn status(
+    supported: Option<Capabilities>,
+    enabled: Option<Capabilities>,
+    application: Capabilities,
+) -> String {
+    if !supported.map_or(false, |supported| supported.contains(application)) {
+        "Not available"
+    } else if enabled.map_or(false, |enabled| enabled.contains(application)) {
+        "Enabled"
+    } else {
+        "Disabled"
+    }
+    .into()
+}
+
+fn management_algorithm_name(algorithm: ManagementAlgorithmId) -> &'static str {
+    match algorithm {
+        ManagementAlgorithmId::Aes128 => "AES128",
+        ManagementAlgorithmId::Aes192 => "AES192",
+        ManagementAlgorithmId::Aes256 => "AES256",
+        _ => "TDES",
+    }
+}
+
+fn algorithm_name(algorithm: AlgorithmId) -> &'static str {
+    match algorithm {
+        AlgorithmId::Rsa1024 => "RSA1024",
+        AlgorithmId::Rsa2048 => "RSA2048",
+        AlgorithmId::Rsa3072 => "RSA3072",
+        AlgorithmId::Rsa4096 => "RSA4096",
+        AlgorithmId::EccP256 => "ECCP256",
+        AlgorithmId::EccP384 => "ECCP384",
+        AlgorithmId::Ed25519 => "ED25519",
+        AlgorithmId::X25519 => "X25519",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ykman_names() {
+        assert_eq!(
+            usb_interfaces(Capabilities::OTP | Capabilities::FIDO2 | Capabilities::PIV),
+            ["OTP", "FIDO", "CCID"]
+        );
+        assert_eq!(usb_interfaces(Capabilities::U2F), ["FIDO"]);
+
+        let supported = Some(Capabilities::PIV | Capabilities::OATH);
+        let enabled = Some(Capabilities::PIV);
+        assert_eq!(status(supported, enabled, Capabilities::PIV), "Enabled");
+        assert_eq!(status(supported, enabled, Capabilities::OATH), "Disabled");
+        assert_eq!(status(None, None, Capabilities::PIV), "Not available");
+    }
+
+    /// Parse device info with the given supported applications (over USB and
+    /// NFC, if any) and form factor byte, from a YubiKey 5.4.3.
+    fn device_info(usb: [u8; 2], nfc: Option<[u8; 2]>, form_factor: u8) -> DeviceInfo {
+        let mut data = vec![0x01, 0x02, usb[0], usb[1], 0x04, 0x01, form_factor];
+        data.extend_from_slice(&[0x05, 0x03, 0x05, 0x04, 0x03]);
+
+        if let Some(nfc) = nfc {
+            data.extend_from_slice(&[0x0d, 0x02, nfc[0], nfc[1]]);
+        }
+
+        DeviceInfo::parse(&data).expect("valid device info")
+    }
+
+    #[test]
+    fn device_types() {
+        let all = [0x02, 0x3b];
+        let fido = [0x02, 0x02];
+
+        assert_eq!(
+            device_type(&device_info(all, Some(all), 0x01), 5),
+            "YubiKey 5 NFC"
+        );
+        assert_eq!(
+            device_type(&device_info(all, None, 0x04), 5),
+            "YubiKey 5C Nano"
+        );
+        assert_eq!(
+            device_type(&device_info(all, None, 0x85), 5),
+            "YubiKey 5Ci FIPS"
+        );
+        assert_eq!(
+            device_type(&device_info([0x02, 0x13], None, 0x03), 5),
+            "YubiKey 5C CSPN"
+        );
+        assert_eq!(
+            device_type(&device_info(fido, Some(fido), 0x43), 5),
+            "Security Key C NFC"
+        );
+        assert_eq!(
+            device_type(&device_info(fido, None, 0x06), 5),
+            "YubiKey Bio"
+        );
+    }
+}
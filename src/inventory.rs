@@ -0,0 +1,464 @@
+//! Inventory of the keys stored in a YubiKey, for auditing purposes.
+//!
+//! An [`Inventory`] records the public key found in each populated slot of a
+//! YubiKey, along with any known weaknesses of those keys, such as RSA keys
+//! generated by the Infineon library affected by ROCA ([CVE-2017-15361]).
+//! It also records the [`CredentialStatus`] of the PIN and PUK, so that
+//! YubiKeys whose PIN or PUK is blocked can be reset before their users are
+//! locked out.
+//!
+//! [CVE-2017-15361]: https://www.yubico.com/support/security-advisories/ysa-2017-01/
+
+use crate::{
+    advisory,
+    capability::Capability,
+    certificate,
+    config::AdminMetadata,
+    error::{Error, Result},
+    piv::{self, AlgorithmId, ManagementAlgorithmId, Origin, PinMetadata, SlotId, SLOTS},
+    policy::{PinPolicy, TouchPolicy},
+    yubikey::{Serial, Version, YubiKey},
+    Certificate, SlotLabels,
+};
+use log::{debug, error, info};
+use num_traits::ToPrimitive;
+use rsa::{traits::PublicKeyParts, BigUint, RsaPublicKey};
+use x509_cert::{der::referenced::OwnedToRef, spki::SubjectPublicKeyInfoOwned};
+
+#[cfg(feature = "pcsc")]
+use {crate::reader::Context, log::warn};
+
+/// Small primes used to detect the ROCA fingerprint.
+///
+/// These are the primes used by the reference detection tool published
+/// alongside the ROCA paper: <https://github.com/crocs-muni/roca>
+const ROCA_PRIMES: [u32; 38] = [
+    3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167,
+];
+
+/// Generator used by the Infineon library when constructing RSA primes.
+const ROCA_GENERATOR: u32 = 65537;
+
+/// Inventory of the keys stored in a YubiKey.
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    /// Serial number of the YubiKey
+    pub serial: Serial,
+
+    /// Firmware version of the YubiKey
+    pub version: Version,
+
+    /// Slots containing a key
+    pub slots: Vec<SlotInventory>,
+
+    /// Status of the PIN and PUK
+    pub credentials: CredentialStatus,
+}
+
+impl Inventory {
+    /// Collect an inventory of the keys stored in the given YubiKey.
+    ///
+    /// Public keys are read from the slot metadata where the firmware supports
+    /// it, and otherwise from the certificate stored alongside each key. Slots
+    /// without a certificate can't be inventoried on older firmware. Any
+    /// [`SlotLabels`] stored on the YubiKey are included, as is the
+    /// [`CredentialStatus`] of the PIN and PUK.
+    pub fn collect(yubikey: &mut YubiKey) -> Result<Self> {
+        let use_metadata = yubikey.supports(Capability::Metadata);
+        let labels = SlotLabels::read(yubikey).unwrap_or_else(|e| {
+            debug!("couldn't read slot labels: {}", e);
+            SlotLabels::default()
+        });
+        let mut slots = vec![];
+
+        for slot in SLOTS.iter().cloned() {
+            if let SlotId::Management(_) = slot {
+                continue;
+            }
+
+            let entry = if use_metadata {
+                slot_from_metadata(yubikey, slot)?
+            } else {
+                slot_from_certificate(yubikey, slot)?
+            };
+
+            slots.extend(entry.map(|mut entry| {
+                entry.label = labels.get(slot).map(String::from);
+                entry
+            }));
+        }
+
+        Ok(Self {
+            serial: yubikey.serial(),
+            version: yubikey.version(),
+            slots,
+            credentials: CredentialStatus::collect(yubikey)?,
+        })
+    }
+
+    /// Does this YubiKey run firmware affected by ROCA (4.2.6 to 4.3.4)?
+    ///
+    /// Keys generated on such a YubiKey may be vulnerable; see
+    /// [`SlotInventory::roca_vulnerable`] for the keys which actually are.
+    pub fn firmware_roca_affected(&self) -> bool {
+        advisory::YSA_2017_01.affects(self.version)
+    }
+
+    /// Iterate over the slots containing an RSA key vulnerable to ROCA.
+    pub fn roca_vulnerable_slots(&self) -> impl Iterator<Item = &SlotInventory> {
+        self.slots.iter().filter(|slot| slot.roca_vulnerable)
+    }
+
+    /// Iterate over the slots containing a key which can be used without the
+    /// PIN, i.e. with the [`PinPolicy::Never`] PIN policy.
+    ///
+    /// The [`SlotId::CardAuthentication`] slot is left out, as it's meant to
+    /// be used without the PIN. PIN policies are only known on firmware 5.3
+    /// and newer.
+    pub fn pin_never_slots(&self) -> impl Iterator<Item = &SlotInventory> {
+        self.slots.iter().filter(|slot| {
+            slot.slot != SlotId::CardAuthentication
+                && matches!(slot.policy, Some((PinPolicy::Never, _)))
+        })
+    }
+}
+
+/// Replace the keys found by [`Inventory::pin_never_slots`] with newly
+/// generated keys of the same algorithm, using the given stricter policies.
+///
+/// WARNING: this is a destructive operation which will destroy the replaced
+/// keys! Certificates stored in their slots no longer match the new keys, and
+/// need to be reissued.
+///
+/// The management key must be authenticated. Returns the inventory entries
+/// of the regenerated slots, or [`Error::ArgumentError`] if `pin_policy`
+/// isn't stricter than [`PinPolicy::Never`].
+pub fn regenerate_pin_never_slots(
+    yubikey: &mut YubiKey,
+    inventory: &Inventory,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<Vec<SlotInventory>> {
+    // The default PIN policy of some slots is `Never`
+    if matches!(pin_policy, PinPolicy::Never | PinPolicy::Default) {
+        error!("slots must be regenerated with an explicit PIN policy requiring the PIN");
+        return Err(Error::ArgumentError);
+    }
+
+    let mut regenerated = vec![];
+
+    for entry in inventory.pin_never_slots() {
+        let algorithm = entry.algorithm.ok_or_else(|| {
+            error!("algorithm of the key in slot {} is unknown", entry.slot);
+            Error::AlgorithmError
+        })?;
+
+        info!(
+            "regenerating key in slot {} with PIN policy {:?}",
+            entry.slot, pin_policy
+        );
+
+        let public_key = piv::generate(yubikey, entry.slot, algorithm, pin_policy, touch_policy)?;
+
+        let mut entry = SlotInventory::new(entry.slot, public_key, Some(Origin::Generated));
+        entry.algorithm = Some(algorithm);
+        entry.policy = Some((pin_policy, touch_policy));
+        regenerated.push(entry);
+    }
+
+    Ok(regenerated)
+}
+
+/// Status of the PIN and PUK of a YubiKey.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CredentialStatus {
+    /// Number of PIN tries remaining, if known (requires firmware 5.3 or
+    /// newer)
+    pub pin_retries: Option<u8>,
+
+    /// Number of PUK tries remaining, if known (requires firmware 5.3 or
+    /// newer)
+    pub puk_retries: Option<u8>,
+
+    /// Whether the PUK was blocked on purpose, as recorded in the
+    /// [`AdminMetadata`] (e.g. when using a PIN-protected management key)
+    pub puk_blocked_on_purpose: bool,
+}
+
+impl CredentialStatus {
+    /// Collect the status of the PIN and PUK of the given YubiKey.
+    ///
+    /// Unlike [`YubiKey::get_pin_retries`], this doesn't end the current PIN
+    /// session: retry counts are read from the PIN and PUK metadata, and are
+    /// left unknown on firmware which doesn't support it.
+    pub fn collect(yubikey: &mut YubiKey) -> Result<Self> {
+        let admin = AdminMetadata::read(yubikey).unwrap_or_else(|e| {
+            debug!("couldn't read admin metadata: {}", e);
+            AdminMetadata::default()
+        });
+
+        let (pin_retries, puk_retries) = if yubikey.supports(Capability::Metadata) {
+            (
+                remaining_retries(yubikey.pin_metadata())?,
+                remaining_retries(yubikey.puk_metadata())?,
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            pin_retries,
+            puk_retries,
+            puk_blocked_on_purpose: admin.puk_blocked,
+        })
+    }
+
+    /// Is the PIN known to be blocked?
+    pub fn pin_blocked(&self) -> bool {
+        self.pin_retries == Some(0)
+    }
+
+    /// Is the PUK known to be blocked, either on purpose or after too many
+    /// wrong attempts?
+    ///
+    /// Once both the PIN and PUK are blocked, the PIV application can only
+    /// be reset: see [`recovery`](crate::recovery).
+    pub fn puk_blocked(&self) -> bool {
+        self.puk_blocked_on_purpose || self.puk_retries == Some(0)
+    }
+}
+
+/// A populated slot in an [`Inventory`].
+#[derive(Clone, Debug)]
+pub struct SlotInventory {
+    /// Slot containing the key
+    pub slot: SlotId,
+
+    /// Public key of the key in the slot
+    pub public_key: SubjectPublicKeyInfoOwned,
+
+    /// Whether the key was generated on the YubiKey or imported, if known
+    pub origin: Option<Origin>,
+
+    /// Algorithm of the key, if known (requires firmware 5.3 or newer)
+    pub algorithm: Option<AlgorithmId>,
+
+    /// PIN and touch policies of the key, if known (requires firmware 5.3 or
+    /// newer)
+    pub policy: Option<(PinPolicy, TouchPolicy)>,
+
+    /// Whether the key is an RSA key with the ROCA (CVE-2017-15361) fingerprint
+    pub roca_vulnerable: bool,
+
+    /// Label assigned to the slot with [`SlotLabels`], if any
+    pub label: Option<String>,
+}
+
+impl SlotInventory {
+    fn new(slot: SlotId, public_key: SubjectPublicKeyInfoOwned, origin: Option<Origin>) -> Self {
+        let roca_vulnerable = RsaPublicKey::try_from(public_key.owned_to_ref())
+            .map(|key| is_roca_vulnerable(&key))
+            .unwrap_or(false);
+
+        Self {
+            slot,
+            public_key,
+            origin,
+            algorithm: None,
+            policy: None,
+            roca_vulnerable,
+            label: None,
+        }
+    }
+}
+
+/// Result of inventorying the YubiKey in a single reader during a [`scan`].
+#[derive(Debug)]
+pub struct ReaderScan {
+    /// Name of the PC/SC reader
+    pub reader: String,
+
+    /// Inventory of the YubiKey in the reader, or the error encountered
+    /// while opening or inventorying it
+    pub result: Result<Inventory>,
+}
+
+/// Combined report from inventorying all detected YubiKeys with [`scan`].
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Results for each reader, in the order the readers were enumerated
+    pub readers: Vec<ReaderScan>,
+}
+
+impl ScanReport {
+    /// Iterate over the inventories of the YubiKeys which were scanned successfully.
+    pub fn inventories(&self) -> impl Iterator<Item = &Inventory> {
+        self.readers
+            .iter()
+            .filter_map(|scan| scan.result.as_ref().ok())
+    }
+
+    /// Iterate over the inventories of the YubiKeys whose PIN or PUK is
+    /// blocked.
+    pub fn blocked(&self) -> impl Iterator<Item = &Inventory> {
+        self.inventories().filter(|inventory| {
+            inventory.credentials.pin_blocked() || inventory.credentials.puk_blocked()
+        })
+    }
+
+    /// Iterate over the readers which couldn't be scanned, and the errors
+    /// encountered.
+    pub fn errors(&self) -> impl Iterator<Item = (&str, Error)> {
+        self.readers.iter().filter_map(|scan| {
+            scan.result
+                .as_ref()
+                .err()
+                .map(|e| (scan.reader.as_str(), *e))
+        })
+    }
+}
+
+/// Open the YubiKeys in all readers available in the given context and
+/// collect their inventories concurrently, using one thread per reader.
+///
+/// An error opening or inventorying one YubiKey doesn't affect the others;
+/// it's recorded in the corresponding [`ReaderScan`] instead.
+#[cfg(feature = "pcsc")]
+pub fn scan(context: &mut Context) -> Result<ScanReport> {
+    let readers: Vec<_> = context.iter()?.collect();
+
+    let readers = std::thread::scope(|scope| {
+        let handles: Vec<_> = readers
+            .iter()
+            .map(|reader| {
+                let handle = scope.spawn(move || {
+                    let mut yubikey = reader.open()?;
+                    Inventory::collect(&mut yubikey)
+                });
+
+                (reader.name().into_owned(), handle)
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|(reader, handle)| {
+                let result = handle.join().unwrap_or_else(|_| {
+                    warn!("inventory of reader {} panicked", reader);
+                    Err(Error::GenericError)
+                });
+
+                if let Err(e) = &result {
+                    debug!("error scanning reader {}: {}", reader, e);
+                }
+
+                ReaderScan { reader, result }
+            })
+            .collect()
+    });
+
+    Ok(ScanReport { readers })
+}
+
+/// Get the number of tries remaining for the PIN or PUK from its metadata.
+fn remaining_retries(metadata: Result<PinMetadata>) -> Result<Option<u8>> {
+    match metadata {
+        Ok(metadata) => Ok(Some(metadata.remaining_retries)),
+        Err(Error::NotSupported) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Inventory a slot using its metadata.
+fn slot_from_metadata(yubikey: &mut YubiKey, slot: SlotId) -> Result<Option<SlotInventory>> {
+    match piv::metadata(yubikey, slot) {
+        Ok(metadata) => Ok(metadata.public.map(|public_key| {
+            let mut entry = SlotInventory::new(slot, public_key, metadata.origin);
+
+            entry.policy = metadata.policy;
+            entry.algorithm = match metadata.algorithm {
+                ManagementAlgorithmId::Asymmetric(algorithm) => Some(algorithm),
+                _ => None,
+            };
+
+            entry
+        })),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Inventory a slot using the certificate stored alongside its key.
+fn slot_from_certificate(yubikey: &mut YubiKey, slot: SlotId) -> Result<Option<SlotInventory>> {
+    let buf = {
+        let txn = yubikey.begin_transaction()?;
+        certificate::read_certificate(&txn, slot)?
+    };
+
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    match Certificate::from_bytes(buf) {
+        Ok(cert) => {
+            let public_key = cert.cert.tbs_certificate.subject_public_key_info.clone();
+            Ok(Some(SlotInventory::new(slot, public_key, None)))
+        }
+        Err(e) => {
+            debug!("error parsing certificate in slot {:?}: {}", slot, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Does the given RSA public key have the fingerprint of keys generated by
+/// the Infineon library affected by ROCA (CVE-2017-15361)?
+///
+/// Moduli generated by the affected library are congruent to a power of
+/// 65537 modulo every small prime, which is vanishingly unlikely for keys
+/// generated otherwise.
+pub fn is_roca_vulnerable(public_key: &RsaPublicKey) -> bool {
+    has_roca_fingerprint(public_key.n())
+}
+
+fn has_roca_fingerprint(modulus: &BigUint) -> bool {
+    ROCA_PRIMES.iter().all(|&prime| {
+        let residue = (modulus % prime)
+            .to_u32()
+            .expect("residue is smaller than the prime");
+
+        // Walk the subgroup generated by 65537 modulo the prime
+        let generator = ROCA_GENERATOR % prime;
+        let mut element = 1;
+
+        loop {
+            if element == residue {
+                return true;
+            }
+
+            element = element * generator % prime;
+
+            if element == 1 {
+                return false;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::Pow;
+
+    #[test]
+    fn roca_fingerprint() {
+        // Any power of 65537 trivially has the fingerprint
+        let modulus = BigUint::from(ROCA_GENERATOR).pow(5u32);
+        assert!(has_roca_fingerprint(&modulus));
+
+        // Multiples of a small prime never have the fingerprint
+        let modulus = BigUint::from(ROCA_GENERATOR).pow(5u32) * BigUint::from(3u32);
+        assert!(!has_roca_fingerprint(&modulus));
+    }
+}
@@ -0,0 +1,339 @@
+//! Journaled writes of several related objects.
+//!
+//! Provisioning a YubiKey usually writes several objects which only make
+//! sense together (e.g. a certificate, the CHUID and the key history). If the
+//! process is interrupted (the YubiKey is unplugged, the host crashes...)
+//! partway through, the YubiKey is left half-provisioned.
+//!
+//! A [`WriteSequence`] avoids this by first backing up the objects it's about
+//! to overwrite into scratch objects, and recording its intent in a journal
+//! object. The journal is cleared once all objects have been written, so a
+//! [`Journal`] remaining on the YubiKey means a sequence was interrupted, and
+//! can be [rolled back](Journal::rollback) to restore the objects it was
+//! writing. [`WriteSequence::commit`] rolls back interrupted sequences before
+//! writing anything.
+//!
+//! The journal and backups are stored in Yubico's vendor-specific object
+//! range, and writing them requires authenticating with the management key,
+//! as for the objects themselves.
+
+use crate::{
+    certificate::{self, CertInfo, Certificate},
+    consts::{CB_OBJ_MAX, CB_STORAGE_MAX, JOURNAL_BACKUPS, OBJ_JOURNAL, OBJ_JOURNAL_BACKUP},
+    error::{Error, Result},
+    middleware::Operation,
+    piv::SlotId,
+    serialization::*,
+    transaction::Transaction,
+    yubikey::YubiKey,
+    Buffer, ObjectId,
+};
+use log::{error, warn};
+
+const TAG_JOURNAL: u16 = 0x80;
+const TAG_JOURNAL_ENTRY: u16 = 0x81;
+
+/// Sequence of object writes applied all together, or not at all.
+#[derive(Clone, Debug, Default)]
+pub struct WriteSequence {
+    writes: Vec<(ObjectId, Buffer)>,
+}
+
+impl WriteSequence {
+    /// Maximum number of objects written by a sequence.
+    pub const MAX_OBJECTS: usize = JOURNAL_BACKUPS;
+
+    /// Create an empty write sequence.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a write of the given data to the given object, deleting it if the
+    /// data is empty.
+    ///
+    /// Returns [`Error::SizeError`] if the data doesn't fit in an object or
+    /// the sequence is full, and [`Error::ArgumentError`] if the object is
+    /// already written by the sequence or is used by the journal.
+    pub fn write(&mut self, object_id: ObjectId, data: &[u8]) -> Result<()> {
+        if data.len() > CB_OBJ_MAX || self.writes.len() >= Self::MAX_OBJECTS {
+            error!("object or write sequence too large");
+            return Err(Error::SizeError);
+        }
+
+        let reserved = OBJ_JOURNAL_BACKUP..OBJ_JOURNAL_BACKUP + Self::MAX_OBJECTS as ObjectId;
+        if object_id == OBJ_JOURNAL
+            || reserved.contains(&object_id)
+            || self.writes.iter().any(|(id, _)| *id == object_id)
+        {
+            error!("object {:06x} can't be written by this sequence", object_id);
+            return Err(Error::ArgumentError);
+        }
+
+        self.writes.push((object_id, Buffer::new(data.to_vec())));
+        Ok(())
+    }
+
+    /// Add a write of the given certificate to the given slot.
+    pub fn certificate(
+        &mut self,
+        slot: SlotId,
+        cert: &Certificate,
+        certinfo: CertInfo,
+    ) -> Result<()> {
+        let mut buf = [0u8; CB_OBJ_MAX];
+        let len = certificate::encode_certificate(&mut buf, cert.as_der(), certinfo)?;
+        self.write(slot.object_id(), &buf[..len])
+    }
+
+    /// Apply the writes of this sequence to the YubiKey.
+    ///
+    /// Any interrupted sequence is rolled back first. If this fails, the
+    /// journal is left on the YubiKey: see [`Journal::rollback`].
+    ///
+    /// Returns [`Error::SizeError`] without writing anything if the backups
+    /// of the objects overwritten and the objects written can't fit in the
+    /// YubiKey's storage together.
+    ///
+    /// The management key must be authenticated.
+    pub fn commit(&self, yubikey: &mut YubiKey) -> Result<()> {
+        yubikey.run_write(
+            Operation::WriteSequence,
+            |_| Ok(()),
+            |yubikey| self.perform(yubikey),
+        )
+    }
+
+    fn perform(&self, yubikey: &mut YubiKey) -> Result<()> {
+        if let Some(journal) = Journal::read(yubikey)? {
+            warn!(
+                "rolling back interrupted write sequence of {} objects",
+                journal.entries.len()
+            );
+            journal.rollback(yubikey)?;
+        }
+
+        let txn = yubikey.begin_transaction()?;
+
+        let mut backups = Vec::with_capacity(self.writes.len());
+        for (object_id, _) in &self.writes {
+            backups.push(fetch_object(&txn, *object_id)?);
+        }
+
+        let backups_len: usize = backups.iter().flatten().map(|data| data.len()).sum();
+        let writes_len: usize = self.writes.iter().map(|(_, data)| data.len()).sum();
+        if backups_len + writes_len > CB_STORAGE_MAX {
+            error!(
+                "write sequence needs {} bytes of storage for backups and {} for writes",
+                backups_len, writes_len
+            );
+            return Err(Error::SizeError);
+        }
+
+        let mut journal = Journal::default();
+
+        for (i, ((object_id, _), backup)) in self.writes.iter().zip(&backups).enumerate() {
+            if let Some(backup) = backup {
+                if let Err(e) = txn.save_object(OBJ_JOURNAL_BACKUP + i as ObjectId, backup) {
+                    // Without a journal, the backups saved so far would only
+                    // take up storage
+                    let _ = journal.clear_backups(&txn);
+                    return Err(e);
+                }
+            }
+
+            journal.entries.push(JournalEntry {
+                object_id: *object_id,
+                backed_up: backup.is_some(),
+            });
+        }
+
+        journal.write(&txn)?;
+
+        for (object_id, data) in &self.writes {
+            txn.save_object(*object_id, data)?;
+        }
+
+        // Cleared first: backups are meaningless without the journal
+        txn.save_object(OBJ_JOURNAL, &[])?;
+        journal.clear_backups(&txn)
+    }
+}
+
+/// Journal of an interrupted [`WriteSequence`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+}
+
+/// Object written by an interrupted [`WriteSequence`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct JournalEntry {
+    object_id: ObjectId,
+    backed_up: bool,
+}
+
+impl Journal {
+    /// Read the journal of an interrupted write sequence from the YubiKey, if
+    /// any.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Option<Self>> {
+        let txn = yubikey.begin_transaction()?;
+
+        match fetch_object(&txn, OBJ_JOURNAL)? {
+            Some(data) => Self::parse(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over the IDs of the objects the interrupted sequence was
+    /// writing.
+    pub fn object_ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        self.entries.iter().map(|entry| entry.object_id)
+    }
+
+    /// Restore the objects the interrupted sequence was writing to their
+    /// previous contents, and remove the journal.
+    ///
+    /// If this is interrupted too, it can safely be retried.
+    ///
+    /// The management key must be authenticated.
+    pub fn rollback(self, yubikey: &mut YubiKey) -> Result<()> {
+        yubikey.run_write(
+            Operation::RollbackSequence,
+            |_| Ok(()),
+            |yubikey| self.perform_rollback(yubikey),
+        )
+    }
+
+    fn perform_rollback(&self, yubikey: &mut YubiKey) -> Result<()> {
+        let txn = yubikey.begin_transaction()?;
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let previous = if entry.backed_up {
+                fetch_object(&txn, OBJ_JOURNAL_BACKUP + i as ObjectId)?
+                    .ok_or(Error::InvalidObject)?
+            } else {
+                Buffer::default()
+            };
+
+            txn.save_object(entry.object_id, &previous)?;
+        }
+
+        txn.save_object(OBJ_JOURNAL, &[])?;
+        self.clear_backups(&txn)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self> {
+        let (_, tlv) = Tlv::parse(data)?;
+        if tlv.tag != TAG_JOURNAL {
+            error!("unexpected tag in journal object: {:02x}", tlv.tag);
+            return Err(Error::InvalidObject);
+        }
+
+        let mut entries = Vec::new();
+        let mut buffer = tlv.value;
+
+        while !buffer.is_empty() {
+            let (rest, tlv) = Tlv::parse(buffer)?;
+
+            if tlv.tag == TAG_JOURNAL_ENTRY {
+                let (object_id, flags) = match tlv.value {
+                    [a, b, c, d, flags] => (ObjectId::from_be_bytes([*a, *b, *c, *d]), *flags),
+                    _ => return Err(Error::InvalidObject),
+                };
+
+                entries.push(JournalEntry {
+                    object_id,
+                    backed_up: flags & 1 != 0,
+                });
+            }
+
+            buffer = rest;
+        }
+
+        Ok(Self { entries })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut entries = vec![0u8; CB_OBJ_MAX];
+        let mut len = 0;
+
+        for entry in &self.entries {
+            let mut value = entry.object_id.to_be_bytes().to_vec();
+            value.push(u8::from(entry.backed_up));
+            len += Tlv::write(&mut entries[len..], TAG_JOURNAL_ENTRY, &value)?;
+        }
+
+        let mut buf = vec![0u8; CB_OBJ_MAX];
+        let offset = Tlv::write(&mut buf, TAG_JOURNAL, &entries[..len])?;
+        buf.truncate(offset);
+
+        Ok(buf)
+    }
+
+    fn write(&self, txn: &Transaction<'_>) -> Result<()> {
+        txn.save_object(OBJ_JOURNAL, &self.serialize()?)
+    }
+
+    fn clear_backups(&self, txn: &Transaction<'_>) -> Result<()> {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.backed_up {
+                txn.save_object(OBJ_JOURNAL_BACKUP + i as ObjectId, &[])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetch an object, returning `None` if it doesn't exist or is empty.
+fn fetch_object(txn: &Transaction<'_>, object_id: ObjectId) -> Result<Option<Buffer>> {
+    match txn.fetch_object(object_id) {
+        Ok(data) if data.is_empty() => Ok(None),
+        Ok(data) => Ok(Some(data)),
+        Err(Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_round_trip() {
+        let journal = Journal {
+            entries: vec![
+                JournalEntry {
+                    object_id: 0x005f_c10a,
+                    backed_up: true,
+                },
+                JournalEntry {
+                    object_id: 0x005f_c102,
+                    backed_up: false,
+                },
+            ],
+        };
+
+        let data = journal.serialize().expect("serialize journal");
+        assert_eq!(Journal::parse(&data).expect("parse journal"), journal);
+        assert_eq!(
+            journal.object_ids().collect::<Vec<_>>(),
+            [0x005f_c10a, 0x005f_c102]
+        );
+    }
+
+    #[test]
+    fn reserved_objects() {
+        let mut sequence = WriteSequence::new();
+        assert!(sequence.write(0x005f_c10a, b"cert").is_ok());
+        assert_eq!(
+            sequence.write(0x005f_c10a, b"cert"),
+            Err(Error::ArgumentError)
+        );
+        assert_eq!(sequence.write(OBJ_JOURNAL, &[]), Err(Error::ArgumentError));
+        assert_eq!(
+            sequence.write(OBJ_JOURNAL_BACKUP + 3, &[]),
+            Err(Error::ArgumentError)
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Export of the public materials of a YubiKey's keys as an RFC 5958
+//! asymmetric key package.
+//!
+//! A [`KeyPackage`] bundles the public key stored in a slot with the
+//! certificate stored alongside it and, where available, the attestation
+//! certificate proving the key was generated on the YubiKey. A list of key
+//! packages is encoded as an `AsymmetricKeyPackage` ([RFC 5958]) so it can
+//! be handed to CAs and relying parties using standard tooling:
+//!
+//! - each slot is a `OneAsymmetricKey` (v2) with an empty `privateKey`, and
+//!   the slot's public key in the `publicKey` field;
+//! - the slot is identified by a PKCS#9 `localKeyId` attribute containing the
+//!   slot ID;
+//! - certificates are included as X.520 `userCertificate` attributes, with the
+//!   attestation certificate identified by Yubico's firmware version
+//!   extension.
+//!
+//! The intermediate attestation certificate is exported as the package for
+//! [`SlotId::Attestation`].
+//!
+//! [RFC 5958]: https://www.rfc-editor.org/rfc/rfc5958
+
+use crate::{
+    error::{Error, Result},
+    inventory::Inventory,
+    piv::SlotId,
+    yubikey::YubiKey,
+    Certificate,
+};
+use log::error;
+use x509_cert::{
+    attr::Attribute,
+    der::{
+        asn1::{Any, BitString, OctetString, SetOfVec},
+        oid::ObjectIdentifier,
+        Decode, Encode, Sequence,
+    },
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
+};
+
+#[cfg(feature = "untested")]
+use {crate::capability::Capability, crate::piv, log::debug};
+
+/// PKCS#9 `localKeyId` attribute (RFC 2985).
+const LOCAL_KEY_ID_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.21");
+
+/// X.520 `userCertificate` attribute (RFC 4519).
+const USER_CERTIFICATE_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.4.36");
+
+/// Yubico's firmware version extension, present in attestation certificates.
+const YUBICO_FIRMWARE_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.3");
+
+/// `OneAsymmetricKey` version which includes the public key.
+const ONE_ASYMMETRIC_KEY_V2: u8 = 1;
+
+/// Public materials of the key in a slot.
+#[derive(Clone, Debug)]
+pub struct KeyPackage {
+    /// Slot containing the key
+    pub slot: SlotId,
+
+    /// Public key of the key in the slot
+    pub public_key: SubjectPublicKeyInfoOwned,
+
+    /// Certificate stored in the slot, if any
+    pub certificate: Option<Certificate>,
+
+    /// Attestation certificate for the key, if it was generated on the
+    /// YubiKey and the firmware supports attestation
+    pub attestation: Option<Certificate>,
+}
+
+impl KeyPackage {
+    /// Collect the key packages for every populated slot of the given
+    /// YubiKey.
+    ///
+    /// Slots are found as by [`Inventory::collect`]. Attestation certificates
+    /// are only collected with the `untested` feature enabled.
+    pub fn collect(yubikey: &mut YubiKey) -> Result<Vec<Self>> {
+        let inventory = Inventory::collect(yubikey)?;
+        let mut packages = Vec::with_capacity(inventory.slots.len());
+
+        for entry in inventory.slots {
+            let certificate = Certificate::read(yubikey, entry.slot).ok();
+
+            #[cfg(feature = "untested")]
+            let attestation = attest(yubikey, entry.slot)?;
+            #[cfg(not(feature = "untested"))]
+            let attestation = None;
+
+            packages.push(Self {
+                slot: entry.slot,
+                public_key: entry.public_key,
+                certificate,
+                attestation,
+            });
+        }
+
+        Ok(packages)
+    }
+
+    /// Encode the given key packages as a DER `AsymmetricKeyPackage`.
+    pub fn to_der(packages: &[Self]) -> Result<Vec<u8>> {
+        let keys = packages
+            .iter()
+            .map(Self::to_asn1)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(keys.to_der()?)
+    }
+
+    /// Parse a DER `AsymmetricKeyPackage` produced by [`KeyPackage::to_der`].
+    pub fn from_der(der: &[u8]) -> Result<Vec<Self>> {
+        Vec::<OneAsymmetricKey>::from_der(der)?
+            .iter()
+            .map(Self::from_asn1)
+            .collect()
+    }
+
+    fn to_asn1(&self) -> Result<OneAsymmetricKey> {
+        let local_key_id = OctetString::new([u8::from(self.slot)])?;
+
+        let mut attributes = SetOfVec::new();
+        attributes.insert(Attribute {
+            oid: LOCAL_KEY_ID_OID,
+            values: SetOfVec::try_from(vec![Any::encode_from(&local_key_id)?])?,
+        })?;
+
+        let certificates = self
+            .certificate
+            .iter()
+            .chain(&self.attestation)
+            .map(|cert| Any::from_der(cert.as_der()))
+            .collect::<der::Result<Vec<_>>>()?;
+
+        if !certificates.is_empty() {
+            attributes.insert(Attribute {
+                oid: USER_CERTIFICATE_OID,
+                values: SetOfVec::try_from(certificates)?,
+            })?;
+        }
+
+        Ok(OneAsymmetricKey {
+            version: ONE_ASYMMETRIC_KEY_V2,
+            private_key_algorithm: self.public_key.algorithm.clone(),
+            private_key: OctetString::new(vec![])?,
+            attributes: Some(attributes),
+            public_key: Some(self.public_key.subject_public_key.clone()),
+        })
+    }
+
+    fn from_asn1(key: &OneAsymmetricKey) -> Result<Self> {
+        let public_key = SubjectPublicKeyInfoOwned {
+            algorithm: key.private_key_algorithm.clone(),
+            subject_public_key: key.public_key.clone().ok_or_else(|| {
+                error!("key package has no public key");
+                Error::ParseError
+            })?,
+        };
+
+        let mut slot = None;
+        let mut certificate = None;
+        let mut attestation = None;
+
+        for attribute in key.attributes.iter().flat_map(|attrs| attrs.iter()) {
+            if attribute.oid == LOCAL_KEY_ID_OID {
+                let id = attribute
+                    .values
+                    .get(0)
+                    .ok_or(Error::ParseError)?
+                    .decode_as::<OctetString>()?;
+
+                match id.as_bytes() {
+                    [id] => slot = Some(SlotId::try_from(*id)?),
+                    _ => return Err(Error::ParseError),
+                }
+            } else if attribute.oid == USER_CERTIFICATE_OID {
+                for value in attribute.values.iter() {
+                    let cert = Certificate::from_bytes(value.to_der()?)?;
+
+                    if is_attestation(&cert) {
+                        attestation = Some(cert);
+                    } else {
+                        certificate = Some(cert);
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            slot: slot.ok_or_else(|| {
+                error!("key package has no slot ID");
+                Error::ParseError
+            })?,
+            public_key,
+            certificate,
+            attestation,
+        })
+    }
+}
+
+/// `OneAsymmetricKey` (RFC 5958 section 2).
+#[derive(Sequence)]
+struct OneAsymmetricKey {
+    version: u8,
+    private_key_algorithm: AlgorithmIdentifierOwned,
+    private_key: OctetString,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", optional = "true")]
+    attributes: Option<SetOfVec<Attribute>>,
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", optional = "true")]
+    public_key: Option<BitString>,
+}
+
+/// Is this a YubiKey attestation certificate?
+fn is_attestation(cert: &Certificate) -> bool {
+    cert.cert
+        .tbs_certificate
+        .extensions
+        .iter()
+        .flatten()
+        .any(|extension| extension.extn_id == YUBICO_FIRMWARE_OID)
+}
+
+/// Attest the key in the given slot, if the YubiKey supports it.
+#[cfg(feature = "untested")]
+fn attest(yubikey: &mut YubiKey, slot: SlotId) -> Result<Option<Certificate>> {
+    if slot == SlotId::Attestation || !yubikey.supports(Capability::Attestation) {
+        return Ok(None);
+    }
+
+    match piv::attest(yubikey, slot) {
+        Ok(cert) => Ok(Some(cert)),
+        Err(e) => {
+            debug!("no attestation for slot {}: {}", slot, e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piv::RetiredSlotId;
+    use rand_core::OsRng;
+
+    #[test]
+    fn round_trip() {
+        let public_key = p256::SecretKey::random(&mut OsRng).public_key();
+        let packages = [
+            KeyPackage {
+                slot: SlotId::Authentication,
+                public_key: SubjectPublicKeyInfoOwned::from_key(public_key).expect("encoded"),
+                certificate: None,
+                attestation: None,
+            },
+            KeyPackage {
+                slot: SlotId::Retired(RetiredSlotId::R3),
+                public_key: SubjectPublicKeyInfoOwned::from_key(public_key).expect("encoded"),
+                certificate: None,
+                attestation: None,
+            },
+        ];
+
+        let der = KeyPackage::to_der(&packages).expect("encoded");
+        let parsed = KeyPackage::from_der(&der).expect("parsed");
+
+        assert_eq!(parsed.len(), 2);
+        for (parsed, package) in parsed.iter().zip(&packages) {
+            assert_eq!(parsed.slot, package.slot);
+            assert_eq!(parsed.public_key, package.public_key);
+            assert!(parsed.certificate.is_none());
+        }
+    }
+}
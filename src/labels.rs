@@ -0,0 +1,139 @@
+//! Human-friendly labels for slots, stored on the YubiKey.
+
+use crate::{
+    consts::{CB_OBJ_MAX, OBJ_SLOT_LABELS},
+    error::{Error, Result},
+    piv::SlotId,
+    serialization::*,
+    yubikey::YubiKey,
+};
+use log::error;
+use std::collections::BTreeMap;
+
+const TAG_SLOT_LABELS: u16 = 0x80;
+const TAG_SLOT_LABEL: u16 = 0x81;
+
+/// Labels assigned to slots (e.g. "prod-code-signing" or "dev-ssh"), stored in
+/// a data object on the YubiKey.
+///
+/// Labels are purely informational: the YubiKey doesn't interpret them, and
+/// they're readable without the PIN. Writing them requires authenticating
+/// with the management key.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SlotLabels {
+    labels: BTreeMap<SlotId, String>,
+}
+
+impl SlotLabels {
+    /// Maximum length of a label in bytes (UTF-8).
+    pub const MAX_LEN: usize = 64;
+
+    /// Read the slot labels stored on the YubiKey.
+    ///
+    /// Returns no labels if none have been written.
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let response = match yubikey
+            .idempotent(|yubikey| yubikey.begin_transaction()?.fetch_object(OBJ_SLOT_LABELS))
+        {
+            Ok(response) => response,
+            Err(Error::NotFound) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        if response.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let (_, tlv) = Tlv::parse(&response)?;
+        if tlv.tag != TAG_SLOT_LABELS {
+            error!("unexpected tag in slot labels object: {:02x}", tlv.tag);
+            return Err(Error::InvalidObject);
+        }
+
+        let mut labels = BTreeMap::new();
+        let mut buffer = tlv.value;
+
+        while !buffer.is_empty() {
+            let (rest, tlv) = Tlv::parse(buffer)?;
+
+            if tlv.tag == TAG_SLOT_LABEL {
+                let (&slot, label) = tlv.value.split_first().ok_or(Error::InvalidObject)?;
+                let label = String::from_utf8(label.to_vec()).map_err(|_| Error::InvalidObject)?;
+                labels.insert(SlotId::try_from(slot)?, label);
+            }
+
+            buffer = rest;
+        }
+
+        Ok(Self { labels })
+    }
+
+    /// Write these slot labels to the YubiKey, replacing any stored labels.
+    ///
+    /// The management key must be authenticated.
+    pub fn write(&self, yubikey: &mut YubiKey) -> Result<()> {
+        let txn = yubikey.begin_transaction()?;
+
+        if self.labels.is_empty() {
+            return txn.save_object(OBJ_SLOT_LABELS, &[]);
+        }
+
+        let mut entries = vec![0u8; CB_OBJ_MAX];
+        let mut len = 0;
+
+        for (&slot, label) in &self.labels {
+            let mut value = vec![slot.into()];
+            value.extend_from_slice(label.as_bytes());
+            len += Tlv::write(&mut entries[len..], TAG_SLOT_LABEL, &value)?;
+        }
+
+        let mut buf = [0u8; CB_OBJ_MAX];
+        let offset = Tlv::write(&mut buf, TAG_SLOT_LABELS, &entries[..len])?;
+
+        txn.save_object(OBJ_SLOT_LABELS, &buf[..offset])
+    }
+
+    /// Get the label of the given slot.
+    pub fn get(&self, slot: SlotId) -> Option<&str> {
+        self.labels.get(&slot).map(String::as_str)
+    }
+
+    /// Set the label of the given slot, replacing any existing label.
+    ///
+    /// Returns [`Error::SizeError`] if the label is longer than
+    /// [`SlotLabels::MAX_LEN`] bytes.
+    pub fn set(&mut self, slot: SlotId, label: impl Into<String>) -> Result<()> {
+        let label = label.into();
+
+        if label.len() > Self::MAX_LEN {
+            error!(
+                "slot label is too long: {} bytes (max {})",
+                label.len(),
+                Self::MAX_LEN
+            );
+            return Err(Error::SizeError);
+        }
+
+        self.labels.insert(slot, label);
+        Ok(())
+    }
+
+    /// Remove the label of the given slot.
+    pub fn remove(&mut self, slot: SlotId) -> Option<String> {
+        self.labels.remove(&slot)
+    }
+
+    /// Iterate over the labelled slots.
+    pub fn iter(&self) -> impl Iterator<Item = (SlotId, &str)> {
+        self.labels
+            .iter()
+            .map(|(&slot, label)| (slot, label.as_str()))
+    }
+
+    /// Find the slot with the given label.
+    pub fn find(&self, label: &str) -> Option<SlotId> {
+        self.iter()
+            .find(|&(_, candidate)| candidate == label)
+            .map(|(slot, _)| slot)
+    }
+}
@@ -43,42 +43,108 @@
 // (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
+pub mod advisory;
 mod apdu;
+pub mod applet;
+#[cfg(feature = "untested")]
+pub mod attestation;
+mod capability;
 mod cccid;
+#[cfg(feature = "ccid")]
+pub mod ccid;
 pub mod certificate;
 mod chuid;
+pub mod clock;
 mod config;
 mod consts;
+pub mod counter;
+mod device;
+#[cfg(feature = "pcsc")]
+pub mod diagnostics;
+#[cfg(feature = "emulator")]
+pub mod emulator;
+pub mod envelope;
 mod error;
+pub mod fingerprint;
+pub mod fleet;
+#[cfg(feature = "pcsc")]
+pub mod global;
+pub mod guard;
+pub mod info;
+pub mod inventory;
+pub mod journal;
+pub mod keypackage;
+mod labels;
 mod metadata;
 mod mgm;
+pub mod middleware;
 #[cfg(feature = "untested")]
 mod mscmap;
 #[cfg(feature = "untested")]
 mod msroots;
+#[cfg(any(feature = "async", feature = "tokio"))]
+pub mod nonblocking;
+pub mod oath;
 mod otp;
+pub mod pin;
 pub mod piv;
 mod policy;
+pub mod prompt;
+pub mod provisioning;
+mod ratelimit;
+#[cfg(feature = "pcsc")]
 pub mod reader;
+mod readonly;
+pub mod recovery;
+pub mod remote;
+pub mod replay;
+#[cfg(feature = "untested")]
+pub mod report;
+pub mod role;
+pub mod scoped;
+pub mod scp03;
+pub mod scp11;
+pub mod secrets;
 mod serialization;
 mod setting;
+pub mod signer;
 mod transaction;
+#[cfg(feature = "untested")]
+pub mod transcript;
+pub mod transport;
+pub mod truststore;
+pub mod uri;
+mod usage;
+pub mod verify;
+mod wear;
+mod wirelog;
 mod yubikey;
 
 pub use crate::{
+    capability::Capability,
     cccid::{CardId, CccId},
     certificate::Certificate,
     chuid::ChuId,
-    config::Config,
+    config::{AdminMetadata, Config},
+    device::{Capabilities, DeviceInfo, FormFactor, ProductVariant},
     error::{Error, Result},
+    labels::SlotLabels,
     mgm::{MgmKey, MgmKey3Des, MgmKeyAes128, MgmKeyAes192, MgmKeyAes256, MgmKeyAlgorithm, MgmType},
     piv::Key,
     policy::{PinPolicy, TouchPolicy},
-    reader::Context,
+    ratelimit::RateLimits,
+    readonly::ReadOnlyYubiKey,
     setting::{Setting, SettingSource},
-    yubikey::{CachedPin, Serial, Version, YubiKey},
+    transport::Disposition,
+    usage::{KeyUsage, KeyUsagePolicy},
+    wear::WriteLog,
+    wirelog::{ApduObserver, WireLog},
+    yubikey::{CachedPin, Serial, SerialFormat, Version, YubiKey},
 };
 
+#[cfg(feature = "pcsc")]
+pub use crate::{global::global, reader::Context};
+
 #[cfg(feature = "untested")]
 pub use crate::{mscmap::MsContainer, msroots::MsRoots};
 
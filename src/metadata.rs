@@ -41,8 +41,8 @@ use crate::consts::{CB_OBJ_MAX, CB_OBJ_TAG_MAX};
 #[cfg(feature = "untested")]
 use std::iter;
 
-const TAG_ADMIN: u8 = 0x80;
-const TAG_PROTECTED: u8 = 0x88;
+const TAG_ADMIN: u16 = 0x80;
+const TAG_PROTECTED: u16 = 0x88;
 pub const OBJ_ADMIN_DATA: u32 = 0x005f_ff00;
 pub const OBJ_PRINTED: u32 = 0x005f_c109;
 
@@ -110,7 +110,7 @@ impl<T: MetadataType> Metadata<T> {
     }
 
     /// Get metadata item
-    pub(crate) fn get_item(&self, tag: u8) -> Result<&[u8]> {
+    pub(crate) fn get_item(&self, tag: u16) -> Result<&[u8]> {
         let mut data = &self.inner[..];
 
         while !data.is_empty() {
@@ -128,15 +128,15 @@ impl<T: MetadataType> Metadata<T> {
 
     /// Set metadata item
     #[cfg(feature = "untested")]
-    pub(crate) fn set_item(&mut self, tag: u8, item: &[u8]) -> Result<()> {
+    pub(crate) fn set_item(&mut self, tag: u16, item: &[u8]) -> Result<()> {
         let mut cb_temp: usize = 0;
-        let mut tag_temp: u8 = 0;
+        let mut tag_temp: u16 = 0;
         let mut cb_len: usize = 0;
 
         let mut offset = 0;
 
         while offset < self.inner.len() {
-            tag_temp = self.inner[offset];
+            tag_temp = self.inner[offset].into();
             offset += 1;
 
             cb_len = get_length(&self.inner[offset..], &mut cb_temp);
@@ -228,11 +228,11 @@ fn get_length_size(length: usize) -> usize {
 mod private {
     use super::*;
     pub trait Sealed {
-        fn tag() -> u8;
+        fn tag() -> u16;
         fn obj_id() -> u32;
     }
     impl Sealed for Protected {
-        fn tag() -> u8 {
+        fn tag() -> u16 {
             TAG_PROTECTED
         }
         fn obj_id() -> u32 {
@@ -240,7 +240,7 @@ mod private {
         }
     }
     impl Sealed for Admin {
-        fn tag() -> u8 {
+        fn tag() -> u16 {
             TAG_ADMIN
         }
         fn obj_id() -> u32 {
@@ -35,10 +35,14 @@ use log::error;
 use rand_core::OsRng;
 use zeroize::{Zeroize, Zeroizing};
 
+#[cfg(feature = "keyring")]
+use crate::yubikey::Serial;
+
 #[cfg(feature = "untested")]
 use crate::{
     consts::{TAG_ADMIN_FLAGS_1, TAG_ADMIN_SALT, TAG_PROTECTED_MGM},
     metadata::{AdminData, ProtectedData},
+    middleware::Operation,
     yubikey::YubiKey,
 };
 use cipher::{
@@ -51,28 +55,30 @@ use cipher::{
 use {pbkdf2::pbkdf2_hmac, sha1::Sha1};
 
 /// YubiKey MGMT Applet Name
-#[cfg(feature = "untested")]
 pub(crate) const APPLET_NAME: &str = "YubiKey MGMT";
 
 /// MGMT Applet ID.
 ///
 /// <https://developers.yubico.com/PIV/Introduction/Admin_access.html>
-#[cfg(feature = "untested")]
 pub(crate) const APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x47, 0x11, 0x17];
 
 pub(crate) const ADMIN_FLAGS_1_PROTECTED_MGM: u8 = 0x02;
 
-#[cfg(feature = "untested")]
-const CB_ADMIN_SALT: usize = 16;
+pub(crate) const CB_ADMIN_SALT: usize = 16;
 
 /// Size of a DES key
 const DES_LEN_DES: usize = 8;
 
 /// The default MGM key loaded for both Triple-DES and AES keys
+#[cfg(not(feature = "no-default-credentials"))]
 const DEFAULT_MGM_KEY: [u8; 24] = [
     1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8, 1, 2, 3, 4, 5, 6, 7, 8,
 ];
 
+/// Service name under which management keys are stored in the OS credential store
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "yubikey-mgm";
+
 /// Number of PBKDF2 iterations to use when deriving from a password
 #[cfg(feature = "untested")]
 const ITER_MGM_PBKDF2: u32 = 10000;
@@ -308,7 +314,7 @@ impl<C: MgmKeyAlgorithm> MgmKey<C> {
     /// Resets the management key for the given YubiKey to the default value.
     ///
     /// This will wipe any metadata related to derived and PIN-protected management keys.
-    #[cfg(feature = "untested")]
+    #[cfg(all(feature = "untested", not(feature = "no-default-credentials")))]
     pub fn set_default(yubikey: &mut YubiKey) -> Result<()> {
         Self::default().set_manual(yubikey, false)
     }
@@ -321,58 +327,64 @@ impl<C: MgmKeyAlgorithm> MgmKey<C> {
     /// This will wipe any metadata related to derived and PIN-protected management keys.
     #[cfg(feature = "untested")]
     pub fn set_manual(&self, yubikey: &mut YubiKey, require_touch: bool) -> Result<()> {
-        let txn = yubikey.begin_transaction()?;
-
-        txn.set_mgm_key(self, require_touch).map_err(|e| {
-            // Log a warning, since the device mgm key is corrupt or we're in a state
-            // where we can't set the mgm key.
-            error!("could not set new derived mgm key, err = {}", e);
-            e
-        })?;
-
-        // After this point, we've set the mgm key, so the function should succeed,
-        // regardless of being able to set the metadata.
+        yubikey.run_write(
+            Operation::SetManagementKey,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+
+                txn.set_mgm_key(self, require_touch).map_err(|e| {
+                    // Log a warning, since the device mgm key is corrupt or we're in a state
+                    // where we can't set the mgm key.
+                    error!("could not set new derived mgm key, err = {}", e);
+                    e
+                })?;
+
+                // After this point, we've set the mgm key, so the function should succeed,
+                // regardless of being able to set the metadata.
+
+                if let Ok(mut admin_data) = AdminData::read(&txn) {
+                    // Clear the protected mgm key bit.
+                    if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
+                        let mut flags_1 = [0u8; 1];
+                        if item.len() == flags_1.len() {
+                            flags_1.copy_from_slice(item);
+                            flags_1[0] &= !ADMIN_FLAGS_1_PROTECTED_MGM;
+
+                            if let Err(e) = admin_data.set_item(TAG_ADMIN_FLAGS_1, &flags_1) {
+                                error!("could not set admin flags item, err = {}", e);
+                            }
+                        } else {
+                            error!(
+                                "admin data flags are an incorrect size: {} (expected {})",
+                                item.len(),
+                                flags_1.len()
+                            );
+                        }
+                    }
 
-        if let Ok(mut admin_data) = AdminData::read(&txn) {
-            // Clear the protected mgm key bit.
-            if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
-                let mut flags_1 = [0u8; 1];
-                if item.len() == flags_1.len() {
-                    flags_1.copy_from_slice(item);
-                    flags_1[0] &= !ADMIN_FLAGS_1_PROTECTED_MGM;
+                    // Remove any existing salt for a derived mgm key.
+                    if let Err(e) = admin_data.set_item(TAG_ADMIN_SALT, &[]) {
+                        error!("could not unset derived mgm salt (err = {})", e)
+                    }
 
-                    if let Err(e) = admin_data.set_item(TAG_ADMIN_FLAGS_1, &flags_1) {
-                        error!("could not set admin flags item, err = {}", e);
+                    if let Err(e) = admin_data.write(&txn) {
+                        error!("could not write admin data, err = {}", e);
                     }
-                } else {
-                    error!(
-                        "admin data flags are an incorrect size: {} (expected {})",
-                        item.len(),
-                        flags_1.len()
-                    );
                 }
-            }
-
-            // Remove any existing salt for a derived mgm key.
-            if let Err(e) = admin_data.set_item(TAG_ADMIN_SALT, &[]) {
-                error!("could not unset derived mgm salt (err = {})", e)
-            }
 
-            if let Err(e) = admin_data.write(&txn) {
-                error!("could not write admin data, err = {}", e);
-            }
-        }
-
-        // Clear any prior mgm key from protected data.
-        if let Ok(mut protected_data) = ProtectedData::read(&txn) {
-            if let Err(e) = protected_data.set_item(TAG_PROTECTED_MGM, &[]) {
-                error!("could not clear protected mgm item, err = {:?}", e);
-            } else if let Err(e) = protected_data.write(&txn) {
-                error!("could not write protected data, err = {:?}", e);
-            }
-        }
+                // Clear any prior mgm key from protected data.
+                if let Ok(mut protected_data) = ProtectedData::read(&txn) {
+                    if let Err(e) = protected_data.set_item(TAG_PROTECTED_MGM, &[]) {
+                        error!("could not clear protected mgm item, err = {:?}", e);
+                    } else if let Err(e) = protected_data.write(&txn) {
+                        error!("could not write protected data, err = {:?}", e);
+                    }
+                }
 
-        Ok(())
+                Ok(())
+            },
+        )
     }
 
     /// Configures the given YubiKey to use this as a PIN-protected management key.
@@ -380,70 +392,136 @@ impl<C: MgmKeyAlgorithm> MgmKey<C> {
     /// This enables key management operations to be performed with access to the PIN.
     #[cfg(feature = "untested")]
     pub fn set_protected(&self, yubikey: &mut YubiKey) -> Result<()> {
-        let txn = yubikey.begin_transaction()?;
+        yubikey.run_write(
+            Operation::SetManagementKey,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+
+                txn.set_mgm_key(self, false).map_err(|e| {
+                    // log a warning, since the device mgm key is corrupt or we're in
+                    // a state where we can't set the mgm key
+                    error!("could not set new derived mgm key, err = {}", e);
+                    e
+                })?;
+
+                // after this point, we've set the mgm key, so the function should
+                // succeed, regardless of being able to set the metadata
+
+                // Fetch the current protected data, or start a blank metadata blob.
+                let mut protected_data = ProtectedData::read(&txn).unwrap_or_default();
+
+                // Set the new mgm key in protected data.
+                if let Err(e) = protected_data.set_item(TAG_PROTECTED_MGM, self.as_ref()) {
+                    error!("could not set protected mgm item, err = {:?}", e);
+                } else {
+                    protected_data.write(&txn).map_err(|e| {
+                        error!("could not write protected data, err = {:?}", e);
+                        e
+                    })?;
+                }
 
-        txn.set_mgm_key(self, false).map_err(|e| {
-            // log a warning, since the device mgm key is corrupt or we're in
-            // a state where we can't set the mgm key
-            error!("could not set new derived mgm key, err = {}", e);
-            e
-        })?;
+                // set the protected mgm flag in admin data
 
-        // after this point, we've set the mgm key, so the function should
-        // succeed, regardless of being able to set the metadata
+                let mut flags_1 = [0u8; 1];
 
-        // Fetch the current protected data, or start a blank metadata blob.
-        let mut protected_data = ProtectedData::read(&txn).unwrap_or_default();
+                let mut admin_data = if let Ok(mut admin_data) = AdminData::read(&txn) {
+                    if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
+                        if item.len() == flags_1.len() {
+                            flags_1.copy_from_slice(item);
+                        } else {
+                            error!(
+                                "admin data flags are an incorrect size: {} (expected {})",
+                                item.len(),
+                                flags_1.len()
+                            );
+                        }
+                    } else {
+                        // flags are not set
+                        error!("admin data exists, but flags are not present");
+                    }
 
-        // Set the new mgm key in protected data.
-        if let Err(e) = protected_data.set_item(TAG_PROTECTED_MGM, self.as_ref()) {
-            error!("could not set protected mgm item, err = {:?}", e);
-        } else {
-            protected_data.write(&txn).map_err(|e| {
-                error!("could not write protected data, err = {:?}", e);
-                e
-            })?;
-        }
+                    // remove any existing salt
+                    if let Err(e) = admin_data.set_item(TAG_ADMIN_SALT, &[]) {
+                        error!("could not unset derived mgm salt (err = {})", e)
+                    }
 
-        // set the protected mgm flag in admin data
+                    admin_data
+                } else {
+                    AdminData::default()
+                };
 
-        let mut flags_1 = [0u8; 1];
+                flags_1[0] |= ADMIN_FLAGS_1_PROTECTED_MGM;
 
-        let mut admin_data = if let Ok(mut admin_data) = AdminData::read(&txn) {
-            if let Ok(item) = admin_data.get_item(TAG_ADMIN_FLAGS_1) {
-                if item.len() == flags_1.len() {
-                    flags_1.copy_from_slice(item);
-                } else {
-                    error!(
-                        "admin data flags are an incorrect size: {} (expected {})",
-                        item.len(),
-                        flags_1.len()
-                    );
+                if let Err(e) = admin_data.set_item(TAG_ADMIN_FLAGS_1, &flags_1) {
+                    error!("could not set admin flags item, err = {}", e);
+                } else if let Err(e) = admin_data.write(&txn) {
+                    error!("could not write admin data, err = {}", e);
                 }
-            } else {
-                // flags are not set
-                error!("admin data exists, but flags are not present");
-            }
 
-            // remove any existing salt
-            if let Err(e) = admin_data.set_item(TAG_ADMIN_SALT, &[]) {
-                error!("could not unset derived mgm salt (err = {})", e)
-            }
+                Ok(())
+            },
+        )
+    }
+
+    /// Configures the given YubiKey to use this management key, and stores it
+    /// in the OS credential store keyed by the YubiKey's serial number.
+    ///
+    /// This is equivalent to a management key "protected by the OS": it can
+    /// later be retrieved with [`MgmKey::get_from_keyring`] by any process
+    /// running as the same user.
+    #[cfg(all(feature = "untested", feature = "keyring"))]
+    pub fn set_in_keyring(&self, yubikey: &mut YubiKey, require_touch: bool) -> Result<()> {
+        self.set_manual(yubikey, require_touch)?;
+        self.store_in_keyring(yubikey.serial())
+    }
 
-            admin_data
-        } else {
-            AdminData::default()
+    /// Get the management key stored in the OS credential store for the
+    /// YubiKey with the given serial number.
+    ///
+    /// Returns [`Error::NotFound`] if no management key has been stored.
+    #[cfg(feature = "keyring")]
+    pub fn get_from_keyring(serial: Serial) -> Result<Self> {
+        let stored = match keyring_entry(serial)?.get_password() {
+            Ok(stored) => Zeroizing::new(stored),
+            Err(keyring::Error::NoEntry) => return Err(Error::NotFound),
+            Err(e) => {
+                error!("could not read stored mgm key, err = {}", e);
+                return Err(Error::GenericError);
+            }
         };
 
-        flags_1[0] |= ADMIN_FLAGS_1_PROTECTED_MGM;
+        let bytes = Zeroizing::new(hex::mixed::decode_vec(stored.as_bytes()).map_err(|_| {
+            error!("stored mgm key for YubiKey {} is not valid hex", serial);
+            Error::ParseError
+        })?);
 
-        if let Err(e) = admin_data.set_item(TAG_ADMIN_FLAGS_1, &flags_1) {
-            error!("could not set admin flags item, err = {}", e);
-        } else if let Err(e) = admin_data.write(&txn) {
-            error!("could not write admin data, err = {}", e);
-        }
+        Self::from_bytes(&bytes)
+    }
 
-        Ok(())
+    /// Store this management key in the OS credential store for the YubiKey
+    /// with the given serial number, replacing any key stored previously.
+    #[cfg(feature = "keyring")]
+    pub fn store_in_keyring(&self, serial: Serial) -> Result<()> {
+        let encoded = Zeroizing::new(hex::upper::encode_string(self.as_ref()));
+
+        keyring_entry(serial)?.set_password(&encoded).map_err(|e| {
+            error!("could not store mgm key, err = {}", e);
+            Error::GenericError
+        })
+    }
+
+    /// Remove the management key stored in the OS credential store for the
+    /// YubiKey with the given serial number, if any.
+    #[cfg(feature = "keyring")]
+    pub fn remove_from_keyring(serial: Serial) -> Result<()> {
+        match keyring_entry(serial)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => {
+                error!("could not remove stored mgm key, err = {}", e);
+                Err(Error::GenericError)
+            }
+        }
     }
 
     /// Return the size of the key used in management operations for a given algorithm
@@ -495,6 +573,9 @@ impl<C: MgmKeyAlgorithm> AsRef<[u8]> for MgmKey<C> {
 }
 
 /// Default MGM key configured on all YubiKeys
+///
+/// Not available with the `no-default-credentials` feature enabled.
+#[cfg(not(feature = "no-default-credentials"))]
 impl<C: MgmKeyAlgorithm> Default for MgmKey<C> {
     fn default() -> Self {
         let key = Key::<C>::from_slice(&DEFAULT_MGM_KEY).clone();
@@ -519,6 +600,16 @@ impl<'a, C: MgmKeyAlgorithm> TryFrom<&'a [u8]> for MgmKey<C> {
     }
 }
 
+/// Get the OS credential store entry holding the management key for the
+/// YubiKey with the given serial number.
+#[cfg(feature = "keyring")]
+fn keyring_entry(serial: Serial) -> Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, &serial.to_string()).map_err(|e| {
+        error!("could not access OS credential store, err = {}", e);
+        Error::GenericError
+    })
+}
+
 // Seal the MgmKeyAlgorithm trait
 mod private {
     pub trait Seal {}
@@ -0,0 +1,315 @@
+//! Middleware wrapping high-level operations on a YubiKey.
+//!
+//! Cross-cutting behavior such as logging, metrics, policy checks, retries or
+//! user prompts can be implemented once as a [`Middleware`] and added to a
+//! [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::add_middleware`](crate::YubiKey::add_middleware), rather than
+//! wrapping every call into this crate.
+//!
+//! Each high-level operation is described by an [`Operation`] and passed
+//! through the middleware chain in the order the middleware was added. Every
+//! middleware decides whether (and how many times) to perform the rest of the
+//! chain, and ultimately the operation itself, by calling [`Next::run`]:
+//!
+//! ```
+//! use yubikey::{middleware::{Middleware, Next, Operation}, Result};
+//!
+//! struct Logger;
+//!
+//! impl Middleware for Logger {
+//!     fn handle(&mut self, operation: Operation, mut next: Next<'_>) -> Result<()> {
+//!         let result = next.run();
+//!         println!("{:?}: {:?}", operation, result);
+//!         result
+//!     }
+//! }
+//! ```
+//!
+//! Operations performed internally while another operation is in progress
+//! (e.g. verifying the PIN before signing) don't pass through the chain again.
+
+use crate::{
+    error::Result,
+    piv::{AlgorithmId, SlotId},
+    ObjectId,
+};
+
+/// High-level operation passed through the middleware chain.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Verify the PIN: see [`YubiKey::verify_pin`](crate::YubiKey::verify_pin).
+    VerifyPin,
+
+    /// Authenticate with the management key: see
+    /// [`YubiKey::authenticate`](crate::YubiKey::authenticate).
+    Authenticate,
+
+    /// Generate a key: see [`piv::generate`](crate::piv::generate).
+    Generate {
+        /// Slot the key is generated in
+        slot: SlotId,
+
+        /// Algorithm of the key
+        algorithm: AlgorithmId,
+    },
+
+    /// Import a key: see `piv::import_rsa_key` and `piv::import_ecc_key`.
+    ImportKey {
+        /// Slot the key is imported into
+        slot: SlotId,
+
+        /// Algorithm of the key
+        algorithm: AlgorithmId,
+    },
+
+    /// Sign data: see [`piv::sign_data`](crate::piv::sign_data).
+    Sign {
+        /// Slot containing the signing key
+        slot: SlotId,
+
+        /// Algorithm of the key
+        algorithm: AlgorithmId,
+    },
+
+    /// Decrypt data: see `piv::decrypt_data`.
+    Decrypt {
+        /// Slot containing the decryption key
+        slot: SlotId,
+
+        /// Algorithm of the key
+        algorithm: AlgorithmId,
+    },
+
+    /// Attest a key: see `piv::attest`.
+    Attest {
+        /// Slot containing the attested key
+        slot: SlotId,
+    },
+
+    /// Write a certificate: see
+    /// [`Certificate::write`](crate::Certificate::write).
+    WriteCertificate {
+        /// Slot the certificate is written to
+        slot: SlotId,
+    },
+
+    /// Save a data object: see `YubiKey::save_object`.
+    SaveObject {
+        /// ID of the saved object
+        object_id: ObjectId,
+    },
+
+    /// Change the management key: see `MgmKey::set_manual` and
+    /// `MgmKey::set_protected`.
+    SetManagementKey,
+
+    /// Reset the PIV application: see `YubiKey::reset_device`.
+    Reset,
+
+    /// Move a key between slots: see [`piv::move_key`](crate::piv::move_key).
+    MoveKey {
+        /// Slot the key is moved from
+        from: SlotId,
+
+        /// Slot the key is moved to
+        to: SlotId,
+    },
+
+    /// Delete a key: see [`piv::delete_key`](crate::piv::delete_key).
+    DeleteKey {
+        /// Slot the key is deleted from
+        slot: SlotId,
+    },
+
+    /// Set the PIN and PUK retry counts, resetting both to their defaults:
+    /// see `YubiKey::set_pin_retries`.
+    SetPinRetries,
+
+    /// Change the PUK: see `YubiKey::change_puk`.
+    ChangePuk,
+
+    /// Block the PUK: see `YubiKey::block_puk`.
+    BlockPuk,
+
+    /// Record when the PIN was last changed: see
+    /// `YubiKey::set_pin_last_changed`.
+    SetPinLastChanged,
+
+    /// Change the PIN: see `YubiKey::change_pin`.
+    ChangePin,
+
+    /// Unblock the PIN with the PUK: see `YubiKey::unblock_pin`.
+    UnblockPin,
+
+    /// Apply a journaled sequence of object writes: see
+    /// [`WriteSequence::commit`](crate::journal::WriteSequence::commit).
+    WriteSequence,
+
+    /// Roll back an interrupted sequence of object writes: see
+    /// [`Journal::rollback`](crate::journal::Journal::rollback).
+    RollbackSequence,
+
+    /// Store a credential in the OATH application: see
+    /// [`TotpSeed::put`](crate::oath::TotpSeed::put).
+    PutOathCredential,
+}
+
+/// Class of sensitive operations, which a user may be asked to approve: see
+/// [`prompt`](crate::prompt).
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[non_exhaustive]
+pub enum OperationClass {
+    /// Signing with a private key
+    Sign,
+
+    /// Decrypting or performing key agreement with a private key
+    Decrypt,
+
+    /// Administering the YubiKey: generating, importing, moving or deleting
+    /// keys, writing certificates and data objects, changing the management
+    /// key, managing the PIN and PUK, or resetting
+    Admin,
+}
+
+impl Operation {
+    /// Does this operation destroy keys or credentials on the YubiKey?
+    ///
+    /// Generating, importing or moving a key overwrites any key already in
+    /// the slot, deleting a key is final, changing the management key or the
+    /// PUK (or resetting the PIN and PUK along with their retry counts) locks
+    /// out anyone holding the old one, blocking the PUK is final, and
+    /// resetting destroys every key and certificate. Writing a certificate
+    /// or data object (including recording when the PIN was last changed,
+    /// and applying or rolling back a sequence of object writes) overwrites
+    /// the one already stored, as does storing an OATH credential with the
+    /// name of an existing one.
+    pub fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            Operation::Generate { .. }
+                | Operation::ImportKey { .. }
+                | Operation::WriteCertificate { .. }
+                | Operation::SaveObject { .. }
+                | Operation::SetManagementKey
+                | Operation::Reset
+                | Operation::MoveKey { .. }
+                | Operation::DeleteKey { .. }
+                | Operation::SetPinRetries
+                | Operation::ChangePuk
+                | Operation::BlockPuk
+                | Operation::SetPinLastChanged
+                | Operation::WriteSequence
+                | Operation::RollbackSequence
+                | Operation::PutOathCredential
+        )
+    }
+
+    /// Class of sensitive operations this operation belongs to, if any.
+    ///
+    /// Verifying the PIN, authenticating with the management key and
+    /// attesting keys aren't sensitive on their own, and have no class.
+    pub fn class(&self) -> Option<OperationClass> {
+        match self {
+            Operation::Sign { .. } => Some(OperationClass::Sign),
+            Operation::Decrypt { .. } => Some(OperationClass::Decrypt),
+            Operation::Generate { .. }
+            | Operation::ImportKey { .. }
+            | Operation::WriteCertificate { .. }
+            | Operation::SaveObject { .. }
+            | Operation::SetManagementKey
+            | Operation::Reset
+            | Operation::MoveKey { .. }
+            | Operation::DeleteKey { .. }
+            | Operation::SetPinRetries
+            | Operation::ChangePuk
+            | Operation::BlockPuk
+            | Operation::SetPinLastChanged
+            | Operation::ChangePin
+            | Operation::UnblockPin
+            | Operation::WriteSequence
+            | Operation::RollbackSequence
+            | Operation::PutOathCredential => Some(OperationClass::Admin),
+            Operation::VerifyPin | Operation::Authenticate | Operation::Attest { .. } => None,
+        }
+    }
+}
+
+/// Middleware wrapping the [`Operation`]s performed with a YubiKey.
+pub trait Middleware: Send {
+    /// Handle the given operation, calling [`Next::run`] to perform it.
+    ///
+    /// Returning an error without calling [`Next::run`] refuses the
+    /// operation, and calling it more than once retries it. The operation
+    /// fails with [`Error::GenericError`](crate::Error::GenericError) if this
+    /// returns `Ok` without it having succeeded.
+    fn handle(&mut self, operation: Operation, next: Next<'_>) -> Result<()>;
+}
+
+/// Remainder of the middleware chain, ending with the operation itself.
+pub struct Next<'a> {
+    inner: &'a mut dyn FnMut() -> Result<()>,
+}
+
+impl Next<'_> {
+    /// Run the rest of the middleware chain and the operation.
+    pub fn run(&mut self) -> Result<()> {
+        (self.inner)()
+    }
+}
+
+/// Pass the given operation through the given middleware chain.
+pub(crate) fn dispatch(
+    chain: &mut [Box<dyn Middleware>],
+    operation: Operation,
+    perform: &mut dyn FnMut() -> Result<()>,
+) -> Result<()> {
+    match chain.split_first_mut() {
+        Some((middleware, rest)) => middleware.handle(
+            operation,
+            Next {
+                inner: &mut || dispatch(rest, operation, perform),
+            },
+        ),
+        None => perform(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the operations it sees, then retries failed operations once.
+    struct Retry(Arc<Mutex<Vec<&'static str>>>, &'static str);
+
+    impl Middleware for Retry {
+        fn handle(&mut self, _operation: Operation, mut next: Next<'_>) -> Result<()> {
+            self.0.lock().expect("poisoned").push(self.1);
+            next.run().or_else(|_| next.run())
+        }
+    }
+
+    #[test]
+    fn chain_order_and_retry() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut chain: Vec<Box<dyn Middleware>> = vec![
+            Box::new(Retry(calls.clone(), "outer")),
+            Box::new(Retry(calls.clone(), "inner")),
+        ];
+
+        let mut attempts = 0;
+        let result = dispatch(&mut chain, Operation::VerifyPin, &mut || {
+            attempts += 1;
+            match attempts {
+                1 => Err(Error::WrongPin { tries: 2 }),
+                _ => Ok(()),
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+        assert_eq!(*calls.lock().expect("poisoned"), ["outer", "inner"]);
+    }
+}
@@ -35,7 +35,7 @@ use log::error;
 
 const OBJ_MSCMAP: u32 = 0x005f_ff10;
 
-const TAG_MSCMAP: u8 = 0x81;
+const TAG_MSCMAP: u16 = 0x81;
 
 /// MS Container Map records.
 ///
@@ -33,9 +33,13 @@
 use crate::{
     consts::{CB_OBJ_MAX, CB_OBJ_TAG_MAX},
     serialization::*,
-    Error, Result, YubiKey,
+    Certificate, Error, Result, YubiKey,
 };
 use log::error;
+use x509_cert::der::{
+    asn1::{Any, ObjectIdentifier},
+    Decode, Encode, Reader, SliceReader, Tag, TagNumber, Tagged,
+};
 
 const OBJ_MSROOTS1: u32 = 0x005f_ff11;
 #[allow(dead_code)]
@@ -46,8 +50,8 @@ const OBJ_MSROOTS3: u32 = 0x005f_ff13;
 const OBJ_MSROOTS4: u32 = 0x005f_ff14;
 const OBJ_MSROOTS5: u32 = 0x005f_ff15;
 
-const TAG_MSROOTS_END: u8 = 0x82;
-const TAG_MSROOTS_MID: u8 = 0x83;
+const TAG_MSROOTS_END: u16 = 0x82;
+const TAG_MSROOTS_MID: u16 = 0x83;
 
 /// PKCS#7-formatted certificate store for enterprise trust roots.
 ///
@@ -102,6 +106,43 @@ impl MsRoots {
         })
     }
 
+    /// Get the certificates in this store.
+    ///
+    /// Returns [`Error::ParseError`] if it isn't a PKCS#7 `ContentInfo`
+    /// containing `SignedData`.
+    pub fn certificates(&self) -> Result<Vec<Certificate>> {
+        let content_info = Any::from_der(&self.0)?;
+        let mut reader = SliceReader::new(content_info.value())?;
+        ObjectIdentifier::decode(&mut reader)?;
+
+        // [0] EXPLICIT SignedData
+        let content = Any::decode(&mut reader)?;
+        if content.tag() != context_specific(TagNumber::N0) {
+            return Err(Error::ParseError);
+        }
+
+        let signed_data = Any::from_der(content.value())?;
+        let mut reader = SliceReader::new(signed_data.value())?;
+        let mut certificates = vec![];
+
+        // The certificates are the optional [0] IMPLICIT SET OF Certificate
+        // following the version, digest algorithms and content
+        while !reader.is_finished() {
+            let field = Any::decode(&mut reader)?;
+
+            if field.tag() == context_specific(TagNumber::N0) {
+                let mut reader = SliceReader::new(field.value())?;
+
+                while !reader.is_finished() {
+                    let certificate = x509_cert::Certificate::decode(&mut reader)?;
+                    certificates.push(Certificate::from_bytes(certificate.to_der()?)?);
+                }
+            }
+        }
+
+        Ok(certificates)
+    }
+
     /// Write `msroots` file to YubiKey
     pub fn write(&self, yubikey: &mut YubiKey) -> Result<()> {
         let mut buf = [0u8; CB_OBJ_MAX];
@@ -157,3 +198,11 @@ impl AsRef<[u8]> for MsRoots {
         self.0.as_ref()
     }
 }
+
+/// Tag of a constructed context-specific field.
+fn context_specific(number: TagNumber) -> Tag {
+    Tag::ContextSpecific {
+        constructed: true,
+        number,
+    }
+}
@@ -0,0 +1,216 @@
+//! Async API for use from async servers.
+//!
+//! PC/SC calls block the calling thread until the YubiKey responds, which can
+//! take seconds (e.g. generating an RSA key, or waiting for a touch), stalling
+//! an async executor if made from one of its tasks. [`AsyncYubiKey`] instead
+//! runs them on a dedicated thread pool, so they can be awaited from any
+//! executor (Tokio, async-std, smol...).
+//!
+//! With the `tokio` feature, the blocking thread pool of the Tokio runtime is
+//! used instead (through [`tokio::task::spawn_blocking`]), in which case the
+//! futures must be polled from within a Tokio runtime.
+//!
+//! # Cancellation safety
+//!
+//! Operations on the YubiKey can't be interrupted once sent. Dropping the
+//! future of an operation before it completes doesn't abort it: it still runs
+//! to completion in the background, and only its result is discarded. In
+//! particular, a cancelled key generation or certificate write may still have
+//! taken effect, so it must be checked for (e.g. with
+//! [`AsyncYubiKey::read_certificate`]) rather than assumed to have failed.
+//!
+//! Operations on the same `AsyncYubiKey` are performed one at a time, in the
+//! order they're started.
+
+use crate::{
+    certificate::{CertInfo, Certificate},
+    error::Result,
+    mgm::{MgmKey, MgmKeyAlgorithm},
+    piv::{self, AlgorithmId, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    yubikey::{Serial, Version, YubiKey},
+    Buffer,
+};
+use std::sync::{Arc, Mutex};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+#[cfg(feature = "pcsc")]
+use crate::reader::ConnectedYubiKey;
+
+/// YubiKey with an async API.
+///
+/// Cloning it returns another handle to the same YubiKey.
+#[derive(Clone, Debug)]
+pub struct AsyncYubiKey {
+    yubikey: Arc<Mutex<YubiKey>>,
+    serial: Serial,
+    version: Version,
+}
+
+impl AsyncYubiKey {
+    /// Open a connection to a YubiKey: see [`YubiKey::open`].
+    #[cfg(feature = "pcsc")]
+    pub async fn open() -> Result<Self> {
+        unblock(YubiKey::open).await.map(Self::from)
+    }
+
+    /// Open a connection to the YubiKey with the given serial number: see
+    /// [`YubiKey::open_by_serial`].
+    #[cfg(feature = "pcsc")]
+    pub async fn open_by_serial(serial: Serial) -> Result<Self> {
+        unblock(move || YubiKey::open_by_serial(serial))
+            .await
+            .map(Self::from)
+    }
+
+    /// List the YubiKeys connected to the system: see [`YubiKey::list`].
+    #[cfg(feature = "pcsc")]
+    pub async fn list() -> Result<Vec<ConnectedYubiKey>> {
+        unblock(YubiKey::list).await
+    }
+
+    /// Open a connection to the YubiKey in the PC/SC reader with the given
+    /// name: see [`YubiKey::open_by_reader`].
+    #[cfg(feature = "pcsc")]
+    pub async fn open_by_reader(name: &str) -> Result<Self> {
+        let name = name.to_owned();
+
+        unblock(move || YubiKey::open_by_reader(&name))
+            .await
+            .map(Self::from)
+    }
+
+    /// Get the serial number of the YubiKey.
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    /// Get the firmware version of the YubiKey.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Run the given function with the YubiKey on the thread pool, once the
+    /// operations started before have completed.
+    ///
+    /// This gives access to the whole blocking API, for operations without an
+    /// async equivalent.
+    pub async fn run<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut YubiKey) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let yubikey = Arc::clone(&self.yubikey);
+
+        unblock(move || {
+            // An operation panicking doesn't leave the YubiKey in an
+            // inconsistent state: at worst, its transaction was abandoned
+            let mut yubikey = match yubikey.lock() {
+                Ok(yubikey) => yubikey,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            f(&mut yubikey)
+        })
+        .await
+    }
+
+    /// Verify the PIN: see [`YubiKey::verify_pin`].
+    pub async fn verify_pin(&self, pin: &[u8]) -> Result<()> {
+        let pin = Buffer::new(pin.to_vec());
+        self.run(move |yubikey| yubikey.verify_pin(&pin)).await
+    }
+
+    /// Authenticate with the management key: see [`YubiKey::authenticate`].
+    pub async fn authenticate<C>(&self, mgm_key: MgmKey<C>) -> Result<()>
+    where
+        C: MgmKeyAlgorithm + Send + 'static,
+    {
+        self.run(move |yubikey| yubikey.authenticate(mgm_key)).await
+    }
+
+    /// Sign data with the key in the given slot: see [`piv::sign_data`].
+    pub async fn sign_data(
+        &self,
+        data: &[u8],
+        algorithm: AlgorithmId,
+        slot: SlotId,
+    ) -> Result<Buffer> {
+        let data = data.to_vec();
+        self.run(move |yubikey| piv::sign_data(yubikey, &data, algorithm, slot))
+            .await
+    }
+
+    /// Generate a key in the given slot: see [`piv::generate`].
+    pub async fn generate(
+        &self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Result<SubjectPublicKeyInfoOwned> {
+        self.run(move |yubikey| piv::generate(yubikey, slot, algorithm, pin_policy, touch_policy))
+            .await
+    }
+
+    /// Read the certificate in the given slot: see [`Certificate::read`].
+    pub async fn read_certificate(&self, slot: SlotId) -> Result<Certificate> {
+        self.run(move |yubikey| Certificate::read(yubikey, slot))
+            .await
+    }
+
+    /// Write the given certificate to the given slot: see
+    /// [`Certificate::write`].
+    pub async fn write_certificate(
+        &self,
+        slot: SlotId,
+        certificate: Certificate,
+        certinfo: CertInfo,
+    ) -> Result<()> {
+        self.run(move |yubikey| certificate.write(yubikey, slot, certinfo))
+            .await
+    }
+
+    /// Delete the certificate in the given slot: see [`Certificate::delete`].
+    #[cfg(feature = "untested")]
+    pub async fn delete_certificate(&self, slot: SlotId) -> Result<()> {
+        self.run(move |yubikey| Certificate::delete(yubikey, slot))
+            .await
+    }
+}
+
+/// Run the given blocking function on the thread pool of the `blocking` crate.
+#[cfg(not(feature = "tokio"))]
+async fn unblock<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    blocking::unblock(f).await
+}
+
+/// Run the given blocking function on the blocking thread pool of the Tokio
+/// runtime.
+#[cfg(feature = "tokio")]
+async fn unblock<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        // Only happens when the runtime is shutting down
+        Err(error) => panic!("blocking task cancelled: {}", error),
+    }
+}
+
+impl From<YubiKey> for AsyncYubiKey {
+    fn from(yubikey: YubiKey) -> Self {
+        Self {
+            serial: yubikey.serial(),
+            version: yubikey.version(),
+            yubikey: Arc::new(Mutex::new(yubikey)),
+        }
+    }
+}
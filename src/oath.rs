@@ -0,0 +1,441 @@
+//! TOTP seeds sealed to a PIV slot, for provisioning into the OATH
+//! application.
+//!
+//! Backups of TOTP seeds are as sensitive as the accounts they protect. A
+//! [`TotpSeed`] can instead be sealed to the ECC key in a PIV slot of the
+//! YubiKey it's meant for (as with [`Secrets::seal_to_slot`]), so the backup
+//! file is useless without that YubiKey. [`provision_sealed`] then opens the
+//! sealed seed with the PIV key and stores it in the OATH application of the
+//! same YubiKey.
+//!
+//! Only OATH applications without a password are supported.
+//!
+//! [`Secrets::seal_to_slot`]: crate::secrets::Secrets::seal_to_slot
+
+use crate::{
+    error::{Error, Result},
+    piv::SlotId,
+    secrets,
+    serialization::Tlv,
+    Buffer,
+};
+use std::fmt;
+use x509_cert::spki::SubjectPublicKeyInfoRef;
+
+#[cfg(feature = "untested")]
+use {
+    crate::{
+        apdu::{Apdu, Ins, StatusWords},
+        middleware::Operation,
+        yubikey::YubiKey,
+    },
+    log::error,
+};
+
+/// OATH application name.
+pub(crate) const APPLET_NAME: &str = "OATH";
+
+/// OATH application ID.
+pub(crate) const APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x21, 0x01];
+
+/// OATH PUT instruction, storing a credential.
+pub(crate) const INS_PUT: u8 = 0x01;
+
+/// Magic bytes at the start of sealed seeds.
+const MAGIC: &[u8; 4] = b"YKTS";
+
+/// Default TOTP period, which isn't included in credential names.
+const DEFAULT_PERIOD: u32 = 30;
+
+/// Minimum length of OATH keys, shorter keys are padded with zeros.
+const MIN_KEY_LEN: usize = 14;
+
+/// Maximum length of OATH credential names.
+const MAX_NAME_LEN: usize = 64;
+
+const TAG_NAME: u16 = 0x71;
+const TAG_KEY: u16 = 0x73;
+
+/// Challenge tag in the response to SELECT, present if a password is set.
+#[cfg(feature = "untested")]
+const TAG_CHALLENGE: u16 = 0x74;
+
+/// TOTP credential type, ORed with the algorithm.
+const TYPE_TOTP: u8 = 0x20;
+
+const SEED_TAG_NAME: u16 = 0x01;
+const SEED_TAG_SECRET: u16 = 0x02;
+const SEED_TAG_ALGORITHM: u16 = 0x03;
+const SEED_TAG_DIGITS: u16 = 0x04;
+const SEED_TAG_PERIOD: u16 = 0x05;
+
+/// HMAC algorithm of an OATH credential.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OathAlgorithm {
+    /// HMAC-SHA1
+    Sha1,
+
+    /// HMAC-SHA256
+    Sha256,
+
+    /// HMAC-SHA512
+    Sha512,
+}
+
+impl OathAlgorithm {
+    fn code(self) -> u8 {
+        match self {
+            OathAlgorithm::Sha1 => 0x01,
+            OathAlgorithm::Sha256 => 0x02,
+            OathAlgorithm::Sha512 => 0x03,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0x01 => Ok(OathAlgorithm::Sha1),
+            0x02 => Ok(OathAlgorithm::Sha256),
+            0x03 => Ok(OathAlgorithm::Sha512),
+            _ => Err(Error::ParseError),
+        }
+    }
+
+    /// Get the block size of the hash function, beyond which HMAC keys are
+    /// hashed.
+    fn block_size(self) -> usize {
+        match self {
+            OathAlgorithm::Sha1 | OathAlgorithm::Sha256 => 64,
+            OathAlgorithm::Sha512 => 128,
+        }
+    }
+
+    fn hash(self, key: &[u8]) -> Buffer {
+        use sha2::Digest;
+
+        Buffer::new(match self {
+            OathAlgorithm::Sha1 => sha1::Sha1::digest(key).to_vec(),
+            OathAlgorithm::Sha256 => sha2::Sha256::digest(key).to_vec(),
+            OathAlgorithm::Sha512 => sha2::Sha512::digest(key).to_vec(),
+        })
+    }
+}
+
+/// Seed of a TOTP credential.
+#[derive(Clone)]
+pub struct TotpSeed {
+    /// Name of the credential, usually `issuer:account`
+    pub name: String,
+
+    /// Shared secret (decoded from its usual base32 form)
+    pub secret: Buffer,
+
+    /// HMAC algorithm
+    pub algorithm: OathAlgorithm,
+
+    /// Number of digits of the codes (6 to 8)
+    pub digits: u8,
+
+    /// Period of the codes, in seconds
+    pub period: u32,
+}
+
+impl fmt::Debug for TotpSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpSeed")
+            .field("name", &self.name)
+            .field("secret", &"<redacted>")
+            .field("algorithm", &self.algorithm)
+            .field("digits", &self.digits)
+            .field("period", &self.period)
+            .finish()
+    }
+}
+
+impl TotpSeed {
+    /// Create a seed with the usual parameters: HMAC-SHA1, 6 digits and a
+    /// period of 30 seconds.
+    pub fn new(name: impl Into<String>, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            name: name.into(),
+            secret: Buffer::new(secret.into()),
+            algorithm: OathAlgorithm::Sha1,
+            digits: 6,
+            period: DEFAULT_PERIOD,
+        }
+    }
+
+    /// Seal this seed so it can only be opened with the ECC key in the given
+    /// slot, whose public key is `public_key`.
+    ///
+    /// Only the public key is needed to seal the seed, so this doesn't
+    /// require access to the YubiKey.
+    pub fn seal_to_slot(
+        &self,
+        slot: SlotId,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<Vec<u8>> {
+        // Check the seed can be stored in the OATH application
+        self.put_data()?;
+        secrets::seal_to_slot(MAGIC, &self.encode()?, slot, public_key)
+    }
+
+    /// Open a seed sealed with [`TotpSeed::seal_to_slot`], using the key in
+    /// the slot it was sealed to.
+    #[cfg(feature = "untested")]
+    pub fn open_with_slot(sealed: &[u8], yubikey: &mut YubiKey) -> Result<Self> {
+        Self::decode(&secrets::open_with_slot(MAGIC, sealed, yubikey)?)
+    }
+
+    /// Store this seed as a credential in the OATH application of the given
+    /// YubiKey, replacing any credential with the same name.
+    ///
+    /// Selecting the OATH application ends the current PIN session, as with
+    /// [`YubiKey::device_info`]. In dry-run mode, the credential is only
+    /// recorded as a planned [`Operation::PutOathCredential`].
+    #[cfg(feature = "untested")]
+    pub fn put(&self, yubikey: &mut YubiKey) -> Result<()> {
+        let data = self.put_data()?;
+
+        yubikey.run_write(
+            Operation::PutOathCredential,
+            |_| Ok(()),
+            |yubikey| put_credential(yubikey, &data),
+        )
+    }
+
+    /// Get the name of the credential in the OATH application, which includes
+    /// the period if it isn't the default one.
+    fn credential_id(&self) -> Result<String> {
+        let id = match self.period {
+            DEFAULT_PERIOD => self.name.clone(),
+            period => format!("{}/{}", period, self.name),
+        };
+
+        if id.is_empty() || id.len() > MAX_NAME_LEN || !(6..=8).contains(&self.digits) {
+            return Err(Error::ArgumentError);
+        }
+
+        Ok(id)
+    }
+
+    /// Get the data of the PUT command storing this seed.
+    fn put_data(&self) -> Result<Buffer> {
+        let id = self.credential_id()?;
+
+        let mut key = if self.secret.len() > self.algorithm.block_size() {
+            self.algorithm.hash(&self.secret)
+        } else {
+            self.secret.clone()
+        };
+
+        if key.len() < MIN_KEY_LEN {
+            key.resize(MIN_KEY_LEN, 0);
+        }
+
+        let mut value = Buffer::new(vec![TYPE_TOTP | self.algorithm.code(), self.digits]);
+        value.extend_from_slice(&key);
+
+        let mut data = Buffer::new(vec![0u8; id.len() + value.len() + 8]);
+        let mut offset = Tlv::write(&mut data, TAG_NAME, id.as_bytes())?;
+        offset += Tlv::write(&mut data[offset..], TAG_KEY, &value)?;
+        data.truncate(offset);
+        Ok(data)
+    }
+
+    fn encode(&self) -> Result<Buffer> {
+        let algorithm = [self.algorithm.code()];
+        let digits = [self.digits];
+        let period = self.period.to_be_bytes();
+
+        let fields: [(u16, &[u8]); 5] = [
+            (SEED_TAG_NAME, self.name.as_bytes()),
+            (SEED_TAG_SECRET, &self.secret),
+            (SEED_TAG_ALGORITHM, &algorithm),
+            (SEED_TAG_DIGITS, &digits),
+            (SEED_TAG_PERIOD, &period),
+        ];
+
+        let len = fields.iter().map(|(_, value)| value.len() + 4).sum();
+        let mut buffer = Buffer::new(vec![0u8; len]);
+        let mut offset = 0;
+
+        for (tag, value) in fields {
+            offset += Tlv::write(&mut buffer[offset..], tag, value)?;
+        }
+
+        buffer.truncate(offset);
+        Ok(buffer)
+    }
+
+    #[cfg_attr(not(any(feature = "untested", test)), allow(dead_code))]
+    fn decode(mut buffer: &[u8]) -> Result<Self> {
+        let mut seed = Self::new(String::new(), vec![]);
+
+        while !buffer.is_empty() {
+            let (rest, tlv) = Tlv::parse(buffer)?;
+
+            match (tlv.tag, tlv.value) {
+                (SEED_TAG_NAME, name) => {
+                    seed.name = String::from_utf8(name.to_vec()).map_err(|_| Error::ParseError)?
+                }
+                (SEED_TAG_SECRET, secret) => seed.secret = Buffer::new(secret.to_vec()),
+                (SEED_TAG_ALGORITHM, &[code]) => seed.algorithm = OathAlgorithm::from_code(code)?,
+                (SEED_TAG_DIGITS, &[digits]) => seed.digits = digits,
+                (SEED_TAG_PERIOD, period) => {
+                    seed.period = u32::from_be_bytes(period.try_into()?);
+                }
+                _ => return Err(Error::ParseError),
+            }
+
+            buffer = rest;
+        }
+
+        Ok(seed)
+    }
+}
+
+/// Store a credential in the OATH application with the given PUT command
+/// data, then select the PIV application again.
+#[cfg(feature = "untested")]
+fn put_credential(yubikey: &mut YubiKey, data: &[u8]) -> Result<()> {
+    yubikey.pin_verified = false;
+    let txn = yubikey.begin_transaction()?;
+
+    let result = (|| {
+        let response = Apdu::new(Ins::SelectApplication)
+            .p1(0x04)
+            .data(APPLET_ID)
+            .transmit(&txn, 261)?;
+
+        if !response.is_success() {
+            error!(
+                "failed selecting OATH application: {:04x}",
+                response.status_words().code()
+            );
+            return Err(match response.status_words() {
+                StatusWords::NotFoundError => Error::AppletNotFound {
+                    applet_name: APPLET_NAME,
+                },
+                _ => Error::GenericError,
+            });
+        }
+
+        if has_tag(response.data(), TAG_CHALLENGE) {
+            error!("OATH application is password protected");
+            return Err(Error::AuthenticationError);
+        }
+
+        let response = Apdu::new(INS_PUT).data(data).transmit(&txn, 261)?;
+
+        match response.status_words() {
+            StatusWords::Success => Ok(()),
+            StatusWords::NoSpaceError => {
+                error!("no space left in the OATH application");
+                Err(Error::SizeError)
+            }
+            StatusWords::SecurityStatusError => Err(Error::AuthenticationError),
+            sw => {
+                error!("failed storing OATH credential: {:04x}", sw.code());
+                Err(Error::GenericError)
+            }
+        }
+    })();
+
+    // Reselect the PIV application even if storing the credential failed
+    txn.select_application()?;
+    result
+}
+
+/// Does the given sequence of TLVs contain one with the given tag?
+#[cfg(feature = "untested")]
+fn has_tag(mut data: &[u8], tag: u16) -> bool {
+    while let Ok((rest, tlv)) = Tlv::parse(data) {
+        if tlv.tag == tag {
+            return true;
+        }
+        data = rest;
+    }
+
+    false
+}
+
+/// Open a seed sealed with [`TotpSeed::seal_to_slot`] with the given YubiKey,
+/// and store it in its OATH application: see [`TotpSeed::put`].
+///
+/// The PIN and touch policies of the key the seed was sealed to must be
+/// satisfied. Returns the name of the stored credential.
+#[cfg(feature = "untested")]
+pub fn provision_sealed(sealed: &[u8], yubikey: &mut YubiKey) -> Result<String> {
+    let seed = TotpSeed::open_with_slot(sealed, yubikey)?;
+    seed.put(yubikey)?;
+    Ok(seed.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_seed() {
+        let mut seed = TotpSeed::new("Example:alice", b"12345678901234567890".to_vec());
+        seed.period = 60;
+
+        let decoded = TotpSeed::decode(&seed.encode().expect("encode")).expect("decode");
+        assert_eq!(decoded.name, "Example:alice");
+        assert_eq!(&decoded.secret[..], b"12345678901234567890");
+        assert_eq!(decoded.period, 60);
+
+        let data = seed.put_data().expect("PUT data");
+        assert_eq!(&data[..2], [0x71, 16]);
+        assert_eq!(&data[2..18], b"60/Example:alice");
+        assert_eq!(&data[18..22], [0x73, 22, 0x21, 6]);
+
+        // Short keys are padded
+        let data = TotpSeed::new("a", b"short".to_vec())
+            .put_data()
+            .expect("PUT data");
+        assert_eq!(data[4], 2 + MIN_KEY_LEN as u8);
+
+        seed.digits = 9;
+        assert_eq!(seed.put_data().err(), Some(Error::ArgumentError));
+    }
+
+    #[cfg(all(feature = "emulator", feature = "untested"))]
+    #[test]
+    fn provision_sealed_seed() {
+        use crate::{emulator::Emulator, piv, piv::AlgorithmId, PinPolicy, Serial, TouchPolicy};
+        use x509_cert::der::referenced::OwnedToRef;
+
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            SlotId::KeyManagement,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let seed = TotpSeed::new("Example:alice", b"12345678901234567890".to_vec());
+        let sealed = seed
+            .seal_to_slot(SlotId::KeyManagement, public_key.owned_to_ref())
+            .expect("seal");
+
+        yubikey.verify_pin(b"123456").expect("verify PIN");
+        let opened = TotpSeed::open_with_slot(&sealed, &mut yubikey).expect("open");
+        assert_eq!(opened.name, seed.name);
+        assert_eq!(opened.secret, seed.secret);
+
+        // The emulator has no OATH application
+        assert!(matches!(
+            provision_sealed(&sealed, &mut yubikey),
+            Err(Error::AppletNotFound { .. })
+        ));
+
+        // Storing the credential is only recorded in dry-run mode
+        yubikey.set_dry_run(true);
+        assert!(provision_sealed(&sealed, &mut yubikey).is_ok());
+        assert_eq!(yubikey.planned_operations(), [Operation::PutOathCredential]);
+    }
+}
@@ -3,3 +3,57 @@ pub(crate) const APPLET_NAME: &str = "YubiKey OTP";
 
 /// YubiKey OTP Applet ID. Needed to query serial on YK4.
 pub(crate) const APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x20, 0x01, 0x01];
+
+/// Modhex alphabet, used by YubiKey OTPs to encode bytes as keystrokes which
+/// are the same on all keyboard layouts.
+const MODHEX_ALPHABET: &[u8; 16] = b"cbdefghijklnrtuv";
+
+/// Encode bytes as modhex.
+pub(crate) fn modhex_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .map(|nibble| char::from(MODHEX_ALPHABET[usize::from(nibble)]))
+        .collect()
+}
+
+/// Decode modhex into bytes, returning `None` if it isn't valid modhex.
+pub(crate) fn modhex_decode(modhex: &str) -> Option<Vec<u8>> {
+    let nibbles = modhex
+        .bytes()
+        .map(|c| {
+            MODHEX_ALPHABET
+                .iter()
+                .position(|&m| m == c.to_ascii_lowercase())
+                .map(|n| n as u8)
+        })
+        .collect::<Option<Vec<u8>>>()?;
+
+    if nibbles.is_empty() || nibbles.len() % 2 != 0 {
+        return None;
+    }
+
+    Some(
+        nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modhex_round_trip() {
+        let bytes = [0x00, 0x6b, 0x8e, 0xff];
+        let modhex = modhex_encode(&bytes);
+
+        assert_eq!(modhex, "cchnjuvv");
+        assert_eq!(modhex_decode(&modhex), Some(bytes.to_vec()));
+        assert_eq!(modhex_decode("CCHNJUVV"), Some(bytes.to_vec()));
+        assert_eq!(modhex_decode("cchnjuva"), None);
+        assert_eq!(modhex_decode("cch"), None);
+    }
+}
@@ -0,0 +1,253 @@
+//! Sources of the PIN for operations which require a verified PIN.
+//!
+//! A [`PinProvider`] set on a [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::set_pin_provider`](crate::YubiKey::set_pin_provider) is
+//! consulted whenever a private key operation needs the PIN to be verified
+//! and no PIN has been cached by [`YubiKey::verify_pin`](crate::YubiKey::verify_pin).
+//!
+//! The following providers are built in:
+//!
+//! - [`EnvPinProvider`]: reads the PIN from an environment variable.
+//! - [`PromptPinProvider`]: prompts for the PIN on the terminal (requires
+//!   the `pin-prompt` feature).
+//! - [`KeyringPinProvider`]: reads the PIN from the OS credential store
+//!   (requires the `keyring` feature).
+//!
+//! How long an operation waits for the provider, if at all, is configured
+//! with [`YubiKey::set_pin_escalation`](crate::YubiKey::set_pin_escalation):
+//! see [`PinEscalation`]. Once the PIN retry counter has fallen to the floor
+//! set with [`YubiKey::set_pin_retry_floor`](crate::YubiKey::set_pin_retry_floor),
+//! the provider is no longer consulted.
+
+use crate::{
+    error::{Error, Result},
+    yubikey::Serial,
+    Buffer,
+};
+use log::error;
+use std::{env, sync::mpsc, thread, time::Duration};
+
+#[cfg(feature = "keyring")]
+use log::debug;
+
+/// Default number of PIN tries left at or below which the [`PinProvider`] is
+/// no longer consulted: see
+/// [`YubiKey::set_pin_retry_floor`](crate::YubiKey::set_pin_retry_floor).
+pub const DEFAULT_PIN_RETRY_FLOOR: u8 = 1;
+
+/// Environment variable read by [`EnvPinProvider::default`].
+pub const DEFAULT_PIN_VAR: &str = "YUBIKEY_PIN";
+
+/// Source of the PIN for a YubiKey.
+pub trait PinProvider: Send {
+    /// Get the PIN for the YubiKey with the given serial number.
+    ///
+    /// Returns `Ok(None)` if this provider has no PIN for the YubiKey, in
+    /// which case the operation is attempted without verifying the PIN.
+    fn pin(&mut self, serial: Serial) -> Result<Option<Buffer>>;
+}
+
+/// What to do when an operation needs the PIN to be verified and no PIN has
+/// been cached by [`YubiKey::verify_pin`](crate::YubiKey::verify_pin).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PinEscalation {
+    /// Get the PIN from the [`PinProvider`], waiting for as long as it
+    /// takes. Without a provider, or if it has no PIN, the operation is
+    /// attempted anyway, leaving it to the YubiKey to reject it.
+    #[default]
+    Wait,
+
+    /// Fail with [`Error::PinRequired`] without consulting the
+    /// [`PinProvider`] or attempting the operation.
+    FailFast,
+
+    /// Get the PIN from the [`PinProvider`], failing with
+    /// [`Error::PinRequired`] if it doesn't answer within the given time,
+    /// has no PIN, or there is no provider.
+    ///
+    /// A provider which doesn't answer in time keeps running in the
+    /// background, and its answer is used by the next operation.
+    Deadline(Duration),
+
+    /// Ask the [`PinProvider`] for the PIN in the background and fail with
+    /// [`Error::PinRequired`] until it answers, so the caller can queue the
+    /// operation and retry it later rather than block.
+    Queue,
+}
+
+impl PinEscalation {
+    /// How long to wait for the [`PinProvider`], or `None` to wait for as
+    /// long as it takes.
+    pub(crate) fn deadline(self) -> Option<Duration> {
+        match self {
+            PinEscalation::Wait => None,
+            PinEscalation::FailFast | PinEscalation::Queue => Some(Duration::ZERO),
+            PinEscalation::Deadline(deadline) => Some(deadline),
+        }
+    }
+}
+
+/// Answer from a [`PinProvider`] consulted in the background, along with the
+/// provider itself.
+pub(crate) type PendingPin = mpsc::Receiver<(Box<dyn PinProvider>, Result<Option<Buffer>>)>;
+
+/// Consult the given [`PinProvider`] in a background thread.
+pub(crate) fn request_pin(mut provider: Box<dyn PinProvider>, serial: Serial) -> PendingPin {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let pin = provider.pin(serial);
+
+        // The YubiKey may have been closed meanwhile
+        let _ = sender.send((provider, pin));
+    });
+
+    receiver
+}
+
+/// Reads the PIN from an environment variable.
+#[derive(Clone, Debug)]
+pub struct EnvPinProvider {
+    var: String,
+}
+
+impl EnvPinProvider {
+    /// Read the PIN from the given environment variable.
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl Default for EnvPinProvider {
+    /// Read the PIN from the `YUBIKEY_PIN` environment variable.
+    fn default() -> Self {
+        Self::new(DEFAULT_PIN_VAR)
+    }
+}
+
+impl PinProvider for EnvPinProvider {
+    fn pin(&mut self, _serial: Serial) -> Result<Option<Buffer>> {
+        match env::var(&self.var) {
+            Ok(pin) => Ok(Some(Buffer::new(pin.into_bytes()))),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(env::VarError::NotUnicode(_)) => {
+                error!("{} is not valid unicode", self.var);
+                Err(Error::ArgumentError)
+            }
+        }
+    }
+}
+
+/// Prompts for the PIN on the controlling terminal, without echoing it.
+#[cfg(feature = "pin-prompt")]
+#[derive(Clone, Debug, Default)]
+pub struct PromptPinProvider {
+    prompt: Option<String>,
+}
+
+#[cfg(feature = "pin-prompt")]
+impl PromptPinProvider {
+    /// Prompt with the given message, rather than one naming the YubiKey's
+    /// serial number.
+    pub fn with_prompt(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: Some(prompt.into()),
+        }
+    }
+}
+
+#[cfg(feature = "pin-prompt")]
+impl PinProvider for PromptPinProvider {
+    fn pin(&mut self, serial: Serial) -> Result<Option<Buffer>> {
+        let prompt = match &self.prompt {
+            Some(prompt) => prompt.clone(),
+            None => format!("Enter PIN for YubiKey {}: ", serial),
+        };
+
+        let pin = rpassword::prompt_password(prompt).map_err(|e| {
+            error!("couldn't read PIN from terminal: {}", e);
+            Error::GenericError
+        })?;
+
+        if pin.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Buffer::new(pin.into_bytes())))
+    }
+}
+
+/// Reads the PIN from the OS credential store (Keychain on macOS, the
+/// Credential Manager on Windows, and the Secret Service on Linux).
+///
+/// PINs are stored under the provider's service name, with the YubiKey's
+/// serial number as the account name.
+#[cfg(feature = "keyring")]
+#[derive(Clone, Debug)]
+pub struct KeyringPinProvider {
+    service: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringPinProvider {
+    /// Store PINs under the given service name.
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+        }
+    }
+
+    /// Store the PIN for the YubiKey with the given serial number.
+    pub fn store(&self, serial: Serial, pin: &[u8]) -> Result<()> {
+        let pin = std::str::from_utf8(pin).map_err(|_| Error::ArgumentError)?;
+
+        self.entry(serial)?.set_password(pin).map_err(|e| {
+            error!("couldn't store PIN for YubiKey {}: {}", serial, e);
+            Error::GenericError
+        })
+    }
+
+    /// Remove the stored PIN for the YubiKey with the given serial number.
+    pub fn remove(&self, serial: Serial) -> Result<()> {
+        match self.entry(serial)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => {
+                error!("couldn't remove PIN for YubiKey {}: {}", serial, e);
+                Err(Error::GenericError)
+            }
+        }
+    }
+
+    fn entry(&self, serial: Serial) -> Result<keyring::Entry> {
+        keyring::Entry::new(&self.service, &serial.to_string()).map_err(|e| {
+            error!("couldn't access OS credential store: {}", e);
+            Error::GenericError
+        })
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl Default for KeyringPinProvider {
+    /// Store PINs under the `yubikey-pin` service name.
+    fn default() -> Self {
+        Self::new("yubikey-pin")
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl PinProvider for KeyringPinProvider {
+    fn pin(&mut self, serial: Serial) -> Result<Option<Buffer>> {
+        match self.entry(serial)?.get_password() {
+            Ok(pin) => Ok(Some(Buffer::new(pin.into_bytes()))),
+            Err(keyring::Error::NoEntry) => {
+                debug!("no PIN stored for YubiKey {}", serial);
+                Ok(None)
+            }
+            Err(e) => {
+                error!("couldn't read PIN for YubiKey {}: {}", serial, e);
+                Err(Error::GenericError)
+            }
+        }
+    }
+}
@@ -6,11 +6,14 @@
 //! Supported algorithms:
 //!
 //! - **Encryption**:
-//!   - RSA: `RSA1024`, `RSA2048`
+//!   - RSA: `RSA1024`, `RSA2048`, `RSA3072`, `RSA4096`
 //!   - ECC: `ECCP256`, `ECCP384` (NIST curves: P-256, P-384)
 //! - **Signatures**:
-//!   - RSASSA-PKCS#1v1.5: `RSA1024`, `RSA2048`
+//!   - RSASSA-PKCS#1v1.5: `RSA1024`, `RSA2048`, `RSA3072`, `RSA4096`
 //!   - ECDSA: `ECCP256`, `ECCP384` (NIST curves: P-256, P-384)
+//!   - EdDSA: `ED25519`
+//!
+//! `RSA3072`, `RSA4096` and `ED25519` require firmware 5.7 or newer.
 
 // Adapted from yubico-piv-tool:
 // <https://github.com/Yubico/yubico-piv-tool/>
@@ -44,16 +47,19 @@
 
 use crate::{
     apdu::{Ins, StatusWords},
+    capability::Capability,
     certificate::{self, Certificate},
     consts::CB_OBJ_MAX,
     error::{Error, Result},
+    middleware::Operation,
     policy::{PinPolicy, TouchPolicy},
     serialization::*,
-    setting,
+    setting, signer,
+    usage::KeyUsage,
     yubikey::YubiKey,
     Buffer, ObjectId,
 };
-use elliptic_curve::{sec1::EncodedPoint as EcPublicKey, PublicKey};
+use elliptic_curve::{ecdh::SharedSecret, sec1::EncodedPoint as EcPublicKey, Curve, PublicKey};
 use log::{debug, error, warn};
 use p256::NistP256;
 use p384::NistP384;
@@ -62,7 +68,10 @@ use std::{
     fmt::{Display, Formatter},
     str::FromStr,
 };
-use x509_cert::{der::Decode, spki::SubjectPublicKeyInfoOwned};
+use x509_cert::{
+    der::{asn1::BitString, Decode},
+    spki::{AlgorithmIdentifierOwned, ObjectIdentifier, SubjectPublicKeyInfoOwned},
+};
 
 #[cfg(feature = "untested")]
 use {
@@ -72,7 +81,27 @@ use {
 };
 
 #[cfg(feature = "untested")]
-use zeroize::Zeroizing;
+use {
+    rsa::{pkcs8::DecodePrivateKey, traits::PrivateKeyParts, RsaPrivateKey},
+    zeroize::Zeroizing,
+};
+
+#[cfg(any(feature = "hazmat", feature = "untested"))]
+use rsa::traits::PublicKeyParts;
+
+#[cfg(feature = "untested")]
+use elliptic_curve::{
+    generic_array::typenum::Unsigned,
+    pkcs8::AssociatedOid,
+    sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    AffinePoint, CurveArithmetic, FieldBytes, FieldBytesSize,
+};
+
+#[cfg(feature = "hazmat")]
+use x509_cert::der::referenced::OwnedToRef;
+
+/// Object identifier of X25519 public keys (RFC 8410).
+pub const X25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.110");
 
 /// PIV Applet Name
 pub(crate) const APPLET_NAME: &str = "PIV";
@@ -83,12 +112,13 @@ pub(crate) const APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x03, 0x08];
 const CB_ECC_POINTP256: usize = 65;
 const CB_ECC_POINTP384: usize = 97;
 
-const TAG_RSA_MODULUS: u8 = 0x81;
-const TAG_RSA_EXP: u8 = 0x82;
-const TAG_ECC_POINT: u8 = 0x86;
+const TAG_RSA_MODULUS: u16 = 0x81;
+const TAG_RSA_EXP: u16 = 0x82;
+const TAG_ECC_POINT: u16 = 0x86;
 
+/// Room for the five CRT parameters of an RSA 4096 key plus the policies.
 #[cfg(feature = "untested")]
-const KEYDATA_LEN: usize = 1024;
+const KEYDATA_LEN: usize = 1400;
 
 #[cfg(feature = "untested")]
 const KEYDATA_RSA_EXP: u64 = 65537;
@@ -96,6 +126,11 @@ const KEYDATA_RSA_EXP: u64 = 65537;
 /// Slot identifiers.
 /// <https://developers.yubico.com/PIV/Introduction/Certificate_slots.html>
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "u8", into = "u8")
+)]
 pub enum SlotId {
     /// This certificate and its associated private key is used to authenticate the card
     /// and the cardholder. This slot is used for things like system login. The end user
@@ -198,6 +233,38 @@ impl FromStr for SlotId {
 }
 
 impl SlotId {
+    /// Get the [`PinPolicy`] a key in this slot has when generated or imported
+    /// with [`PinPolicy::Default`].
+    pub fn default_pin_policy(self) -> PinPolicy {
+        match self {
+            SlotId::Signature => PinPolicy::Always,
+            SlotId::CardAuthentication | SlotId::Attestation => PinPolicy::Never,
+            _ => PinPolicy::Once,
+        }
+    }
+
+    /// Get the [`TouchPolicy`] a key in this slot has when generated or imported
+    /// with [`TouchPolicy::Default`].
+    pub fn default_touch_policy(self) -> TouchPolicy {
+        TouchPolicy::Never
+    }
+
+    /// Resolve [`PinPolicy::Default`] to the concrete PIN policy for this slot.
+    pub fn resolve_pin_policy(self, policy: PinPolicy) -> PinPolicy {
+        match policy {
+            PinPolicy::Default => self.default_pin_policy(),
+            other => other,
+        }
+    }
+
+    /// Resolve [`TouchPolicy::Default`] to the concrete touch policy for this slot.
+    pub fn resolve_touch_policy(self, policy: TouchPolicy) -> TouchPolicy {
+        match policy {
+            TouchPolicy::Default => self.default_touch_policy(),
+            other => other,
+        }
+    }
+
     /// Returns the [`ObjectId`] that corresponds to a given [`SlotId`].
     pub(crate) fn object_id(self) -> ObjectId {
         match self {
@@ -482,11 +549,23 @@ pub enum AlgorithmId {
     /// 2048-bit RSA.
     Rsa2048,
 
+    /// 3072-bit RSA (requires firmware 5.7 or newer).
+    Rsa3072,
+
+    /// 4096-bit RSA (requires firmware 5.7 or newer).
+    Rsa4096,
+
     /// ECDSA with the NIST P256 curve.
     EccP256,
 
     /// ECDSA with the NIST P384 curve.
     EccP384,
+
+    /// EdDSA with Ed25519 (requires firmware 5.7 or newer).
+    Ed25519,
+
+    /// Key agreement with X25519 (requires firmware 5.7 or newer).
+    X25519,
 }
 
 impl TryFrom<u8> for AlgorithmId {
@@ -496,8 +575,12 @@ impl TryFrom<u8> for AlgorithmId {
         match value {
             0x06 => Ok(AlgorithmId::Rsa1024),
             0x07 => Ok(AlgorithmId::Rsa2048),
+            0x05 => Ok(AlgorithmId::Rsa3072),
+            0x16 => Ok(AlgorithmId::Rsa4096),
             0x11 => Ok(AlgorithmId::EccP256),
             0x14 => Ok(AlgorithmId::EccP384),
+            0xe0 => Ok(AlgorithmId::Ed25519),
+            0xe1 => Ok(AlgorithmId::X25519),
             _ => Err(Error::AlgorithmError),
         }
     }
@@ -508,8 +591,12 @@ impl From<AlgorithmId> for u8 {
         match id {
             AlgorithmId::Rsa1024 => 0x06,
             AlgorithmId::Rsa2048 => 0x07,
+            AlgorithmId::Rsa3072 => 0x05,
+            AlgorithmId::Rsa4096 => 0x16,
             AlgorithmId::EccP256 => 0x11,
             AlgorithmId::EccP384 => 0x14,
+            AlgorithmId::Ed25519 => 0xe0,
+            AlgorithmId::X25519 => 0xe1,
         }
     }
 }
@@ -520,21 +607,54 @@ impl AlgorithmId {
         Tlv::write(buf, 0x80, &[self.into()])
     }
 
+    /// Is this an RSA algorithm?
+    pub fn is_rsa(self) -> bool {
+        matches!(
+            self,
+            AlgorithmId::Rsa1024
+                | AlgorithmId::Rsa2048
+                | AlgorithmId::Rsa3072
+                | AlgorithmId::Rsa4096
+        )
+    }
+
+    /// Get the size of the RSA modulus in bytes, if this is an RSA algorithm.
+    pub fn rsa_len(self) -> Option<usize> {
+        match self {
+            AlgorithmId::Rsa1024 => Some(128),
+            AlgorithmId::Rsa2048 => Some(256),
+            AlgorithmId::Rsa3072 => Some(384),
+            AlgorithmId::Rsa4096 => Some(512),
+            AlgorithmId::EccP256
+            | AlgorithmId::EccP384
+            | AlgorithmId::Ed25519
+            | AlgorithmId::X25519 => None,
+        }
+    }
+
     #[cfg(feature = "untested")]
     fn get_elem_len(self) -> usize {
         match self {
             AlgorithmId::Rsa1024 => 64,
             AlgorithmId::Rsa2048 => 128,
+            AlgorithmId::Rsa3072 => 192,
+            AlgorithmId::Rsa4096 => 256,
             AlgorithmId::EccP256 => 32,
             AlgorithmId::EccP384 => 48,
+            AlgorithmId::Ed25519 | AlgorithmId::X25519 => 32,
         }
     }
 
     #[cfg(feature = "untested")]
-    fn get_param_tag(self) -> u8 {
+    fn get_param_tag(self) -> u16 {
         match self {
-            AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => 0x01,
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => 0x01,
             AlgorithmId::EccP256 | AlgorithmId::EccP384 => 0x6,
+            AlgorithmId::Ed25519 => 0x07,
+            AlgorithmId::X25519 => 0x08,
         }
     }
 }
@@ -584,6 +704,58 @@ impl Key {
     }
 }
 
+/// Ensure the given PIN and touch policies are supported by the YubiKey's
+/// firmware before attempting to use them for a new key.
+fn check_policies(
+    yubikey: &YubiKey,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<()> {
+    let custom_policy = pin_policy != PinPolicy::Default || touch_policy != TouchPolicy::Default;
+
+    if custom_policy && !yubikey.supports(Capability::PinTouchPolicy) {
+        error!(
+            "PIN and touch policies require firmware {} (YubiKey has {})",
+            Capability::PinTouchPolicy.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::PolicyUnsupported);
+    }
+
+    if touch_policy == TouchPolicy::Cached && !yubikey.supports(Capability::CachedTouch) {
+        error!(
+            "cached touch policy requires firmware {} (YubiKey has {})",
+            Capability::CachedTouch.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::PolicyUnsupported);
+    }
+
+    Ok(())
+}
+
+/// Ensure the given algorithm is supported by the YubiKey's firmware before
+/// attempting to use it for a new key.
+fn check_algorithm(yubikey: &YubiKey, algorithm: AlgorithmId) -> Result<()> {
+    let capability = match algorithm {
+        AlgorithmId::Rsa3072 | AlgorithmId::Rsa4096 => Capability::LargeRsaKeys,
+        AlgorithmId::Ed25519 | AlgorithmId::X25519 => Capability::Curve25519Keys,
+        _ => return Ok(()),
+    };
+
+    if !yubikey.supports(capability) {
+        error!(
+            "{:?} keys require firmware {} (YubiKey has {})",
+            algorithm,
+            capability.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::AlgorithmError);
+    }
+
+    Ok(())
+}
+
 /// Generate new key.
 pub fn generate(
     yubikey: &mut YubiKey,
@@ -591,6 +763,61 @@ pub fn generate(
     algorithm: AlgorithmId,
     pin_policy: PinPolicy,
     touch_policy: TouchPolicy,
+) -> Result<SubjectPublicKeyInfoOwned> {
+    yubikey.run_write(
+        Operation::Generate { slot, algorithm },
+        |_| placeholder_public_key(algorithm),
+        |yubikey| generate_key(yubikey, slot, algorithm, pin_policy, touch_policy),
+    )
+}
+
+/// Public key of the given algorithm returned in place of a generated one in
+/// dry-run mode: a fixed, well-formed key no one holds the private key of
+/// (the modulus of the RSA key isn't even a product of two primes).
+fn placeholder_public_key(algorithm: AlgorithmId) -> Result<SubjectPublicKeyInfoOwned> {
+    let mut scalar = [0u8; 48];
+    let spki = match algorithm {
+        AlgorithmId::Rsa1024
+        | AlgorithmId::Rsa2048
+        | AlgorithmId::Rsa3072
+        | AlgorithmId::Rsa4096 => {
+            let bits = algorithm.rsa_len().ok_or(Error::AlgorithmError)? * 8;
+            let modulus = (BigUint::from(1u8) << (bits - 1)) | BigUint::from(1u8);
+            let key = RsaPublicKey::new(modulus, BigUint::from(65537u32))
+                .map_err(|_| Error::AlgorithmError)?;
+            SubjectPublicKeyInfoOwned::from_key(key)
+        }
+        AlgorithmId::EccP256 => {
+            scalar[31] = 1;
+            let key =
+                p256::SecretKey::from_slice(&scalar[..32]).map_err(|_| Error::AlgorithmError)?;
+            SubjectPublicKeyInfoOwned::from_key(key.public_key())
+        }
+        AlgorithmId::EccP384 => {
+            scalar[47] = 1;
+            let key = p384::SecretKey::from_slice(&scalar).map_err(|_| Error::AlgorithmError)?;
+            SubjectPublicKeyInfoOwned::from_key(key.public_key())
+        }
+        AlgorithmId::Ed25519 => {
+            let key = ed25519_dalek::SigningKey::from_bytes(&[0; 32]);
+            SubjectPublicKeyInfoOwned::from_key(key.verifying_key())
+        }
+        // The base point, i.e. the public key of the scalar 1
+        AlgorithmId::X25519 => {
+            scalar[0] = 9;
+            return x25519_public_key(&scalar[..32]);
+        }
+    };
+
+    spki.map_err(|_| Error::AlgorithmError)
+}
+
+fn generate_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    algorithm: AlgorithmId,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
 ) -> Result<SubjectPublicKeyInfoOwned> {
     // Keygen messages
     // TODO(tarcieri): extract these into an I18N-handling type?
@@ -603,50 +830,48 @@ pub fn generate(
     const SZ_ROCA_BLOCK_ADMIN: &str = "was blocked due to an administrator configuration setting.";
     const SZ_ROCA_DEFAULT: &str = "was permitted by default, but is not recommended.  The default behavior will change in a future Yubico release.";
 
-    let setting_roca: setting::Setting;
-
     match algorithm {
-        AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => {
-            if yubikey.version.major == 4
-                && (yubikey.version.minor < 3
-                    || yubikey.version.minor == 3 && (yubikey.version.patch < 5))
-            {
-                setting_roca = setting::Setting::get(SZ_SETTING_ROCA, true);
-
-                let psz_msg = match setting_roca.source {
-                    setting::SettingSource::User => {
-                        if setting_roca.value {
-                            SZ_ROCA_ALLOW_USER
-                        } else {
-                            SZ_ROCA_BLOCK_USER
-                        }
+        AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048
+            if yubikey.version.major == 4 && !yubikey.version.is_at_least(4, 3, 5) =>
+        {
+            let setting_roca = setting::Setting::get(SZ_SETTING_ROCA, true);
+
+            let psz_msg = match setting_roca.source {
+                setting::SettingSource::User => {
+                    if setting_roca.value {
+                        SZ_ROCA_ALLOW_USER
+                    } else {
+                        SZ_ROCA_BLOCK_USER
                     }
-                    setting::SettingSource::Admin => {
-                        if setting_roca.value {
-                            SZ_ROCA_ALLOW_ADMIN
-                        } else {
-                            SZ_ROCA_BLOCK_ADMIN
-                        }
+                }
+                setting::SettingSource::Admin => {
+                    if setting_roca.value {
+                        SZ_ROCA_ALLOW_ADMIN
+                    } else {
+                        SZ_ROCA_BLOCK_ADMIN
                     }
-                    _ => SZ_ROCA_DEFAULT,
-                };
+                }
+                _ => SZ_ROCA_DEFAULT,
+            };
 
-                warn!(
-                    "YubiKey serial number {} is affected by vulnerability CVE-2017-15361 \
-                     (ROCA) and should be replaced. On-chip key generation {}  See \
-                     YSA-2017-01 <https://www.yubico.com/support/security-advisories/ysa-2017-01/> \
-                     for additional information on device replacement and mitigation assistance",
-                    yubikey.serial, psz_msg
-                );
+            warn!(
+                "YubiKey serial number {} is affected by vulnerability CVE-2017-15361 \
+                 (ROCA) and should be replaced. On-chip key generation {}  See \
+                 YSA-2017-01 <https://www.yubico.com/support/security-advisories/ysa-2017-01/> \
+                 for additional information on device replacement and mitigation assistance",
+                yubikey.serial, psz_msg
+            );
 
-                if !setting_roca.value {
-                    return Err(Error::NotSupported);
-                }
+            if !setting_roca.value {
+                return Err(Error::NotSupported);
             }
         }
         _ => (),
     }
 
+    check_algorithm(yubikey, algorithm)?;
+    check_policies(yubikey, pin_policy, touch_policy)?;
+
     let txn = yubikey.begin_transaction()?;
 
     let templ = [0, Ins::GenerateAsymmetric.code(), 0, slot.into()];
@@ -696,6 +921,9 @@ pub fn generate(
         }
     }
 
+    drop(txn);
+    yubikey.slot_policies.remove(&slot);
+
     let value = response.data();
     read_public_key(algorithm, value, true)
 }
@@ -709,6 +937,9 @@ fn write_key(
     touch_policy: TouchPolicy,
     algorithm: AlgorithmId,
 ) -> Result<()> {
+    check_algorithm(yubikey, algorithm)?;
+    check_policies(yubikey, pin_policy, touch_policy)?;
+
     let mut key_data = Buffer::new(vec![0u8; KEYDATA_LEN]);
     let templ = [0, Ins::ImportKey.code(), algorithm.into(), slot.into()];
     let mut offset = 0;
@@ -719,7 +950,7 @@ fn write_key(
     for (i, param) in params.into_iter().enumerate() {
         offset += Tlv::write_as(
             &mut key_data[offset..],
-            param_tag + (i as u8),
+            param_tag + (i as u16),
             elem_len,
             |buf| {
                 let padding = elem_len - param.len();
@@ -740,6 +971,9 @@ fn write_key(
         .transfer_data(&templ, &key_data[..offset], 256)?
         .status_words();
 
+    drop(txn);
+    yubikey.slot_policies.remove(&slot);
+
     match status_words {
         StatusWords::Success => Ok(()),
         StatusWords::SecurityStatusError => Err(Error::AuthenticationError),
@@ -775,7 +1009,7 @@ impl RsaKeyData {
 
         let totient = {
             let p_t = &p - BigUint::one();
-            let q_t = &p - BigUint::one();
+            let q_t = &q - BigUint::one();
 
             p_t.lcm(&q_t)
         };
@@ -803,14 +1037,58 @@ impl RsaKeyData {
         })
     }
 
+    /// Extracts the key data of an RSA private key encoded as PKCS#8 DER.
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self> {
+        let key = RsaPrivateKey::from_pkcs8_der(der).map_err(|_| Error::KeyError)?;
+        Self::try_from(&key)
+    }
+
     fn total_len(&self) -> usize {
         self.p.len() + self.q.len() + self.dp.len() + self.qinv.len()
     }
 }
 
+/// Splits an RSA private key into the CRT components the YubiKey needs.
+///
+/// Errors with [`Error::AlgorithmError`] if the key doesn't have exactly two
+/// primes, or its public exponent isn't 65537 (the only one the YubiKey
+/// supports).
+#[cfg(feature = "untested")]
+impl TryFrom<&RsaPrivateKey> for RsaKeyData {
+    type Error = Error;
+
+    fn try_from(key: &RsaPrivateKey) -> Result<Self> {
+        let (p, q) = match key.primes() {
+            [p, q] => (p, q),
+            _ => return Err(Error::AlgorithmError),
+        };
+
+        if BigUint::from_u64(KEYDATA_RSA_EXP).as_ref() != Some(key.e()) {
+            return Err(Error::AlgorithmError);
+        }
+
+        let dp = key.d() % (p - BigUint::one());
+        let dq = key.d() % (q - BigUint::one());
+
+        let qinv = q.clone().mod_inverse(p).ok_or(Error::AlgorithmError)?;
+        let (_, qinv) = qinv.to_bytes_be();
+
+        Ok(RsaKeyData {
+            p: Zeroizing::new(p.to_bytes_be()),
+            q: Zeroizing::new(q.to_bytes_be()),
+            dp: Zeroizing::new(dp.to_bytes_be()),
+            dq: Zeroizing::new(dq.to_bytes_be()),
+            qinv: Zeroizing::new(qinv),
+        })
+    }
+}
+
 /// Imports a private RSA encryption or signing key into the YubiKey.
 ///
-/// Errors if `algorithm` isn't `AlgorithmId::Rsa1024` or `AlgorithmId::Rsa2048`.
+/// Errors if `algorithm` isn't an RSA algorithm, and with
+/// [`Error::NotSupported`] for `AlgorithmId::Rsa3072` or
+/// `AlgorithmId::Rsa4096` unless the firmware supports
+/// [`Capability::LargeRsaKeys`].
 #[cfg(feature = "untested")]
 pub fn import_rsa_key(
     yubikey: &mut YubiKey,
@@ -822,6 +1100,17 @@ pub fn import_rsa_key(
 ) -> Result<()> {
     match algorithm {
         AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => (),
+        AlgorithmId::Rsa3072 | AlgorithmId::Rsa4096 => {
+            if !yubikey.supports(Capability::LargeRsaKeys) {
+                error!(
+                    "{:?} keys require firmware {} (YubiKey has {})",
+                    algorithm,
+                    Capability::LargeRsaKeys.min_version(),
+                    yubikey.version()
+                );
+                return Err(Error::NotSupported);
+            }
+        }
         _ => return Err(Error::AlgorithmError),
     }
 
@@ -837,9 +1126,56 @@ pub fn import_rsa_key(
         key_data.qinv.as_slice(),
     ];
 
-    write_key(yubikey, slot, params, pin_policy, touch_policy, algorithm)?;
+    yubikey.run_write(
+        Operation::ImportKey { slot, algorithm },
+        |_| Ok(()),
+        |yubikey| {
+            write_key(
+                yubikey,
+                slot,
+                params.clone(),
+                pin_policy,
+                touch_policy,
+                algorithm,
+            )
+        },
+    )
+}
 
-    Ok(())
+/// Imports an RSA private key into the YubiKey, e.g. to move an existing CA
+/// or TLS key onto it.
+///
+/// The algorithm is chosen from the size of the key. Keys encoded as PKCS#8
+/// can be decoded with [`RsaKeyData::from_pkcs8_der`] and imported with
+/// [`import_rsa_key`] instead.
+///
+/// Errors with [`Error::AlgorithmError`] if the key isn't a 1024, 2048, 3072
+/// or 4096-bit key with the public exponent 65537, and with
+/// [`Error::NotSupported`] for 3072 and 4096-bit keys on firmware before 5.7.
+#[cfg(feature = "untested")]
+pub fn import_rsa_private_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    key: &RsaPrivateKey,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<()> {
+    let algorithm = match key.size() {
+        128 => AlgorithmId::Rsa1024,
+        256 => AlgorithmId::Rsa2048,
+        384 => AlgorithmId::Rsa3072,
+        512 => AlgorithmId::Rsa4096,
+        _ => return Err(Error::AlgorithmError),
+    };
+
+    import_rsa_key(
+        yubikey,
+        slot,
+        algorithm,
+        RsaKeyData::try_from(key)?,
+        touch_policy,
+        pin_policy,
+    )
 }
 
 /// Imports a private ECC encryption or signing key into the YubiKey.
@@ -865,46 +1201,503 @@ pub fn import_ecc_key(
 
     let params = vec![key_data];
 
-    write_key(yubikey, slot, params, pin_policy, touch_policy, algorithm)?;
+    yubikey.run_write(
+        Operation::ImportKey { slot, algorithm },
+        |_| Ok(()),
+        |yubikey| {
+            write_key(
+                yubikey,
+                slot,
+                params.clone(),
+                pin_policy,
+                touch_policy,
+                algorithm,
+            )
+        },
+    )
+}
+
+/// Imports an Ed25519 signing key into the YubiKey.
+///
+/// Requires firmware 5.7 or newer.
+#[cfg(feature = "untested")]
+pub fn import_ed25519_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    key: &ed25519_dalek::SigningKey,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<()> {
+    let algorithm = AlgorithmId::Ed25519;
+    let seed = Zeroizing::new(key.to_bytes());
+
+    yubikey.run_write(
+        Operation::ImportKey { slot, algorithm },
+        |_| Ok(()),
+        |yubikey| {
+            write_key(
+                yubikey,
+                slot,
+                vec![seed.as_slice()],
+                pin_policy,
+                touch_policy,
+                algorithm,
+            )
+        },
+    )
+}
+
+/// Imports an X25519 key agreement key, given as its 32-byte secret scalar,
+/// into the YubiKey.
+///
+/// Requires firmware 5.7 or newer.
+#[cfg(feature = "untested")]
+pub fn import_x25519_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    secret: &[u8; 32],
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<()> {
+    let algorithm = AlgorithmId::X25519;
+
+    yubikey.run_write(
+        Operation::ImportKey { slot, algorithm },
+        |_| Ok(()),
+        |yubikey| {
+            write_key(
+                yubikey,
+                slot,
+                vec![secret.as_slice()],
+                pin_policy,
+                touch_policy,
+                algorithm,
+            )
+        },
+    )
+}
+
+/// ECC private key which can be imported into the YubiKey with
+/// [`import_ecc_private_key`].
+#[cfg(feature = "untested")]
+#[derive(Clone)]
+pub enum EccPrivateKey {
+    /// NIST P-256 key
+    P256(p256::SecretKey),
+
+    /// NIST P-384 key
+    P384(p384::SecretKey),
+}
+
+#[cfg(feature = "untested")]
+impl EccPrivateKey {
+    /// Decodes a P-256 or P-384 private key encoded as SEC1 DER (i.e. an
+    /// `ECPrivateKey` structure).
+    pub fn from_sec1_der(der: &[u8]) -> Result<Self> {
+        p256::SecretKey::from_sec1_der(der)
+            .map(Self::P256)
+            .or_else(|_| p384::SecretKey::from_sec1_der(der).map(Self::P384))
+            .map_err(|_| Error::KeyError)
+    }
+
+    /// Decodes a P-256 or P-384 private key encoded as PKCS#8 DER.
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self> {
+        p256::SecretKey::from_pkcs8_der(der)
+            .map(Self::P256)
+            .or_else(|_| p384::SecretKey::from_pkcs8_der(der).map(Self::P384))
+            .map_err(|_| Error::KeyError)
+    }
+
+    /// Returns the algorithm of the key.
+    pub fn algorithm(&self) -> AlgorithmId {
+        match self {
+            EccPrivateKey::P256(_) => AlgorithmId::EccP256,
+            EccPrivateKey::P384(_) => AlgorithmId::EccP384,
+        }
+    }
+
+    /// Returns the private scalar of the key, as sent to the YubiKey.
+    fn to_bytes(&self) -> Buffer {
+        match self {
+            EccPrivateKey::P256(key) => Zeroizing::new(key.to_bytes().to_vec()),
+            EccPrivateKey::P384(key) => Zeroizing::new(key.to_bytes().to_vec()),
+        }
+    }
+}
+
+#[cfg(feature = "untested")]
+impl From<p256::SecretKey> for EccPrivateKey {
+    fn from(key: p256::SecretKey) -> Self {
+        EccPrivateKey::P256(key)
+    }
+}
+
+#[cfg(feature = "untested")]
+impl From<p384::SecretKey> for EccPrivateKey {
+    fn from(key: p384::SecretKey) -> Self {
+        EccPrivateKey::P384(key)
+    }
+}
+
+#[cfg(feature = "untested")]
+impl std::fmt::Debug for EccPrivateKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("EccPrivateKey")
+            .field(&self.algorithm())
+            .finish()
+    }
+}
+
+/// Imports a P-256 or P-384 private key into the YubiKey, e.g. a key
+/// generated externally.
+///
+/// Keys encoded as SEC1 or PKCS#8 can be decoded with
+/// [`EccPrivateKey::from_sec1_der`] and [`EccPrivateKey::from_pkcs8_der`].
+#[cfg(feature = "untested")]
+pub fn import_ecc_private_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    key: impl Into<EccPrivateKey>,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<()> {
+    let key = key.into();
+
+    import_ecc_key(
+        yubikey,
+        slot,
+        key.algorithm(),
+        &key.to_bytes(),
+        touch_policy,
+        pin_policy,
+    )
+}
+
+/// Move the key in slot `from` to slot `to`, e.g. to promote a key generated
+/// in a retired slot to one of the standard slots without regenerating it.
+///
+/// The key keeps its PIN and touch policies, and `from` is left empty. The
+/// certificate in `from`, if any, isn't moved: write it to `to` with
+/// [`Certificate::write`].
+///
+/// Requires authentication with the management key. Returns
+/// [`Error::NotSupported`] if the YubiKey doesn't support
+/// [`Capability::MoveKey`], and [`Error::KeyError`] if either slot can't hold
+/// a movable key (the attestation and management slots can't).
+pub fn move_key(yubikey: &mut YubiKey, from: SlotId, to: SlotId) -> Result<()> {
+    yubikey.run_write(
+        Operation::MoveKey { from, to },
+        |yubikey| check_move_key(yubikey, from, to),
+        |yubikey| {
+            check_move_key(yubikey, from, to)?;
+
+            let templ = [0, Ins::MoveKey.code(), to.into(), from.into()];
+            let txn = yubikey.begin_transaction()?;
+            let response = txn.transfer_data(&templ, &[], 255)?;
+
+            if !response.is_success() {
+                let err_msg = format!("failed to move key from slot {} to slot {}", from, to);
+
+                return match response.status_words() {
+                    StatusWords::IncorrectSlotError | StatusWords::IncorrectParamError => {
+                        error!("{} (incorrect slot)", err_msg);
+                        Err(Error::KeyError)
+                    }
+                    StatusWords::ReferenceDataNotFoundError => {
+                        error!("{} (no key in slot {})", err_msg, from);
+                        Err(Error::NotFound)
+                    }
+                    StatusWords::SecurityStatusError => {
+                        error!("{} (not authenticated)", err_msg);
+                        Err(Error::AuthenticationError)
+                    }
+                    other => {
+                        error!("{} (error {:?})", err_msg, other);
+                        Err(Error::GenericError)
+                    }
+                };
+            }
+
+            drop(txn);
+
+            match yubikey.slot_policies.remove(&from) {
+                Some(policy) => yubikey.slot_policies.insert(to, policy),
+                None => yubikey.slot_policies.remove(&to),
+            };
+
+            Ok(())
+        },
+    )
+}
+
+/// Delete the key in the given slot, without resetting the whole PIV
+/// application.
+///
+/// The certificate in the slot, if any, isn't deleted: delete it with
+/// `Certificate::delete`.
+///
+/// Requires authentication with the management key. Returns
+/// [`Error::NotSupported`] if the YubiKey doesn't support
+/// [`Capability::DeleteKey`], and [`Error::KeyError`] if the slot can't hold
+/// a deletable key (the attestation and management slots can't).
+pub fn delete_key(yubikey: &mut YubiKey, slot: SlotId) -> Result<()> {
+    yubikey.run_write(
+        Operation::DeleteKey { slot },
+        |yubikey| check_delete_key(yubikey, slot),
+        |yubikey| {
+            check_delete_key(yubikey, slot)?;
+
+            // DELETE KEY is MOVE KEY with 0xff as the destination
+            let templ = [0, Ins::MoveKey.code(), 0xff, slot.into()];
+            let txn = yubikey.begin_transaction()?;
+            let response = txn.transfer_data(&templ, &[], 255)?;
+
+            if !response.is_success() {
+                let err_msg = format!("failed to delete key in slot {}", slot);
+
+                return match response.status_words() {
+                    StatusWords::IncorrectSlotError | StatusWords::IncorrectParamError => {
+                        error!("{} (incorrect slot)", err_msg);
+                        Err(Error::KeyError)
+                    }
+                    StatusWords::ReferenceDataNotFoundError => {
+                        error!("{} (no key in slot)", err_msg);
+                        Err(Error::NotFound)
+                    }
+                    StatusWords::SecurityStatusError => {
+                        error!("{} (not authenticated)", err_msg);
+                        Err(Error::AuthenticationError)
+                    }
+                    other => {
+                        error!("{} (error {:?})", err_msg, other);
+                        Err(Error::GenericError)
+                    }
+                };
+            }
+
+            drop(txn);
+            yubikey.slot_policies.remove(&slot);
+
+            Ok(())
+        },
+    )
+}
+
+/// Check the YubiKey can delete the key in the given slot.
+fn check_delete_key(yubikey: &YubiKey, slot: SlotId) -> Result<()> {
+    if !yubikey.supports(Capability::DeleteKey) {
+        error!(
+            "deleting keys requires firmware {} (YubiKey has {})",
+            Capability::DeleteKey.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::NotSupported);
+    }
+
+    if matches!(slot, SlotId::Attestation | SlotId::Management(_)) {
+        error!("can't delete the key in slot {}", slot);
+        return Err(Error::KeyError);
+    }
 
     Ok(())
 }
 
-/// Generate an attestation certificate for a stored key.
+/// Check the YubiKey can move a key from slot `from` to slot `to`.
+fn check_move_key(yubikey: &YubiKey, from: SlotId, to: SlotId) -> Result<()> {
+    if !yubikey.supports(Capability::MoveKey) {
+        error!(
+            "moving keys requires firmware {} (YubiKey has {})",
+            Capability::MoveKey.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::NotSupported);
+    }
+
+    for slot in [from, to] {
+        if matches!(slot, SlotId::Attestation | SlotId::Management(_)) {
+            error!("can't move keys to or from slot {}", slot);
+            return Err(Error::KeyError);
+        }
+    }
+
+    if from == to {
+        error!("can't move the key in slot {} to itself", from);
+        return Err(Error::KeyError);
+    }
+
+    Ok(())
+}
+
+/// Generate an attestation certificate for a stored key, proving it was
+/// generated on the YubiKey (imported keys can't be attested).
+///
+/// The attestation certificate is signed by the intermediate attestation
+/// certificate returned by [`attestation_certificate`]: see the
+/// [`attestation`](crate::attestation) module to verify it.
+///
+/// Returns [`Error::NotSupported`] if the YubiKey doesn't support
+/// [`Capability::Attestation`], and [`Error::KeyError`] for the attestation
+/// and management slots.
 ///
 /// <https://developers.yubico.com/PIV/Introduction/PIV_attestation.html>
 #[cfg(feature = "untested")]
-pub fn attest(yubikey: &mut YubiKey, key: SlotId) -> Result<Buffer> {
-    let templ = [0, Ins::Attest.code(), key.into(), 0];
-    let txn = yubikey.begin_transaction()?;
-    let response = txn.transfer_data(&templ, &[], CB_OBJ_MAX)?;
+pub fn attest(yubikey: &mut YubiKey, key: SlotId) -> Result<Certificate> {
+    if !yubikey.supports(Capability::Attestation) {
+        error!(
+            "attestation requires firmware {} (YubiKey has {})",
+            Capability::Attestation.min_version(),
+            yubikey.version()
+        );
+        return Err(Error::NotSupported);
+    }
 
-    if !response.is_success() {
-        if response.status_words() == StatusWords::NotSupportedError {
-            return Err(Error::NotSupported);
-        } else {
+    if matches!(key, SlotId::Attestation | SlotId::Management(_)) {
+        error!("can't attest the key in slot {}", key);
+        return Err(Error::KeyError);
+    }
+
+    yubikey.run(Operation::Attest { slot: key }, |yubikey| {
+        let templ = [0, Ins::Attest.code(), key.into(), 0];
+        let txn = yubikey.begin_transaction()?;
+        let response = txn.transfer_data(&templ, &[], CB_OBJ_MAX)?;
+
+        if !response.is_success() {
+            return match response.status_words() {
+                StatusWords::NotSupportedError => Err(Error::NotSupported),
+                StatusWords::ReferenceDataNotFoundError => {
+                    error!("no key to attest in slot {}", key);
+                    Err(Error::NotFound)
+                }
+                other => {
+                    error!("failed to attest key in slot {} (error {:?})", key, other);
+                    Err(Error::GenericError)
+                }
+            };
+        }
+
+        if response.data().first() != Some(&0x30) {
+            error!("attestation of slot {} isn't a certificate", key);
             return Err(Error::GenericError);
         }
-    }
 
-    if response.data()[0] != 0x30 {
-        return Err(Error::GenericError);
-    }
+        Certificate::from_bytes(response.data().to_vec())
+    })
+}
 
-    Ok(Buffer::new(response.data().into()))
+/// Read the intermediate attestation certificate stored in
+/// [`SlotId::Attestation`], which signs the attestation certificates
+/// generated by `attest`.
+///
+/// It's signed by Yubico's PIV attestation root CA, unless the YubiKey was
+/// re-keyed for enterprise attestation.
+pub fn attestation_certificate(yubikey: &mut YubiKey) -> Result<Certificate> {
+    Certificate::read(yubikey, SlotId::Attestation)
 }
 
 /// Sign data using a PIV key.
+///
+/// `raw_in` is the digest to sign for ECDSA, the padded block for RSA, and
+/// the whole message for Ed25519.
 pub fn sign_data(
     yubikey: &mut YubiKey,
     raw_in: &[u8],
     algorithm: AlgorithmId,
     key: SlotId,
 ) -> Result<Buffer> {
-    let txn = yubikey.begin_transaction()?;
+    yubikey.run(
+        Operation::Sign {
+            slot: key,
+            algorithm,
+        },
+        |yubikey| {
+            yubikey.usage_policy.check(key, KeyUsage::Sign)?;
+            yubikey.rate_limiter.signature(yubikey.clock.now())?;
+            yubikey.ensure_pin_verified_for(key)?;
+            let txn = yubikey.begin_transaction()?;
+
+            // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
+            txn.authenticated_command(raw_in, algorithm, key, false)
+        },
+    )
+}
+
+/// Sign a message digest with RSASSA-PSS using an RSA PIV key, with MGF1
+/// over the same hash `D` as the digest (e.g. `Sha256`).
+///
+/// PIV cards only perform the raw RSA operation, so the message is encoded
+/// with EMSA-PSS host-side before being signed with [`sign_data`]. The salt
+/// defaults to random bytes from the OS RNG, the length of the digest, as
+/// expected by most verifiers.
+///
+/// Returns [`Error::AlgorithmError`] if `algorithm` isn't an RSA algorithm,
+/// and [`Error::SizeError`] if `digest` isn't the size of the output of `D`.
+pub fn sign_data_pss<D>(
+    yubikey: &mut YubiKey,
+    digest: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+    salt: Option<&[u8]>,
+) -> Result<Buffer>
+where
+    D: 'static + sha2::Digest + sha2::digest::DynDigest + Send + Sync,
+{
+    let modulus_len = algorithm.rsa_len().ok_or(Error::AlgorithmError)?;
+    let encoded = signer::pss_encode::<D>(digest, salt, modulus_len)?;
+    sign_data(yubikey, &encoded, algorithm, key)
+}
+
+/// Perform the raw RSA private key operation (`block^d mod n`) with the key
+/// in the given slot.
+///
+/// HAZMAT: no padding is applied to or checked in `block`. Protocols built on
+/// this must pad blocks securely themselves: unpadded or poorly padded blocks
+/// can yield forgeable signatures or leak information about the key. Prefer
+/// [`sign_data`] with a standard padding whenever possible.
+///
+/// `block` must be exactly the size of the modulus. Where the YubiKey reports
+/// slot metadata (firmware 5.3 or newer), the slot must contain an RSA key of
+/// that size and `block` must be smaller than its modulus as well. Returns
+/// [`Error::SizeError`] otherwise.
+#[cfg(feature = "hazmat")]
+pub fn raw_rsa_operation(yubikey: &mut YubiKey, slot: SlotId, block: &[u8]) -> Result<Buffer> {
+    let algorithm = match block.len() {
+        128 => AlgorithmId::Rsa1024,
+        256 => AlgorithmId::Rsa2048,
+        384 => AlgorithmId::Rsa3072,
+        512 => AlgorithmId::Rsa4096,
+        len => {
+            error!(
+                "raw RSA blocks must be 128, 256, 384 or 512 bytes long (got {})",
+                len
+            );
+            return Err(Error::SizeError);
+        }
+    };
+
+    if yubikey.supports(Capability::Metadata) {
+        let metadata = metadata(yubikey, slot)?;
 
-    // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
-    txn.authenticated_command(raw_in, algorithm, key, false)
+        if metadata.algorithm != ManagementAlgorithmId::Asymmetric(algorithm) {
+            error!(
+                "slot {} contains a {:?} key, not {:?}",
+                slot, metadata.algorithm, algorithm
+            );
+            return Err(Error::SizeError);
+        }
+
+        let modulus = metadata
+            .public
+            .as_ref()
+            .and_then(|public| RsaPublicKey::try_from(public.owned_to_ref()).ok())
+            .map(|public| public.n().clone());
+
+        if modulus.map_or(false, |modulus| BigUint::from_bytes_be(block) >= modulus) {
+            error!("raw RSA block must be smaller than the modulus");
+            return Err(Error::SizeError);
+        }
+    }
+
+    sign_data(yubikey, block, algorithm, slot)
 }
 
 /// Decrypt data using a PIV key.
@@ -915,18 +1708,299 @@ pub fn decrypt_data(
     algorithm: AlgorithmId,
     key: SlotId,
 ) -> Result<Buffer> {
-    let txn = yubikey.begin_transaction()?;
+    yubikey.run(
+        Operation::Decrypt {
+            slot: key,
+            algorithm,
+        },
+        |yubikey| {
+            yubikey.usage_policy.check(key, KeyUsage::Decrypt)?;
+            yubikey.ensure_pin_verified_for(key)?;
+            let txn = yubikey.begin_transaction()?;
+
+            // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
+            txn.authenticated_command(input, algorithm, key, true)
+        },
+    )
+}
+
+/// Decrypt a message encrypted with RSAES-PKCS1-v1_5 using an RSA PIV key,
+/// removing the padding of the result of [`decrypt_data`] in constant time
+/// with the `rsa` crate.
+///
+/// Returns [`Error::AlgorithmError`] if `algorithm` isn't an RSA algorithm,
+/// and [`Error::GenericError`] if the padding is invalid.
+#[cfg(feature = "untested")]
+pub fn decrypt_pkcs1v15(
+    yubikey: &mut YubiKey,
+    ciphertext: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+) -> Result<Buffer> {
+    if !algorithm.is_rsa() {
+        return Err(Error::AlgorithmError);
+    }
+
+    let block = decrypt_data(yubikey, ciphertext, algorithm, key)?;
+    signer::unpad(&block, rsa::Pkcs1v15Encrypt)
+}
+
+/// Decrypt a message encrypted with RSAES-OAEP using an RSA PIV key, with
+/// the given label and MGF1, both over `D` (e.g. `Sha256`), removing the
+/// padding of the result of [`decrypt_data`] in constant time with the `rsa`
+/// crate.
+///
+/// Returns [`Error::AlgorithmError`] if `algorithm` isn't an RSA algorithm,
+/// and [`Error::GenericError`] if the padding or label is invalid.
+#[cfg(feature = "untested")]
+pub fn decrypt_oaep<D>(
+    yubikey: &mut YubiKey,
+    ciphertext: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+    label: &str,
+) -> Result<Buffer>
+where
+    D: 'static + sha2::Digest + sha2::digest::DynDigest + Send + Sync,
+{
+    if !algorithm.is_rsa() {
+        return Err(Error::AlgorithmError);
+    }
+
+    let block = decrypt_data(yubikey, ciphertext, algorithm, key)?;
+    signer::unpad(&block, rsa::Oaep::new_with_label::<D, _>(label))
+}
+
+/// Perform X25519 key agreement between the key in the given slot and the
+/// peer's public key, returning the raw shared secret.
+///
+/// The shared secret isn't uniformly random: derive keys from it with a KDF
+/// (e.g. HKDF) rather than using it directly. Requires firmware 5.7 or newer.
+#[cfg(feature = "untested")]
+pub fn x25519_key_agreement(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    peer_public: &[u8; 32],
+) -> Result<Buffer> {
+    decrypt_data(yubikey, peer_public, AlgorithmId::X25519, slot)
+}
+
+/// Perform ECDH key agreement between the P-256 or P-384 key in the given
+/// slot and the peer's public key on the same curve.
+///
+/// Like any ECDH shared secret, the result must be passed through a KDF
+/// before being used as a key: see [`derive_symmetric_key`], or
+/// [`SharedSecret::extract`] for other hash functions.
+#[cfg(feature = "untested")]
+pub fn key_agreement<C>(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    peer_public: &PublicKey<C>,
+) -> Result<SharedSecret<C>>
+where
+    C: CurveArithmetic + AssociatedOid,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+    FieldBytesSize<C>: ModulusSize,
+{
+    let algorithm = if C::OID == NistP256::OID {
+        AlgorithmId::EccP256
+    } else if C::OID == NistP384::OID {
+        AlgorithmId::EccP384
+    } else {
+        error!("key agreement is only supported on P-256 and P-384");
+        return Err(Error::AlgorithmError);
+    };
+
+    let point = peer_public.to_encoded_point(false);
+    let shared = decrypt_data(yubikey, point.as_bytes(), algorithm, slot)?;
+
+    if shared.len() != FieldBytesSize::<C>::USIZE {
+        error!("unexpected shared secret length: {}", shared.len());
+        return Err(Error::SizeError);
+    }
+
+    Ok(SharedSecret::from(FieldBytes::<C>::clone_from_slice(
+        &shared,
+    )))
+}
+
+/// Derive a symmetric key of `len` bytes from an ECDH shared secret with
+/// HKDF-SHA256 (RFC 5869), e.g. one returned by [`key_agreement`].
+///
+/// Returns [`Error::SizeError`] if `len` exceeds 8160 bytes, the most HKDF
+/// can output.
+pub fn derive_symmetric_key<C: Curve>(
+    secret: &SharedSecret<C>,
+    salt: Option<&[u8]>,
+    info: &[u8],
+    len: usize,
+) -> Result<Buffer> {
+    let mut key = Buffer::new(vec![0; len]);
+    secret
+        .extract::<sha2::Sha256>(salt)
+        .expand(info, &mut key)
+        .map_err(|_| Error::SizeError)?;
+    Ok(key)
+}
+
+/// Derive a stable secret bound to the key in the given slot, for the given
+/// context.
+///
+/// The same key and context always yield the same 32-byte secret, and the
+/// secret can't be derived without the key, so it can e.g. unlock disk
+/// encryption or bind an enrollment to this YubiKey. Each context yields an
+/// independent secret. The key's PIN and touch policies apply as for any
+/// other private key operation.
+///
+/// The construction, with `DST = "yubikey.rs device secret v1"`, is:
+///
+/// - ECC (P-256 and P-384) keys: the context is hashed to a curve point with
+///   the `P256_XMD:SHA-256_SSWU_RO_` or `P384_XMD:SHA-384_SSWU_RO_` suite of
+///   RFC 9380 (with `DST` as domain separation tag), and `IKM` is the
+///   x-coordinate of the ECDH shared secret between the key and that point.
+/// - RSA keys: `IKM` is the RSASSA-PKCS1-v1_5 signature (with SHA-256) of
+///   `DST || context`, which is deterministic. Anyone obtaining a signature
+///   of the same message with this key can derive the secret, so don't use
+///   RSA keys which sign messages from untrusted parties.
+///
+/// The secret is then `HKDF-SHA256(salt = SHA-256(SPKI), IKM, info = DST ||
+/// context)`, where `SPKI` is the DER-encoded public key of the slot.
+///
+/// The slot's public key is read from its metadata, so this requires
+/// firmware 5.3 or newer.
+#[cfg(feature = "untested")]
+pub fn derive_device_secret(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    context: &[u8],
+) -> Result<Zeroizing<[u8; 32]>> {
+    use crate::certificate::yubikey_signer::{
+        KeyType, Rsa1024, Rsa2048, Rsa3072, Rsa4096, YubiRsa,
+    };
+    use elliptic_curve::hash2curve::{ExpandMsgXmd, GroupDigest};
+    use hkdf::Hkdf;
+    use sha2::{Digest, Sha256, Sha384};
+    use x509_cert::der::Encode;
+
+    const DST: &[u8] = b"yubikey.rs device secret v1";
+
+    if !yubikey.supports(Capability::Metadata) {
+        error!("deriving device secrets requires firmware 5.3 or newer");
+        return Err(Error::NotSupported);
+    }
+
+    let metadata = metadata(yubikey, slot)?;
+    let public_key = metadata.public.ok_or(Error::KeyError)?;
+    let message = [DST, context].concat();
+
+    let ikm = match metadata.algorithm {
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::EccP256) => {
+            let point = NistP256::hash_from_bytes::<ExpandMsgXmd<Sha256>>(&[context], &[DST])
+                .map_err(|_| Error::GenericError)?;
+            let point = EcPublicKey::<NistP256>::from(point.to_affine());
+            decrypt_data(yubikey, point.as_bytes(), algorithm, slot)?
+        }
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::EccP384) => {
+            let point = NistP384::hash_from_bytes::<ExpandMsgXmd<Sha384>>(&[context], &[DST])
+                .map_err(|_| Error::GenericError)?;
+            let point = EcPublicKey::<NistP384>::from(point.to_affine());
+            decrypt_data(yubikey, point.as_bytes(), algorithm, slot)?
+        }
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::Rsa1024) => {
+            let block = YubiRsa::<Rsa1024>::prepare(&message).map_err(|_| Error::GenericError)?;
+            sign_data(yubikey, &block, algorithm, slot)?
+        }
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::Rsa2048) => {
+            let block = YubiRsa::<Rsa2048>::prepare(&message).map_err(|_| Error::GenericError)?;
+            sign_data(yubikey, &block, algorithm, slot)?
+        }
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::Rsa3072) => {
+            let block = YubiRsa::<Rsa3072>::prepare(&message).map_err(|_| Error::GenericError)?;
+            sign_data(yubikey, &block, algorithm, slot)?
+        }
+        ManagementAlgorithmId::Asymmetric(algorithm @ AlgorithmId::Rsa4096) => {
+            let block = YubiRsa::<Rsa4096>::prepare(&message).map_err(|_| Error::GenericError)?;
+            sign_data(yubikey, &block, algorithm, slot)?
+        }
+        algorithm => {
+            error!("can't derive device secrets with {:?} keys", algorithm);
+            return Err(Error::AlgorithmError);
+        }
+    };
+
+    let salt = Sha256::digest(public_key.to_der()?);
+    let mut secret = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), &ikm)
+        .expand(&message, secret.as_mut())
+        .map_err(|_| Error::GenericError)?;
+
+    Ok(secret)
+}
+
+/// User interactions required to perform a batch of private key operations.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Interactions {
+    /// Number of times the user will be asked for the PIN
+    pub pin_entries: usize,
+    /// Number of times the user will need to touch the YubiKey
+    pub touches: usize,
+}
+
+impl Interactions {
+    /// Does the batch require any interaction from the user at all?
+    pub fn is_none(&self) -> bool {
+        self.pin_entries == 0 && self.touches == 0
+    }
+}
+
+/// Estimate the user interactions needed to perform one private key
+/// operation with each of the given slots, in order.
+///
+/// This is computed from the PIN and touch policies of the slots before any
+/// operation is started, so that interactive tools can warn the user up front
+/// (e.g. "this will need 5 touches"). [`PinPolicy::Once`] only counts a PIN
+/// entry if the PIN hasn't already been verified in this session, and
+/// [`TouchPolicy::Cached`] counts one touch per slot, assuming the batch
+/// completes within the YubiKey's 15 second touch cache.
+pub fn estimate_interactions(yubikey: &mut YubiKey, slots: &[SlotId]) -> Result<Interactions> {
+    let mut interactions = Interactions::default();
+    let mut pin_verified = yubikey.is_pin_verified();
+    let mut touched = Vec::new();
+
+    for &slot in slots {
+        let (pin_policy, touch_policy) = yubikey.slot_policy(slot)?;
+
+        match pin_policy {
+            PinPolicy::Always => interactions.pin_entries += 1,
+            PinPolicy::Default | PinPolicy::Once if !pin_verified => {
+                interactions.pin_entries += 1;
+                pin_verified = true;
+            }
+            _ => (),
+        }
 
-    // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
-    txn.authenticated_command(input, algorithm, key, true)
+        match touch_policy {
+            TouchPolicy::Always => interactions.touches += 1,
+            TouchPolicy::Cached if !touched.contains(&slot) => {
+                interactions.touches += 1;
+                touched.push(slot);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(interactions)
 }
 
 /// Read metadata
 pub fn metadata(yubikey: &mut YubiKey, slot: SlotId) -> Result<SlotMetadata> {
-    let txn = yubikey.begin_transaction()?;
     let templ = [0, Ins::GetMetadata.code(), 0, slot.into()];
 
-    let response = txn.transfer_data(&templ, &[], CB_OBJ_MAX)?;
+    let response = yubikey.idempotent(|yubikey| {
+        yubikey
+            .begin_transaction()?
+            .transfer_data(&templ, &[], CB_OBJ_MAX)
+    })?;
 
     match response.status_words() {
         StatusWords::Success => {
@@ -934,12 +2008,16 @@ pub fn metadata(yubikey: &mut YubiKey, slot: SlotId) -> Result<SlotMetadata> {
             SlotMetadata::try_from(buf)
         }
         StatusWords::ReferenceDataNotFoundError => Err(Error::NotFound),
-        // Requires firmware 5.2.3
+        // Requires firmware 5.3.0
         StatusWords::NotSupportedError => Err(Error::NotSupported),
         _ => Err(Error::GenericError),
     }
 }
 
+/// Version of the schema of the canonical JSON encoding of [`SlotMetadata`].
+#[cfg(feature = "serde")]
+pub const SLOT_METADATA_SCHEMA: u32 = 1;
+
 /// Metadata from a slot
 #[derive(Debug)]
 pub struct SlotMetadata {
@@ -1071,6 +2149,130 @@ impl TryFrom<Buffer> for SlotMetadata {
     }
 }
 
+/// Canonical JSON encoding of [`SlotMetadata`].
+///
+/// Fields are in lexicographic order, and values are encoded as on the wire
+/// so that they're independent of how this crate names them.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SlotMetadataJson {
+    algorithm: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pin_policy: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retries: Option<RetriesJson>,
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    touch_policy: Option<u8>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetriesJson {
+    remaining_count: u8,
+    retry_count: u8,
+}
+
+#[cfg(feature = "serde")]
+impl SlotMetadata {
+    /// Encode this metadata as canonical JSON, for inclusion in inventory
+    /// exports and other documents which are signed or compared bytewise.
+    ///
+    /// The encoding is compact, with fields in lexicographic order and
+    /// absent fields left out:
+    ///
+    /// - `algorithm`: algorithm identifier, as on the wire
+    /// - `default`: whether the PIN, PUK or management key has its default
+    ///   value
+    /// - `origin`: 1 if the key was generated on the YubiKey, 2 if imported
+    /// - `pin_policy`, `touch_policy`: policies, as on the wire
+    /// - `public_key`: base64-encoded DER `SubjectPublicKeyInfo`
+    /// - `retries`: `remaining_count` and `retry_count` of the PIN or PUK
+    /// - `schema`: [`SLOT_METADATA_SCHEMA`]
+    pub fn to_json(&self) -> Result<String> {
+        use base64ct::{Base64, Encoding};
+        use x509_cert::der::Encode;
+
+        let json = SlotMetadataJson {
+            algorithm: self.algorithm.into(),
+            default: self.default,
+            origin: self.origin.map(u8::from),
+            pin_policy: self.policy.map(|(pin, _)| pin.into()),
+            public_key: self
+                .public
+                .as_ref()
+                .map(|public| public.to_der().map(|der| Base64::encode_string(&der)))
+                .transpose()?,
+            retries: self.retries.as_ref().map(|retries| RetriesJson {
+                remaining_count: retries.remaining_count,
+                retry_count: retries.retry_count,
+            }),
+            schema: SLOT_METADATA_SCHEMA,
+            touch_policy: self.policy.map(|(_, touch)| touch.into()),
+        };
+
+        serde_json::to_string(&json).map_err(|e| {
+            error!("couldn't serialize slot metadata: {}", e);
+            Error::GenericError
+        })
+    }
+
+    /// Decode metadata from the JSON encoding produced by
+    /// [`SlotMetadata::to_json`].
+    ///
+    /// Returns [`Error::ParseError`] if the JSON is malformed or uses a
+    /// different schema version.
+    pub fn from_json(json: &str) -> Result<Self> {
+        use base64ct::{Base64, Encoding};
+
+        let json: SlotMetadataJson = serde_json::from_str(json).map_err(|e| {
+            error!("couldn't parse slot metadata: {}", e);
+            Error::ParseError
+        })?;
+
+        if json.schema != SLOT_METADATA_SCHEMA {
+            error!("unsupported slot metadata schema: {}", json.schema);
+            return Err(Error::ParseError);
+        }
+
+        let policy = match (json.pin_policy, json.touch_policy) {
+            (Some(pin), Some(touch)) => {
+                Some((PinPolicy::try_from(pin)?, TouchPolicy::try_from(touch)?))
+            }
+            (None, None) => None,
+            _ => return Err(Error::ParseError),
+        };
+
+        let public = json
+            .public_key
+            .map(|public| -> Result<_> {
+                let der = Base64::decode_vec(&public).map_err(|_| Error::ParseError)?;
+                Ok(SubjectPublicKeyInfoOwned::from_der(&der)?)
+            })
+            .transpose()?;
+
+        Ok(Self {
+            algorithm: ManagementAlgorithmId::try_from(json.algorithm)?,
+            policy,
+            origin: json.origin.map(Origin::try_from).transpose()?,
+            public,
+            default: json.default,
+            retries: json.retries.map(|retries| Retries {
+                retry_count: retries.retry_count,
+                remaining_count: retries.remaining_count,
+            }),
+        })
+    }
+}
+
 /// The number of retries used and remaining.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Retries {
@@ -1080,6 +2282,74 @@ pub struct Retries {
     pub remaining_count: u8,
 }
 
+/// Metadata of the PIN or PUK, as returned by [`YubiKey::pin_metadata`] and
+/// [`YubiKey::puk_metadata`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinMetadata {
+    /// Whether the PIN or PUK is still the factory default
+    pub default: bool,
+    /// Number of attempts allowed before it gets blocked
+    pub total_retries: u8,
+    /// Remaining attempts
+    pub remaining_retries: u8,
+}
+
+impl TryFrom<SlotMetadata> for PinMetadata {
+    type Error = Error;
+
+    fn try_from(metadata: SlotMetadata) -> Result<Self> {
+        match metadata {
+            SlotMetadata {
+                algorithm: ManagementAlgorithmId::PinPuk,
+                default: Some(default),
+                retries: Some(retries),
+                ..
+            } => Ok(Self {
+                default,
+                total_retries: retries.retry_count,
+                remaining_retries: retries.remaining_count,
+            }),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+/// Metadata of the management key, as returned by
+/// [`YubiKey::mgm_metadata`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MgmMetadata {
+    /// Algorithm of the management key
+    pub algorithm: ManagementAlgorithmId,
+    /// Whether the management key is still the factory default
+    pub default: bool,
+    /// Whether authenticating with the management key requires touch
+    pub touch_policy: TouchPolicy,
+}
+
+impl TryFrom<SlotMetadata> for MgmMetadata {
+    type Error = Error;
+
+    fn try_from(metadata: SlotMetadata) -> Result<Self> {
+        match metadata {
+            SlotMetadata {
+                algorithm:
+                    algorithm @ (ManagementAlgorithmId::ThreeDes
+                    | ManagementAlgorithmId::Aes128
+                    | ManagementAlgorithmId::Aes192
+                    | ManagementAlgorithmId::Aes256),
+                default: Some(default),
+                policy: Some((_, touch_policy)),
+                ..
+            } => Ok(Self {
+                algorithm,
+                default,
+                touch_policy,
+            }),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
 /// Origin of a slot
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Origin {
@@ -1089,6 +2359,15 @@ pub enum Origin {
     Generated,
 }
 
+impl From<Origin> for u8 {
+    fn from(origin: Origin) -> u8 {
+        match origin {
+            Origin::Generated => 1,
+            Origin::Imported => 2,
+        }
+    }
+}
+
 impl TryFrom<u8> for Origin {
     type Error = Error;
 
@@ -1110,7 +2389,10 @@ fn read_public_key(
     //
     //    0x7f 0x49 -> Application | Constructed | 0x49
     match algorithm {
-        AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => {
+        AlgorithmId::Rsa1024
+        | AlgorithmId::Rsa2048
+        | AlgorithmId::Rsa3072
+        | AlgorithmId::Rsa4096 => {
             // It appears that the inner application-specific value returned by the
             // YubiKey is constructed such that RSA pubkeys can be parsed in two ways:
             //
@@ -1209,9 +2491,59 @@ fn read_public_key(
 
             Ok(SubjectPublicKeyInfoOwned::from_der(pubkey.as_bytes())?)
         }
+        AlgorithmId::Ed25519 => {
+            // 2-byte ASN.1 tag, 1-byte length
+            let data = if skip_asn1_tag { &input[3..] } else { input };
+            let (_, tlv) = Tlv::parse(data)?;
+
+            if tlv.tag != TAG_ECC_POINT {
+                error!("failed to parse public key structure");
+                return Err(Error::ParseError);
+            }
+
+            let point = tlv.value.try_into().map_err(|_| {
+                error!("unexpected length");
+                Error::AlgorithmError
+            })?;
+
+            let pubkey = ed25519_dalek::VerifyingKey::from_bytes(point)
+                .map_err(|_| Error::InvalidObject)?
+                .to_public_key_der()
+                .map_err(|_| Error::InvalidObject)?;
+
+            Ok(SubjectPublicKeyInfoOwned::from_der(pubkey.as_bytes())?)
+        }
+        AlgorithmId::X25519 => {
+            // 2-byte ASN.1 tag, 1-byte length
+            let data = if skip_asn1_tag { &input[3..] } else { input };
+            let (_, tlv) = Tlv::parse(data)?;
+
+            if tlv.tag != TAG_ECC_POINT {
+                error!("failed to parse public key structure");
+                return Err(Error::ParseError);
+            }
+
+            if tlv.value.len() != 32 {
+                error!("unexpected length");
+                return Err(Error::AlgorithmError);
+            }
+
+            x25519_public_key(tlv.value)
+        }
     }
 }
 
+/// Encode a raw X25519 public key as a `SubjectPublicKeyInfo`.
+fn x25519_public_key(point: &[u8]) -> Result<SubjectPublicKeyInfoOwned> {
+    Ok(SubjectPublicKeyInfoOwned {
+        algorithm: AlgorithmIdentifierOwned {
+            oid: X25519_OID,
+            parameters: None,
+        },
+        subject_public_key: BitString::from_bytes(point)?,
+    })
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// Algorithms as reported by the metadata command.
 pub enum ManagementAlgorithmId {
@@ -1256,3 +2588,90 @@ impl From<ManagementAlgorithmId> for u8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_metadata() {
+        let pin = SlotMetadata::try_from(Buffer::new(vec![
+            0x01, 0x01, 0xff, 0x05, 0x01, 0x01, 0x06, 0x02, 0x03, 0x02,
+        ]))
+        .expect("parse");
+        assert_eq!(
+            PinMetadata::try_from(pin),
+            Ok(PinMetadata {
+                default: true,
+                total_retries: 3,
+                remaining_retries: 2,
+            })
+        );
+
+        let mgm = SlotMetadata::try_from(Buffer::new(vec![
+            0x01, 0x01, 0x0a, 0x02, 0x02, 0x00, 0x02, 0x05, 0x01, 0x00,
+        ]))
+        .expect("parse");
+        assert_eq!(
+            MgmMetadata::try_from(mgm),
+            Ok(MgmMetadata {
+                algorithm: ManagementAlgorithmId::Aes192,
+                default: false,
+                touch_policy: TouchPolicy::Always,
+            })
+        );
+
+        let mgm = SlotMetadata::try_from(Buffer::new(vec![
+            0x01, 0x01, 0x03, 0x02, 0x02, 0x00, 0x01, 0x05, 0x01, 0x01,
+        ]))
+        .expect("parse");
+        assert_eq!(PinMetadata::try_from(mgm), Err(Error::ParseError));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn slot_metadata_json() {
+        use p256::{elliptic_curve::rand_core::OsRng, pkcs8::EncodePublicKey};
+
+        let pin = SlotMetadata {
+            algorithm: ManagementAlgorithmId::PinPuk,
+            policy: None,
+            origin: None,
+            public: None,
+            default: Some(true),
+            retries: Some(Retries {
+                retry_count: 3,
+                remaining_count: 3,
+            }),
+        };
+        assert_eq!(
+            pin.to_json().expect("encode"),
+            r#"{"algorithm":255,"default":true,"retries":{"remaining_count":3,"retry_count":3},"schema":1}"#
+        );
+
+        let der = p256::SecretKey::random(&mut OsRng)
+            .public_key()
+            .to_public_key_der()
+            .expect("SPKI");
+        let key = SlotMetadata {
+            algorithm: ManagementAlgorithmId::Asymmetric(AlgorithmId::EccP256),
+            policy: Some((PinPolicy::Once, TouchPolicy::Never)),
+            origin: Some(Origin::Generated),
+            public: Some(SubjectPublicKeyInfoOwned::from_der(der.as_bytes()).expect("SPKI")),
+            default: None,
+            retries: None,
+        };
+        let json = key.to_json().expect("encode");
+        let decoded = SlotMetadata::from_json(&json).expect("decode");
+        assert_eq!(decoded.algorithm, key.algorithm);
+        assert_eq!(decoded.policy, key.policy);
+        assert_eq!(decoded.origin, key.origin);
+        assert_eq!(decoded.public, key.public);
+        assert_eq!(decoded.to_json().expect("encode"), json);
+
+        assert_eq!(
+            SlotMetadata::from_json(&json.replace(r#""schema":1"#, r#""schema":2"#)).err(),
+            Some(Error::ParseError)
+        );
+    }
+}
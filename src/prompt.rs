@@ -0,0 +1,300 @@
+//! Prompting the user to approve sensitive operations.
+//!
+//! On shared machines, a YubiKey left plugged in can be used by any process
+//! able to reach the agent holding it. A [`PermissionPrompt`] added to a
+//! [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::add_middleware`](crate::YubiKey::add_middleware) asks a
+//! [`Prompt`] (e.g. an `ssh-askpass`-style dialog) to approve each sensitive
+//! operation, by [class](OperationClass), before it's performed.
+//!
+//! The host agent describes who is asking for each operation by setting the
+//! calling context, and the user can choose to remember their decision for
+//! that context and class of operations:
+//!
+//! ```no_run
+//! use yubikey::{
+//!     prompt::{Decision, PermissionPrompt, PermissionRequest},
+//!     YubiKey,
+//! };
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! let prompt = PermissionPrompt::new(|request: &PermissionRequest<'_>| {
+//!     println!("{} wants to {:?}", request.context, request.operation);
+//!     Decision::AllowForContext
+//! });
+//! yubikey.add_middleware(prompt.clone());
+//!
+//! prompt.set_context("ssh (pid 4242)");
+//! // ... sign with the YubiKey ...
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! Operations are only prompted for once, even if they're retried by
+//! middleware added after the prompt.
+
+use crate::{
+    error::{Error, Result},
+    middleware::{Middleware, Next, Operation, OperationClass},
+};
+use log::{debug, error};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+/// Operation a [`Prompt`] is asked to approve.
+#[derive(Copy, Clone, Debug)]
+pub struct PermissionRequest<'a> {
+    /// Calling context set with [`PermissionPrompt::set_context`]
+    pub context: &'a str,
+
+    /// Class of the operation
+    pub class: OperationClass,
+
+    /// The operation itself
+    pub operation: Operation,
+}
+
+/// Decision of a [`Prompt`] about a [`PermissionRequest`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Allow this operation only
+    Allow,
+
+    /// Deny this operation only
+    Deny,
+
+    /// Allow this operation, and any other operation of the same class in
+    /// the same context
+    AllowForContext,
+
+    /// Deny this operation, and any other operation of the same class in
+    /// the same context
+    DenyForContext,
+}
+
+/// User interface asked to approve sensitive operations.
+pub trait Prompt: Send {
+    /// Ask the user whether the given operation should be performed.
+    fn ask(&mut self, request: &PermissionRequest<'_>) -> Decision;
+}
+
+impl<F> Prompt for F
+where
+    F: FnMut(&PermissionRequest<'_>) -> Decision + Send,
+{
+    fn ask(&mut self, request: &PermissionRequest<'_>) -> Decision {
+        self(request)
+    }
+}
+
+/// Middleware asking a [`Prompt`] to approve sensitive operations.
+///
+/// Clones share their calling context and remembered decisions, so a clone
+/// can be added to a YubiKey while the original is used to set the context.
+#[derive(Clone)]
+pub struct PermissionPrompt {
+    prompt: Arc<Mutex<Box<dyn Prompt>>>,
+    state: Arc<Mutex<State>>,
+}
+
+/// State shared between clones of a [`PermissionPrompt`].
+struct State {
+    /// Classes of operations requiring approval
+    classes: BTreeSet<OperationClass>,
+
+    /// Current calling context
+    context: String,
+
+    /// Decisions remembered by context and class
+    remembered: BTreeMap<(String, OperationClass), bool>,
+}
+
+impl PermissionPrompt {
+    /// Ask the given prompt to approve every sensitive operation.
+    pub fn new(prompt: impl Prompt + 'static) -> Self {
+        Self {
+            prompt: Arc::new(Mutex::new(Box::new(prompt))),
+            state: Arc::new(Mutex::new(State {
+                classes: [
+                    OperationClass::Sign,
+                    OperationClass::Decrypt,
+                    OperationClass::Admin,
+                ]
+                .into_iter()
+                .collect(),
+                context: String::new(),
+                remembered: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Only ask for approval of operations of the given classes, performing
+    /// the others without prompting.
+    pub fn only(self, classes: impl IntoIterator<Item = OperationClass>) -> Self {
+        self.lock().classes = classes.into_iter().collect();
+        self
+    }
+
+    /// Set the context of the operations performed from now on, shown to the
+    /// user and used to remember their decisions (e.g. the name and process
+    /// ID of the client of an agent).
+    pub fn set_context(&self, context: impl Into<String>) {
+        self.lock().context = context.into();
+    }
+
+    /// Get the current calling context.
+    pub fn context(&self) -> String {
+        self.lock().context.clone()
+    }
+
+    /// Forget every remembered decision, so the user is asked again.
+    pub fn forget(&self) {
+        self.lock().remembered.clear();
+    }
+
+    /// Check whether the given operation is approved, asking the prompt if
+    /// no decision was remembered for its context and class.
+    fn approve(&self, operation: Operation) -> bool {
+        let class = match operation.class() {
+            Some(class) => class,
+            None => return true,
+        };
+
+        let context = {
+            let state = self.lock();
+
+            if !state.classes.contains(&class) {
+                return true;
+            }
+
+            if let Some(&allowed) = state.remembered.get(&(state.context.clone(), class)) {
+                debug!(
+                    "{:?} {} by remembered decision for {:?}",
+                    operation,
+                    if allowed { "allowed" } else { "denied" },
+                    state.context
+                );
+                return allowed;
+            }
+
+            state.context.clone()
+        };
+
+        // The prompt may take a while, so the state isn't locked meanwhile
+        let decision = self
+            .prompt
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .ask(&PermissionRequest {
+                context: &context,
+                class,
+                operation,
+            });
+
+        let allowed = matches!(decision, Decision::Allow | Decision::AllowForContext);
+
+        if matches!(
+            decision,
+            Decision::AllowForContext | Decision::DenyForContext
+        ) {
+            self.lock().remembered.insert((context, class), allowed);
+        }
+
+        allowed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Middleware for PermissionPrompt {
+    fn handle(&mut self, operation: Operation, mut next: Next<'_>) -> Result<()> {
+        if !self.approve(operation) {
+            error!("{:?} denied by the user", operation);
+            return Err(Error::OperationDenied);
+        }
+
+        next.run()
+    }
+}
+
+impl fmt::Debug for PermissionPrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+
+        f.debug_struct("PermissionPrompt")
+            .field("classes", &state.classes)
+            .field("context", &state.context)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        middleware,
+        piv::{AlgorithmId, SlotId},
+    };
+
+    const SIGN: Operation = Operation::Sign {
+        slot: SlotId::Signature,
+        algorithm: AlgorithmId::EccP256,
+    };
+
+    #[test]
+    fn prompts_by_context_and_class() {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let prompt = PermissionPrompt::new({
+            let asked = asked.clone();
+            move |request: &PermissionRequest<'_>| {
+                asked
+                    .lock()
+                    .expect("poisoned")
+                    .push((request.context.to_owned(), request.class));
+
+                match request.context {
+                    "agent" => Decision::AllowForContext,
+                    "intruder" => Decision::DenyForContext,
+                    _ => Decision::Deny,
+                }
+            }
+        })
+        .only([OperationClass::Sign]);
+
+        let mut chain: Vec<Box<dyn Middleware>> = vec![Box::new(prompt.clone())];
+        let mut performed = 0;
+        let mut run = |operation| {
+            middleware::dispatch(&mut chain, operation, &mut || {
+                performed += 1;
+                Ok(())
+            })
+        };
+
+        prompt.set_context("agent");
+        assert!(run(SIGN).is_ok());
+        assert!(run(SIGN).is_ok());
+        assert!(run(Operation::Reset).is_ok());
+
+        prompt.set_context("intruder");
+        assert_eq!(run(SIGN), Err(Error::OperationDenied));
+        assert_eq!(run(SIGN), Err(Error::OperationDenied));
+        assert!(run(Operation::VerifyPin).is_ok());
+
+        prompt.forget();
+        assert_eq!(run(SIGN), Err(Error::OperationDenied));
+
+        assert_eq!(performed, 4);
+        assert_eq!(
+            *asked.lock().expect("poisoned"),
+            [
+                ("agent".to_owned(), OperationClass::Sign),
+                ("intruder".to_owned(), OperationClass::Sign),
+                ("intruder".to_owned(), OperationClass::Sign),
+            ]
+        );
+    }
+}
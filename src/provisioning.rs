@@ -0,0 +1,462 @@
+//! Tamper-evident provisioning history, stored on the YubiKey.
+//!
+//! When administrative keys are shared, working out who changed what on a
+//! YubiKey after an incident is guesswork. A [`ProvisioningLog`] records
+//! provisioning events (who, when, which slot, what was done) in a data
+//! object on the YubiKey itself, appended to by [`ProvisioningLog::append`].
+//!
+//! Entries are hash-chained: each one carries a truncated SHA-256 digest of
+//! its contents and of the previous entry's digest, so editing, reordering or
+//! removing entries is detected by [`ProvisioningLog::verify`]. As anyone
+//! holding the management key could rewrite the whole chain consistently,
+//! record the [head](ProvisioningLog::head) of the log outside the YubiKey
+//! (e.g. in an audit system) to detect that too.
+//!
+//! The log is stored in Yubico's vendor-specific object range. Once it fills
+//! the object, the oldest entries are dropped, the digest of the last dropped
+//! entry being kept as the base of the chain.
+//!
+//! [`migrate_algorithm`] replaces the key in a slot with one of another
+//! algorithm, e.g. to move off RSA-2048 to ECC.
+//!
+//! Provisioning can be rehearsed against a live YubiKey in
+//! [dry-run mode](YubiKey::set_dry_run): only reads are performed, and the
+//! generations, writes and resets which would have happened are listed by
+//! [`YubiKey::planned_operations`] for review.
+
+use crate::{
+    certificate::{CertInfo, Certificate},
+    clock,
+    consts::{CB_OBJ_MAX, OBJ_PROVISIONING_LOG},
+    error::{Error, Result},
+    middleware::Operation,
+    piv::{self, AlgorithmId, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    serialization::*,
+    yubikey::YubiKey,
+};
+use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+const TAG_LOG: u16 = 0x80;
+const TAG_ENTRY: u16 = 0x81;
+const TAG_BASE: u16 = 0x82;
+
+/// Length of the truncated SHA-256 digests chaining entries.
+pub const DIGEST_LEN: usize = 16;
+
+/// Length of an entry's value before the actor: timestamp, action, slot and
+/// digest.
+const ENTRY_HEADER_LEN: usize = 8 + 1 + 1 + DIGEST_LEN;
+
+/// Provisioning action recorded in a [`ProvisioningLog`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProvisioningAction {
+    /// A key was generated
+    GenerateKey,
+
+    /// A key was imported
+    ImportKey,
+
+    /// A certificate was written
+    WriteCertificate,
+
+    /// A certificate was deleted
+    DeleteCertificate,
+
+    /// The management key was changed
+    ChangeManagementKey,
+
+    /// The PIV application was reset
+    Reset,
+
+    /// Application-defined action
+    Other(u8),
+}
+
+impl ProvisioningAction {
+    /// Get the code of this action.
+    pub fn code(self) -> u8 {
+        match self {
+            ProvisioningAction::GenerateKey => 0x01,
+            ProvisioningAction::ImportKey => 0x02,
+            ProvisioningAction::WriteCertificate => 0x03,
+            ProvisioningAction::DeleteCertificate => 0x04,
+            ProvisioningAction::ChangeManagementKey => 0x05,
+            ProvisioningAction::Reset => 0x06,
+            ProvisioningAction::Other(code) => code,
+        }
+    }
+}
+
+impl From<u8> for ProvisioningAction {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => ProvisioningAction::GenerateKey,
+            0x02 => ProvisioningAction::ImportKey,
+            0x03 => ProvisioningAction::WriteCertificate,
+            0x04 => ProvisioningAction::DeleteCertificate,
+            0x05 => ProvisioningAction::ChangeManagementKey,
+            0x06 => ProvisioningAction::Reset,
+            code => ProvisioningAction::Other(code),
+        }
+    }
+}
+
+/// Entry of a [`ProvisioningLog`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogEntry {
+    timestamp: u64,
+    action: ProvisioningAction,
+    slot: Option<SlotId>,
+    actor: String,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl LogEntry {
+    /// Get the time of the event.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp)
+    }
+
+    /// Get the action performed.
+    pub fn action(&self) -> ProvisioningAction {
+        self.action
+    }
+
+    /// Get the slot the action was performed on, if any.
+    pub fn slot(&self) -> Option<SlotId> {
+        self.slot
+    }
+
+    /// Get who performed the action.
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// Get the digest chaining this entry to the previous ones.
+    pub fn digest(&self) -> [u8; DIGEST_LEN] {
+        self.digest
+    }
+
+    /// Compute the digest of this entry, following the given digest.
+    fn chain(&self, previous: &[u8; DIGEST_LEN]) -> [u8; DIGEST_LEN] {
+        let digest = Sha256::new()
+            .chain_update(previous)
+            .chain_update(self.timestamp.to_be_bytes())
+            .chain_update([self.action.code(), self.slot.map_or(0, u8::from)])
+            .chain_update(self.actor.as_bytes())
+            .finalize();
+
+        let mut truncated = [0u8; DIGEST_LEN];
+        truncated.copy_from_slice(&digest[..DIGEST_LEN]);
+        truncated
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(ENTRY_HEADER_LEN + self.actor.len());
+        value.extend_from_slice(&self.timestamp.to_be_bytes());
+        value.push(self.action.code());
+        value.push(self.slot.map_or(0, u8::from));
+        value.extend_from_slice(&self.digest);
+        value.extend_from_slice(self.actor.as_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Self> {
+        if value.len() < ENTRY_HEADER_LEN {
+            error!("provisioning log entry is too short");
+            return Err(Error::InvalidObject);
+        }
+
+        let (header, actor) = value.split_at(ENTRY_HEADER_LEN);
+        let (timestamp, rest) = header.split_at(8);
+
+        Ok(Self {
+            timestamp: u64::from_be_bytes(timestamp.try_into()?),
+            action: rest[0].into(),
+            slot: match rest[1] {
+                0 => None,
+                slot => Some(SlotId::try_from(slot)?),
+            },
+            actor: String::from_utf8(actor.to_vec()).map_err(|_| Error::InvalidObject)?,
+            digest: rest[2..].try_into()?,
+        })
+    }
+}
+
+/// Hash-chained provisioning history, stored in a data object on the
+/// YubiKey: see the [module documentation](self).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProvisioningLog {
+    base: [u8; DIGEST_LEN],
+    entries: Vec<LogEntry>,
+}
+
+impl ProvisioningLog {
+    /// Maximum length of an actor in bytes (UTF-8).
+    pub const MAX_ACTOR_LEN: usize = 64;
+
+    /// Read the provisioning log stored on the YubiKey.
+    ///
+    /// Returns an empty log if none has been written. The log isn't verified:
+    /// see [`ProvisioningLog::verify`].
+    pub fn read(yubikey: &mut YubiKey) -> Result<Self> {
+        let response = match yubikey.idempotent(|yubikey| {
+            yubikey
+                .begin_transaction()?
+                .fetch_object(OBJ_PROVISIONING_LOG)
+        }) {
+            Ok(response) => response,
+            Err(Error::NotFound) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        if response.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let (_, tlv) = Tlv::parse(&response)?;
+        if tlv.tag != TAG_LOG {
+            error!("unexpected tag in provisioning log object: {:02x}", tlv.tag);
+            return Err(Error::InvalidObject);
+        }
+
+        let mut log = Self::default();
+        let mut buffer = tlv.value;
+
+        while !buffer.is_empty() {
+            let (rest, tlv) = Tlv::parse(buffer)?;
+
+            match tlv.tag {
+                TAG_BASE => log.base = tlv.value.try_into()?,
+                TAG_ENTRY => log.entries.push(LogEntry::decode(tlv.value)?),
+                _ => (),
+            }
+
+            buffer = rest;
+        }
+
+        Ok(log)
+    }
+
+    /// Record an action performed by the given actor (e.g. an operator's
+    /// name, or the host running the provisioning) on the YubiKey, at the
+    /// current time of its [`Clock`](crate::clock::Clock).
+    ///
+    /// The stored log is verified first, and isn't extended if it has been
+    /// tampered with. The management key must be authenticated.
+    pub fn append(
+        yubikey: &mut YubiKey,
+        actor: &str,
+        action: ProvisioningAction,
+        slot: Option<SlotId>,
+    ) -> Result<LogEntry> {
+        if actor.len() > Self::MAX_ACTOR_LEN {
+            error!(
+                "provisioning log actor is too long: {} bytes (max {})",
+                actor.len(),
+                Self::MAX_ACTOR_LEN
+            );
+            return Err(Error::SizeError);
+        }
+
+        let mut log = Self::read(yubikey)?;
+        log.verify()?;
+
+        let mut entry = LogEntry {
+            timestamp: clock::unix_time(yubikey.clock())?.as_secs(),
+            action,
+            slot,
+            actor: actor.into(),
+            digest: [0; DIGEST_LEN],
+        };
+        entry.digest = entry.chain(&log.head());
+        log.entries.push(entry.clone());
+
+        let encoded = loop {
+            match log.encode() {
+                Ok(encoded) => break encoded,
+                Err(Error::SizeError) if log.entries.len() > 1 => {
+                    let dropped = log.entries.remove(0);
+                    log.base = dropped.digest;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let operation = Operation::SaveObject {
+            object_id: OBJ_PROVISIONING_LOG,
+        };
+        yubikey.run_write(
+            operation,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+                txn.save_object(OBJ_PROVISIONING_LOG, &encoded)
+            },
+        )?;
+        Ok(entry)
+    }
+
+    /// Get the entries of this log, oldest first.
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    /// Get the digest of the latest entry, which covers the whole log.
+    pub fn head(&self) -> [u8; DIGEST_LEN] {
+        self.entries.last().map_or(self.base, |entry| entry.digest)
+    }
+
+    /// Check the entries of this log are correctly chained.
+    ///
+    /// Returns [`Error::InvalidObject`] if they aren't, i.e. the log has been
+    /// tampered with.
+    pub fn verify(&self) -> Result<()> {
+        let mut previous = self.base;
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            if entry.chain(&previous) != entry.digest {
+                error!("provisioning log entry {} has been tampered with", index);
+                return Err(Error::InvalidObject);
+            }
+
+            previous = entry.digest;
+        }
+
+        Ok(())
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut entries = vec![0u8; CB_OBJ_MAX];
+        let mut len = Tlv::write(&mut entries, TAG_BASE, &self.base)?;
+
+        for entry in &self.entries {
+            len += Tlv::write(&mut entries[len..], TAG_ENTRY, &entry.encode())?;
+        }
+
+        let mut buf = vec![0u8; CB_OBJ_MAX];
+        let len = Tlv::write(&mut buf, TAG_LOG, &entries[..len])?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+/// Key and certificate of a slot migrated with [`migrate_algorithm`].
+#[derive(Clone, Debug)]
+pub struct Migration {
+    /// Public key of the new key
+    pub public_key: SubjectPublicKeyInfoOwned,
+
+    /// Certificate of the new key, written to the slot
+    pub certificate: Certificate,
+
+    /// Certificate previously stored in the slot, if any (e.g. to revoke it)
+    pub previous_certificate: Option<Certificate>,
+}
+
+/// Replace the key in the given slot with a newly generated key of the given
+/// algorithm, keeping its PIN and touch policies where they can be read from
+/// the slot metadata (firmware 5.3 or newer).
+///
+/// `issue_certificate` obtains the certificate of the new key, e.g. by having
+/// a CA sign a request for it, or by issuing a self-signed one. It's given the
+/// new public key and the certificate previously stored in the slot (e.g. to
+/// reuse its subject), and the certificate it returns is written to the slot.
+/// Slot labels and other data objects are left untouched.
+///
+/// WARNING: this is a destructive operation which will destroy the replaced
+/// key! If `issue_certificate` fails, the previous certificate is left in
+/// the slot although it no longer matches its key.
+///
+/// The management key must be authenticated. Returns [`Error::KeyError`] if
+/// the issued certificate isn't for the new key.
+pub fn migrate_algorithm<F>(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    algorithm: AlgorithmId,
+    issue_certificate: F,
+) -> Result<Migration>
+where
+    F: FnOnce(
+        &mut YubiKey,
+        &SubjectPublicKeyInfoOwned,
+        Option<&Certificate>,
+    ) -> Result<Certificate>,
+{
+    let (pin_policy, touch_policy) = match piv::metadata(yubikey, slot) {
+        Ok(metadata) => metadata
+            .policy
+            .unwrap_or((PinPolicy::Default, TouchPolicy::Default)),
+        Err(e) => {
+            debug!("using default policies for slot {}: {}", slot, e);
+            (PinPolicy::Default, TouchPolicy::Default)
+        }
+    };
+
+    let previous_certificate = match Certificate::read(yubikey, slot) {
+        Ok(certificate) => Some(certificate),
+        Err(Error::NotFound) | Err(Error::InvalidObject) => None,
+        Err(e) => return Err(e),
+    };
+
+    info!("migrating key in slot {} to {:?}", slot, algorithm);
+
+    let public_key = piv::generate(yubikey, slot, algorithm, pin_policy, touch_policy)?;
+    let certificate = issue_certificate(yubikey, &public_key, previous_certificate.as_ref())?;
+
+    if certificate.cert.tbs_certificate.subject_public_key_info != public_key {
+        error!("certificate issued for slot {} isn't for its new key", slot);
+        return Err(Error::KeyError);
+    }
+
+    certificate.write(yubikey, slot, CertInfo::Uncompressed)?;
+
+    Ok(Migration {
+        public_key,
+        certificate,
+        previous_certificate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(previous: &[u8; DIGEST_LEN], actor: &str) -> LogEntry {
+        let mut entry = LogEntry {
+            timestamp: 1_700_000_000,
+            action: ProvisioningAction::GenerateKey,
+            slot: Some(SlotId::Signature),
+            actor: actor.into(),
+            digest: [0; DIGEST_LEN],
+        };
+        entry.digest = entry.chain(previous);
+        entry
+    }
+
+    #[test]
+    fn tamper_detection() {
+        let first = entry(&[0; DIGEST_LEN], "alice");
+        let second = entry(&first.digest, "bob");
+        let mut log = ProvisioningLog {
+            base: [0; DIGEST_LEN],
+            entries: vec![first, second],
+        };
+        assert!(log.verify().is_ok());
+        assert_eq!(log.head(), log.entries[1].digest);
+
+        let decoded = LogEntry::decode(&log.entries[1].encode()).expect("decode");
+        assert_eq!(decoded, log.entries[1]);
+
+        log.entries[0].actor = "mallory".into();
+        assert_eq!(log.verify(), Err(Error::InvalidObject));
+
+        log.entries.remove(0);
+        assert_eq!(log.verify(), Err(Error::InvalidObject));
+    }
+}
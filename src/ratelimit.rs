@@ -0,0 +1,213 @@
+//! Rate limits protecting YubiKeys from runaway callers.
+//!
+//! A bug retrying PIN verification in a loop can lock the PIN (and, once the
+//! PUK is exhausted too, the PIV application) in a fraction of a second, and
+//! one issuing signatures in a tight loop needlessly wears the hardware.
+//!
+//! [`RateLimits`] set on a [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::set_rate_limits`](crate::YubiKey::set_rate_limits) refuse
+//! such operations with [`Error::RateLimited`] before they reach the card.
+//! Limits apply per `YubiKey` handle, and are measured with its
+//! [`Clock`](crate::clock::Clock).
+
+use crate::error::{Error, Result};
+use log::error;
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+/// Limits on the rate of PIN verification attempts and signatures.
+///
+/// The default limits are [`RateLimits::unlimited`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RateLimits {
+    /// Maximum number of PIN verification attempts per period
+    pin_attempts: Option<Window>,
+
+    /// Maximum number of consecutive failed PIN verifications
+    pin_failures: Option<u32>,
+
+    /// Maximum number of signatures per period
+    signatures: Option<Window>,
+}
+
+impl RateLimits {
+    /// Limits which allow any number of operations.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `count` PIN verification attempts in any `period`.
+    pub fn max_pin_attempts(mut self, count: u32, period: Duration) -> Self {
+        self.pin_attempts = Some(Window { count, period });
+        self
+    }
+
+    /// Refuse to verify the PIN after `count` consecutive wrong PINs, until
+    /// the limits are reset with [`YubiKey::set_rate_limits`].
+    ///
+    /// Set this below the number of PIN retries of the YubiKey to ensure a
+    /// misconfigured PIN can't lock it.
+    ///
+    /// [`YubiKey::set_rate_limits`]: crate::YubiKey::set_rate_limits
+    pub fn max_consecutive_pin_failures(mut self, count: u32) -> Self {
+        self.pin_failures = Some(count);
+        self
+    }
+
+    /// Allow at most `count` signatures in any `period`.
+    pub fn max_signatures(mut self, count: u32, period: Duration) -> Self {
+        self.signatures = Some(Window { count, period });
+        self
+    }
+}
+
+/// Maximum number of operations in a sliding window of time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Window {
+    count: u32,
+    period: Duration,
+}
+
+/// State of the [`RateLimits`] of a YubiKey handle.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    pin_attempts: VecDeque<SystemTime>,
+    pin_failures: u32,
+    signatures: VecDeque<SystemTime>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter enforcing the given limits.
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Get the limits enforced by this rate limiter.
+    pub(crate) fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Record a PIN verification attempt at the given time, or return
+    /// [`Error::RateLimited`] if it exceeds the limits.
+    pub(crate) fn pin_attempt(&mut self, now: SystemTime) -> Result<()> {
+        if let Some(max) = self.limits.pin_failures {
+            if self.pin_failures >= max {
+                error!(
+                    "PIN verification refused after {} consecutive failures",
+                    self.pin_failures
+                );
+                return Err(Error::RateLimited { retry_after: None });
+            }
+        }
+
+        admit(self.limits.pin_attempts, &mut self.pin_attempts, now).map_err(|e| {
+            error!("PIN verification attempts rate limited");
+            e
+        })
+    }
+
+    /// Record the outcome of a PIN verification.
+    pub(crate) fn pin_result(&mut self, result: &Result<()>) {
+        match result {
+            Ok(()) => self.pin_failures = 0,
+            Err(Error::WrongPin { .. }) => self.pin_failures += 1,
+            Err(_) => (),
+        }
+    }
+
+    /// Record a signature at the given time, or return
+    /// [`Error::RateLimited`] if it exceeds the limits.
+    pub(crate) fn signature(&mut self, now: SystemTime) -> Result<()> {
+        admit(self.limits.signatures, &mut self.signatures, now).map_err(|e| {
+            error!("signatures rate limited");
+            e
+        })
+    }
+}
+
+/// Admit an operation at the given time into the window of past operations,
+/// unless it's full.
+fn admit(
+    window: Option<Window>,
+    history: &mut VecDeque<SystemTime>,
+    now: SystemTime,
+) -> Result<()> {
+    let window = match window {
+        Some(window) => window,
+        None => return Ok(()),
+    };
+
+    // Operations timestamped in the future (if the clock went backwards) are
+    // kept until they fall out of the window
+    let elapsed = |time: &SystemTime| now.duration_since(*time).unwrap_or_default();
+
+    while history
+        .front()
+        .map_or(false, |time| elapsed(time) >= window.period)
+    {
+        history.pop_front();
+    }
+
+    if history.len() >= window.count as usize {
+        let retry_after = history
+            .front()
+            .map_or(window.period, |time| window.period - elapsed(time));
+        return Err(Error::RateLimited {
+            retry_after: Some(retry_after),
+        });
+    }
+
+    history.push_back(now);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn signature_window() {
+        let limits = RateLimits::unlimited().max_signatures(2, Duration::from_secs(10));
+        let mut limiter = RateLimiter::new(limits);
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert!(limiter.signature(t(0)).is_ok());
+        assert!(limiter.signature(t(4)).is_ok());
+        assert_eq!(
+            limiter.signature(t(6)),
+            Err(Error::RateLimited {
+                retry_after: Some(Duration::from_secs(4))
+            })
+        );
+        assert!(limiter.signature(t(10)).is_ok());
+        assert!(limiter.signature(t(13)).is_err());
+        assert!(limiter.signature(t(14)).is_ok());
+    }
+
+    #[test]
+    fn consecutive_pin_failures() {
+        let limits = RateLimits::unlimited().max_consecutive_pin_failures(2);
+        let mut limiter = RateLimiter::new(limits);
+        let wrong = Err(Error::WrongPin { tries: 2 });
+
+        assert!(limiter.pin_attempt(UNIX_EPOCH).is_ok());
+        limiter.pin_result(&wrong);
+        assert!(limiter.pin_attempt(UNIX_EPOCH).is_ok());
+        limiter.pin_result(&Ok(()));
+        assert!(limiter.pin_attempt(UNIX_EPOCH).is_ok());
+        limiter.pin_result(&wrong);
+        assert!(limiter.pin_attempt(UNIX_EPOCH).is_ok());
+        limiter.pin_result(&wrong);
+        assert_eq!(
+            limiter.pin_attempt(UNIX_EPOCH),
+            Err(Error::RateLimited { retry_after: None })
+        );
+    }
+}
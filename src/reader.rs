@@ -1,11 +1,16 @@
 //! Support for enumerating available PC/SC card readers.
 
-use crate::{Error, Result, YubiKey};
+use crate::{
+    diagnostics, transport::PcscTransport, Error, FormFactor, Result, Serial, Version, YubiKey,
+};
+use log::{debug, error, info};
 use std::{
     borrow::Cow,
-    ffi::CStr,
+    collections::VecDeque,
+    ffi::{CStr, CString},
     fmt,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 /// Iterator over connected readers
@@ -29,8 +34,19 @@ impl fmt::Debug for Context {
 impl Context {
     /// Open a PC/SC context, which can be used to enumerate available PC/SC
     /// readers (which can be used to connect to YubiKeys).
+    ///
+    /// Failures are logged along with their likely causes: see
+    /// [`diagnostics::diagnose`].
     pub fn open() -> Result<Self> {
-        let ctx = pcsc::Context::establish(pcsc::Scope::System)?;
+        let ctx = pcsc::Context::establish(pcsc::Scope::System).map_err(|e| {
+            let e = Error::from(e);
+
+            for diagnosis in diagnostics::diagnose(&e) {
+                error!("{}: {}", diagnosis, diagnosis.remediation());
+            }
+
+            e
+        })?;
         let reader_names = vec![0u8; ctx.list_readers_len()?];
         Ok(Self {
             ctx: Arc::new(Mutex::new(ctx)),
@@ -60,6 +76,255 @@ impl Context {
 
         Ok(readers.into_iter())
     }
+
+    /// Watch for cards being inserted into or removed from readers: see
+    /// [`watch`].
+    pub fn watch(&self) -> Result<Watch> {
+        let ctx = self.ctx.lock().map_err(|_| Error::GenericError)?.clone();
+        Watch::new(ctx)
+    }
+}
+
+/// Watch for YubiKeys (or other cards) being inserted or removed, returning
+/// a blocking iterator over [`ReaderEvent`]s.
+///
+/// Cards already present when watching starts are reported as inserted
+/// first. USB YubiKeys appear as a reader with a card already present, and
+/// are reported as inserted when plugged in, and removed when unplugged.
+///
+/// ```no_run
+/// use yubikey::reader::{self, ReaderEvent};
+///
+/// for event in reader::watch()? {
+///     match event? {
+///         ReaderEvent::Inserted { reader, serial } => {
+///             println!("inserted into {}: {:?}", reader, serial)
+///         }
+///         ReaderEvent::Removed { reader } => println!("removed from {}", reader),
+///         _ => (),
+///     }
+/// }
+/// # Ok::<(), yubikey::Error>(())
+/// ```
+pub fn watch() -> Result<Watch> {
+    Context::open()?.watch()
+}
+
+/// Insertion or removal of a card.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ReaderEvent {
+    /// A card was inserted into a reader (or a reader with a card in it was
+    /// connected, as happens when plugging in a USB YubiKey)
+    Inserted {
+        /// Name of the reader
+        reader: String,
+
+        /// Serial number of the YubiKey, if the card is a YubiKey and its
+        /// serial number could be read
+        serial: Option<Serial>,
+    },
+
+    /// A card was removed from a reader (or the reader was disconnected)
+    Removed {
+        /// Name of the reader
+        reader: String,
+    },
+}
+
+/// Blocking iterator over [`ReaderEvent`]s, created with [`watch`].
+///
+/// Iteration ends once watching is cancelled with a [`WatchCanceller`].
+pub struct Watch {
+    /// PC/SC context used to wait for events
+    ctx: pcsc::Context,
+
+    /// States of the watched readers, preceded by the PnP notification
+    /// pseudo-reader which reports readers being connected or disconnected
+    states: Vec<pcsc::ReaderState>,
+
+    /// Events reported by PC/SC but not returned yet
+    pending: VecDeque<ReaderEvent>,
+
+    /// Has watching been cancelled?
+    cancelled: bool,
+}
+
+impl Watch {
+    fn new(ctx: pcsc::Context) -> Result<Self> {
+        let mut watch = Self {
+            ctx,
+            states: vec![pcsc::ReaderState::new(
+                pcsc::PNP_NOTIFICATION(),
+                pcsc::State::UNAWARE,
+            )],
+            pending: VecDeque::new(),
+            cancelled: false,
+        };
+
+        // Learn the current state, reporting cards already present
+        watch.update(Some(Duration::ZERO))?;
+        Ok(watch)
+    }
+
+    /// Get a handle which can cancel watching from another thread.
+    pub fn canceller(&self) -> WatchCanceller {
+        WatchCanceller(self.ctx.clone())
+    }
+
+    /// Wait up to `timeout` for the next event, returning `None` if there
+    /// was none (or if watching has been cancelled).
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ReaderEvent>> {
+        if self.pending.is_empty() && !self.cancelled {
+            self.update(Some(timeout))?;
+        }
+
+        Ok(self.pending.pop_front())
+    }
+
+    /// Wait up to `timeout` (or forever if `None`) for readers to change
+    /// state, queuing the resulting events.
+    fn update(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.sync_readers()?;
+
+        match self.ctx.get_status_change(timeout, &mut self.states) {
+            Ok(()) => (),
+            Err(pcsc::Error::Timeout) => return Ok(()),
+            Err(pcsc::Error::Cancelled) => {
+                self.cancelled = true;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        for state in &mut self.states[1..] {
+            let was_present = state.current_state().contains(pcsc::State::PRESENT);
+            let event = state.event_state();
+            let present = event.contains(pcsc::State::PRESENT)
+                && !event.intersects(pcsc::State::UNKNOWN | pcsc::State::IGNORE);
+
+            let reader = state.name().to_string_lossy().into_owned();
+
+            if present && !was_present {
+                let serial = read_serial(&self.ctx, state);
+                self.pending
+                    .push_back(ReaderEvent::Inserted { reader, serial });
+            } else if was_present && !present {
+                self.pending.push_back(ReaderEvent::Removed { reader });
+            }
+
+            state.sync_current_state();
+        }
+
+        self.states[0].sync_current_state();
+        Ok(())
+    }
+
+    /// Watch the readers currently connected, and stop watching the readers
+    /// which were disconnected.
+    fn sync_readers(&mut self) -> Result<()> {
+        let names = match self.ctx.list_readers_owned() {
+            Ok(names) => names,
+            Err(pcsc::Error::NoReadersAvailable) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = vec![];
+
+        self.states.retain(|state| {
+            let name = state.name();
+            let keep = name == pcsc::PNP_NOTIFICATION() || names.iter().any(|n| **n == *name);
+
+            if !keep && state.current_state().contains(pcsc::State::PRESENT) {
+                removed.push(name.to_string_lossy().into_owned());
+            }
+
+            keep
+        });
+
+        self.pending.extend(
+            removed
+                .into_iter()
+                .map(|reader| ReaderEvent::Removed { reader }),
+        );
+
+        for name in names {
+            if !self.states.iter().any(|state| state.name() == &*name) {
+                self.states
+                    .push(pcsc::ReaderState::new(name, pcsc::State::UNAWARE));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<ReaderEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.cancelled {
+            if let Err(e) = self.update(None) {
+                return Some(Err(e));
+            }
+        }
+
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch")
+            .field("pending", &self.pending)
+            .field("cancelled", &self.cancelled)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Handle cancelling a [`Watch`], e.g. from another thread.
+#[derive(Clone)]
+pub struct WatchCanceller(pcsc::Context);
+
+impl WatchCanceller {
+    /// Cancel watching: a blocked [`Watch`] returns, and iteration ends.
+    pub fn cancel(&self) -> Result<()> {
+        Ok(self.0.cancel()?)
+    }
+}
+
+impl fmt::Debug for WatchCanceller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchCanceller").finish_non_exhaustive()
+    }
+}
+
+/// Read the serial number of the YubiKey just inserted into the given
+/// reader, if possible.
+fn read_serial(ctx: &pcsc::Context, state: &pcsc::ReaderState) -> Option<Serial> {
+    if !is_yubikey_atr(state.atr()) || state.event_state().contains(pcsc::State::EXCLUSIVE) {
+        return None;
+    }
+
+    let name = CString::from(state.name());
+    let reader = Reader::new(&name, Arc::new(Mutex::new(ctx.clone())));
+
+    match reader.open() {
+        Ok(yubikey) => {
+            let serial = yubikey.serial();
+            // Leave the YubiKey as it is for other applications
+            yubikey.leave_card();
+            Some(serial)
+        }
+        Err(e) => {
+            debug!(
+                "couldn't read serial number in reader '{}': {}",
+                reader.name(),
+                e
+            );
+            None
+        }
+    }
 }
 
 /// An individual connected PC/SC card reader.
@@ -85,14 +350,253 @@ impl<'ctx> Reader<'ctx> {
     }
 
     /// Open a connection to this reader, returning a `YubiKey` if successful.
+    ///
+    /// This uses the default [`OpenOptions`].
     pub fn open(&self) -> Result<YubiKey> {
-        self.try_into()
+        self.open_with(&OpenOptions::default())
+    }
+
+    /// Open a connection to this reader with the given [`OpenOptions`],
+    /// returning a `YubiKey` if successful.
+    pub fn open_with(&self, options: &OpenOptions) -> Result<YubiKey> {
+        if options.card_timeout.is_some() || options.check_atr {
+            let atr = self.wait_for_card(options.card_timeout)?;
+
+            if options.check_atr && !is_yubikey_atr(&atr) {
+                debug!(
+                    "skipping reader '{}': ATR {:02x?} doesn't identify a YubiKey",
+                    self.name(),
+                    atr
+                );
+                return Err(Error::NotFound);
+            }
+        }
+
+        let mut yubikey = if options.exclusive {
+            let transport = self
+                .transport_with(pcsc::ShareMode::Exclusive)
+                .map_err(|e| {
+                    error!("error connecting to reader '{}': {}", self.name(), e);
+                    e
+                })?;
+
+            info!("connected to reader exclusively: {}", self.name());
+            YubiKey::open_with_transport(transport, self.name())?
+        } else {
+            self.try_into()?
+        };
+
+        if options.require_fips_mode && !yubikey.is_fips_approved()? {
+            error!(
+                "YubiKey {} isn't operating in FIPS approved mode",
+                yubikey.serial()
+            );
+            return Err(Error::NotFipsApproved);
+        }
+
+        Ok(yubikey)
+    }
+
+    /// Connect to the card in this reader, returning a [`PcscTransport`] to
+    /// it, e.g. to wrap it in a [`Recorder`](crate::replay::Recorder) before
+    /// opening it with [`YubiKey::open_with_transport`].
+    pub fn transport(&self) -> Result<PcscTransport> {
+        self.transport_with(pcsc::ShareMode::Shared)
     }
 
-    /// Connect to this reader, returning its `pcsc::Card`.
-    pub(crate) fn connect(&self) -> Result<pcsc::Card> {
+    /// Connect to the card in this reader in the given mode, returning a
+    /// [`PcscTransport`] to it.
+    fn transport_with(&self, share_mode: pcsc::ShareMode) -> Result<PcscTransport> {
+        Ok(PcscTransport::new(self.connect(share_mode)?, self.name()).with_share_mode(share_mode))
+    }
+
+    /// Get the ATR of the card in this reader.
+    pub fn atr(&self) -> Result<Vec<u8>> {
+        self.wait_for_card(None)
+    }
+
+    /// Wait up to `timeout` for a card to be present in this reader and not
+    /// in exclusive use by another application, returning its ATR.
+    ///
+    /// If `timeout` is `None` the current state is checked without waiting.
+    fn wait_for_card(&self, timeout: Option<Duration>) -> Result<Vec<u8>> {
+        // Clone the context so other readers can be used while we're waiting
+        let ctx = self.ctx.lock().map_err(|_| Error::GenericError)?.clone();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut states = [pcsc::ReaderState::new(self.name, pcsc::State::UNAWARE)];
+        let mut wait = Duration::ZERO;
+
+        loop {
+            ctx.get_status_change(wait, &mut states)?;
+
+            let state = states[0].event_state();
+
+            if state.contains(pcsc::State::PRESENT)
+                && !state.intersects(pcsc::State::EXCLUSIVE | pcsc::State::MUTE)
+            {
+                return Ok(states[0].atr().to_vec());
+            }
+
+            wait = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => Duration::ZERO,
+            };
+
+            if wait.is_zero() {
+                let err = if !state.contains(pcsc::State::PRESENT) {
+                    pcsc::Error::NoSmartcard
+                } else if state.contains(pcsc::State::MUTE) {
+                    pcsc::Error::UnresponsiveCard
+                } else {
+                    pcsc::Error::SharingViolation
+                };
+
+                return Err(err.into());
+            }
+
+            states[0].sync_current_state();
+        }
+    }
+
+    /// Connect to this reader in the given mode, returning its `pcsc::Card`.
+    pub(crate) fn connect(&self, share_mode: pcsc::ShareMode) -> Result<pcsc::Card> {
         // TODO(tarcieri): better error?
         let ctx = self.ctx.lock().map_err(|_| Error::GenericError)?;
-        Ok(ctx.connect(self.name, pcsc::ShareMode::Shared, pcsc::Protocols::T1)?)
+        Ok(ctx.connect(self.name, share_mode, pcsc::Protocols::T1)?)
+    }
+}
+
+/// YubiKey connected to a reader, as listed by [`YubiKey::list`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectedYubiKey {
+    /// Name of the reader
+    pub reader: String,
+
+    /// Serial number of the YubiKey
+    pub serial: Serial,
+
+    /// Firmware version of the YubiKey
+    pub version: Version,
+
+    /// Form factor of the YubiKey, if reported (firmware 4.1+)
+    pub form_factor: Option<FormFactor>,
+}
+
+impl ConnectedYubiKey {
+    /// Open the listed YubiKey.
+    pub fn open(&self) -> Result<YubiKey> {
+        YubiKey::open_by_serial(self.serial)
+    }
+}
+
+/// Options controlling how a YubiKey is opened from a [`Reader`], or with
+/// [`YubiKey::open_with`].
+///
+/// # Shared and exclusive access
+///
+/// By default, the card is connected in shared mode (`SCARD_SHARE_SHARED`),
+/// so other PIV consumers (e.g. the OS smart card stack) can keep using it.
+/// Each operation runs in its own PC/SC transaction, during which the card
+/// is reserved for this application; in between, other applications may use
+/// it, and if one resets the card, the connection is reestablished as
+/// described in [`YubiKey::revalidate`].
+///
+/// With [`OpenOptions::exclusive`], the card is connected in exclusive mode
+/// (`SCARD_SHARE_EXCLUSIVE`) instead: other applications can't use it until
+/// the `YubiKey` is closed, and opening fails with a sharing violation if
+/// another application is connected to it.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    /// Maximum time to wait for a card to be inserted into the reader (and
+    /// not be in exclusive use by another application) before connecting to
+    /// it. If `None` (the default), a missing card fails immediately.
+    ///
+    /// This doesn't bound how long selecting the PIV application takes once
+    /// connected.
+    pub card_timeout: Option<Duration>,
+
+    /// Skip cards whose ATR doesn't identify them as a YubiKey (see
+    /// [`is_yubikey_atr`]), without connecting to them and selecting the PIV
+    /// application. Disabled by default, as some readers (e.g. NFC readers)
+    /// report ATRs which don't name the YubiKey.
+    pub check_atr: bool,
+
+    /// Refuse to open YubiKeys whose PIV application isn't operating in FIPS
+    /// approved mode with [`Error::NotFipsApproved`]. Disabled by default.
+    ///
+    /// See [`YubiKey::is_fips_approved`] for the firmware requirements.
+    pub require_fips_mode: bool,
+
+    /// Connect to the card in exclusive mode rather than shared mode.
+    /// Disabled by default.
+    pub exclusive: bool,
+}
+
+impl OpenOptions {
+    /// Wait up to the given time for a card to be inserted into the reader.
+    pub fn card_timeout(mut self, timeout: Duration) -> Self {
+        self.card_timeout = Some(timeout);
+        self
+    }
+
+    /// Skip cards whose ATR doesn't identify them as a YubiKey.
+    pub fn check_atr(mut self) -> Self {
+        self.check_atr = true;
+        self
+    }
+
+    /// Require the PIV application to be operating in FIPS approved mode.
+    pub fn require_fips_mode(mut self) -> Self {
+        self.require_fips_mode = true;
+        self
+    }
+
+    /// Connect to the card in exclusive mode, so no other application can use
+    /// it while it's open.
+    pub fn exclusive(mut self) -> Self {
+        self.exclusive = true;
+        self
+    }
+}
+
+/// Does the given ATR identify a YubiKey?
+///
+/// The historical bytes of the ATR of YubiKeys (over USB and NFC) contain
+/// the ASCII string "YubiKey" (in varying case, e.g. `Yubikey4`, `YubiKey`).
+pub fn is_yubikey_atr(atr: &[u8]) -> bool {
+    const NEEDLE: &[u8] = b"yubikey";
+
+    atr.windows(NEEDLE.len())
+        .any(|window| window.eq_ignore_ascii_case(NEEDLE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yubikey_atr() {
+        // YubiKey 5 NFC over USB: "YubiKey@" in the historical bytes
+        assert!(is_yubikey_atr(&[
+            0x3b, 0xfd, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15, 0x80, 0x73, 0xc0, 0x21, 0xc0,
+            0x57, 0x59, 0x75, 0x62, 0x69, 0x4b, 0x65, 0x79, 0x40,
+        ]));
+
+        // YubiKey NEO: "YubikeyNEOr3"
+        assert!(is_yubikey_atr(&[
+            0x3b, 0xfc, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15, 0x59, 0x75, 0x62, 0x69, 0x6b,
+            0x65, 0x79, 0x4e, 0x45, 0x4f, 0x72, 0x33, 0xe1,
+        ]));
+
+        // The name is matched in any case, anywhere
+        assert!(is_yubikey_atr(b"YUBIKEY"));
+        assert!(!is_yubikey_atr(&[]));
+        assert!(!is_yubikey_atr(b"Yubi"));
+        assert!(!is_yubikey_atr(b"Yubi Key"));
+
+        // Generic JavaCard
+        assert!(!is_yubikey_atr(&[
+            0x3b, 0x8a, 0x80, 0x01, 0x80, 0x31, 0x80, 0x73, 0x00, 0x21, 0x00, 0x00, 0x00,
+        ]));
     }
 }
@@ -0,0 +1,151 @@
+//! Read-only access to a YubiKey.
+
+use crate::{
+    advisory::Advisory,
+    capability::Capability,
+    cccid::CccId,
+    certificate::Certificate,
+    chuid::ChuId,
+    config::Config,
+    device::DeviceInfo,
+    error::Result,
+    info::Info,
+    inventory::Inventory,
+    labels::SlotLabels,
+    piv::{self, MgmMetadata, PinMetadata, SlotId, SlotMetadata},
+    yubikey::{Serial, Version, YubiKey},
+};
+use std::fmt;
+
+/// Handle to a YubiKey which can only read from it, e.g. for inventory or
+/// monitoring agents which must never alter the YubiKeys they inspect.
+///
+/// Unlike [`YubiKey`], it doesn't give access to any operation modifying
+/// the YubiKey, so a buggy agent can't alter it. In addition, any command
+/// modifying the YubiKey is refused without being sent to it, as in
+/// [dry-run mode](YubiKey::set_dry_run).
+///
+/// Open one with [`YubiKey::open_read_only`], or convert a [`YubiKey`]
+/// opened by other means.
+pub struct ReadOnlyYubiKey {
+    yubikey: YubiKey,
+}
+
+impl ReadOnlyYubiKey {
+    /// Get the name of the associated PC/SC card reader.
+    pub fn name(&self) -> &str {
+        self.yubikey.name()
+    }
+
+    /// Get the YubiKey's PIV application version.
+    pub fn version(&self) -> Version {
+        self.yubikey.version()
+    }
+
+    /// Get YubiKey device serial number.
+    pub fn serial(&self) -> Serial {
+        self.yubikey.serial()
+    }
+
+    /// Does this YubiKey's firmware support the given [`Capability`]?
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.yubikey.supports(capability)
+    }
+
+    /// Get the known security advisories affecting this YubiKey's firmware.
+    pub fn advisories(&self) -> Vec<&'static Advisory> {
+        self.yubikey.advisories()
+    }
+
+    /// Get device configuration.
+    pub fn config(&mut self) -> Result<Config> {
+        self.yubikey.config()
+    }
+
+    /// Get Cardholder Unique Identifier (CHUID).
+    pub fn chuid(&mut self) -> Result<ChuId> {
+        self.yubikey.chuid()
+    }
+
+    /// Get Cardholder Capability Container (CCC) Identifier.
+    pub fn cccid(&mut self) -> Result<CccId> {
+        self.yubikey.cccid()
+    }
+
+    /// Get information about this YubiKey from its Management application:
+    /// see [`YubiKey::device_info`].
+    pub fn device_info(&mut self) -> Result<DeviceInfo> {
+        self.yubikey.device_info()
+    }
+
+    /// Get the number of PIN retries.
+    pub fn get_pin_retries(&mut self) -> Result<u8> {
+        self.yubikey.get_pin_retries()
+    }
+
+    /// Get the metadata of the PIN: see [`YubiKey::pin_metadata`].
+    pub fn pin_metadata(&mut self) -> Result<PinMetadata> {
+        self.yubikey.pin_metadata()
+    }
+
+    /// Get the metadata of the PUK: see [`YubiKey::puk_metadata`].
+    pub fn puk_metadata(&mut self) -> Result<PinMetadata> {
+        self.yubikey.puk_metadata()
+    }
+
+    /// Get the metadata of the management key: see
+    /// [`YubiKey::mgm_metadata`].
+    pub fn mgm_metadata(&mut self) -> Result<MgmMetadata> {
+        self.yubikey.mgm_metadata()
+    }
+
+    /// Get the PIV keys contained in this YubiKey.
+    pub fn piv_keys(&mut self) -> Result<Vec<piv::Key>> {
+        self.yubikey.piv_keys()
+    }
+
+    /// Read the certificate in the given slot.
+    pub fn certificate(&mut self, slot: SlotId) -> Result<Certificate> {
+        Certificate::read(&mut self.yubikey, slot)
+    }
+
+    /// Read the metadata of the given slot: see [`piv::metadata`].
+    pub fn metadata(&mut self, slot: SlotId) -> Result<SlotMetadata> {
+        piv::metadata(&mut self.yubikey, slot)
+    }
+
+    /// Read the [`SlotLabels`] stored on this YubiKey.
+    pub fn slot_labels(&mut self) -> Result<SlotLabels> {
+        SlotLabels::read(&mut self.yubikey)
+    }
+
+    /// Collect an [`Inventory`] of the keys stored in this YubiKey.
+    pub fn inventory(&mut self) -> Result<Inventory> {
+        Inventory::collect(&mut self.yubikey)
+    }
+
+    /// Gather the [`Info`] describing this YubiKey.
+    pub fn info(&mut self) -> Result<Info> {
+        Info::read(&mut self.yubikey)
+    }
+
+    /// Disconnect from the YubiKey: see [`YubiKey::close`].
+    pub fn close(self) -> Result<()> {
+        self.yubikey.close()
+    }
+}
+
+impl From<YubiKey> for ReadOnlyYubiKey {
+    fn from(mut yubikey: YubiKey) -> Self {
+        yubikey.set_dry_run(true);
+        Self { yubikey }
+    }
+}
+
+impl fmt::Debug for ReadOnlyYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReadOnlyYubiKey")
+            .field(&self.yubikey)
+            .finish()
+    }
+}
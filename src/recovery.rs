@@ -0,0 +1,163 @@
+//! Guided recovery of YubiKeys with a blocked PIN or PUK.
+//!
+//! Recovering a blocked PIV application follows a fixed sequence:
+//!
+//! 1. While the PIN has tries remaining, no recovery is needed.
+//! 2. Once the PIN is blocked, it can be unblocked (and set to a new value)
+//!    with the PUK.
+//! 3. Once the PUK is blocked as well, the only option left is to reset the
+//!    PIV application, destroying all keys and certificates.
+//!
+//! [`RecoveryState::assess`] determines which of these states a YubiKey is in,
+//! and [`RecoveryState::next_steps`] lists the [`RecoveryStep`]s available
+//! from it, so that recovery wizards can present consistent options. Each step
+//! returns the state the YubiKey is in afterwards.
+
+use crate::{
+    capability::Capability,
+    error::{Error, Result},
+    yubikey::YubiKey,
+};
+#[cfg(feature = "untested")]
+use log::error;
+
+/// Recovery state of the PIV application of a YubiKey.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecoveryState {
+    /// The PIN isn't blocked, so no recovery is needed.
+    Unblocked {
+        /// Number of PIN tries remaining
+        pin_retries: u8,
+    },
+
+    /// The PIN is blocked, and can be unblocked with the PUK.
+    PinBlocked {
+        /// Number of PUK tries remaining, if it can be determined (requires
+        /// firmware 5.3 or newer)
+        puk_retries: Option<u8>,
+    },
+
+    /// Both the PIN and the PUK are blocked, so the PIV application can only
+    /// be reset.
+    PukBlocked,
+}
+
+/// Steps which can be taken to recover a YubiKey.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecoveryStep {
+    /// Unblock the PIN with the PUK, setting a new PIN: see [`unblock_pin`].
+    UnblockPin,
+
+    /// Reset the PIV application to its factory state: see [`reset_piv`].
+    ResetPiv,
+}
+
+impl RecoveryState {
+    /// Determine the recovery state of the given YubiKey.
+    ///
+    /// This ends the current PIN session, as when calling
+    /// [`YubiKey::get_pin_retries`].
+    pub fn assess(yubikey: &mut YubiKey) -> Result<Self> {
+        let pin_retries = yubikey.get_pin_retries()?;
+
+        if pin_retries > 0 {
+            return Ok(RecoveryState::Unblocked { pin_retries });
+        }
+
+        match puk_retries(yubikey)? {
+            Some(0) => Ok(RecoveryState::PukBlocked),
+            puk_retries => Ok(RecoveryState::PinBlocked { puk_retries }),
+        }
+    }
+
+    /// Get the steps which can be taken from this state.
+    pub fn next_steps(&self) -> &'static [RecoveryStep] {
+        match self {
+            RecoveryState::Unblocked { .. } => &[],
+            // Without the PUK retry count it's unknown whether the PUK is
+            // blocked, so leave it to the YubiKey to refuse a reset
+            RecoveryState::PinBlocked { puk_retries: None } => {
+                &[RecoveryStep::UnblockPin, RecoveryStep::ResetPiv]
+            }
+            RecoveryState::PinBlocked { .. } => &[RecoveryStep::UnblockPin],
+            RecoveryState::PukBlocked => &[RecoveryStep::ResetPiv],
+        }
+    }
+
+    /// Is the PIV application usable without any recovery?
+    pub fn is_unblocked(&self) -> bool {
+        matches!(self, RecoveryState::Unblocked { .. })
+    }
+}
+
+/// Unblock the PIN of the given YubiKey with the PUK, setting it to `new_pin`.
+///
+/// Returns [`Error::WrongPin`] with the number of PUK tries remaining if the
+/// PUK is wrong; once it reaches zero, the YubiKey is in the
+/// [`RecoveryState::PukBlocked`] state.
+#[cfg(feature = "untested")]
+pub fn unblock_pin(yubikey: &mut YubiKey, puk: &[u8], new_pin: &[u8]) -> Result<RecoveryState> {
+    yubikey.unblock_pin(puk, new_pin)?;
+    RecoveryState::assess(yubikey)
+}
+
+/// Reset the PIV application of the given YubiKey to its factory state.
+///
+/// WARNING: this is a destructive operation which will destroy all keys and
+/// certificates!
+///
+/// The YubiKey only permits this once both the PIN and the PUK are blocked,
+/// so this returns [`Error::ArgumentError`] unless [`RecoveryStep::ResetPiv`]
+/// is one of the next steps from the YubiKey's current state.
+#[cfg(feature = "untested")]
+pub fn reset_piv(yubikey: &mut YubiKey) -> Result<RecoveryState> {
+    let state = RecoveryState::assess(yubikey)?;
+
+    if !state.next_steps().contains(&RecoveryStep::ResetPiv) {
+        error!("PIV can only be reset once the PIN and PUK are blocked");
+        return Err(Error::ArgumentError);
+    }
+
+    yubikey.reset_device()?;
+    RecoveryState::assess(yubikey)
+}
+
+/// Get the number of PUK tries remaining, where the firmware reports it.
+fn puk_retries(yubikey: &mut YubiKey) -> Result<Option<u8>> {
+    if !yubikey.supports(Capability::Metadata) {
+        return Ok(None);
+    }
+
+    match yubikey.puk_metadata() {
+        Ok(metadata) => Ok(Some(metadata.remaining_retries)),
+        Err(Error::NotSupported) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_steps() {
+        let unblocked = RecoveryState::Unblocked { pin_retries: 3 };
+        assert!(unblocked.is_unblocked());
+        assert!(unblocked.next_steps().is_empty());
+
+        let pin_blocked = RecoveryState::PinBlocked {
+            puk_retries: Some(2),
+        };
+        assert!(!pin_blocked.is_unblocked());
+        assert_eq!(pin_blocked.next_steps(), [RecoveryStep::UnblockPin]);
+
+        assert_eq!(
+            RecoveryState::PinBlocked { puk_retries: None }.next_steps(),
+            [RecoveryStep::UnblockPin, RecoveryStep::ResetPiv]
+        );
+        assert_eq!(
+            RecoveryState::PukBlocked.next_steps(),
+            [RecoveryStep::ResetPiv]
+        );
+    }
+}
@@ -0,0 +1,463 @@
+//! Transport to a YubiKey plugged into another machine.
+//!
+//! An agent running on the machine the YubiKey is plugged into (e.g. a
+//! provisioning kiosk) serves its [`Transport`] with [`serve`], and a
+//! [`RemoteTransport`] connected to the agent tunnels APDUs to it, so the
+//! YubiKey can be driven from a central server with the same high-level API
+//! as a local one:
+//!
+//! ```no_run
+//! use std::process::{Command, Stdio};
+//! use yubikey::{remote::RemoteTransport, YubiKey};
+//!
+//! // The agent on the kiosk serves the YubiKey over its standard I/O with
+//! // `remote::serve(&mut PcscTransport::new(card, name), stdin, stdout)`
+//! let mut ssh = Command::new("ssh")
+//!     .args(["kiosk-1", "yubikey-agent"])
+//!     .stdin(Stdio::piped())
+//!     .stdout(Stdio::piped())
+//!     .spawn()?;
+//!
+//! let transport = RemoteTransport::new(
+//!     ssh.stdout.take().expect("stdout"),
+//!     ssh.stdin.take().expect("stdin"),
+//! );
+//! let mut yubikey = YubiKey::open_with_transport(transport, "kiosk-1")?;
+//! yubikey.verify_pin(b"123456")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The agent gives whoever connects to it full control of the YubiKey, and
+//! PINs and management keys are sent through the tunnel: it must only be
+//! reachable over an authenticated and encrypted channel, such as an SSH
+//! channel or a TCP connection with mutual TLS (any stream implementing
+//! [`Read`] and [`Write`] can be used).
+//!
+//! # Protocol
+//!
+//! Requests and responses are frames made of a type byte, the big-endian
+//! 32-bit length of the payload, and the payload. Requests begin and end a
+//! transaction, transmit a command APDU (prefixed with the big-endian 32-bit
+//! maximum length of the response) within one, check the connection, or
+//! reconnect or disconnect (with a disposition byte). Responses are either a
+//! success, with the response APDU or the connection status as payload, or
+//! an error, with an error code as payload. Errors signalling the card was
+//! reset or removed are preserved, so the YubiKey recovers from them as it
+//! would locally.
+
+use crate::{
+    error::{Error, Result},
+    transport::{Disposition, Exchange, Transport},
+};
+use log::{debug, error};
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+/// Largest frame payload accepted: an extended APDU and its header.
+const MAX_PAYLOAD: usize = 4 + 7 + 65_535 + 3;
+
+const REQ_BEGIN: u8 = 0x01;
+const REQ_TRANSMIT: u8 = 0x02;
+const REQ_END: u8 = 0x03;
+const REQ_IS_CONNECTED: u8 = 0x04;
+const REQ_RECONNECT: u8 = 0x05;
+const REQ_DISCONNECT: u8 = 0x06;
+
+const RESP_OK: u8 = 0x00;
+const RESP_ERROR: u8 = 0x01;
+
+/// Error code of errors without a more specific code.
+const ERROR_GENERIC: u8 = 0x00;
+
+/// Error code of errors signalling the card was reset.
+const ERROR_CARD_RESET: u8 = 0x01;
+
+/// Error code of errors signalling the card was removed.
+const ERROR_CARD_REMOVED: u8 = 0x02;
+
+/// Error codes of the other PC/SC errors preserved by the protocol.
+#[cfg(feature = "pcsc")]
+const PCSC_ERRORS: [(u8, pcsc::Error); 3] = [
+    (0x03, pcsc::Error::SharingViolation),
+    (0x04, pcsc::Error::UnresponsiveCard),
+    (0x05, pcsc::Error::InvalidHandle),
+];
+
+/// Dispositions, by their code in requests.
+const DISPOSITIONS: [Disposition; 4] = [
+    Disposition::LeaveCard,
+    Disposition::ResetCard,
+    Disposition::UnpowerCard,
+    Disposition::EjectCard,
+];
+
+/// [`Transport`] tunnelling APDUs to an agent serving a YubiKey with
+/// [`serve`].
+pub struct RemoteTransport {
+    connection: RefCell<Connection>,
+}
+
+impl RemoteTransport {
+    /// Talk to the agent through the given reader and writer, e.g. the
+    /// standard output and input of an SSH process running the agent.
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            connection: RefCell::new(Connection {
+                reader: Box::new(BufReader::new(reader)),
+                writer: Box::new(BufWriter::new(writer)),
+            }),
+        }
+    }
+
+    /// Connect to an agent listening on the given TCP address.
+    ///
+    /// The connection is neither authenticated nor encrypted: this is only
+    /// suitable for agents reachable through a secure tunnel (e.g. an SSH
+    /// port forward). Use [`RemoteTransport::new`] with a TLS stream
+    /// otherwise.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(io_error)?;
+        let reader = stream.try_clone().map_err(io_error)?;
+        Ok(Self::new(reader, stream))
+    }
+
+    fn request(&self, request: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        self.connection.borrow_mut().request(request, payload)
+    }
+}
+
+impl fmt::Debug for RemoteTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteTransport").finish_non_exhaustive()
+    }
+}
+
+impl Transport for RemoteTransport {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        self.request(REQ_BEGIN, &[])?;
+        Ok(Box::new(RemoteExchange(&self.connection)))
+    }
+
+    fn is_connected(&self) -> bool {
+        match self.request(REQ_IS_CONNECTED, &[]) {
+            Ok(status) => status == [1],
+            Err(e) => {
+                debug!("couldn't check connection of remote YubiKey: {}", e);
+                false
+            }
+        }
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        self.request(REQ_RECONNECT, &[disposition_code(disposition)])
+            .map(drop)
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()> {
+        self.request(REQ_DISCONNECT, &[disposition_code(disposition)])
+            .map(drop)
+    }
+}
+
+/// Transaction with a remote YubiKey, ended when dropped.
+struct RemoteExchange<'a>(&'a RefCell<Connection>);
+
+impl Exchange for RemoteExchange<'_> {
+    fn transmit(&self, command: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let recv_len = u32::try_from(recv_len).map_err(|_| Error::SizeError)?;
+        let payload = [&recv_len.to_be_bytes()[..], command].concat();
+        self.0.borrow_mut().request(REQ_TRANSMIT, &payload)
+    }
+}
+
+impl Drop for RemoteExchange<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.borrow_mut().request(REQ_END, &[]) {
+            debug!("couldn't end transaction with remote YubiKey: {}", e);
+        }
+    }
+}
+
+/// Stream to or from the agent.
+struct Connection {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Connection {
+    /// Send a request, and receive the payload of its response.
+    fn request(&mut self, request: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        write_frame(&mut self.writer, request, payload).map_err(io_error)?;
+
+        match read_frame(&mut self.reader).map_err(io_error)? {
+            Some((RESP_OK, payload)) => Ok(payload),
+            Some((RESP_ERROR, code)) => Err(decode_error(&code)),
+            Some((response, _)) => {
+                error!("unexpected response from YubiKey agent: {:02x}", response);
+                Err(Error::GenericError)
+            }
+            None => {
+                error!("YubiKey agent closed the connection");
+                Err(Error::CardRemoved)
+            }
+        }
+    }
+}
+
+/// Serve the given transport to a [`RemoteTransport`], receiving requests
+/// from the given reader and sending responses to the given writer, until
+/// the reader reaches its end.
+///
+/// Returns an error if the connection fails or the peer violates the
+/// protocol. Errors of the transport itself are sent to the peer.
+pub fn serve(transport: &mut dyn Transport, reader: impl Read, writer: impl Write) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some((request, payload)) = read_frame(&mut reader).map_err(io_error)? {
+        let response = match request {
+            REQ_BEGIN => match transport.begin_transaction() {
+                Ok(exchange) => {
+                    respond(&mut writer, Ok(vec![]))?;
+
+                    if !serve_transaction(&*exchange, &mut reader, &mut writer)? {
+                        return Ok(());
+                    }
+
+                    continue;
+                }
+                Err(e) => Err(e),
+            },
+            REQ_IS_CONNECTED => Ok(vec![u8::from(transport.is_connected())]),
+            REQ_RECONNECT => parse_disposition(&payload)
+                .and_then(|disposition| transport.reconnect(disposition))
+                .map(|()| vec![]),
+            REQ_DISCONNECT => parse_disposition(&payload)
+                .and_then(|disposition| transport.disconnect(disposition))
+                .map(|()| vec![]),
+            _ => {
+                error!("unexpected request to YubiKey agent: {:02x}", request);
+                return Err(Error::GenericError);
+            }
+        };
+
+        respond(&mut writer, response)?;
+    }
+
+    Ok(())
+}
+
+/// Serve the requests of a transaction, until it ends (returning `true`) or
+/// the reader reaches its end (returning `false`).
+fn serve_transaction(
+    exchange: &dyn Exchange,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<bool> {
+    while let Some((request, payload)) = read_frame(reader).map_err(io_error)? {
+        match request {
+            REQ_TRANSMIT if payload.len() >= 4 => {
+                let (recv_len, command) = payload.split_at(4);
+                let recv_len =
+                    u32::from_be_bytes([recv_len[0], recv_len[1], recv_len[2], recv_len[3]]);
+                let recv_len = usize::try_from(recv_len).map_err(|_| Error::SizeError)?;
+
+                respond(writer, exchange.transmit(command, recv_len))?;
+            }
+            REQ_END => {
+                respond(writer, Ok(vec![]))?;
+                return Ok(true);
+            }
+            _ => {
+                error!("unexpected request in transaction: {:02x}", request);
+                return Err(Error::GenericError);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Send the response to a request.
+fn respond(writer: &mut impl Write, response: Result<Vec<u8>>) -> Result<()> {
+    match response {
+        Ok(payload) => write_frame(writer, RESP_OK, &payload),
+        Err(e) => write_frame(writer, RESP_ERROR, &[encode_error(e)]),
+    }
+    .map_err(io_error)
+}
+
+/// Write a frame, and flush it.
+fn write_frame(writer: &mut impl Write, frame_type: u8, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    writer.write_all(&[frame_type])?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Read a frame, returning `None` if the reader is at its end.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+
+    match reader.read_exact(&mut header[..1]) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    reader.read_exact(&mut header[1..])?;
+
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+fn io_error(e: io::Error) -> Error {
+    error!("connection to YubiKey agent failed: {}", e);
+    Error::GenericError
+}
+
+fn encode_error(e: Error) -> u8 {
+    if e.is_card_reset() {
+        return ERROR_CARD_RESET;
+    }
+
+    if e.is_card_removed() {
+        return ERROR_CARD_REMOVED;
+    }
+
+    #[cfg(feature = "pcsc")]
+    if let Error::PcscError { inner: Some(e) } = e {
+        if let Some((code, _)) = PCSC_ERRORS.iter().find(|(_, error)| *error == e) {
+            return *code;
+        }
+    }
+
+    ERROR_GENERIC
+}
+
+fn decode_error(code: &[u8]) -> Error {
+    match code {
+        [ERROR_CARD_RESET] => return Error::CardReset,
+        [ERROR_CARD_REMOVED] => return Error::CardRemoved,
+        _ => (),
+    }
+
+    #[cfg(feature = "pcsc")]
+    if let Some((_, inner)) = PCSC_ERRORS
+        .iter()
+        .find(|(error_code, _)| code == [*error_code])
+    {
+        return Error::PcscError {
+            inner: Some(*inner),
+        };
+    }
+
+    Error::GenericError
+}
+
+fn disposition_code(disposition: Disposition) -> u8 {
+    DISPOSITIONS
+        .iter()
+        .position(|d| *d == disposition)
+        .and_then(|code| u8::try_from(code).ok())
+        .unwrap_or(0)
+}
+
+fn parse_disposition(payload: &[u8]) -> Result<Disposition> {
+    match payload {
+        [code] => DISPOSITIONS
+            .get(usize::from(*code))
+            .copied()
+            .ok_or(Error::ArgumentError),
+        _ => Err(Error::ArgumentError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Card echoing the INS byte of commands, and reset by INS `0xff`.
+    struct MockTransport;
+
+    struct MockExchange;
+
+    impl Transport for MockTransport {
+        fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+            Ok(Box::new(MockExchange))
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn reconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+
+        fn disconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Exchange for MockExchange {
+        fn transmit(&self, command: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+            match command[1] {
+                0xff => Err(Error::CardReset),
+                ins => Ok(vec![ins, 0x90, 0x00]),
+            }
+        }
+    }
+
+    #[test]
+    fn tunnel_apdus() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("address");
+
+        let agent = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("accept");
+            let reader = stream.try_clone().expect("clone");
+            serve(&mut MockTransport, reader, stream)
+        });
+
+        let mut transport = RemoteTransport::connect(addr).expect("connect");
+        assert!(transport.is_connected());
+
+        {
+            let exchange = transport.begin_transaction().expect("begin");
+            assert_eq!(
+                exchange.transmit(&[0x00, 0xfd, 0x00, 0x00], 261),
+                Ok(vec![0xfd, 0x90, 0x00])
+            );
+
+            // Card resets are reported as such
+            assert_eq!(
+                exchange.transmit(&[0x00, 0xff, 0x00, 0x00], 261),
+                Err(Error::CardReset)
+            );
+        }
+
+        assert!(transport.reconnect(Disposition::LeaveCard).is_ok());
+        assert!(transport.disconnect(Disposition::LeaveCard).is_ok());
+
+        drop(transport);
+        assert_eq!(agent.join().expect("agent"), Ok(()));
+    }
+}
@@ -0,0 +1,358 @@
+//! Recording and replay of the APDUs exchanged with a YubiKey, for
+//! regression tests against real device traces.
+//!
+//! A [`Recorder`] wraps the [`Transport`] to a real YubiKey, writing every
+//! command and response exchanged over it to a recording. A [`Replay`] is a
+//! transport answering commands from such a recording, so a test can run the
+//! same session again without the YubiKey:
+//!
+//! ```no_run
+//! use yubikey::{reader::Context, replay::{Recorder, Replay}, YubiKey};
+//!
+//! // Record a session with a real YubiKey...
+//! let mut readers = Context::open()?;
+//! let reader = readers.iter()?.next().expect("no reader");
+//! let recorder = Recorder::create("tests/traces/retries.apdu", reader.transport()?)?;
+//! let mut yubikey = YubiKey::open_with_transport(recorder, reader.name())?;
+//! let retries = yubikey.get_pin_retries()?;
+//! drop(yubikey);
+//!
+//! // ...and replay it in a test
+//! let replay = Replay::open("tests/traces/retries.apdu")?;
+//! let mut yubikey = YubiKey::open_with_transport(replay, "replay")?;
+//! assert_eq!(yubikey.get_pin_retries()?, retries);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Recordings are text files with one APDU per line, in hex: commands are
+//! prefixed with `>` and responses with `<`. Empty lines and lines starting
+//! with `#` are ignored, so recordings can be annotated.
+//!
+//! The data of commands carrying PINs, PUKs, management keys or private keys
+//! is zeroed in recordings (preserving its length), and only its length is
+//! checked on replay. Other commands must match the recording exactly: a
+//! replay fails on the first command which diverges from it. In particular,
+//! management key authentication can't be replayed, as the host challenge is
+//! random.
+
+use crate::{
+    apdu::Ins,
+    error::{Error, Result},
+    transport::{Disposition, Exchange, Transport},
+    wirelog::{self, COMMAND_HEADER_LEN},
+};
+use log::error;
+use std::{
+    cell::RefCell,
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// [`Transport`] recording the APDUs exchanged over another transport.
+pub struct Recorder<T: Transport> {
+    /// Transport to the YubiKey
+    inner: T,
+
+    /// Where the recording is written
+    writer: Box<dyn Write + Send>,
+}
+
+impl<T: Transport> Recorder<T> {
+    /// Record the APDUs exchanged over the given transport to the file at the
+    /// given path, replacing it if it exists.
+    pub fn create(path: impl AsRef<Path>, inner: T) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), inner)
+    }
+
+    /// Record the APDUs exchanged over the given transport to the given
+    /// writer.
+    pub fn new(writer: impl Write + Send + 'static, inner: T) -> io::Result<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        writeln!(writer, "# yubikey.rs APDU recording")?;
+        writer.flush()?;
+
+        Ok(Self { inner, writer })
+    }
+}
+
+impl<T: Transport> fmt::Debug for Recorder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder").finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        Ok(Box::new(RecordingExchange {
+            inner: self.inner.begin_transaction()?,
+            writer: RefCell::new(&mut self.writer),
+        }))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        self.inner.reconnect(disposition)
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()> {
+        self.inner.disconnect(disposition)
+    }
+}
+
+/// Transaction recording the APDUs exchanged in another transaction.
+struct RecordingExchange<'tx> {
+    inner: Box<dyn Exchange + 'tx>,
+    writer: RefCell<&'tx mut Box<dyn Write + Send>>,
+}
+
+impl RecordingExchange<'_> {
+    fn record(&self, direction: char, apdu: &[u8]) -> Result<()> {
+        let mut writer = self.writer.borrow_mut();
+
+        writeln!(writer, "{} {}", direction, hex::lower::encode_string(apdu))
+            .and_then(|()| writer.flush())
+            .map_err(|e| {
+                error!("couldn't write APDU recording: {}", e);
+                Error::GenericError
+            })
+    }
+}
+
+impl Exchange for RecordingExchange<'_> {
+    fn transmit(&self, command: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let mut recorded = command.to_vec();
+        if is_secret_command(command) {
+            recorded[COMMAND_HEADER_LEN..].fill(0);
+        }
+
+        self.record('>', &recorded)?;
+        let response = self.inner.transmit(command, recv_len)?;
+        self.record('<', &response)?;
+        Ok(response)
+    }
+}
+
+/// [`Transport`] answering commands from a recording made by a [`Recorder`].
+///
+/// Cloning it returns another handle to the same replay, e.g. to check it
+/// [`is_finished`](Replay::is_finished) once the YubiKey opened with it is
+/// dropped.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    /// Recorded commands and their responses
+    exchanges: Arc<Vec<(Vec<u8>, Vec<u8>)>>,
+
+    /// Index of the next exchange to replay
+    next: Arc<AtomicUsize>,
+}
+
+impl Replay {
+    /// Replay the recording in the file at the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let recording = fs::read_to_string(path).map_err(|e| {
+            error!("couldn't read APDU recording {}: {}", path.display(), e);
+            Error::GenericError
+        })?;
+
+        Self::parse(&recording)
+    }
+
+    /// Parse a recording.
+    pub fn parse(recording: &str) -> Result<Self> {
+        let mut exchanges = vec![];
+        let mut command = None;
+
+        for (number, line) in recording.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (direction, apdu) = match (line.strip_prefix('>'), line.strip_prefix('<')) {
+                (Some(apdu), _) => ('>', apdu),
+                (_, Some(apdu)) => ('<', apdu),
+                _ => ('?', line),
+            };
+
+            let apdu = hex::mixed::decode_vec(apdu.trim()).map_err(|_| {
+                error!("invalid APDU on line {} of recording", number + 1);
+                Error::ParseError
+            })?;
+
+            match (direction, command.take()) {
+                ('>', None) => command = Some(apdu),
+                ('<', Some(command)) => exchanges.push((command, apdu)),
+                _ => {
+                    error!("unexpected APDU on line {} of recording", number + 1);
+                    return Err(Error::ParseError);
+                }
+            }
+        }
+
+        if command.is_some() {
+            error!("recording ends with a command without response");
+            return Err(Error::ParseError);
+        }
+
+        Ok(Self {
+            exchanges: Arc::new(exchanges),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Have all the recorded commands been replayed?
+    pub fn is_finished(&self) -> bool {
+        self.next.load(Ordering::SeqCst) == self.exchanges.len()
+    }
+}
+
+impl Transport for Replay {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        Ok(Box::new(ReplayExchange(self)))
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn reconnect(&mut self, _disposition: Disposition) -> Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self, _disposition: Disposition) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Transaction replaying a recording.
+struct ReplayExchange<'tx>(&'tx Replay);
+
+impl Exchange for ReplayExchange<'_> {
+    fn transmit(&self, command: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+        let index = self.0.next.load(Ordering::SeqCst);
+
+        let (recorded, response) = self.0.exchanges.get(index).ok_or_else(|| {
+            error!("no more commands in recording, got {:02x?}", command);
+            Error::GenericError
+        })?;
+
+        let matches = if is_secret_command(command) {
+            recorded.len() == command.len() && recorded[..4] == command[..4]
+        } else {
+            recorded[..] == command[..]
+        };
+
+        if !matches {
+            error!(
+                "command {} diverges from recording: expected {:02x?}, got {:02x?}",
+                index + 1,
+                recorded,
+                command
+            );
+            return Err(Error::GenericError);
+        }
+
+        self.0.next.store(index + 1, Ordering::SeqCst);
+        Ok(response.clone())
+    }
+}
+
+/// Does the given command carry a PIN, PUK, management key or private key
+/// in its data?
+fn is_secret_command(command: &[u8]) -> bool {
+    command.len() > COMMAND_HEADER_LEN && wirelog::is_secret_command(Ins::from(command[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Serial, YubiKey};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("poisoned").write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Simulated YubiKey 5 answering the commands sent when opening it, and
+    /// accepting any PIN.
+    struct MockTransport;
+
+    struct MockExchange;
+
+    impl Transport for MockTransport {
+        fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+            Ok(Box::new(MockExchange))
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn reconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+
+        fn disconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Exchange for MockExchange {
+        fn transmit(&self, command: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+            Ok(match command[1] {
+                // GET VERSION
+                0xfd => vec![5, 4, 3, 0x90, 0x00],
+                // GET SERIAL
+                0xf8 => vec![0x00, 0xbc, 0x61, 0x4e, 0x90, 0x00],
+                _ => vec![0x90, 0x00],
+            })
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let shared = Shared::default();
+        let recorder = Recorder::new(shared.clone(), MockTransport).expect("recorder");
+        let mut yubikey = YubiKey::open_with_transport(recorder, "mock").expect("open");
+        yubikey.verify_pin(b"123456").expect("verify PIN");
+        drop(yubikey);
+
+        let recording =
+            String::from_utf8(shared.0.lock().expect("poisoned").clone()).expect("UTF-8");
+        assert!(recording.contains("> 00200080080000000000000000\n"));
+        assert!(!recording.contains("313233343536"));
+
+        let replay = Replay::parse(&recording).expect("parse recording");
+        let mut yubikey = YubiKey::open_with_transport(replay.clone(), "replay").expect("open");
+        assert_eq!(yubikey.serial(), Serial(12_345_678));
+        assert!(!replay.is_finished());
+
+        // Secrets are only checked for length
+        assert!(yubikey.verify_pin(b"654321").is_ok());
+        assert!(replay.is_finished());
+
+        // Commands beyond the recording fail
+        assert!(yubikey.get_pin_retries().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Signed reports of a YubiKey's hardware and keys, for asset attestation.
+//!
+//! A [`Report`] captures the serial number and firmware version of a YubiKey,
+//! the public key in each populated slot, and the attestation certificates
+//! vouching that those keys were generated on the device. Signing the report
+//! with a key held in one of the slots produces a [`SignedReport`], which an
+//! auditor can later verify to prove what hardware and keys existed at the
+//! time the report was generated.
+//!
+//! Verification needs Yubico's PIV attestation root CA (or the CA of
+//! YubiKeys re-keyed for enterprise attestation) in a [`VerificationBundle`]:
+//! the report's signature alone proves nothing, as anyone can sign a report
+//! with a key of their own.
+//!
+//! Signed reports are encoded in DER, using the following structure:
+//!
+//! ```text
+//! SignedReport ::= SEQUENCE {
+//!     tbsReport           TBSReport,
+//!     signatureAlgorithm  AlgorithmIdentifier,
+//!     signature           BIT STRING
+//! }
+//!
+//! TBSReport ::= SEQUENCE {
+//!     serial              INTEGER,
+//!     firmware            OCTET STRING (SIZE(3)),
+//!     generatedAt         GeneralizedTime,
+//!     signingSlot         INTEGER,
+//!     slots               SEQUENCE OF SlotReport,
+//!     intermediate        Certificate OPTIONAL
+//! }
+//!
+//! SlotReport ::= SEQUENCE {
+//!     slot                INTEGER,
+//!     publicKey           SubjectPublicKeyInfo,
+//!     attestation         Certificate OPTIONAL
+//! }
+//! ```
+
+use crate::{
+    attestation::{AttestationRequirements, VerificationBundle},
+    capability::Capability,
+    certificate::{
+        self,
+        yubikey_signer::{Ed25519, KeyType, Rsa1024, Rsa2048, Rsa3072, Rsa4096, Signer, YubiRsa},
+    },
+    clock,
+    error::{Error, Result},
+    inventory::Inventory,
+    piv::{self, SlotId},
+    verify,
+    yubikey::{Serial, Version, YubiKey},
+    Certificate,
+};
+use log::{debug, error};
+use rsa::{traits::PublicKeyParts, RsaPublicKey};
+use std::time::Duration;
+use x509_cert::{
+    der::{
+        asn1::{BitString, GeneralizedTime, OctetString},
+        oid::AssociatedOid,
+        referenced::OwnedToRef,
+        Decode, Encode, Sequence,
+    },
+    spki::{
+        AlgorithmIdentifierOwned, DynSignatureAlgorithmIdentifier, SignatureBitStringEncoding,
+        SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef,
+    },
+};
+
+/// Contents of a report on a YubiKey.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// Serial number of the YubiKey
+    pub serial: Serial,
+
+    /// Firmware version of the YubiKey
+    pub version: Version,
+
+    /// Time the report was generated, since the Unix epoch
+    pub generated_at: Duration,
+
+    /// Slots containing a key
+    pub slots: Vec<SlotReport>,
+
+    /// Intermediate attestation certificate, issued to the YubiKey by Yubico
+    pub intermediate: Option<Certificate>,
+}
+
+impl Report {
+    /// Collect a report on the given YubiKey.
+    ///
+    /// Slots are collected as by [`Inventory::collect`]. Where the firmware
+    /// supports attestation, each slot's key is attested; keys which were
+    /// imported rather than generated on the YubiKey have no attestation.
+    pub fn collect(yubikey: &mut YubiKey) -> Result<Self> {
+        let inventory = Inventory::collect(yubikey)?;
+        let attest = yubikey.supports(Capability::Attestation);
+        let mut slots = Vec::with_capacity(inventory.slots.len());
+
+        for entry in inventory.slots {
+            let attestation = if attest {
+                match piv::attest(yubikey, entry.slot) {
+                    Ok(cert) => Some(cert),
+                    Err(e) => {
+                        debug!("no attestation for slot {}: {}", entry.slot, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            slots.push(SlotReport {
+                slot: entry.slot,
+                public_key: entry.public_key,
+                attestation,
+            });
+        }
+
+        let intermediate = if attest {
+            Certificate::read(yubikey, SlotId::Attestation).ok()
+        } else {
+            None
+        };
+
+        // GeneralizedTime has a resolution of one second
+        let now = clock::unix_time(yubikey.clock())?;
+        let generated_at = Duration::from_secs(now.as_secs());
+
+        Ok(Self {
+            serial: inventory.serial,
+            version: inventory.version,
+            generated_at,
+            slots,
+            intermediate,
+        })
+    }
+
+    /// Find the report for the given slot.
+    pub fn slot(&self, slot: SlotId) -> Option<&SlotReport> {
+        self.slots.iter().find(|entry| entry.slot == slot)
+    }
+
+    /// Sign this report with the key in the given slot of the YubiKey.
+    ///
+    /// The slot must be one of the slots in this report, and hold an RSA or
+    /// ECC key. The PIN must already be verified if the key's PIN policy
+    /// requires it.
+    pub fn sign(self, yubikey: &mut YubiKey, slot: SlotId) -> Result<SignedReport> {
+        let public_key = self
+            .slot(slot)
+            .ok_or_else(|| {
+                error!("signing slot {} is not part of the report", slot);
+                Error::NotFound
+            })?
+            .public_key
+            .clone();
+
+        let tbs = self.to_asn1(slot)?;
+        let msg = tbs.to_der()?;
+        let (signature_algorithm, signature) =
+            sign_message(yubikey, slot, public_key.owned_to_ref(), &msg)?;
+
+        let der = SignedReportAsn1 {
+            tbs,
+            signature_algorithm,
+            signature,
+        }
+        .to_der()?;
+
+        Ok(SignedReport {
+            report: self,
+            signing_slot: slot,
+            der,
+        })
+    }
+
+    fn to_asn1(&self, signing_slot: SlotId) -> Result<TbsReportAsn1> {
+        let slots = self
+            .slots
+            .iter()
+            .map(|entry| SlotReportAsn1 {
+                slot: entry.slot.into(),
+                public_key: entry.public_key.clone(),
+                attestation: entry.attestation.as_ref().map(|cert| cert.cert.clone()),
+            })
+            .collect();
+
+        Ok(TbsReportAsn1 {
+            serial: self.serial.into(),
+            firmware: OctetString::new([
+                self.version.major,
+                self.version.minor,
+                self.version.patch,
+            ])?,
+            generated_at: GeneralizedTime::from_unix_duration(self.generated_at)?,
+            signing_slot: signing_slot.into(),
+            slots,
+            intermediate: self.intermediate.as_ref().map(|cert| cert.cert.clone()),
+        })
+    }
+
+    fn from_asn1(tbs: &TbsReportAsn1) -> Result<Self> {
+        let firmware: [u8; 3] = tbs.firmware.as_bytes().try_into()?;
+        let slots = tbs
+            .slots
+            .iter()
+            .map(|entry| {
+                Ok(SlotReport {
+                    slot: SlotId::try_from(entry.slot)?,
+                    public_key: entry.public_key.clone(),
+                    attestation: entry.attestation.as_ref().map(to_certificate).transpose()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            serial: Serial(tbs.serial),
+            version: Version::new(firmware),
+            generated_at: tbs.generated_at.to_unix_duration(),
+            slots,
+            intermediate: tbs.intermediate.as_ref().map(to_certificate).transpose()?,
+        })
+    }
+}
+
+/// A populated slot in a [`Report`].
+#[derive(Clone, Debug)]
+pub struct SlotReport {
+    /// Slot containing the key
+    pub slot: SlotId,
+
+    /// Public key of the key in the slot
+    pub public_key: SubjectPublicKeyInfoOwned,
+
+    /// Attestation certificate for the key, issued by the intermediate
+    /// attestation certificate, if the key was generated on the YubiKey
+    pub attestation: Option<Certificate>,
+}
+
+/// A [`Report`] signed by the key in one of its slots.
+#[derive(Clone, Debug)]
+pub struct SignedReport {
+    /// Contents of the report
+    report: Report,
+
+    /// Slot holding the key which signed the report
+    signing_slot: SlotId,
+
+    /// DER encoding of the signed report
+    der: Vec<u8>,
+}
+
+impl SignedReport {
+    /// Parse a DER encoded signed report.
+    ///
+    /// This doesn't verify the signature: use [`SignedReport::verify`] before
+    /// relying on the contents of the report.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let signed = SignedReportAsn1::from_der(der)?;
+
+        Ok(Self {
+            report: Report::from_asn1(&signed.tbs)?,
+            signing_slot: SlotId::try_from(signed.tbs.signing_slot)?,
+            der: der.to_vec(),
+        })
+    }
+
+    /// Returns the DER encoding of this signed report.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Returns the contents of the report.
+    pub fn report(&self) -> &Report {
+        &self.report
+    }
+
+    /// Returns the slot holding the key which signed the report.
+    pub fn signing_slot(&self) -> SlotId {
+        self.signing_slot
+    }
+
+    /// Verify this report against the roots of the given bundle.
+    ///
+    /// The report's intermediate attestation certificate must chain to one of
+    /// the bundle's roots, each attestation in the report must be signed by
+    /// it and cover the public key of its slot, and the report must be signed
+    /// by the attested key in the signing slot.
+    ///
+    /// Returns [`Error::AttestationError`] if the bundle has no roots, or the
+    /// signing key or any of the report's keys fails attestation.
+    pub fn verify(&self, bundle: &VerificationBundle) -> Result<()> {
+        if bundle.roots().is_empty() {
+            error!("verification bundle has no root certificates");
+            return Err(Error::AttestationError);
+        }
+
+        let intermediate = self.report.intermediate.as_ref().ok_or_else(|| {
+            error!("report has no intermediate attestation certificate");
+            Error::AttestationError
+        })?;
+        bundle.check_chain(intermediate)?;
+
+        let requirements = AttestationRequirements::default();
+
+        for entry in &self.report.slots {
+            if let Some(attestation) = &entry.attestation {
+                requirements.verify_signed(
+                    attestation,
+                    intermediate,
+                    entry.public_key.owned_to_ref(),
+                )?;
+            }
+        }
+
+        let signer = self
+            .report
+            .slot(self.signing_slot)
+            .filter(|entry| entry.attestation.is_some())
+            .ok_or_else(|| {
+                error!("signing key in slot {} isn't attested", self.signing_slot);
+                Error::AttestationError
+            })?;
+
+        let signed = SignedReportAsn1::from_der(&self.der)?;
+        let signature = signed.signature.as_bytes().ok_or(Error::SignatureError)?;
+
+        certificate::verify_signature(
+            signer.public_key.owned_to_ref(),
+            &signed.signature_algorithm,
+            &signed.tbs.to_der()?,
+            signature,
+        )
+    }
+}
+
+#[derive(Sequence)]
+struct SignedReportAsn1 {
+    tbs: TbsReportAsn1,
+    signature_algorithm: AlgorithmIdentifierOwned,
+    signature: BitString,
+}
+
+#[derive(Sequence)]
+struct TbsReportAsn1 {
+    serial: u32,
+    firmware: OctetString,
+    generated_at: GeneralizedTime,
+    signing_slot: u8,
+    slots: Vec<SlotReportAsn1>,
+    #[asn1(optional = "true")]
+    intermediate: Option<x509_cert::Certificate>,
+}
+
+#[derive(Sequence)]
+struct SlotReportAsn1 {
+    slot: u8,
+    public_key: SubjectPublicKeyInfoOwned,
+    #[asn1(optional = "true")]
+    attestation: Option<x509_cert::Certificate>,
+}
+
+fn to_certificate(cert: &x509_cert::Certificate) -> Result<Certificate> {
+    Certificate::from_bytes(cert.to_der()?)
+}
+
+/// Sign a message with the key in the given slot, choosing the signature
+/// algorithm from the key's public key.
+pub(crate) fn sign_message(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    msg: &[u8],
+) -> Result<(AlgorithmIdentifierOwned, BitString)> {
+    if public_key.algorithm.oid == verify::ED25519_OID {
+        return sign_with::<Ed25519>(yubikey, slot, public_key, msg);
+    } else if let Ok(curve) = public_key.algorithm.parameters_oid() {
+        if curve == p256::NistP256::OID {
+            return sign_with::<p256::NistP256>(yubikey, slot, public_key, msg);
+        } else if curve == p384::NistP384::OID {
+            return sign_with::<p384::NistP384>(yubikey, slot, public_key, msg);
+        }
+    } else if let Ok(rsa) = RsaPublicKey::try_from(public_key.clone()) {
+        match rsa.size() * 8 {
+            1024 => return sign_with::<YubiRsa<Rsa1024>>(yubikey, slot, public_key, msg),
+            2048 => return sign_with::<YubiRsa<Rsa2048>>(yubikey, slot, public_key, msg),
+            3072 => return sign_with::<YubiRsa<Rsa3072>>(yubikey, slot, public_key, msg),
+            4096 => return sign_with::<YubiRsa<Rsa4096>>(yubikey, slot, public_key, msg),
+            _ => (),
+        }
+    }
+
+    error!("unsupported key for signing reports in slot {}", slot);
+    Err(Error::AlgorithmError)
+}
+
+fn sign_with<KT: KeyType>(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    msg: &[u8],
+) -> Result<(AlgorithmIdentifierOwned, BitString)> {
+    let signer = Signer::<KT>::new(yubikey, slot, public_key)?;
+    let algorithm = signer
+        .signature_algorithm_identifier()
+        .map_err(|_| Error::AlgorithmError)?;
+    let signature = signature::Signer::try_sign(&signer, msg).map_err(|e| {
+        error!("failed signing report: {}", e);
+        Error::GenericError
+    })?;
+
+    Ok((algorithm, signature.to_bitstring()?))
+}
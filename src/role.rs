@@ -0,0 +1,165 @@
+//! Handles separating day-to-day operations from administration.
+//!
+//! Services performing private key operations typically hold a YubiKey open
+//! for their whole lifetime, while only occasionally (if ever) needing to
+//! administer it. An [`Operator`] handle only offers the operations allowed
+//! to PIN holders: reading certificates and metadata, signing and
+//! decrypting. Administrative operations (generating and importing keys,
+//! writing objects, changing the management key, resetting...) require
+//! explicitly [elevating](Operator::elevate) it to an [`Admin`] handle by
+//! authenticating with the management key.
+//!
+//! The management key authentication ends when the [`Admin`] handle is
+//! dropped, so it can't linger on the long-lived operator handle.
+
+use crate::{
+    certificate::Certificate,
+    error::Result,
+    mgm::{MgmKey, MgmKeyAlgorithm},
+    pin::{PinEscalation, PinProvider},
+    piv::{self, AlgorithmId, SlotId, SlotMetadata},
+    yubikey::{Serial, Version, YubiKey},
+    Buffer,
+};
+use log::warn;
+use std::ops::{Deref, DerefMut};
+
+/// Handle to a YubiKey only offering PIN-level operations.
+#[derive(Debug)]
+pub struct Operator {
+    yubikey: YubiKey,
+}
+
+impl Operator {
+    /// Create an operator handle to the given YubiKey.
+    pub fn new(yubikey: YubiKey) -> Self {
+        Self { yubikey }
+    }
+
+    /// Get the serial number of the YubiKey.
+    pub fn serial(&self) -> Serial {
+        self.yubikey.serial()
+    }
+
+    /// Get the firmware version of the YubiKey.
+    pub fn version(&self) -> Version {
+        self.yubikey.version()
+    }
+
+    /// Verify the PIN: see [`YubiKey::verify_pin`].
+    pub fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        self.yubikey.verify_pin(pin)
+    }
+
+    /// Set the [`PinProvider`] supplying the PIN when needed: see
+    /// [`YubiKey::set_pin_provider`].
+    pub fn set_pin_provider(&mut self, provider: Option<Box<dyn PinProvider>>) {
+        self.yubikey.set_pin_provider(provider);
+    }
+
+    /// Set what happens when the PIN is needed and none is cached: see
+    /// [`YubiKey::set_pin_escalation`].
+    pub fn set_pin_escalation(&mut self, escalation: PinEscalation) {
+        self.yubikey.set_pin_escalation(escalation);
+    }
+
+    /// Set the number of PIN tries left at which the [`PinProvider`] is no
+    /// longer consulted: see [`YubiKey::set_pin_retry_floor`].
+    pub fn set_pin_retry_floor(&mut self, floor: u8) {
+        self.yubikey.set_pin_retry_floor(floor);
+    }
+
+    /// Get the number of PIN retries remaining.
+    pub fn get_pin_retries(&mut self) -> Result<u8> {
+        self.yubikey.get_pin_retries()
+    }
+
+    /// Read the certificate in the given slot.
+    pub fn certificate(&mut self, slot: SlotId) -> Result<Certificate> {
+        Certificate::read(&mut self.yubikey, slot)
+    }
+
+    /// Read the metadata of the key in the given slot.
+    pub fn metadata(&mut self, slot: SlotId) -> Result<SlotMetadata> {
+        piv::metadata(&mut self.yubikey, slot)
+    }
+
+    /// Sign data with the key in the given slot: see [`piv::sign_data`].
+    pub fn sign_data(
+        &mut self,
+        data: &[u8],
+        algorithm: AlgorithmId,
+        slot: SlotId,
+    ) -> Result<Buffer> {
+        piv::sign_data(&mut self.yubikey, data, algorithm, slot)
+    }
+
+    /// Decrypt data with the key in the given slot: see
+    /// [`piv::decrypt_data`].
+    #[cfg(feature = "untested")]
+    pub fn decrypt_data(
+        &mut self,
+        data: &[u8],
+        algorithm: AlgorithmId,
+        slot: SlotId,
+    ) -> Result<Buffer> {
+        piv::decrypt_data(&mut self.yubikey, data, algorithm, slot)
+    }
+
+    /// Authenticate with the given management key, returning an [`Admin`]
+    /// handle for administrative operations.
+    pub fn elevate<C: MgmKeyAlgorithm>(&mut self, mgm_key: MgmKey<C>) -> Result<Admin<'_>> {
+        self.yubikey.authenticate(mgm_key)?;
+        Ok(Admin {
+            yubikey: &mut self.yubikey,
+        })
+    }
+
+    /// Get the underlying YubiKey, with unrestricted access.
+    pub fn into_inner(self) -> YubiKey {
+        self.yubikey
+    }
+}
+
+/// Handle to a YubiKey authenticated with the management key, offering all
+/// operations.
+///
+/// Dereferences to [`YubiKey`], so it can be passed to functions such as
+/// [`piv::generate`]. Dropping it ends the management key authentication,
+/// keeping the PIN verified if it was (by verifying the cached PIN again).
+#[derive(Debug)]
+pub struct Admin<'a> {
+    yubikey: &'a mut YubiKey,
+}
+
+impl Deref for Admin<'_> {
+    type Target = YubiKey;
+
+    fn deref(&self) -> &YubiKey {
+        self.yubikey
+    }
+}
+
+impl DerefMut for Admin<'_> {
+    fn deref_mut(&mut self) -> &mut YubiKey {
+        self.yubikey
+    }
+}
+
+impl Drop for Admin<'_> {
+    fn drop(&mut self) {
+        // Resetting the security status clears the PIN verification too
+        let pin_verified = self.yubikey.is_pin_verified();
+
+        if let Err(e) = self.yubikey.reset_security_status() {
+            warn!("couldn't end management key authentication: {}", e);
+            return;
+        }
+
+        if pin_verified {
+            if let Err(e) = self.yubikey.reverify_cached_pin() {
+                warn!("couldn't verify the PIN again: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Handles to a single key of a shared YubiKey, restricted to one usage.
+//!
+//! Applications embedding third-party code (e.g. plugins) may want to let it
+//! sign with one of their keys without giving it the YubiKey itself, which
+//! would let it generate keys, change credentials or reset the PIV
+//! application.
+//!
+//! A [`SharedYubiKey`] can be cloned and used from several threads, and
+//! hands out [`ScopedKey`]s which can only perform one [`KeyUsage`] with the
+//! key in one slot:
+//!
+//! ```no_run
+//! use yubikey::{piv::SlotId, scoped::SharedYubiKey, KeyUsage, YubiKey};
+//!
+//! let yubikey = SharedYubiKey::from(YubiKey::open()?);
+//! let key = yubikey.scoped(SlotId::Signature, KeyUsage::Sign);
+//!
+//! // ... hand `key` to a plugin, which can only do this:
+//! let signature = key.sign(b"message")?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! Operations through a `ScopedKey` are also subject to the YubiKey's
+//! [`KeyUsagePolicy`](crate::KeyUsagePolicy) and middleware.
+
+use crate::{
+    error::{Error, Result},
+    piv::SlotId,
+    usage::KeyUsage,
+    yubikey::YubiKey,
+    Buffer,
+};
+use log::error;
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+/// YubiKey shared between threads.
+///
+/// Cloning it returns another handle to the same YubiKey.
+#[derive(Clone)]
+pub struct SharedYubiKey {
+    yubikey: Arc<Mutex<YubiKey>>,
+}
+
+impl SharedYubiKey {
+    /// Lock the YubiKey, giving access to the whole API until the returned
+    /// guard is dropped.
+    pub fn lock(&self) -> MutexGuard<'_, YubiKey> {
+        // An operation panicking doesn't leave the YubiKey in an inconsistent
+        // state: at worst, its transaction was abandoned
+        self.yubikey.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get a handle which can only perform the given usage with the key in
+    /// the given slot.
+    pub fn scoped(&self, slot: SlotId, usage: KeyUsage) -> ScopedKey {
+        ScopedKey {
+            yubikey: Arc::clone(&self.yubikey),
+            slot,
+            usage,
+        }
+    }
+}
+
+impl From<YubiKey> for SharedYubiKey {
+    fn from(yubikey: YubiKey) -> Self {
+        Self {
+            yubikey: Arc::new(Mutex::new(yubikey)),
+        }
+    }
+}
+
+impl fmt::Debug for SharedYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedYubiKey").field(&*self.lock()).finish()
+    }
+}
+
+/// Handle which can only perform one [`KeyUsage`] with the key in one slot
+/// of a [`SharedYubiKey`].
+///
+/// Cloning it returns another handle with the same scope.
+#[derive(Clone)]
+pub struct ScopedKey {
+    yubikey: Arc<Mutex<YubiKey>>,
+    slot: SlotId,
+    usage: KeyUsage,
+}
+
+impl ScopedKey {
+    /// Get the slot of the key.
+    pub fn slot(&self) -> SlotId {
+        self.slot
+    }
+
+    /// Get the usage this handle is restricted to.
+    pub fn usage(&self) -> KeyUsage {
+        self.usage
+    }
+
+    /// Get the public key, detected as in [`YubiKey::signer`].
+    pub fn public_key(&self) -> Result<SubjectPublicKeyInfoOwned> {
+        Ok(self.lock().signer(self.slot)?.public_key().clone())
+    }
+
+    /// Sign the given message: see [`SlotSigner::sign`].
+    ///
+    /// Returns [`Error::OperationDenied`] unless this handle is restricted to
+    /// [`KeyUsage::Sign`].
+    ///
+    /// [`SlotSigner::sign`]: crate::signer::SlotSigner::sign
+    pub fn sign(&self, msg: &[u8]) -> Result<Buffer> {
+        self.check(KeyUsage::Sign)?;
+        self.lock().signer(self.slot)?.sign(msg)
+    }
+
+    /// Decrypt the given PKCS#1 v1.5 ciphertext: see
+    /// [`SlotDecryptor::decrypt`].
+    ///
+    /// Returns [`Error::OperationDenied`] unless this handle is restricted to
+    /// [`KeyUsage::Decrypt`].
+    ///
+    /// [`SlotDecryptor::decrypt`]: crate::signer::SlotDecryptor::decrypt
+    #[cfg(feature = "untested")]
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Buffer> {
+        self.check(KeyUsage::Decrypt)?;
+        self.lock().decryptor(self.slot)?.decrypt(ciphertext)
+    }
+
+    /// Perform key agreement with the given peer public key: see
+    /// [`SlotDecryptor::agree`].
+    ///
+    /// Returns [`Error::OperationDenied`] unless this handle is restricted to
+    /// [`KeyUsage::Decrypt`].
+    ///
+    /// [`SlotDecryptor::agree`]: crate::signer::SlotDecryptor::agree
+    #[cfg(feature = "untested")]
+    pub fn agree(&self, peer_public_key: &[u8]) -> Result<Buffer> {
+        self.check(KeyUsage::Decrypt)?;
+        self.lock().decryptor(self.slot)?.agree(peer_public_key)
+    }
+
+    /// Check this handle is restricted to the given usage.
+    fn check(&self, usage: KeyUsage) -> Result<()> {
+        if usage != self.usage {
+            error!(
+                "{:?} with the key in slot {} denied: handle restricted to {:?}",
+                usage, self.slot, self.usage
+            );
+            return Err(Error::OperationDenied);
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, YubiKey> {
+        self.yubikey.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for ScopedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedKey")
+            .field("slot", &self.slot)
+            .field("usage", &self.usage)
+            .finish_non_exhaustive()
+    }
+}
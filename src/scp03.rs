@@ -0,0 +1,624 @@
+//! SCP03 secure channel.
+//!
+//! GlobalPlatform's Secure Channel Protocol 03 mutually authenticates the
+//! host and the YubiKey with static AES keys, then encrypts and MACs every
+//! command and response exchanged. Yubico requires it for some FIPS
+//! deployments, notably over NFC, where PINs, management keys and imported
+//! private keys would otherwise be sent in the clear.
+//!
+//! Open a YubiKey with [`YubiKey::open_with_scp03`], or secure an open one
+//! with [`YubiKey::enable_scp03`]. The secure channel is then opened each
+//! time the PIV application is selected, and all PIV commands (including
+//! management key authentication and key import) are sent through it.
+//! Commands sent to other applications (e.g. to read the device info) are
+//! not secured.
+//!
+//! Only AES-128 keys are supported, with the full security level (command
+//! and response encryption and MACs).
+//!
+//! [`YubiKey::open_with_scp03`]: crate::YubiKey::open_with_scp03
+//! [`YubiKey::enable_scp03`]: crate::YubiKey::enable_scp03
+
+use crate::{
+    apdu::{Apdu, StatusWords},
+    error::{Error, Result},
+    scp11::{self, Scp11Params},
+    transaction::Transaction,
+    Buffer,
+};
+use aes::Aes128;
+use cipher::{
+    block_padding::Iso7816, generic_array::GenericArray, BlockDecryptMut, BlockEncrypt,
+    BlockEncryptMut, KeyInit, KeyIvInit,
+};
+use cmac::{Cmac, Mac};
+use log::error;
+use rand_core::{OsRng, RngCore};
+use std::{cell::RefCell, fmt};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+/// INITIALIZE UPDATE instruction, starting the secure channel.
+pub(crate) const INS_INITIALIZE_UPDATE: u8 = 0x50;
+
+/// EXTERNAL AUTHENTICATE instruction, authenticating the host.
+pub(crate) const INS_EXTERNAL_AUTHENTICATE: u8 = 0x82;
+
+/// Class byte bit flagging commands sent through the secure channel.
+pub(crate) const CLA_SECURE: u8 = 0x04;
+
+/// Security level: command and response encryption and MACs.
+pub(crate) const SECURITY_LEVEL: u8 = 0x33;
+
+/// SCP03 identifier in the key information returned by INITIALIZE UPDATE.
+pub(crate) const SCP03: u8 = 0x03;
+
+/// Length of AES-128 keys and blocks.
+pub(crate) const BLOCK_LEN: usize = 16;
+
+/// Length of truncated MACs, challenges and cryptograms.
+const HALF_BLOCK_LEN: usize = 8;
+
+/// Largest command data sent in a single short APDU.
+const CHUNK_LEN: usize = 255;
+
+/// Derivation constants (GlobalPlatform Amendment D, section 6.2.1).
+pub(crate) const DERIVE_CARD_CRYPTOGRAM: u8 = 0x00;
+pub(crate) const DERIVE_HOST_CRYPTOGRAM: u8 = 0x01;
+const DERIVE_S_ENC: u8 = 0x04;
+const DERIVE_S_MAC: u8 = 0x06;
+const DERIVE_S_RMAC: u8 = 0x07;
+
+/// YubiKey factory default key (the same for all keys in the default set).
+#[cfg(not(feature = "no-default-credentials"))]
+const DEFAULT_KEY: [u8; BLOCK_LEN] = [
+    0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f,
+];
+
+/// Static SCP03 keys, provisioned on the YubiKey's security domain.
+///
+/// Only the encryption and MAC keys are needed to open a secure channel.
+#[derive(Clone)]
+pub struct Scp03Keys {
+    key_version: u8,
+    enc: Zeroizing<[u8; BLOCK_LEN]>,
+    mac: Zeroizing<[u8; BLOCK_LEN]>,
+}
+
+impl Scp03Keys {
+    /// Create SCP03 keys from the key version number of the key set and its
+    /// AES-128 encryption and MAC keys.
+    pub fn new(key_version: u8, enc: [u8; BLOCK_LEN], mac: [u8; BLOCK_LEN]) -> Self {
+        Self {
+            key_version,
+            enc: Zeroizing::new(enc),
+            mac: Zeroizing::new(mac),
+        }
+    }
+
+    /// Get the key version number of the key set.
+    pub fn key_version(&self) -> u8 {
+        self.key_version
+    }
+}
+
+/// The YubiKey factory default key set (key version `0xff`), whose keys are
+/// publicly known: replace them before relying on the secure channel.
+///
+/// Not available with the `no-default-credentials` feature enabled.
+#[cfg(not(feature = "no-default-credentials"))]
+impl Default for Scp03Keys {
+    fn default() -> Self {
+        Self::new(0xff, DEFAULT_KEY, DEFAULT_KEY)
+    }
+}
+
+impl fmt::Debug for Scp03Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scp03Keys")
+            .field("key_version", &self.key_version)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Credentials a secure channel is opened with.
+#[derive(Clone)]
+pub(crate) enum Credentials {
+    Scp03(Scp03Keys),
+    Scp11(Scp11Params),
+}
+
+impl From<Scp03Keys> for Credentials {
+    fn from(keys: Scp03Keys) -> Self {
+        Credentials::Scp03(keys)
+    }
+}
+
+impl From<Scp11Params> for Credentials {
+    fn from(params: Scp11Params) -> Self {
+        Credentials::Scp11(params)
+    }
+}
+
+/// Secure channel configured on a YubiKey, and the session currently open
+/// with it (if any).
+pub(crate) struct SecureChannel {
+    credentials: Credentials,
+    session: Option<Session>,
+
+    /// Is a session being opened, i.e. are the commands of the handshake
+    /// being sent (in the clear)?
+    opening: bool,
+}
+
+impl SecureChannel {
+    pub fn new(credentials: impl Into<Credentials>) -> Self {
+        Self {
+            credentials: credentials.into(),
+            session: None,
+            opening: false,
+        }
+    }
+
+    /// Is a session open, i.e. are commands sent through the secure channel?
+    pub fn is_open(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Are the commands opening a session being sent? They are the only
+    /// ones sent in the clear, besides selecting an application.
+    pub fn is_opening(&self) -> bool {
+        self.opening
+    }
+
+    /// Open a session, once an application has been selected.
+    pub fn open(channel: &RefCell<Self>, txn: &Transaction<'_>) -> Result<()> {
+        let credentials = {
+            let mut channel = channel.borrow_mut();
+            channel.session = None;
+            channel.opening = true;
+            channel.credentials.clone()
+        };
+
+        let session = match &credentials {
+            Credentials::Scp03(keys) => Session::open(txn, keys),
+            Credentials::Scp11(params) => scp11::open(txn, params),
+        };
+
+        let mut channel = channel.borrow_mut();
+        channel.opening = false;
+        channel.session = Some(session?);
+        Ok(())
+    }
+
+    /// Close the session, e.g. because an application was selected.
+    pub fn close(&mut self) {
+        self.session = None;
+    }
+
+    /// Wrap a serialized command APDU, returning the APDUs to send.
+    pub fn wrap(&mut self, command: &[u8]) -> Result<Vec<Buffer>> {
+        let session = self.session.as_mut().ok_or(Error::SecureChannelError)?;
+        session.wrap(command)
+    }
+
+    /// Unwrap a response APDU (including data returned by GET RESPONSE),
+    /// closing the session if it isn't authentic.
+    pub fn unwrap(&mut self, response: &[u8]) -> Result<Buffer> {
+        let session = self.session.as_ref().ok_or(Error::SecureChannelError)?;
+
+        session.unwrap(response).map_err(|e| {
+            self.session = None;
+            e
+        })
+    }
+}
+
+/// Session keys and state of an open secure channel.
+pub(crate) struct Session {
+    enc: Zeroizing<[u8; BLOCK_LEN]>,
+    mac: Zeroizing<[u8; BLOCK_LEN]>,
+    rmac: Zeroizing<[u8; BLOCK_LEN]>,
+
+    /// MAC chaining value: the full MAC of the last command
+    mac_chain: [u8; BLOCK_LEN],
+
+    /// Encryption counter of the next command
+    counter: u32,
+}
+
+impl Session {
+    /// Derive the session keys from the static keys and the context (the
+    /// host challenge followed by the card challenge).
+    pub fn new(keys: &Scp03Keys, context: &[u8]) -> Self {
+        Self {
+            enc: Zeroizing::new(derive(&keys.enc, DERIVE_S_ENC, context, 128)),
+            mac: Zeroizing::new(derive(&keys.mac, DERIVE_S_MAC, context, 128)),
+            rmac: Zeroizing::new(derive(&keys.mac, DERIVE_S_RMAC, context, 128)),
+            mac_chain: [0; BLOCK_LEN],
+            counter: 1,
+        }
+    }
+
+    /// Create a session from keys established otherwise (e.g. by SCP11).
+    pub fn from_keys(
+        enc: [u8; BLOCK_LEN],
+        mac: [u8; BLOCK_LEN],
+        rmac: [u8; BLOCK_LEN],
+        mac_chain: [u8; BLOCK_LEN],
+    ) -> Self {
+        Self {
+            enc: Zeroizing::new(enc),
+            mac: Zeroizing::new(mac),
+            rmac: Zeroizing::new(rmac),
+            mac_chain,
+            counter: 1,
+        }
+    }
+
+    /// Compute the card or host cryptogram.
+    pub fn cryptogram(&self, constant: u8, context: &[u8]) -> [u8; HALF_BLOCK_LEN] {
+        truncate(&derive(&self.mac, constant, context, 64))
+    }
+
+    /// Compute the C-MAC of a command (its header and data), updating the
+    /// MAC chaining value.
+    pub fn command_mac(&mut self, message: &[u8]) -> [u8; HALF_BLOCK_LEN] {
+        let mut input = self.mac_chain.to_vec();
+        input.extend_from_slice(message);
+        self.mac_chain = cmac(&self.mac, &input);
+        truncate(&self.mac_chain)
+    }
+
+    /// Compute the R-MAC of response data and status words.
+    fn response_mac(&self, data: &[u8], sw: &[u8]) -> [u8; HALF_BLOCK_LEN] {
+        let mut input = self.mac_chain.to_vec();
+        input.extend_from_slice(data);
+        input.extend_from_slice(sw);
+        truncate(&cmac(&self.rmac, &input))
+    }
+
+    /// Get the initial value for encrypting the data of the command with the
+    /// given counter (`0x00` prefix) or of its response (`0x80` prefix).
+    fn iv(&self, prefix: u8, counter: u32) -> [u8; BLOCK_LEN] {
+        let mut block = [0u8; BLOCK_LEN];
+        block[0] = prefix;
+        block[BLOCK_LEN - 4..].copy_from_slice(&counter.to_be_bytes());
+        encrypt_block(&self.enc, block)
+    }
+
+    /// Start a session: send INITIALIZE UPDATE, check the card cryptogram
+    /// and authenticate with EXTERNAL AUTHENTICATE.
+    fn open(txn: &Transaction<'_>, keys: &Scp03Keys) -> Result<Self> {
+        let mut host_challenge = [0u8; HALF_BLOCK_LEN];
+        OsRng.fill_bytes(&mut host_challenge);
+
+        let response = Apdu::new(INS_INITIALIZE_UPDATE)
+            .cla(0x80)
+            .params(keys.key_version, 0x00)
+            .data(host_challenge)
+            .transmit(txn, 261)?;
+
+        match response.status_words() {
+            StatusWords::Success => (),
+            StatusWords::NotSupportedError | StatusWords::Other(0x6e00) => {
+                error!("YubiKey doesn't support SCP03");
+                return Err(Error::NotSupported);
+            }
+            sw => {
+                error!("INITIALIZE UPDATE failed: {:04x}", sw.code());
+                return Err(Error::AuthenticationError);
+            }
+        }
+
+        // Key diversification data (10 bytes), key information (3 bytes),
+        // card challenge and card cryptogram
+        let data = response.data();
+        if data.len() < 29 || data[11] != SCP03 {
+            error!("unexpected response to INITIALIZE UPDATE");
+            return Err(Error::SecureChannelError);
+        }
+
+        let mut context = host_challenge.to_vec();
+        context.extend_from_slice(&data[13..21]);
+
+        let mut session = Self::new(keys, &context);
+        let card_cryptogram = session.cryptogram(DERIVE_CARD_CRYPTOGRAM, &context);
+
+        if !bool::from(card_cryptogram.ct_eq(&data[21..29])) {
+            error!("card cryptogram mismatch: wrong SCP03 keys?");
+            return Err(Error::AuthenticationError);
+        }
+
+        let host_cryptogram = session.cryptogram(DERIVE_HOST_CRYPTOGRAM, &context);
+        let header = [
+            0x80 | CLA_SECURE,
+            INS_EXTERNAL_AUTHENTICATE,
+            SECURITY_LEVEL,
+            0x00,
+            (2 * HALF_BLOCK_LEN) as u8,
+        ];
+        let mac = session.command_mac(&[&header[..], &host_cryptogram].concat());
+
+        let response = Apdu::new(INS_EXTERNAL_AUTHENTICATE)
+            .cla(header[0])
+            .params(SECURITY_LEVEL, 0x00)
+            .data([host_cryptogram, mac].concat())
+            .transmit(txn, 261)?;
+
+        if !response.is_success() {
+            error!(
+                "EXTERNAL AUTHENTICATE failed: {:04x}",
+                response.status_words().code()
+            );
+            return Err(Error::AuthenticationError);
+        }
+
+        Ok(session)
+    }
+
+    /// Encrypt and MAC a serialized command APDU, chaining it in short APDUs.
+    fn wrap(&mut self, command: &[u8]) -> Result<Vec<Buffer>> {
+        let (cla, ins, p1, p2, data) = parse_command(command)?;
+        let cla = cla | CLA_SECURE;
+
+        let mut body = if data.is_empty() {
+            vec![]
+        } else {
+            cbc_encrypt(&self.enc, self.iv(0x00, self.counter), data)
+        };
+        self.counter = self.counter.wrapping_add(1);
+
+        let mac =
+            self.command_mac(&[&mac_header(cla, ins, p1, p2, body.len())[..], &body].concat());
+        body.extend_from_slice(&mac);
+
+        let mut chunks = body.chunks(CHUNK_LEN).peekable();
+        let mut apdus = vec![];
+
+        while let Some(chunk) = chunks.next() {
+            let cla = if chunks.peek().is_some() {
+                cla | 0x10
+            } else {
+                cla
+            };
+            let mut apdu = vec![cla, ins, p1, p2, chunk.len() as u8];
+            apdu.extend_from_slice(chunk);
+            apdus.push(Zeroizing::new(apdu));
+        }
+
+        Ok(apdus)
+    }
+
+    /// Check the R-MAC of a response APDU and decrypt its data.
+    ///
+    /// Errors, which have no R-MAC, are returned as they are.
+    fn unwrap(&self, response: &[u8]) -> Result<Buffer> {
+        if response.len() < 2 {
+            return Err(Error::SecureChannelError);
+        }
+
+        let (data, sw) = response.split_at(response.len() - 2);
+
+        if data.is_empty() && !has_response_mac(sw) {
+            return Ok(Zeroizing::new(sw.to_vec()));
+        }
+
+        if data.len() < HALF_BLOCK_LEN {
+            error!("response has no R-MAC");
+            return Err(Error::SecureChannelError);
+        }
+
+        let (data, mac) = data.split_at(data.len() - HALF_BLOCK_LEN);
+
+        if !bool::from(self.response_mac(data, sw).ct_eq(mac)) {
+            error!("R-MAC mismatch: response isn't authentic");
+            return Err(Error::SecureChannelError);
+        }
+
+        let mut plaintext = if data.is_empty() {
+            Zeroizing::new(vec![])
+        } else {
+            let iv = self.iv(0x80, self.counter.wrapping_sub(1));
+            cbc_decrypt(&self.enc, iv, data)?
+        };
+
+        plaintext.extend_from_slice(sw);
+        Ok(plaintext)
+    }
+
+    /// Check the C-MAC of a command received by the card and decrypt its
+    /// data, as the emulator does.
+    #[cfg(feature = "emulator")]
+    pub fn unwrap_command(
+        &mut self,
+        cla: u8,
+        ins: u8,
+        p1: u8,
+        p2: u8,
+        body: &[u8],
+    ) -> Option<Buffer> {
+        if body.len() < HALF_BLOCK_LEN {
+            return None;
+        }
+
+        let (data, mac) = body.split_at(body.len() - HALF_BLOCK_LEN);
+        let expected =
+            self.command_mac(&[&mac_header(cla, ins, p1, p2, data.len())[..], data].concat());
+
+        if !bool::from(expected.ct_eq(mac)) {
+            return None;
+        }
+
+        let counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+
+        if data.is_empty() {
+            return Some(Zeroizing::new(vec![]));
+        }
+
+        let iv = self.iv(0x00, counter);
+        cbc_decrypt(&self.enc, iv, data).ok()
+    }
+
+    /// Encrypt and MAC the data of a response (successful, or with a
+    /// warning) sent by the card, as the emulator does.
+    #[cfg(feature = "emulator")]
+    pub fn wrap_response(&self, data: &[u8], sw: StatusWords) -> Vec<u8> {
+        let mut body = if data.is_empty() {
+            vec![]
+        } else {
+            let iv = self.iv(0x80, self.counter.wrapping_sub(1));
+            cbc_encrypt(&self.enc, iv, data)
+        };
+
+        let mac = self.response_mac(&body, &sw.code().to_be_bytes());
+        body.extend_from_slice(&mac);
+        body
+    }
+}
+
+/// Do responses with the given status words have an R-MAC? Only errors
+/// don't: successes and warnings (`62xx` and `63xx`, e.g. a wrong PIN) do.
+pub(crate) fn has_response_mac(sw: &[u8]) -> bool {
+    matches!(sw, [0x90, 0x00] | [0x61..=0x63, _])
+}
+
+/// Split a serialized command APDU (short or extended) into its class,
+/// instruction, parameters and data.
+fn parse_command(command: &[u8]) -> Result<(u8, u8, u8, u8, &[u8])> {
+    match command {
+        [cla, ins, p1, p2] | [cla, ins, p1, p2, _] | [cla, ins, p1, p2, 0, _, _] => {
+            Ok((*cla, *ins, *p1, *p2, &[]))
+        }
+        [cla, ins, p1, p2, 0, lc1, lc2, rest @ ..] => {
+            let lc = usize::from(u16::from_be_bytes([*lc1, *lc2]));
+            let data = rest.get(..lc).ok_or(Error::SizeError)?;
+            Ok((*cla, *ins, *p1, *p2, data))
+        }
+        [cla, ins, p1, p2, lc, rest @ ..] => {
+            let data = rest.get(..usize::from(*lc)).ok_or(Error::SizeError)?;
+            Ok((*cla, *ins, *p1, *p2, data))
+        }
+        _ => Err(Error::SizeError),
+    }
+}
+
+/// Header of a secured command as covered by its C-MAC, whose Lc includes
+/// the C-MAC itself.
+fn mac_header(cla: u8, ins: u8, p1: u8, p2: u8, data_len: usize) -> Vec<u8> {
+    let lc = data_len + HALF_BLOCK_LEN;
+
+    if lc > CHUNK_LEN {
+        let [_, _, hi, lo] = (lc as u32).to_be_bytes();
+        vec![cla, ins, p1, p2, 0, hi, lo]
+    } else {
+        vec![cla, ins, p1, p2, lc as u8]
+    }
+}
+
+/// Key derivation function: NIST SP 800-108 in counter mode, with AES-CMAC
+/// (GlobalPlatform Amendment D, section 4.1.5). At most 128 bits are output.
+fn derive(key: &[u8; BLOCK_LEN], constant: u8, context: &[u8], bits: u16) -> [u8; BLOCK_LEN] {
+    let mut input = vec![0u8; 11];
+    input.push(constant);
+    input.push(0x00);
+    input.extend_from_slice(&bits.to_be_bytes());
+    input.push(0x01);
+    input.extend_from_slice(context);
+    cmac(key, &input)
+}
+
+/// AES-CMAC (NIST SP 800-38B).
+pub(crate) fn cmac(key: &[u8; BLOCK_LEN], message: &[u8]) -> [u8; BLOCK_LEN] {
+    let mut mac = <Cmac<Aes128> as KeyInit>::new(GenericArray::from_slice(key));
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn truncate(block: &[u8; BLOCK_LEN]) -> [u8; HALF_BLOCK_LEN] {
+    let mut truncated = [0u8; HALF_BLOCK_LEN];
+    truncated.copy_from_slice(&block[..HALF_BLOCK_LEN]);
+    truncated
+}
+
+fn encrypt_block(key: &[u8; BLOCK_LEN], block: [u8; BLOCK_LEN]) -> [u8; BLOCK_LEN] {
+    let mut block = GenericArray::from(block);
+    Aes128::new(GenericArray::from_slice(key)).encrypt_block(&mut block);
+    block.into()
+}
+
+/// AES-CBC encryption, padding the data to a whole number of blocks
+/// (ISO/IEC 9797-1 method 2).
+fn cbc_encrypt(key: &[u8; BLOCK_LEN], iv: [u8; BLOCK_LEN], data: &[u8]) -> Vec<u8> {
+    cbc::Encryptor::<Aes128>::new(key.into(), &iv.into()).encrypt_padded_vec_mut::<Iso7816>(data)
+}
+
+/// AES-CBC decryption, removing the padding added by [`cbc_encrypt`].
+fn cbc_decrypt(key: &[u8; BLOCK_LEN], iv: [u8; BLOCK_LEN], data: &[u8]) -> Result<Buffer> {
+    if data.len() % BLOCK_LEN != 0 {
+        error!("secure channel data isn't a whole number of blocks");
+        return Err(Error::SecureChannelError);
+    }
+
+    let mut plaintext = Zeroizing::new(data.to_vec());
+    let len = cbc::Decryptor::<Aes128>::new(key.into(), &iv.into())
+        .decrypt_padded_mut::<Iso7816>(&mut plaintext)
+        .map_err(|_| {
+            error!("invalid padding in secure channel data");
+            Error::SecureChannelError
+        })?
+        .len();
+
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC4493_KEY: [u8; BLOCK_LEN] = [
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f,
+        0x3c,
+    ];
+
+    #[test]
+    fn cmac_test_vectors() {
+        assert_eq!(
+            cmac(&RFC4493_KEY, &[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+
+        let message = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        assert_eq!(
+            cmac(&RFC4493_KEY, &message),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+    }
+
+    #[test]
+    fn padding_round_trip() {
+        let iv = [0x42; BLOCK_LEN];
+
+        for len in [0, 1, 15, 16, 17] {
+            let data = vec![0u8; len];
+            let ciphertext = cbc_encrypt(&RFC4493_KEY, iv, &data);
+            assert_eq!(ciphertext.len() % BLOCK_LEN, 0);
+            assert!(ciphertext.len() > len);
+            assert_eq!(
+                &cbc_decrypt(&RFC4493_KEY, iv, &ciphertext).expect("decrypt")[..],
+                &data[..]
+            );
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! SCP11 secure channel.
+//!
+//! GlobalPlatform's Secure Channel Protocol 11 establishes session keys with
+//! elliptic curve key agreement instead of the static keys of
+//! [SCP03](crate::scp03), authenticating the YubiKey with a certificate
+//! chain. It is meant for deployments where no symmetric keys can be shared
+//! with the hosts, notably contactless provisioning over NFC.
+//!
+//! Two variants are supported:
+//!
+//! - SCP11b only authenticates the YubiKey: the host checks its security
+//!   domain (SD) certificate chain against its trust anchors.
+//! - SCP11a also authenticates the host, or off-card entity (OCE), with a
+//!   key and certificate chain issued by a CA known to the YubiKey.
+//!
+//! Open a YubiKey with [`YubiKey::open_with_scp11`], or secure an open one
+//! with [`YubiKey::enable_scp11`]. As with SCP03, the secure channel is then
+//! opened each time the PIV application is selected, and commands and
+//! responses are encrypted and MACed with the session keys.
+//!
+//! Only P-256 keys are supported. The SD certificates must be valid at the
+//! time of the YubiKey's [`Clock`](crate::clock::Clock), and all but the
+//! last one must be CA certificates; revocation isn't checked.
+//!
+//! [`YubiKey::open_with_scp11`]: crate::YubiKey::open_with_scp11
+//! [`YubiKey::enable_scp11`]: crate::YubiKey::enable_scp11
+
+use crate::{
+    apdu::StatusWords,
+    certificate::Certificate,
+    consts::CB_BUF_MAX,
+    error::{Error, Result},
+    scp03::{cmac, Session, BLOCK_LEN},
+    serialization::Tlv,
+    transaction::Transaction,
+};
+use log::error;
+use p256::{ecdh, elliptic_curve::sec1::ToEncodedPoint, PublicKey, SecretKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use x509_cert::der::{Reader, SliceReader};
+use zeroize::Zeroizing;
+
+/// GET DATA instruction, used to read the SD certificate chain.
+pub(crate) const INS_GET_DATA: u8 = 0xca;
+
+/// PERFORM SECURITY OPERATION instruction, sending OCE certificates.
+pub(crate) const INS_PERFORM_SECURITY_OPERATION: u8 = 0x2a;
+
+/// MUTUAL AUTHENTICATE instruction, opening an SCP11a session.
+pub(crate) const INS_MUTUAL_AUTHENTICATE: u8 = 0x82;
+
+/// INTERNAL AUTHENTICATE instruction, opening an SCP11b session.
+pub(crate) const INS_INTERNAL_AUTHENTICATE: u8 = 0x88;
+
+/// Tag of the SD certificate store data object.
+pub(crate) const TAG_CERTIFICATE_STORE: u16 = 0xbf21;
+
+/// Tag of ephemeral public keys.
+pub(crate) const TAG_EPHEMERAL_KEY: u16 = 0x5f49;
+
+/// Tag of the receipt returned by the YubiKey.
+pub(crate) const TAG_RECEIPT: u16 = 0x86;
+
+/// Key identifiers of the SD keys.
+pub(crate) const KID_SCP11A: u8 = 0x11;
+pub(crate) const KID_SCP11B: u8 = 0x13;
+
+/// Key identifier of the OCE CA key, used to verify OCE certificates.
+pub(crate) const KID_OCE_CA: u8 = 0x10;
+
+/// Key usage qualifier: secure messaging with command and response
+/// encryption and MACs.
+const KEY_USAGE: u8 = 0x3c;
+
+/// Key type of the session keys: AES.
+const KEY_TYPE: u8 = 0x88;
+
+/// Number of session keys derived: receipt, S-ENC, S-MAC, S-RMAC and DEK.
+const SESSION_KEYS: usize = 5;
+
+/// OCE key and certificate chain, used to authenticate the host with SCP11a.
+#[derive(Clone)]
+struct Oce {
+    ca_key_version: u8,
+    key: SecretKey,
+    certificates: Vec<Certificate>,
+}
+
+/// Parameters of an SCP11 secure channel.
+#[derive(Clone)]
+pub struct Scp11Params {
+    key_version: u8,
+    trust_anchors: Vec<Certificate>,
+    oce: Option<Oce>,
+}
+
+impl Scp11Params {
+    /// Parameters of an SCP11b secure channel, authenticating the YubiKey
+    /// with the SD key of the given key version.
+    ///
+    /// The SD certificate chain must be issued by one of the trust anchors.
+    pub fn scp11b(key_version: u8, trust_anchors: Vec<Certificate>) -> Self {
+        Self {
+            key_version,
+            trust_anchors,
+            oce: None,
+        }
+    }
+
+    /// Parameters of an SCP11a secure channel, also authenticating the host
+    /// with the given OCE key.
+    ///
+    /// The OCE certificate chain (leaf last, for the OCE key) must be issued
+    /// by the OCE CA key of the given key version on the YubiKey.
+    pub fn scp11a(
+        key_version: u8,
+        trust_anchors: Vec<Certificate>,
+        oce_ca_key_version: u8,
+        oce_key: SecretKey,
+        oce_certificates: Vec<Certificate>,
+    ) -> Self {
+        Self {
+            key_version,
+            trust_anchors,
+            oce: Some(Oce {
+                ca_key_version: oce_ca_key_version,
+                key: oce_key,
+                certificates: oce_certificates,
+            }),
+        }
+    }
+
+    /// Get the key version number of the SD key.
+    pub fn key_version(&self) -> u8 {
+        self.key_version
+    }
+
+    /// Does the secure channel authenticate the host (SCP11a)?
+    pub fn is_mutual(&self) -> bool {
+        self.oce.is_some()
+    }
+
+    /// Get the key identifier of the SD key.
+    fn key_id(&self) -> u8 {
+        if self.is_mutual() {
+            KID_SCP11A
+        } else {
+            KID_SCP11B
+        }
+    }
+}
+
+impl fmt::Debug for Scp11Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scp11Params")
+            .field("key_version", &self.key_version)
+            .field("mutual", &self.is_mutual())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Start a session: read and verify the SD certificate chain, send the OCE
+/// certificates (SCP11a), then agree on session keys with INTERNAL or MUTUAL
+/// AUTHENTICATE and check the receipt.
+pub(crate) fn open(txn: &Transaction<'_>, params: &Scp11Params) -> Result<Session> {
+    let sd_key = sd_public_key(txn, params)?;
+
+    if let Some(oce) = &params.oce {
+        send_oce_certificates(txn, oce)?;
+    }
+
+    let ephemeral = SecretKey::random(&mut OsRng);
+    let command = authentication_data(params.is_mutual(), &ephemeral.public_key());
+
+    let (ins, name) = if params.is_mutual() {
+        (INS_MUTUAL_AUTHENTICATE, "MUTUAL AUTHENTICATE")
+    } else {
+        (INS_INTERNAL_AUTHENTICATE, "INTERNAL AUTHENTICATE")
+    };
+    let response = txn.transfer_data(
+        &[0x80, ins, params.key_version, params.key_id()],
+        &command,
+        261,
+    )?;
+
+    if !response.is_success() {
+        error!("{} failed: {:04x}", name, response.status_words().code());
+        return Err(Error::AuthenticationError);
+    }
+
+    let (rest, card_ephemeral) = Tlv::parse(response.data()).map_err(|_| {
+        error!("unexpected response to {}", name);
+        Error::SecureChannelError
+    })?;
+    let card_ephemeral_tlv = &response.data()[..response.data().len() - rest.len()];
+    let receipt = match Tlv::parse(rest) {
+        Ok((_, receipt)) if receipt.tag == TAG_RECEIPT => receipt.value,
+        _ => {
+            error!("missing receipt in response to {}", name);
+            return Err(Error::SecureChannelError);
+        }
+    };
+
+    if card_ephemeral.tag != TAG_EPHEMERAL_KEY {
+        error!("missing ephemeral key in response to {}", name);
+        return Err(Error::SecureChannelError);
+    }
+
+    let card_ephemeral =
+        PublicKey::from_sec1_bytes(card_ephemeral.value).map_err(|_| Error::SecureChannelError)?;
+    let static_key = params.oce.as_ref().map_or(&ephemeral, |oce| &oce.key);
+
+    let mut shared_secrets = Zeroizing::new(diffie_hellman(&ephemeral, &card_ephemeral).to_vec());
+    shared_secrets.extend_from_slice(&*diffie_hellman(static_key, &sd_key));
+
+    let (session, expected_receipt) = session(
+        &shared_secrets,
+        &[&command[..], card_ephemeral_tlv].concat(),
+    );
+
+    if !bool::from(expected_receipt.ct_eq(receipt)) {
+        error!("SCP11 receipt mismatch: YubiKey isn't authentic");
+        return Err(Error::AuthenticationError);
+    }
+
+    Ok(session)
+}
+
+/// Read the SD certificate chain and verify it against the trust anchors,
+/// returning the SD public key.
+fn sd_public_key(txn: &Transaction<'_>, params: &Scp11Params) -> Result<PublicKey> {
+    let key_ref = Tlv::encode(0x83, &[params.key_id(), params.key_version]);
+    let [p1, p2] = TAG_CERTIFICATE_STORE.to_be_bytes();
+
+    let response = txn.transfer_data(
+        &[0x00, INS_GET_DATA, p1, p2],
+        &Tlv::encode(0xa6, &key_ref),
+        CB_BUF_MAX,
+    )?;
+
+    match response.status_words() {
+        StatusWords::Success => (),
+        StatusWords::NotFoundError => {
+            error!("YubiKey has no SCP11 key {:02x}", params.key_version);
+            return Err(Error::NotFound);
+        }
+        StatusWords::NotSupportedError | StatusWords::Other(0x6e00) => {
+            error!("YubiKey doesn't support SCP11");
+            return Err(Error::NotSupported);
+        }
+        sw => {
+            error!("reading SD certificates failed: {:04x}", sw.code());
+            return Err(Error::GenericError);
+        }
+    }
+
+    let certificates = parse_certificates(response.data())?;
+    let (first, leaf) = match (certificates.first(), certificates.last()) {
+        (Some(first), Some(leaf)) => (first, leaf),
+        _ => {
+            error!("YubiKey returned no SD certificates");
+            return Err(Error::AuthenticationError);
+        }
+    };
+
+    let anchored = params.trust_anchors.iter().any(|anchor| {
+        anchor.as_der() == first.as_der() || first.verify_with_key(anchor.subject_pki()).is_ok()
+    });
+
+    if !anchored {
+        error!("SD certificate chain isn't issued by a trust anchor");
+        return Err(Error::AuthenticationError);
+    }
+
+    for pair in certificates.windows(2) {
+        if !pair[0].is_ca() {
+            error!(
+                "SD certificate {} isn't a CA certificate",
+                pair[0].subject()
+            );
+            return Err(Error::AuthenticationError);
+        }
+
+        if pair[1].verify_with_key(pair[0].subject_pki()).is_err() {
+            error!("invalid SD certificate chain: {}", pair[1].subject());
+            return Err(Error::AuthenticationError);
+        }
+    }
+
+    let now = txn.now();
+    for certificate in &certificates {
+        let validity = &certificate.cert.tbs_certificate.validity;
+
+        if now < validity.not_before.to_system_time() || now > validity.not_after.to_system_time() {
+            error!("SD certificate {} isn't valid now", certificate.subject());
+            return Err(Error::AuthenticationError);
+        }
+    }
+
+    PublicKey::from_sec1_bytes(leaf.subject_pki().subject_public_key.raw_bytes()).map_err(|_| {
+        error!("SD certificate doesn't hold a P-256 key");
+        Error::AuthenticationError
+    })
+}
+
+/// Send the OCE certificate chain with PERFORM SECURITY OPERATION.
+fn send_oce_certificates(txn: &Transaction<'_>, oce: &Oce) -> Result<()> {
+    for (index, certificate) in oce.certificates.iter().enumerate() {
+        let p2 = if index + 1 < oce.certificates.len() {
+            KID_OCE_CA | 0x80
+        } else {
+            KID_OCE_CA
+        };
+
+        let response = txn.transfer_data(
+            &[0x80, INS_PERFORM_SECURITY_OPERATION, oce.ca_key_version, p2],
+            certificate.as_der(),
+            261,
+        )?;
+
+        if !response.is_success() {
+            error!(
+                "YubiKey rejected OCE certificate {}: {:04x}",
+                certificate.subject(),
+                response.status_words().code()
+            );
+            return Err(Error::AuthenticationError);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse concatenated DER certificates.
+fn parse_certificates(data: &[u8]) -> Result<Vec<Certificate>> {
+    let mut reader = SliceReader::new(data).map_err(|_| Error::InvalidObject)?;
+    let mut certificates = vec![];
+
+    while !reader.is_finished() {
+        let der = reader.tlv_bytes().map_err(|_| Error::InvalidObject)?;
+        certificates.push(Certificate::from_bytes(der.to_vec())?);
+    }
+
+    Ok(certificates)
+}
+
+/// Build the data of INTERNAL or MUTUAL AUTHENTICATE: the key agreement
+/// parameters, followed by the host's ephemeral public key.
+fn authentication_data(mutual: bool, ephemeral: &PublicKey) -> Vec<u8> {
+    let parameters = [
+        Tlv::encode(0x90, &[0x11, u8::from(mutual)]),
+        Tlv::encode(0x95, &[KEY_USAGE]),
+        Tlv::encode(0x80, &[KEY_TYPE]),
+        Tlv::encode(0x81, &[BLOCK_LEN as u8]),
+    ]
+    .concat();
+
+    [
+        Tlv::encode(0xa6, &parameters),
+        Tlv::encode(
+            TAG_EPHEMERAL_KEY,
+            ephemeral.to_encoded_point(false).as_bytes(),
+        ),
+    ]
+    .concat()
+}
+
+/// Derive the session keys from the shared secrets, returning the session
+/// and the receipt expected over the key agreement data.
+pub(crate) fn session(shared_secrets: &[u8], key_agreement: &[u8]) -> (Session, [u8; BLOCK_LEN]) {
+    let keys = x963_kdf(
+        shared_secrets,
+        &[KEY_USAGE, KEY_TYPE, BLOCK_LEN as u8],
+        SESSION_KEYS * BLOCK_LEN,
+    );
+    let key = |index: usize| {
+        let mut key = [0u8; BLOCK_LEN];
+        key.copy_from_slice(&keys[index * BLOCK_LEN..(index + 1) * BLOCK_LEN]);
+        key
+    };
+
+    let receipt = cmac(&key(0), key_agreement);
+    (Session::from_keys(key(1), key(2), key(3), receipt), receipt)
+}
+
+/// ANSI X9.63 key derivation function with SHA-256, as in the
+/// `ansi-x963-kdf` crate (which needs a newer `digest` than `sha2` 0.10).
+fn x963_kdf(secret: &[u8], shared_info: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+    let mut output = Zeroizing::new(Vec::with_capacity(len));
+    let mut counter = 1u32;
+
+    while output.len() < len {
+        let digest = Sha256::new()
+            .chain_update(secret)
+            .chain_update(counter.to_be_bytes())
+            .chain_update(shared_info)
+            .finalize();
+        output.extend_from_slice(&digest);
+        counter += 1;
+    }
+
+    output.truncate(len);
+    output
+}
+
+/// ECDH over P-256, returning the x-coordinate of the shared point.
+pub(crate) fn diffie_hellman(secret: &SecretKey, public: &PublicKey) -> Zeroizing<[u8; 32]> {
+    let shared = ecdh::diffie_hellman(secret.to_nonzero_scalar(), public.as_affine());
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    bytes.copy_from_slice(shared.raw_secret_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x963_kdf_test_vector() {
+        let key = x963_kdf(&[0x00], &[0x00], 32);
+        assert_eq!(
+            &key[..],
+            [
+                0x58, 0x86, 0x11, 0xf6, 0x57, 0x41, 0xc1, 0x71, 0xa3, 0xd9, 0x2c, 0x1d, 0x53, 0x43,
+                0xf5, 0xdd, 0x67, 0xf4, 0xfc, 0x47, 0x2f, 0xc5, 0x6f, 0x01, 0xc9, 0xbc, 0x56, 0x8f,
+                0x5a, 0xc2, 0xa6, 0x23
+            ]
+        );
+
+        // Output longer than a digest, truncated
+        let key = x963_kdf(&[0x00], &[], 40);
+        assert_eq!(&key[32..], [0x89, 0xee, 0xfc, 0x18, 0xfa, 0x4b, 0x81, 0x5b]);
+    }
+
+    #[test]
+    fn tlv_round_trip() {
+        let encoded = [
+            Tlv::encode(TAG_EPHEMERAL_KEY, &[0xaa; 65]),
+            Tlv::encode(TAG_RECEIPT, &[0xbb; 16]),
+        ]
+        .concat();
+
+        let (rest, ephemeral) = Tlv::parse(&encoded).expect("ephemeral key");
+        assert_eq!(ephemeral.tag, TAG_EPHEMERAL_KEY);
+        assert_eq!(ephemeral.value, &[0xaa; 65][..]);
+
+        let (rest, receipt) = Tlv::parse(rest).expect("receipt");
+        assert_eq!(receipt.tag, TAG_RECEIPT);
+        assert_eq!(receipt.value, &[0xbb; 16][..]);
+        assert!(rest.is_empty());
+
+        assert!(Tlv::parse(&encoded[..10]).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+//! Encrypted storage of provisioning secrets.
+//!
+//! Provisioning a YubiKey is often split across several steps (or machines),
+//! and the PIN, PUK and management key chosen in one step need to be kept
+//! safely until the next. [`Secrets`] can be sealed into an encrypted bundle
+//! under a key-encryption key (KEK) which is either:
+//!
+//! - derived from a passphrase with PBKDF2-HMAC-SHA256, or
+//! - agreed with an ECC key held in a PIV slot (ECDH with an ephemeral key),
+//!   so that the bundle can only be opened with that YubiKey.
+//!
+//! Bundles are encrypted with AES-256-GCM, and decrypted secrets are zeroized
+//! when dropped.
+
+use crate::{
+    error::{Error, Result},
+    piv::{AlgorithmId, SlotId},
+    serialization::Tlv,
+    Buffer,
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use hkdf::Hkdf;
+use log::error;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::fmt;
+use x509_cert::{der::oid::AssociatedOid, spki::SubjectPublicKeyInfoRef};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "untested")]
+use crate::{piv, yubikey::YubiKey};
+
+/// Magic bytes at the start of every bundle.
+const MAGIC: &[u8; 4] = b"YKSB";
+
+/// Version of the bundle format.
+const FORMAT_VERSION: u8 = 1;
+
+/// KEK derived from a passphrase.
+const KEK_PASSPHRASE: u8 = 0x01;
+
+/// KEK agreed with the key in a PIV slot.
+const KEK_SLOT: u8 = 0x02;
+
+/// PBKDF2 iterations used when sealing with a passphrase.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Fewest PBKDF2 iterations accepted when opening a bundle, so a tampered
+/// bundle can't weaken the KEK derivation: those sealed by this crate.
+const MIN_PBKDF2_ITERATIONS: u32 = PBKDF2_ITERATIONS;
+
+/// Most PBKDF2 iterations accepted when opening a bundle, so a tampered
+/// bundle can't make opening it take hours.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Size of the PBKDF2 salt.
+const SALT_LEN: usize = 16;
+
+/// Size of the AES-GCM nonce.
+const NONCE_LEN: usize = 12;
+
+/// HKDF info used when deriving a KEK from an ECDH shared secret.
+const HKDF_INFO: &[u8] = b"yubikey.rs secrets bundle";
+
+const TAG_PIN: u16 = 0x01;
+const TAG_PUK: u16 = 0x02;
+const TAG_MGM_KEY: u16 = 0x03;
+
+/// Secrets chosen while provisioning a YubiKey.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    /// PIN
+    pub pin: Option<Buffer>,
+
+    /// PIN Unblocking Key
+    pub puk: Option<Buffer>,
+
+    /// Management key
+    pub mgm_key: Option<Buffer>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("pin", &self.pin.as_ref().map(|_| "<redacted>"))
+            .field("puk", &self.puk.as_ref().map(|_| "<redacted>"))
+            .field("mgm_key", &self.mgm_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Secrets {
+    /// Seal these secrets into a bundle encrypted under a KEK derived from
+    /// the given passphrase.
+    pub fn seal_with_passphrase(&self, passphrase: &[u8]) -> Result<Vec<u8>> {
+        self.seal_with_passphrase_iterations(passphrase, PBKDF2_ITERATIONS)
+    }
+
+    fn seal_with_passphrase_iterations(
+        &self,
+        passphrase: &[u8],
+        iterations: u32,
+    ) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut header = header(MAGIC, KEK_PASSPHRASE);
+        header.extend_from_slice(&iterations.to_be_bytes());
+        header.extend_from_slice(&salt);
+
+        let kek = passphrase_kek(passphrase, &salt, iterations);
+        seal(header, &self.encode()?, &kek)
+    }
+
+    /// Seal these secrets into a bundle which can only be opened with the
+    /// ECC key in the given slot, whose public key is `public_key`.
+    ///
+    /// Only the public key is needed to seal the bundle, so this doesn't
+    /// require access to the YubiKey. Opening the bundle requires the key's
+    /// PIN and touch policies to be satisfied.
+    pub fn seal_to_slot(
+        &self,
+        slot: SlotId,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<Vec<u8>> {
+        seal_to_slot(MAGIC, &self.encode()?, slot, public_key)
+    }
+
+    /// Open a bundle sealed with [`Secrets::seal_with_passphrase`].
+    ///
+    /// Returns [`Error::AuthenticationError`] if the passphrase is wrong or
+    /// the bundle has been tampered with, and [`Error::ParseError`] if the
+    /// bundle's PBKDF2 iteration count is outside of the accepted range
+    /// (600,000 to 10,000,000 iterations).
+    pub fn open_with_passphrase(bundle: &[u8], passphrase: &[u8]) -> Result<Self> {
+        let mut reader = BundleReader::new(bundle, MAGIC, KEK_PASSPHRASE)?;
+        let iterations = u32::from_be_bytes(reader.take(4)?.try_into()?);
+        let salt = reader.take(SALT_LEN)?;
+
+        if !(MIN_PBKDF2_ITERATIONS..=MAX_PBKDF2_ITERATIONS).contains(&iterations) {
+            error!(
+                "secrets bundle uses {} PBKDF2 iterations, outside of the accepted range",
+                iterations
+            );
+            return Err(Error::ParseError);
+        }
+
+        let kek = passphrase_kek(passphrase, salt, iterations);
+        Self::decode(&reader.open(&kek)?)
+    }
+
+    /// Open a bundle sealed with [`Secrets::seal_to_slot`], using the key in
+    /// the slot it was sealed to.
+    #[cfg(feature = "untested")]
+    pub fn open_with_slot(bundle: &[u8], yubikey: &mut YubiKey) -> Result<Self> {
+        Self::decode(&open_with_slot(MAGIC, bundle, yubikey)?)
+    }
+
+    fn encode(&self) -> Result<Buffer> {
+        let fields = [
+            (TAG_PIN, &self.pin),
+            (TAG_PUK, &self.puk),
+            (TAG_MGM_KEY, &self.mgm_key),
+        ];
+
+        let len = fields
+            .iter()
+            .filter_map(|(_, value)| value.as_ref())
+            .map(|value| value.len() + 4)
+            .sum();
+
+        let mut buffer = Buffer::new(vec![0u8; len]);
+        let mut offset = 0;
+
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                offset += Tlv::write(&mut buffer[offset..], tag, value)?;
+            }
+        }
+
+        buffer.truncate(offset);
+        Ok(buffer)
+    }
+
+    fn decode(mut buffer: &[u8]) -> Result<Self> {
+        let mut secrets = Self::default();
+
+        while !buffer.is_empty() {
+            let (rest, tlv) = Tlv::parse(buffer)?;
+            let value = Some(Buffer::new(tlv.value.to_vec()));
+
+            match tlv.tag {
+                TAG_PIN => secrets.pin = value,
+                TAG_PUK => secrets.puk = value,
+                TAG_MGM_KEY => secrets.mgm_key = value,
+                _ => return Err(Error::ParseError),
+            }
+
+            buffer = rest;
+        }
+
+        Ok(secrets)
+    }
+}
+
+/// Seal the given plaintext into a bundle which can only be opened with the
+/// ECC key in the given slot, whose public key is `public_key`.
+pub(crate) fn seal_to_slot(
+    magic: &[u8; 4],
+    plaintext: &[u8],
+    slot: SlotId,
+    public_key: SubjectPublicKeyInfoRef<'_>,
+) -> Result<Vec<u8>> {
+    let (algorithm, ephemeral, shared) = agree_ephemeral(public_key)?;
+
+    let mut header = header(magic, KEK_SLOT);
+    header.push(slot.into());
+    header.push(algorithm.into());
+    header.push(u8::try_from(ephemeral.len()).map_err(|_| Error::SizeError)?);
+    header.extend_from_slice(&ephemeral);
+
+    let kek = slot_kek(&shared, &ephemeral, HKDF_INFO)?;
+    seal(header, plaintext, &kek)
+}
+
+/// Agree on a secret with the given P-256 or P-384 public key using an
+/// ephemeral key, returning the key's algorithm, the ephemeral public key and
+/// the shared secret.
+pub(crate) fn agree_ephemeral(
+    public_key: SubjectPublicKeyInfoRef<'_>,
+) -> Result<(AlgorithmId, Vec<u8>, Zeroizing<Vec<u8>>)> {
+    let curve = public_key
+        .algorithm
+        .parameters_oid()
+        .map_err(|_| Error::AlgorithmError)?;
+
+    let (algorithm, ephemeral, shared) = if curve == p256::NistP256::OID {
+        let public_key = p256::PublicKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+        let secret = p256::ecdh::EphemeralSecret::random(&mut OsRng);
+        let ephemeral = p256::EncodedPoint::from(secret.public_key());
+        let shared = secret.diffie_hellman(&public_key);
+        (
+            AlgorithmId::EccP256,
+            ephemeral.as_bytes().to_vec(),
+            Zeroizing::new(shared.raw_secret_bytes().to_vec()),
+        )
+    } else if curve == p384::NistP384::OID {
+        let public_key = p384::PublicKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+        let secret = p384::ecdh::EphemeralSecret::random(&mut OsRng);
+        let ephemeral = p384::EncodedPoint::from(secret.public_key());
+        let shared = secret.diffie_hellman(&public_key);
+        (
+            AlgorithmId::EccP384,
+            ephemeral.as_bytes().to_vec(),
+            Zeroizing::new(shared.raw_secret_bytes().to_vec()),
+        )
+    } else {
+        error!("only P-256 or P-384 keys can be used for key agreement");
+        return Err(Error::AlgorithmError);
+    };
+
+    Ok((algorithm, ephemeral, shared))
+}
+
+/// Open a bundle sealed with [`seal_to_slot`], using the key in the slot it
+/// was sealed to.
+#[cfg(feature = "untested")]
+pub(crate) fn open_with_slot(
+    magic: &[u8; 4],
+    bundle: &[u8],
+    yubikey: &mut YubiKey,
+) -> Result<Buffer> {
+    let mut reader = BundleReader::new(bundle, magic, KEK_SLOT)?;
+    let slot = SlotId::try_from(reader.take(1)?[0])?;
+    let algorithm = AlgorithmId::try_from(reader.take(1)?[0])?;
+    let ephemeral_len = usize::from(reader.take(1)?[0]);
+    let ephemeral = reader.take(ephemeral_len)?;
+
+    let shared = piv::decrypt_data(yubikey, ephemeral, algorithm, slot)?;
+    let kek = slot_kek(&shared, ephemeral, HKDF_INFO)?;
+    reader.open(&kek)
+}
+
+/// Encrypt the given plaintext, authenticating the given header.
+fn seal(mut bundle: Vec<u8>, plaintext: &[u8], kek: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = Aes256Gcm::new(kek.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &bundle,
+            },
+        )
+        .map_err(|_| Error::GenericError)?;
+
+    bundle.extend_from_slice(&nonce);
+    bundle.extend_from_slice(&ciphertext);
+    Ok(bundle)
+}
+
+/// Reader for the header fields of a bundle.
+struct BundleReader<'a> {
+    bundle: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BundleReader<'a> {
+    /// Check the bundle's format and KEK type.
+    fn new(bundle: &'a [u8], magic: &[u8; 4], kek_type: u8) -> Result<Self> {
+        if bundle.get(..magic.len()) != Some(&magic[..])
+            || bundle.get(magic.len()) != Some(&FORMAT_VERSION)
+        {
+            error!("not a secrets bundle, or unsupported version");
+            return Err(Error::ParseError);
+        }
+
+        if bundle.get(magic.len() + 1) != Some(&kek_type) {
+            error!("secrets bundle was sealed with a different kind of KEK");
+            return Err(Error::ArgumentError);
+        }
+
+        Ok(Self {
+            bundle,
+            offset: magic.len() + 2,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bundle
+            .get(self.offset..self.offset + len)
+            .ok_or(Error::ParseError)?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    /// Decrypt the rest of the bundle, authenticating the header read so far.
+    fn open(mut self, kek: &[u8; 32]) -> Result<Buffer> {
+        let header = &self.bundle[..self.offset];
+        let nonce = self.take(NONCE_LEN)?;
+        let ciphertext = &self.bundle[self.offset..];
+
+        Aes256Gcm::new(kek.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map(Buffer::new)
+            .map_err(|_| {
+                error!("couldn't decrypt secrets bundle");
+                Error::AuthenticationError
+            })
+    }
+}
+
+/// Start a bundle with the given magic bytes and KEK type.
+fn header(magic: &[u8; 4], kek_type: u8) -> Vec<u8> {
+    let mut header = magic.to_vec();
+    header.push(FORMAT_VERSION);
+    header.push(kek_type);
+    header
+}
+
+fn passphrase_kek(passphrase: &[u8], salt: &[u8], iterations: u32) -> Zeroizing<[u8; 32]> {
+    let mut kek = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, iterations, kek.as_mut());
+    kek
+}
+
+/// Derive a KEK from a secret agreed with [`agree_ephemeral`] with
+/// HKDF-SHA256, salted with the ephemeral public key.
+pub(crate) fn slot_kek(
+    shared: &[u8],
+    ephemeral: &[u8],
+    info: &[u8],
+) -> Result<Zeroizing<[u8; 32]>> {
+    let mut kek = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(ephemeral), shared)
+        .expand(info, kek.as_mut())
+        .map_err(|_| Error::GenericError)?;
+    Ok(kek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_round_trip() {
+        let secrets = Secrets {
+            pin: Some(Buffer::new(b"123456".to_vec())),
+            puk: None,
+            mgm_key: Some(Buffer::new(vec![0x42; 24])),
+        };
+
+        let bundle = secrets
+            .seal_with_passphrase(b"correct horse")
+            .expect("sealed");
+
+        let opened = Secrets::open_with_passphrase(&bundle, b"correct horse").expect("opened");
+        assert_eq!(opened.pin.as_deref(), Some(&b"123456".to_vec()));
+        assert!(opened.puk.is_none());
+        assert_eq!(opened.mgm_key.as_deref(), Some(&vec![0x42; 24]));
+
+        assert_eq!(
+            Secrets::open_with_passphrase(&bundle, b"battery staple").err(),
+            Some(Error::AuthenticationError)
+        );
+    }
+
+    #[test]
+    fn passphrase_iterations_bounds() {
+        let secrets = Secrets {
+            pin: Some(Buffer::new(b"123456".to_vec())),
+            ..Secrets::default()
+        };
+
+        let mut bundle = secrets
+            .seal_with_passphrase_iterations(b"correct horse", 1000)
+            .expect("sealed");
+
+        assert_eq!(
+            Secrets::open_with_passphrase(&bundle, b"correct horse").err(),
+            Some(Error::ParseError)
+        );
+
+        bundle[6..10].copy_from_slice(&(MAX_PBKDF2_ITERATIONS + 1).to_be_bytes());
+        assert_eq!(
+            Secrets::open_with_passphrase(&bundle, b"correct horse").err(),
+            Some(Error::ParseError)
+        );
+    }
+}
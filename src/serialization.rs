@@ -37,21 +37,31 @@ pub const OBJ_DISCOVERY: u32 = 0x7e;
 // TODO(tarcieri): refactor these into better serializers/message builders
 
 /// A Type-Length-Value object that has been parsed from a buffer.
+///
+/// Tags are one byte, or two bytes when the low five bits of the first one
+/// are set (BER-TLV, as in the GlobalPlatform and `7F49` templates).
 pub(crate) struct Tlv<'a> {
-    pub(crate) tag: u8,
+    pub(crate) tag: u16,
     pub(crate) value: &'a [u8],
 }
 
 impl<'a> Tlv<'a> {
     /// Parses a `Tlv` from a buffer, returning the remainder of the buffer.
     pub(crate) fn parse(buffer: &'a [u8]) -> Result<(&'a [u8], Self)> {
-        if buffer.len() < CB_OBJ_TAG_MIN || !has_valid_length(&buffer[1..], buffer.len() - 1) {
+        let (tag, buffer) = match buffer {
+            [first, second, rest @ ..] if first & 0x1f == 0x1f => {
+                (u16::from_be_bytes([*first, *second]), rest)
+            }
+            [first, rest @ ..] => (u16::from(*first), rest),
+            [] => return Err(Error::SizeError),
+        };
+
+        if buffer.len() < CB_OBJ_TAG_MIN - 1 || !has_valid_length(buffer, buffer.len()) {
             return Err(Error::SizeError);
         }
 
-        let tag = buffer[0];
         let mut len = 0;
-        let offset = 1 + get_length(&buffer[1..], &mut len);
+        let offset = get_length(buffer, &mut len);
         let buffer = buffer.get(offset..).ok_or(Error::SizeError)?;
 
         if buffer.len() >= len {
@@ -64,31 +74,35 @@ impl<'a> Tlv<'a> {
 
     /// Takes a [`Buffer`] containing a single `Tlv` with the given tag, and returns a
     /// `Buffer` containing only the value part of the `Tlv`.
-    pub(crate) fn parse_single(mut buffer: Buffer, tag: u8) -> Result<Buffer> {
-        if buffer.len() < CB_OBJ_TAG_MIN || !has_valid_length(&buffer[1..], buffer.len() - 1) {
-            return Err(Error::SizeError);
-        }
+    pub(crate) fn parse_single(mut buffer: Buffer, tag: u16) -> Result<Buffer> {
+        let (found, offset, len) = {
+            let (rest, tlv) = Tlv::parse(&buffer)?;
+            let len = tlv.value.len();
+            (tlv.tag, buffer.len() - rest.len() - len, len)
+        };
 
-        if tag != buffer[0] {
+        if tag != found {
             return Err(Error::GenericError);
         };
 
-        let mut len = 0;
-        let offset = 1 + get_length(&buffer[1..], &mut len);
-
         buffer.copy_within(offset..offset + len, 0);
         buffer.truncate(len);
         Ok(buffer)
     }
 
-    /// Writes a TLV to the given buffer.
-    pub(crate) fn write(buffer: &mut [u8], tag: u8, value: &[u8]) -> Result<usize> {
-        if buffer.len() < CB_OBJ_TAG_MIN {
-            return Err(Error::SizeError);
-        }
-        buffer[0] = tag;
+    /// Encodes a TLV with the given one or two-byte tag.
+    pub(crate) fn encode(tag: u16, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0u8; 5 + value.len()];
+        // The buffer is large enough for any tag and length
+        let len = Self::write(&mut encoded, tag, value).unwrap_or_default();
+        encoded.truncate(len);
+        encoded
+    }
 
-        let offset = 1 + set_length(&mut buffer[1..], value.len())?;
+    /// Writes a TLV to the given buffer.
+    pub(crate) fn write(buffer: &mut [u8], tag: u16, value: &[u8]) -> Result<usize> {
+        let offset = write_tag(buffer, tag)?;
+        let offset = offset + set_length(&mut buffer[offset..], value.len())?;
 
         if buffer.len() < offset + value.len() {
             return Err(Error::SizeError);
@@ -103,19 +117,15 @@ impl<'a> Tlv<'a> {
     /// `value` is guaranteed to be called with a mutable slice of length `length`.
     pub(crate) fn write_as<Gen>(
         buffer: &mut [u8],
-        tag: u8,
+        tag: u16,
         length: usize,
         value: Gen,
     ) -> Result<usize>
     where
         Gen: FnOnce(&mut [u8]),
     {
-        if buffer.len() < CB_OBJ_TAG_MIN {
-            return Err(Error::SizeError);
-        }
-        buffer[0] = tag;
-
-        let offset = 1 + set_length(&mut buffer[1..], length)?;
+        let offset = write_tag(buffer, tag)?;
+        let offset = offset + set_length(&mut buffer[offset..], length)?;
 
         if buffer.len() < offset + length {
             return Err(Error::SizeError);
@@ -126,6 +136,22 @@ impl<'a> Tlv<'a> {
     }
 }
 
+/// Write a one or two-byte tag, returning its size. The buffer must also
+/// have room for a length.
+fn write_tag(buffer: &mut [u8], tag: u16) -> Result<usize> {
+    match (u8::try_from(tag), buffer) {
+        (Ok(tag), [first, _, ..]) => {
+            *first = tag;
+            Ok(1)
+        }
+        (Err(_), [first, second, _, ..]) => {
+            [*first, *second] = tag.to_be_bytes();
+            Ok(2)
+        }
+        _ => Err(Error::SizeError),
+    }
+}
+
 /// Set length
 pub(crate) fn set_length(buffer: &mut [u8], length: usize) -> Result<usize> {
     if length < 0x80 {
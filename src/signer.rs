@@ -0,0 +1,925 @@
+//! Signers and decryptors for the key in a slot, whatever its algorithm.
+//!
+//! [`Signer`](crate::certificate::yubikey_signer::Signer) requires the
+//! algorithm of the key to be chosen at compile time. [`SlotSigner`] (and
+//! [`SlotDecryptor`]) instead detect it when created with
+//! [`YubiKey::signer`] (and [`YubiKey::decryptor`]), from the slot metadata
+//! or, on YubiKeys which don't support it, from the certificate in the slot:
+//!
+//! ```no_run
+//! use yubikey::{piv::SlotId, YubiKey};
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! yubikey.verify_pin(b"123456")?;
+//!
+//! let signer = yubikey.signer(SlotId::Signature)?;
+//! let signature = signer.sign(b"message")?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+
+use crate::{
+    certificate::{
+        yubikey_signer::{Ed25519, KeyType, Rsa1024, Rsa2048, Rsa3072, Rsa4096, YubiRsa},
+        Certificate,
+    },
+    error::{Error, Result},
+    piv::{self, AlgorithmId, ManagementAlgorithmId, SlotId},
+    verify,
+    yubikey::YubiKey,
+    Buffer,
+};
+use log::{debug, error};
+use rand_core::{CryptoRng, OsRng, RngCore};
+use rsa::{traits::PublicKeyParts, BigUint, Pss, RsaPrivateKey, RsaPublicKey};
+use sha2::{digest::DynDigest, Digest, Sha256};
+use signature::{Keypair, SignatureEncoding};
+use std::{cell::RefCell, fmt, sync::Mutex};
+use x509_cert::{
+    der::{
+        self, asn1::BitString, oid::AssociatedOid, referenced::OwnedToRef, referenced::RefToOwned,
+        Document,
+    },
+    spki::{
+        self, AlgorithmIdentifierOwned, DynSignatureAlgorithmIdentifier, EncodePublicKey,
+        SignatureBitStringEncoding, SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef,
+    },
+};
+
+#[cfg(feature = "untested")]
+use {
+    rsa::traits::PaddingScheme,
+    subtle::{ConditionallySelectable, ConstantTimeEq},
+};
+
+/// Signer using the key in a slot, created with [`YubiKey::signer`].
+///
+/// Messages are hashed with SHA-256 (SHA-384 for P-384 keys), and signed
+/// with ECDSA or RSASSA-PKCS1-v1_5 depending on the key. Ed25519 keys sign
+/// messages as they are.
+pub struct SlotSigner<'y> {
+    key: SlotKey<'y>,
+}
+
+impl<'y> SlotSigner<'y> {
+    pub(crate) fn new(yubikey: &'y mut YubiKey, slot: SlotId) -> Result<Self> {
+        SlotKey::new(yubikey, slot).map(|key| Self { key })
+    }
+
+    /// Get the slot of the key.
+    pub fn slot(&self) -> SlotId {
+        self.key.slot
+    }
+
+    /// Get the algorithm of the key.
+    pub fn algorithm(&self) -> AlgorithmId {
+        self.key.algorithm
+    }
+
+    /// Get the public key.
+    pub fn public_key(&self) -> &SubjectPublicKeyInfoOwned {
+        &self.key.public_key
+    }
+
+    /// Sign the given message, returning a DER-encoded ECDSA signature or an
+    /// RSA signature.
+    pub fn sign(&self, msg: &[u8]) -> Result<Buffer> {
+        let prepared = match self.key.algorithm {
+            AlgorithmId::Rsa1024 => YubiRsa::<Rsa1024>::prepare(msg),
+            AlgorithmId::Rsa2048 => YubiRsa::<Rsa2048>::prepare(msg),
+            AlgorithmId::Rsa3072 => YubiRsa::<Rsa3072>::prepare(msg),
+            AlgorithmId::Rsa4096 => YubiRsa::<Rsa4096>::prepare(msg),
+            AlgorithmId::EccP256 => p256::NistP256::prepare(msg),
+            AlgorithmId::EccP384 => p384::NistP384::prepare(msg),
+            AlgorithmId::Ed25519 => Ed25519::prepare(msg),
+            AlgorithmId::X25519 => return Err(Error::AlgorithmError),
+        }
+        .map_err(|_| Error::SizeError)?;
+
+        piv::sign_data(
+            &mut self.key.yubikey.borrow_mut(),
+            &prepared,
+            self.key.algorithm,
+            self.key.slot,
+        )
+    }
+}
+
+impl signature::Signer<Buffer> for SlotSigner<'_> {
+    fn try_sign(&self, msg: &[u8]) -> signature::Result<Buffer> {
+        self.sign(msg).map_err(signature::Error::from_source)
+    }
+}
+
+impl fmt::Debug for SlotSigner<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlotSigner")
+            .field("slot", &self.key.slot)
+            .field("algorithm", &self.key.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Signer using the key in a slot, producing typed [`Signature`]s, created
+/// with [`YubiKey::any_signer`].
+///
+/// Unlike [`Signer`](crate::certificate::yubikey_signer::Signer), it isn't
+/// generic over the algorithm of the key, so it can be used as a
+/// `dyn signature::Signer<Signature>` (e.g. by plugins or FFI layers), and
+/// with the `x509-cert` builders.
+pub struct AnySigner<'y> {
+    signer: SlotSigner<'y>,
+    verifying_key: VerifyingKey,
+}
+
+impl<'y> AnySigner<'y> {
+    pub(crate) fn new(yubikey: &'y mut YubiKey, slot: SlotId) -> Result<Self> {
+        Self::try_from(SlotSigner::new(yubikey, slot)?)
+    }
+
+    /// Get the slot of the key.
+    pub fn slot(&self) -> SlotId {
+        self.signer.slot()
+    }
+
+    /// Get the algorithm of the key.
+    pub fn algorithm(&self) -> AlgorithmId {
+        self.signer.algorithm()
+    }
+
+    /// Get the public key.
+    pub fn public_key(&self) -> &SubjectPublicKeyInfoOwned {
+        self.signer.public_key()
+    }
+
+    /// Sign the given message.
+    pub fn sign(&self, msg: &[u8]) -> Result<Signature> {
+        let signature = self.signer.sign(msg)?;
+
+        match self.algorithm() {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => {
+                rsa::pkcs1v15::Signature::try_from(&signature[..]).map(Signature::Rsa)
+            }
+            AlgorithmId::EccP256 => {
+                p256::ecdsa::DerSignature::try_from(&signature[..]).map(Signature::P256)
+            }
+            AlgorithmId::EccP384 => {
+                p384::ecdsa::DerSignature::try_from(&signature[..]).map(Signature::P384)
+            }
+            AlgorithmId::Ed25519 => {
+                ed25519_dalek::Signature::from_slice(&signature).map(Signature::Ed25519)
+            }
+            AlgorithmId::X25519 => return Err(Error::AlgorithmError),
+        }
+        .map_err(|_| Error::ParseError)
+    }
+}
+
+impl<'y> TryFrom<SlotSigner<'y>> for AnySigner<'y> {
+    type Error = Error;
+
+    fn try_from(signer: SlotSigner<'y>) -> Result<Self> {
+        let public_key = signer.public_key().owned_to_ref();
+
+        let verifying_key = match signer.algorithm() {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => RsaPublicKey::try_from(public_key)
+                .map(|key| VerifyingKey::Rsa(rsa::pkcs1v15::VerifyingKey::new(key))),
+            AlgorithmId::EccP256 => {
+                p256::ecdsa::VerifyingKey::try_from(public_key).map(VerifyingKey::P256)
+            }
+            AlgorithmId::EccP384 => {
+                p384::ecdsa::VerifyingKey::try_from(public_key).map(VerifyingKey::P384)
+            }
+            AlgorithmId::Ed25519 => {
+                ed25519_dalek::VerifyingKey::try_from(public_key).map(VerifyingKey::Ed25519)
+            }
+            AlgorithmId::X25519 => return Err(Error::AlgorithmError),
+        }
+        .map_err(|_| Error::ParseError)?;
+
+        Ok(Self {
+            signer,
+            verifying_key,
+        })
+    }
+}
+
+impl signature::Signer<Signature> for AnySigner<'_> {
+    fn try_sign(&self, msg: &[u8]) -> signature::Result<Signature> {
+        self.sign(msg).map_err(signature::Error::from_source)
+    }
+}
+
+impl Keypair for AnySigner<'_> {
+    type VerifyingKey = VerifyingKey;
+
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying_key.clone()
+    }
+}
+
+impl DynSignatureAlgorithmIdentifier for AnySigner<'_> {
+    fn signature_algorithm_identifier(&self) -> spki::Result<AlgorithmIdentifierOwned> {
+        self.verifying_key.signature_algorithm_identifier()
+    }
+}
+
+impl fmt::Debug for AnySigner<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnySigner")
+            .field("slot", &self.slot())
+            .field("algorithm", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+/// RSASSA-PSS signer using the RSA key in a slot, with MGF1 over the same
+/// hash `D` as the message, created with [`YubiKey::pss_signer`].
+///
+/// The PSS encoding is done host-side, with a random salt the length of
+/// the hash, and the YubiKey only performs the raw RSA operation: see
+/// [`piv::sign_data_pss`].
+pub struct PssSigner<'y, D: Digest> {
+    key: SlotKey<'y>,
+    verifying_key: rsa::pss::VerifyingKey<D>,
+}
+
+impl<'y, D> PssSigner<'y, D>
+where
+    D: 'static + Digest + DynDigest + Send + Sync,
+{
+    pub(crate) fn new(yubikey: &'y mut YubiKey, slot: SlotId) -> Result<Self> {
+        let key = SlotKey::new(yubikey, slot)?;
+
+        if !key.algorithm.is_rsa() {
+            return Err(Error::AlgorithmError);
+        }
+
+        let public_key =
+            RsaPublicKey::try_from(key.public_key.owned_to_ref()).map_err(|_| Error::ParseError)?;
+
+        Ok(Self {
+            key,
+            verifying_key: rsa::pss::VerifyingKey::new(public_key),
+        })
+    }
+
+    /// Get the slot of the key.
+    pub fn slot(&self) -> SlotId {
+        self.key.slot
+    }
+
+    /// Get the public key.
+    pub fn public_key(&self) -> &SubjectPublicKeyInfoOwned {
+        &self.key.public_key
+    }
+
+    /// Sign the given message.
+    pub fn sign(&self, msg: &[u8]) -> Result<rsa::pss::Signature> {
+        let signature = piv::sign_data_pss::<D>(
+            &mut self.key.yubikey.borrow_mut(),
+            &D::digest(msg),
+            self.key.algorithm,
+            self.key.slot,
+            None,
+        )?;
+
+        rsa::pss::Signature::try_from(&signature[..]).map_err(|_| Error::ParseError)
+    }
+}
+
+impl<D> signature::Signer<rsa::pss::Signature> for PssSigner<'_, D>
+where
+    D: 'static + Digest + DynDigest + Send + Sync,
+{
+    fn try_sign(&self, msg: &[u8]) -> signature::Result<rsa::pss::Signature> {
+        self.sign(msg).map_err(signature::Error::from_source)
+    }
+}
+
+impl<D: Digest> Keypair for PssSigner<'_, D> {
+    type VerifyingKey = rsa::pss::VerifyingKey<D>;
+
+    fn verifying_key(&self) -> rsa::pss::VerifyingKey<D> {
+        self.verifying_key.clone()
+    }
+}
+
+impl<D: Digest + AssociatedOid> DynSignatureAlgorithmIdentifier for PssSigner<'_, D> {
+    fn signature_algorithm_identifier(&self) -> spki::Result<AlgorithmIdentifierOwned> {
+        rsa::pss::get_default_pss_signature_algo_id::<D>()
+    }
+}
+
+impl<D: Digest> fmt::Debug for PssSigner<'_, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PssSigner")
+            .field("slot", &self.key.slot)
+            .field("algorithm", &self.key.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Signature made by an [`AnySigner`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Signature {
+    /// RSASSA-PKCS1-v1_5 signature with SHA-256
+    Rsa(rsa::pkcs1v15::Signature),
+
+    /// ECDSA signature over P-256 with SHA-256
+    P256(p256::ecdsa::DerSignature),
+
+    /// ECDSA signature over P-384 with SHA-384
+    P384(p384::ecdsa::DerSignature),
+
+    /// Ed25519 signature
+    Ed25519(ed25519_dalek::Signature),
+}
+
+impl Signature {
+    /// Get the encoding of the signature: a big-endian integer the size of
+    /// the modulus for RSA, a DER-encoded `Ecdsa-Sig-Value` for ECDSA, and
+    /// `R || S` for Ed25519.
+    pub fn to_vec(&self) -> Vec<u8> {
+        match self {
+            Self::Rsa(signature) => signature.to_vec(),
+            Self::P256(signature) => signature.to_vec(),
+            Self::P384(signature) => signature.to_vec(),
+            Self::Ed25519(signature) => signature.to_vec(),
+        }
+    }
+}
+
+impl SignatureBitStringEncoding for Signature {
+    fn to_bitstring(&self) -> der::Result<BitString> {
+        match self {
+            Self::Rsa(signature) => signature.to_bitstring(),
+            Self::P256(signature) => signature.to_bitstring(),
+            Self::P384(signature) => signature.to_bitstring(),
+            Self::Ed25519(signature) => BitString::from_bytes(&signature.to_bytes()),
+        }
+    }
+}
+
+/// Public key verifying the [`Signature`]s made by an [`AnySigner`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum VerifyingKey {
+    /// RSA public key, verifying RSASSA-PKCS1-v1_5 signatures with SHA-256
+    Rsa(rsa::pkcs1v15::VerifyingKey<Sha256>),
+
+    /// P-256 public key
+    P256(p256::ecdsa::VerifyingKey),
+
+    /// P-384 public key
+    P384(p384::ecdsa::VerifyingKey),
+
+    /// Ed25519 public key
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl EncodePublicKey for VerifyingKey {
+    fn to_public_key_der(&self) -> spki::Result<Document> {
+        match self {
+            Self::Rsa(key) => key.to_public_key_der(),
+            Self::P256(key) => key.to_public_key_der(),
+            Self::P384(key) => key.to_public_key_der(),
+            Self::Ed25519(key) => key.to_public_key_der(),
+        }
+    }
+}
+
+impl DynSignatureAlgorithmIdentifier for VerifyingKey {
+    fn signature_algorithm_identifier(&self) -> spki::Result<AlgorithmIdentifierOwned> {
+        match self {
+            Self::Rsa(key) => key.signature_algorithm_identifier(),
+            Self::P256(key) => key.signature_algorithm_identifier(),
+            Self::P384(key) => key.signature_algorithm_identifier(),
+            Self::Ed25519(key) => key.signature_algorithm_identifier(),
+        }
+    }
+}
+
+/// Decryptor using the key in a slot, created with [`YubiKey::decryptor`].
+#[cfg(feature = "untested")]
+pub struct SlotDecryptor<'y> {
+    key: SlotKey<'y>,
+}
+
+#[cfg(feature = "untested")]
+impl<'y> SlotDecryptor<'y> {
+    pub(crate) fn new(yubikey: &'y mut YubiKey, slot: SlotId) -> Result<Self> {
+        SlotKey::new(yubikey, slot).map(|key| Self { key })
+    }
+
+    /// Get the slot of the key.
+    pub fn slot(&self) -> SlotId {
+        self.key.slot
+    }
+
+    /// Get the algorithm of the key.
+    pub fn algorithm(&self) -> AlgorithmId {
+        self.key.algorithm
+    }
+
+    /// Get the public key.
+    pub fn public_key(&self) -> &SubjectPublicKeyInfoOwned {
+        &self.key.public_key
+    }
+
+    /// Decrypt a message encrypted with RSAES-PKCS1-v1_5 for an RSA key.
+    ///
+    /// Returns [`Error::AlgorithmError`] for ECC keys, which can only be used
+    /// for key agreement with [`SlotDecryptor::agree`].
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Buffer> {
+        match self.key.algorithm {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => (),
+            _ => return Err(Error::AlgorithmError),
+        }
+
+        piv::decrypt_pkcs1v15(
+            &mut self.key.yubikey.borrow_mut(),
+            ciphertext,
+            self.key.algorithm,
+            self.key.slot,
+        )
+    }
+
+    /// Decrypt a message encrypted with RSAES-OAEP for an RSA key, with the
+    /// given label and MGF1, both over `D`.
+    ///
+    /// Returns [`Error::AlgorithmError`] for ECC keys.
+    pub fn decrypt_oaep<D>(&self, ciphertext: &[u8], label: &str) -> Result<Buffer>
+    where
+        D: 'static + Digest + DynDigest + Send + Sync,
+    {
+        piv::decrypt_oaep::<D>(
+            &mut self.key.yubikey.borrow_mut(),
+            ciphertext,
+            self.key.algorithm,
+            self.key.slot,
+            label,
+        )
+    }
+
+    /// Perform ECDH key agreement with the given peer public key (a SEC1
+    /// encoded point) for an ECC key, returning the shared secret.
+    ///
+    /// Returns [`Error::AlgorithmError`] for RSA keys.
+    pub fn agree(&self, peer_public_key: &[u8]) -> Result<Buffer> {
+        match self.key.algorithm {
+            AlgorithmId::EccP256 | AlgorithmId::EccP384 => (),
+            _ => return Err(Error::AlgorithmError),
+        }
+
+        piv::decrypt_data(
+            &mut self.key.yubikey.borrow_mut(),
+            peer_public_key,
+            self.key.algorithm,
+            self.key.slot,
+        )
+    }
+}
+
+#[cfg(feature = "untested")]
+impl fmt::Debug for SlotDecryptor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlotDecryptor")
+            .field("slot", &self.key.slot)
+            .field("algorithm", &self.key.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Key in a slot, with its detected algorithm.
+struct SlotKey<'y> {
+    yubikey: RefCell<&'y mut YubiKey>,
+    slot: SlotId,
+    algorithm: AlgorithmId,
+    public_key: SubjectPublicKeyInfoOwned,
+}
+
+impl<'y> SlotKey<'y> {
+    fn new(yubikey: &'y mut YubiKey, slot: SlotId) -> Result<Self> {
+        let (algorithm, public_key) = match piv::metadata(yubikey, slot) {
+            Ok(metadata) => match (metadata.algorithm, metadata.public) {
+                (ManagementAlgorithmId::Asymmetric(algorithm), Some(public_key)) => {
+                    (algorithm, public_key)
+                }
+                _ => return Err(Error::AlgorithmError),
+            },
+            Err(Error::NotFound) => return Err(Error::NotFound),
+            Err(e) => {
+                debug!(
+                    "couldn't read metadata of slot {} ({}), using its certificate",
+                    slot, e
+                );
+
+                let cert = Certificate::read(yubikey, slot)?;
+                let public_key = cert.subject_pki().ref_to_owned();
+                (algorithm_of(public_key.owned_to_ref())?, public_key)
+            }
+        };
+
+        Ok(Self {
+            yubikey: RefCell::new(yubikey),
+            slot,
+            algorithm,
+            public_key,
+        })
+    }
+}
+
+/// Get the algorithm of the given public key.
+pub(crate) fn algorithm_of(public_key: SubjectPublicKeyInfoRef<'_>) -> Result<AlgorithmId> {
+    if public_key.algorithm.oid == verify::ED25519_OID {
+        return Ok(AlgorithmId::Ed25519);
+    }
+
+    if public_key.algorithm.oid == piv::X25519_OID {
+        return Ok(AlgorithmId::X25519);
+    }
+
+    if let Ok(curve) = public_key.algorithm.parameters_oid() {
+        return if curve == p256::NistP256::OID {
+            Ok(AlgorithmId::EccP256)
+        } else if curve == p384::NistP384::OID {
+            Ok(AlgorithmId::EccP384)
+        } else {
+            Err(Error::AlgorithmError)
+        };
+    }
+
+    let public_key = RsaPublicKey::try_from(public_key).map_err(|_| Error::AlgorithmError)?;
+
+    match public_key.size() {
+        128 => Ok(AlgorithmId::Rsa1024),
+        256 => Ok(AlgorithmId::Rsa2048),
+        384 => Ok(AlgorithmId::Rsa3072),
+        512 => Ok(AlgorithmId::Rsa4096),
+        _ => Err(Error::AlgorithmError),
+    }
+}
+
+/// Throwaway RSA keys, by modulus size, through which padding is encoded and
+/// removed with the `rsa` crate: see [`unpad`] and [`pss_encode`].
+static PADDING_KEYS: Mutex<Vec<RsaPrivateKey>> = Mutex::new(Vec::new());
+
+/// Get the throwaway RSA key with a modulus of `modulus_len` bytes,
+/// generating it the first time (which takes a while for 4096-bit keys).
+fn padding_key(modulus_len: usize) -> Result<RsaPrivateKey> {
+    match modulus_len {
+        128 | 256 | 384 | 512 => (),
+        _ => return Err(Error::SizeError),
+    }
+
+    let mut keys = PADDING_KEYS.lock().map_err(|_| Error::GenericError)?;
+
+    if let Some(key) = keys.iter().find(|key| key.size() == modulus_len) {
+        return Ok(key.clone());
+    }
+
+    let key = RsaPrivateKey::new(&mut OsRng, modulus_len * 8).map_err(|e| {
+        error!("couldn't generate RSA padding key: {}", e);
+        Error::GenericError
+    })?;
+
+    keys.push(key.clone());
+    Ok(key)
+}
+
+/// Perform the raw RSA public key operation with `key`, returning a block
+/// the size of its modulus.
+fn rsa_encrypt(key: &RsaPrivateKey, block: &[u8]) -> Result<Buffer> {
+    let encrypted = rsa::hazmat::rsa_encrypt(key, &BigUint::from_bytes_be(block))
+        .map_err(|_| Error::GenericError)?
+        .to_bytes_be();
+
+    let mut padded = Buffer::new(vec![0; key.size()]);
+    let offset = padded
+        .len()
+        .checked_sub(encrypted.len())
+        .ok_or(Error::GenericError)?;
+    padded[offset..].copy_from_slice(&encrypted);
+    Ok(padded)
+}
+
+/// Remove the padding of a block decrypted by a YubiKey (with the raw RSA
+/// operation) with the given encryption padding scheme of the `rsa` crate,
+/// e.g. [`rsa::Pkcs1v15Encrypt`] or [`rsa::Oaep`].
+///
+/// The block is encrypted again with a throwaway key of the same size, and
+/// decrypted with the padding scheme, so the padding is checked and removed
+/// by the `rsa` crate in constant time. Invalid padding is reported as
+/// [`Error::GenericError`], like any other failure, and isn't logged.
+#[cfg(feature = "untested")]
+pub(crate) fn unpad(block: &[u8], padding: impl PaddingScheme) -> Result<Buffer> {
+    let key = padding_key(block.len())?;
+
+    // Padded blocks start with a zero byte, keeping them below the throwaway
+    // modulus. Other blocks are zeroed rather than rejected here, so as not
+    // to branch on their contents: an all-zero block is invalid padding for
+    // all schemes
+    let mut block = Buffer::new(block.to_vec());
+    let first_ok = block[0].ct_eq(&0);
+
+    for byte in block.iter_mut() {
+        byte.conditional_assign(&0, !first_ok);
+    }
+
+    let ciphertext = rsa_encrypt(&key, &block)?;
+
+    key.decrypt_blinded(&mut OsRng, padding, &ciphertext)
+        .map(Buffer::new)
+        .map_err(|_| Error::GenericError)
+}
+
+/// Encode a message digest with EMSA-PSS (RFC 8017, section 9.1.1) for an
+/// RSA key with a modulus of `modulus_len` bytes, with MGF1 over `D`.
+///
+/// The salt defaults to random bytes from the OS RNG, the length of the
+/// digest. The message is signed with the `rsa` crate using a throwaway key
+/// of the same size, and the signature is encrypted again with it to get the
+/// encoded message.
+pub(crate) fn pss_encode<D>(
+    digest: &[u8],
+    salt: Option<&[u8]>,
+    modulus_len: usize,
+) -> Result<Buffer>
+where
+    D: 'static + Digest + DynDigest + Send + Sync,
+{
+    if digest.len() != <D as Digest>::output_size() {
+        return Err(Error::SizeError);
+    }
+
+    let key = padding_key(modulus_len)?;
+
+    let signature = match salt {
+        Some(salt) => key.sign_with_rng(
+            &mut FixedSalt(salt),
+            Pss::new_with_salt::<D>(salt.len()),
+            digest,
+        ),
+        None => key.sign_with_rng(&mut OsRng, Pss::new::<D>(), digest),
+    }
+    .map_err(|_| Error::SizeError)?;
+
+    rsa_encrypt(&key, &signature)
+}
+
+/// "RNG" returning the given salt, to sign with [`Pss`] with a chosen salt.
+///
+/// Unblinded [`Pss`] signing only uses the RNG to generate the salt.
+struct FixedSalt<'a>(&'a [u8]);
+
+impl RngCore for FixedSalt<'_> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for (byte, salt) in dest.iter_mut().zip(self.0.iter().cycle()) {
+            *byte = *salt;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> core::result::Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for FixedSalt<'_> {}
+
+#[cfg(all(test, feature = "emulator"))]
+mod tests {
+    use super::*;
+    use crate::{
+        certificate::CertInfo, emulator::Emulator, piv::RetiredSlotId, verify, PinPolicy, Serial,
+        TouchPolicy,
+    };
+    use sha2::Sha256;
+    use std::{str::FromStr, time::Duration};
+    use x509_cert::{der::Encode, name::Name, serial_number::SerialNumber, time::Validity};
+
+    /// Generate a key with a self-signed certificate in the given slot of an
+    /// emulated YubiKey, which doesn't support slot metadata.
+    fn emulated_key(slot: SlotId, algorithm: AlgorithmId) -> YubiKey {
+        let mut yubikey = Emulator::new(Serial(1)).open_authenticated().expect("open");
+        yubikey.verify_pin(b"123456").expect("verify PIN");
+
+        let public_key = piv::generate(
+            &mut yubikey,
+            slot,
+            algorithm,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .expect("generate");
+
+        let validity = Validity::from_now(Duration::from_secs(3600)).expect("validity");
+        let name = Name::from_str("CN=signer").expect("name");
+        let serial = SerialNumber::from(1u32);
+
+        match algorithm {
+            AlgorithmId::EccP256 => Certificate::generate_self_signed::<_, p256::NistP256>(
+                &mut yubikey,
+                slot,
+                serial,
+                validity,
+                name,
+                public_key,
+                |_| Ok(()),
+            ),
+            _ => Certificate::generate_self_signed::<_, YubiRsa<Rsa1024>>(
+                &mut yubikey,
+                slot,
+                serial,
+                validity,
+                name,
+                public_key,
+                |_| Ok(()),
+            ),
+        }
+        .expect("certificate")
+        .write(&mut yubikey, slot, CertInfo::Uncompressed)
+        .expect("write certificate");
+
+        yubikey
+    }
+
+    #[test]
+    fn sign_with_detected_algorithm() {
+        for algorithm in [AlgorithmId::EccP256, AlgorithmId::Rsa1024] {
+            let slot = SlotId::Retired(RetiredSlotId::R2);
+            let mut yubikey = emulated_key(slot, algorithm);
+
+            let signer = yubikey.signer(slot).expect("signer");
+            assert_eq!(signer.algorithm(), algorithm);
+
+            let signature = signer.sign(b"message").expect("sign");
+            let public_key = signer.public_key().clone();
+            drop(signer);
+
+            let verified = match algorithm {
+                AlgorithmId::EccP256 => {
+                    verify::ecdsa::<Sha256>(public_key.owned_to_ref(), b"message", &signature)
+                }
+                _ => verify::pkcs1v15::<Sha256>(public_key.owned_to_ref(), b"message", &signature),
+            };
+            assert!(verified.is_ok());
+        }
+
+        let mut yubikey = Emulator::new(Serial(1)).open().expect("open");
+        assert!(yubikey.signer(SlotId::Signature).is_err());
+    }
+
+    #[test]
+    fn sign_with_any_signer() {
+        use x509_cert::builder::{Builder, CertificateBuilder, Profile};
+
+        for algorithm in [AlgorithmId::EccP256, AlgorithmId::Rsa1024] {
+            let slot = SlotId::Retired(RetiredSlotId::R3);
+            let mut yubikey = emulated_key(slot, algorithm);
+            let signer = yubikey.any_signer(slot).expect("signer");
+
+            let dyn_signer: &dyn signature::Signer<Signature> = &signer;
+            let signature = dyn_signer.try_sign(b"message").expect("sign");
+            assert!(matches!(
+                (algorithm, &signature),
+                (AlgorithmId::EccP256, Signature::P256(_))
+                    | (AlgorithmId::Rsa1024, Signature::Rsa(_))
+            ));
+
+            let cert = CertificateBuilder::new(
+                Profile::Root,
+                SerialNumber::from(2u32),
+                Validity::from_now(Duration::from_secs(3600)).expect("validity"),
+                Name::from_str("CN=any").expect("name"),
+                signer.public_key().clone(),
+                &signer,
+            )
+            .expect("builder")
+            .build::<Signature>()
+            .expect("build");
+
+            let cert = Certificate::from_bytes(cert.to_der().expect("encode")).expect("decode");
+            assert!(cert.verify_self_signed().is_ok());
+        }
+    }
+
+    #[test]
+    fn sign_with_pss() {
+        use signature::{Signer, Verifier};
+
+        let slot = SlotId::Retired(RetiredSlotId::R4);
+        let mut yubikey = emulated_key(slot, AlgorithmId::Rsa1024);
+        let signer = yubikey.pss_signer::<Sha256>(slot).expect("signer");
+
+        let signature = signer.try_sign(b"message").expect("sign");
+        let verifying_key = signer.verifying_key();
+        assert!(verifying_key.verify(b"message", &signature).is_ok());
+        assert!(verifying_key.verify(b"other", &signature).is_err());
+        drop(signer);
+
+        let digest = Sha256::digest(b"message");
+        assert_eq!(
+            piv::sign_data_pss::<Sha256>(
+                &mut yubikey,
+                &digest[..16],
+                AlgorithmId::Rsa1024,
+                slot,
+                None
+            )
+            .err(),
+            Some(Error::SizeError)
+        );
+
+        let mut yubikey = emulated_key(slot, AlgorithmId::EccP256);
+        assert_eq!(
+            yubikey.pss_signer::<Sha256>(slot).err(),
+            Some(Error::AlgorithmError)
+        );
+    }
+
+    #[cfg(feature = "untested")]
+    #[test]
+    fn decrypt_with_detected_algorithm() {
+        use rand_core::OsRng;
+
+        let slot = SlotId::KeyManagement;
+        let mut yubikey = emulated_key(slot, AlgorithmId::Rsa1024);
+        let decryptor = yubikey.decryptor(slot).expect("decryptor");
+
+        let public_key =
+            RsaPublicKey::try_from(decryptor.public_key().owned_to_ref()).expect("RSA key");
+        let ciphertext = public_key
+            .encrypt(&mut OsRng, rsa::Pkcs1v15Encrypt, b"secret")
+            .expect("encrypt");
+
+        assert_eq!(
+            &decryptor.decrypt(&ciphertext).expect("decrypt")[..],
+            b"secret"
+        );
+        assert_eq!(decryptor.agree(&[4; 65]), Err(Error::AlgorithmError));
+
+        let ciphertext = public_key
+            .encrypt(
+                &mut OsRng,
+                rsa::Oaep::new_with_label::<Sha256, _>("label"),
+                b"secret",
+            )
+            .expect("encrypt");
+        assert_eq!(
+            &decryptor
+                .decrypt_oaep::<Sha256>(&ciphertext, "label")
+                .expect("decrypt")[..],
+            b"secret"
+        );
+        assert_eq!(
+            decryptor.decrypt_oaep::<Sha256>(&ciphertext, ""),
+            Err(Error::GenericError)
+        );
+        assert_eq!(
+            decryptor.decrypt_oaep::<sha2::Sha384>(&ciphertext, "label"),
+            Err(Error::GenericError)
+        );
+
+        let mut block = [1; 128];
+        block[..2].copy_from_slice(&[0, 2]);
+        block[126] = 0;
+        block[127] = 7;
+        assert_eq!(
+            &unpad(&block, rsa::Pkcs1v15Encrypt).expect("unpad")[..],
+            [7]
+        );
+        block[0] = 1;
+        assert_eq!(
+            unpad(&block, rsa::Pkcs1v15Encrypt),
+            Err(Error::GenericError)
+        );
+        block[..10].copy_from_slice(&[0, 2, 1, 1, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(
+            unpad(&block, rsa::Pkcs1v15Encrypt),
+            Err(Error::GenericError)
+        );
+        assert_eq!(
+            unpad(&block[1..], rsa::Pkcs1v15Encrypt),
+            Err(Error::SizeError)
+        );
+    }
+}
@@ -2,16 +2,25 @@
 
 use crate::{
     apdu::Response,
-    apdu::{Apdu, Ins, StatusWords},
+    apdu::{Apdu, Ins, StatusWords, APDU_DATA_MAX, EXTENDED_APDU_DATA_MAX},
+    clock::{Clock, SystemClock},
     consts::{CB_BUF_MAX, CB_OBJ_MAX},
     error::{Error, Result},
     otp,
     piv::{self, AlgorithmId, SlotId},
+    scp03::SecureChannel,
     serialization::*,
+    transport::{Exchange, Transport},
+    wear::WriteLog,
+    wirelog::{Observer, WireLog},
     yubikey::*,
     Buffer, ObjectId,
 };
-use log::{error, trace};
+use log::{debug, error, trace};
+use std::{
+    cell::{Cell, RefCell},
+    time::SystemTime,
+};
 use zeroize::Zeroizing;
 
 #[cfg(feature = "untested")]
@@ -26,19 +35,110 @@ pub(crate) enum ChangeRefAction {
     UnblockPin,
 }
 
-/// Exclusive transaction with the YubiKey's PC/SC card.
+/// Exclusive transaction with the YubiKey's card.
 pub(crate) struct Transaction<'tx> {
-    inner: pcsc::Transaction<'tx>,
+    inner: Box<dyn Exchange + 'tx>,
+    write_log: Option<&'tx RefCell<WriteLog>>,
+    wire_log: Option<&'tx RefCell<WireLog>>,
+    apdu_observer: Option<&'tx RefCell<Observer>>,
+    card_reset: Option<&'tx Cell<bool>>,
+    extended_apdus: Option<&'tx Cell<bool>>,
+    secure_channel: Option<&'tx RefCell<SecureChannel>>,
+    clock: Option<&'tx dyn Clock>,
+    conformance: bool,
+    dry_run: bool,
 }
 
 impl<'tx> Transaction<'tx> {
-    /// Create a new transaction with the given card.
-    pub fn new(card: &'tx mut pcsc::Card) -> Result<Self> {
+    /// Create a new transaction over the given transport.
+    pub fn new(transport: &'tx mut dyn Transport) -> Result<Self> {
         Ok(Transaction {
-            inner: card.transaction()?,
+            inner: transport.begin_transaction()?,
+            write_log: None,
+            wire_log: None,
+            apdu_observer: None,
+            card_reset: None,
+            extended_apdus: None,
+            secure_channel: None,
+            clock: None,
+            conformance: false,
+            dry_run: false,
         })
     }
 
+    /// Record the APDUs exchanged during this transaction in the given log.
+    pub fn with_wire_log(mut self, wire_log: Option<&'tx RefCell<WireLog>>) -> Self {
+        self.wire_log = wire_log;
+        self
+    }
+
+    /// Pass the APDUs exchanged during this transaction to the given observer.
+    pub fn with_apdu_observer(mut self, apdu_observer: Option<&'tx RefCell<Observer>>) -> Self {
+        self.apdu_observer = apdu_observer;
+        self
+    }
+
+    /// Refuse Yubico-proprietary instructions and objects during this
+    /// transaction: see [`YubiKey::set_conformance_mode`].
+    ///
+    /// [`YubiKey::set_conformance_mode`]: crate::YubiKey::set_conformance_mode
+    pub fn with_conformance(mut self, conformance: bool) -> Self {
+        self.conformance = conformance;
+        self
+    }
+
+    /// Refuse instructions modifying the card during this transaction: see
+    /// [`YubiKey::set_dry_run`].
+    ///
+    /// [`YubiKey::set_dry_run`]: crate::YubiKey::set_dry_run
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Flag the card as reset if a command fails because it was reset during
+    /// this transaction.
+    pub fn with_card_reset(mut self, card_reset: &'tx Cell<bool>) -> Self {
+        self.card_reset = Some(card_reset);
+        self
+    }
+
+    /// Send large amounts of data with extended APDUs while the given flag
+    /// is set, clearing it if the card or reader turns out not to support
+    /// them: see [`Transaction::transfer_data`].
+    pub fn with_extended_apdus(mut self, extended_apdus: &'tx Cell<bool>) -> Self {
+        self.extended_apdus = Some(extended_apdus);
+        self
+    }
+
+    /// Send PIV commands through the given SCP03 secure channel, opening it
+    /// whenever the PIV application is selected.
+    pub fn with_secure_channel(
+        mut self,
+        secure_channel: Option<&'tx RefCell<SecureChannel>>,
+    ) -> Self {
+        self.secure_channel = secure_channel;
+        self
+    }
+
+    /// Read the current time from the given clock, e.g. to check the
+    /// validity of SCP11 certificates, instead of the system clock.
+    pub fn with_clock(mut self, clock: &'tx dyn Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Get the current time of the clock used during this transaction.
+    pub fn now(&self) -> SystemTime {
+        self.clock.unwrap_or(&SystemClock).now()
+    }
+
+    /// Record the objects saved during this transaction in the given log.
+    pub fn with_write_log(mut self, write_log: &'tx RefCell<WriteLog>) -> Self {
+        self.write_log = Some(write_log);
+        self
+    }
+
     /// Transmit a single serialized APDU to the card this transaction is open
     /// with and receive a response.
     ///
@@ -49,14 +149,106 @@ impl<'tx> Transaction<'tx> {
     pub fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
         trace!(">>> {:?}", send_buffer);
 
-        let mut recv_buffer = vec![0u8; recv_len];
+        if self.conformance {
+            if let Some(&ins) = send_buffer.get(1) {
+                if Ins::from(ins).is_proprietary() {
+                    error!(
+                        "instruction {:02x} is Yubico-proprietary, refused in conformance mode",
+                        ins
+                    );
+                    return Err(Error::NotSupported);
+                }
+            }
+        }
+
+        if self.dry_run {
+            if let Some(&ins) = send_buffer.get(1) {
+                if Ins::from(ins).modifies_card() {
+                    error!(
+                        "instruction {:02x} modifies the card, refused in dry-run mode",
+                        ins
+                    );
+                    return Err(Error::OperationDenied);
+                }
+            }
+        }
+
+        if let Some(channel) = self.secure_channel {
+            if send_buffer.get(1).copied().map(Ins::from) == Some(Ins::SelectApplication) {
+                // Selecting an application ends the secure channel session,
+                // which is opened again with the selected application
+                channel.borrow_mut().close();
+                let response = self.exchange(send_buffer, recv_len)?;
+
+                if status_words(&response).is_success() {
+                    SecureChannel::open(channel, self)?;
+                }
+
+                return Ok(response);
+            } else if channel.borrow().is_open() {
+                return self.transmit_secure(channel, send_buffer);
+            } else if !channel.borrow().is_opening() {
+                // e.g. after a response failed authentication
+                error!("no secure channel session open, refusing to send command in the clear");
+                return Err(Error::SecureChannelError);
+            }
+        }
+
+        self.exchange(send_buffer, recv_len)
+    }
+
+    /// Send a command through the secure channel: wrap it, send it (chained
+    /// in short APDUs), gather its response and unwrap it.
+    ///
+    /// Command chaining and GET RESPONSE happen below the secure channel, so
+    /// the returned response is complete.
+    fn transmit_secure(&self, channel: &RefCell<SecureChannel>, command: &[u8]) -> Result<Vec<u8>> {
+        let apdus = channel.borrow_mut().wrap(command)?;
+        let mut response = vec![];
+
+        for apdu in &apdus {
+            response = self.exchange(apdu, 261)?;
+
+            if !status_words(&response).is_success() {
+                break;
+            }
+        }
+
+        // The R-MAC covers the whole response data
+        while let StatusWords::BytesRemaining { .. } = status_words(&response) {
+            response.truncate(response.len() - 2);
+            response.extend(self.exchange(&Apdu::new(Ins::GetResponseApdu).to_bytes(), 261)?);
+        }
+
+        Ok(channel.borrow_mut().unwrap(&response)?.to_vec())
+    }
+
+    /// Exchange a serialized APDU with the card as it is.
+    fn exchange(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        if let Some(wire_log) = self.wire_log {
+            wire_log.borrow_mut().record_command(send_buffer);
+        }
+
+        if let Some(apdu_observer) = self.apdu_observer {
+            apdu_observer.borrow_mut().command(send_buffer);
+        }
+
+        let recv_buffer = self.inner.transmit(send_buffer, recv_len).map_err(|e| {
+            if let (true, Some(card_reset)) = (e.is_card_reset(), self.card_reset) {
+                card_reset.set(true);
+            }
+
+            e
+        })?;
 
-        let len = self
-            .inner
-            .transmit(send_buffer, recv_buffer.as_mut())?
-            .len();
+        if let Some(wire_log) = self.wire_log {
+            wire_log.borrow_mut().record_response(&recv_buffer);
+        }
+
+        if let Some(apdu_observer) = self.apdu_observer {
+            apdu_observer.borrow_mut().response(&recv_buffer);
+        }
 
-        recv_buffer.truncate(len);
         Ok(recv_buffer)
     }
 
@@ -284,18 +476,15 @@ impl<'tx> Transaction<'tx> {
         decipher: bool,
     ) -> Result<Buffer> {
         let in_len = sign_in.len();
-        let mut indata = [0u8; 1024];
+        let mut indata = vec![0u8; in_len + 16];
         let templ = [0, Ins::Authenticate.code(), algorithm.into(), key.into()];
 
         match algorithm {
-            AlgorithmId::Rsa1024 | AlgorithmId::Rsa2048 => {
-                let key_len = if let AlgorithmId::Rsa1024 = algorithm {
-                    128
-                } else {
-                    256
-                };
-
-                if in_len != key_len {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => {
+                if Some(in_len) != algorithm.rsa_len() {
                     return Err(Error::SizeError);
                 }
             }
@@ -311,11 +500,26 @@ impl<'tx> Transaction<'tx> {
                     return Err(Error::SizeError);
                 }
             }
+            AlgorithmId::Ed25519 => {
+                // Messages are signed as they are, rather than hashed first
+                if decipher {
+                    return Err(Error::AlgorithmError);
+                }
+            }
+            AlgorithmId::X25519 => {
+                if !decipher {
+                    return Err(Error::AlgorithmError);
+                }
+
+                if in_len != 32 {
+                    return Err(Error::SizeError);
+                }
+            }
         }
 
         let bytes = if in_len < 0x80 {
             1
-        } else if in_len < 0xff {
+        } else if in_len < 0x100 {
             2
         } else {
             3
@@ -327,7 +531,9 @@ impl<'tx> Transaction<'tx> {
                 Tlv::write(
                     &mut buf[2..],
                     match (algorithm, decipher) {
-                        (AlgorithmId::EccP256, true) | (AlgorithmId::EccP384, true) => 0x85,
+                        (AlgorithmId::EccP256, true)
+                        | (AlgorithmId::EccP384, true)
+                        | (AlgorithmId::X25519, true) => 0x85,
                         _ => 0x81,
                     },
                     sign_in
@@ -377,16 +583,60 @@ impl<'tx> Transaction<'tx> {
     /// messages into smaller APDU-sized messages (using the provided APDU
     /// template to construct them), and then sending those via
     /// [`Transaction::transmit`].
+    ///
+    /// If enabled (see [`Transaction::with_extended_apdus`]), extended APDUs
+    /// are used, so messages of up to 65535 bytes are sent in one go. If the
+    /// card or reader rejects them, they're disabled and the message is sent
+    /// again with command chaining.
     pub fn transfer_data(&self, templ: &[u8], in_data: &[u8], max_out: usize) -> Result<Response> {
+        if let Some(extended_apdus) = self.extended_apdus.filter(|enabled| enabled.get()) {
+            match self.transfer(templ, in_data, max_out, true) {
+                Ok(response) if response.status_words() != StatusWords::WrongLengthError => {
+                    return Ok(response)
+                }
+                Ok(_) => debug!("extended APDU rejected by card; using command chaining"),
+                #[cfg(feature = "pcsc")]
+                Err(e @ Error::PcscError { inner: Some(_) })
+                    if !e.is_card_reset() && !e.is_card_removed() =>
+                {
+                    debug!("extended APDU failed ({}); using command chaining", e)
+                }
+                Err(e) => return Err(e),
+            }
+
+            extended_apdus.set(false);
+        }
+
+        self.transfer(templ, in_data, max_out, false)
+    }
+
+    /// Send/receive data with short or extended APDUs.
+    fn transfer(
+        &self,
+        templ: &[u8],
+        in_data: &[u8],
+        max_out: usize,
+        extended: bool,
+    ) -> Result<Response> {
+        // Secured commands are chained below the secure channel, so they're
+        // passed to it whole
+        let extended = extended || self.secure_channel.map_or(false, |c| c.borrow().is_open());
+
+        let (max_size, recv_len) = if extended {
+            (EXTENDED_APDU_DATA_MAX, EXTENDED_APDU_DATA_MAX + 3)
+        } else {
+            (APDU_DATA_MAX, 261)
+        };
+
         let mut in_offset = 0;
         let mut out_data = vec![];
         let mut sw;
 
         loop {
-            let mut this_size = 0xff;
+            let mut this_size = max_size;
 
-            let cla = if in_offset + 0xff < in_data.len() {
-                0x10
+            let cla = if in_offset + max_size < in_data.len() {
+                templ[0] | 0x10
             } else {
                 this_size = in_data.len() - in_offset;
                 templ[0]
@@ -394,11 +644,17 @@ impl<'tx> Transaction<'tx> {
 
             trace!("going to send {} bytes in this go", this_size);
 
-            let response = Apdu::new(templ[1])
+            let mut apdu = Apdu::new(templ[1]);
+
+            if extended {
+                apdu.extended();
+            }
+
+            let response = apdu
                 .cla(cla)
                 .params(templ[2], templ[3])
                 .data(&in_data[in_offset..(in_offset + this_size)])
-                .transmit(self, 261)?;
+                .transmit(self, recv_len)?;
 
             sw = response.status_words();
 
@@ -453,8 +709,28 @@ impl<'tx> Transaction<'tx> {
         Ok(Response::new(sw, out_data))
     }
 
+    /// Check the given object is defined by SP 800-73-4 in conformance mode.
+    fn check_object(&self, object_id: ObjectId) -> Result<()> {
+        // Discovery and BIT group template objects, and the data objects
+        // from the cardholder capability container to the pairing code
+        // reference data container
+        let standard = matches!(object_id, 0x7e | 0x7f61 | 0x005f_c101..=0x005f_c123);
+
+        if self.conformance && !standard {
+            error!(
+                "object {:06x} is Yubico-proprietary, refused in conformance mode",
+                object_id
+            );
+            return Err(Error::NotSupported);
+        }
+
+        Ok(())
+    }
+
     /// Fetch an object.
     pub fn fetch_object(&self, object_id: ObjectId) -> Result<Buffer> {
+        self.check_object(object_id)?;
+
         let mut indata = [0u8; 5];
         let templ = [0, Ins::GetData.code(), 0x3f, 0xff];
 
@@ -489,6 +765,8 @@ impl<'tx> Transaction<'tx> {
 
     /// Save an object.
     pub fn save_object(&self, object_id: ObjectId, indata: &[u8]) -> Result<()> {
+        self.check_object(object_id)?;
+
         let templ = [0, Ins::PutData.code(), 0x3f, 0xff];
 
         // TODO(tarcieri): replace with vector
@@ -510,9 +788,23 @@ impl<'tx> Transaction<'tx> {
             .status_words();
 
         match status_words {
-            StatusWords::Success => Ok(()),
+            StatusWords::Success => {
+                if let Some(write_log) = self.write_log {
+                    write_log.borrow_mut().record(object_id);
+                }
+
+                Ok(())
+            }
             StatusWords::SecurityStatusError => Err(Error::AuthenticationError),
             _ => Err(Error::GenericError),
         }
     }
 }
+
+/// Get the status words of a serialized response APDU.
+fn status_words(response: &[u8]) -> StatusWords {
+    match response {
+        [.., sw1, sw2] => StatusWords::from(u16::from_be_bytes([*sw1, *sw2])),
+        _ => StatusWords::None,
+    }
+}
@@ -0,0 +1,438 @@
+//! Signed transcripts of the operations performed on a YubiKey, for audits.
+//!
+//! A [`Transcript`] added to a [`YubiKey`] with
+//! [`YubiKey::add_middleware`](crate::YubiKey::add_middleware) records every
+//! high-level [`Operation`] performed during a session (e.g. provisioning),
+//! when it was performed and its outcome. Signing the transcript with a key
+//! held in one of the slots produces a [`SignedTranscript`], giving auditors
+//! evidence of exactly what was done to the YubiKey:
+//!
+//! ```no_run
+//! use yubikey::{piv::SlotId, transcript::Transcript, YubiKey};
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! let transcript = Transcript::new();
+//! yubikey.add_middleware(transcript.clone());
+//!
+//! // ... provision the YubiKey ...
+//!
+//! # let public_key: x509_cert::spki::SubjectPublicKeyInfoOwned = todo!();
+//! # use x509_cert::der::referenced::OwnedToRef;
+//! let signed = transcript.sign(&mut yubikey, SlotId::Signature, public_key.owned_to_ref())?;
+//! std::fs::write("transcript.der", signed.as_der())?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Only operations performed through the YubiKey the transcript was added to
+//! are recorded; the data exchanged (e.g. keys, signatures) isn't. Signed
+//! transcripts are encoded in DER, using the following structure, so the
+//! same session always produces the same transcript:
+//!
+//! ```text
+//! SignedTranscript ::= SEQUENCE {
+//!     tbsTranscript       TBSTranscript,
+//!     signatureAlgorithm  AlgorithmIdentifier,
+//!     signature           BIT STRING
+//! }
+//!
+//! TBSTranscript ::= SEQUENCE {
+//!     serial              INTEGER,
+//!     firmware            OCTET STRING (SIZE(3)),
+//!     signedAt            GeneralizedTime,
+//!     signingSlot         INTEGER,
+//!     entries             SEQUENCE OF TranscriptEntry
+//! }
+//!
+//! TranscriptEntry ::= SEQUENCE {
+//!     time                GeneralizedTime,
+//!     operation           INTEGER,
+//!     slot                [0] IMPLICIT INTEGER OPTIONAL,
+//!     algorithm           [1] IMPLICIT INTEGER OPTIONAL,
+//!     objectId            [2] IMPLICIT INTEGER OPTIONAL,
+//!     error               [3] IMPLICIT UTF8String OPTIONAL,
+//!     sourceSlot          [4] IMPLICIT INTEGER OPTIONAL
+//! }
+//! ```
+//!
+//! Operations are numbered in the order of the [`Operation`] variants, from
+//! 1 for `VerifyPin`. Failed operations have the [code](crate::Error::code)
+//! of their error.
+
+use crate::{
+    certificate,
+    clock::{self, Clock, SystemClock},
+    error::{Error, Result},
+    middleware::{Middleware, Next, Operation},
+    piv::{AlgorithmId, SlotId},
+    report,
+    yubikey::{Serial, Version, YubiKey},
+};
+use log::error;
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use x509_cert::{
+    der::{
+        asn1::{BitString, GeneralizedTime, OctetString},
+        Decode, Encode, Sequence,
+    },
+    spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoRef},
+};
+
+/// Operation performed on a YubiKey, as recorded in a [`Transcript`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TranscriptEntry {
+    /// Time the operation was performed, since the Unix epoch
+    pub time: Duration,
+
+    /// Operation performed
+    pub operation: Operation,
+
+    /// Code of the error the operation failed with, if it failed
+    pub error: Option<String>,
+}
+
+/// Shared state of a transcript.
+struct State {
+    entries: Vec<TranscriptEntry>,
+    clock: Box<dyn Clock>,
+}
+
+/// [`Middleware`] recording the operations performed on a YubiKey, to be
+/// [signed](Transcript::sign) at the end of the session.
+///
+/// Clones share the same transcript, so one can be added to the YubiKey
+/// while another is kept to sign it.
+#[derive(Clone)]
+pub struct Transcript {
+    state: Arc<Mutex<State>>,
+}
+
+impl Transcript {
+    /// Start an empty transcript.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                entries: vec![],
+                clock: Box::new(SystemClock),
+            })),
+        }
+    }
+
+    /// Set the [`Clock`] the time operations are performed at is read from.
+    pub fn with_clock(self, clock: impl Clock + 'static) -> Self {
+        self.lock().clock = Box::new(clock);
+        self
+    }
+
+    /// Get the operations recorded so far.
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        self.lock().entries.clone()
+    }
+
+    /// Sign the operations recorded so far with the key in the given slot of
+    /// the YubiKey, whose public key is given.
+    ///
+    /// The key must be an RSA or ECC key. The PIN must already be verified
+    /// if the key's PIN policy requires it. Signing is itself recorded in
+    /// the transcript, after the entries it covers.
+    pub fn sign(
+        &self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        public_key: SubjectPublicKeyInfoRef<'_>,
+    ) -> Result<SignedTranscript> {
+        let entries = self.entries();
+
+        // GeneralizedTime has a resolution of one second
+        let now = clock::unix_time(yubikey.clock())?;
+        let signed_at = Duration::from_secs(now.as_secs());
+
+        let tbs = TbsTranscriptAsn1 {
+            serial: yubikey.serial().into(),
+            firmware: OctetString::new([
+                yubikey.version().major,
+                yubikey.version().minor,
+                yubikey.version().patch,
+            ])?,
+            signed_at: GeneralizedTime::from_unix_duration(signed_at)?,
+            signing_slot: slot.into(),
+            entries: entries.iter().map(EntryAsn1::new).collect::<Result<_>>()?,
+        };
+
+        let msg = tbs.to_der()?;
+        let (signature_algorithm, signature) =
+            report::sign_message(yubikey, slot, public_key, &msg)?;
+
+        let der = SignedTranscriptAsn1 {
+            tbs,
+            signature_algorithm,
+            signature,
+        }
+        .to_der()?;
+
+        Ok(SignedTranscript {
+            serial: yubikey.serial(),
+            version: yubikey.version(),
+            signed_at,
+            signing_slot: slot,
+            entries,
+            der,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl fmt::Debug for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transcript")
+            .field("entries", &self.lock().entries)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Transcript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Transcript {
+    fn handle(&mut self, operation: Operation, mut next: Next<'_>) -> Result<()> {
+        let result = next.run();
+
+        let mut state = self.lock();
+        let time = clock::unix_time(state.clock.as_ref()).unwrap_or_default();
+
+        state.entries.push(TranscriptEntry {
+            time: Duration::from_secs(time.as_secs()),
+            operation,
+            error: result.err().map(|e| e.code().to_owned()),
+        });
+
+        result
+    }
+}
+
+/// A [`Transcript`] signed by the key in one of the slots of the YubiKey.
+#[derive(Clone, Debug)]
+pub struct SignedTranscript {
+    serial: Serial,
+    version: Version,
+    signed_at: Duration,
+    signing_slot: SlotId,
+    entries: Vec<TranscriptEntry>,
+    der: Vec<u8>,
+}
+
+impl SignedTranscript {
+    /// Parse a DER encoded signed transcript.
+    ///
+    /// This doesn't verify the signature: use [`SignedTranscript::verify`]
+    /// before relying on the contents of the transcript.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let signed = SignedTranscriptAsn1::from_der(der)?;
+        let tbs = &signed.tbs;
+        let firmware: [u8; 3] = tbs.firmware.as_bytes().try_into()?;
+
+        Ok(Self {
+            serial: Serial(tbs.serial),
+            version: Version::new(firmware),
+            signed_at: tbs.signed_at.to_unix_duration(),
+            signing_slot: SlotId::try_from(tbs.signing_slot)?,
+            entries: tbs
+                .entries
+                .iter()
+                .map(EntryAsn1::to_entry)
+                .collect::<Result<_>>()?,
+            der: der.to_vec(),
+        })
+    }
+
+    /// Returns the DER encoding of this signed transcript.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Returns the serial number of the YubiKey.
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    /// Returns the firmware version of the YubiKey.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the time the transcript was signed, since the Unix epoch.
+    pub fn signed_at(&self) -> Duration {
+        self.signed_at
+    }
+
+    /// Returns the slot holding the key which signed the transcript.
+    pub fn signing_slot(&self) -> SlotId {
+        self.signing_slot
+    }
+
+    /// Returns the operations recorded in the transcript.
+    pub fn entries(&self) -> &[TranscriptEntry] {
+        &self.entries
+    }
+
+    /// Verify the signature of this transcript against the given public key.
+    ///
+    /// The public key should come from a trusted source, such as the
+    /// signing slot's attestation certificate after verifying its chain to
+    /// Yubico's attestation root.
+    pub fn verify(&self, public_key: SubjectPublicKeyInfoRef<'_>) -> Result<()> {
+        let signed = SignedTranscriptAsn1::from_der(&self.der)?;
+        let signature = signed.signature.as_bytes().ok_or(Error::SignatureError)?;
+
+        certificate::verify_signature(
+            public_key,
+            &signed.signature_algorithm,
+            &signed.tbs.to_der()?,
+            signature,
+        )
+    }
+}
+
+#[derive(Sequence)]
+struct SignedTranscriptAsn1 {
+    tbs: TbsTranscriptAsn1,
+    signature_algorithm: AlgorithmIdentifierOwned,
+    signature: BitString,
+}
+
+#[derive(Sequence)]
+struct TbsTranscriptAsn1 {
+    serial: u32,
+    firmware: OctetString,
+    signed_at: GeneralizedTime,
+    signing_slot: u8,
+    entries: Vec<EntryAsn1>,
+}
+
+#[derive(Sequence)]
+struct EntryAsn1 {
+    time: GeneralizedTime,
+    operation: u8,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", optional = "true")]
+    slot: Option<u8>,
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", optional = "true")]
+    algorithm: Option<u8>,
+    #[asn1(context_specific = "2", tag_mode = "IMPLICIT", optional = "true")]
+    object_id: Option<u32>,
+    #[asn1(context_specific = "3", tag_mode = "IMPLICIT", optional = "true")]
+    error: Option<String>,
+    #[asn1(context_specific = "4", tag_mode = "IMPLICIT", optional = "true")]
+    source_slot: Option<u8>,
+}
+
+impl EntryAsn1 {
+    fn new(entry: &TranscriptEntry) -> Result<Self> {
+        let (operation, slot, algorithm, object_id) = match entry.operation {
+            Operation::VerifyPin => (1, None, None, None),
+            Operation::Authenticate => (2, None, None, None),
+            Operation::Generate { slot, algorithm } => (3, Some(slot), Some(algorithm), None),
+            Operation::ImportKey { slot, algorithm } => (4, Some(slot), Some(algorithm), None),
+            Operation::Sign { slot, algorithm } => (5, Some(slot), Some(algorithm), None),
+            Operation::Decrypt { slot, algorithm } => (6, Some(slot), Some(algorithm), None),
+            Operation::Attest { slot } => (7, Some(slot), None, None),
+            Operation::WriteCertificate { slot } => (8, Some(slot), None, None),
+            Operation::SaveObject { object_id } => (9, None, None, Some(object_id)),
+            Operation::SetManagementKey => (10, None, None, None),
+            Operation::Reset => (11, None, None, None),
+            Operation::MoveKey { to, .. } => (12, Some(to), None, None),
+            Operation::DeleteKey { slot } => (13, Some(slot), None, None),
+            Operation::SetPinRetries => (14, None, None, None),
+            Operation::ChangePuk => (15, None, None, None),
+            Operation::BlockPuk => (16, None, None, None),
+            Operation::SetPinLastChanged => (17, None, None, None),
+            Operation::ChangePin => (18, None, None, None),
+            Operation::UnblockPin => (19, None, None, None),
+            Operation::WriteSequence => (20, None, None, None),
+            Operation::RollbackSequence => (21, None, None, None),
+            Operation::PutOathCredential => (22, None, None, None),
+        };
+
+        let source_slot = match entry.operation {
+            Operation::MoveKey { from, .. } => Some(from.into()),
+            _ => None,
+        };
+
+        Ok(Self {
+            time: GeneralizedTime::from_unix_duration(entry.time)?,
+            operation,
+            slot: slot.map(u8::from),
+            algorithm: algorithm.map(u8::from),
+            object_id,
+            error: entry.error.clone(),
+            source_slot,
+        })
+    }
+
+    fn to_entry(&self) -> Result<TranscriptEntry> {
+        let slot = || -> Result<SlotId> { SlotId::try_from(self.slot.ok_or(Error::ParseError)?) };
+        let algorithm = || -> Result<AlgorithmId> {
+            AlgorithmId::try_from(self.algorithm.ok_or(Error::ParseError)?)
+        };
+
+        let operation = match self.operation {
+            1 => Operation::VerifyPin,
+            2 => Operation::Authenticate,
+            3 => Operation::Generate {
+                slot: slot()?,
+                algorithm: algorithm()?,
+            },
+            4 => Operation::ImportKey {
+                slot: slot()?,
+                algorithm: algorithm()?,
+            },
+            5 => Operation::Sign {
+                slot: slot()?,
+                algorithm: algorithm()?,
+            },
+            6 => Operation::Decrypt {
+                slot: slot()?,
+                algorithm: algorithm()?,
+            },
+            7 => Operation::Attest { slot: slot()? },
+            8 => Operation::WriteCertificate { slot: slot()? },
+            9 => Operation::SaveObject {
+                object_id: self.object_id.ok_or(Error::ParseError)?,
+            },
+            10 => Operation::SetManagementKey,
+            11 => Operation::Reset,
+            12 => Operation::MoveKey {
+                from: SlotId::try_from(self.source_slot.ok_or(Error::ParseError)?)?,
+                to: slot()?,
+            },
+            13 => Operation::DeleteKey { slot: slot()? },
+            14 => Operation::SetPinRetries,
+            15 => Operation::ChangePuk,
+            16 => Operation::BlockPuk,
+            17 => Operation::SetPinLastChanged,
+            18 => Operation::ChangePin,
+            19 => Operation::UnblockPin,
+            20 => Operation::WriteSequence,
+            21 => Operation::RollbackSequence,
+            22 => Operation::PutOathCredential,
+            operation => {
+                error!("unknown operation in transcript: {}", operation);
+                return Err(Error::ParseError);
+            }
+        };
+
+        Ok(TranscriptEntry {
+            time: self.time.to_unix_duration(),
+            operation,
+            error: self.error.clone(),
+        })
+    }
+}
@@ -0,0 +1,217 @@
+//! Transports exchanging APDUs with a YubiKey.
+//!
+//! A [`YubiKey`](crate::YubiKey) communicates with the card through a
+//! [`Transport`]. By default, it's a `PcscTransport` connected to a PC/SC
+//! reader (with the `pcsc` feature), but other transports (e.g.
+//! [direct USB CCID access](crate::ccid), a
+//! [proxy to a remote YubiKey](crate::remote), or a simulated card for tests)
+//! can be supplied to
+//! [`YubiKey::open_with_transport`](crate::YubiKey::open_with_transport).
+
+use crate::error::Result;
+
+#[cfg(feature = "pcsc")]
+use crate::error::Error;
+#[cfg(feature = "pcsc")]
+use log::debug;
+#[cfg(feature = "pcsc")]
+use pcsc::Card;
+#[cfg(feature = "pcsc")]
+use std::ffi::CString;
+
+/// Connection to a card over which APDUs are exchanged.
+pub trait Transport: Send {
+    /// Begin a transaction with the card, during which no other application
+    /// may use it. The transaction ends when the returned [`Exchange`] is
+    /// dropped.
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>>;
+
+    /// Check the connection to the card is still usable.
+    fn is_connected(&self) -> bool;
+
+    /// Reconnect to the card, handling it with the given disposition.
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()>;
+
+    /// Disconnect from the card, handling it with the given disposition.
+    ///
+    /// The transport isn't used again once this succeeds.
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()>;
+}
+
+/// How to leave the card when reconnecting to or disconnecting from it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Disposition {
+    /// Leave the card as it is, keeping its state (e.g. a verified PIN)
+    LeaveCard,
+
+    /// Reset the card, ending any session with it
+    ResetCard,
+
+    /// Power the card down, which also ends any session with it
+    UnpowerCard,
+
+    /// Eject the card, where the reader supports it
+    EjectCard,
+}
+
+#[cfg(feature = "pcsc")]
+impl From<Disposition> for pcsc::Disposition {
+    fn from(disposition: Disposition) -> pcsc::Disposition {
+        match disposition {
+            Disposition::LeaveCard => pcsc::Disposition::LeaveCard,
+            Disposition::ResetCard => pcsc::Disposition::ResetCard,
+            Disposition::UnpowerCard => pcsc::Disposition::UnpowerCard,
+            Disposition::EjectCard => pcsc::Disposition::EjectCard,
+        }
+    }
+}
+
+/// Transaction with a card, exchanging APDUs.
+pub trait Exchange {
+    /// Transmit a serialized command APDU, and receive the response APDU
+    /// (of at most `recv_len` bytes).
+    fn transmit(&self, command: &[u8], recv_len: usize) -> Result<Vec<u8>>;
+}
+
+/// [`Transport`] to a card in a PC/SC reader.
+#[cfg(feature = "pcsc")]
+pub struct PcscTransport {
+    /// Connected card, only `None` once disconnected
+    card: Option<Card>,
+
+    /// Name of the reader, to connect again if reconnecting fails
+    reader_name: String,
+
+    /// Mode the card was connected in, to reconnect in the same mode
+    share_mode: pcsc::ShareMode,
+}
+
+#[cfg(feature = "pcsc")]
+impl PcscTransport {
+    /// Create a transport to the given card, connected to the reader with the
+    /// given name in shared mode.
+    pub fn new(card: Card, reader_name: impl Into<String>) -> Self {
+        Self {
+            card: Some(card),
+            reader_name: reader_name.into(),
+            share_mode: pcsc::ShareMode::Shared,
+        }
+    }
+
+    /// Set the mode the card was connected in, so it's reconnected in the
+    /// same mode.
+    pub fn with_share_mode(mut self, share_mode: pcsc::ShareMode) -> Self {
+        self.share_mode = share_mode;
+        self
+    }
+
+    fn card(&mut self) -> Result<&mut Card> {
+        self.card.as_mut().ok_or(Error::PcscError {
+            inner: Some(pcsc::Error::InvalidHandle),
+        })
+    }
+}
+
+#[cfg(feature = "pcsc")]
+impl Transport for PcscTransport {
+    fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+        Ok(Box::new(PcscExchange(self.card()?.transaction()?)))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.card
+            .as_ref()
+            .map_or(false, |card| card.status2_owned().is_ok())
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        let reader_name = self.reader_name.clone();
+        let share_mode = self.share_mode;
+        let card = self.card()?;
+
+        if let Err(e) = card.reconnect(share_mode, pcsc::Protocols::T1, disposition.into()) {
+            debug!("couldn't reuse card handle ({}); connecting again", e);
+            let name = CString::new(reader_name).map_err(|_| Error::GenericError)?;
+            let ctx = pcsc::Context::establish(pcsc::Scope::System)?;
+            *card = ctx.connect(&name, share_mode, pcsc::Protocols::T1)?;
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<()> {
+        let card = match self.card.take() {
+            Some(card) => card,
+            None => return Ok(()),
+        };
+
+        card.disconnect(disposition.into()).map_err(|(card, e)| {
+            self.card = Some(card);
+            e.into()
+        })
+    }
+}
+
+/// PC/SC transaction.
+#[cfg(feature = "pcsc")]
+struct PcscExchange<'tx>(pcsc::Transaction<'tx>);
+
+#[cfg(feature = "pcsc")]
+impl Exchange for PcscExchange<'_> {
+    fn transmit(&self, command: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let mut recv_buffer = vec![0u8; recv_len];
+        let len = self.0.transmit(command, &mut recv_buffer)?.len();
+        recv_buffer.truncate(len);
+        Ok(recv_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Serial, Version, YubiKey};
+
+    /// Simulated YubiKey 5 answering the commands sent when opening it.
+    struct MockTransport;
+
+    struct MockExchange;
+
+    impl Transport for MockTransport {
+        fn begin_transaction(&mut self) -> Result<Box<dyn Exchange + '_>> {
+            Ok(Box::new(MockExchange))
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn reconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+
+        fn disconnect(&mut self, _disposition: Disposition) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Exchange for MockExchange {
+        fn transmit(&self, command: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+            Ok(match command[1] {
+                // GET VERSION
+                0xfd => vec![5, 4, 3, 0x90, 0x00],
+                // GET SERIAL
+                0xf8 => vec![0x00, 0xbc, 0x61, 0x4e, 0x90, 0x00],
+                _ => vec![0x90, 0x00],
+            })
+        }
+    }
+
+    #[test]
+    fn open_with_transport() {
+        let yubikey = YubiKey::open_with_transport(MockTransport, "mock").expect("open");
+        assert_eq!(yubikey.name(), "mock");
+        assert_eq!(yubikey.version(), Version::new([5, 4, 3]));
+        assert_eq!(yubikey.serial(), Serial(12_345_678));
+        assert!(yubikey.close().is_ok());
+    }
+}
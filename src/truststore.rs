@@ -0,0 +1,130 @@
+//! Export of the CA certificates stored on a YubiKey.
+//!
+//! Applications which bootstrap trust from a hardware token need the CA
+//! certificates it carries, wherever they are stored. A [`TrustStore`]
+//! collects them from:
+//!
+//! - the [`SlotId::Attestation`] slot, holding the intermediate certificate
+//!   attestations chain to,
+//! - retired key slots holding a CA certificate (as flagged by its basic
+//!   constraints extension),
+//! - with the `untested` feature, the `msroots` enterprise trust store.
+//!
+//! The bundle can be exported as PEM with [`TrustStore::to_pem`], or the DER
+//! certificates added one by one to a TLS library's root store.
+
+use crate::{
+    certificate::Certificate,
+    error::{Error, Result},
+    piv::{self, SlotId},
+    yubikey::YubiKey,
+};
+use log::debug;
+use x509_cert::der::pem::{self, LineEnding};
+
+/// Where a [`TrustedCertificate`] was found on the YubiKey.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CertificateSource {
+    /// The attestation intermediate certificate
+    Attestation,
+
+    /// A CA certificate in a retired key slot
+    Slot(SlotId),
+
+    /// The `msroots` enterprise trust store
+    MsRoots,
+}
+
+/// CA certificate found on the YubiKey.
+#[derive(Clone, Debug)]
+pub struct TrustedCertificate {
+    /// Where the certificate was found
+    pub source: CertificateSource,
+
+    /// The certificate
+    pub certificate: Certificate,
+}
+
+/// CA certificates stored on a YubiKey.
+#[derive(Clone, Debug, Default)]
+pub struct TrustStore {
+    certificates: Vec<TrustedCertificate>,
+}
+
+impl TrustStore {
+    /// Collect the CA certificates stored on the given YubiKey.
+    ///
+    /// Certificates stored more than once are only included once.
+    pub fn collect(yubikey: &mut YubiKey) -> Result<Self> {
+        let mut store = Self::default();
+
+        for key in piv::Key::list(yubikey)? {
+            let source = match key.slot() {
+                SlotId::Attestation => CertificateSource::Attestation,
+                slot @ SlotId::Retired(_) if key.certificate().is_ca() => {
+                    CertificateSource::Slot(slot)
+                }
+                _ => continue,
+            };
+
+            store.push(source, key.certificate().clone());
+        }
+
+        #[cfg(feature = "untested")]
+        match crate::MsRoots::read(yubikey) {
+            Ok(Some(msroots)) => {
+                for certificate in msroots.certificates()? {
+                    store.push(CertificateSource::MsRoots, certificate);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => debug!("couldn't read msroots: {}", e),
+        }
+
+        Ok(store)
+    }
+
+    /// Get the certificates in this store.
+    pub fn certificates(&self) -> &[TrustedCertificate] {
+        &self.certificates
+    }
+
+    /// Is this store empty?
+    pub fn is_empty(&self) -> bool {
+        self.certificates.is_empty()
+    }
+
+    /// Encode the certificates in this store as a PEM bundle.
+    pub fn to_pem(&self) -> Result<String> {
+        let mut bundle = String::new();
+
+        for trusted in &self.certificates {
+            let encoded =
+                pem::encode_string("CERTIFICATE", LineEnding::LF, trusted.certificate.as_der())
+                    .map_err(|_| Error::GenericError)?;
+            bundle.push_str(&encoded);
+        }
+
+        Ok(bundle)
+    }
+
+    fn push(&mut self, source: CertificateSource, certificate: Certificate) {
+        if self
+            .certificates
+            .iter()
+            .any(|trusted| trusted.certificate.as_der() == certificate.as_der())
+        {
+            debug!(
+                "skipping duplicate CA certificate {}",
+                certificate.subject()
+            );
+            return;
+        }
+
+        self.certificates.push(TrustedCertificate {
+            source,
+            certificate,
+        });
+    }
+}
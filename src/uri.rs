@@ -0,0 +1,246 @@
+//! URIs identifying a key stored in a particular YubiKey.
+//!
+//! Two URI schemes are supported, which can be used in configuration files to
+//! select which key should be used:
+//!
+//! - `yubikey:serial=12345678;slot=9a`: the crate's own scheme, naming the
+//!   YubiKey by serial number and the slot by its hex identifier.
+//! - `pkcs11:serial=12345678;id=%01`: a subset of the PKCS#11 URI scheme
+//!   ([RFC 7512]), as used with Yubico's `ykcs11` module. The slot is
+//!   identified by the `CKA_ID` that `ykcs11` assigns to its key objects.
+//!
+//! The `serial` attribute is optional in both schemes; URIs without it
+//! resolve to the only YubiKey connected (see [`YubiKey::open`]).
+//!
+//! [RFC 7512]: https://www.rfc-editor.org/rfc/rfc7512
+
+use crate::{
+    error::{Error, Result},
+    piv::{RetiredSlotId, SlotId},
+    yubikey::Serial,
+};
+use log::error;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+#[cfg(feature = "pcsc")]
+use crate::yubikey::YubiKey;
+
+/// Scheme of this crate's own URIs.
+const YUBIKEY_SCHEME: &str = "yubikey:";
+
+/// Scheme of PKCS#11 URIs.
+const PKCS11_SCHEME: &str = "pkcs11:";
+
+/// Reference to a key stored in a slot of a particular YubiKey.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyUri {
+    /// Serial number of the YubiKey, if specified
+    pub serial: Option<Serial>,
+
+    /// Slot containing the key
+    pub slot: SlotId,
+}
+
+impl KeyUri {
+    /// Open the YubiKey this URI refers to.
+    #[cfg(feature = "pcsc")]
+    pub fn open(&self) -> Result<YubiKey> {
+        match self.serial {
+            Some(serial) => YubiKey::open_by_serial(serial),
+            None => YubiKey::open(),
+        }
+    }
+
+    /// Format this URI as a PKCS#11 URI understood by `ykcs11`.
+    pub fn to_pkcs11(&self) -> Result<String> {
+        let id = pkcs11_id(self.slot).ok_or(Error::ArgumentError)?;
+
+        Ok(match self.serial {
+            Some(serial) => format!("{}serial={};id=%{:02X}", PKCS11_SCHEME, serial, id),
+            None => format!("{}id=%{:02X}", PKCS11_SCHEME, id),
+        })
+    }
+
+    /// Parse the attributes of a `yubikey:` URI.
+    fn parse_yubikey(attrs: &str) -> Result<Self> {
+        let mut serial = None;
+        let mut slot = None;
+
+        for (name, value) in attributes(attrs)? {
+            match name {
+                "serial" => serial = Some(value.parse()?),
+                "slot" => slot = Some(value.parse()?),
+                _ => {
+                    error!("unknown attribute in YubiKey URI: {}", name);
+                    return Err(Error::ParseError);
+                }
+            }
+        }
+
+        Ok(Self {
+            serial,
+            slot: slot.ok_or(Error::ParseError)?,
+        })
+    }
+
+    /// Parse the path attributes of a `pkcs11:` URI.
+    ///
+    /// Attributes which don't narrow down the key (e.g. `token` or `type`)
+    /// are ignored, as is the query component.
+    fn parse_pkcs11(uri: &str) -> Result<Self> {
+        let path = uri.split('?').next().unwrap_or_default();
+        let mut serial = None;
+        let mut slot = None;
+
+        for (name, value) in attributes(path)? {
+            match name {
+                "serial" => serial = Some(percent_decode_str(value)?.parse()?),
+                "id" => match percent_decode(value)?.as_slice() {
+                    [id] => slot = Some(slot_from_pkcs11_id(*id)?),
+                    _ => return Err(Error::ParseError),
+                },
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            serial,
+            slot: slot.ok_or(Error::ParseError)?,
+        })
+    }
+}
+
+impl FromStr for KeyUri {
+    type Err = Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        if let Some(attrs) = uri.strip_prefix(YUBIKEY_SCHEME) {
+            Self::parse_yubikey(attrs)
+        } else if let Some(attrs) = uri.strip_prefix(PKCS11_SCHEME) {
+            Self::parse_pkcs11(attrs)
+        } else {
+            error!("unsupported key URI scheme: {}", uri);
+            Err(Error::ParseError)
+        }
+    }
+}
+
+impl Display for KeyUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(YUBIKEY_SCHEME)?;
+
+        if let Some(serial) = self.serial {
+            write!(f, "serial={};", serial)?;
+        }
+
+        write!(f, "slot={:02x}", u8::from(self.slot))
+    }
+}
+
+/// Split `name=value` attributes separated by `;`.
+fn attributes(attrs: &str) -> Result<Vec<(&str, &str)>> {
+    attrs
+        .split(';')
+        .filter(|attr| !attr.is_empty())
+        .map(|attr| attr.split_once('=').ok_or(Error::ParseError))
+        .collect()
+}
+
+/// Decode a percent-encoded attribute value.
+fn percent_decode(value: &str) -> Result<Vec<u8>> {
+    let mut bytes = value.bytes();
+    let mut decoded = Vec::with_capacity(value.len());
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().ok_or(Error::ParseError)?,
+                bytes.next().ok_or(Error::ParseError)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| Error::ParseError)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| Error::ParseError)?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Decode a percent-encoded attribute value as a string.
+fn percent_decode_str(value: &str) -> Result<String> {
+    String::from_utf8(percent_decode(value)?).map_err(|_| Error::ParseError)
+}
+
+/// Get the `CKA_ID` assigned by `ykcs11` to the key in the given slot.
+fn pkcs11_id(slot: SlotId) -> Option<u8> {
+    match slot {
+        SlotId::Authentication => Some(1),
+        SlotId::Signature => Some(2),
+        SlotId::KeyManagement => Some(3),
+        SlotId::CardAuthentication => Some(4),
+        SlotId::Retired(retired) => Some(u8::from(retired) - u8::from(RetiredSlotId::R1) + 5),
+        SlotId::Attestation => Some(25),
+        SlotId::Management(_) => None,
+    }
+}
+
+/// Get the slot holding the key `ykcs11` assigns the given `CKA_ID` to.
+fn slot_from_pkcs11_id(id: u8) -> Result<SlotId> {
+    match id {
+        1 => Ok(SlotId::Authentication),
+        2 => Ok(SlotId::Signature),
+        3 => Ok(SlotId::KeyManagement),
+        4 => Ok(SlotId::CardAuthentication),
+        5..=24 => {
+            RetiredSlotId::try_from(id - 5 + u8::from(RetiredSlotId::R1)).map(SlotId::Retired)
+        }
+        25 => Ok(SlotId::Attestation),
+        _ => Err(Error::ParseError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_yubikey_uri() {
+        let uri: KeyUri = "yubikey:serial=12345678;slot=9a"
+            .parse()
+            .expect("valid URI");
+        assert_eq!(uri.serial, Some(Serial(12345678)));
+        assert_eq!(uri.slot, SlotId::Authentication);
+        assert_eq!(uri.to_string(), "yubikey:serial=12345678;slot=9a");
+
+        let uri: KeyUri = "yubikey:slot=82".parse().expect("valid URI");
+        assert_eq!(uri.serial, None);
+        assert_eq!(uri.slot, SlotId::Retired(RetiredSlotId::R1));
+
+        assert!("yubikey:serial=12345678".parse::<KeyUri>().is_err());
+        assert!("yubikey:slot=9a;color=red".parse::<KeyUri>().is_err());
+    }
+
+    #[test]
+    fn parse_pkcs11_uri() {
+        let uri: KeyUri = "pkcs11:token=YubiKey%20PIV;serial=12345678;id=%02;type=private"
+            .parse()
+            .expect("valid URI");
+        assert_eq!(uri.serial, Some(Serial(12345678)));
+        assert_eq!(uri.slot, SlotId::Signature);
+
+        let uri: KeyUri = "pkcs11:id=%18?pin-source=file:/pin"
+            .parse()
+            .expect("valid URI");
+        assert_eq!(uri.slot, SlotId::Retired(RetiredSlotId::R20));
+        assert_eq!(
+            uri.to_pkcs11().expect("slot has a PKCS#11 ID"),
+            "pkcs11:id=%18"
+        );
+
+        assert!("pkcs11:id=%1A".parse::<KeyUri>().is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Guardrails separating the usages of keys in different PIV slots.
+//!
+//! NIST SP 800-73-4 assigns each PIV slot a purpose: the key in the
+//! [`SlotId::Signature`] slot is meant for digital signatures, and the key in
+//! the [`SlotId::KeyManagement`] slot is meant for encryption. The YubiKey
+//! itself doesn't enforce this, so accidentally mixing key usages can go
+//! unnoticed and break compliance.
+//!
+//! A [`KeyUsagePolicy`] can be set on a [`YubiKey`](crate::YubiKey) with
+//! [`YubiKey::set_key_usage_policy`](crate::YubiKey::set_key_usage_policy) to
+//! refuse such operations before they reach the card.
+
+use crate::{
+    error::{Error, Result},
+    piv::SlotId,
+};
+use log::error;
+use std::collections::BTreeMap;
+
+/// Private key operations which can be performed with a PIV key.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum KeyUsage {
+    /// Creating digital signatures.
+    Sign,
+
+    /// Decrypting data or performing key agreement.
+    Decrypt,
+}
+
+/// Policy describing which [`KeyUsage`]s are permitted for each slot.
+///
+/// The default policy is [`KeyUsagePolicy::permissive`], which allows any
+/// usage with any slot.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyUsagePolicy {
+    /// Enforce the usages assigned to slots by SP 800-73-4
+    strict: bool,
+
+    /// Usages explicitly allowed or denied for particular slots
+    overrides: BTreeMap<(SlotId, KeyUsage), bool>,
+}
+
+impl KeyUsagePolicy {
+    /// Policy which allows any usage with any slot.
+    pub fn permissive() -> Self {
+        Self::default()
+    }
+
+    /// Policy which refuses to decrypt with the key in the
+    /// [`SlotId::Signature`] slot, and to sign with the key in the
+    /// [`SlotId::KeyManagement`] slot.
+    pub fn strict() -> Self {
+        Self {
+            strict: true,
+            overrides: BTreeMap::new(),
+        }
+    }
+
+    /// Allow the given usage of the key in the given slot, regardless of the
+    /// rest of the policy.
+    pub fn allow(mut self, slot: SlotId, usage: KeyUsage) -> Self {
+        self.overrides.insert((slot, usage), true);
+        self
+    }
+
+    /// Deny the given usage of the key in the given slot, regardless of the
+    /// rest of the policy.
+    pub fn deny(mut self, slot: SlotId, usage: KeyUsage) -> Self {
+        self.overrides.insert((slot, usage), false);
+        self
+    }
+
+    /// Is the given usage of the key in the given slot permitted?
+    pub fn is_allowed(&self, slot: SlotId, usage: KeyUsage) -> bool {
+        if let Some(&allowed) = self.overrides.get(&(slot, usage)) {
+            return allowed;
+        }
+
+        !self.strict
+            || !matches!(
+                (slot, usage),
+                (SlotId::Signature, KeyUsage::Decrypt) | (SlotId::KeyManagement, KeyUsage::Sign)
+            )
+    }
+
+    /// Check the given usage of the key in the given slot is permitted,
+    /// returning [`Error::OperationDenied`] if it isn't.
+    pub fn check(&self, slot: SlotId, usage: KeyUsage) -> Result<()> {
+        if self.is_allowed(slot, usage) {
+            Ok(())
+        } else {
+            error!("{:?} with the key in slot {} denied by policy", usage, slot);
+            Err(Error::OperationDenied)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piv::RetiredSlotId;
+
+    #[test]
+    fn permissive() {
+        let policy = KeyUsagePolicy::permissive();
+        assert!(policy.is_allowed(SlotId::Signature, KeyUsage::Decrypt));
+        assert!(policy.is_allowed(SlotId::KeyManagement, KeyUsage::Sign));
+    }
+
+    #[test]
+    fn strict() {
+        let policy = KeyUsagePolicy::strict();
+        assert!(policy.is_allowed(SlotId::Signature, KeyUsage::Sign));
+        assert!(policy.is_allowed(SlotId::KeyManagement, KeyUsage::Decrypt));
+        assert!(policy.is_allowed(SlotId::Authentication, KeyUsage::Sign));
+        assert_eq!(
+            policy.check(SlotId::Signature, KeyUsage::Decrypt),
+            Err(Error::OperationDenied)
+        );
+        assert_eq!(
+            policy.check(SlotId::KeyManagement, KeyUsage::Sign),
+            Err(Error::OperationDenied)
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let retired = SlotId::Retired(RetiredSlotId::R1);
+        let policy = KeyUsagePolicy::strict()
+            .allow(SlotId::KeyManagement, KeyUsage::Sign)
+            .deny(retired, KeyUsage::Decrypt);
+
+        assert!(policy.is_allowed(SlotId::KeyManagement, KeyUsage::Sign));
+        assert!(!policy.is_allowed(SlotId::Signature, KeyUsage::Decrypt));
+        assert!(!policy.is_allowed(retired, KeyUsage::Decrypt));
+        assert!(policy.is_allowed(retired, KeyUsage::Sign));
+    }
+}
@@ -0,0 +1,222 @@
+//! Host-side verification of signatures produced by a YubiKey.
+//!
+//! These helpers take the public key as a [`SubjectPublicKeyInfoRef`] (as
+//! returned by [`piv::generate`](crate::piv::generate) or read from a
+//! certificate) and accept signatures in exactly the encoding produced by
+//! [`piv::sign_data`](crate::piv::sign_data):
+//!
+//! - RSA signatures as raw big-endian integers the size of the modulus
+//! - ECDSA signatures as ASN.1 DER `Ecdsa-Sig-Value`s
+//! - Ed25519 signatures as 64-byte `R || S` values
+//!
+//! All functions return [`Error::SignatureError`] if the signature is
+//! invalid, and [`Error::KeyError`] if the public key is not of the expected
+//! type.
+
+use crate::error::{Error, Result};
+use rsa::{pkcs1v15, pss, RsaPublicKey};
+use sha2::digest::{Digest, FixedOutputReset};
+use signature::{hazmat::PrehashVerifier, Verifier};
+use x509_cert::{
+    der::oid::{AssociatedOid, ObjectIdentifier},
+    spki::SubjectPublicKeyInfoRef,
+};
+
+/// Object identifier of Ed25519 public keys and signatures (RFC 8410).
+pub const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Verify an RSASSA-PKCS#1v1.5 signature over `msg`, hashed with `D`.
+pub fn pkcs1v15<D>(
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<()>
+where
+    D: Digest + AssociatedOid,
+{
+    let public_key = RsaPublicKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+    let signature = pkcs1v15::Signature::try_from(signature).map_err(|_| Error::SignatureError)?;
+
+    pkcs1v15::VerifyingKey::<D>::new(public_key)
+        .verify(msg, &signature)
+        .map_err(|_| Error::SignatureError)
+}
+
+/// Verify an RSASSA-PSS signature over `msg`, hashed with `D` and using MGF1
+/// with the same digest.
+pub fn pss<D>(public_key: SubjectPublicKeyInfoRef<'_>, msg: &[u8], signature: &[u8]) -> Result<()>
+where
+    D: Digest + FixedOutputReset,
+{
+    let public_key = RsaPublicKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+    let signature = pss::Signature::try_from(signature).map_err(|_| Error::SignatureError)?;
+
+    pss::VerifyingKey::<D>::new(public_key)
+        .verify(msg, &signature)
+        .map_err(|_| Error::SignatureError)
+}
+
+/// Verify an ECDSA signature over `msg`, hashed with `D`, made with a P-256
+/// or P-384 key.
+pub fn ecdsa<D>(public_key: SubjectPublicKeyInfoRef<'_>, msg: &[u8], signature: &[u8]) -> Result<()>
+where
+    D: Digest,
+{
+    ecdsa_prehash(public_key, &D::digest(msg), signature)
+}
+
+/// Verify an ECDSA signature over an already computed digest, made with a
+/// P-256 or P-384 key.
+pub fn ecdsa_prehash(
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    digest: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let curve = public_key
+        .algorithm
+        .parameters_oid()
+        .map_err(|_| Error::KeyError)?;
+
+    let result = if curve == p256::NistP256::OID {
+        let public_key =
+            p256::ecdsa::VerifyingKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+        let signature =
+            p256::ecdsa::Signature::from_der(signature).map_err(|_| Error::SignatureError)?;
+        public_key.verify_prehash(digest, &signature)
+    } else if curve == p384::NistP384::OID {
+        let public_key =
+            p384::ecdsa::VerifyingKey::try_from(public_key).map_err(|_| Error::KeyError)?;
+        let signature =
+            p384::ecdsa::Signature::from_der(signature).map_err(|_| Error::SignatureError)?;
+        public_key.verify_prehash(digest, &signature)
+    } else {
+        return Err(Error::KeyError);
+    };
+
+    result.map_err(|_| Error::SignatureError)
+}
+
+/// Verify an Ed25519 signature over `msg`.
+pub fn ed25519(
+    public_key: SubjectPublicKeyInfoRef<'_>,
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    if public_key.algorithm.oid != ED25519_OID {
+        return Err(Error::KeyError);
+    }
+
+    let public_key = public_key
+        .subject_public_key
+        .as_bytes()
+        .and_then(|bytes| bytes.try_into().ok())
+        .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(bytes).ok())
+        .ok_or(Error::KeyError)?;
+    let signature =
+        ed25519_dalek::Signature::from_slice(signature).map_err(|_| Error::SignatureError)?;
+
+    public_key
+        .verify(msg, &signature)
+        .map_err(|_| Error::SignatureError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+    use rsa::{pkcs8::EncodePublicKey, RsaPrivateKey};
+    use sha2::Sha256;
+    use signature::{RandomizedSigner, SignatureEncoding, Signer};
+    use x509_cert::der::{Decode, Document};
+
+    const MSG: &[u8] = b"verified";
+
+    fn spki(document: &Document) -> SubjectPublicKeyInfoRef<'_> {
+        SubjectPublicKeyInfoRef::from_der(document.as_bytes()).expect("SPKI")
+    }
+
+    #[test]
+    fn verify_rsa() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 1024).expect("RSA key");
+        let public_key = private_key
+            .to_public_key()
+            .to_public_key_der()
+            .expect("public key");
+        let public_key = spki(&public_key);
+
+        let signature = pkcs1v15::SigningKey::<Sha256>::new(private_key.clone())
+            .sign(MSG)
+            .to_vec();
+        assert_eq!(
+            pkcs1v15::<Sha256>(public_key.clone(), MSG, &signature),
+            Ok(())
+        );
+        assert_eq!(
+            pkcs1v15::<Sha256>(public_key.clone(), b"other", &signature),
+            Err(Error::SignatureError)
+        );
+
+        let signature = pss::BlindedSigningKey::<Sha256>::new(private_key)
+            .sign_with_rng(&mut OsRng, MSG)
+            .to_vec();
+        assert_eq!(pss::<Sha256>(public_key.clone(), MSG, &signature), Ok(()));
+        assert_eq!(
+            ecdsa::<Sha256>(public_key, MSG, &signature),
+            Err(Error::KeyError)
+        );
+    }
+
+    #[test]
+    fn verify_ecdsa() {
+        let signing_key = p256::ecdsa::SigningKey::random(&mut OsRng);
+        let public_key = signing_key
+            .verifying_key()
+            .to_public_key_der()
+            .expect("public key");
+        let public_key = spki(&public_key);
+
+        let signature: p256::ecdsa::Signature = signing_key.sign(MSG);
+        let signature = signature.to_der();
+        assert_eq!(
+            ecdsa::<Sha256>(public_key.clone(), MSG, signature.as_bytes()),
+            Ok(())
+        );
+        assert_eq!(
+            ecdsa_prehash(
+                public_key.clone(),
+                &Sha256::digest(MSG),
+                signature.as_bytes()
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            ecdsa::<Sha256>(public_key.clone(), b"other", signature.as_bytes()),
+            Err(Error::SignatureError)
+        );
+        assert_eq!(
+            ed25519(public_key, MSG, signature.as_bytes()),
+            Err(Error::KeyError)
+        );
+    }
+
+    #[test]
+    fn verify_ed25519() {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let public_key = signing_key
+            .verifying_key()
+            .to_public_key_der()
+            .expect("public key");
+        let public_key = spki(&public_key);
+
+        let signature = signing_key.sign(MSG).to_bytes();
+        assert_eq!(ed25519(public_key.clone(), MSG, &signature), Ok(()));
+        assert_eq!(
+            ed25519(public_key.clone(), b"other", &signature),
+            Err(Error::SignatureError)
+        );
+        assert_eq!(
+            pkcs1v15::<Sha256>(public_key, MSG, &signature),
+            Err(Error::KeyError)
+        );
+    }
+}
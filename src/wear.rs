@@ -0,0 +1,107 @@
+//! Accounting of writes to data objects.
+//!
+//! Data objects are stored in flash, which endures a limited number of writes.
+//! Provisioning workflows which rewrite the same objects over and over (e.g.
+//! in a loop, or on every run of a script) can wear it out without anyone
+//! noticing. A [`WriteLog`] counts the writes made to each object through a
+//! [`YubiKey`](crate::YubiKey) since it was opened, and logs a warning once an
+//! object's writes exceed a budget set with
+//! [`YubiKey::set_write_budget`](crate::YubiKey::set_write_budget).
+//!
+//! The counts are best-effort: writes made by other processes, or before the
+//! YubiKey was opened, aren't accounted for.
+
+use crate::ObjectId;
+use log::warn;
+use std::collections::BTreeMap;
+
+/// Writes made to each data object during a session.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteLog {
+    /// Number of writes to each object
+    writes: BTreeMap<ObjectId, u32>,
+
+    /// Number of writes to a single object above which a warning is logged
+    budget: Option<u32>,
+}
+
+impl WriteLog {
+    /// Get the number of writes made to the given object.
+    pub fn writes(&self, object_id: ObjectId) -> u32 {
+        self.writes.get(&object_id).copied().unwrap_or_default()
+    }
+
+    /// Get the total number of writes made to all objects.
+    pub fn total(&self) -> u32 {
+        self.writes.values().sum()
+    }
+
+    /// Iterate over the written objects and their number of writes.
+    pub fn iter(&self) -> impl Iterator<Item = (ObjectId, u32)> + '_ {
+        self.writes
+            .iter()
+            .map(|(&object_id, &writes)| (object_id, writes))
+    }
+
+    /// Get the per-object write budget, if any.
+    pub fn budget(&self) -> Option<u32> {
+        self.budget
+    }
+
+    /// Get the number of writes which can be made to the given object before
+    /// it exceeds the write budget, if one is set.
+    pub fn remaining(&self, object_id: ObjectId) -> Option<u32> {
+        self.budget
+            .map(|budget| budget.saturating_sub(self.writes(object_id)))
+    }
+
+    /// Iterate over the objects whose writes exceed the write budget.
+    pub fn over_budget(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        self.iter()
+            .filter(|&(_, writes)| self.budget.map_or(false, |budget| writes > budget))
+            .map(|(object_id, _)| object_id)
+    }
+
+    /// Set the per-object write budget.
+    pub(crate) fn set_budget(&mut self, budget: Option<u32>) {
+        self.budget = budget;
+    }
+
+    /// Record a write to the given object, warning when it first exceeds the
+    /// write budget.
+    pub(crate) fn record(&mut self, object_id: ObjectId) {
+        let writes = self.writes.entry(object_id).or_default();
+        *writes = writes.saturating_add(1);
+
+        if self.budget == Some(*writes - 1) {
+            warn!(
+                "object 0x{:06x} has been written {} times this session, exceeding the write budget",
+                object_id, writes
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let mut log = WriteLog::default();
+        log.set_budget(Some(2));
+
+        log.record(0x5fc10a);
+        log.record(0x5fc10a);
+        log.record(0x5fc10b);
+
+        assert_eq!(log.total(), 3);
+        assert_eq!(log.remaining(0x5fc10a), Some(0));
+        assert_eq!(log.over_budget().count(), 0);
+
+        log.record(0x5fc10a);
+
+        assert_eq!(log.writes(0x5fc10a), 3);
+        assert_eq!(log.over_budget().collect::<Vec<_>>(), [0x5fc10a]);
+    }
+}
@@ -0,0 +1,314 @@
+//! Export of the APDUs exchanged with a YubiKey, for attaching to bug reports.
+//!
+//! A [`WireLog`] records every command sent to the YubiKey and every response
+//! received from it in the [pcap] format, so traces can be inspected with
+//! tools such as Wireshark or `tcpdump`. Packets use the `LINKTYPE_USER0`
+//! link type, and consist of one direction byte (`0` for commands sent to the
+//! YubiKey, `1` for responses) followed by the APDU.
+//!
+//! Applications doing their own audit logging or debugging can instead
+//! receive the APDUs with an [`ApduObserver`], without enabling `trace` level
+//! logging (which doesn't redact anything).
+//!
+//! In both cases, secrets are redacted by zeroing them (preserving their
+//! length):
+//!
+//! - the data of commands carrying PINs, PUKs, management keys or private
+//!   keys (VERIFY, CHANGE REFERENCE DATA, RESET RETRY COUNTER, SET MGM KEY
+//!   and IMPORT KEY)
+//! - the PIN-protected management key stored in the printed information
+//!   object, when it's read or written
+//!
+//! [pcap]: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-03.html
+
+use crate::{apdu::Ins, metadata::OBJ_PRINTED, oath};
+use log::error;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// pcap link type for private use.
+const LINKTYPE_USER0: u32 = 147;
+
+/// Maximum length of a recorded packet.
+const SNAPLEN: u32 = 65535;
+
+/// Direction byte of commands sent to the YubiKey.
+const DIRECTION_COMMAND: u8 = 0;
+
+/// Direction byte of responses received from the YubiKey.
+const DIRECTION_RESPONSE: u8 = 1;
+
+/// Length of the header of a command APDU (CLA, INS, P1, P2, Lc).
+pub(crate) const COMMAND_HEADER_LEN: usize = 5;
+
+/// Length of the header of an extended command APDU with data (CLA, INS, P1,
+/// P2, and Lc as a zero byte followed by two bytes).
+const EXTENDED_COMMAND_HEADER_LEN: usize = 7;
+
+/// Observer of the APDUs exchanged with a YubiKey, with secrets redacted.
+///
+/// Set it on a [`YubiKey`](crate::YubiKey) with
+/// [`YubiKey::set_apdu_observer`](crate::YubiKey::set_apdu_observer).
+pub trait ApduObserver: Send {
+    /// Observe a command APDU sent to the YubiKey.
+    fn command(&mut self, apdu: &[u8]);
+
+    /// Observe a response APDU (data and status words) received from the
+    /// YubiKey.
+    fn response(&mut self, apdu: &[u8]);
+}
+
+/// [`ApduObserver`] and the state of the redaction of the APDUs it observes.
+pub(crate) struct Observer {
+    inner: Box<dyn ApduObserver>,
+    redactor: Redactor,
+}
+
+impl Observer {
+    pub fn new(inner: Box<dyn ApduObserver>) -> Self {
+        Self {
+            inner,
+            redactor: Redactor::default(),
+        }
+    }
+
+    pub fn command(&mut self, command: &[u8]) {
+        let command = self.redactor.command(command);
+        self.inner.command(&command);
+    }
+
+    pub fn response(&mut self, response: &[u8]) {
+        let response = self.redactor.response(response);
+        self.inner.response(&response);
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observer").finish_non_exhaustive()
+    }
+}
+
+/// Redaction of secrets from a sequence of APDUs.
+#[derive(Debug, Default)]
+struct Redactor {
+    /// Whether the data of responses to the last command must be redacted
+    redact_response: bool,
+}
+
+impl Redactor {
+    /// Redact a command sent to the YubiKey.
+    fn command(&mut self, command: &[u8]) -> Vec<u8> {
+        let mut command = command.to_vec();
+
+        if command.len() > COMMAND_HEADER_LEN {
+            let ins = Ins::from(command[1]);
+            let header_len = if command[COMMAND_HEADER_LEN - 1] == 0
+                && command.len() > EXTENDED_COMMAND_HEADER_LEN
+            {
+                EXTENDED_COMMAND_HEADER_LEN
+            } else {
+                COMMAND_HEADER_LEN
+            };
+            let data = &mut command[header_len..];
+
+            // A GET RESPONSE continues the response to the previous command
+            if ins != Ins::GetResponseApdu {
+                self.redact_response = ins == Ins::GetData && is_printed_object(data);
+            }
+
+            let redact_command =
+                is_secret_command(ins) || (ins == Ins::PutData && is_printed_object(data));
+
+            if redact_command {
+                data.fill(0);
+            }
+        } else if command.get(1).copied().map(Ins::from) != Some(Ins::GetResponseApdu) {
+            self.redact_response = false;
+        }
+
+        command
+    }
+
+    /// Redact a response received from the YubiKey.
+    fn response(&self, response: &[u8]) -> Vec<u8> {
+        let mut response = response.to_vec();
+
+        if self.redact_response {
+            let data_len = response.len().saturating_sub(2);
+            response[..data_len].fill(0);
+        }
+
+        response
+    }
+}
+
+/// Log of the APDUs exchanged with a YubiKey, written in the pcap format.
+///
+/// Set it on a [`YubiKey`](crate::YubiKey) with
+/// [`YubiKey::set_wire_log`](crate::YubiKey::set_wire_log).
+pub struct WireLog {
+    /// Where the log is written
+    writer: Box<dyn Write + Send>,
+
+    redactor: Redactor,
+}
+
+impl WireLog {
+    /// Create a wire log writing to the file at the given path, replacing it
+    /// if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Create a wire log writing to the given writer.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+
+        writer.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_USER0.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            redactor: Redactor::default(),
+        })
+    }
+
+    /// Record a command sent to the YubiKey.
+    pub(crate) fn record_command(&mut self, command: &[u8]) {
+        let command = self.redactor.command(command);
+        self.record(DIRECTION_COMMAND, &command);
+    }
+
+    /// Record a response received from the YubiKey.
+    pub(crate) fn record_response(&mut self, response: &[u8]) {
+        let response = self.redactor.response(response);
+        self.record(DIRECTION_RESPONSE, &response);
+    }
+
+    fn record(&mut self, direction: u8, apdu: &[u8]) {
+        if let Err(e) = self.write_packet(direction, apdu) {
+            error!("couldn't write to wire log: {}", e);
+        }
+    }
+
+    fn write_packet(&mut self, direction: u8, apdu: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let len = u32::try_from(apdu.len() + 1)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "APDU too long"))?;
+
+        // The seconds field is 32 bits wide in the pcap format
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&[direction])?;
+        self.writer.write_all(apdu)?;
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for WireLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireLog").finish_non_exhaustive()
+    }
+}
+
+/// Does the data of commands with the given instruction carry PINs, PUKs,
+/// management keys, private keys or OATH secrets?
+pub(crate) fn is_secret_command(ins: Ins) -> bool {
+    matches!(
+        ins,
+        Ins::Verify
+            | Ins::ChangeReference
+            | Ins::ResetRetry
+            | Ins::SetMgmKey
+            | Ins::ImportKey
+            | Ins::Other(oath::INS_PUT)
+    )
+}
+
+/// Does the data of a GET DATA or PUT DATA command refer to the printed
+/// information object?
+fn is_printed_object(data: &[u8]) -> bool {
+    let [_, id @ ..] = OBJ_PRINTED.to_be_bytes();
+    data.starts_with(&[0x5c, 0x03]) && data[2..].starts_with(&id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("poisoned").write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Get the packets written to a wire log.
+    fn packets(log: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        let mut rest = &log[24..];
+
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().expect("4 bytes")) as usize;
+            packets.push(rest[16..16 + len].to_vec());
+            rest = &rest[16 + len..];
+        }
+
+        packets
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let shared = Shared::default();
+        let mut log = WireLog::new(shared.clone()).expect("header written");
+
+        log.record_command(&[0x00, 0x20, 0x00, 0x80, 0x02, 0x31, 0x32]);
+        log.record_response(&[0x90, 0x00]);
+        log.record_command(&[0x00, 0xcb, 0x3f, 0xff, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x09]);
+        log.record_response(&[0x53, 0x01, 0xaa, 0x61, 0x01]);
+        log.record_command(&[0x00, 0xc0, 0x00, 0x00]);
+        log.record_response(&[0xbb, 0x90, 0x00]);
+        log.record_command(&[0x00, 0xcb, 0x3f, 0xff, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x02]);
+        log.record_response(&[0x53, 0x01, 0xcc, 0x90, 0x00]);
+        log.record_command(&[
+            0x00, 0xcb, 0x3f, 0xff, 0x00, 0x00, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x09, 0x00, 0x00,
+        ]);
+        log.record_response(&[0x53, 0x01, 0xdd, 0x90, 0x00]);
+
+        let log = shared.0.lock().expect("poisoned");
+        assert_eq!(&log[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+
+        let packets = packets(&log);
+        assert_eq!(packets.len(), 10);
+        assert_eq!(packets[0], [0x00, 0x00, 0x20, 0x00, 0x80, 0x02, 0x00, 0x00]);
+        assert_eq!(packets[3], [0x01, 0x00, 0x00, 0x00, 0x61, 0x01]);
+        assert_eq!(packets[5], [0x01, 0x00, 0x90, 0x00]);
+        assert_eq!(packets[7], [0x01, 0x53, 0x01, 0xcc, 0x90, 0x00]);
+        assert_eq!(packets[9], [0x01, 0x00, 0x00, 0x00, 0x90, 0x00]);
+    }
+}
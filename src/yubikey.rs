@@ -31,36 +31,65 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 use crate::{
+    advisory::{self, Advisory},
     apdu::{Apdu, Ins},
+    capability::Capability,
     cccid::CccId,
     chuid::ChuId,
+    clock::{Clock, SystemClock},
     config::Config,
+    device::DeviceInfo,
     error::{Error, Result},
-    mgm::{MgmKey, MgmKeyAlgorithm},
-    piv,
-    reader::{Context, Reader},
+    mgm::{self, MgmKey, MgmKeyAlgorithm},
+    middleware::{self, Middleware, Operation},
+    otp,
+    pin::{self, PendingPin, PinEscalation, PinProvider},
+    piv::{self, ManagementAlgorithmId, ManagementSlotId, MgmMetadata, PinMetadata, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    ratelimit::{RateLimiter, RateLimits},
+    scp03::{Scp03Keys, SecureChannel},
+    scp11::Scp11Params,
+    signer::{AnySigner, PssSigner, SlotSigner},
     transaction::Transaction,
+    transport::{Disposition, Transport},
+    usage::KeyUsagePolicy,
+    wear::WriteLog,
+    wirelog::{ApduObserver, Observer, WireLog},
+    Buffer,
 };
-use log::{error, info};
-use pcsc::{Card, Disposition};
+use log::{debug, error, info, warn};
 use rand_core::{OsRng, RngCore};
+use secrecy::ExposeSecret;
 use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
     fmt::{self, Display},
+    mem,
+    ops::{Deref, DerefMut},
     str::FromStr,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, SystemTime},
 };
 
+#[cfg(feature = "pcsc")]
+use crate::{
+    reader::{ConnectedYubiKey, Context, OpenOptions, Reader},
+    readonly::ReadOnlyYubiKey,
+    transport::PcscTransport,
+};
+
+#[cfg(feature = "serde")]
+use crate::info::Info;
+
 #[cfg(feature = "untested")]
-use {
-    crate::{
-        apdu::StatusWords,
-        consts::{TAG_ADMIN_FLAGS_1, TAG_ADMIN_TIMESTAMP},
-        metadata::AdminData,
-        mgm,
-        transaction::ChangeRefAction,
-        Buffer, ObjectId,
-    },
-    secrecy::ExposeSecret,
-    std::time::{SystemTime, UNIX_EPOCH},
+use crate::{
+    apdu::StatusWords,
+    clock,
+    consts::{TAG_ADMIN_FLAGS_1, TAG_ADMIN_TIMESTAMP},
+    metadata::AdminData,
+    signer::SlotDecryptor,
+    transaction::ChangeRefAction,
+    ObjectId,
 };
 
 /// Flag for PUK blocked
@@ -71,11 +100,19 @@ pub(crate) const KEY_CARDMGM: u8 = 0x9b;
 
 const TAG_DYN_AUTH: u8 = 0x7c;
 
+/// Idle time after which the connection is revalidated before use.
+const DEFAULT_REVALIDATE_AFTER: Duration = Duration::from_secs(60);
+
 /// Cached YubiKey PIN.
 pub type CachedPin = secrecy::SecretVec<u8>;
 
 /// YubiKey serial number.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct Serial(pub u32);
 
 impl From<u32> for Serial {
@@ -104,11 +141,81 @@ impl TryFrom<&[u8]> for Serial {
     }
 }
 
+/// Prefix of OTP public IDs derived from the serial number (as with
+/// `ykman otp yubiotp --serial-public-id`).
+const SERIAL_PUBLIC_ID_PREFIX: [u8; 2] = [0xff, 0x00];
+
+impl Serial {
+    /// Format this serial number in the given format.
+    pub fn format(self, format: SerialFormat) -> String {
+        match format {
+            SerialFormat::Decimal => self.0.to_string(),
+            SerialFormat::Hex => format!("{:08x}", self.0),
+            SerialFormat::Modhex => otp::modhex_encode(&self.0.to_be_bytes()),
+        }
+    }
+
+    /// Parse a serial number in the given format.
+    pub fn parse_as(s: &str, format: SerialFormat) -> Result<Self> {
+        match format {
+            SerialFormat::Decimal => s.parse().map(Serial).map_err(|_| Error::ParseError),
+            SerialFormat::Hex => {
+                let hex = s
+                    .strip_prefix("0x")
+                    .or_else(|| s.strip_prefix("0X"))
+                    .unwrap_or(s);
+
+                u32::from_str_radix(hex, 16)
+                    .map(Serial)
+                    .map_err(|_| Error::ParseError)
+            }
+            SerialFormat::Modhex => {
+                let bytes = otp::modhex_decode(s).ok_or(Error::ParseError)?;
+                Serial::try_from(bytes.as_slice()).map_err(|_| Error::ParseError)
+            }
+        }
+    }
+
+    /// Get the OTP public ID derived from this serial number, i.e. the
+    /// modhex prefix of the OTPs typed by a YubiKey configured to use its
+    /// serial number as its public ID.
+    pub fn otp_public_id(self) -> String {
+        let mut id = SERIAL_PUBLIC_ID_PREFIX.to_vec();
+        id.extend_from_slice(&self.0.to_be_bytes());
+        otp::modhex_encode(&id)
+    }
+
+    /// Get the serial number from an OTP (or its public ID) typed by a
+    /// YubiKey configured to use its serial number as its public ID.
+    pub fn from_otp(otp: &str) -> Result<Self> {
+        let id = otp
+            .get(..12)
+            .and_then(otp::modhex_decode)
+            .ok_or(Error::ParseError)?;
+
+        match id.split_at(SERIAL_PUBLIC_ID_PREFIX.len()) {
+            (prefix, serial) if prefix == SERIAL_PUBLIC_ID_PREFIX => Serial::try_from(serial),
+            _ => {
+                error!("OTP public ID isn't derived from a serial number");
+                Err(Error::ParseError)
+            }
+        }
+    }
+}
+
+/// Parses serial numbers in any [`SerialFormat`]: hex serial numbers must be
+/// prefixed with `0x`, as they're otherwise indistinguishable from decimal.
 impl FromStr for Serial {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        s.parse().map(Serial).map_err(|_| Error::ParseError)
+        if s.starts_with("0x") || s.starts_with("0X") {
+            Serial::parse_as(s, SerialFormat::Hex)
+        } else if s.bytes().all(|c| c.is_ascii_digit()) {
+            Serial::parse_as(s, SerialFormat::Decimal)
+        } else {
+            Serial::parse_as(s, SerialFormat::Modhex)
+        }
     }
 }
 
@@ -118,8 +225,37 @@ impl Display for Serial {
     }
 }
 
-/// YubiKey version.
+impl fmt::LowerHex for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::UpperHex for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+/// Formats in which YubiKey serial numbers are displayed.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SerialFormat {
+    /// Decimal, as printed on the YubiKey and shown by `ykman info`
+    Decimal,
+
+    /// Hexadecimal, zero-padded to 8 digits
+    Hex,
+
+    /// Modhex, as used in YubiKey OTPs, zero-padded to 8 characters
+    Modhex,
+}
+
+/// YubiKey version.
+///
+/// Versions are ordered by their major, minor and patch components, in that
+/// order.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Version {
     /// Major version component
     pub major: u8,
@@ -140,6 +276,11 @@ impl Version {
             patch: bytes[2],
         }
     }
+
+    /// Is this version the given version or newer?
+    pub fn is_at_least(self, major: u8, minor: u8, patch: u8) -> bool {
+        self >= Version::new([major, minor, patch])
+    }
 }
 
 impl Display for Version {
@@ -148,6 +289,87 @@ impl Display for Version {
     }
 }
 
+/// Parses versions in the `major.minor.patch` format (e.g. `5.4.3`).
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut components = s.split('.').map(|c| c.parse::<u8>());
+
+        match (
+            components.next(),
+            components.next(),
+            components.next(),
+            components.next(),
+        ) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                Ok(Version::new([major, minor, patch]))
+            }
+            _ => {
+                error!("invalid version: {:?}", s);
+                Err(Error::ParseError)
+            }
+        }
+    }
+}
+
+/// Connection to a YubiKey, disconnected with a configurable [`Disposition`]
+/// when dropped.
+pub(crate) struct Connection {
+    /// Transport to the card
+    transport: Box<dyn Transport>,
+
+    /// Whether the transport is still connected
+    connected: bool,
+
+    /// Disposition to disconnect with when dropped
+    drop_disposition: Disposition,
+}
+
+impl Connection {
+    fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            connected: true,
+            drop_disposition: Disposition::ResetCard,
+        }
+    }
+
+    fn disconnect(mut self, disposition: Disposition) -> core::result::Result<(), (Self, Error)> {
+        match self.transport.disconnect(disposition) {
+            Ok(()) => {
+                self.connected = false;
+                Ok(())
+            }
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl Deref for Connection {
+    type Target = dyn Transport;
+
+    fn deref(&self) -> &(dyn Transport + 'static) {
+        self.transport.as_ref()
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut (dyn Transport + 'static) {
+        self.transport.as_mut()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.connected {
+            if let Err(e) = self.transport.disconnect(self.drop_disposition) {
+                error!("failed to disconnect from card: {}", e);
+            }
+        }
+    }
+}
+
 /// YubiKey device: primary API for opening a session and performing various operations.
 ///
 /// Almost all functionality in this library will require an open session
@@ -155,11 +377,31 @@ impl Display for Version {
 // TODO(tarcieri): reduce coupling to internal fields via `pub(crate)`
 #[cfg_attr(not(feature = "untested"), allow(dead_code))]
 pub struct YubiKey {
-    pub(crate) card: Card,
+    pub(crate) card: Connection,
     pub(crate) name: String,
     pub(crate) pin: Option<CachedPin>,
     pub(crate) version: Version,
     pub(crate) serial: Serial,
+    pub(crate) pin_verified: bool,
+    pub(crate) slot_policies: BTreeMap<SlotId, (PinPolicy, TouchPolicy)>,
+    pub(crate) usage_policy: KeyUsagePolicy,
+    pub(crate) last_used: SystemTime,
+    pub(crate) revalidate_after: Option<Duration>,
+    pub(crate) card_reset: Cell<bool>,
+    pub(crate) extended_apdus: Cell<bool>,
+    pub(crate) secure_channel: Option<RefCell<SecureChannel>>,
+    pub(crate) pin_provider: Option<Box<dyn PinProvider>>,
+    pub(crate) pin_escalation: PinEscalation,
+    pub(crate) pin_retry_floor: u8,
+    pub(crate) pending_pin: Option<PendingPin>,
+    pub(crate) write_log: RefCell<WriteLog>,
+    pub(crate) middleware: Vec<Box<dyn Middleware>>,
+    pub(crate) wire_log: Option<RefCell<WireLog>>,
+    pub(crate) apdu_observer: Option<RefCell<Observer>>,
+    pub(crate) clock: Box<dyn Clock>,
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) conformance: bool,
+    pub(crate) dry_run: Option<Vec<Operation>>,
 }
 
 impl fmt::Debug for YubiKey {
@@ -183,20 +425,41 @@ impl YubiKey {
     /// open is returned.
     ///
     /// If you need to operate in environments with more than one YubiKey
-    /// attached to the same system, use [`YubiKey::open_by_serial`] or
+    /// attached to the same system, use [`YubiKey::open_by_serial`],
+    /// [`YubiKey::open_by_reader`] or
     /// [`yubikey::reader::Context`][`Context`] to select from the available
     /// PC/SC readers.
+    ///
+    /// The YubiKey is opened in shared mode: use [`YubiKey::open_with`] to
+    /// open it exclusively.
+    #[cfg(feature = "pcsc")]
     pub fn open() -> Result<Self> {
+        Self::open_with(&OpenOptions::default())
+    }
+
+    /// Open a read-only handle to a YubiKey, which can't be used to modify
+    /// it: see [`ReadOnlyYubiKey`].
+    ///
+    /// The YubiKey is selected as with [`YubiKey::open`].
+    #[cfg(feature = "pcsc")]
+    pub fn open_read_only() -> Result<ReadOnlyYubiKey> {
+        Self::open().map(ReadOnlyYubiKey::from)
+    }
+
+    /// Open the connected YubiKey with the given [`OpenOptions`], e.g. to
+    /// connect to it in exclusive mode: see [`YubiKey::open`].
+    #[cfg(feature = "pcsc")]
+    pub fn open_with(options: &OpenOptions) -> Result<Self> {
         let mut yubikey: Option<Self> = None;
 
         let mut readers = Context::open()?;
         for reader in readers.iter()? {
-            if let Ok(yk_found) = reader.open() {
+            if let Ok(yk_found) = reader.open_with(options) {
                 if let Some(yk_stored) = yubikey {
                     // We found two YubiKeys, so we won't use either.
                     // Don't reset them.
-                    let _ = yk_stored.disconnect(Disposition::LeaveCard);
-                    let _ = yk_found.disconnect(Disposition::LeaveCard);
+                    yk_stored.leave_card();
+                    yk_found.leave_card();
 
                     error!("multiple YubiKeys detected!");
                     return Err(Error::PcscError { inner: None });
@@ -215,7 +478,120 @@ impl YubiKey {
         }
     }
 
+    /// List the YubiKeys connected to the system, e.g. to let users pick one.
+    ///
+    /// Only cards whose ATR identifies them as a YubiKey are opened (see
+    /// [`OpenOptions::check_atr`]), so other smart cards aren't disturbed;
+    /// YubiKeys in readers reporting another ATR (e.g. some NFC readers)
+    /// aren't listed.
+    ///
+    /// Each YubiKey is opened in turn, in shared mode, and left as it was
+    /// once its details have been read. Reading them selects the PIV and
+    /// Management applications, which ends the PIN session another
+    /// application may have with the YubiKey. YubiKeys which can't be opened
+    /// (e.g. because another application is using them exclusively) are
+    /// skipped.
+    #[cfg(feature = "pcsc")]
+    pub fn list() -> Result<Vec<ConnectedYubiKey>> {
+        let mut readers = Context::open()?;
+        let mut yubikeys = vec![];
+        let options = OpenOptions::default().check_atr();
+
+        for reader in readers.iter()? {
+            let mut yubikey = match reader.open_with(&options) {
+                Ok(yubikey) => yubikey,
+                Err(e) => {
+                    debug!("skipping reader '{}': {}", reader.name(), e);
+                    continue;
+                }
+            };
+
+            let form_factor = match yubikey.device_info() {
+                Ok(info) => Some(info.form_factor),
+                Err(e) => {
+                    debug!("no device info for YubiKey {}: {}", yubikey.serial(), e);
+                    None
+                }
+            };
+
+            yubikeys.push(ConnectedYubiKey {
+                reader: reader.name().into_owned(),
+                serial: yubikey.serial(),
+                version: yubikey.version(),
+                form_factor,
+            });
+
+            yubikey.leave_card();
+        }
+
+        Ok(yubikeys)
+    }
+
+    /// Open the connected YubiKey (as [`YubiKey::open`] does), and send all
+    /// PIV commands through an SCP03 secure channel opened with the given
+    /// keys: see the [`scp03`](crate::scp03) module.
+    #[cfg(feature = "pcsc")]
+    pub fn open_with_scp03(keys: Scp03Keys) -> Result<Self> {
+        let mut yubikey = Self::open()?;
+        yubikey.enable_scp03(keys)?;
+        Ok(yubikey)
+    }
+
+    /// Send all subsequent PIV commands through an SCP03 secure channel
+    /// opened with the given keys: see the [`scp03`](crate::scp03) module.
+    ///
+    /// The PIV application is selected again to open the secure channel,
+    /// which ends any PIN verification or management key authentication.
+    /// If the secure channel can't be opened (e.g. because the keys are
+    /// wrong), commands keep being sent as they were.
+    pub fn enable_scp03(&mut self, keys: Scp03Keys) -> Result<()> {
+        self.enable_secure_channel(SecureChannel::new(keys))
+    }
+
+    /// Open the connected YubiKey (as [`YubiKey::open`] does), and send all
+    /// PIV commands through an SCP11 secure channel opened with the given
+    /// parameters: see the [`scp11`](crate::scp11) module.
+    #[cfg(feature = "pcsc")]
+    pub fn open_with_scp11(params: Scp11Params) -> Result<Self> {
+        let mut yubikey = Self::open()?;
+        yubikey.enable_scp11(params)?;
+        Ok(yubikey)
+    }
+
+    /// Send all subsequent PIV commands through an SCP11 secure channel
+    /// opened with the given parameters: see the [`scp11`](crate::scp11)
+    /// module.
+    ///
+    /// As with [`YubiKey::enable_scp03`], the PIV application is selected
+    /// again to open the secure channel, and commands keep being sent as they
+    /// were if it can't be opened (e.g. because the YubiKey's certificate
+    /// chain isn't trusted).
+    pub fn enable_scp11(&mut self, params: Scp11Params) -> Result<()> {
+        self.enable_secure_channel(SecureChannel::new(params))
+    }
+
+    fn enable_secure_channel(&mut self, channel: SecureChannel) -> Result<()> {
+        self.secure_channel = Some(RefCell::new(channel));
+        self.pin_verified = false;
+
+        let result = self
+            .begin_transaction()
+            .and_then(|txn| txn.select_application());
+
+        if result.is_err() {
+            self.secure_channel = None;
+        }
+
+        result
+    }
+
+    /// Are PIV commands sent through a secure channel (SCP03 or SCP11)?
+    pub fn uses_secure_channel(&self) -> bool {
+        self.secure_channel.is_some()
+    }
+
     /// Open a YubiKey with a specific serial number.
+    #[cfg(feature = "pcsc")]
     pub fn open_by_serial(serial: Serial) -> Result<Self> {
         let mut readers = Context::open()?;
 
@@ -243,7 +619,7 @@ impl YubiKey {
                 return Ok(yubikey);
             } else {
                 // We didn't want this YubiKey; don't reset it.
-                let _ = yubikey.disconnect(Disposition::LeaveCard);
+                yubikey.leave_card();
             }
         }
 
@@ -255,29 +631,124 @@ impl YubiKey {
         })
     }
 
+    /// Open the YubiKey in the PC/SC reader with the given name, as listed by
+    /// [`Context::iter`].
+    #[cfg(feature = "pcsc")]
+    pub fn open_by_reader(name: &str) -> Result<Self> {
+        let mut readers = Context::open()?;
+
+        let reader = readers
+            .iter()?
+            .find(|reader| reader.name() == name)
+            .ok_or_else(|| {
+                error!("no reader named '{}'", name);
+                Error::NotFound
+            })?;
+
+        reader.open()
+    }
+
+    /// Open a YubiKey over the given [`Transport`], instead of a PC/SC
+    /// reader.
+    ///
+    /// The name identifies the YubiKey in logs, like the name of a reader.
+    pub fn open_with_transport(
+        transport: impl Transport + 'static,
+        name: impl Into<String>,
+    ) -> Result<Self> {
+        let mut transport: Box<dyn Transport> = Box::new(transport);
+
+        let mut app_version_serial = || -> Result<(Version, Serial)> {
+            let txn = Transaction::new(transport.as_mut())?;
+
+            if let Err(e) = txn.select_application() {
+                return Err(match e {
+                    Error::AppletNotFound { .. } => match DeviceInfo::read(&txn) {
+                        Ok(info) if !info.has_piv() => {
+                            error!("{} has no PIV application", info.variant());
+                            Error::NoPivApplication
+                        }
+                        _ => e,
+                    },
+                    _ => e,
+                });
+            }
+
+            let v = txn.get_version()?;
+            let s = txn.get_serial(v)?;
+            Ok((v, s))
+        };
+
+        match app_version_serial() {
+            Err(e) => {
+                error!("Could not use reader: {}", e);
+
+                // We were unable to use the card, so we've effectively only connected as
+                // a side-effect of determining this. Avoid disrupting its internal state
+                // any further (e.g. preserve the PIN cache of whatever applet is selected
+                // currently).
+                if let Err(e) = transport.disconnect(Disposition::LeaveCard) {
+                    error!("Failed to disconnect gracefully from card: {}", e);
+                }
+
+                Err(e)
+            }
+            Ok((version, serial)) => Ok(YubiKey {
+                card: Connection::new(transport),
+                name: name.into(),
+                pin: None,
+                version,
+                serial,
+                pin_verified: false,
+                slot_policies: BTreeMap::new(),
+                usage_policy: KeyUsagePolicy::default(),
+                last_used: SystemTime::now(),
+                revalidate_after: Some(DEFAULT_REVALIDATE_AFTER),
+                card_reset: Cell::new(false),
+                extended_apdus: Cell::new(Capability::ExtendedApdu.is_supported_by(version)),
+                secure_channel: None,
+                pin_provider: None,
+                pin_escalation: PinEscalation::default(),
+                pin_retry_floor: pin::DEFAULT_PIN_RETRY_FLOOR,
+                pending_pin: None,
+                write_log: RefCell::default(),
+                middleware: Vec::new(),
+                wire_log: None,
+                apdu_observer: None,
+                clock: Box::new(SystemClock),
+                rate_limiter: RateLimiter::default(),
+                conformance: false,
+                dry_run: None,
+            }),
+        }
+    }
+
     /// Reconnect to a YubiKey.
     #[cfg(feature = "untested")]
     pub fn reconnect(&mut self) -> Result<()> {
         info!("trying to reconnect to current reader");
 
-        self.card.reconnect(
-            pcsc::ShareMode::Shared,
-            pcsc::Protocols::T1,
-            Disposition::ResetCard,
-        )?;
+        self.card.reconnect(Disposition::ResetCard)?;
 
         let pin = self
             .pin
             .as_ref()
             .map(|p| Buffer::new(p.expose_secret().clone()));
 
-        let txn = Transaction::new(&mut self.card)?;
+        let txn = Transaction::new(&mut *self.card)?
+            .with_wire_log(self.wire_log.as_ref())
+            .with_apdu_observer(self.apdu_observer.as_ref())
+            .with_secure_channel(self.secure_channel.as_ref())
+            .with_clock(&*self.clock);
         txn.select_application()?;
 
         if let Some(p) = &pin {
             txn.verify_pin(p)?;
         }
 
+        drop(txn);
+        self.pin_verified = pin.is_some();
+
         Ok(())
     }
 
@@ -288,8 +759,10 @@ impl YubiKey {
     /// # Note
     ///
     /// `YubiKey` implements `Drop` which automatically disconnects the card using
-    /// `Disposition::ResetCard`; you only need to call this function if you want to
-    /// handle errors or use a different disposition method.
+    /// the disposition set with [`YubiKey::set_drop_disposition`]
+    /// (`Disposition::ResetCard` by default); you only need to call this function
+    /// if you want to handle errors or use a different disposition method.
+    #[allow(clippy::result_large_err)]
     pub fn disconnect(self, disposition: Disposition) -> core::result::Result<(), (Self, Error)> {
         let Self {
             card,
@@ -297,6 +770,26 @@ impl YubiKey {
             pin,
             version,
             serial,
+            pin_verified,
+            slot_policies,
+            usage_policy,
+            last_used,
+            revalidate_after,
+            card_reset,
+            extended_apdus,
+            secure_channel,
+            pin_provider,
+            pin_escalation,
+            pin_retry_floor,
+            pending_pin,
+            write_log,
+            middleware,
+            wire_log,
+            apdu_observer,
+            clock,
+            rate_limiter,
+            conformance,
+            dry_run,
         } = self;
 
         card.disconnect(disposition).map_err(|(card, e)| {
@@ -307,16 +800,245 @@ impl YubiKey {
                     pin,
                     version,
                     serial,
+                    pin_verified,
+                    slot_policies,
+                    usage_policy,
+                    last_used,
+                    revalidate_after,
+                    card_reset,
+                    extended_apdus,
+                    secure_channel,
+                    pin_provider,
+                    pin_escalation,
+                    pin_retry_floor,
+                    pending_pin,
+                    write_log,
+                    middleware,
+                    wire_log,
+                    apdu_observer,
+                    clock,
+                    rate_limiter,
+                    conformance,
+                    dry_run,
                 },
-                e.into(),
+                e,
             )
         })
     }
 
+    /// Disconnect from the YubiKey using the disposition set with
+    /// [`YubiKey::set_drop_disposition`].
+    ///
+    /// Unlike dropping the `YubiKey`, this reports failures to disconnect.
+    pub fn close(self) -> Result<()> {
+        let disposition = self.card.drop_disposition;
+        self.disconnect(disposition).map_err(|(_, e)| e)
+    }
+
+    /// Disconnect from the card, leaving it as it is for other applications,
+    /// even if disconnecting fails (dropping the `YubiKey` would reset it).
+    #[cfg(feature = "pcsc")]
+    pub(crate) fn leave_card(mut self) {
+        self.set_drop_disposition(Disposition::LeaveCard);
+
+        if let Err((_, e)) = self.disconnect(Disposition::LeaveCard) {
+            debug!("couldn't disconnect from reader: {}", e);
+        }
+    }
+
+    /// Set what happens to the card when this `YubiKey` is dropped or
+    /// [closed](YubiKey::close):
+    ///
+    /// - `Disposition::ResetCard` (the default) resets the card, ending any
+    ///   PIN verification or management key authentication
+    /// - `Disposition::UnpowerCard` powers the card down, which also ends them
+    /// - `Disposition::LeaveCard` leaves the card as-is, so they remain in
+    ///   effect for the next application using the card
+    pub fn set_drop_disposition(&mut self, disposition: Disposition) {
+        self.card.drop_disposition = disposition;
+    }
+
     /// Begin a transaction.
+    ///
+    /// If the card was reset during a previous transaction, it's reconnected
+    /// to first (see [`YubiKey::recover`]). Otherwise, if the YubiKey hasn't
+    /// been used for longer than the revalidation threshold, the connection
+    /// is revalidated first.
     pub(crate) fn begin_transaction(&mut self) -> Result<Transaction<'_>> {
-        // TODO(tarcieri): reconnect support
-        Transaction::new(&mut self.card)
+        if self.card_reset.get() {
+            self.recover()?;
+        } else if let Some(threshold) = self.revalidate_after {
+            // Wall clock time is used (rather than `Instant`) as it advances
+            // while the system is suspended.
+            let idle = self
+                .clock
+                .now()
+                .duration_since(self.last_used)
+                .unwrap_or_default();
+
+            if idle >= threshold {
+                self.revalidate()?;
+            }
+        }
+
+        self.last_used = self.clock.now();
+
+        let card_reset = &self.card_reset;
+        let txn = Transaction::new(&mut *self.card).map_err(|e| {
+            if e.is_card_reset() {
+                card_reset.set(true);
+            }
+
+            e
+        })?;
+
+        Ok(txn
+            .with_card_reset(&self.card_reset)
+            .with_extended_apdus(&self.extended_apdus)
+            .with_secure_channel(self.secure_channel.as_ref())
+            .with_clock(&*self.clock)
+            .with_write_log(&self.write_log)
+            .with_wire_log(self.wire_log.as_ref())
+            .with_apdu_observer(self.apdu_observer.as_ref())
+            .with_conformance(self.conformance)
+            .with_dry_run(self.dry_run.is_some()))
+    }
+
+    /// Check the connection to the YubiKey is still usable, reconnecting if
+    /// it isn't.
+    ///
+    /// PC/SC handles are silently invalidated when the system sleeps or
+    /// hibernates, or when the YubiKey is reset. In that case this reconnects
+    /// to the same reader, checks the same YubiKey is present, selects the
+    /// PIV application and verifies the cached PIN again (if the PIN had
+    /// been verified). Management key authentication is not restored.
+    ///
+    /// This is called automatically before operations once the YubiKey has
+    /// been idle for a while; see [`YubiKey::set_revalidate_after`].
+    pub fn revalidate(&mut self) -> Result<()> {
+        self.last_used = self.clock.now();
+
+        if self.card.is_connected() {
+            return Ok(());
+        }
+
+        info!("connection to reader '{}' lost; reconnecting", self.name);
+        self.reconnect_session()
+    }
+
+    /// Reconnect after the card was reset, e.g. by another application or by
+    /// the reader being power-cycled, restoring the PIV session as
+    /// [`YubiKey::revalidate`] does.
+    ///
+    /// Fails with [`Error::SessionLost`] if the PIN had been verified, but
+    /// can't be verified again as it wasn't cached.
+    fn recover(&mut self) -> Result<()> {
+        info!("card in reader '{}' was reset; reconnecting", self.name);
+
+        // If this fails, recovering (and verifying the PIN again) is
+        // attempted again by the next transaction
+        let was_verified = self.pin_verified;
+        if let Err(e) = self.reconnect_session() {
+            self.pin_verified = was_verified;
+            return Err(e);
+        }
+        self.card_reset.set(false);
+
+        if was_verified && !self.pin_verified {
+            warn!("PIN verification of YubiKey {} was lost", self.serial);
+            return Err(Error::SessionLost);
+        }
+
+        Ok(())
+    }
+
+    /// Reconnect to the card, select the PIV application and verify the
+    /// cached PIN again (if the PIN had been verified).
+    fn reconnect_session(&mut self) -> Result<()> {
+        self.card.reconnect(Disposition::LeaveCard)?;
+
+        let was_verified = self.pin_verified;
+        self.pin_verified = false;
+
+        let pin = self
+            .pin
+            .as_ref()
+            .map(|p| Buffer::new(p.expose_secret().clone()));
+
+        let txn = Transaction::new(&mut *self.card)?
+            .with_wire_log(self.wire_log.as_ref())
+            .with_apdu_observer(self.apdu_observer.as_ref())
+            .with_secure_channel(self.secure_channel.as_ref())
+            .with_clock(&*self.clock);
+        txn.select_application()?;
+
+        let serial = txn.get_serial(self.version)?;
+        if serial != self.serial {
+            error!(
+                "expected YubiKey {} in reader '{}', found {}",
+                self.serial, self.name, serial
+            );
+            return Err(Error::NotFound);
+        }
+
+        if let (true, Some(pin)) = (was_verified, &pin) {
+            txn.verify_pin(pin)?;
+            drop(txn);
+            self.pin_verified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Perform an operation which is safe to repeat (e.g. reading an object),
+    /// repeating it once if it fails because the card was reset.
+    ///
+    /// Other operations fail with the PC/SC error when the card is reset, and
+    /// the card is reconnected to at the start of the next operation.
+    pub(crate) fn idempotent<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        match op(self) {
+            Err(e) if e.is_card_reset() => {
+                self.recover()?;
+                op(self)
+            }
+            result => result,
+        }
+    }
+
+    /// Are extended APDUs used to send large amounts of data (e.g. certificates
+    /// and imported keys) in one go?
+    ///
+    /// They're enabled when the firmware supports them (see
+    /// [`Capability::ExtendedApdu`]), and disabled automatically, falling back
+    /// to command chaining, if the card or reader rejects them.
+    pub fn uses_extended_apdus(&self) -> bool {
+        self.extended_apdus.get()
+    }
+
+    /// Enable or disable extended APDUs: see [`YubiKey::uses_extended_apdus`].
+    ///
+    /// Returns [`Error::NotSupported`] when enabling them if the firmware
+    /// doesn't support them.
+    pub fn set_extended_apdus(&mut self, enabled: bool) -> Result<()> {
+        if enabled && !self.supports(Capability::ExtendedApdu) {
+            return Err(Error::NotSupported);
+        }
+
+        self.extended_apdus.set(enabled);
+        Ok(())
+    }
+
+    /// Set how long the YubiKey may be idle before the connection is
+    /// revalidated with [`YubiKey::revalidate`] at the start of the next
+    /// operation. Defaults to 60 seconds; `None` disables revalidation.
+    ///
+    /// Setting this to [`Duration::ZERO`] revalidates before every operation,
+    /// which can be used to test resilience to suspend and resume.
+    pub fn set_revalidate_after(&mut self, threshold: Option<Duration>) {
+        self.revalidate_after = threshold;
     }
 
     /// Get the name of the associated PC/SC card reader.
@@ -331,6 +1053,18 @@ impl YubiKey {
         self.version
     }
 
+    /// Does this YubiKey's firmware support the given [`Capability`]?
+    pub fn supports(&self, capability: Capability) -> bool {
+        capability.is_supported_by(self.version)
+    }
+
+    /// Get the known security advisories affecting this YubiKey's firmware.
+    ///
+    /// See the [`advisory`] module for the advisories which are checked.
+    pub fn advisories(&self) -> Vec<&'static Advisory> {
+        advisory::affecting(self.version).collect()
+    }
+
     /// Get YubiKey device serial number.
     ///
     /// This always uses the cached version queried when the key is initialized.
@@ -355,6 +1089,12 @@ impl YubiKey {
 
     /// Authenticate to the card using the provided management key (MGM).
     pub fn authenticate<C: MgmKeyAlgorithm>(&mut self, mgm_key: MgmKey<C>) -> Result<()> {
+        self.run(Operation::Authenticate, |yubikey| {
+            yubikey.authenticate_mgm(&mgm_key)
+        })
+    }
+
+    fn authenticate_mgm<C: MgmKeyAlgorithm>(&mut self, mgm_key: &MgmKey<C>) -> Result<()> {
         let txn = self.begin_transaction()?;
 
         // get a challenge from the card
@@ -402,26 +1142,178 @@ impl YubiKey {
         mgm_key.check_challenge(&host_challenge, &authentication.data()[4..])
     }
 
-    /// Get the PIV keys contained in this YubiKey.
-    pub fn piv_keys(&mut self) -> Result<Vec<piv::Key>> {
-        piv::Key::list(self)
-    }
+    /// Get `len` random bytes generated by the YubiKey's random number generator.
+    ///
+    /// These are obtained by repeatedly requesting a challenge for external
+    /// authentication with the management key, and can be used as an
+    /// additional entropy source when seeding a host RNG. Requesting a
+    /// challenge doesn't require (or disturb) a verified PIN, but abandons
+    /// any management key authentication in progress.
+    ///
+    /// Returns [`Error::NotSupported`] if the YubiKey doesn't support
+    /// [`Capability::RandomChallenge`].
+    pub fn random_bytes(&mut self, len: usize) -> Result<Buffer> {
+        if !self.supports(Capability::RandomChallenge) {
+            error!(
+                "random challenges require firmware {} (YubiKey has {})",
+                Capability::RandomChallenge.min_version(),
+                self.version
+            );
+            return Err(Error::NotSupported);
+        }
+
+        // The challenge is produced by the management key's cipher
+        let algorithm = match self.mgm_metadata() {
+            Ok(metadata) => u8::from(metadata.algorithm),
+            Err(Error::NotSupported) => u8::from(ManagementAlgorithmId::ThreeDes),
+            Err(e) => return Err(e),
+        };
 
-    /// Deauthenticate.
-    #[cfg(feature = "untested")]
-    pub fn deauthenticate(&mut self) -> Result<()> {
         let txn = self.begin_transaction()?;
+        let mut output = Buffer::new(Vec::with_capacity(len));
+
+        while output.len() < len {
+            let response = Apdu::new(Ins::Authenticate)
+                .params(algorithm, KEY_CARDMGM)
+                .data([TAG_DYN_AUTH, 0x02, 0x81, 0x00])
+                .transmit(&txn, 261)?;
+
+            if !response.is_success() {
+                error!(
+                    "failed requesting challenge: {:04x}",
+                    response.status_words().code()
+                );
+                return Err(Error::NotSupported);
+            }
 
-        let status_words = Apdu::new(Ins::SelectApplication)
-            .p1(0x04)
-            .data(mgm::APPLET_ID)
-            .transmit(&txn, 255)?
-            .status_words();
+            // Response is `7C len 81 len <challenge>`
+            let challenge = response.data().get(4..).ok_or(Error::SizeError)?;
+            if challenge.is_empty() {
+                return Err(Error::SizeError);
+            }
 
-        if !status_words.is_success() {
-            error!(
-                "Failed selecting mgmt application: {:04x}",
-                status_words.code()
+            let remaining = len - output.len();
+            output.extend_from_slice(&challenge[..remaining.min(challenge.len())]);
+        }
+
+        Ok(output)
+    }
+
+    /// Get a JSON description of this YubiKey, with the same fields as the
+    /// output of `ykman info` and `ykman piv info`: see [`Info`].
+    #[cfg(feature = "serde")]
+    pub fn info_json(&mut self) -> Result<String> {
+        let info = Info::read(self)?;
+        serde_json::to_string_pretty(&info).map_err(|e| {
+            error!("couldn't serialize device info: {}", e);
+            Error::GenericError
+        })
+    }
+
+    /// Get a signer using the key in the given slot, whose algorithm is
+    /// detected from the slot metadata or the certificate in the slot.
+    pub fn signer(&mut self, slot: SlotId) -> Result<SlotSigner<'_>> {
+        SlotSigner::new(self, slot)
+    }
+
+    /// Get a signer using the key in the given slot, like
+    /// [`YubiKey::signer`], which returns typed signatures and can be used as
+    /// a `dyn signature::Signer`.
+    pub fn any_signer(&mut self, slot: SlotId) -> Result<AnySigner<'_>> {
+        AnySigner::new(self, slot)
+    }
+
+    /// Get a RSASSA-PSS signer using the RSA key in the given slot, hashing
+    /// messages with `D`: see [`PssSigner`].
+    pub fn pss_signer<D>(&mut self, slot: SlotId) -> Result<PssSigner<'_, D>>
+    where
+        D: 'static + sha2::Digest + sha2::digest::DynDigest + Send + Sync,
+    {
+        PssSigner::new(self, slot)
+    }
+
+    /// Get a decryptor using the key in the given slot, whose algorithm is
+    /// detected from the slot metadata or the certificate in the slot.
+    #[cfg(feature = "untested")]
+    pub fn decryptor(&mut self, slot: SlotId) -> Result<SlotDecryptor<'_>> {
+        SlotDecryptor::new(self, slot)
+    }
+
+    /// Get the PIV keys contained in this YubiKey.
+    pub fn piv_keys(&mut self) -> Result<Vec<piv::Key>> {
+        piv::Key::list(self)
+    }
+
+    /// End any PIN verification or management key authentication, without
+    /// disconnecting from the YubiKey.
+    ///
+    /// The cached PIN is forgotten as well, so it won't be verified again
+    /// when revalidating the connection. A [`PinProvider`] set with
+    /// [`YubiKey::set_pin_provider`] will still be consulted the next time the
+    /// PIN is needed.
+    pub fn clear_auth_state(&mut self) -> Result<()> {
+        self.pin = None;
+        self.reset_security_status()
+    }
+
+    /// Clear the PIN verification and management key authentication status
+    /// of the PIV applet, keeping the cached PIN (if any) so it can be
+    /// verified again when needed.
+    pub(crate) fn reset_security_status(&mut self) -> Result<()> {
+        self.pin_verified = false;
+
+        let txn = self.begin_transaction()?;
+
+        // Selecting another applet clears the PIV applet's security status.
+        // YubiKeys without the management applet rely on reselecting PIV.
+        let status_words = Apdu::new(Ins::SelectApplication)
+            .p1(0x04)
+            .data(mgm::APPLET_ID)
+            .transmit(&txn, 255)?
+            .status_words();
+
+        if !status_words.is_success() {
+            debug!(
+                "couldn't select mgmt application: {:04x}",
+                status_words.code()
+            );
+        }
+
+        txn.select_application()
+    }
+
+    /// Verify the cached PIN again, e.g. after the security status was reset,
+    /// so that the PIN stays verified.
+    ///
+    /// Does nothing if no PIN is cached.
+    pub(crate) fn reverify_cached_pin(&mut self) -> Result<()> {
+        let pin = match &self.pin {
+            Some(pin) => Buffer::new(pin.expose_secret().clone()),
+            None => return Ok(()),
+        };
+
+        self.begin_transaction()?.verify_pin(&pin)?;
+        self.pin_verified = true;
+        Ok(())
+    }
+
+    /// Deauthenticate.
+    #[cfg(feature = "untested")]
+    pub fn deauthenticate(&mut self) -> Result<()> {
+        // Selecting another applet clears the PIV applet's security status
+        self.pin_verified = false;
+        let txn = self.begin_transaction()?;
+
+        let status_words = Apdu::new(Ins::SelectApplication)
+            .p1(0x04)
+            .data(mgm::APPLET_ID)
+            .transmit(&txn, 255)?
+            .status_words();
+
+        if !status_words.is_success() {
+            error!(
+                "Failed selecting mgmt application: {:04x}",
+                status_words.code()
             );
             return Err(match status_words {
                 StatusWords::NotFoundError => Error::AppletNotFound {
@@ -434,35 +1326,544 @@ impl YubiKey {
         Ok(())
     }
 
+    /// Is the PIV application of this YubiKey operating in FIPS approved
+    /// mode?
+    ///
+    /// This is read with [`YubiKey::device_info`], and requires firmware 5.7
+    /// or newer: see [`DeviceInfo::is_piv_fips_approved`].
+    pub fn is_fips_approved(&mut self) -> Result<bool> {
+        Ok(self.device_info()?.is_piv_fips_approved())
+    }
+
+    /// Get information about this YubiKey from its Management application,
+    /// such as its form factor and [`ProductVariant`](crate::ProductVariant).
+    ///
+    /// Selecting the Management application ends the current PIN session,
+    /// so a verified PIN is verified again with the cached one afterwards.
+    pub fn device_info(&mut self) -> Result<DeviceInfo> {
+        let pin_verified = mem::replace(&mut self.pin_verified, false);
+
+        let info = {
+            let txn = self.begin_transaction()?;
+            let info = DeviceInfo::read(&txn);
+
+            // Reselect the PIV application even if reading the device info failed
+            txn.select_application()?;
+            info
+        };
+
+        if pin_verified {
+            if let Err(e) = self.reverify_cached_pin() {
+                warn!("couldn't verify the PIN again: {}", e);
+            }
+        }
+
+        info
+    }
+
     /// Verify device PIN.
     pub fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
-        {
+        self.run(Operation::VerifyPin, |yubikey| {
+            yubikey.verify_pin_inner(pin)
+        })
+    }
+
+    fn verify_pin_inner(&mut self, pin: &[u8]) -> Result<()> {
+        // An empty PIN only queries whether the PIN is verified
+        if !pin.is_empty() {
+            self.rate_limiter.pin_attempt(self.clock.now())?;
+        }
+
+        let result = {
             let txn = self.begin_transaction()?;
-            txn.verify_pin(pin)?;
+            txn.verify_pin(pin)
+        };
+
+        if !pin.is_empty() {
+            self.rate_limiter.pin_result(&result);
+        }
+
+        if let Err(e) = result {
+            if !pin.is_empty() {
+                self.pin_verified = false;
+            }
+
+            return Err(e);
         }
 
         if !pin.is_empty() {
-            self.pin = Some(CachedPin::new(pin.into()))
+            self.pin = Some(CachedPin::new(pin.into()));
+            self.pin_verified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Has the PIN been verified in the current session?
+    ///
+    /// This is tracked host-side: the session ends whenever the PIV applet is
+    /// reselected (e.g. by [`YubiKey::get_pin_retries`]) or the card is reconnected.
+    pub fn is_pin_verified(&self) -> bool {
+        self.pin_verified
+    }
+
+    /// Ensure the PIN has been verified as required by the given [`PinPolicy`],
+    /// using the cached PIN from a previous call to [`YubiKey::verify_pin`],
+    /// or otherwise the PIN from the [`PinProvider`] (if any).
+    ///
+    /// - [`PinPolicy::Never`]: nothing is verified.
+    /// - [`PinPolicy::Once`] (and [`PinPolicy::Default`]): the PIN is only verified
+    ///   if it hasn't already been verified in the current session.
+    /// - [`PinPolicy::Always`]: the PIN is verified again, as the YubiKey requires
+    ///   it immediately before every private key operation.
+    ///
+    /// If no PIN has been cached, what happens is determined by the
+    /// [`PinEscalation`] set with [`YubiKey::set_pin_escalation`]: by default,
+    /// if no PIN is available this is a no-op, leaving it to the YubiKey to
+    /// reject any subsequent operation which needs a verified PIN.
+    ///
+    /// A PIN rejected as wrong is never tried again: the cached PIN is
+    /// forgotten, or the [`PinProvider`] which gave it is removed, and
+    /// [`Error::WrongPin`] returned.
+    pub fn ensure_pin_verified(&mut self, policy: PinPolicy) -> Result<()> {
+        let needs_verify = match policy {
+            PinPolicy::Never => false,
+            PinPolicy::Default | PinPolicy::Once => !self.pin_verified,
+            PinPolicy::Always => true,
+        };
+
+        if !needs_verify {
+            return Ok(());
+        }
+
+        let (pin, from_provider) = match &self.pin {
+            Some(pin) => (Buffer::new(pin.expose_secret().clone()), false),
+            None => match self.provider_pin()? {
+                Some(pin) => (pin, true),
+                None => return Ok(()),
+            },
+        };
+
+        match self.verify_pin(&pin) {
+            // Trying the same wrong PIN again before every operation would
+            // soon block it: forget it, so it has to be supplied anew
+            Err(Error::WrongPin { tries }) => {
+                if from_provider {
+                    warn!(
+                        "PIN provider gave a wrong PIN for YubiKey {}, disabling it",
+                        self.serial
+                    );
+                    self.pin_provider = None;
+                    self.pending_pin = None;
+                } else {
+                    warn!(
+                        "cached PIN for YubiKey {} is wrong, forgetting it",
+                        self.serial
+                    );
+                    self.pin = None;
+                }
+
+                Err(Error::WrongPin { tries })
+            }
+            result => result,
+        }
+    }
+
+    /// Get the PIN from the [`PinProvider`] as configured by the
+    /// [`PinEscalation`].
+    fn provider_pin(&mut self) -> Result<Option<Buffer>> {
+        if self.pin_escalation == PinEscalation::FailFast {
+            return Err(Error::PinRequired);
+        }
+
+        if self.pin_provider.is_some() || self.pending_pin.is_some() {
+            self.check_pin_retry_floor()?;
+        }
+
+        let deadline = self.pin_escalation.deadline();
+
+        let pending = match (self.pending_pin.take(), &mut self.pin_provider, deadline) {
+            (Some(pending), _, _) => pending,
+            (None, Some(provider), None) => return provider.pin(self.serial),
+            (None, Some(_), Some(_)) => match self.pin_provider.take() {
+                Some(provider) => pin::request_pin(provider, self.serial),
+                None => return Err(Error::PinRequired),
+            },
+            (None, None, None) => return Ok(None),
+            (None, None, Some(_)) => return Err(Error::PinRequired),
+        };
+
+        let answer = match deadline {
+            Some(deadline) => pending.recv_timeout(deadline),
+            None => pending.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match answer {
+            Ok((provider, pin)) => {
+                self.pin_provider = Some(provider);
+
+                match pin? {
+                    Some(pin) => Ok(Some(pin)),
+                    None if deadline.is_none() => Ok(None),
+                    None => Err(Error::PinRequired),
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                debug!("still waiting for the PIN of YubiKey {}", self.serial);
+                self.pending_pin = Some(pending);
+                Err(Error::PinRequired)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("PIN provider panicked");
+                Err(Error::GenericError)
+            }
+        }
+    }
+
+    /// Refuse to use a PIN from the [`PinProvider`] once the PIN retry counter
+    /// has fallen to the floor set with [`YubiKey::set_pin_retry_floor`], so a
+    /// wrong one can't block the PIN.
+    fn check_pin_retry_floor(&mut self) -> Result<()> {
+        // Verifying an empty PIN reports the remaining tries without using one
+        let tries = match self.begin_transaction()?.verify_pin(&[]) {
+            Ok(()) => return Ok(()),
+            Err(Error::WrongPin { tries }) => tries,
+            Err(e) => return Err(e),
+        };
+
+        if tries <= self.pin_retry_floor {
+            warn!(
+                "YubiKey {} has {} PIN tries left, not consulting the PIN provider",
+                self.serial, tries
+            );
+            return Err(Error::PinRequired);
         }
 
         Ok(())
     }
 
+    /// Verify the cached PIN (if any) as required by the PIN policy of the key
+    /// in the given slot, prior to performing a private key operation with it.
+    pub(crate) fn ensure_pin_verified_for(&mut self, slot: SlotId) -> Result<()> {
+        if self.pin.is_none()
+            && self.pin_provider.is_none()
+            && self.pending_pin.is_none()
+            && self.pin_escalation == PinEscalation::Wait
+        {
+            return Ok(());
+        }
+
+        let (pin_policy, _) = self.slot_policy(slot).unwrap_or_else(|e| {
+            debug!("couldn't determine policy for slot {}: {}", slot, e);
+            (slot.default_pin_policy(), slot.default_touch_policy())
+        });
+
+        self.ensure_pin_verified(pin_policy)
+    }
+
+    /// Get the PIN and touch policies of the key in the given slot.
+    ///
+    /// These are read from the slot metadata on YubiKeys which support it
+    /// (firmware 5.3+), and otherwise assumed to be the defaults for the slot.
+    /// Results are cached for the lifetime of this `YubiKey`.
+    pub fn slot_policy(&mut self, slot: SlotId) -> Result<(PinPolicy, TouchPolicy)> {
+        if let Some(policy) = self.slot_policies.get(&slot) {
+            return Ok(*policy);
+        }
+
+        let policy = match piv::metadata(self, slot) {
+            Ok(metadata) => metadata.policy.map(|(pin_policy, touch_policy)| {
+                (
+                    slot.resolve_pin_policy(pin_policy),
+                    slot.resolve_touch_policy(touch_policy),
+                )
+            }),
+            Err(Error::NotSupported) => None,
+            Err(e) => return Err(e),
+        }
+        .unwrap_or((slot.default_pin_policy(), slot.default_touch_policy()));
+
+        self.slot_policies.insert(slot, policy);
+        Ok(policy)
+    }
+
+    /// Set the [`PinProvider`] consulted when an operation requires a verified
+    /// PIN and none has been cached by [`YubiKey::verify_pin`].
+    ///
+    /// A PIN obtained from the provider is cached once it has been verified,
+    /// as if it had been passed to [`YubiKey::verify_pin`].
+    pub fn set_pin_provider(&mut self, provider: Option<Box<dyn PinProvider>>) {
+        self.pin_provider = provider;
+        self.pending_pin = None;
+    }
+
+    /// Get the [`PinEscalation`] applied when an operation requires a verified
+    /// PIN and none has been cached.
+    pub fn pin_escalation(&self) -> PinEscalation {
+        self.pin_escalation
+    }
+
+    /// Set the [`PinEscalation`] applied when an operation requires a verified
+    /// PIN and none has been cached: whether to fail fast, or how long to wait
+    /// for the [`PinProvider`].
+    pub fn set_pin_escalation(&mut self, escalation: PinEscalation) {
+        self.pin_escalation = escalation;
+    }
+
+    /// Get the number of PIN tries left at or below which the [`PinProvider`]
+    /// is no longer consulted.
+    pub fn pin_retry_floor(&self) -> u8 {
+        self.pin_retry_floor
+    }
+
+    /// Set the number of PIN tries left at or below which the [`PinProvider`]
+    /// is no longer consulted, and operations needing the PIN fail with
+    /// [`Error::PinRequired`] instead (1 by default, so the last try is left
+    /// to [`YubiKey::verify_pin`]).
+    pub fn set_pin_retry_floor(&mut self, floor: u8) {
+        self.pin_retry_floor = floor;
+    }
+
+    /// Has the [`PinProvider`] been asked for the PIN in the background, with
+    /// its answer yet to be used by an operation?
+    ///
+    /// See [`PinEscalation::Deadline`] and [`PinEscalation::Queue`].
+    pub fn is_pin_pending(&self) -> bool {
+        self.pending_pin.is_some()
+    }
+
+    /// Get the [`KeyUsagePolicy`] applied to private key operations.
+    pub fn key_usage_policy(&self) -> &KeyUsagePolicy {
+        &self.usage_policy
+    }
+
+    /// Set the [`KeyUsagePolicy`] applied to private key operations.
+    ///
+    /// Operations refused by the policy fail with [`Error::OperationDenied`]
+    /// without being sent to the YubiKey.
+    pub fn set_key_usage_policy(&mut self, policy: KeyUsagePolicy) {
+        self.usage_policy = policy;
+    }
+
+    /// Is conformance mode enabled? See [`YubiKey::set_conformance_mode`].
+    pub fn is_conformance_mode(&self) -> bool {
+        self.conformance
+    }
+
+    /// Enable or disable conformance mode, in which only the instructions
+    /// and data objects defined by SP 800-73-4 are used.
+    ///
+    /// Yubico-proprietary instructions (e.g. reading slot metadata,
+    /// attestation, importing keys or changing the management key) and
+    /// objects (e.g. [`SlotLabels`](crate::SlotLabels) or the admin data)
+    /// fail with [`Error::NotSupported`] without being sent to the YubiKey,
+    /// and functionality relying on them falls back on standard behavior
+    /// where possible (e.g. slots are assumed to have their default PIN and
+    /// touch policies). This is meant for validating that applications
+    /// interoperate with non-Yubico PIV cards and middleware.
+    ///
+    /// The serial number and firmware version are still read with
+    /// proprietary instructions when connecting to the YubiKey.
+    ///
+    /// Slot policies cached by [`YubiKey::slot_policy`] are discarded when
+    /// the mode changes, as they depend on whether metadata can be read.
+    pub fn set_conformance_mode(&mut self, enabled: bool) {
+        if self.conformance != enabled {
+            self.slot_policies.clear();
+        }
+
+        self.conformance = enabled;
+    }
+
+    /// Is dry-run mode enabled? See [`YubiKey::set_dry_run`].
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// Enable or disable dry-run mode, in which only reads are performed.
+    ///
+    /// High-level operations modifying the YubiKey (generating or importing
+    /// keys, writing certificates and objects, changing the management key,
+    /// the PIN or the PUK, or resetting) aren't performed but recorded, so
+    /// that provisioning can be reviewed before touching the hardware: see
+    /// [`YubiKey::planned_operations`]. They succeed without passing through
+    /// the [`Middleware`] chain, generating a key returning a placeholder
+    /// public key of the requested algorithm. Any other command modifying
+    /// the YubiKey (e.g. writing slot labels), or sent to another
+    /// application and not known to only read it, fails with
+    /// [`Error::OperationDenied`] without being sent to it.
+    ///
+    /// Enabling dry-run mode clears the operations planned so far.
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Get the operations which would have modified the YubiKey since
+    /// dry-run mode was enabled, in order.
+    pub fn planned_operations(&self) -> &[Operation] {
+        self.dry_run.as_deref().unwrap_or_default()
+    }
+
+    /// Get the [`RateLimits`] applied to PIN verification and signatures.
+    pub fn rate_limits(&self) -> &RateLimits {
+        self.rate_limiter.limits()
+    }
+
+    /// Set the [`RateLimits`] applied to PIN verification and signatures,
+    /// resetting the counts of past operations.
+    ///
+    /// Operations exceeding the limits fail with [`Error::RateLimited`]
+    /// without being sent to the YubiKey.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter = RateLimiter::new(limits);
+    }
+
+    /// Set the [`WireLog`] recording the APDUs exchanged with this YubiKey,
+    /// or `None` to stop recording them.
+    pub fn set_wire_log(&mut self, wire_log: Option<WireLog>) {
+        self.wire_log = wire_log.map(RefCell::new);
+    }
+
+    /// Set the [`ApduObserver`] receiving the APDUs exchanged with this
+    /// YubiKey, with secrets redacted, or `None` to stop observing them.
+    pub fn set_apdu_observer(&mut self, observer: Option<Box<dyn ApduObserver>>) {
+        self.apdu_observer = observer.map(|observer| RefCell::new(Observer::new(observer)));
+    }
+
+    /// Set the [`Clock`] this YubiKey reads the current time from.
+    ///
+    /// By default, the system time is used: see [`SystemClock`].
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.last_used = clock.now();
+        self.clock = Box::new(clock);
+    }
+
+    /// Get the [`Clock`] this YubiKey reads the current time from.
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Add a [`Middleware`] to the end of the chain which high-level
+    /// [`Operation`]s pass through.
+    pub fn add_middleware(&mut self, middleware: impl Middleware + 'static) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Remove all [`Middleware`].
+    pub fn clear_middleware(&mut self) {
+        self.middleware.clear();
+    }
+
+    /// Perform the given operation, passing it through the middleware chain.
+    pub(crate) fn run<T>(
+        &mut self,
+        operation: Operation,
+        mut perform: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        if self.middleware.is_empty() {
+            return perform(self);
+        }
+
+        // Take the chain for the duration of the operation, so nested
+        // operations don't pass through it again
+        let mut chain = mem::take(&mut self.middleware);
+        let mut output = None;
+
+        let result = middleware::dispatch(&mut chain, operation, &mut || {
+            output = Some(perform(self)?);
+            Ok(())
+        });
+
+        chain.append(&mut self.middleware);
+        self.middleware = chain;
+        result?;
+
+        output.ok_or_else(|| {
+            error!("middleware completed {:?} without performing it", operation);
+            Error::GenericError
+        })
+    }
+
+    /// Perform the given operation modifying the YubiKey, passing it through
+    /// the middleware chain, or only record it in dry-run mode, returning the
+    /// output of `simulate` instead.
+    pub(crate) fn run_write<T>(
+        &mut self,
+        operation: Operation,
+        simulate: impl FnOnce(&mut Self) -> Result<T>,
+        perform: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        if self.dry_run.is_none() {
+            return self.run(operation, perform);
+        }
+
+        let output = simulate(self)?;
+        info!("dry run: would perform {:?}", operation);
+
+        if let Some(planned) = &mut self.dry_run {
+            planned.push(operation);
+        }
+
+        Ok(output)
+    }
+
+    /// Get the [`WriteLog`] of data objects written during this session.
+    pub fn write_log(&self) -> WriteLog {
+        self.write_log.borrow().clone()
+    }
+
+    /// Set the number of writes to a single data object during this session
+    /// above which a warning is logged, or `None` to never warn.
+    ///
+    /// There is no budget by default.
+    pub fn set_write_budget(&mut self, budget: Option<u32>) {
+        self.write_log.get_mut().set_budget(budget);
+    }
+
     /// Get the number of PIN retries.
     pub fn get_pin_retries(&mut self) -> Result<u8> {
-        let txn = self.begin_transaction()?;
+        self.idempotent(|yubikey| {
+            // The re-select below ends the current PIN verification session
+            yubikey.pin_verified = false;
 
-        // Force a re-select to unverify, because once verified the spec dictates that
-        // subsequent verify calls will return a "verification not needed" instead of
-        // the number of tries left...
-        txn.select_application()?;
+            let txn = yubikey.begin_transaction()?;
 
-        // WRONG_PIN is expected on successful query.
-        match txn.verify_pin(&[]) {
-            Ok(()) => Ok(0), // TODO(tarcieri): verify this matches `yubico-piv-tool`
-            Err(Error::WrongPin { tries }) => Ok(tries),
-            Err(e) => Err(e),
-        }
+            // Force a re-select to unverify, because once verified the spec dictates that
+            // subsequent verify calls will return a "verification not needed" instead of
+            // the number of tries left...
+            txn.select_application()?;
+
+            // WRONG_PIN is expected on successful query.
+            match txn.verify_pin(&[]) {
+                Ok(()) => Ok(0), // TODO(tarcieri): verify this matches `yubico-piv-tool`
+                Err(Error::WrongPin { tries }) => Ok(tries),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Get the metadata of the PIN: whether it's the default PIN, and its
+    /// total and remaining retries.
+    ///
+    /// Unlike [`YubiKey::get_pin_retries`], this doesn't end the current PIN
+    /// verification session. Returns [`Error::NotSupported`] if the YubiKey
+    /// doesn't support [`Capability::Metadata`].
+    pub fn pin_metadata(&mut self) -> Result<PinMetadata> {
+        piv::metadata(self, SlotId::Management(ManagementSlotId::Pin))?.try_into()
+    }
+
+    /// Get the metadata of the PUK: see [`YubiKey::pin_metadata`].
+    pub fn puk_metadata(&mut self) -> Result<PinMetadata> {
+        piv::metadata(self, SlotId::Management(ManagementSlotId::Puk))?.try_into()
+    }
+
+    /// Get the metadata of the management key: its algorithm, whether it's
+    /// the default key, and its touch policy.
+    ///
+    /// Returns [`Error::NotSupported`] if the YubiKey doesn't support
+    /// [`Capability::Metadata`].
+    pub fn mgm_metadata(&mut self) -> Result<MgmMetadata> {
+        piv::metadata(self, SlotId::Management(ManagementSlotId::Management))?.try_into()
     }
 
     /// Set the number of PIN retries.
@@ -473,18 +1874,24 @@ impl YubiKey {
             return Ok(());
         }
 
-        let txn = self.begin_transaction()?;
+        self.run_write(
+            Operation::SetPinRetries,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
 
-        let templ = [0, Ins::SetPinRetries.code(), pin_tries, puk_tries];
+                let templ = [0, Ins::SetPinRetries.code(), pin_tries, puk_tries];
 
-        let status_words = txn.transfer_data(&templ, &[], 255)?.status_words();
+                let status_words = txn.transfer_data(&templ, &[], 255)?.status_words();
 
-        match status_words {
-            StatusWords::Success => Ok(()),
-            StatusWords::AuthBlockedError => Err(Error::AuthenticationError),
-            StatusWords::SecurityStatusError => Err(Error::AuthenticationError),
-            _ => Err(Error::GenericError),
-        }
+                match status_words {
+                    StatusWords::Success => Ok(()),
+                    StatusWords::AuthBlockedError => Err(Error::AuthenticationError),
+                    StatusWords::SecurityStatusError => Err(Error::AuthenticationError),
+                    _ => Err(Error::GenericError),
+                }
+            },
+        )
     }
 
     /// Change the Personal Identification Number (PIN).
@@ -492,44 +1899,51 @@ impl YubiKey {
     /// The default PIN code is `123456`.
     #[cfg(feature = "untested")]
     pub fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
-        {
-            let txn = self.begin_transaction()?;
-            txn.change_ref(ChangeRefAction::ChangePin, current_pin, new_pin)?;
-        }
+        self.run_write(
+            Operation::ChangePin,
+            |_| Ok(()),
+            |yubikey| {
+                {
+                    let txn = yubikey.begin_transaction()?;
+                    txn.change_ref(ChangeRefAction::ChangePin, current_pin, new_pin)?;
+                }
 
-        if !new_pin.is_empty() {
-            self.pin = Some(CachedPin::new(new_pin.into()));
-        }
+                if !new_pin.is_empty() {
+                    yubikey.pin = Some(CachedPin::new(new_pin.into()));
+                }
 
-        Ok(())
+                Ok(())
+            },
+        )
     }
 
     /// Set PIN last changed.
     #[cfg(feature = "untested")]
     pub fn set_pin_last_changed(yubikey: &mut YubiKey) -> Result<()> {
-        let txn = yubikey.begin_transaction()?;
-
-        let mut admin_data = AdminData::read(&txn)?;
-
         // TODO(tarcieri): double check this is little endian
-        let tnow = SystemTime::now()
-            .duration_since(UNIX_EPOCH)?
-            .as_secs()
-            .to_le_bytes();
-
-        admin_data
-            .set_item(TAG_ADMIN_TIMESTAMP, &tnow)
-            .map_err(|e| {
-                error!("could not set pin timestamp, err = {}", e);
-                e
-            })?;
-
-        admin_data.write(&txn).map_err(|e| {
-            error!("could not write admin data, err = {}", e);
-            e
-        })?;
-
-        Ok(())
+        let tnow = clock::unix_time(yubikey.clock())?.as_secs().to_le_bytes();
+
+        yubikey.run_write(
+            Operation::SetPinLastChanged,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+
+                let mut admin_data = AdminData::read(&txn)?;
+
+                admin_data
+                    .set_item(TAG_ADMIN_TIMESTAMP, &tnow)
+                    .map_err(|e| {
+                        error!("could not set pin timestamp, err = {}", e);
+                        e
+                    })?;
+
+                admin_data.write(&txn).map_err(|e| {
+                    error!("could not write admin data, err = {}", e);
+                    e
+                })
+            },
+        )
     }
 
     /// Change the PIN Unblocking Key (PUK). PUKs are codes for resetting
@@ -541,13 +1955,28 @@ impl YubiKey {
     /// The default PUK code is `12345678`.
     #[cfg(feature = "untested")]
     pub fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
-        let txn = self.begin_transaction()?;
-        txn.change_ref(ChangeRefAction::ChangePuk, current_puk, new_puk)
+        self.run_write(
+            Operation::ChangePuk,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+                txn.change_ref(ChangeRefAction::ChangePuk, current_puk, new_puk)
+            },
+        )
     }
 
     /// Block PUK: permanently prevent the PIN from becoming unblocked.
     #[cfg(feature = "untested")]
     pub fn block_puk(&mut self) -> Result<()> {
+        self.run_write(
+            Operation::BlockPuk,
+            |_| Ok(()),
+            |yubikey| yubikey.block_puk_inner(),
+        )
+    }
+
+    #[cfg(feature = "untested")]
+    fn block_puk_inner(&mut self) -> Result<()> {
         let mut puk = [0x30, 0x42, 0x41, 0x44, 0x46, 0x30, 0x30, 0x44];
         let mut tries_remaining: i32 = -1;
         let mut flags = [0];
@@ -611,22 +2040,33 @@ impl YubiKey {
     /// configured PIN Unblocking Key (PUK).
     #[cfg(feature = "untested")]
     pub fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
-        let txn = self.begin_transaction()?;
-        txn.change_ref(ChangeRefAction::UnblockPin, puk, new_pin)
+        self.run_write(
+            Operation::UnblockPin,
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+                txn.change_ref(ChangeRefAction::UnblockPin, puk, new_pin)
+            },
+        )
     }
 
     /// Fetch an object from the YubiKey.
     #[cfg(feature = "untested")]
     pub fn fetch_object(&mut self, object_id: ObjectId) -> Result<Buffer> {
-        let txn = self.begin_transaction()?;
-        txn.fetch_object(object_id)
+        self.idempotent(|yubikey| yubikey.begin_transaction()?.fetch_object(object_id))
     }
 
     /// Save an object.
     #[cfg(feature = "untested")]
     pub fn save_object(&mut self, object_id: ObjectId, indata: &mut [u8]) -> Result<()> {
-        let txn = self.begin_transaction()?;
-        txn.save_object(object_id, indata)
+        self.run_write(
+            Operation::SaveObject { object_id },
+            |_| Ok(()),
+            |yubikey| {
+                let txn = yubikey.begin_transaction()?;
+                txn.save_object(object_id, indata)
+            },
+        )
     }
 
     /// Reset YubiKey.
@@ -636,63 +2076,118 @@ impl YubiKey {
     /// The reset function is only available when both pins are blocked.
     #[cfg(feature = "untested")]
     pub fn reset_device(&mut self) -> Result<()> {
-        let templ = [0, Ins::Reset.code(), 0, 0];
-        let txn = self.begin_transaction()?;
-        let status_words = txn.transfer_data(&templ, &[], 255)?.status_words();
-
-        if !status_words.is_success() {
-            return Err(Error::GenericError);
-        }
+        self.run_write(
+            Operation::Reset,
+            |_| Ok(()),
+            |yubikey| {
+                yubikey.pin_verified = false;
+                yubikey.slot_policies.clear();
+
+                let templ = [0, Ins::Reset.code(), 0, 0];
+                let txn = yubikey.begin_transaction()?;
+                let status_words = txn.transfer_data(&templ, &[], 255)?.status_words();
+
+                if !status_words.is_success() {
+                    return Err(Error::GenericError);
+                }
 
-        Ok(())
+                Ok(())
+            },
+        )
     }
 }
 
+#[cfg(feature = "pcsc")]
 impl<'a> TryFrom<&'a Reader<'_>> for YubiKey {
     type Error = Error;
 
     fn try_from(reader: &'a Reader<'_>) -> Result<Self> {
-        let mut card = reader.connect().map_err(|e| {
+        let card = reader.connect(pcsc::ShareMode::Shared).map_err(|e| {
             error!("error connecting to reader '{}': {}", reader.name(), e);
             e
         })?;
 
         info!("connected to reader: {}", reader.name());
+        YubiKey::open_with_transport(PcscTransport::new(card, reader.name()), reader.name())
+    }
+}
 
-        let mut app_version_serial = || -> Result<(Version, Serial)> {
-            let txn = Transaction::new(&mut card)?;
-            txn.select_application()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let v = txn.get_version()?;
-            let s = txn.get_serial(v)?;
-            Ok((v, s))
-        };
+    #[test]
+    fn serial_formats() {
+        let serial = Serial(7_048_830);
 
-        match app_version_serial() {
-            Err(e) => {
-                error!("Could not use reader: {}", e);
+        assert_eq!(serial.format(SerialFormat::Decimal), "7048830");
+        assert_eq!(serial.format(SerialFormat::Hex), "006b8e7e");
+        assert_eq!(serial.format(SerialFormat::Modhex), "cchnjuiu");
+        assert_eq!(serial.otp_public_id(), "vvcccchnjuiu");
 
-                // We were unable to use the card, so we've effectively only connected as
-                // a side-effect of determining this. Avoid disrupting its internal state
-                // any further (e.g. preserve the PIN cache of whatever applet is selected
-                // currently).
-                if let Err((_, e)) = card.disconnect(Disposition::LeaveCard) {
-                    error!("Failed to disconnect gracefully from card: {}", e);
-                }
+        for s in ["7048830", "0x006b8e7e", "0X6B8E7E", "cchnjuiu", "hnjuiu"] {
+            assert_eq!(s.parse::<Serial>(), Ok(serial));
+        }
 
-                Err(e)
-            }
-            Ok((version, serial)) => {
-                let yubikey = YubiKey {
-                    card,
-                    name: String::from(reader.name()),
-                    pin: None,
-                    version,
-                    serial,
-                };
+        assert_eq!(
+            Serial::from_otp("vvcccchnjuiuhdhvlgrftgdttnrghjiiclrdvrnitbvd"),
+            Ok(serial)
+        );
+        assert!(Serial::from_otp("ccccccjlkgjt").is_err());
+    }
 
-                Ok(yubikey)
-            }
+    #[test]
+    fn serial_rejects_invalid_input() {
+        for s in [
+            // Empty, or out of range
+            "",
+            "0x",
+            "4294967296",
+            "0x100000000",
+            "cccccchnjuiu",
+            // Mixed formats
+            "7048830cc",
+            "0xcchnjuiu",
+            "cchn7e7e",
+            "6b8e7e",
+            // Invalid characters or modhex of odd length
+            "-7048830",
+            "0x-6b8e7e",
+            "cchnjuix",
+            "chnjuiu",
+            " 7048830",
+        ] {
+            assert_eq!(s.parse::<Serial>(), Err(Error::ParseError), "{:?}", s);
+        }
+
+        assert_eq!(
+            Serial::parse_as("cchnjuiu", SerialFormat::Hex),
+            Err(Error::ParseError)
+        );
+        assert_eq!(
+            Serial::parse_as("006b8e7e", SerialFormat::Decimal),
+            Err(Error::ParseError)
+        );
+        assert_eq!(
+            Serial::parse_as("0x006b8e7e", SerialFormat::Modhex),
+            Err(Error::ParseError)
+        );
+    }
+
+    #[test]
+    fn version_parse_and_order() {
+        let version: Version = "5.4.3".parse().expect("version");
+
+        assert_eq!(version, Version::new([5, 4, 3]));
+        assert_eq!(version.to_string(), "5.4.3");
+        assert!(version > Version::new([4, 3, 7]));
+        assert!(version > Version::new([5, 3, 9]));
+        assert!(version < Version::new([5, 7, 0]));
+        assert!(version.is_at_least(5, 4, 3));
+        assert!(!version.is_at_least(5, 4, 4));
+
+        for invalid in ["5.4", "5.4.3.1", "5.x.3", "5.4.256", "5..3", ""] {
+            assert!(invalid.parse::<Version>().is_err(), "{:?}", invalid);
         }
     }
 }
@@ -1,24 +1,40 @@
 //! Integration tests
-
+//!
+//! These expect a YubiKey in the default state, so they need the default
+//! management key and are unavailable with `no-default-credentials` (or
+//! without `pcsc`).
+//!
+//! Tests which generate keys or change the management key are refused unless
+//! the YubiKey's serial number is listed in `YUBIKEY_ALLOW_DESTRUCTIVE`, so
+//! that they can't be run against a YubiKey in use by accident.
+
+#![cfg(all(feature = "pcsc", not(feature = "no-default-credentials")))]
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
 
 use log::trace;
 use once_cell::sync::Lazy;
 use rand_core::{OsRng, RngCore};
-use rsa::{pkcs1v15, RsaPublicKey};
-use sha2::{Digest, Sha256};
-use signature::hazmat::PrehashVerifier;
 use std::{env, str::FromStr, sync::Mutex, time::Duration};
 use x509_cert::{der::Encode, name::Name, serial_number::SerialNumber, time::Validity};
 use yubikey::{
+    applet,
     certificate::yubikey_signer,
-    certificate::Certificate,
+    certificate::{CertInfo, Certificate},
+    guard::DestructiveGuard,
+    inventory::{self, Inventory},
+    journal::{Journal, WriteSequence},
     piv::{self, AlgorithmId, Key, ManagementAlgorithmId, ManagementSlotId, RetiredSlotId, SlotId},
-    Error, MgmKey3Des, MgmKeyAes192, PinPolicy, Serial, TouchPolicy, YubiKey,
+    reader::{self, ReaderEvent},
+    Disposition, Error, MgmKey3Des, MgmKeyAes192, PinPolicy, RateLimits, Serial, SlotLabels,
+    TouchPolicy, YubiKey,
 };
 #[cfg(feature = "untested")]
-use yubikey::{MgmKey, MgmKeyAlgorithm};
+use yubikey::{
+    attestation::{AttestationRequirements, VerificationBundle},
+    report::{Report, SignedReport},
+    MgmKey, MgmKeyAlgorithm,
+};
 
 static YUBIKEY: Lazy<Mutex<YubiKey>> = Lazy::new(|| {
     // Only show logs if `RUST_LOG` is set
@@ -26,7 +42,7 @@ static YUBIKEY: Lazy<Mutex<YubiKey>> = Lazy::new(|| {
         env_logger::builder().format_timestamp(None).init();
     }
 
-    let yubikey = if let Ok(serial) = env::var("YUBIKEY_SERIAL") {
+    let mut yubikey = if let Ok(serial) = env::var("YUBIKEY_SERIAL") {
         let serial = Serial::from_str(&serial).unwrap();
         YubiKey::open_by_serial(serial).unwrap()
     } else {
@@ -36,6 +52,9 @@ static YUBIKEY: Lazy<Mutex<YubiKey>> = Lazy::new(|| {
     trace!("serial: {}", yubikey.serial());
     trace!("version: {}", yubikey.version());
 
+    let guard = DestructiveGuard::from_env(yubikey.serial()).unwrap();
+    yubikey.add_middleware(guard);
+
     Mutex::new(yubikey)
 });
 
@@ -84,6 +103,16 @@ fn test_get_config() {
     trace!("config: {:?}", config_result.unwrap());
 }
 
+#[cfg(feature = "serde")]
+#[test]
+#[ignore]
+fn test_info_json() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    let json = yubikey.info_json().unwrap();
+    assert!(json.contains(&format!("\"serial_number\": {}", yubikey.serial())));
+    trace!("info: {}", json);
+}
+
 //
 // Cryptographic key support
 //
@@ -109,6 +138,195 @@ fn test_verify_pin() {
     assert!(yubikey.verify_pin(b"123456").is_ok());
 }
 
+#[test]
+#[ignore]
+fn test_clear_auth_state() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+
+    assert!(yubikey.clear_auth_state().is_ok());
+    assert!(!yubikey.is_pin_verified());
+
+    // An empty PIN only queries whether the PIN is verified
+    assert!(yubikey.verify_pin(b"").is_err());
+    assert!(yubikey.config().is_ok());
+}
+
+#[test]
+#[ignore]
+fn test_pin_rate_limits() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    yubikey.set_rate_limits(RateLimits::unlimited().max_consecutive_pin_failures(1));
+
+    assert!(matches!(
+        yubikey.verify_pin(b"000000"),
+        Err(Error::WrongPin { .. })
+    ));
+    assert_eq!(
+        yubikey.verify_pin(b"123456"),
+        Err(Error::RateLimited { retry_after: None })
+    );
+
+    // Resetting the limits clears the count of failures
+    yubikey.set_rate_limits(RateLimits::unlimited());
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+}
+
+#[test]
+#[ignore]
+fn test_piv_applets() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+
+    let selected = applet::selected_piv_applet(&mut yubikey)
+        .unwrap()
+        .expect("PIV applet selected");
+    assert!(selected.is_piv());
+
+    let instances = applet::piv_instances(&mut yubikey).unwrap();
+    assert_eq!(instances.first(), Some(&selected));
+
+    let aids: Vec<&[u8]> = applet::KNOWN_APPLETS.iter().map(|&(_, aid)| aid).collect();
+    let applets = applet::probe(&mut yubikey, &aids).unwrap();
+    assert!(applets.iter().any(|applet| applet.is_piv()));
+    assert!(yubikey.config().is_ok());
+}
+
+#[test]
+#[ignore]
+fn test_revalidate() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+
+    // Revalidate before every operation, as if resuming from suspend
+    yubikey.set_revalidate_after(Some(Duration::ZERO));
+    assert!(yubikey.revalidate().is_ok());
+    assert!(yubikey.is_pin_verified());
+    assert!(yubikey.config().is_ok());
+    yubikey.set_revalidate_after(Some(Duration::from_secs(60)));
+}
+
+#[test]
+#[ignore]
+fn test_list() {
+    let (serial, version) = {
+        let yubikey = YUBIKEY.lock().unwrap();
+        (yubikey.serial(), yubikey.version())
+    };
+
+    let listed = YubiKey::list().unwrap();
+    let yubikey = listed.iter().find(|yk| yk.serial == serial).unwrap();
+    assert_eq!(yubikey.version, version);
+    assert!(!yubikey.reader.is_empty());
+}
+
+#[test]
+#[ignore]
+fn test_open_by_reader() {
+    let serial = YUBIKEY.lock().unwrap().serial();
+    let mut readers = reader::Context::open().unwrap();
+
+    // The YubiKey is held open, so open another connection to its reader
+    let name = readers
+        .iter()
+        .unwrap()
+        .find(|reader| match reader.open() {
+            Ok(yubikey) => {
+                let found = yubikey.serial() == serial;
+                let _ = yubikey.disconnect(Disposition::LeaveCard);
+                found
+            }
+            Err(_) => false,
+        })
+        .unwrap()
+        .name()
+        .into_owned();
+
+    let yubikey = YubiKey::open_by_reader(&name).unwrap();
+    assert_eq!(yubikey.serial(), serial);
+    let _ = yubikey.disconnect(Disposition::LeaveCard);
+    assert!(matches!(
+        YubiKey::open_by_reader("no such reader"),
+        Err(Error::NotFound)
+    ));
+}
+
+#[test]
+#[ignore]
+fn test_open_exclusive() {
+    let _yubikey = YUBIKEY.lock().unwrap();
+
+    // The YubiKey is held open in shared mode, so it can't be opened exclusively
+    let options = reader::OpenOptions::default().exclusive();
+    assert!(YubiKey::open_with(&options).is_err());
+}
+
+#[test]
+#[ignore]
+fn test_global() {
+    let serial = YUBIKEY.lock().unwrap().serial();
+    yubikey::global::init(yubikey::global::GlobalConfig::new().serial(serial));
+
+    assert_eq!(yubikey::global().unwrap().serial(), serial);
+
+    // Panicking while holding the handle closes it rather than poisoning it
+    let result = std::thread::spawn(|| {
+        let _yubikey = yubikey::global().unwrap();
+        panic!("holding the global YubiKey");
+    })
+    .join();
+    assert!(result.is_err());
+
+    assert_eq!(yubikey::global().unwrap().serial(), serial);
+    yubikey::global::close();
+}
+
+#[test]
+#[ignore]
+fn test_watch_readers() {
+    let serial = YUBIKEY.lock().unwrap().serial();
+
+    // The connected YubiKey is reported as inserted when watching starts
+    let mut watch = reader::watch().unwrap();
+    let mut found = false;
+
+    while let Some(event) = watch.next_timeout(Duration::ZERO).unwrap() {
+        if let ReaderEvent::Inserted {
+            serial: Some(s), ..
+        } = event
+        {
+            found |= s == serial;
+        }
+    }
+
+    assert!(found);
+
+    let canceller = watch.canceller();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        canceller.cancel().unwrap();
+    });
+
+    assert!(watch.next().is_none());
+    thread.join().unwrap();
+}
+
+#[test]
+#[ignore]
+fn test_estimate_interactions() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+
+    // The signature slot defaults to PIN-always, so each use requires the PIN
+    let interactions =
+        piv::estimate_interactions(&mut yubikey, &[SlotId::Signature, SlotId::Signature]).unwrap();
+    assert!(interactions.pin_entries >= 2);
+
+    // Card authentication defaults to PIN-never
+    let interactions =
+        piv::estimate_interactions(&mut yubikey, &[SlotId::CardAuthentication]).unwrap();
+    assert_eq!(interactions.pin_entries, 0);
+}
+
 fn get_mgm_key_meta(yubikey: &mut YubiKey) -> piv::SlotMetadata {
     piv::metadata(yubikey, SlotId::Management(ManagementSlotId::Management)).unwrap()
 }
@@ -231,16 +449,68 @@ fn generate_self_signed_rsa_cert() {
     // Verify that the certificate is signed correctly
     //
 
-    let pubkey = RsaPublicKey::try_from(cert.subject_pki()).expect("valid rsa key");
-    let pubkey = pkcs1v15::VerifyingKey::<Sha256>::new(pubkey);
+    assert!(cert.verify_self_signed().is_ok());
+
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(cert
+        .verify_with_slot(&mut yubikey, SlotId::Retired(RetiredSlotId::R1))
+        .is_ok());
+}
+
+#[cfg(feature = "hazmat")]
+#[test]
+#[ignore]
+fn test_raw_rsa_operation() {
+    use rsa::{traits::PublicKeyParts, BigUint, RsaPublicKey};
+    use x509_cert::der::referenced::OwnedToRef;
+
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    auth_default_mgm(&mut yubikey);
+
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::Rsa2048,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public_key = RsaPublicKey::try_from(generated.owned_to_ref()).unwrap();
 
-    let data = cert.cert.to_der().expect("serialize certificate");
-    let tbs_cert_len = u16::from_be_bytes(data[6..8].try_into().unwrap()) as usize;
-    let msg = &data[4..8 + tbs_cert_len];
-    let sig = pkcs1v15::Signature::try_from(&data[data.len() - 128..]).unwrap();
-    let hash = Sha256::digest(msg);
+    // Caller-prepared block, recovered with the public exponent
+    let mut block = [0xaa; 256];
+    block[0] = 0x00;
+    let output = piv::raw_rsa_operation(&mut yubikey, slot, &block).unwrap();
+    let recovered = BigUint::from_bytes_be(&output).modpow(public_key.e(), public_key.n());
+    assert_eq!(recovered.to_bytes_be(), &block[1..]);
 
-    assert!(pubkey.verify_prehash(&hash, &sig).is_ok());
+    assert_eq!(
+        piv::raw_rsa_operation(&mut yubikey, slot, &block[..255]).err(),
+        Some(Error::SizeError)
+    );
+}
+
+#[test]
+#[ignore]
+fn test_rewrite_unchanged_cert() {
+    let cert = generate_self_signed_cert::<p256::NistP256>();
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    auth_default_mgm(&mut yubikey);
+
+    // Identical content is only skipped when asked to
+    assert!(cert
+        .write(&mut yubikey, slot, CertInfo::Uncompressed)
+        .is_ok());
+    assert!(!cert
+        .write_if_changed(&mut yubikey, slot, CertInfo::Uncompressed)
+        .unwrap());
+
+    let read_back = Certificate::read(&mut yubikey, slot).unwrap();
+    assert_eq!(read_back.as_der(), cert.as_der());
 }
 
 #[test]
@@ -252,17 +522,209 @@ fn generate_self_signed_ec_cert() {
     // Verify that the certificate is signed correctly
     //
 
-    let vk = p256::ecdsa::VerifyingKey::try_from(cert.subject_pki()).expect("ecdsa key expected");
+    assert!(cert.verify_self_signed().is_ok());
+
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(cert
+        .verify_with_slot(&mut yubikey, SlotId::Retired(RetiredSlotId::R1))
+        .is_ok());
+}
+
+#[cfg(feature = "untested")]
+#[test]
+#[ignore]
+fn generate_self_signed_attested_cert() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    auth_default_mgm(&mut yubikey);
 
-    let data = cert.cert.to_der().expect("serialize certificate");
-    let tbs_cert_len = data[6] as usize;
-    let sig_algo_len = data[7 + tbs_cert_len + 1] as usize;
-    let sig_start = 7 + tbs_cert_len + 2 + sig_algo_len + 3;
-    let msg = &data[4..7 + tbs_cert_len];
-    let sig = p256::ecdsa::Signature::from_der(&data[sig_start..]).unwrap();
+    let slot = SlotId::Retired(RetiredSlotId::R2);
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let cert = Certificate::generate_self_signed_attested::<_, p256::NistP256>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=testSubject").expect("parse name"),
+        generated.clone(),
+        &AttestationRequirements::default().pin_policy(PinPolicy::Once),
+        |_builder| Ok(()),
+    )
+    .unwrap();
+    assert!(cert.verify_self_signed().is_ok());
 
-    use p256::ecdsa::signature::Verifier;
-    assert!(vk.verify(msg, &sig).is_ok());
+    // The attestation doesn't meet the requirements, so nothing is signed
+    let result = Certificate::generate_self_signed_attested::<_, p256::NistP256>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x02]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=testSubject").expect("parse name"),
+        generated,
+        &AttestationRequirements::default().touch_policy(TouchPolicy::Always),
+        |_builder| Ok(()),
+    );
+    assert!(matches!(result, Err(Error::AttestationError)));
+}
+
+#[cfg(feature = "untested")]
+#[test]
+#[ignore]
+fn test_derive_device_secret() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    auth_default_mgm(&mut yubikey);
+
+    let slot = SlotId::Retired(RetiredSlotId::R2);
+    piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+
+    let disk = piv::derive_device_secret(&mut yubikey, slot, b"disk").unwrap();
+    let enrollment = piv::derive_device_secret(&mut yubikey, slot, b"enrollment").unwrap();
+
+    assert_eq!(
+        *piv::derive_device_secret(&mut yubikey, slot, b"disk").unwrap(),
+        *disk
+    );
+    assert_ne!(*disk, *enrollment);
+}
+
+#[cfg(feature = "untested")]
+#[test]
+#[ignore]
+fn test_signed_report() {
+    let cert = generate_self_signed_cert::<p256::NistP256>();
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    let report = Report::collect(&mut yubikey).unwrap();
+    assert!(report.slot(slot).is_some());
+
+    let signed = report.sign(&mut yubikey, slot).unwrap();
+    let parsed = SignedReport::from_der(signed.as_der()).unwrap();
+    assert_eq!(parsed.signing_slot(), slot);
+    assert_eq!(parsed.report().serial, yubikey.serial());
+    assert_eq!(
+        parsed.report().slot(slot).unwrap().public_key,
+        cert.cert.tbs_certificate.subject_public_key_info
+    );
+
+    // Without a trusted root, the report proves nothing
+    assert!(matches!(
+        parsed.verify(&VerificationBundle::default()),
+        Err(Error::AttestationError)
+    ));
+}
+
+#[test]
+#[ignore]
+fn test_regenerate_pin_never_slots() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    auth_default_mgm(&mut yubikey);
+
+    let slot = SlotId::Retired(RetiredSlotId::R3);
+    piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let inventory = Inventory::collect(&mut yubikey).unwrap();
+    assert!(inventory.pin_never_slots().any(|entry| entry.slot == slot));
+
+    let regenerated = inventory::regenerate_pin_never_slots(
+        &mut yubikey,
+        &inventory,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+    assert!(regenerated.iter().any(|entry| entry.slot == slot));
+
+    let inventory = Inventory::collect(&mut yubikey).unwrap();
+    assert_eq!(inventory.pin_never_slots().count(), 0);
+}
+
+#[test]
+#[ignore]
+fn test_slot_labels() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    auth_default_mgm(&mut yubikey);
+
+    let mut labels = SlotLabels::default();
+    labels.set(SlotId::Signature, "prod-code-signing").unwrap();
+    labels.set(SlotId::Authentication, "dev-ssh").unwrap();
+    assert!(labels.set(SlotId::KeyManagement, "x".repeat(65)).is_err());
+    labels.write(&mut yubikey).unwrap();
+
+    let read = SlotLabels::read(&mut yubikey).unwrap();
+    assert_eq!(read, labels);
+    assert_eq!(read.find("dev-ssh"), Some(SlotId::Authentication));
+
+    SlotLabels::default().write(&mut yubikey).unwrap();
+    assert_eq!(
+        SlotLabels::read(&mut yubikey).unwrap(),
+        SlotLabels::default()
+    );
+}
+
+#[test]
+#[ignore]
+fn test_conformance_mode() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    yubikey.set_conformance_mode(true);
+
+    assert_eq!(SlotLabels::read(&mut yubikey), Err(Error::NotSupported));
+    assert_eq!(
+        piv::metadata(&mut yubikey, SlotId::Authentication).err(),
+        Some(Error::NotSupported)
+    );
+    assert!(yubikey.chuid().is_ok());
+
+    yubikey.set_conformance_mode(false);
+    assert!(SlotLabels::read(&mut yubikey).is_ok());
+}
+
+#[test]
+#[ignore]
+fn test_write_sequence() {
+    let mut yubikey = YUBIKEY.lock().unwrap();
+    auth_default_mgm(&mut yubikey);
+
+    let mut labels = SlotLabels::default();
+    labels.set(SlotId::Signature, "prod-code-signing").unwrap();
+    labels.write(&mut yubikey).unwrap();
+
+    // Delete the slot labels object
+    let mut sequence = WriteSequence::new();
+    sequence.write(0x005f_ff20, &[]).unwrap();
+    sequence.commit(&mut yubikey).unwrap();
+
+    assert!(Journal::read(&mut yubikey).unwrap().is_none());
+    assert_eq!(
+        SlotLabels::read(&mut yubikey).unwrap(),
+        SlotLabels::default()
+    );
 }
 
 #[test]
@@ -395,7 +857,7 @@ fn test_read_metadata_missing_key() {
 #[ignore]
 fn test_parse_cert_from_der() {
     let bob_der = std::fs::read("tests/assets/Bob.der").expect(".der file not found");
-    let cert = Certificate::from_bytes(bob_der).expect("Failed to parse valid certificate");
+    let cert = Certificate::from_bytes(bob_der.clone()).expect("Failed to parse valid certificate");
     assert_eq!(
         cert.subject(),
         "CN=Bob",
@@ -409,4 +871,42 @@ fn test_parse_cert_from_der() {
         cert.issuer(),
         "CN=Ferdinand Linnenberg CA"
     );
+    assert_eq!(cert.as_der(), &bob_der[..]);
+    assert_eq!(
+        cert.tbs_bytes(),
+        cert.cert.tbs_certificate.to_der().unwrap()
+    );
+    assert_eq!(cert.signature_bytes(), cert.cert.signature.raw_bytes());
+}
+
+//
+// Async API
+//
+
+#[cfg(feature = "async")]
+#[test]
+#[ignore]
+fn test_async_sign() {
+    use yubikey::nonblocking::AsyncYubiKey;
+
+    // Hold the lock so other tests don't use the YubiKey concurrently
+    let guard = YUBIKEY.lock().unwrap();
+    let serial = guard.serial();
+    let yubikey = futures_lite::future::block_on(AsyncYubiKey::open_by_serial(serial)).unwrap();
+
+    futures_lite::future::block_on(async {
+        yubikey.verify_pin(b"123456").await.unwrap();
+
+        let cert = yubikey
+            .read_certificate(SlotId::Authentication)
+            .await
+            .unwrap();
+        trace!("certificate: {}", cert.subject());
+
+        let digest = [0u8; 32];
+        let signature = yubikey
+            .sign_data(&digest, AlgorithmId::EccP256, SlotId::Authentication)
+            .await;
+        trace!("signature: {:?}", signature);
+    });
 }